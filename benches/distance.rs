@@ -0,0 +1,24 @@
+// The distance functions run once per row in every similarity search.
+// Run with `cargo bench --bench distance`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use pixelbox::engine::{byte_distance, cosine_distance, hamming_distance};
+
+/// Deterministic bytes that look random enough to keep the compiler from cheating.
+fn make_hash(length: usize, seed: u32) -> Vec<u8> {
+	(0..length as u32).map(|i| (i.wrapping_add(seed).wrapping_mul(2654435761) >> 24) as u8).collect()
+}
+
+fn bench_distances(c: &mut Criterion) {
+	// Perceptual hashes are short.  Embeddings are hundreds to thousands of values.
+	for length in [8, 512, 2048] {
+		let a = make_hash(length, 1);
+		let b = make_hash(length, 2);
+		c.bench_function(&format!("hamming_distance {}", length), |bench| bench.iter(|| hamming_distance(black_box(&a), black_box(&b))));
+		c.bench_function(&format!("byte_distance {}", length), |bench| bench.iter(|| byte_distance(black_box(&a), black_box(&b))));
+		c.bench_function(&format!("cosine_distance {}", length), |bench| bench.iter(|| cosine_distance(black_box(&a), black_box(&b))));
+	}
+}
+
+criterion_group!(benches, bench_distances);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+/* C API for the PixelBox index.  See src/ffi.rs. */
+#ifndef PIXELBOX_H
+#define PIXELBOX_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct PixelBoxEngine PixelBoxEngine;
+
+/* Open an existing database.  Returns NULL on failure.  Close with pixelbox_close. */
+PixelBoxEngine *pixelbox_open(const char *db_path);
+void pixelbox_close(PixelBoxEngine *engine);
+
+/* These return JSON: {"total_results": n, "results": [...]} or {"error": "..."}.
+   Every returned string must be freed with pixelbox_free_string. */
+char *pixelbox_query_json(PixelBoxEngine *engine, const char *query);
+char *pixelbox_similar_file(PixelBoxEngine *engine, const char *image_path);
+void pixelbox_free_string(char *text);
+
+/* Indexing runs in the background.  The status is JSON like the above:
+   {"active", "progress", "num_indexed", "num_pending", "last_indexed"}. */
+void pixelbox_start_reindexing(PixelBoxEngine *engine);
+char *pixelbox_indexing_status_json(PixelBoxEngine *engine);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
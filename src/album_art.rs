@@ -0,0 +1,290 @@
+// Music is indexed by its embedded cover art, with the song's title, artist, and album as tags.
+// MP3s need an ID3v2 tag and FLACs a PICTURE block.  Files without a cover can't be indexed.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+const ID3_MAGIC: &[u8] = b"ID3";
+const FLAC_MAGIC: &[u8] = b"fLaC";
+const FRONT_COVER: u8 = 3; // The ID3 and FLAC picture type for the front cover.  Preferred over any other picture.
+const FLAC_PICTURE_BLOCK: u8 = 6;
+const FLAC_VORBIS_COMMENT_BLOCK: u8 = 4;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AudioInfo {
+	pub cover: Vec<u8>, // The encoded image, usually a JPEG or PNG.
+	pub title: Option<String>,
+	pub artist: Option<String>,
+	pub album: Option<String>,
+}
+
+impl AudioInfo {
+	/// Tags to index the cover under, so songs can be found by album.
+	pub fn to_tags(&self) -> HashMap<String, String> {
+		let mut tags = HashMap::new();
+		for (name, value) in [("Title", &self.title), ("Artist", &self.artist), ("Album", &self.album)] {
+			if let Some(value) = value {
+				tags.insert(name.to_string(), value.clone());
+			}
+		}
+		tags
+	}
+}
+
+pub fn is_audio_file(bytes: &[u8]) -> bool {
+	bytes.starts_with(ID3_MAGIC) || bytes.starts_with(FLAC_MAGIC)
+}
+
+/// The cover and song details, or an error if there's no cover.
+pub fn read_audio_info(bytes: &[u8]) -> Result<AudioInfo> {
+	let info = if bytes.starts_with(FLAC_MAGIC) { read_flac(bytes)? } else { read_id3(bytes)? };
+	if info.cover.is_empty() {
+		return Err(anyhow!("No cover art."));
+	}
+	Ok(info)
+}
+
+fn read_id3(bytes: &[u8]) -> Result<AudioInfo> {
+	if bytes.len() < 10 || !bytes.starts_with(ID3_MAGIC) {
+		return Err(anyhow!("Not an ID3 tag."));
+	}
+	let version = bytes[3];
+	let flags = bytes[5];
+	let tag_end = (10 + syncsafe(&bytes[6..10])).min(bytes.len());
+	let mut tag = bytes[10..tag_end].to_vec();
+	// Before 2.4 unsynchronisation covered the whole tag.  2.4 marks it per frame.
+	if version < 4 && flags & 0x80 != 0 {
+		tag = resynchronise(&tag);
+	}
+	let mut at = 0;
+	if flags & 0x40 != 0 && tag.len() >= 4 {
+		// The extended header counts its own size in 2.4, but not in 2.3.
+		at = if version >= 4 { syncsafe(&tag[0..4]) } else { 4 + be_u32(&tag[0..4]) as usize };
+	}
+
+	let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+	let mut info = AudioInfo::default();
+	let mut cover_type = None;
+	while at + header_len <= tag.len() && tag[at] != 0 {
+		let header = &tag[at..at + header_len];
+		let id = String::from_utf8_lossy(&header[..id_len]).to_string();
+		let size = match version {
+			2 => ((header[3] as usize) << 16) | ((header[4] as usize) << 8) | header[5] as usize,
+			3 => be_u32(&header[4..8]) as usize,
+			_ => syncsafe(&header[4..8]),
+		};
+		let start = at + header_len;
+		let end = start.saturating_add(size).min(tag.len());
+		at = end;
+		let mut frame = tag[start..end].to_vec();
+		if version >= 4 {
+			let format_flags = header[9];
+			if format_flags & 0x02 != 0 {
+				frame = resynchronise(&frame);
+			}
+			if format_flags & 0x01 != 0 && frame.len() >= 4 {
+				frame.drain(..4); // The data length indicator.
+			}
+		}
+		if frame.is_empty() {
+			continue;
+		}
+
+		match id.as_str() {
+			"TIT2" | "TT2" => info.title = decode_id3_text(frame[0], &frame[1..]),
+			"TPE1" | "TP1" => info.artist = decode_id3_text(frame[0], &frame[1..]),
+			"TALB" | "TAL" => info.album = decode_id3_text(frame[0], &frame[1..]),
+			"APIC" | "PIC" => {
+				let encoding = frame[0];
+				// 2.2 has a three letter format instead of a MIME type.
+				let after_format = if version == 2 { 4 } else { 1 + frame[1..].iter().position(|&b| b == 0).unwrap_or(frame.len()) + 1 };
+				let Some(&picture_type) = frame.get(after_format) else { continue; };
+				let description = &frame[(after_format + 1).min(frame.len())..];
+				let data = &description[text_terminator_end(encoding, description)..];
+				if !data.is_empty() && cover_type != Some(FRONT_COVER) {
+					cover_type = Some(picture_type);
+					info.cover = data.to_vec();
+				}
+			},
+			_ => {},
+		}
+	}
+	Ok(info)
+}
+
+fn read_flac(bytes: &[u8]) -> Result<AudioInfo> {
+	let mut info = AudioInfo::default();
+	let mut cover_type = None;
+	let mut at = FLAC_MAGIC.len();
+	while at + 4 <= bytes.len() {
+		let is_last = bytes[at] & 0x80 != 0;
+		let block_type = bytes[at] & 0x7f;
+		let length = ((bytes[at + 1] as usize) << 16) | ((bytes[at + 2] as usize) << 8) | bytes[at + 3] as usize;
+		let block = bytes.get(at + 4..at + 4 + length).ok_or_else(|| anyhow!("FLAC metadata block runs past the end of the file."))?;
+		at += 4 + length;
+
+		if block_type == FLAC_PICTURE_BLOCK {
+			let field = |at: usize| block.get(at..at + 4).map(be_u32).ok_or_else(|| anyhow!("Truncated FLAC picture."));
+			let picture_type = field(0)? as u8;
+			let mime_len = field(4)? as usize;
+			let description_at = 8 + mime_len;
+			// Width, height, depth, and number of colors come between the description and the data.
+			let data_len_at = description_at + 4 + field(description_at)? as usize + 16;
+			let data_len = field(data_len_at)? as usize;
+			let data = block.get(data_len_at + 4..data_len_at + 4 + data_len).ok_or_else(|| anyhow!("Truncated FLAC picture."))?;
+			if cover_type != Some(FRONT_COVER) {
+				cover_type = Some(picture_type);
+				info.cover = data.to_vec();
+			}
+		} else if block_type == FLAC_VORBIS_COMMENT_BLOCK {
+			for comment in vorbis_comments(block) {
+				let Some((name, value)) = comment.split_once('=') else { continue; };
+				match name.to_uppercase().as_str() {
+					"TITLE" => info.title = Some(value.to_string()),
+					"ARTIST" => info.artist = Some(value.to_string()),
+					"ALBUM" => info.album = Some(value.to_string()),
+					_ => {},
+				}
+			}
+		}
+		if is_last {
+			break;
+		}
+	}
+	Ok(info)
+}
+
+/// The NAME=value strings in a Vorbis comment block.  Unlike the rest of FLAC, these are little-endian.
+fn vorbis_comments(block: &[u8]) -> Vec<String> {
+	let le_u32 = |at: usize| block.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+	let mut comments = vec![];
+	let Some(vendor_len) = le_u32(0) else { return comments; };
+	let mut at = 4 + vendor_len;
+	let count = le_u32(at).unwrap_or(0);
+	at += 4;
+	for _ in 0..count {
+		let Some(len) = le_u32(at) else { break; };
+		let Some(text) = block.get(at + 4..at + 4 + len) else { break; };
+		comments.push(String::from_utf8_lossy(text).to_string());
+		at += 4 + len;
+	}
+	comments
+}
+
+/// Text in one of ID3's four encodings, up to the first terminator.
+fn decode_id3_text(encoding: u8, bytes: &[u8]) -> Option<String> {
+	let text = match encoding {
+		0 => bytes.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect(),
+		1 | 2 => {
+			let mut units: Vec<u16> = bytes.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+			// Encoding 1 starts with a byte order mark.  Encoding 2 is always big-endian.
+			if encoding == 1 && units.first() == Some(&0xfffe) {
+				units = units.into_iter().map(u16::swap_bytes).collect();
+			}
+			let units: Vec<u16> = units.into_iter().skip_while(|&u| u == 0xfeff).take_while(|&u| u != 0).collect();
+			String::from_utf16_lossy(&units)
+		},
+		_ => String::from_utf8_lossy(bytes.split(|&b| b == 0).next().unwrap_or(&[])).to_string(),
+	};
+	let text = text.trim().to_string();
+	if text.is_empty() { None } else { Some(text) }
+}
+
+/// Where the data after a terminated string starts.  UTF-16 strings end in two zero bytes.
+fn text_terminator_end(encoding: u8, bytes: &[u8]) -> usize {
+	if encoding == 1 || encoding == 2 {
+		(0..bytes.len() / 2).find(|&i| bytes[i * 2] == 0 && bytes[i * 2 + 1] == 0).map(|i| i * 2 + 2).unwrap_or(bytes.len())
+	} else {
+		bytes.iter().position(|&b| b == 0).map(|i| i + 1).unwrap_or(bytes.len())
+	}
+}
+
+/// Drop the zero bytes inserted after every 0xFF.
+fn resynchronise(bytes: &[u8]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(bytes.len());
+	for (i, &b) in bytes.iter().enumerate() {
+		if !(b == 0 && i > 0 && bytes[i - 1] == 0xff) {
+			out.push(b);
+		}
+	}
+	out
+}
+
+/// ID3 sizes use seven bits of each byte.
+fn syncsafe(bytes: &[u8]) -> usize {
+	bytes.iter().fold(0, |size, &b| (size << 7) | (b & 0x7f) as usize)
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+	u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::album_art::*;
+
+	fn id3_frame(id: &str, data: &[u8]) -> Vec<u8> {
+		let mut frame = id.as_bytes().to_vec();
+		frame.extend((data.len() as u32).to_be_bytes());
+		frame.extend([0, 0]);
+		frame.extend(data);
+		frame
+	}
+
+	#[test]
+	fn test_id3() {
+		let mut frames = id3_frame("TIT2", b"\x03Blue Monday");
+		frames.extend(id3_frame("TALB", b"\x01\xff\xfeP\x00o\x00w\x00e\x00r\x00\x00\x00"));
+		frames.extend(id3_frame("APIC", b"\x00image/png\x00\x04back\x00BACK"));
+		frames.extend(id3_frame("APIC", b"\x00image/png\x00\x03\x00FRONT"));
+		frames.extend(id3_frame("APIC", b"\x00image/png\x00\x05\x00LEAFLET"));
+		let mut file = b"ID3\x03\x00\x00".to_vec();
+		file.extend([0, 0, (frames.len() >> 7) as u8, (frames.len() & 0x7f) as u8]);
+		file.extend(frames);
+		file.extend([0xff, 0xfb, 0x90, 0x00]); // The start of the audio.
+
+		assert!(is_audio_file(&file));
+		let info = read_audio_info(&file).unwrap();
+		assert_eq!(info.cover, b"FRONT");
+		assert_eq!(info.title.as_deref(), Some("Blue Monday"));
+		assert_eq!(info.album.as_deref(), Some("Power"));
+		assert_eq!(info.artist, None);
+		assert_eq!(info.to_tags().len(), 2);
+
+		let no_cover = b"ID3\x03\x00\x00\x00\x00\x00\x00";
+		assert!(read_audio_info(no_cover).is_err());
+		assert!(!is_audio_file(b"\x89PNG"));
+	}
+
+	#[test]
+	fn test_flac() {
+		let mut comments = 6u32.to_le_bytes().to_vec();
+		comments.extend(b"vendor");
+		comments.extend(2u32.to_le_bytes());
+		for comment in [&b"ARTIST=New Order"[..], b"album=Substance"] {
+			comments.extend((comment.len() as u32).to_le_bytes());
+			comments.extend(comment);
+		}
+		let mut picture = 3u32.to_be_bytes().to_vec();
+		picture.extend(10u32.to_be_bytes());
+		picture.extend(b"image/jpeg");
+		picture.extend(5u32.to_be_bytes());
+		picture.extend(b"cover");
+		picture.extend([0; 16]);
+		picture.extend(5u32.to_be_bytes());
+		picture.extend(b"COVER");
+
+		let mut file = b"fLaC".to_vec();
+		for (block_type, block) in [(0u8, vec![0; 34]), (FLAC_VORBIS_COMMENT_BLOCK, comments), (0x80 | FLAC_PICTURE_BLOCK, picture)] {
+			file.push(block_type);
+			file.extend(&(block.len() as u32).to_be_bytes()[1..]);
+			file.extend(block);
+		}
+
+		let info = read_audio_info(&file).unwrap();
+		assert_eq!(info.cover, b"COVER");
+		assert_eq!(info.artist.as_deref(), Some("New Order"));
+		assert_eq!(info.album.as_deref(), Some("Substance"));
+		assert!(read_audio_info(&file[..file.len() - 3]).is_err());
+	}
+}
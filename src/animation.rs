@@ -0,0 +1,193 @@
+// Animated GIFs, PNGs, and WebPs are indexed by their middle frame, since the first is often blank or a title card.
+// They're tagged animated:true, with their frame count and length, so they can be told apart from stills.
+
+use anyhow::Result;
+use image::{AnimationDecoder, DynamicImage, ImageFormat};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::time::Duration;
+
+use crate::image_hashes::{dequantize_embedding, quantize_embedding};
+
+pub const ANIMATED_TAG: &str = "animated";
+pub const SAMPLE_FRAMES: usize = 5; // How many frames are hashed together when several are wanted, spread evenly through the animation.
+const MAX_FRAMES: usize = 5000; // Frames past this aren't counted or sampled.  Each has to be decoded to get to the next.
+const MAX_KEPT_FRAMES: usize = 64; // Frames held while reading an animation, to sample from once its length is known.
+const PNG_SIGNATURE_SIZE: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct AnimationInfo {
+	pub frames: Vec<DynamicImage>, // The middle frame first, then any others that were sampled.
+	pub frame_count: usize,
+	pub duration: Duration, // One time through.
+}
+
+impl AnimationInfo {
+	pub fn to_tags(&self) -> HashMap<String, String> {
+		let mut tags = HashMap::new();
+		tags.insert(ANIMATED_TAG.to_string(), "true".to_string());
+		tags.insert("Frame Count".to_string(), self.frame_count.to_string());
+		tags.insert("Loop Length".to_string(), format!("{:.2}s", self.duration.as_secs_f64()));
+		tags
+	}
+}
+
+/// The frames of an animated GIF, PNG, or WebP, or None for stills and other files.
+/// With sample_frames over one, that many frames are kept from across the animation.  Otherwise only the middle one is.
+pub fn read_animation(bytes: &[u8], sample_frames: usize) -> Result<Option<AnimationInfo>> {
+	let frames = match image::guess_format(bytes) {
+		Ok(ImageFormat::Gif) => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+		Ok(ImageFormat::Png) => {
+			// Most PNGs are stills, so they're ruled out by their chunks before a decoder is made.
+			if apng_frame_count(bytes).unwrap_or(0) < 2 {
+				return Ok(None);
+			}
+			let decoder = PngDecoder::new(Cursor::new(bytes))?;
+			if !decoder.is_apng() {
+				return Ok(None);
+			}
+			decoder.apng().into_frames()
+		},
+		Ok(ImageFormat::WebP) => {
+			let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+			if !decoder.has_animation() {
+				return Ok(None);
+			}
+			decoder.into_frames()
+		},
+		_ => return Ok(None),
+	};
+
+	// Each frame has to be decoded to get to the next, so they're counted and kept in the same pass.
+	// Every stride-th frame is kept, and the stride doubles when too many are, so long animations don't all stay in memory.
+	let mut kept: Vec<(usize, DynamicImage)> = vec![];
+	let mut stride = 1;
+	let mut frame_count = 0;
+	let mut duration = Duration::ZERO;
+	for frame in frames.take(MAX_FRAMES) {
+		let frame = frame?;
+		let (numerator, denominator) = frame.delay().numer_denom_ms();
+		duration += Duration::from_secs_f64(numerator as f64 / denominator.max(1) as f64 / 1000.0);
+		if frame_count % stride == 0 {
+			kept.push((frame_count, DynamicImage::ImageRgba8(frame.into_buffer())));
+			if kept.len() > MAX_KEPT_FRAMES {
+				stride *= 2;
+				kept.retain(|(idx, _)| idx % stride == 0);
+			}
+		}
+		frame_count += 1;
+	}
+	if frame_count < 2 {
+		return Ok(None);
+	}
+
+	// The kept frames nearest the ones we want.  They're the ones we want unless there were more than MAX_KEPT_FRAMES.
+	let nearest = |target: usize| kept.iter().map(|(idx, _)| *idx).min_by_key(|idx| idx.abs_diff(target)).unwrap_or(0);
+	let wanted: Vec<usize> = sample_indices(frame_count, sample_frames).into_iter().map(nearest).collect();
+	let middle = nearest(frame_count / 2);
+	let mut sampled: Vec<(usize, DynamicImage)> = kept.into_iter().filter(|(idx, _)| wanted.contains(idx)).collect();
+	// The middle frame goes first, since it stands in for the animation.
+	sampled.sort_by_key(|(idx, _)| *idx != middle);
+	Ok(Some(AnimationInfo { frames: sampled.into_iter().map(|(_, frame)| frame).collect(), frame_count, duration }))
+}
+
+/// The frame count from a PNG's animation control chunk, or None if it doesn't have one before its image data.
+fn apng_frame_count(bytes: &[u8]) -> Option<u32> {
+	let mut chunks = bytes.get(PNG_SIGNATURE_SIZE..)?;
+	// Each chunk is its length, its type, its data, and a CRC.
+	while chunks.len() >= 8 {
+		let length = u32::from_be_bytes(chunks[..4].try_into().ok()?) as usize;
+		let data = chunks.get(8..8usize.checked_add(length)?)?;
+		match &chunks[4..8] {
+			b"acTL" => return Some(u32::from_be_bytes(data.get(..4)?.try_into().ok()?)),
+			b"IDAT" => return None,
+			_ => chunks = chunks.get(12 + length..)?,
+		}
+	}
+	None
+}
+
+/// The average of some embeddings, so an animation can be found by any of its frames.
+pub fn mean_embedding(embeddings: &[Vec<u8>]) -> Vec<u8> {
+	let dequantized: Vec<Vec<f32>> = embeddings.iter().map(|e| dequantize_embedding(e)).collect();
+	let length = dequantized.iter().map(Vec::len).min().unwrap_or(0);
+	let mean: Vec<f32> = (0..length).map(|idx| dequantized.iter().map(|e| e[idx]).sum::<f32>() / dequantized.len() as f32).collect();
+	quantize_embedding(&mean)
+}
+
+/// sample_frames evenly spread frames, and always the middle one.
+fn sample_indices(frame_count: usize, sample_frames: usize) -> Vec<usize> {
+	let mut indices: Vec<usize> = (0..sample_frames.min(frame_count)).map(|k| (2 * k + 1) * frame_count / (2 * sample_frames.min(frame_count))).collect();
+	indices.push(frame_count / 2);
+	indices.sort();
+	indices.dedup();
+	indices
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::animation::*;
+	use image::{Delay, Frame, Rgba, RgbaImage};
+	use image::codecs::gif::GifEncoder;
+
+	fn make_test_gif(colors: &[[u8; 4]]) -> Vec<u8> {
+		let mut gif = vec![];
+		{
+			let mut encoder = GifEncoder::new(&mut gif);
+			let frames = colors.iter().map(|&color| Frame::from_parts(RgbaImage::from_pixel(4, 4, Rgba(color)), 0, 0, Delay::from_numer_denom_ms(250, 1)));
+			encoder.encode_frames(frames).unwrap();
+		}
+		gif
+	}
+
+	#[test]
+	fn test_read_animation() {
+		let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255], [255, 255, 255, 255]];
+		let gif = make_test_gif(&colors);
+		let info = read_animation(&gif, 1).unwrap().unwrap();
+		assert_eq!(info.frame_count, 4);
+		assert_eq!(info.duration, Duration::from_secs(1));
+		assert_eq!(info.frames.len(), 1);
+		assert_eq!(info.frames[0].to_rgba8().get_pixel(1, 1).0, colors[2]);
+		let tags = info.to_tags();
+		assert_eq!(tags.get(ANIMATED_TAG).map(String::as_str), Some("true"));
+		assert_eq!(tags.get("Loop Length").map(String::as_str), Some("1.00s"));
+
+		// Sampled frames still start with the middle one.
+		let sampled = read_animation(&gif, SAMPLE_FRAMES).unwrap().unwrap();
+		assert_eq!(sampled.frames.len(), 4);
+		assert_eq!(sampled.frames[0].to_rgba8().get_pixel(1, 1).0, colors[2]);
+
+		// Stills aren't animations, even in formats that can be.
+		assert!(read_animation(&make_test_gif(&colors[..1]), 1).unwrap().is_none());
+		let mut png = vec![];
+		DynamicImage::new_rgb8(2, 2).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+		assert!(read_animation(&png, 1).unwrap().is_none());
+		assert!(read_animation(b"not an image", 1).unwrap().is_none());
+		assert_eq!(apng_frame_count(&png), None);
+		let mut apng = png[..PNG_SIGNATURE_SIZE + 25].to_vec(); // The signature and IHDR.
+		apng.extend_from_slice(&[0, 0, 0, 8]);
+		apng.extend_from_slice(b"acTL");
+		apng.extend_from_slice(&[0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(apng_frame_count(&apng), Some(3));
+
+		// Long animations are sampled from the frames that could be kept.
+		let shades: Vec<[u8; 4]> = (0..200).map(|shade| [shade as u8, 0, 0, 255]).collect();
+		let long = read_animation(&make_test_gif(&shades), SAMPLE_FRAMES).unwrap().unwrap();
+		assert_eq!(long.frame_count, 200);
+		assert_eq!(long.frames.len(), SAMPLE_FRAMES);
+		assert!(long.frames[0].to_rgba8().get_pixel(1, 1).0[0].abs_diff(100) <= 2);
+	}
+
+	#[test]
+	fn test_sample_indices() {
+		assert_eq!(sample_indices(100, 1), vec![50]);
+		assert_eq!(sample_indices(100, 5), vec![10, 30, 50, 70, 90]);
+		assert_eq!(sample_indices(4, 5), vec![0, 1, 2, 3]);
+		assert_eq!(sample_indices(10, 2), vec![2, 5, 7]);
+		assert_eq!(mean_embedding(&[vec![0, 254], vec![254, 254]]), vec![127, 254]);
+	}
+}
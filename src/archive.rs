@@ -0,0 +1,649 @@
+// Reading files out of zip, tar, and 7z containers.
+// Only what's needed to pull images out of zips: stored and deflated entries with traditional encryption, no zip64 or AES.  7z is left to sevenz-rust.
+// Entries are read one at a time from wherever the archive is, so big archives don't have to fit in memory.
+
+use anyhow::{anyhow, Result};
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use sevenz_rust::{Password, SevenZReader};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const MAX_COMMENT_SIZE: usize = 0xFFFF;
+
+/// Put between a container's path and the name of a file inside it to make the inner file's indexed path.
+pub const ENTRY_SEPARATOR: &'static str = "!/";
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+const AES_ENCRYPTED: u16 = 99;
+const ENCRYPTED_FLAG: u16 = 1;
+const DATA_DESCRIPTOR_FLAG: u16 = 1 << 3; // The CRC is after the data, so the encryption header is checked against the modification time instead.
+const ENCRYPTION_HEADER_SIZE: u64 = 12;
+
+/// Files inside archives bigger than this are skipped, whatever the archive says they'll inflate to.
+pub const MAX_ENTRY_SIZE: u64 = 512 * 1024 * 1024;
+
+const TAR_BLOCK_SIZE: usize = 512;
+const MAX_PREALLOCATION: u64 = 16 * 1024 * 1024;
+
+/// The archives images are indexed out of.  They're told apart by extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveFormat {
+	Zip,
+	Tar,
+	TarGz,
+	SevenZip,
+}
+
+impl ArchiveFormat {
+	pub fn from_filename(filename: &str) -> Option<ArchiveFormat> {
+		let filename = filename.to_lowercase();
+		if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
+			return Some(ArchiveFormat::TarGz);
+		}
+		match filename.rsplit_once('.')?.1 {
+			"zip" => Some(ArchiveFormat::Zip),
+			"tar" => Some(ArchiveFormat::Tar),
+			"7z" => Some(ArchiveFormat::SevenZip),
+			_ => None,
+		}
+	}
+}
+
+/// A file in a zip, from zip_entries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZipEntry {
+	pub name: String,
+	pub compressed_size: u64,
+	pub uncompressed_size: u64,
+	compression: u16,
+	flags: u16,
+	modified_time: u16,
+	crc: u32,
+	local_header_offset: u64,
+}
+
+impl ZipEntry {
+	pub fn is_encrypted(&self) -> bool {
+		self.flags & ENCRYPTED_FLAG != 0
+	}
+}
+
+pub fn is_zip(bytes: &[u8]) -> bool {
+	bytes.starts_with(&LOCAL_HEADER_SIGNATURE.to_le_bytes())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16> {
+	let le_bytes = bytes.get(offset..offset+2).ok_or_else(|| anyhow!("The zip is cut off."))?;
+	Ok(u16::from_le_bytes([le_bytes[0], le_bytes[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32> {
+	let le_bytes = bytes.get(offset..offset+4).ok_or_else(|| anyhow!("The zip is cut off."))?;
+	Ok(u32::from_le_bytes([le_bytes[0], le_bytes[1], le_bytes[2], le_bytes[3]]))
+}
+
+/// Every file in the zip, in the order of the central directory.  Directories are left out.
+pub fn zip_entries(bytes: &[u8]) -> Result<Vec<ZipEntry>> {
+	read_zip_entries(&mut Cursor::new(bytes))
+}
+
+/// Like zip_entries, but only the end of the zip and its directory are read.
+pub fn read_zip_entries(zip: &mut (impl Read + Seek)) -> Result<Vec<ZipEntry>> {
+	// The end record is last, but can be followed by a comment.
+	let zip_size = zip.seek(SeekFrom::End(0))?;
+	let tail_size = zip_size.min((END_OF_CENTRAL_DIRECTORY_SIZE + MAX_COMMENT_SIZE) as u64);
+	zip.seek(SeekFrom::Start(zip_size - tail_size))?;
+	let mut tail = vec![0u8; tail_size as usize];
+	zip.read_exact(&mut tail)?;
+	let end_record = (0..=tail.len().saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE)).rev()
+		.find(|&idx| read_u32(&tail, idx).ok() == Some(END_OF_CENTRAL_DIRECTORY_SIGNATURE))
+		.ok_or_else(|| anyhow!("Not a zip file."))?;
+	let entry_count = read_u16(&tail, end_record + 10)? as usize;
+	let directory_size = read_u32(&tail, end_record + 12)? as u64;
+	zip.seek(SeekFrom::Start(read_u32(&tail, end_record + 16)? as u64))?;
+	let mut directory = vec![];
+	zip.take(directory_size).read_to_end(&mut directory)?;
+
+	let mut entries = Vec::with_capacity(entry_count);
+	let mut offset = 0;
+	for _ in 0..entry_count {
+		if read_u32(&directory, offset)? != CENTRAL_HEADER_SIGNATURE {
+			return Err(anyhow!("The zip's directory is damaged."));
+		}
+		let name_length = read_u16(&directory, offset + 28)? as usize;
+		let extra_length = read_u16(&directory, offset + 30)? as usize;
+		let comment_length = read_u16(&directory, offset + 32)? as usize;
+		let name = directory.get(offset + 46..offset + 46 + name_length).ok_or_else(|| anyhow!("The zip is cut off."))?;
+		let entry = ZipEntry {
+			name: String::from_utf8_lossy(name).to_string(),
+			flags: read_u16(&directory, offset + 8)?,
+			compression: read_u16(&directory, offset + 10)?,
+			modified_time: read_u16(&directory, offset + 12)?,
+			crc: read_u32(&directory, offset + 16)?,
+			compressed_size: read_u32(&directory, offset + 20)? as u64,
+			uncompressed_size: read_u32(&directory, offset + 24)? as u64,
+			local_header_offset: read_u32(&directory, offset + 42)? as u64,
+		};
+		if !entry.name.ends_with('/') {
+			entries.push(entry);
+		}
+		offset += 46 + name_length + extra_length + comment_length;
+	}
+	Ok(entries)
+}
+
+/// The uncompressed contents of an entry.
+pub fn read_zip_entry(bytes: &[u8], entry: &ZipEntry) -> Result<Vec<u8>> {
+	read_zip_entry_from(&mut Cursor::new(bytes), entry, None)
+}
+
+/// Like read_zip_entry, but only the entry is read from the zip.  Encrypted entries need the password.
+pub fn read_zip_entry_from(zip: &mut (impl Read + Seek), entry: &ZipEntry, password: Option<&str>) -> Result<Vec<u8>> {
+	if entry.uncompressed_size > MAX_ENTRY_SIZE {
+		return Err(anyhow!("{} is too big to index from inside the zip.", entry.name));
+	}
+	let mut header = [0u8; 30];
+	zip.seek(SeekFrom::Start(entry.local_header_offset))?;
+	zip.read_exact(&mut header).map_err(|_| anyhow!("The zip is cut off."))?;
+	if read_u32(&header, 0)? != LOCAL_HEADER_SIGNATURE {
+		return Err(anyhow!("The zip entry {} is damaged.", entry.name));
+	}
+	// The local header can have a different extra field than the central one.
+	zip.seek(SeekFrom::Current(read_u16(&header, 26)? as i64 + read_u16(&header, 28)? as i64))?;
+
+	let mut data: Box<dyn Read + '_> = Box::new(zip.take(entry.compressed_size));
+	let mut stored_size = entry.compressed_size;
+	if entry.is_encrypted() {
+		if entry.compression == AES_ENCRYPTED {
+			return Err(anyhow!("Unable to read {}: AES encrypted zips aren't supported.", entry.name));
+		}
+		let password = password.ok_or_else(|| anyhow!("{} is encrypted, and no password was given for it.", entry.name))?;
+		let check_byte = if entry.flags & DATA_DESCRIPTOR_FLAG != 0 { (entry.modified_time >> 8) as u8 } else { (entry.crc >> 24) as u8 };
+		data = Box::new(ZipCryptoReader::new(data, password, check_byte).map_err(|e| anyhow!("{}: {}", entry.name, e))?);
+		stored_size = stored_size.saturating_sub(ENCRYPTION_HEADER_SIZE);
+	}
+	// Reading one past the limit catches entries that inflate to more than they said they would.
+	// The sizes come from the zip, so they're only trusted as far as what was actually read.
+	let mut contents = Vec::with_capacity(entry.compressed_size.min(MAX_PREALLOCATION) as usize);
+	match entry.compression {
+		STORED => data.take(MAX_ENTRY_SIZE + 1).read_to_end(&mut contents)?,
+		DEFLATED => DeflateDecoder::new(data).take(MAX_ENTRY_SIZE + 1).read_to_end(&mut contents)?,
+		other => return Err(anyhow!("Unable to read {}: compression method {} isn't supported.", entry.name, other)),
+	};
+	if contents.len() as u64 > MAX_ENTRY_SIZE {
+		return Err(anyhow!("{} is too big to index from inside the zip.", entry.name));
+	}
+	if entry.compression == STORED && (contents.len() as u64) < stored_size {
+		return Err(anyhow!("The zip is cut off."));
+	}
+	// One in 256 wrong passwords gets past the check byte.
+	if entry.is_encrypted() && crc32(&contents) != entry.crc {
+		return Err(anyhow!("The password for {} is wrong, or the entry is damaged.", entry.name));
+	}
+	Ok(contents)
+}
+
+/// The contents of the named entry, or None if there's no such entry.
+pub fn read_zip_file(bytes: &[u8], name: &str) -> Result<Option<Vec<u8>>> {
+	match zip_entries(bytes)?.into_iter().find(|e| e.name == name) {
+		Some(entry) => Ok(Some(read_zip_entry(bytes, &entry)?)),
+		None => Ok(None),
+	}
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+	let mut table = [0u32; 256];
+	let mut idx = 0;
+	while idx < 256 {
+		let mut crc = idx as u32;
+		let mut bit = 0;
+		while bit < 8 {
+			crc = if crc & 1 != 0 { 0xEDB88320 ^ (crc >> 1) } else { crc >> 1 };
+			bit += 1;
+		}
+		table[idx] = crc;
+		idx += 1;
+	}
+	table
+}
+
+fn crc32_byte(crc: u32, byte: u8) -> u32 {
+	CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+	!bytes.iter().fold(!0u32, |crc, &b| crc32_byte(crc, b))
+}
+
+/// The keys of traditional PKWARE encryption, which is still what most tools write when they're given a password.
+struct ZipCrypto {
+	keys: [u32; 3],
+}
+
+impl ZipCrypto {
+	fn new(password: &str) -> ZipCrypto {
+		let mut cipher = ZipCrypto { keys: [0x12345678, 0x23456789, 0x34567890] };
+		for &b in password.as_bytes() {
+			cipher.update(b);
+		}
+		cipher
+	}
+
+	fn update(&mut self, plain: u8) {
+		self.keys[0] = crc32_byte(self.keys[0], plain);
+		self.keys[1] = self.keys[1].wrapping_add(self.keys[0] & 0xFF).wrapping_mul(134775813).wrapping_add(1);
+		self.keys[2] = crc32_byte(self.keys[2], (self.keys[1] >> 24) as u8);
+	}
+
+	fn key_byte(&self) -> u8 {
+		let temp = (self.keys[2] | 2) as u16;
+		(temp.wrapping_mul(temp ^ 1) >> 8) as u8
+	}
+
+	fn decrypt(&mut self, byte: u8) -> u8 {
+		let plain = byte ^ self.key_byte();
+		self.update(plain);
+		plain
+	}
+}
+
+struct ZipCryptoReader<R: Read> {
+	inner: R,
+	cipher: ZipCrypto,
+}
+
+impl<R: Read> ZipCryptoReader<R> {
+	/// Reads past the encryption header, which ends with check_byte if the password is right.
+	fn new(mut inner: R, password: &str, check_byte: u8) -> Result<Self> {
+		let mut cipher = ZipCrypto::new(password);
+		let mut header = [0u8; ENCRYPTION_HEADER_SIZE as usize];
+		inner.read_exact(&mut header).map_err(|_| anyhow!("The zip is cut off."))?;
+		let decrypted: Vec<u8> = header.iter().map(|&b| cipher.decrypt(b)).collect();
+		if decrypted[decrypted.len() - 1] != check_byte {
+			return Err(anyhow!("The password is wrong."));
+		}
+		Ok(ZipCryptoReader { inner, cipher })
+	}
+}
+
+impl<R: Read> Read for ZipCryptoReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let read = self.inner.read(buf)?;
+		for b in &mut buf[..read] {
+			*b = self.cipher.decrypt(*b);
+		}
+		Ok(read)
+	}
+}
+
+/// Give visit the name and contents of each file in the archive that wanted accepts, in the order they're stored, until it returns false.
+/// Files that can't be read, like ones over MAX_ENTRY_SIZE, are given to visit as errors.  An archive that can't be read at all is an error.
+/// The password is for encrypted zips and 7zs.
+pub fn read_archive_files(format: ArchiveFormat, mut archive: impl Read + Seek, password: Option<&str>, wanted: impl Fn(&str) -> bool, mut visit: impl FnMut(&str, Result<Vec<u8>>) -> bool) -> Result<()> {
+	match format {
+		ArchiveFormat::Zip => {
+			for entry in read_zip_entries(&mut archive)?.iter().filter(|e| wanted(&e.name)) {
+				if !visit(&entry.name, read_zip_entry_from(&mut archive, entry, password)) {
+					break;
+				}
+			}
+			Ok(())
+		},
+		ArchiveFormat::Tar => read_tar_files(archive, wanted, visit),
+		ArchiveFormat::TarGz => read_tar_files(MultiGzDecoder::new(archive), wanted, visit),
+		ArchiveFormat::SevenZip => {
+			let archive_size = archive.seek(SeekFrom::End(0))?;
+			archive.seek(SeekFrom::Start(0))?;
+			let mut reader = SevenZReader::new(archive, archive_size, password.map(Password::from).unwrap_or_else(Password::empty))?;
+			reader.for_each_entries(|entry, contents| {
+				// Windows archivers can store backslashes.
+				let name = entry.name().replace('\\', "/");
+				if entry.is_directory() || !wanted(&name) || entry.size() > MAX_ENTRY_SIZE {
+					// Solid archives are one stream, so the files that are skipped still have to be read past.
+					std::io::copy(contents, &mut std::io::sink())?;
+					if !entry.is_directory() && wanted(&name) {
+						return Ok(visit(&name, Err(anyhow!("{} is too big to index from inside the 7z.", name))));
+					}
+					return Ok(true);
+				}
+				let mut data = vec![];
+				let read = contents.read_to_end(&mut data).map(|_| data).map_err(anyhow::Error::from);
+				Ok(visit(&name, read))
+			})?;
+			Ok(())
+		},
+	}
+}
+
+/// Tars are read front to back, so a gzipped one doesn't have to be inflated all at once.
+fn read_tar_files(mut tar: impl Read, wanted: impl Fn(&str) -> bool, mut visit: impl FnMut(&str, Result<Vec<u8>>) -> bool) -> Result<()> {
+	let mut header = [0u8; TAR_BLOCK_SIZE];
+	let mut long_name: Option<Result<String>> = None; // From the GNU or pax header before a file with a name too long for its own header.
+	loop {
+		// Tars end with two empty blocks, but not every tool writes them.
+		let mut filled = 0;
+		while filled < TAR_BLOCK_SIZE {
+			match tar.read(&mut header[filled..])? {
+				0 => break,
+				read => filled += read,
+			}
+		}
+		if filled == 0 || header.iter().all(|&b| b == 0) {
+			return Ok(());
+		}
+		if filled < TAR_BLOCK_SIZE {
+			return Err(anyhow!("The tar is cut off."));
+		}
+		// The checksum is summed with its own field as spaces.
+		let checksum: u64 = header.iter().enumerate().map(|(idx, &b)| if (148..156).contains(&idx) { b' ' as u64 } else { b as u64 }).sum();
+		if tar_number(&header[148..156])? != checksum {
+			return Err(anyhow!("Not a tar file, or its header is damaged."));
+		}
+
+		let size = tar_number(&header[124..136])?;
+		// Damaged headers can give sizes so big that padding them to a whole block overflows.
+		let padded_size = size.div_ceil(TAR_BLOCK_SIZE as u64).checked_mul(TAR_BLOCK_SIZE as u64).ok_or_else(|| anyhow!("Not a tar file, or its header is damaged."))?;
+		let mut data = (&mut tar).take(padded_size);
+		let read_contents = |data: &mut dyn Read| -> Result<Vec<u8>> {
+			if size > MAX_ENTRY_SIZE {
+				return Err(anyhow!("It's too big to index from inside the tar."));
+			}
+			let mut contents = vec![];
+			data.take(size).read_to_end(&mut contents)?;
+			if contents.len() as u64 != size {
+				return Err(anyhow!("The tar is cut off."));
+			}
+			Ok(contents)
+		};
+		// A long name that couldn't be read fails the file it's for, under the name in the file's own header.
+		let (name, mut name_error) = match long_name.take() {
+			Some(Ok(name)) => (name, None),
+			Some(Err(e)) => (tar_name(&header), Some(e)),
+			None => (tar_name(&header), None),
+		};
+		let name = name.trim_start_matches("./"); // Tars of the current folder put this before everything.
+		match header[156] {
+			// GNU long names.
+			b'L' => long_name = Some(read_contents(&mut data).map(|name| tar_string(&name))),
+			// Pax headers are lines of "length key=value".
+			b'x' => {
+				long_name = read_contents(&mut data).map(|records| String::from_utf8_lossy(&records).lines()
+					.filter_map(|record| record.split_once(' ')?.1.strip_prefix("path="))
+					.next_back()
+					.map(|path| path.to_string())
+				).transpose();
+			},
+			// Regular files.  Everything else, like folders and links, has nothing to index.
+			0 | b'0' | b'7' if (name_error.is_some() || wanted(name)) && !visit(name, name_error.take().map_or_else(|| read_contents(&mut data), Err)) => return Ok(()),
+			_ => {},
+		}
+		std::io::copy(&mut data, &mut std::io::sink())?;
+	}
+}
+
+/// A name field, which is null-terminated unless it fills the field.
+fn tar_string(field: &[u8]) -> String {
+	let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+	String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+/// The file's name from its own header.  Ustar headers have a prefix for names over 100 bytes.
+fn tar_name(header: &[u8; TAR_BLOCK_SIZE]) -> String {
+	let name = tar_string(&header[0..100]);
+	let prefix = tar_string(&header[345..500]);
+	if header[257..262] == *b"ustar" && !prefix.is_empty() {
+		format!("{}/{}", prefix, name)
+	} else {
+		name
+	}
+}
+
+/// Numbers are octal text, padded with spaces or nulls.  GNU tar writes ones too big for that in base 256, marked by the high bit.
+fn tar_number(field: &[u8]) -> Result<u64> {
+	if field[0] & 0x80 != 0 {
+		return Ok(field[1..].iter().fold((field[0] & 0x7F) as u64, |n, &b| (n << 8) | b as u64));
+	}
+	let digits = String::from_utf8_lossy(field);
+	let digits = digits.trim_matches(|c| c == ' ' || c == '\0');
+	if digits.is_empty() {
+		return Ok(0);
+	}
+	u64::from_str_radix(digits, 8).map_err(|_| anyhow!("Not a tar file, or its header is damaged."))
+}
+
+/// The path a file inside a container is indexed under, like /books/Dune.epub!/OEBPS/images/map.jpg
+pub fn entry_path(container_path: &str, entry: &str) -> String {
+	format!("{}{}{}", container_path, ENTRY_SEPARATOR, entry)
+}
+
+/// The file on disk that a path from entry_path is in.  Paths of plain files are their own container.
+pub fn container_path(path: &str) -> &str {
+	path.split(ENTRY_SEPARATOR).next().unwrap_or(path)
+}
+
+/// Build a zip of stored entries, for tests elsewhere.
+#[cfg(test)]
+pub(crate) fn make_test_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+	let mut zip = vec![];
+	let mut central = vec![];
+	for (name, contents) in files {
+		let offset = zip.len() as u32;
+		let header = |signature: u32| {
+			let mut header = signature.to_le_bytes().to_vec();
+			header.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]); // Versions, flags, method, time, date, and crc.
+			header.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+			header.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+			header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+			header
+		};
+		zip.extend(header(LOCAL_HEADER_SIGNATURE));
+		zip.extend_from_slice(&[0, 0]);
+		zip.extend_from_slice(name.as_bytes());
+		zip.extend_from_slice(contents);
+
+		let mut entry = header(CENTRAL_HEADER_SIGNATURE);
+		entry.insert(4, 0); // The central header has an extra "version made by" field.
+		entry.insert(4, 20);
+		entry.extend_from_slice(&[0; 12]); // Extra and comment lengths, disk, and attributes.
+		entry.extend_from_slice(&offset.to_le_bytes());
+		entry.extend_from_slice(name.as_bytes());
+		central.extend(entry);
+	}
+	let central_offset = zip.len() as u32;
+	zip.extend_from_slice(&central);
+	zip.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+	zip.extend_from_slice(&[0, 0, 0, 0]);
+	zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+	zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+	zip.extend_from_slice(&(central.len() as u32).to_le_bytes());
+	zip.extend_from_slice(&central_offset.to_le_bytes());
+	zip.extend_from_slice(&[0, 0]);
+	zip
+}
+
+#[cfg(test)]
+/// A tar of files, for tests.  Names over 100 bytes get a GNU long name header, and names ending in / are folders.
+pub(crate) fn make_test_tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+	let mut tar = vec![];
+	let mut push = |name: &[u8], kind: u8, contents: &[u8]| {
+		let mut header = [0u8; TAR_BLOCK_SIZE];
+		header[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+		header[100..107].copy_from_slice(b"0000644");
+		header[124..135].copy_from_slice(format!("{:011o}", contents.len()).as_bytes());
+		header[148..156].fill(b' ');
+		header[156] = kind;
+		header[257..265].copy_from_slice(b"ustar\x0000");
+		let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+		header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+		tar.extend_from_slice(&header);
+		tar.extend_from_slice(contents);
+		tar.resize(tar.len().div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE, 0);
+	};
+	for (name, contents) in files {
+		if name.len() > 100 {
+			push(b"././@LongLink", b'L', name.as_bytes());
+		}
+		push(name.as_bytes(), if name.ends_with('/') { b'5' } else { b'0' }, contents);
+	}
+	tar.resize(tar.len() + 2 * TAR_BLOCK_SIZE, 0);
+	tar
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::archive::*;
+	use flate2::write::{DeflateEncoder, GzEncoder};
+	use flate2::Compression;
+	use sevenz_rust::{AesEncoderOptions, SeqReader, SevenZArchiveEntry, SevenZMethod, SevenZWriter, SourceReader};
+	use std::io::Write;
+
+	#[test]
+	fn test_read_zip() {
+		let zip = make_test_zip(&[("mimetype", b"application/x-krita"), ("dir/", b""), ("dir/preview.png", b"not really a png")]);
+		assert!(is_zip(&zip));
+		let entries = zip_entries(&zip).unwrap();
+		assert_eq!(entries.iter().map(|e| e.name.as_str()).collect::<Vec<&str>>(), vec!["mimetype", "dir/preview.png"]);
+		assert_eq!(read_zip_file(&zip, "dir/preview.png").unwrap(), Some(b"not really a png".to_vec()));
+		assert_eq!(read_zip_file(&zip, "missing.png").unwrap(), None);
+
+		// Deflated entries are inflated.
+		let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+		encoder.write_all(&[7u8; 1000]).unwrap();
+		let compressed = encoder.finish().unwrap();
+		let entry = ZipEntry { compression: DEFLATED, compressed_size: compressed.len() as u64, uncompressed_size: 1000, ..entries[0].clone() };
+		let mut deflated_zip = make_test_zip(&[("mimetype", &compressed)]);
+		deflated_zip.truncate(30 + "mimetype".len() + compressed.len());
+		assert_eq!(read_zip_entry(&deflated_zip, &entry).unwrap(), vec![7u8; 1000]);
+
+		assert_eq!(entry_path("/comics/Bone 03.cbz", "page1.jpg"), "/comics/Bone 03.cbz!/page1.jpg");
+		assert_eq!(container_path("/comics/Bone 03.cbz!/page1.jpg"), "/comics/Bone 03.cbz");
+		assert_eq!(container_path("/comics/cover.jpg"), "/comics/cover.jpg");
+		assert!(!is_zip(b"8BPS"));
+		assert!(zip_entries(b"PK\x03\x04 but nothing else").is_err());
+		assert!(read_zip_entry(&zip[..40], &entries[1]).is_err());
+		// Entries that would be too big to hold aren't read.
+		assert!(read_zip_entry(&zip, &ZipEntry { uncompressed_size: MAX_ENTRY_SIZE + 1, ..entries[1].clone() }).is_err());
+	}
+
+	#[test]
+	fn test_read_encrypted_zip() {
+		assert_eq!(crc32(b"123456789"), 0xCBF43926);
+		let contents = b"not really a png, but a secret one";
+		let crc = crc32(contents);
+		// Encrypted the way zip -P does, after a header ending in the top byte of the CRC.
+		let mut cipher = ZipCrypto::new("hunter2");
+		let encrypted: Vec<u8> = [3u8; 11].iter().chain(&[(crc >> 24) as u8]).chain(contents).map(|&b| {
+			let encrypted = b ^ cipher.key_byte();
+			cipher.update(b);
+			encrypted
+		}).collect();
+		let zip = make_test_zip(&[("secret.png", &encrypted)]);
+		let entry = ZipEntry { flags: ENCRYPTED_FLAG, crc, ..zip_entries(&zip).unwrap()[0].clone() };
+		assert_eq!(read_zip_entry_from(&mut Cursor::new(&zip), &entry, Some("hunter2")).unwrap(), contents);
+		assert!(read_zip_entry_from(&mut Cursor::new(&zip), &entry, Some("hunter3")).is_err());
+		assert!(read_zip_entry(&zip, &entry).is_err());
+		assert!(read_zip_entry_from(&mut Cursor::new(&zip), &ZipEntry { compression: AES_ENCRYPTED, ..entry.clone() }, Some("hunter2")).is_err());
+
+		let mut writer = SevenZWriter::new(Cursor::new(vec![])).unwrap();
+		writer.set_content_methods(vec![AesEncoderOptions::new("hunter2".into()).into(), SevenZMethod::LZMA2.into()]);
+		let mut seven_entry = SevenZArchiveEntry::new();
+		seven_entry.name = "secret.png".to_string();
+		seven_entry.has_stream = true;
+		writer.push_archive_entry(seven_entry, Some(&contents[..])).unwrap();
+		let seven = writer.finish().unwrap().into_inner();
+		let read_7z = |password: Option<&str>| -> Result<Vec<u8>> {
+			let mut read = vec![];
+			read_archive_files(ArchiveFormat::SevenZip, Cursor::new(&seven), password, |_| true, |_, contents| {
+				read.push(contents);
+				true
+			})?;
+			read.pop().unwrap_or_else(|| Err(anyhow!("Nothing was read.")))
+		};
+		assert_eq!(read_7z(Some("hunter2")).unwrap(), contents);
+		assert!(read_7z(None).is_err());
+		assert!(read_7z(Some("hunter3")).is_err());
+	}
+
+	#[test]
+	fn test_read_archives() {
+		// Files that can't be read fail the whole read here, to check they're reported.
+		let read = |format: ArchiveFormat, bytes: &[u8]| -> Result<Vec<(String, Vec<u8>)>> {
+			let mut files = vec![];
+			read_archive_files(format, Cursor::new(bytes), None, |name| name.ends_with(".png"), |name, contents| {
+				files.push(contents.map(|contents| (name.to_string(), contents)));
+				true
+			})?;
+			files.into_iter().collect()
+		};
+		let long_name = format!("{}/deep.png", "nested".repeat(20));
+		let files: [(&str, &[u8]); 4] = [("notes.txt", b"not an image"), ("photos/", b""), ("photos/a.png", b"not really a png"), (&long_name, &[7u8; 600])];
+		let dotted_files = files.map(|(name, contents)| (if name.starts_with("photos") { format!("./{}", name) } else { name.to_string() }, contents));
+		let expected = vec![("photos/a.png".to_string(), b"not really a png".to_vec()), (long_name.clone(), vec![7u8; 600])];
+
+		assert_eq!(read(ArchiveFormat::Zip, &make_test_zip(&files)).unwrap(), expected);
+		let tar = make_test_tar(&files);
+		assert_eq!(read(ArchiveFormat::Tar, &tar).unwrap(), expected);
+		let mut encoder = GzEncoder::new(vec![], Compression::default());
+		encoder.write_all(&tar).unwrap();
+		assert_eq!(read(ArchiveFormat::TarGz, &encoder.finish().unwrap()).unwrap(), expected);
+		// Some tools leave off the empty blocks at the end.
+		assert_eq!(read(ArchiveFormat::Tar, &tar[..tar.len() - 2 * TAR_BLOCK_SIZE]).unwrap(), expected);
+		assert!(read(ArchiveFormat::Tar, &tar[..tar.len() - 3 * TAR_BLOCK_SIZE]).is_err());
+		assert!(read(ArchiveFormat::Tar, &[b'x'; TAR_BLOCK_SIZE]).is_err());
+		let mut huge = make_test_tar(&[("a.png", b"not really a png")]);
+		huge[124..136].fill(0xFF); // The biggest size base-256 can give.
+		huge[148..156].fill(b' ');
+		let checksum: u64 = huge[..TAR_BLOCK_SIZE].iter().map(|&b| b as u64).sum();
+		huge[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+		assert!(read(ArchiveFormat::Tar, &huge).is_err());
+
+		// A long name too big to read fails only the file it names.
+		let tar = make_test_tar(&[(&long_name, b"not really a png"), ("b.png", b"not really a png")]);
+		let mut name_header = tar[..TAR_BLOCK_SIZE].to_vec();
+		name_header[124..136].copy_from_slice(format!("{:011o}\0", MAX_ENTRY_SIZE + 1).as_bytes());
+		name_header[148..156].fill(b' ');
+		let checksum: u64 = name_header.iter().map(|&b| b as u64).sum();
+		name_header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+		let padded_name = (MAX_ENTRY_SIZE + 1).div_ceil(TAR_BLOCK_SIZE as u64) * TAR_BLOCK_SIZE as u64;
+		let oversized = Cursor::new(name_header).chain(std::io::repeat(0).take(padded_name)).chain(Cursor::new(&tar[2 * TAR_BLOCK_SIZE..]));
+		let mut visited = vec![];
+		read_tar_files(oversized, |name| name.ends_with(".png"), |name, contents| {
+			visited.push((name.to_string(), contents.is_ok()));
+			true
+		}).unwrap();
+		assert_eq!(visited, vec![(long_name[..100].to_string(), false), ("b.png".to_string(), true)]);
+		assert_eq!(read(ArchiveFormat::Tar, &make_test_tar(&dotted_files.iter().map(|(name, contents)| (name.as_str(), *contents)).collect::<Vec<_>>())).unwrap(), expected);
+
+		// A solid 7z, where the files that are skipped have to be read past.
+		let mut writer = SevenZWriter::new(Cursor::new(vec![])).unwrap();
+		let entries = files.iter().filter(|(name, _)| !name.ends_with('/')).map(|(name, _)| {
+			let mut entry = SevenZArchiveEntry::new();
+			entry.name = name.replace('/', "\\");
+			entry.has_stream = true;
+			entry
+		}).collect();
+		let contents = files.iter().filter(|(name, _)| !name.ends_with('/')).map(|(_, contents)| SourceReader::from(*contents)).collect();
+		writer.push_archive_entries(entries, SeqReader::new(contents)).unwrap();
+		assert_eq!(read(ArchiveFormat::SevenZip, &writer.finish().unwrap().into_inner()).unwrap(), expected);
+		assert!(read(ArchiveFormat::SevenZip, b"7z but not really").is_err());
+
+		// Stopping early.
+		let mut visited = 0;
+		read_archive_files(ArchiveFormat::Tar, Cursor::new(&tar), None, |_| true, |_, _| { visited += 1; false }).unwrap();
+		assert_eq!(visited, 1);
+
+		assert_eq!(ArchiveFormat::from_filename("scans.TAR.GZ"), Some(ArchiveFormat::TarGz));
+		assert_eq!(ArchiveFormat::from_filename("scans.tgz"), Some(ArchiveFormat::TarGz));
+		assert_eq!(ArchiveFormat::from_filename("scans.tar"), Some(ArchiveFormat::Tar));
+		assert_eq!(ArchiveFormat::from_filename("scans.7z"), Some(ArchiveFormat::SevenZip));
+		assert_eq!(ArchiveFormat::from_filename("scans.zip"), Some(ArchiveFormat::Zip));
+		assert_eq!(ArchiveFormat::from_filename("comic.cbz"), None);
+		assert_eq!(ArchiveFormat::from_filename("gz"), None);
+	}
+}
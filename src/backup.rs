@@ -0,0 +1,330 @@
+// Incremental backups of a DB into a directory.
+// Backups are kept in chains: a full copy of the DB, then files holding only the pages that changed since the backup before.
+// Restoring a backup replays its chain up to that point.  Pruning removes whole chains, oldest first.
+
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ring::digest;
+
+pub const INCREMENTALS_PER_FULL: usize = 6; // After this many incremental backups the next one starts a new chain.
+const BACKUP_PREFIX: &'static str = "pixelbox_backup_";
+const FULL_SUFFIX: &'static str = "_full.db";
+const INCREMENTAL_SUFFIX: &'static str = "_incremental.db";
+const PAGE_DIFF_SCHEMA: [&'static str; 3] = [
+	"CREATE TABLE backup_info (name TEXT PRIMARY KEY, value TEXT)",
+	"CREATE TABLE pages (page_number INTEGER PRIMARY KEY, data BLOB NOT NULL)", // Only the pages that changed.
+	"CREATE TABLE page_hashes (page_number INTEGER PRIMARY KEY, hash BLOB NOT NULL)", // The SHA-256 of every page, after this backup.
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupFile {
+	pub path: PathBuf,
+	pub timestamp_ms: u64,
+	pub full: bool,
+}
+
+impl BackupFile {
+	fn from_path(path: PathBuf) -> Option<Self> {
+		let name = path.file_name()?.to_str()?;
+		let rest = name.strip_prefix(BACKUP_PREFIX)?;
+		let (timestamp, full) = if let Some(timestamp) = rest.strip_suffix(FULL_SUFFIX) {
+			(timestamp, true)
+		} else {
+			(rest.strip_suffix(INCREMENTAL_SUFFIX)?, false)
+		};
+		let timestamp_ms = timestamp.parse().ok()?;
+		Some(BackupFile { path, timestamp_ms, full })
+	}
+
+	fn filename(timestamp_ms: u64, full: bool) -> String {
+		// Zero padded so names sort by time.
+		format!("{}{:015}{}", BACKUP_PREFIX, timestamp_ms, if full { FULL_SUFFIX } else { INCREMENTAL_SUFFIX })
+	}
+}
+
+/// Every backup in the directory, oldest first.  Files that aren't ours are ignored.
+pub fn list_backups(directory: &Path) -> Result<Vec<BackupFile>> {
+	let mut backups = vec![];
+	for entry in std::fs::read_dir(directory)? {
+		if let Some(backup) = BackupFile::from_path(entry?.path()) {
+			backups.push(backup);
+		}
+	}
+	backups.sort_by_key(|b| b.timestamp_ms);
+	Ok(backups)
+}
+
+/// How long ago the newest backup in the directory was made.  None if there aren't any.
+pub fn time_since_last_backup(directory: &Path) -> Result<Option<Duration>> {
+	if !directory.exists() {
+		return Ok(None);
+	}
+	let now = now_ms();
+	Ok(list_backups(directory)?.last().map(|b| Duration::from_millis(now.saturating_sub(b.timestamp_ms))))
+}
+
+/// Store a snapshot of the DB, made with the backup API, in the directory.
+/// The snapshot becomes a full backup if there's no chain to add to, otherwise only its changed pages are stored.
+/// The snapshot file is consumed either way.  Returns the new backup, or None if nothing changed.
+pub fn store_snapshot(snapshot: &Path, directory: &Path) -> Result<Option<PathBuf>> {
+	let backups = list_backups(directory)?;
+	let chain_start = backups.iter().rposition(|b| b.full);
+	let page_size = read_page_size(snapshot)?;
+
+	// The hashes of every page as of the last backup in the chain, if we can add to it.
+	let previous_hashes = match chain_start {
+		Some(start) if backups.len() - start - 1 < INCREMENTALS_PER_FULL => {
+			let last = backups.last().unwrap();
+			if last.full {
+				hash_pages(&last.path, read_page_size(&last.path)?)?
+			} else {
+				let conn = Connection::open(&last.path)?;
+				let last_page_size: u64 = read_backup_info(&conn, "page_size")?;
+				if last_page_size == page_size {
+					let mut stmt = conn.prepare("SELECT hash FROM page_hashes ORDER BY page_number")?;
+					// Backups from older versions stored integer hashes.  Those can't be compared, so they start a new chain.
+					let hashes = stmt.query_map([], |row| Ok(row.get_ref(0)?.as_blob().ok().map(|h| h.to_vec())))?.collect::<rusqlite::Result<Option<Vec<Vec<u8>>>>>()?;
+					hashes.unwrap_or_default()
+				} else {
+					vec![]
+				}
+			}
+		},
+		_ => vec![],
+	};
+
+	let timestamp_ms = next_timestamp(&backups);
+	if previous_hashes.is_empty() {
+		let path = directory.join(BackupFile::filename(timestamp_ms, true));
+		std::fs::rename(snapshot, &path)?;
+		return Ok(Some(path));
+	}
+
+	let hashes = hash_pages(snapshot, page_size)?;
+	let changed: Vec<usize> = (0..hashes.len()).filter(|&i| previous_hashes.get(i) != Some(&hashes[i])).collect();
+	if changed.is_empty() && hashes.len() == previous_hashes.len() {
+		std::fs::remove_file(snapshot)?;
+		return Ok(None);
+	}
+
+	let start = &backups[chain_start.unwrap()];
+	let path = directory.join(BackupFile::filename(timestamp_ms, false));
+	let mut partial_path = path.as_os_str().to_owned();
+	partial_path.push(".partial");
+	let partial_path = PathBuf::from(partial_path);
+	let result = (|| -> Result<()> {
+		let mut conn = Connection::open(&partial_path)?;
+		let tx = conn.transaction()?;
+		for table in PAGE_DIFF_SCHEMA {
+			tx.execute(table, [])?;
+		}
+		let info = [
+			("full_backup", start.path.file_name().unwrap().to_string_lossy().to_string()),
+			("page_size", page_size.to_string()),
+			("page_count", hashes.len().to_string()),
+		];
+		for (name, value) in info {
+			tx.execute("INSERT INTO backup_info (name, value) VALUES (?, ?)", params![name, value])?;
+		}
+		{
+			let mut snapshot_file = File::open(snapshot)?;
+			let mut page = vec![0u8; page_size as usize];
+			let mut insert_page = tx.prepare("INSERT INTO pages (page_number, data) VALUES (?, ?)")?;
+			for &idx in &changed {
+				snapshot_file.seek(SeekFrom::Start(idx as u64 * page_size))?;
+				snapshot_file.read_exact(&mut page)?;
+				insert_page.execute(params![idx as i64, &page])?;
+			}
+			let mut insert_hash = tx.prepare("INSERT INTO page_hashes (page_number, hash) VALUES (?, ?)")?;
+			for (idx, hash) in hashes.iter().enumerate() {
+				insert_hash.execute(params![idx as i64, hash])?;
+			}
+		}
+		tx.commit()?;
+		conn.close().map_err(|(_, e)| e)?;
+		std::fs::rename(&partial_path, &path)?;
+		Ok(())
+	})();
+	if result.is_err() {
+		let _ = std::fs::remove_file(&partial_path);
+	}
+	result?;
+	std::fs::remove_file(snapshot)?;
+	Ok(Some(path))
+}
+
+/// Rebuild the DB as it was when the given backup was made, writing it to target.
+/// Anything already at target is replaced.
+pub fn restore_backup(backup: &Path, target: &Path) -> Result<()> {
+	let backup_file = BackupFile::from_path(backup.to_path_buf()).ok_or_else(|| anyhow!("{} isn't a PixelBox backup.", backup.display()))?;
+	let directory = backup.parent().unwrap_or(Path::new("."));
+
+	// Find the full backup this one builds on and every incremental backup in between.
+	let backups = list_backups(directory)?;
+	let end = backups.iter().position(|b| b.path.file_name() == backup.file_name()).ok_or_else(|| anyhow!("{} doesn't exist.", backup.display()))?;
+	let start = backups[..=end].iter().rposition(|b| b.full).ok_or_else(|| anyhow!("The full backup that {} builds on is missing.", backup.display()))?;
+	if !backup_file.full {
+		let conn = Connection::open(backup)?;
+		let full_backup: String = read_backup_info(&conn, "full_backup")?;
+		if backups[start].path.file_name().map(|n| n.to_string_lossy() != full_backup).unwrap_or(true) {
+			return Err(anyhow!("The full backup that {} builds on, {}, is missing.", backup.display(), full_backup));
+		}
+	}
+
+	let mut partial_path = target.as_os_str().to_owned();
+	partial_path.push(".partial");
+	let partial_path = PathBuf::from(partial_path);
+	let result = (|| -> Result<()> {
+		std::fs::copy(&backups[start].path, &partial_path)?;
+		let mut restored = std::fs::OpenOptions::new().write(true).open(&partial_path)?;
+		let mut final_size = None;
+		for incremental in &backups[start+1..=end] {
+			let conn = Connection::open(&incremental.path)?;
+			let page_size: u64 = read_backup_info(&conn, "page_size")?;
+			let page_count: u64 = read_backup_info(&conn, "page_count")?;
+			let mut stmt = conn.prepare("SELECT page_number, data FROM pages ORDER BY page_number")?;
+			let mut rows = stmt.query([])?;
+			while let Some(row) = rows.next()? {
+				let page_number: i64 = row.get(0)?;
+				let data: Vec<u8> = row.get(1)?;
+				restored.seek(SeekFrom::Start(page_number as u64 * page_size))?;
+				restored.write_all(&data)?;
+			}
+			final_size = Some(page_count * page_size);
+		}
+		if let Some(size) = final_size {
+			restored.set_len(size)?;
+		}
+		restored.sync_all()?;
+		drop(restored);
+
+		let conn = Connection::open(&partial_path)?;
+		let check: String = conn.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+		if check != "ok" {
+			return Err(anyhow!("The restored DB is damaged: {}", check));
+		}
+		conn.close().map_err(|(_, e)| e)?;
+		std::fs::rename(&partial_path, target)?;
+		Ok(())
+	})();
+	if result.is_err() {
+		let _ = std::fs::remove_file(&partial_path);
+	}
+	result
+}
+
+/// Remove all but the newest chains_to_keep chains.  At least one is always kept.  Returns how many files were removed.
+pub fn prune_backups(directory: &Path, chains_to_keep: usize) -> Result<usize> {
+	let backups = list_backups(directory)?;
+	let chain_starts: Vec<usize> = backups.iter().enumerate().filter(|(_, b)| b.full).map(|(i, _)| i).collect();
+	if chain_starts.len() <= chains_to_keep.max(1) {
+		return Ok(0);
+	}
+	let first_kept = chain_starts[chain_starts.len() - chains_to_keep.max(1)];
+	for backup in &backups[..first_kept] {
+		std::fs::remove_file(&backup.path)?;
+	}
+	Ok(first_kept)
+}
+
+fn read_backup_info<T: std::str::FromStr>(conn: &Connection, name: &str) -> Result<T> {
+	let value: Option<String> = conn.query_row("SELECT value FROM backup_info WHERE name = ?", [name], |row| row.get(0)).optional()?;
+	value.and_then(|v| v.parse().ok()).ok_or_else(|| anyhow!("Backup is missing its {}.", name))
+}
+
+fn read_page_size(db_path: &Path) -> Result<u64> {
+	let conn = Connection::open(db_path)?;
+	let page_size: i64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+	Ok(page_size as u64)
+}
+
+fn hash_pages(db_path: &Path, page_size: u64) -> Result<Vec<Vec<u8>>> {
+	let mut reader = BufReader::new(File::open(db_path)?);
+	let mut page = vec![0u8; page_size as usize];
+	let mut hashes = vec![];
+	loop {
+		match reader.read_exact(&mut page) {
+			Ok(_) => hashes.push(digest::digest(&digest::SHA256, &page).as_ref().to_vec()),
+			Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+			Err(e) => return Err(e.into()),
+		}
+	}
+	Ok(hashes)
+}
+
+fn now_ms() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Now, or just after the newest backup if the clock hasn't moved past it, so names stay unique and ordered.
+fn next_timestamp(backups: &[BackupFile]) -> u64 {
+	let newest = backups.last().map(|b| b.timestamp_ms + 1).unwrap_or(0);
+	now_ms().max(newest)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::backup::*;
+
+	fn write_db(path: &Path, rows: usize) {
+		let conn = Connection::open(path).unwrap();
+		conn.execute("CREATE TABLE IF NOT EXISTS t (id INTEGER PRIMARY KEY, data BLOB)", []).unwrap();
+		conn.execute("DELETE FROM t WHERE id >= ?", [rows as i64]).unwrap();
+		for i in 0..rows {
+			conn.execute("INSERT OR IGNORE INTO t (id, data) VALUES (?, ?)", params![i as i64, vec![i as u8; 2000]]).unwrap();
+		}
+	}
+
+	fn row_count(path: &Path) -> i64 {
+		Connection::open(path).unwrap().query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0)).unwrap()
+	}
+
+	#[test]
+	fn test_incremental_backup_and_restore() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_backups_{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		let db_path = dir.join("source.db");
+		let snapshot = dir.join("snapshot.db");
+
+		let mut stored = vec![];
+		for rows in [Some(50), Some(60), None, Some(20)] {
+			if let Some(rows) = rows {
+				write_db(&db_path, rows);
+			}
+			std::fs::copy(&db_path, &snapshot).unwrap();
+			stored.push(store_snapshot(&snapshot, &dir).unwrap());
+		}
+		assert!(!snapshot.exists());
+		assert!(stored[2].is_none()); // Nothing changed.
+		let backups = list_backups(&dir).unwrap();
+		assert_eq!(backups.iter().map(|b| b.full).collect::<Vec<bool>>(), vec![true, false, false]);
+		assert!(std::fs::metadata(&backups[1].path).unwrap().len() < std::fs::metadata(&backups[0].path).unwrap().len());
+
+		let restored = dir.join("restored.db");
+		for (backup, rows) in backups.iter().zip([50, 60, 20]) {
+			restore_backup(&backup.path, &restored).unwrap();
+			assert_eq!(row_count(&restored), rows);
+		}
+
+		// Fill the chain so a new one starts, then keep only the new one.
+		for rows in 1..=INCREMENTALS_PER_FULL {
+			write_db(&db_path, rows);
+			std::fs::copy(&db_path, &snapshot).unwrap();
+			store_snapshot(&snapshot, &dir).unwrap();
+		}
+		let backups = list_backups(&dir).unwrap();
+		assert_eq!(backups.iter().map(|b| b.full).collect::<Vec<bool>>(), [vec![true], vec![false; INCREMENTALS_PER_FULL], vec![true, false]].concat());
+		assert_eq!(prune_backups(&dir, 1).unwrap(), INCREMENTALS_PER_FULL + 1);
+		let backups = list_backups(&dir).unwrap();
+		assert_eq!(backups.len(), 2);
+		restore_backup(&backups[1].path, &restored).unwrap();
+		assert_eq!(row_count(&restored), INCREMENTALS_PER_FULL as i64);
+
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+}
@@ -0,0 +1,281 @@
+// Covers and pages of ebooks and comic archives.
+// EPUBs and CBZs are both zips underneath.  RAR and 7z comics (CBR, CB7) aren't supported.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use crate::archive;
+
+const PAGE_EXTENSIONS: &'static [&str] = &["png", "jpg", "jpeg", "gif", "webp", "bmp"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BookFormat {
+	Epub,
+	ComicArchive,
+}
+
+impl BookFormat {
+	/// Books are just zips, so they can only be told apart by extension.
+	pub fn from_filename(filename: &str) -> Option<BookFormat> {
+		let extension = filename.rsplit_once('.')?.1.to_lowercase();
+		match extension.as_str() {
+			"epub" => Some(BookFormat::Epub),
+			"cbz" => Some(BookFormat::ComicArchive),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BookInfo {
+	pub cover: String, // The zip entry of the cover image.
+	pub pages: Vec<String>, // Every image in the book, in reading order as best we can tell.
+	pub title: Option<String>,
+	pub author: Option<String>,
+}
+
+impl BookInfo {
+	pub fn to_tags(&self) -> HashMap<String, String> {
+		let mut tags = HashMap::new();
+		if let Some(title) = &self.title {
+			tags.insert("Title".to_string(), title.clone());
+		}
+		if let Some(author) = &self.author {
+			tags.insert("Author".to_string(), author.clone());
+		}
+		tags.insert("PageCount".to_string(), self.pages.len().to_string());
+		tags
+	}
+}
+
+/// Find the cover, pages, title, and author of a book.
+pub fn read_book_info(format: BookFormat, bytes: &[u8]) -> Result<BookInfo> {
+	match format {
+		BookFormat::Epub => read_epub_info(bytes),
+		BookFormat::ComicArchive => read_comic_info(bytes),
+	}
+}
+
+/// The contents of one image in the book, like the cover.
+pub fn read_page(bytes: &[u8], page: &str) -> Result<Vec<u8>> {
+	archive::read_zip_file(bytes, page)?.ok_or_else(|| anyhow!("The book has no page {}.", page))
+}
+
+fn is_page_image(name: &str) -> bool {
+	let extension = name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).unwrap_or_default();
+	PAGE_EXTENSIONS.contains(&extension.as_str())
+}
+
+fn read_epub_info(bytes: &[u8]) -> Result<BookInfo> {
+	// The container says where the package file is, and the package file lists everything else.
+	let container = archive::read_zip_file(bytes, "META-INF/container.xml")?.ok_or_else(|| anyhow!("Not an EPUB: there's no container.xml."))?;
+	let container = String::from_utf8_lossy(&container).to_string();
+	let container = roxmltree::Document::parse(&container)?;
+	let package_path = container.descendants()
+		.find(|n| n.has_tag_name("rootfile"))
+		.and_then(|n| n.attribute("full-path"))
+		.ok_or_else(|| anyhow!("The EPUB's container doesn't name a package file."))?
+		.to_string();
+	let package = archive::read_zip_file(bytes, &package_path)?.ok_or_else(|| anyhow!("The EPUB's package file {} is missing.", package_path))?;
+	let package = String::from_utf8_lossy(&package).to_string();
+	let package = roxmltree::Document::parse(&package)?;
+
+	// Manifest hrefs are relative to the package file.
+	let package_dir = package_path.rsplit_once('/').map(|(dir, _)| format!("{}/", dir)).unwrap_or_default();
+	let items: Vec<(&str, String, &str, &str)> = package.descendants()
+		.filter(|n| n.has_tag_name("item"))
+		.filter_map(|n| Some((n.attribute("id").unwrap_or(""), resolve_href(&package_dir, n.attribute("href")?), n.attribute("media-type").unwrap_or(""), n.attribute("properties").unwrap_or(""))))
+		.collect();
+	let pages: Vec<String> = items.iter()
+		.filter(|(_, href, media_type, _)| media_type.starts_with("image/") && is_page_image(href))
+		.map(|(_, href, _, _)| href.clone())
+		.collect();
+
+	// EPUB 3 marks the cover in the manifest.  EPUB 2 points to it from a meta tag.  Failing both, a lot of books call it "cover".
+	let legacy_cover_id = package.descendants()
+		.find(|n| n.has_tag_name("meta") && n.attribute("name") == Some("cover"))
+		.and_then(|n| n.attribute("content"));
+	let is_page = |href: &String| pages.contains(href);
+	let cover = items.iter().find(|(_, href, _, properties)| properties.split_whitespace().any(|p| p == "cover-image") && is_page(href))
+		.or_else(|| items.iter().find(|(id, href, _, _)| Some(*id) == legacy_cover_id && is_page(href)))
+		.or_else(|| items.iter().find(|(id, href, _, _)| (id.to_lowercase().contains("cover") || href.to_lowercase().contains("cover")) && is_page(href)))
+		.map(|(_, href, _, _)| href.clone())
+		.or_else(|| pages.first().cloned())
+		.ok_or_else(|| anyhow!("The EPUB has no images to use as a cover."))?;
+
+	let metadata_text = |name: &str| -> Option<String> {
+		package.descendants()
+			.find(|n| n.tag_name().name() == name && n.parent().map(|p| p.tag_name().name() == "metadata").unwrap_or(false))
+			.and_then(|n| n.text())
+			.map(|t| t.trim().to_string())
+			.filter(|t| !t.is_empty())
+	};
+
+	Ok(BookInfo {
+		cover,
+		title: metadata_text("title"),
+		author: metadata_text("creator"),
+		pages,
+	})
+}
+
+fn read_comic_info(bytes: &[u8]) -> Result<BookInfo> {
+	let entries = archive::zip_entries(bytes)?;
+	// Zips made on a Mac carry a shadow copy of every file in __MACOSX.
+	let mut pages: Vec<String> = entries.iter()
+		.map(|e| e.name.clone())
+		.filter(|name| is_page_image(name) && !name.starts_with("__MACOSX/") && !name.rsplit('/').next().unwrap_or("").starts_with('.'))
+		.collect();
+	// Pages are rarely zero-padded consistently, so page2 has to come before page10.
+	pages.sort_by(|a, b| natural_order(a, b));
+	let cover = pages.first().cloned().ok_or_else(|| anyhow!("The comic archive has no pages."))?;
+
+	// ComicRack's ComicInfo.xml is the closest thing comics have to a standard for metadata.
+	let mut title = None;
+	let mut author = None;
+	if let Some(comic_info) = archive::read_zip_file(bytes, "ComicInfo.xml")? {
+		let comic_info = String::from_utf8_lossy(&comic_info).to_string();
+		if let Ok(doc) = roxmltree::Document::parse(&comic_info) {
+			let text = |name: &str| doc.descendants().find(|n| n.has_tag_name(name)).and_then(|n| n.text()).map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+			title = match (text("Series"), text("Number"), text("Title")) {
+				(Some(series), Some(number), _) => Some(format!("{} #{}", series, number)),
+				(_, _, Some(title)) => Some(title),
+				(series, _, None) => series,
+			};
+			author = text("Writer");
+		}
+	}
+
+	Ok(BookInfo { cover, pages, title, author })
+}
+
+/// Undo the percent-encoding in an href and make it relative to the root of the zip.
+fn resolve_href(base_dir: &str, href: &str) -> String {
+	let href = href.split('#').next().unwrap_or("");
+	let mut decoded = vec![];
+	let bytes = href.as_bytes();
+	let mut idx = 0;
+	while idx < bytes.len() {
+		let escaped = if bytes[idx] == b'%' { href.get(idx+1..idx+3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) } else { None };
+		match escaped {
+			Some(byte) => {
+				decoded.push(byte);
+				idx += 3;
+			},
+			None => {
+				decoded.push(bytes[idx]);
+				idx += 1;
+			}
+		}
+	}
+
+	// Resolve any ../ against the package directory.
+	let mut parts: Vec<String> = base_dir.split('/').filter(|p| !p.is_empty()).map(|p| p.to_string()).collect();
+	for part in String::from_utf8_lossy(&decoded).split('/') {
+		match part {
+			"" | "." => {},
+			".." => { parts.pop(); },
+			_ => parts.push(part.to_string()),
+		}
+	}
+	parts.join("/")
+}
+
+/// Compare names with runs of digits compared by value, so "page2" comes before "page10".
+fn natural_order(a: &str, b: &str) -> std::cmp::Ordering {
+	let mut a = a.chars().peekable();
+	let mut b = b.chars().peekable();
+	loop {
+		match (a.peek().copied(), b.peek().copied()) {
+			(None, None) => return std::cmp::Ordering::Equal,
+			(None, Some(_)) => return std::cmp::Ordering::Less,
+			(Some(_), None) => return std::cmp::Ordering::Greater,
+			(Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+				let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+					let mut digits = String::new();
+					while let Some(c) = chars.peek().copied().filter(|c| c.is_ascii_digit()) {
+						digits.push(c);
+						chars.next();
+					}
+					digits
+				};
+				let (x, y) = (take_number(&mut a), take_number(&mut b));
+				let (x_trimmed, y_trimmed) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+				let ordering = x_trimmed.len().cmp(&y_trimmed.len()).then_with(|| x_trimmed.cmp(y_trimmed));
+				if ordering != std::cmp::Ordering::Equal {
+					return ordering;
+				}
+			},
+			(Some(x), Some(y)) => {
+				let ordering = x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase());
+				if ordering != std::cmp::Ordering::Equal {
+					return ordering;
+				}
+				a.next();
+				b.next();
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::book::*;
+	use crate::archive::make_test_zip;
+
+	#[test]
+	fn test_epub() {
+		let container = br#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+	<rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#;
+		let package = br#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+	<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+		<dc:title>The Left Hand of Darkness</dc:title>
+		<dc:creator>Ursula K. Le Guin</dc:creator>
+		<meta name="cover" content="front"/>
+	</metadata>
+	<manifest>
+		<item id="chapter1" href="text/chapter1.xhtml" media-type="application/xhtml+xml"/>
+		<item id="map" href="images/map.png" media-type="image/png"/>
+		<item id="front" href="../art/front%20cover.jpg" media-type="image/jpeg"/>
+	</manifest>
+</package>"#;
+		let epub = make_test_zip(&[("mimetype", b"application/epub+zip"), ("META-INF/container.xml", container), ("OEBPS/content.opf", package), ("art/front cover.jpg", b"jpeg")]);
+		let info = read_book_info(BookFormat::Epub, &epub).unwrap();
+		assert_eq!(info.cover, "art/front cover.jpg");
+		assert_eq!(info.pages, vec!["OEBPS/images/map.png", "art/front cover.jpg"]);
+		assert_eq!(info.title.as_deref(), Some("The Left Hand of Darkness"));
+		assert_eq!(info.author.as_deref(), Some("Ursula K. Le Guin"));
+		assert_eq!(info.to_tags().get("PageCount").map(|s| s.as_str()), Some("2"));
+		assert_eq!(read_page(&epub, &info.cover).unwrap(), b"jpeg");
+		assert!(read_page(&epub, "OEBPS/images/map.png").is_err());
+
+		// EPUB 3 covers are marked in the manifest instead.
+		let package3 = br#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0"><metadata/><manifest>
+			<item id="a" href="a.png" media-type="image/png"/>
+			<item id="b" href="b.png" media-type="image/png" properties="cover-image"/>
+		</manifest></package>"#;
+		let epub3 = make_test_zip(&[("META-INF/container.xml", container), ("OEBPS/content.opf", package3)]);
+		assert_eq!(read_book_info(BookFormat::Epub, &epub3).unwrap().cover, "OEBPS/b.png");
+
+		assert!(read_book_info(BookFormat::Epub, &make_test_zip(&[("mimetype", b"application/epub+zip")])).is_err());
+	}
+
+	#[test]
+	fn test_comic_archive() {
+		let comic_info = b"<ComicInfo><Series>Bone</Series><Number>3</Number><Writer>Jeff Smith</Writer></ComicInfo>";
+		let cbz = make_test_zip(&[("page10.jpg", b"10"), ("page2.jpg", b"2"), ("__MACOSX/page1.jpg", b""), ("page1.jpg", b"1"), ("ComicInfo.xml", comic_info)]);
+		let info = read_book_info(BookFormat::ComicArchive, &cbz).unwrap();
+		assert_eq!(info.pages, vec!["page1.jpg", "page2.jpg", "page10.jpg"]);
+		assert_eq!(info.cover, "page1.jpg");
+		assert_eq!(info.title.as_deref(), Some("Bone #3"));
+		assert_eq!(info.author.as_deref(), Some("Jeff Smith"));
+
+		assert_eq!(BookFormat::from_filename("Bone 03.CBZ"), Some(BookFormat::ComicArchive));
+		assert_eq!(BookFormat::from_filename("Dune.epub"), Some(BookFormat::Epub));
+		assert_eq!(BookFormat::from_filename("Dune.zip"), None);
+		assert!(read_book_info(BookFormat::ComicArchive, &make_test_zip(&[("notes.txt", b"")])).is_err());
+	}
+}
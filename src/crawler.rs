@@ -1,83 +1,1480 @@
 use anyhow::{Result, anyhow};
-use crossbeam::channel::{Receiver, Sender, unbounded};
-use glob::glob;
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender, SendTimeoutError, unbounded};
+use glob::{glob, Pattern};
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{BufReader, BufRead, Read};
-use std::path::PathBuf;
+use std::io::{BufReader, Cursor, Read, Seek};
+use parking_lot::Mutex;
+use ring::digest;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::{archive, book, mail, mesh, sniff, video};
 use crate::indexed_image::{IndexedImage, stringify_filepath};
 
-const SUPPORTED_IMAGE_EXTENSIONS: &'static [&str; 12] = &["png", "bmp", "jpg", "jpeg", "jfif", "gif", "tiff", "pnm", "webp", "ico", "tga", "exr"];
+const SUPPORTED_IMAGE_EXTENSIONS: &'static [&str; 41] = &["png", "bmp", "jpg", "jpeg", "jfif", "gif", "tiff", "pnm", "webp", "ico", "tga", "exr", "dds", "ktx2", "psd", "psb", "xcf", "kra", "ttf", "otf", "ttc", "stl", "obj", "gltf", "glb", "mp3", "flac", "epub", "cbz", "cr2", "cr3", "nef", "arw", "dng", "orf", "rw2", "raf", "srw", "pef", "svg", "svgz"];
 
-/// Given a vec of directory globs and a set of valid extensions,
-/// crawl the disk and index images.
+// How often a paused crawl checks whether it's been resumed.  Also how often a crawl waiting on a full queue checks whether it's been stopped.
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Walking stops once this many files are waiting to be loaded, and carries on as they're taken.
+pub const MAX_PENDING_FILEPATHS: usize = 1000;
+// Loading stops once this many images are waiting to be stored.  Each has its thumbnail and hashes, so far fewer are held than paths.
+pub const MAX_PENDING_IMAGES: usize = 64;
+// How much nicer than normal crawl threads are when CrawlOptions::low_priority is set.  19 is the nicest there is.
+#[cfg(target_os = "linux")]
+const LOW_PRIORITY_NICENESS: i32 = 10;
+// Filesystems whose files are read with CrawlOptions::io_timeout, by the magic number statfs gives for them.  Everything else is taken to be local.
+#[cfg(target_os = "linux")]
+const NETWORK_FILESYSTEMS: &'static [u32] = &[
+	0x6969, // NFS
+	0x517B, // SMB
+	0xFF534D42, // CIFS
+	0xFE534D42, // SMB2
+	0x65735546, // FUSE, like sshfs and rclone
+	0x5346414F, // AFS
+	0x73757245, // Coda
+	0x01021997, // 9P, like the Windows drives in WSL
+];
+// How long to wait before trying a folder that didn't answer again, times how many tries it's had.  A NAS that's asleep can take a while to spin up.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+// Changes in watched folders are held until they've been quiet this long, so a file that's still being copied in isn't loaded half written.
+pub const WATCH_DEBOUNCE: Duration = Duration::from_secs(2);
+// Folders that never stop changing still have their changes sent this often.
+const MAX_WATCH_DELAY: Duration = Duration::from_secs(30);
+
+/// How much work to do on each file, beyond indexing the image in it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CrawlOptions {
+	pub index_book_pages: bool, // Send every image in an ebook or comic too, not just the book with its cover.
+	pub hash_cropped_frames: bool, // Embed the picture without its borders and edges too.  See IndexedImage::cropped_hash.
+	pub hash_animation_frames: bool, // Embed animations by several frames averaged.  See animation::SAMPLE_FRAMES.
+	pub newest_first: bool, // Walk everything before loading anything, then load the most recently modified files first.
+	pub skip_hidden: bool, // Don't walk into hidden files and folders, like .thumbnails, .cache, and .git.  See is_hidden.
+	pub min_file_size: u64, // Skip files smaller than this many bytes, like icons and tracking pixels.  Archives, books, and mail are read whatever their size, and what's in them is checked instead.
+	pub max_file_size: u64, // Skip files bigger than this many bytes.  0 for no limit.
+	pub max_files_per_second: u32, // Load at most this many files a second, across every loader.  0 for no limit.
+	pub low_priority: bool, // Ask the OS to run the crawl after everything else that wants the CPU.  See lower_thread_priority.
+	pub io_timeout: Duration, // Give up on a folder or file on a network share that hasn't answered in this long, like one that's asleep or gone.  Zero to wait as long as it takes.
+	pub io_retries: u32, // How many more times to try a folder that timed out or whose share is down before it's skipped as unreachable.  See read_patiently.
+	pub skip_archives: bool, // Don't look inside archives, books, or mail.  Books and mail are still indexed themselves.
+	pub max_archive_depth: u32, // How many archives deep to look for archives inside archives.  At 0, only the images right inside an archive are indexed.
+	pub archive_password: Option<String>, // For encrypted zips and 7zs.
+	pub exclusions: Arc<Exclusions>, // Files and entries that are skipped before they're loaded.
+}
+
+impl CrawlOptions {
+	/// True if a file this many bytes long is within min_file_size and max_file_size.
+	pub fn wants_size(&self, size:u64) -> bool {
+		size >= self.min_file_size && (self.max_file_size == 0 || size <= self.max_file_size)
+	}
+
+	/// True if the file at path is an archive, book, or mail file whose images will be read out of it.
+	/// Their own size isn't checked against wants_size, since it says nothing about the size of the images in them.
+	fn reads_entries(&self, path:&Path) -> bool {
+		let pathstring = path.to_string_lossy();
+		!self.skip_archives && (
+			mail::is_mail_file(path.file_name().and_then(OsStr::to_str).unwrap_or(""))
+			|| archive::ArchiveFormat::from_filename(&pathstring).is_some()
+			|| (self.index_book_pages && book::BookFormat::from_filename(&pathstring).is_some())
+		)
+	}
+}
+
+/// What's been excluded from the index, as it was when the crawl started.  See Engine::exclude_image.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Exclusions {
+	pub paths: HashSet<String>, // Paths as stringify_filepath gives them, and entries as archive::entry_path does.
+	pub content_hashes: HashSet<Vec<u8>>, // The file_content_hash of files on disk, so copies and moved files are skipped too.
+}
+
+impl Exclusions {
+	/// True if the file was excluded, or is a copy of one that was.  The file is only hashed if something's been excluded by content.
+	/// Files that can't be read aren't excluded, so loading them fails like it would have.
+	pub fn excludes(&self, file_path:&Path) -> bool {
+		if self.paths.is_empty() && self.content_hashes.is_empty() {
+			return false;
+		}
+		self.paths.contains(&stringify_filepath(file_path))
+			|| (!self.content_hashes.is_empty() && file_content_hash(file_path).map(|hash| self.content_hashes.contains(&hash)).unwrap_or(false))
+	}
+
+	/// True if the image in a book, archive, or mail file was excluded.  They're only excluded by path.
+	pub fn excludes_entry(&self, entry_path:&str) -> bool {
+		self.paths.contains(entry_path)
+	}
+}
+
+/// The SHA-256 of a file, read a piece at a time so big files aren't loaded all at once.
+pub fn file_content_hash(path: &Path) -> Result<Vec<u8>> {
+	let mut file = File::open(path)?;
+	let mut context = digest::Context::new(&digest::SHA256);
+	let mut buffer = vec![0u8; 64 * 1024];
+	loop {
+		let bytes_read = file.read(&mut buffer)?;
+		if bytes_read == 0 {
+			break;
+		}
+		context.update(&buffer[..bytes_read]);
+	}
+	Ok(context.finish().as_ref().to_vec())
+}
+
+/// A folder to crawl, and how.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrawlFolder {
+	pub glob: String,
+	pub priority: i64, // Folders with a higher priority are crawled first.
+	pub ignore_patterns: Vec<String>, // See is_ignored.
+	pub recursive: bool, // Look in subfolders too.
+	pub extensions: Vec<String>, // Only files with these extensions, lowercase and without the dot.  Empty for every kind we can read.
+	pub scan_archives: bool, // Look inside archives, books, and mail for more images.  Book pages are only indexed if CrawlOptions::index_book_pages is set too.
+	pub follow_symlinks: bool, // Crawl linked files and folders too.  Links that loop back into a folder they're in are never followed.
+	pub archive_password: String, // For the encrypted zips and 7zs in it.  Empty if there isn't one.
+}
+
+impl Default for CrawlFolder {
+	fn default() -> Self {
+		CrawlFolder {
+			glob: String::new(),
+			priority: 0,
+			ignore_patterns: vec![],
+			recursive: true,
+			extensions: vec![],
+			scan_archives: true,
+			follow_symlinks: true,
+			archive_password: String::new(),
+		}
+	}
+}
+
+impl CrawlFolder {
+	/// How to load the files in this folder, given how the whole crawl loads them.
+	pub fn crawl_options(&self, options:&CrawlOptions) -> CrawlOptions {
+		let archive_password = if self.archive_password.is_empty() { options.archive_password.clone() } else { Some(self.archive_password.clone()) };
+		CrawlOptions { skip_archives: options.skip_archives || !self.scan_archives, archive_password, ..options.clone() }
+	}
+
+	/// True if crawling this folder would load the file, or walk into the folder, at path.
+	pub fn wants(&self, path:&Path) -> bool {
+		let root = Path::new(&self.glob);
+		let depth = match path.strip_prefix(root) {
+			Ok(relative) => relative.components().count(),
+			Err(_) => return false,
+		};
+		let is_dir = path.is_dir();
+		if depth == 0 || (!self.recursive && (is_dir || depth > 1)) {
+			return false;
+		}
+		let ignore_patterns: Vec<(String, Pattern)> = self.ignore_patterns.iter().filter_map(|p| Pattern::new(p).ok().map(|pattern| (p.clone(), pattern))).collect();
+		if is_dir {
+			return !is_ignored(path, root, &ignore_patterns) && (self.follow_symlinks || !is_symlinked(path, root));
+		}
+		!is_skipped(path, root, self, &ignore_patterns)
+	}
+}
+
+/// Running totals for a crawl, for showing progress.  Shared with whoever's storing the images, who counts what's stored.
+/// Also holds the crawl's threads, so whoever's storing the images can wait for them with join_threads.
+#[derive(Debug, Default)]
+pub struct CrawlCounters {
+	pub files_found: AtomicU64, // Files that might have images in them.
+	pub finished_finding: AtomicBool, // Set once every folder has been walked, so files_found won't go up any more.
+	pub files_processed: AtomicU64, // Files that were loaded, skipped, or couldn't be loaded.
+	pub images_stored: AtomicU64,
+	pub failed: AtomicU64, // Files that couldn't be loaded and images that couldn't be stored.
+	pub unreachable: Mutex<Vec<String>>, // Folders that couldn't be reached, by their globs.  Nothing more is loaded from them until the next crawl.
+	threads: Mutex<Vec<JoinHandle<()>>>, // The walking and loading threads.
+}
+
+impl CrawlCounters {
+	/// Wait for the crawl's threads to end.  They end once everything they found is loaded, or once the crawl is stopped.
+	/// Returns the first of their panics, if any of them panicked.
+	pub fn join_threads(&self) -> std::thread::Result<()> {
+		let threads: Vec<JoinHandle<()>> = self.threads.lock().drain(..).collect();
+		// Every thread is joined before the first panic is passed on.
+		let results: Vec<std::thread::Result<()>> = threads.into_iter().map(|thread| thread.join()).collect();
+		results.into_iter().collect()
+	}
+}
+
+/// The files waiting to be loaded with how to load them, the images loaded from them, and the paths that couldn't be loaded with why.
+pub type CrawlChannels = (Receiver<(PathBuf, CrawlOptions)>, Receiver<IndexedImage>, Receiver<(String, String)>);
+
+/// Given a vec of folders and a set of valid extensions, crawl the disk and index images.
+/// Folders with a higher priority are crawled first, and files matching a folder's ignore patterns are skipped.
 /// Returns a Channel with Images as they're created.
-pub fn crawl_globs_async(globs:Vec<String>, parallel_file_loaders:usize) -> (Receiver<PathBuf>, Receiver<IndexedImage>) {
+/// Setting stop ends the crawl early.  Files that were already loaded are still sent.
+/// Setting pause holds the crawl where it is until it's cleared.  Nothing queued is lost.
+/// Files found and processed are added to counters as the crawl goes.
+/// The paths that couldn't be read or loaded are sent to the third channel with why.
+/// Folders whose share is asleep or gone are tried options.io_retries more times, then skipped and added to counters.unreachable instead of failing file by file.
+/// The first two channels are bounded by MAX_PENDING_FILEPATHS and MAX_PENDING_IMAGES, so walking and loading wait whenever whoever's storing the images falls behind.
+pub fn crawl_globs_async(folders:Vec<CrawlFolder>, parallel_file_loaders:usize, options:CrawlOptions, stop:Arc<AtomicBool>, pause:Arc<AtomicBool>, counters:Arc<CrawlCounters>) -> CrawlChannels {
+	crawl_async(folders, vec![], parallel_file_loaders, options, stop, pause, counters)
+}
+
+/// Like crawl_globs_async, but the given files are loaded first, each with its own options, before any folders are walked.
+/// Files that are gone or can't be read are sent to the failure channel.
+pub fn crawl_async(mut folders:Vec<CrawlFolder>, files:Vec<(PathBuf, CrawlOptions)>, parallel_file_loaders:usize, options:CrawlOptions, stop:Arc<AtomicBool>, pause:Arc<AtomicBool>, counters:Arc<CrawlCounters>) -> CrawlChannels {
 
-	let (file_tx, file_rx) = unbounded();
-	let (image_tx, image_rx) = unbounded();
+	let (file_tx, file_rx) = bounded(MAX_PENDING_FILEPATHS);
+	let (image_tx, image_rx) = bounded(MAX_PENDING_IMAGES);
+	let (failure_tx, failure_rx) = unbounded(); // Just paths and messages, and the storage thread takes them as they come.
 
 	// TODO: A bloom filter to make sure we don't reprocess any images we have already.
 
+	let throttle = Arc::new(Throttle::new(options.max_files_per_second));
+	let low_priority = options.low_priority;
+	let globs: Arc<Vec<String>> = Arc::new(folders.iter().map(|folder| folder.glob.clone()).collect());
+	let mut threads = vec![];
+
 	// Crawling Thread.
 	{
 		let tx = file_tx.clone();
-		std::thread::spawn(move || {
+		let failures = failure_tx.clone();
+		let stop = stop.clone();
+		let pause = pause.clone();
+		let counters = counters.clone();
+		let crawler = std::thread::spawn(move || {
 			println!("Crawler reporting for duty.");
-			for mut g in globs {
-				g.push(std::path::MAIN_SEPARATOR);
-				g.push_str("**");
-				g.push(std::path::MAIN_SEPARATOR);
-				g.push_str("*.*");
-				for maybe_fname in glob(&g).expect("Failed to interpret glob pattern.") {
-					match maybe_fname {
-						Ok(path) => {
-							println!("Checking {}", stringify_filepath(&path));
-							if path.is_file() {
-								if let Err(e) = tx.send(path) {
-									eprintln!("Failed to submit image for processing: {}", e);
+			if low_priority {
+				lower_thread_priority();
+			}
+			// The sort is stable, so folders with the same priority keep their order.
+			folders.sort_by_key(|folder| std::cmp::Reverse(folder.priority));
+			let mut network_folders: HashMap<PathBuf, bool> = HashMap::new();
+			for (path, file_options) in files {
+				wait_while_paused(&pause, &stop);
+				if stop.load(Ordering::Relaxed) {
+					return;
+				}
+				let folder = path.parent().unwrap_or(&path).to_path_buf();
+				let file_options = match network_folders.get(&folder) {
+					Some(&on_network) => patient_if(on_network, &file_options),
+					None => {
+						let on_network = is_on_network_share(&folder, &file_options, &stop);
+						network_folders.insert(folder, on_network);
+						patient_if(on_network, &file_options)
+					},
+				};
+				let probe = path.clone();
+				match read_patiently(&file_options, &stop, move || probe.metadata()) {
+					Ok(metadata) if metadata.is_file() => {
+						if !file_options.wants_size(metadata.len()) && !file_options.reads_entries(&path) {
+							continue;
+						}
+						counters.files_found.fetch_add(1, Ordering::Relaxed);
+						if !send_unless_stopped(&tx, (path, file_options), &stop) {
+							return;
+						}
+					},
+					Ok(_) => {
+						let _ = failures.send((stringify_filepath(&path), "It isn't a file any more.".to_string()));
+					},
+					Err(e) => {
+						let _ = failures.send((stringify_filepath(&path), e.to_string()));
+					},
+				}
+			}
+			let mut found: Vec<(i64, Option<SystemTime>, PathBuf, CrawlOptions)> = vec![];
+			for folder in folders {
+				let root = PathBuf::from(&folder.glob);
+				// The engine checks patterns before they're saved, so any that don't parse are from somewhere else and are skipped.
+				let ignore_patterns: Vec<(String, Pattern)> = folder.ignore_patterns.iter().filter_map(|p| Pattern::new(p).ok().map(|pattern| (p.clone(), pattern))).collect();
+				let priority = folder.priority;
+				let folder_options = folder.crawl_options(&options);
+				// The folder's glob can match more than one folder, and each is walked in turn.  Files it matches are checked like the files in a folder.
+				let matches = match expand_glob(&folder.glob, &options, &stop) {
+					Ok(matches) => matches,
+					Err(e) => {
+						if is_unreachable(&e) {
+							mark_unreachable(&counters, &folder.glob, &e);
+						}
+						continue;
+					},
+				};
+				for (dir, is_file) in matches {
+					// Checked once for each folder rather than each file, since watching a read for the timeout takes a thread of its own.
+					let dir_options = patient_if(is_on_network_share(&dir, &folder_options, &stop), &folder_options);
+					let mut unreachable = None;
+					let mut visit = |entry: std::result::Result<PathBuf, (PathBuf, std::io::Error)>| {
+						wait_while_paused(&pause, &stop);
+						if stop.load(Ordering::Relaxed) {
+							return false;
+						}
+						match entry {
+							Ok(path) => {
+								if is_skipped(&path, &root, &folder, &ignore_patterns) {
+									return true;
+								}
+								let metadata = path.metadata().ok();
+								if metadata.as_ref().is_some_and(|m| !options.wants_size(m.len())) && !options.reads_entries(&path) {
+									return true;
 								}
+								println!("Checking {}", stringify_filepath(&path));
+								counters.files_found.fetch_add(1, Ordering::Relaxed);
+								if options.newest_first {
+									let modified = metadata.and_then(|m| m.modified().ok());
+									found.push((priority, modified, path, dir_options.clone()));
+									true
+								} else {
+									send_unless_stopped(&tx, (path, dir_options.clone()), &stop)
+								}
+							},
+							Err((_, e)) if is_unreachable(&e) => {
+								// The rest of the share would fail the same way, so it's skipped with one note instead of a failure for every folder in it.
+								unreachable = Some(e);
+								false
+							},
+							Err((path, e)) => {
+								if !is_ignored(&path, &root, &ignore_patterns) {
+									let _ = failures.send((stringify_filepath(&path), e.to_string()));
+								}
+								true
 							}
-						},
-						Err(e) => eprintln!("Failed to match glob: {}", e)
+						}
+					};
+					let keep_going = if is_file {
+						visit(Ok(dir))
+					} else {
+						walk_folder(&dir, folder.recursive, folder.follow_symlinks, &dir_options, &stop, &mut vec![], &mut visit)
+					};
+					if let Some(e) = unreachable {
+						mark_unreachable(&counters, &folder.glob, &e);
+						break;
+					}
+					if !keep_going {
+						return;
 					}
 				}
 			}
+			counters.finished_finding.store(true, Ordering::Relaxed);
+			// Newest first within each priority.  Files without a modification time go last.
+			found.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)));
+			for (_, _, path, folder_options) in found {
+				wait_while_paused(&pause, &stop);
+				if stop.load(Ordering::Relaxed) {
+					return;
+				}
+				if !send_unless_stopped(&tx, (path, folder_options), &stop) {
+					return;
+				}
+			}
 			drop(tx);
 		});
+		threads.push(crawler);
 	}
 
 	// Image Processing Thread.
 	for _ in 0..parallel_file_loaders {
 		let rx = file_rx.clone();
 		let tx = image_tx.clone();
-		std::thread::spawn(move || {
-			while let Ok(file_path) = rx.recv() {
-				// File path is any generic file, not necessarily an image file.
-				// We need to check if it's an image, a zip file, or something else.
-				if let Some(extension) = file_path.extension().and_then(OsStr::to_str) {
-					let mut is_image_file = false;
-					for &ext in SUPPORTED_IMAGE_EXTENSIONS {
-						if extension.eq_ignore_ascii_case(ext) {
-							is_image_file = true;
-						}
-					}
+		let failures = failure_tx.clone();
+		let stop = stop.clone();
+		let pause = pause.clone();
+		let counters = counters.clone();
+		let throttle = throttle.clone();
+		let globs = globs.clone();
+		let loader = std::thread::spawn(move || {
+			if low_priority {
+				lower_thread_priority();
+			}
+			while let Ok((file_path, options)) = rx.recv() {
+				wait_while_paused(&pause, &stop);
+				throttle.wait(&stop);
+				if stop.load(Ordering::Relaxed) {
+					break;
+				}
+				let folder = crawled_folder(&file_path, &globs);
+				if !counters.unreachable.lock().contains(&folder) {
+					process_file(&file_path, &folder, options, &tx, &failures, &stop, &counters);
+				}
+				counters.files_processed.fetch_add(1, Ordering::Relaxed);
+			}
+		});
+		threads.push(loader);
+	}
+	counters.threads.lock().extend(threads);
 
-					if is_image_file {
-						match IndexedImage::from_file_path(&file_path.as_path()) {
-							Ok(img) => {
-								tx.send(img);
-							},
-							Err(e) => {
-								println!("Error processing {}: {}", file_path.display(), e);
-							}
-						}
+	(file_rx, image_rx, failure_rx)
+}
+
+/// Watches folders for files and folders that are made, changed, moved, or deleted.  Watching stops when it's dropped.
+pub struct FolderWatcher {
+	_watcher: Option<Box<dyn Send>>, // None if the changes come from somewhere else.  See from_events.
+	changes: Receiver<Vec<PathBuf>>,
+}
+
+impl FolderWatcher {
+	/// Watch the folders, and their subfolders if they're recursive.  Folders that can't be watched, like ones that are gone, are skipped.
+	pub fn new(folders:&[CrawlFolder]) -> Result<FolderWatcher> {
+		let (event_tx, event_rx) = unbounded();
+		let watcher = watch_folders(folders, event_tx)?;
+		Ok(FolderWatcher { _watcher: Some(watcher), changes: debounce_changes(event_rx, WATCH_DEBOUNCE) })
+	}
+
+	/// Watch nothing, and report the paths sent to events as changes instead.
+	pub fn from_events(events:Receiver<Vec<PathBuf>>, debounce:Duration) -> FolderWatcher {
+		FolderWatcher { _watcher: None, changes: debounce_changes(events, debounce) }
+	}
+
+	/// Batches of changed paths as they settle, for waiting on them.  take_changes gathers up the ones waiting.
+	pub fn changes(&self) -> &Receiver<Vec<PathBuf>> {
+		&self.changes
+	}
+
+	/// Every path that's changed and settled since the last call, once each.  Paths that are gone were deleted or moved away.
+	pub fn take_changes(&self) -> Vec<PathBuf> {
+		let mut changes: Vec<PathBuf> = self.changes.try_iter().flatten().collect();
+		changes.sort();
+		changes.dedup();
+		changes
+	}
+}
+
+#[cfg(feature = "watch")]
+fn watch_folders(folders:&[CrawlFolder], events:Sender<Vec<PathBuf>>) -> Result<Box<dyn Send>> {
+	use notify::Watcher;
+	let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+		match event {
+			// Files being opened and read aren't changes.
+			Ok(event) if event.kind.is_access() => {},
+			Ok(event) => {
+				let _ = events.send(event.paths);
+			},
+			Err(e) => eprintln!("Error while watching folders: {}", e),
+		}
+	})?;
+	for folder in folders {
+		let mode = if folder.recursive { notify::RecursiveMode::Recursive } else { notify::RecursiveMode::NonRecursive };
+		if let Err(e) = watcher.watch(Path::new(&folder.glob), mode) {
+			eprintln!("Failed to watch {}: {}", folder.glob, e);
+		}
+	}
+	Ok(Box::new(watcher))
+}
+
+#[cfg(not(feature = "watch"))]
+fn watch_folders(_folders:&[CrawlFolder], _events:Sender<Vec<PathBuf>>) -> Result<Box<dyn Send>> {
+	Err(anyhow!("This build can't watch folders.  Rebuild with --features watch."))
+}
+
+/// Gather the paths from events into batches, sending each once no more have come for debounce, or once its first path has waited MAX_WATCH_DELAY.
+/// Stops when events is closed.
+fn debounce_changes(events:Receiver<Vec<PathBuf>>, debounce:Duration) -> Receiver<Vec<PathBuf>> {
+	let (changes_tx, changes_rx) = unbounded();
+	std::thread::spawn(move || {
+		let mut pending: Vec<PathBuf> = vec![];
+		let mut first_change = Instant::now();
+		loop {
+			let received = if pending.is_empty() {
+				events.recv().map_err(|_| RecvTimeoutError::Disconnected)
+			} else {
+				events.recv_timeout(debounce.min(MAX_WATCH_DELAY.saturating_sub(first_change.elapsed())))
+			};
+			let settled = match received {
+				Ok(paths) => {
+					if pending.is_empty() {
+						first_change = Instant::now();
+					}
+					pending.extend(paths);
+					first_change.elapsed() >= MAX_WATCH_DELAY
+				},
+				Err(RecvTimeoutError::Timeout) => true,
+				Err(RecvTimeoutError::Disconnected) => {
+					if !pending.is_empty() {
+						let _ = changes_tx.send(pending);
 					}
-				} // Else we have to skip it.  No extension.
+					return;
+				},
+			};
+			if settled && changes_tx.send(std::mem::take(&mut pending)).is_err() {
+				return;
+			}
+		}
+	});
+	changes_rx
+}
+
+/// Send every file in dir, and in its subfolders if recursive, to found, until it returns false.  Returns false if it did.
+/// Folders that can't be read are sent with why, after read_patiently has given them a chance to answer.
+/// Linked files and folders are skipped unless follow_symlinks is set, and hidden ones if options.skip_hidden is.
+/// A link is never followed back into a folder it's inside of, so links that loop end instead of being walked around forever.
+fn walk_folder(dir:&Path, recursive:bool, follow_symlinks:bool, options:&CrawlOptions, stop:&AtomicBool, ancestors:&mut Vec<FolderId>, found:&mut dyn FnMut(std::result::Result<PathBuf, (PathBuf, std::io::Error)>) -> bool) -> bool {
+	let reading = dir.to_path_buf();
+	let (id, mut paths) = match read_patiently(options, stop, move || {
+		let id = folder_id(&reading)?;
+		// Read in full here, so a share that stops answering partway through the list is given up on too.
+		let paths: Vec<PathBuf> = std::fs::read_dir(&reading)?.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect();
+		Ok((id, paths))
+	}) {
+		Ok(read) => read,
+		Err(e) => return found(Err((dir.to_path_buf(), e))),
+	};
+	if ancestors.contains(&id) {
+		return true;
+	}
+	// Sorted, so folders are crawled in the same order every time.
+	paths.sort();
+	ancestors.push(id);
+	let mut keep_going = true;
+	for path in paths {
+		if (!follow_symlinks && path.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false)) || (options.skip_hidden && is_hidden_file(&path)) {
+			continue;
+		}
+		keep_going = if path.is_dir() {
+			!recursive || walk_folder(&path, recursive, follow_symlinks, options, stop, ancestors, found)
+		} else if path.is_file() {
+			found(Ok(path))
+		} else {
+			true
+		};
+		if !keep_going {
+			break;
+		}
+	}
+	ancestors.pop();
+	keep_going
+}
+
+/// The folders and files a glob matches, and whether each is a file.  A glob that's just a path and matches nothing says why, so a share that's down isn't taken for a folder that was deleted.
+fn expand_glob(pattern:&str, options:&CrawlOptions, stop:&AtomicBool) -> std::io::Result<Vec<(PathBuf, bool)>> {
+	let pattern = pattern.to_string();
+	read_patiently(options, stop, move || {
+		let matches: Vec<(PathBuf, bool)> = glob(&pattern).expect("Failed to interpret glob pattern.").filter_map(|maybe_match| maybe_match.ok()).map(|path| {
+			let is_file = path.is_file();
+			(path, is_file)
+		}).collect();
+		if matches.is_empty() && Pattern::escape(&pattern) == pattern {
+			std::fs::metadata(&pattern)?;
+		}
+		Ok(matches)
+	})
+}
+
+/// Run read on a thread of its own, and give up on it if it hasn't finished after options.io_timeout.
+/// A read that's stuck on a share that's gone can't be interrupted, so its thread is left to finish whenever it does.
+/// Reads that fail like the share is asleep or gone are tried up to options.io_retries more times, waiting longer each time for it to wake up.
+fn read_patiently<T: Send + 'static>(options:&CrawlOptions, stop:&AtomicBool, read:impl Fn() -> std::io::Result<T> + Send + Sync + 'static) -> std::io::Result<T> {
+	let read = Arc::new(read);
+	let mut tries = 0;
+	loop {
+		let result = if options.io_timeout.is_zero() {
+			read()
+		} else {
+			let (tx, rx) = bounded(1);
+			let read = read.clone();
+			std::thread::spawn(move || {
+				let _ = tx.send(read());
+			});
+			match rx.recv_timeout(options.io_timeout) {
+				Ok(result) => result,
+				Err(RecvTimeoutError::Timeout) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("It didn't answer in {:.1} seconds.", options.io_timeout.as_secs_f64()))),
+				Err(RecvTimeoutError::Disconnected) => Err(std::io::Error::other("Reading it crashed.")),
 			}
+		};
+		match result {
+			Err(e) if tries < options.io_retries && is_unreachable(&e) && !stop.load(Ordering::Relaxed) => {
+				tries += 1;
+				let retry_at = Instant::now() + RETRY_DELAY * tries;
+				while !stop.load(Ordering::Relaxed) && Instant::now() < retry_at {
+					std::thread::sleep(PAUSE_POLL_INTERVAL);
+				}
+			},
+			result => return result,
+		}
+	}
+}
+
+/// True if the error looks like the share a file is on is asleep or gone, rather than something being wrong with the file itself.
+fn is_unreachable(e:&std::io::Error) -> bool {
+	use std::io::ErrorKind::*;
+	if matches!(e.kind(), TimedOut | HostUnreachable | NetworkUnreachable | NetworkDown | NotConnected | ConnectionReset | ConnectionAborted | StaleNetworkFileHandle) {
+		return true;
+	}
+	#[cfg(unix)]
+	if e.raw_os_error() == Some(libc::EHOSTDOWN) {
+		return true;
+	}
+	#[cfg(windows)]
+	{
+		// ERROR_BAD_NETPATH, ERROR_UNEXP_NET_ERR, ERROR_NETNAME_DELETED, ERROR_BAD_NET_NAME, and ERROR_SEM_TIMEOUT.
+		const NETWORK_ERRORS: &[i32] = &[53, 59, 64, 67, 121];
+		if e.raw_os_error().is_some_and(|code| NETWORK_ERRORS.contains(&code)) {
+			return true;
+		}
+	}
+	false
+}
+
+/// Add the folder to counters.unreachable, once.
+fn mark_unreachable(counters:&CrawlCounters, folder:&str, e:&std::io::Error) {
+	let mut unreachable = counters.unreachable.lock();
+	if !unreachable.iter().any(|f| f == folder) {
+		eprintln!("Skipping {} until the next crawl.  It couldn't be reached: {}", folder, e);
+		unreachable.push(folder.to_string());
+	}
+}
+
+/// The folder glob the file was found under, for noting it in counters.unreachable.
+fn crawled_folder(path:&Path, globs:&[String]) -> String {
+	// Which folder a glob with wildcards matched can't be told from the path, so the file's own folder stands in for it.
+	globs.iter().filter(|glob| path.starts_with(glob.as_str())).max_by_key(|glob| glob.len()).cloned()
+		.unwrap_or_else(|| path.parent().unwrap_or(path).display().to_string())
+}
+
+/// The options for reading from a folder: as they are if it's on a network share, and without io_timeout if it isn't.
+fn patient_if(on_network:bool, options:&CrawlOptions) -> CrawlOptions {
+	if on_network {
+		options.clone()
+	} else {
+		CrawlOptions { io_timeout: Duration::ZERO, ..options.clone() }
+	}
+}
+
+/// True if the folder might be on a network share.  Asking can hang on a share that's gone too, so it's asked patiently, and anything that doesn't answer counts.
+fn is_on_network_share(dir:&Path, options:&CrawlOptions, stop:&AtomicBool) -> bool {
+	if options.io_timeout.is_zero() {
+		return false;
+	}
+	let dir = dir.to_path_buf();
+	read_patiently(options, stop, move || Ok(is_network_folder(&dir))).unwrap_or(true)
+}
+
+/// True if the folder is on a network filesystem.  On systems where that can't be told, no folder is, so reading them is never timed out.
+fn is_network_folder(dir:&Path) -> bool {
+	#[cfg(target_os = "linux")]
+	{
+		use std::os::unix::ffi::OsStrExt;
+		let Ok(path) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+			return true;
+		};
+		let mut stats: libc::statfs = unsafe { std::mem::zeroed() };
+		if unsafe { libc::statfs(path.as_ptr(), &mut stats) } != 0 {
+			return true;
+		}
+		NETWORK_FILESYSTEMS.contains(&(stats.f_type as u32))
+	}
+	#[cfg(windows)]
+	{
+		use std::os::windows::ffi::OsStrExt;
+		#[link(name = "kernel32")]
+		extern "system" {
+			fn GetDriveTypeW(root: *const u16) -> u32;
+		}
+		const DRIVE_REMOTE: u32 = 4;
+		// The drive, like C:\, or the share, like \\server\share\.
+		let root: Vec<u16> = match dir.components().next() {
+			Some(std::path::Component::Prefix(prefix)) => prefix.as_os_str().encode_wide().chain("\\".encode_utf16()).chain([0]).collect(),
+			_ => return true,
+		};
+		unsafe { GetDriveTypeW(root.as_ptr()) == DRIVE_REMOTE }
+	}
+	#[cfg(not(any(target_os = "linux", windows)))]
+	{
+		let _ = dir;
+		false
+	}
+}
+
+/// True if the path, or any folder it's in below root, is hidden.
+pub fn is_hidden(path:&Path, root:&Path) -> bool {
+	path.ancestors().take_while(|p| p.starts_with(root) && *p != root).any(is_hidden_file)
+}
+
+/// True if the file or folder's name starts with a dot, or on Windows, if it has the hidden attribute.
+fn is_hidden_file(path:&Path) -> bool {
+	if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')) {
+		return true;
+	}
+	#[cfg(windows)]
+	{
+		use std::os::windows::fs::MetadataExt;
+		const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+		if path.symlink_metadata().is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0) {
+			return true;
+		}
+	}
+	false
+}
+
+/// What a folder is, however it's linked to.  Every path to the same folder has the same id.
+#[cfg(unix)]
+type FolderId = (u64, u64);
+#[cfg(not(unix))]
+type FolderId = PathBuf;
+
+#[cfg(unix)]
+fn folder_id(dir:&Path) -> std::io::Result<FolderId> {
+	use std::os::unix::fs::MetadataExt;
+	let metadata = dir.metadata()?;
+	Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn folder_id(dir:&Path) -> std::io::Result<FolderId> {
+	dir.canonicalize()
+}
+
+/// True if the path matches one of the patterns.  Like a .gitignore, patterns with a slash in them, like **/node_modules/**, are matched against the path from root.
+/// Patterns without one, like *.tmp or .thumbnails, are matched against the name of each file and folder in that path.
+fn is_ignored(path:&Path, root:&Path, patterns:&[(String, Pattern)]) -> bool {
+	let relative = path.strip_prefix(root).unwrap_or(path);
+	patterns.iter().any(|(text, pattern)| {
+		if text.contains('/') {
+			pattern.matches_path(relative)
+		} else {
+			relative.components().any(|component| pattern.matches(&component.as_os_str().to_string_lossy()))
+		}
+	})
+}
+
+/// True if a folder's settings leave out the file at path: it's ignored, doesn't have one of the extensions, or is linked to when links aren't followed.
+fn is_skipped(path:&Path, root:&Path, folder:&CrawlFolder, ignore_patterns:&[(String, Pattern)]) -> bool {
+	is_ignored(path, root, ignore_patterns) || !has_extension(path, &folder.extensions) || (!folder.follow_symlinks && is_symlinked(path, root))
+}
+
+/// True if there's no list of extensions, or the path has one of them.
+fn has_extension(path:&Path, extensions:&[String]) -> bool {
+	extensions.is_empty() || path.extension().and_then(OsStr::to_str).map(|ext| extensions.iter().any(|allowed| ext.eq_ignore_ascii_case(allowed))).unwrap_or(false)
+}
+
+/// True if the path, or any folder it's in below root, is a symbolic link.
+fn is_symlinked(path:&Path, root:&Path) -> bool {
+	let is_link = |p: &Path| p.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false);
+	is_link(path) || path.ancestors().skip(1).take_while(|p| p.starts_with(root) && *p != root).any(is_link)
+}
+
+/// Load the images in one file and send them to be stored, or the reason they couldn't be loaded to failures.
+/// If the file's share stops answering, folder, the one it was found under, is marked unreachable instead.
+fn process_file(file_path:&Path, folder:&str, options:CrawlOptions, tx:&Sender<IndexedImage>, failures:&Sender<(String, String)>, stop:&AtomicBool, counters:&CrawlCounters) {
+	// File path is any generic file, not necessarily an image file.
+	// We need to check if it's an image, a zip file, or something else.
+	// Whatever it is, excluded files are skipped before anything in them is decoded.
+	if options.exclusions.excludes(file_path) {
+		return;
+	}
+	// Emails and chat exports aren't images themselves, but the images in them are.
+	if mail::is_mail_file(file_path.file_name().and_then(OsStr::to_str).unwrap_or("")) {
+		if options.skip_archives {
+			return;
+		}
+		if let Err(e) = send_mail_images(file_path, options, tx, failures, stop) {
+			let _ = failures.send((stringify_filepath(file_path), e.to_string()));
+			counters.failed.fetch_add(1, Ordering::Relaxed);
+		}
+		return;
+	}
+
+	// Neither are zips, tars, and 7zs.
+	if let Some(format) = archive::ArchiveFormat::from_filename(&file_path.to_string_lossy()) {
+		if options.skip_archives {
+			return;
+		}
+		if let Err(e) = send_archive_images(file_path, format, options, tx, failures, stop, counters) {
+			let _ = failures.send((stringify_filepath(file_path), e.to_string()));
+			counters.failed.fetch_add(1, Ordering::Relaxed);
+		}
+		return;
+	}
+
+	let read = if options.io_timeout.is_zero() {
+		read_image_file(file_path)
+	} else {
+		// Only reading the file is timed out, so a share that stops answering partway through one can't hold up the loader.
+		// Decoding it isn't.  A slow decode doesn't mean the share is gone.
+		let path = file_path.to_path_buf();
+		read_patiently(&options, stop, move || read_image_file(&path))
+	};
+	let loaded = match read {
+		Ok(read) => read.and_then(|read| decode_image(file_path, read, &options)),
+		Err(e) if is_unreachable(&e) => {
+			mark_unreachable(counters, folder, &e);
+			return;
+		},
+		Err(e) => Err(e.into()),
+	};
+	match loaded {
+		Ok(None) => return,
+		Ok(Some(img)) => {
+			let _ = tx.send(img);
+		},
+		Err(e) => {
+			let _ = failures.send((stringify_filepath(file_path), e.to_string()));
+			counters.failed.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+	let book_format = if options.index_book_pages && !options.skip_archives { book::BookFormat::from_filename(&file_path.to_string_lossy()) } else { None };
+	if let Some(format) = book_format {
+		if let Err(e) = send_book_pages(file_path, format, options, tx, failures, stop) {
+			let _ = failures.send((stringify_filepath(file_path), format!("Couldn't read the pages: {}", e)));
+		}
+	}
+}
+
+/// A file read in to be decoded.
+enum ReadImage {
+	NotAnImage,
+	Video, // FFmpeg reads videos itself as it decodes them, so only whether they can be found is checked.
+	Bytes(Vec<u8>),
+}
+
+/// Read in the file, if it's an image or video we can decode.
+/// The IO errors are the outer ones, so read_patiently can tell when a share is gone.  The inner ones are for files that aren't what they're named as.
+fn read_image_file(file_path:&Path) -> std::io::Result<Result<ReadImage>> {
+	let is_image = match is_image_file(file_path, file_path.extension().and_then(OsStr::to_str)) {
+		Ok(is_image) => is_image,
+		Err(e) => return match e.downcast::<std::io::Error>() {
+			Ok(e) => Err(e),
+			Err(e) => Ok(Err(e)),
+		},
+	};
+	Ok(Ok(if !is_image {
+		ReadImage::NotAnImage
+	} else if is_supported_video(file_path) {
+		std::fs::metadata(file_path)?;
+		ReadImage::Video
+	} else {
+		ReadImage::Bytes(std::fs::read(file_path)?)
+	}))
+}
+
+/// Decode what read_image_file read.  None if it isn't an image.
+fn decode_image(file_path:&Path, read:ReadImage, options:&CrawlOptions) -> Result<Option<IndexedImage>> {
+	Ok(match read {
+		ReadImage::NotAnImage => None,
+		ReadImage::Video => Some(IndexedImage::from_file_path_cropped(file_path, options.hash_cropped_frames, options.hash_animation_frames)?),
+		ReadImage::Bytes(mut bytes) => Some(IndexedImage::from_file_bytes(file_path, &mut bytes, options.hash_cropped_frames, options.hash_animation_frames)?),
+	})
+}
+
+/// True if the file might be an image or video we can read.  Names are trusted, except that images without an extension are told apart by their first bytes,
+/// and so is junk named like a format whose files always start the same way.  That's an error, so it's skipped without reading the rest of it.
+fn is_image_file(file_path:&Path, extension:Option<&str>) -> Result<bool> {
+	match extension {
+		_ if is_supported_video(file_path) => Ok(true),
+		Some(extension) if !is_supported_image(extension) => Ok(false),
+		// Compiled object files share OBJ's extension.  They aren't junk, just not models.
+		Some(extension) if extension.eq_ignore_ascii_case("obj") => Ok(mesh::looks_like_obj(&sniff::read_head(file_path)?)),
+		Some(extension) if !sniff::has_signature(extension) => Ok(true),
+		_ => {
+			// Whatever it starts like, it's decoded by what it is rather than what it's named.
+			let is_image = sniff::sniff_image_type(&sniff::read_head(file_path)?).is_some();
+			match extension {
+				Some(extension) if !is_image => Err(anyhow!("It's named like a {} file, but it doesn't start like any image.", extension.to_uppercase())),
+				_ => Ok(is_image),
+			}
+		}
+	}
+}
+
+/// True if the extension is one of the SUPPORTED_IMAGE_EXTENSIONS, in any case.
+fn is_supported_image(extension:&str) -> bool {
+	SUPPORTED_IMAGE_EXTENSIONS.iter().any(|ext| extension.eq_ignore_ascii_case(ext))
+}
+
+/// True for videos, if this build can read them.  Only files on disk can be, not ones in archives or mail.
+fn is_supported_video(file_path:&Path) -> bool {
+	video::SUPPORTED && video::is_video_file(&file_path.to_string_lossy())
+}
+
+/// Send item, waiting while the channel is full.  Gives up if stop is set or nothing's receiving any more.  Returns whether it was sent.
+fn send_unless_stopped<T>(tx:&Sender<T>, mut item:T, stop:&AtomicBool) -> bool {
+	loop {
+		match tx.send_timeout(item, PAUSE_POLL_INTERVAL) {
+			Ok(()) => return true,
+			Err(SendTimeoutError::Timeout(unsent)) if !stop.load(Ordering::Relaxed) => item = unsent,
+			Err(_) => return false,
+		}
+	}
+}
+
+/// Block while pause is set.  Returns right away once stop is set, so a paused crawl can still be shut down.
+pub fn wait_while_paused(pause:&AtomicBool, stop:&AtomicBool) {
+	while pause.load(Ordering::Relaxed) && !stop.load(Ordering::Relaxed) {
+		std::thread::sleep(PAUSE_POLL_INTERVAL);
+	}
+}
+
+/// Spaces out the files the loaders of a crawl start on, so no more than so many are loaded a second.
+struct Throttle {
+	interval: Option<Duration>, // None for no limit.
+	next_turn: Mutex<Instant>,
+}
+
+impl Throttle {
+	fn new(files_per_second:u32) -> Self {
+		let interval = if files_per_second > 0 { Some(Duration::from_secs(1) / files_per_second) } else { None };
+		Throttle { interval, next_turn: Mutex::new(Instant::now()) }
+	}
+
+	/// Block until it's this file's turn.  Returns right away once stop is set.
+	fn wait(&self, stop:&AtomicBool) {
+		let Some(interval) = self.interval else {
+			return;
+		};
+		let turn = {
+			let mut next_turn = self.next_turn.lock();
+			let turn = (*next_turn).max(Instant::now());
+			*next_turn = turn + interval;
+			turn
+		};
+		while !stop.load(Ordering::Relaxed) {
+			let now = Instant::now();
+			if now >= turn {
+				break;
+			}
+			std::thread::sleep((turn - now).min(PAUSE_POLL_INTERVAL));
+		}
+	}
+}
+
+/// Ask the OS to run this thread only when nothing else wants the CPU as much.
+/// Linux and Windows give threads priorities of their own.  Elsewhere priorities are for the whole app, so this does nothing rather than slowing all of it, and the throttle is all there is.
+fn lower_thread_priority() {
+	// Who is 0, so only this thread is changed.
+	#[cfg(target_os = "linux")]
+	if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, LOW_PRIORITY_NICENESS) } != 0 {
+		eprintln!("Failed to lower the priority of a crawl thread: {}", std::io::Error::last_os_error());
+	}
+	#[cfg(windows)]
+	{
+		#[link(name = "kernel32")]
+		extern "system" {
+			fn GetCurrentThread() -> *mut std::ffi::c_void;
+			fn SetThreadPriority(thread: *mut std::ffi::c_void, priority: i32) -> i32;
+		}
+		// Background mode lowers the thread's disk and memory priorities along with its CPU priority.
+		const THREAD_MODE_BACKGROUND_BEGIN: i32 = 0x00010000;
+		if unsafe { SetThreadPriority(GetCurrentThread(), THREAD_MODE_BACKGROUND_BEGIN) } == 0 {
+			eprintln!("Failed to lower the priority of a crawl thread: {}", std::io::Error::last_os_error());
+		}
+	}
+}
+
+/// Index every image in a book besides the cover, which was indexed as the book itself.
+fn send_book_pages(book_path:&Path, format:book::BookFormat, options:CrawlOptions, tx:&Sender<IndexedImage>, failures:&Sender<(String, String)>, stop:&AtomicBool) -> Result<()> {
+	let bytes = std::fs::read(book_path)?;
+	let info = book::read_book_info(format, &bytes)?;
+	let book_pathstring = stringify_filepath(book_path);
+	for page in info.pages.iter().filter(|&p| *p != info.cover) {
+		if stop.load(Ordering::Relaxed) {
+			break;
+		}
+		let path = archive::entry_path(&book_pathstring, page);
+		if options.exclusions.excludes_entry(&path) {
+			continue;
+		}
+		let filename = page.rsplit('/').next().unwrap_or(page).to_string();
+		let page_bytes = book::read_page(&bytes, page);
+		if matches!(&page_bytes, Ok(page_bytes) if !options.wants_size(page_bytes.len() as u64)) {
+			continue;
+		}
+		match page_bytes.and_then(|mut page_bytes| IndexedImage::from_memory_cropped(&mut page_bytes, filename, path.clone(), options.hash_cropped_frames, options.hash_animation_frames)) {
+			Ok(img) => {
+				tx.send(img)?;
+			},
+			Err(e) => {
+				let _ = failures.send((path, e.to_string()));
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Index every image attached to the messages in an email or chat export, tagged with who sent it and when.
+fn send_mail_images(mail_path:&Path, options:CrawlOptions, tx:&Sender<IndexedImage>, failures:&Sender<(String, String)>, stop:&AtomicBool) -> Result<()> {
+	let filename = mail_path.file_name().and_then(OsStr::to_str).unwrap_or("");
+	let bytes = std::fs::read(mail_path)?;
+	let mail_pathstring = stringify_filepath(mail_path);
+	let (attachments, undecodable) = mail::read_mail_images(filename, &bytes)?;
+	for (entry, e) in undecodable {
+		let _ = failures.send((archive::entry_path(&mail_pathstring, &entry), e));
+	}
+	for mut attachment in attachments {
+		if stop.load(Ordering::Relaxed) {
+			break;
+		}
+		let path = archive::entry_path(&mail_pathstring, &attachment.entry);
+		if options.exclusions.excludes_entry(&path) || !options.wants_size(attachment.bytes.len() as u64) {
+			continue;
+		}
+		match IndexedImage::from_memory_cropped(&mut attachment.bytes, attachment.filename.clone(), path.clone(), options.hash_cropped_frames, options.hash_animation_frames) {
+			Ok(mut img) => {
+				img.tags.extend(attachment.to_tags());
+				tx.send(img)?;
+			},
+			Err(e) => {
+				let _ = failures.send((path, e.to_string()));
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Index every image in a zip, tar, or 7z.  The other files in it are skipped.
+fn send_archive_images(archive_path:&Path, format:archive::ArchiveFormat, options:CrawlOptions, tx:&Sender<IndexedImage>, failures:&Sender<(String, String)>, stop:&AtomicBool, counters:&CrawlCounters) -> Result<()> {
+	let archive = BufReader::new(File::open(archive_path)?);
+	send_archive_entries(archive, format, &stringify_filepath(archive_path), options, &EntrySenders { tx, failures, counters }, stop)
+}
+
+/// Where the images in an archive are sent, and where the ones that can't be loaded are sent and counted.
+struct EntrySenders<'a> {
+	tx: &'a Sender<IndexedImage>,
+	failures: &'a Sender<(String, String)>,
+	counters: &'a CrawlCounters,
+}
+
+impl EntrySenders<'_> {
+	fn fail(&self, path:String, reason:String) {
+		let _ = self.failures.send((path, reason));
+		self.counters.failed.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+/// Index every image in an archive, and in the archives inside it down to options.max_archive_depth more levels.
+/// Images are indexed under the path of each archive they're in, like /scans.zip!/2019.tar!/001.png
+/// Each image or inner archive that can't be loaded is sent to failures and counted as failed.
+fn send_archive_entries(archive:impl Read + Seek, format:archive::ArchiveFormat, archive_pathstring:&str, options:CrawlOptions, senders:&EntrySenders, stop:&AtomicBool) -> Result<()> {
+	let is_image = |entry: &str| entry.rsplit_once('.').map(|(_, extension)| is_supported_image(extension)).unwrap_or(false);
+	let inner_format = |entry: &str| if options.max_archive_depth > 0 { archive::ArchiveFormat::from_filename(entry) } else { None };
+	archive::read_archive_files(format, archive, options.archive_password.as_deref(), |entry| is_image(entry) || inner_format(entry).is_some(), |entry, contents| {
+		if stop.load(Ordering::Relaxed) {
+			return false;
+		}
+		let path = archive::entry_path(archive_pathstring, entry);
+		if let Some(inner) = inner_format(entry) {
+			if let Err(e) = contents.and_then(|contents| send_archive_entries(Cursor::new(contents), inner, &path, CrawlOptions { max_archive_depth: options.max_archive_depth - 1, ..options.clone() }, senders, stop)) {
+				senders.fail(path, e.to_string());
+			}
+			return true;
+		}
+		if options.exclusions.excludes_entry(&path) || matches!(&contents, Ok(contents) if !options.wants_size(contents.len() as u64)) {
+			return true;
+		}
+		let filename = entry.rsplit('/').next().unwrap_or(entry).to_string();
+		// Compiled object files share OBJ's extension.  They aren't failures, just not models.
+		if matches!(&contents, Ok(contents) if mesh::MeshFormat::from_filename(&filename) == Some(mesh::MeshFormat::Obj) && !mesh::looks_like_obj(contents)) {
+			return true;
+		}
+		match contents.and_then(|mut contents| IndexedImage::from_memory_cropped(&mut contents, filename, path.clone(), options.hash_cropped_frames, options.hash_animation_frames)) {
+			// Nothing's storing them any more.
+			Ok(img) => senders.tx.send(img).is_ok(),
+			Err(e) => {
+				senders.fail(path, e.to_string());
+				true
+			}
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::crawler::*;
+
+	#[test]
+	fn test_pause_crawl() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_pause_crawl_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		std::fs::write(dir.join("a.png"), b"not really a png").unwrap();
+
+		// Without file loaders, the crawled paths stay in the queue for us to read.
+		let (stop, pause) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(true)));
+		let (file_rx, _image_rx, _failure_rx) = crawl_globs_async(vec![CrawlFolder { glob: dir.display().to_string(), ..Default::default() }], 0, CrawlOptions::default(), stop.clone(), pause.clone(), Arc::new(CrawlCounters::default()));
+		assert!(file_rx.recv_timeout(Duration::from_millis(300)).is_err());
+		pause.store(false, Ordering::Relaxed);
+		assert_eq!(file_rx.recv_timeout(Duration::from_secs(5)).unwrap().0, dir.join("a.png"));
+
+		// Stopping wakes anything that's paused.
+		pause.store(true, Ordering::Relaxed);
+		stop.store(true, Ordering::Relaxed);
+		wait_while_paused(&pause, &stop);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_ignore_patterns() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_ignore_patterns_{}", std::process::id()));
+		for path in ["a.png", "node_modules/b.png", "sub/c.tmp", "sub/.thumbnails/d.png", "sub/e.png"] {
+			std::fs::create_dir_all(dir.join(path).parent().unwrap()).unwrap();
+			std::fs::write(dir.join(path), b"not really a png").unwrap();
+		}
+		let folder = CrawlFolder {
+			glob: dir.display().to_string(),
+			ignore_patterns: vec!["**/node_modules/**".to_string(), "*.tmp".to_string(), ".thumbnails".to_string(), "[invalid".to_string()],
+			..Default::default()
+		};
+		let crawl = |folder: CrawlFolder| {
+			let (file_rx, _image_rx, failure_rx) = crawl_globs_async(vec![folder], 0, CrawlOptions::default(), Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)), Arc::new(CrawlCounters::default()));
+			let mut found: Vec<PathBuf> = file_rx.iter().map(|(path, _)| path.strip_prefix(&dir).unwrap().to_path_buf()).collect();
+			found.sort();
+			(found, failure_rx.iter().count())
+		};
+		let expected = vec![PathBuf::from("a.png"), Path::new("sub").join("e.png")];
+		assert_eq!(crawl(folder.clone()), (expected.clone(), 0));
+		// Files a glob matches are checked like the ones in the folders it matches, instead of being walked like folders.
+		assert_eq!(crawl(CrawlFolder { glob: dir.join("*").display().to_string(), ..folder }), (expected, 0));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_folder_options() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_folder_options_{}", std::process::id()));
+		for path in ["top.png", "top.JPG", "sub/deep.png"] {
+			std::fs::create_dir_all(dir.join(path).parent().unwrap()).unwrap();
+			std::fs::write(dir.join(path), b"not really a png").unwrap();
+		}
+		#[cfg(unix)]
+		std::os::unix::fs::symlink(dir.join("sub"), dir.join("link")).unwrap();
+
+		let crawl = |folder: CrawlFolder| {
+			let folder = CrawlFolder { glob: dir.display().to_string(), ..folder };
+			let (file_rx, _image_rx, _failure_rx) = crawl_globs_async(vec![folder], 0, CrawlOptions::default(), Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)), Arc::new(CrawlCounters::default()));
+			let mut found: Vec<(String, bool)> = file_rx.iter().map(|(path, options)| (path.strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/"), options.skip_archives)).collect();
+			found.sort();
+			found
+		};
+		let names = |found: Vec<(String, bool)>| found.into_iter().map(|(name, _)| name).collect::<Vec<String>>();
+		assert_eq!(names(crawl(CrawlFolder { recursive: false, extensions: vec!["jpg".to_string()], ..Default::default() })), vec!["top.JPG"]);
+		assert_eq!(names(crawl(CrawlFolder { follow_symlinks: false, ..Default::default() })), vec!["sub/deep.png", "top.JPG", "top.png"]);
+		let everything = crawl(CrawlFolder { scan_archives: false, ..Default::default() });
+		assert!(everything.iter().all(|(_, skip_archives)| *skip_archives));
+		#[cfg(unix)]
+		assert_eq!(names(everything), vec!["link/deep.png", "sub/deep.png", "top.JPG", "top.png"]);
+
+		// Watched changes are checked against the same options.
+		let folder = CrawlFolder { glob: dir.display().to_string(), recursive: false, ignore_patterns: vec!["*.JPG".to_string()], ..Default::default() };
+		assert!(folder.wants(&dir.join("top.png")));
+		assert!(!folder.wants(&dir.join("top.JPG")));
+		assert!(!folder.wants(&dir.join("sub")) && !folder.wants(&dir.join("sub/deep.png")));
+		assert!(!folder.wants(&dir) && !folder.wants(Path::new("/somewhere/else.png")));
+		let folder = CrawlFolder { recursive: true, ..folder };
+		assert!(folder.wants(&dir.join("sub")) && folder.wants(&dir.join("sub/deep.png")));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_skip_hidden() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_skip_hidden_{}", std::process::id()));
+		for path in ["a.png", ".b.png", ".cache/c.png", "sub/d.png", "sub/.git/e.png"] {
+			std::fs::create_dir_all(dir.join(path).parent().unwrap()).unwrap();
+			std::fs::write(dir.join(path), b"not really a png").unwrap();
+		}
+		let crawl = |skip_hidden: bool| {
+			let options = CrawlOptions { skip_hidden, ..Default::default() };
+			let (file_rx, _image_rx, _failure_rx) = crawl_globs_async(vec![CrawlFolder { glob: dir.display().to_string(), ..Default::default() }], 0, options, Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)), Arc::new(CrawlCounters::default()));
+			let mut found: Vec<String> = file_rx.iter().map(|(path, _)| path.strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/")).collect();
+			found.sort();
+			found
+		};
+		assert_eq!(crawl(true), vec!["a.png", "sub/d.png"]);
+		assert_eq!(crawl(false).len(), 5);
+
+		// Only what's below the folder counts, so a hidden folder can still be crawled itself.
+		assert!(is_hidden(&dir.join("sub/.git/e.png"), &dir));
+		assert!(!is_hidden(&dir.join("sub/d.png"), &dir));
+		assert!(!is_hidden(&dir.join(".cache/c.png"), &dir.join(".cache")));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_size_limits() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_size_limits_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		for (name, size) in [("icon.png", 10), ("photo.png", 100), ("huge.png", 1000)] {
+			std::fs::write(dir.join(name), vec![0u8; size]).unwrap();
+		}
+		let zip = archive::make_test_zip(&[("icon.png", &[0u8; 10]), ("photo.png", &[0u8; 100])]);
+		assert!(zip.len() > 200);
+		std::fs::write(dir.join("pack.zip"), &zip).unwrap();
+		let crawl = |min_file_size: u64, max_file_size: u64| {
+			let options = CrawlOptions { min_file_size, max_file_size, ..Default::default() };
+			let (file_rx, _image_rx, _failure_rx) = crawl_globs_async(vec![CrawlFolder { glob: dir.display().to_string(), ..Default::default() }], 0, options, Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)), Arc::new(CrawlCounters::default()));
+			let mut found: Vec<String> = file_rx.iter().map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_string()).collect();
+			found.sort();
+			found
+		};
+		// Archives are read whatever their size.
+		assert_eq!(crawl(0, 0), vec!["huge.png", "icon.png", "pack.zip", "photo.png"]);
+		assert_eq!(crawl(50, 0), vec!["huge.png", "pack.zip", "photo.png"]);
+		assert_eq!(crawl(50, 200), vec!["pack.zip", "photo.png"]);
+		assert_eq!(crawl(100, 100), vec!["pack.zip", "photo.png"]);
+		let options = CrawlOptions { min_file_size: 50, max_file_size: 200, skip_archives: true, ..Default::default() };
+		assert!(!options.reads_entries(&dir.join("pack.zip")));
+
+		// It's what's in them that's checked.  The one image big enough is tried, and fails, since it isn't really a png.
+		let (tx, image_rx) = unbounded();
+		let (failure_tx, failure_rx) = unbounded();
+		let options = CrawlOptions { min_file_size: 50, max_file_size: 200, ..Default::default() };
+		send_archive_images(&dir.join("pack.zip"), archive::ArchiveFormat::Zip, options, &tx, &failure_tx, &AtomicBool::new(false), &CrawlCounters::default()).unwrap();
+		drop((tx, failure_tx));
+		assert_eq!(image_rx.iter().count(), 0);
+		assert_eq!(failure_rx.iter().map(|(path, _)| path).collect::<Vec<String>>(), vec![archive::entry_path(&stringify_filepath(&dir.join("pack.zip")), "photo.png")]);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_is_image_file() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_is_image_file_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let mut png = vec![];
+		image::DynamicImage::new_rgb8(2, 2).write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+		for (name, bytes) in [("no extension", &png[..]), ("notes", b"Not an image."), ("junk.png", b"Not an image either."), ("really a png.jpg", &png[..]), ("odd.tga", b"TGAs can start with anything."), ("readme.txt", b"Text."), ("cube.obj", b"v 0 0 0\n"), ("main.obj", b"\x64\x86\x05\0\0\0\0\0")] {
+			std::fs::write(dir.join(name), bytes).unwrap();
+		}
+		let check = |name: &str| is_image_file(&dir.join(name), Path::new(name).extension().and_then(OsStr::to_str));
+		assert!(check("no extension").unwrap());
+		assert!(!check("notes").unwrap());
+		assert!(check("junk.png").unwrap_err().to_string().contains("PNG"));
+		assert!(check("really a png.jpg").unwrap());
+		assert!(check("odd.tga").unwrap());
+		assert!(!check("readme.txt").unwrap());
+		assert!(check("cube.obj").unwrap());
+		assert!(!check("main.obj").unwrap());
+
+		// Files without an extension are found, so they can be checked.
+		let (file_rx, _image_rx, _failure_rx) = crawl_globs_async(vec![CrawlFolder { glob: dir.display().to_string(), ..Default::default() }], 0, CrawlOptions::default(), Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)), Arc::new(CrawlCounters::default()));
+		assert_eq!(file_rx.iter().count(), 8);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_throttle() {
+		let stop = AtomicBool::new(false);
+		let unlimited = Throttle::new(0);
+		let started = Instant::now();
+		for _ in 0..100 {
+			unlimited.wait(&stop);
+		}
+		assert!(started.elapsed() < Duration::from_millis(50));
+
+		// The first file goes right away, and each after that waits its turn.
+		let throttle = Throttle::new(20);
+		let started = Instant::now();
+		for _ in 0..5 {
+			throttle.wait(&stop);
+		}
+		assert!(started.elapsed() >= Duration::from_millis(190));
+
+		let throttle = Throttle::new(1);
+		throttle.wait(&stop);
+		stop.store(true, Ordering::Relaxed);
+		let started = Instant::now();
+		throttle.wait(&stop);
+		assert!(started.elapsed() < Duration::from_millis(500));
+	}
+
+	#[test]
+	fn test_unreachable() {
+		let stop = AtomicBool::new(false);
+		let patient = CrawlOptions { io_timeout: Duration::from_millis(50), ..Default::default() };
+		let started = Instant::now();
+		let hung = read_patiently(&patient, &stop, || { std::thread::sleep(Duration::from_millis(500)); Ok(()) });
+		assert_eq!(hung.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+		assert!(started.elapsed() < Duration::from_millis(400));
+
+		// Shares that are down are tried again, and files that are just missing aren't.
+		let tries = Arc::new(AtomicU64::new(0));
+		let counted = tries.clone();
+		let retrying = CrawlOptions { io_retries: 1, ..Default::default() };
+		let woke_up = read_patiently(&retrying, &stop, move || match counted.fetch_add(1, Ordering::Relaxed) {
+			0 => Err(std::io::Error::from(std::io::ErrorKind::NotConnected)),
+			_ => Ok(()),
+		});
+		assert!(woke_up.is_ok());
+		assert_eq!(tries.load(Ordering::Relaxed), 2);
+		let counted = tries.clone();
+		let missing = read_patiently(&CrawlOptions { io_retries: 3, ..Default::default() }, &stop, move || {
+			counted.fetch_add(1, Ordering::Relaxed);
+			std::fs::metadata("/no/such/folder")
 		});
+		assert!(!is_unreachable(&missing.unwrap_err()));
+		assert_eq!(tries.load(Ordering::Relaxed), 3);
+
+		// Once a folder is unreachable, nothing more is loaded from it, and it's only noted once.
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_unreachable_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		std::fs::write(dir.join("a.png"), b"").unwrap();
+		let globs = vec![dir.display().to_string()];
+		let counters = CrawlCounters::default();
+		assert_eq!(crawled_folder(&dir.join("sub/a.png"), &globs), globs[0]);
+		assert_eq!(crawled_folder(Path::new("/elsewhere/a.png"), &globs), "/elsewhere");
+		let e = std::io::Error::from(std::io::ErrorKind::TimedOut);
+		mark_unreachable(&counters, &globs[0], &e);
+		mark_unreachable(&counters, &globs[0], &e);
+		assert_eq!(*counters.unreachable.lock(), globs);
+
+		// Only reading a file on a share is timed out.  A file that's read but can't be decoded is a failure, not a sign the share is gone.
+		std::fs::write(dir.join("junk.png"), b"not really a png").unwrap();
+		assert!(matches!(read_image_file(&dir.join("junk.png")), Ok(Err(_))));
+		assert!(read_image_file(&dir.join("missing.png")).is_err());
+		assert!(matches!(read_image_file(&dir.join("notes.txt")), Ok(Ok(ReadImage::NotAnImage))));
+		let counters = CrawlCounters::default();
+		let (tx, _image_rx) = unbounded();
+		let (failure_tx, failure_rx) = unbounded();
+		process_file(&dir.join("junk.png"), &globs[0], patient.clone(), &tx, &failure_tx, &stop, &counters);
+		assert_eq!(failure_rx.try_iter().count(), 1);
+		assert_eq!(counters.failed.load(Ordering::Relaxed), 1);
+		assert!(counters.unreachable.lock().is_empty());
+
+		// Only folders on network shares are read with the timeout.
+		#[cfg(target_os = "linux")]
+		assert!(!is_network_folder(&dir));
+		assert_eq!(patient_if(false, &patient).io_timeout, Duration::ZERO);
+		assert_eq!(patient_if(true, &patient), patient);
+		assert!(!is_on_network_share(&dir, &CrawlOptions::default(), &stop));
+
+		// A folder that's been deleted isn't unreachable, just empty.
+		let counters = Arc::new(CrawlCounters::default());
+		let never = || Arc::new(AtomicBool::new(false));
+		let (file_rx, _image_rx, _failure_rx) = crawl_globs_async(vec![CrawlFolder { glob: dir.join("gone").display().to_string(), ..Default::default() }], 0, patient, never(), never(), counters.clone());
+		assert_eq!(file_rx.iter().count(), 0);
+		assert!(counters.unreachable.lock().is_empty());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_symlink_loops() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_symlink_loops_{}", std::process::id()));
+		for path in ["top.png", "sub/deep.png"] {
+			std::fs::create_dir_all(dir.join(path).parent().unwrap()).unwrap();
+			std::fs::write(dir.join(path), b"not really a png").unwrap();
+		}
+		// Each link leads back up, so following them without checking would go around forever.
+		std::os::unix::fs::symlink(&dir, dir.join("sub/up")).unwrap();
+		std::os::unix::fs::symlink(dir.join("sub"), dir.join("sub/here")).unwrap();
+		std::os::unix::fs::symlink(dir.join("sub"), dir.join("across")).unwrap();
+		let (file_rx, _image_rx, _failure_rx) = crawl_globs_async(vec![CrawlFolder { glob: dir.display().to_string(), ..Default::default() }], 0, CrawlOptions::default(), Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)), Arc::new(CrawlCounters::default()));
+		let mut found: Vec<String> = file_rx.iter().map(|(path, _)| path.strip_prefix(&dir).unwrap().to_string_lossy().to_string()).collect();
+		found.sort();
+		assert_eq!(found, vec!["across/deep.png", "sub/deep.png", "top.png"]);
+		std::fs::remove_dir_all(&dir).unwrap();
 	}
 
-	(file_rx, image_rx)
-}
\ No newline at end of file
+	#[test]
+	fn test_folder_watcher() {
+		let (events, received) = unbounded();
+		let watcher = FolderWatcher::from_events(received, Duration::from_millis(200));
+		events.send(vec![PathBuf::from("/a.png"), PathBuf::from("/b.png")]).unwrap();
+		events.send(vec![PathBuf::from("/a.png")]).unwrap();
+		// Nothing's sent until the changes settle, and then each path is sent once.
+		assert!(watcher.take_changes().is_empty());
+		std::thread::sleep(Duration::from_millis(600));
+		assert_eq!(watcher.take_changes(), vec![PathBuf::from("/a.png"), PathBuf::from("/b.png")]);
+		assert!(watcher.take_changes().is_empty());
+		// What's waiting when the events end is still sent.
+		events.send(vec![PathBuf::from("/c.png")]).unwrap();
+		drop(events);
+		std::thread::sleep(Duration::from_millis(100));
+		assert_eq!(watcher.take_changes(), vec![PathBuf::from("/c.png")]);
+	}
+
+	#[test]
+	fn test_crawl_archives() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_crawl_archives_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let tar = archive::make_test_tar(&[("notes.txt", b"not an image"), ("scans/", b""), ("scans/a.png", b"not really a png"), ("scans/clip.mp4", b"not really a video")]);
+		let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+		std::io::Write::write_all(&mut encoder, &tar).unwrap();
+		std::fs::write(dir.join("scans.tar.gz"), encoder.finish().unwrap()).unwrap();
+		std::fs::write(dir.join("broken.7z"), b"not really a 7z").unwrap();
+		let deeper = archive::make_test_zip(&[("c.png", b"not really a png")]);
+		let inner = archive::make_test_tar(&[("b.png", b"not really a png"), ("deeper.zip", &deeper)]);
+		std::fs::write(dir.join("nested.zip"), archive::make_test_zip(&[("inner.tar", &inner), ("broken.tar", b"not really a tar")])).unwrap();
+
+		let crawl = |scan_archives: bool, max_archive_depth: u32| {
+			let counters = Arc::new(CrawlCounters::default());
+			let folder = CrawlFolder { glob: dir.display().to_string(), scan_archives, ..Default::default() };
+			let options = CrawlOptions { max_archive_depth, ..Default::default() };
+			let (_file_rx, image_rx, failure_rx) = crawl_globs_async(vec![folder], 1, options, Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)), counters.clone());
+			assert_eq!(image_rx.iter().count(), 0);
+			let mut failures: Vec<String> = failure_rx.try_iter().map(|(path, _)| path).collect();
+			failures.sort();
+			(failures, counters.failed.load(Ordering::Relaxed))
+		};
+		// The images in an archive are loaded from it, and the rest of its files are skipped.  So are its videos, since they have to be read from disk.
+		assert_eq!(is_supported_video(Path::new("clip.MP4")), video::SUPPORTED);
+		let tar_path = stringify_filepath(&dir.join("scans.tar.gz"));
+		let broken_7z = stringify_filepath(&dir.join("broken.7z"));
+		assert_eq!(crawl(true, 0), (vec![broken_7z.clone(), archive::entry_path(&tar_path, "scans/a.png")], 2));
+		assert_eq!(crawl(false, 2), (vec![], 0));
+
+		// Archives inside archives are looked in as deep as they're allowed to be, and their images get a path through each of them.
+		let nested_path = stringify_filepath(&dir.join("nested.zip"));
+		let inner_path = archive::entry_path(&nested_path, "inner.tar");
+		let deepest = archive::entry_path(&archive::entry_path(&inner_path, "deeper.zip"), "c.png");
+		assert_eq!(deepest, format!("{}!/inner.tar!/deeper.zip!/c.png", nested_path));
+		let mut expected = vec![broken_7z, archive::entry_path(&tar_path, "scans/a.png"), archive::entry_path(&nested_path, "broken.tar"), archive::entry_path(&inner_path, "b.png")];
+		expected.sort();
+		// An archive inside one that can't be read is a failure like any other entry.
+		assert_eq!(crawl(true, 1), (expected.clone(), 4));
+		expected.push(deepest);
+		expected.sort();
+		assert_eq!(crawl(true, 2), (expected, 5));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_crawl_backpressure() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_crawl_backpressure_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		for i in 0..MAX_PENDING_FILEPATHS + 5 {
+			std::fs::write(dir.join(format!("{}.png", i)), b"not really a png").unwrap();
+		}
+		let crawl = |stop: Arc<AtomicBool>, counters: Arc<CrawlCounters>| {
+			let (file_rx, _image_rx, _failure_rx) = crawl_globs_async(vec![CrawlFolder { glob: dir.display().to_string(), ..Default::default() }], 0, CrawlOptions::default(), stop, Arc::new(AtomicBool::new(false)), counters);
+			// Nothing's loading the files, so the crawl waits once the queue is full.
+			std::thread::sleep(Duration::from_millis(500));
+			file_rx
+		};
+
+		let counters = Arc::new(CrawlCounters::default());
+		let file_rx = crawl(Arc::new(AtomicBool::new(false)), counters.clone());
+		assert_eq!(file_rx.len(), MAX_PENDING_FILEPATHS);
+		assert!(!counters.finished_finding.load(Ordering::Relaxed));
+		// And carries on as they're taken.
+		assert_eq!(file_rx.iter().count(), MAX_PENDING_FILEPATHS + 5);
+		assert!(counters.finished_finding.load(Ordering::Relaxed));
+
+		// Stopping doesn't wait for room.  The crawl gives up, so the queue ends without the rest.
+		let stop = Arc::new(AtomicBool::new(false));
+		let file_rx = crawl(stop.clone(), Arc::new(CrawlCounters::default()));
+		stop.store(true, Ordering::Relaxed);
+		std::thread::sleep(Duration::from_millis(300));
+		assert_eq!(file_rx.iter().count(), MAX_PENDING_FILEPATHS);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_newest_first() {
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_newest_first_{}", std::process::id()));
+		let (low, high) = (dir.join("low"), dir.join("high"));
+		std::fs::create_dir_all(&low).unwrap();
+		std::fs::create_dir_all(&high).unwrap();
+		let now = SystemTime::now();
+		for (path, age) in [(low.join("newest.png"), 0), (high.join("old.png"), 300), (high.join("new.png"), 60)] {
+			std::fs::write(&path, b"not really a png").unwrap();
+			File::options().write(true).open(&path).unwrap().set_modified(now - Duration::from_secs(age)).unwrap();
+		}
+
+		let counters = Arc::new(CrawlCounters::default());
+		let crawl = |newest_first: bool| {
+			let options = CrawlOptions { newest_first, ..Default::default() };
+			let folders = vec![CrawlFolder { glob: low.display().to_string(), priority: 0, ..Default::default() }, CrawlFolder { glob: high.display().to_string(), priority: 5, ..Default::default() }];
+			let (file_rx, _image_rx, _failure_rx) = crawl_globs_async(folders, 0, options, Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)), counters.clone());
+			file_rx.iter().map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_string()).collect::<Vec<String>>()
+		};
+		assert_eq!(crawl(true), vec!["new.png", "old.png", "newest.png"]);
+		assert_eq!(counters.files_found.load(Ordering::Relaxed), 3);
+		assert!(counters.finished_finding.load(Ordering::Relaxed));
+		assert_eq!(counters.files_processed.load(Ordering::Relaxed), 0); // Nothing was loading them.
+		// The higher priority folder is still crawled first, but in whatever order it's walked in.
+		assert_eq!(crawl(false)[2], "newest.png");
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
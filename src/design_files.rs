@@ -0,0 +1,618 @@
+// Layered working files from design tools: Photoshop (PSD and PSB), GIMP (XCF), and Krita (KRA).
+// Each is indexed by its flattened image.  Photoshop and Krita save one alongside the layers.
+// GIMP doesn't, so the visible layers are composited here.
+
+use anyhow::{anyhow, Result};
+use flate2::read::ZlibDecoder;
+use image::{DynamicImage, Rgba, RgbaImage};
+use std::io::Read;
+
+use crate::archive;
+
+const PSD_MAGIC: &[u8] = b"8BPS";
+const XCF_MAGIC: &[u8] = b"gimp xcf ";
+const KRITA_MIMETYPE: &[u8] = b"application/x-krita";
+const KRITA_MERGED_IMAGE: &'static str = "mergedimage.png";
+const KRITA_PREVIEW: &'static str = "preview.png";
+const XCF_TILE_SIZE: u32 = 64;
+const MAX_PIXELS: usize = 16384 * 16384; // Larger canvases aren't decoded.  The header's size is untrusted.
+
+/// True if the bytes start like a PSD, XCF, or KRA file.
+pub fn is_design_file(bytes: &[u8]) -> bool {
+	bytes.starts_with(PSD_MAGIC) || bytes.starts_with(XCF_MAGIC) || is_krita(bytes)
+}
+
+// Krita files are zips that start with an uncompressed mimetype entry.
+fn is_krita(bytes: &[u8]) -> bool {
+	archive::is_zip(bytes) && bytes.iter().take(128).copied().collect::<Vec<u8>>().windows(KRITA_MIMETYPE.len()).any(|w| w == KRITA_MIMETYPE)
+}
+
+/// The flattened image of a design file.
+pub fn decode_design_file(bytes: &[u8]) -> Result<DynamicImage> {
+	if bytes.starts_with(PSD_MAGIC) {
+		decode_psd(bytes)
+	} else if bytes.starts_with(XCF_MAGIC) {
+		decode_xcf(bytes)
+	} else if is_krita(bytes) {
+		// The merged image is full size, but older versions only saved the preview.
+		let png = match archive::read_zip_file(bytes, KRITA_MERGED_IMAGE)? {
+			Some(png) => png,
+			None => archive::read_zip_file(bytes, KRITA_PREVIEW)?.ok_or_else(|| anyhow!("This Krita file has no saved preview."))?,
+		};
+		Ok(image::load_from_memory(&png)?)
+	} else {
+		Err(anyhow!("Not a PSD, XCF, or KRA file."))
+	}
+}
+
+/// Reads big-endian numbers, as both PSD and XCF use.
+struct BigEndianReader<'a> {
+	bytes: &'a [u8],
+	offset: usize,
+}
+
+impl<'a> BigEndianReader<'a> {
+	fn new(bytes: &'a [u8], offset: usize) -> Self {
+		BigEndianReader { bytes, offset }
+	}
+
+	fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+		let taken = self.bytes.get(self.offset..self.offset.saturating_add(length)).ok_or_else(|| anyhow!("The file is cut off."))?;
+		self.offset += length;
+		Ok(taken)
+	}
+
+	fn u8(&mut self) -> Result<u8> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16> {
+		let b = self.take(2)?;
+		Ok(u16::from_be_bytes([b[0], b[1]]))
+	}
+
+	fn u32(&mut self) -> Result<u32> {
+		let b = self.take(4)?;
+		Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+	}
+
+	fn u64(&mut self) -> Result<u64> {
+		Ok(((self.u32()? as u64) << 32) | self.u32()? as u64)
+	}
+}
+
+/// PSD and PSB files end with a flattened copy of the image, saved with "Maximize Compatibility".
+/// Without it, or when the copy is in a form that can't be read, the thumbnail Photoshop keeps in the image resources is used instead.
+fn decode_psd(bytes: &[u8]) -> Result<DynamicImage> {
+	let mut reader = BigEndianReader::new(bytes, PSD_MAGIC.len());
+	let is_psb = reader.u16()? == 2;
+	reader.take(6)?; // Reserved.
+	let header = PsdHeader {
+		is_psb,
+		channel_count: reader.u16()? as usize,
+		height: reader.u32()?,
+		width: reader.u32()?,
+		depth: reader.u16()?,
+		color_mode: reader.u16()?,
+	};
+
+	// Skip the color mode data and layers to get to the flattened image.
+	let color_mode_length = reader.u32()? as usize;
+	reader.take(color_mode_length)?;
+	let resources_length = reader.u32()? as usize;
+	let resources = reader.take(resources_length)?;
+	let layers_length = if is_psb { reader.u64()? as usize } else { reader.u32()? as usize };
+	reader.take(layers_length)?;
+
+	let composite = decode_psd_composite(&mut reader, &header);
+	match (composite, read_psd_thumbnail(resources)) {
+		// Files saved without a flattened copy still have the space for one, left a single color.
+		(Ok(img), Some(thumbnail)) if is_one_color(&img) => Ok(thumbnail),
+		(Ok(img), _) => Ok(DynamicImage::ImageRgba8(img)),
+		(Err(_), Some(thumbnail)) => Ok(thumbnail),
+		(Err(e), None) => Err(e),
+	}
+}
+
+struct PsdHeader {
+	is_psb: bool,
+	channel_count: usize,
+	height: u32,
+	width: u32,
+	depth: u16,
+	color_mode: u16,
+}
+
+/// The flattened image at the end of a PSD.  Only 8 and 16-bit grayscale, RGB, and CMYK are read.  16-bit channels lose their low byte.
+fn decode_psd_composite(reader: &mut BigEndianReader, header: &PsdHeader) -> Result<RgbaImage> {
+	const GRAYSCALE: u16 = 1;
+	const RGB: u16 = 3;
+	const CMYK: u16 = 4;
+
+	let color_channels = match header.color_mode {
+		RGB => 3,
+		CMYK => 4,
+		GRAYSCALE => 1,
+		_ => return Err(anyhow!("Unable to read PSD files in color mode {}.", header.color_mode)),
+	};
+	let bytes_per_sample = match header.depth {
+		8 => 1,
+		16 => 2,
+		_ => return Err(anyhow!("Unable to read {}-bit PSD files.", header.depth)),
+	};
+	let (width, height) = (header.width, header.height);
+	let row_size = width as usize * bytes_per_sample;
+	let plane_size = pixel_count(width, height, "PSD")? * bytes_per_sample;
+	let channel_count = header.channel_count;
+	let compression = reader.u16()?;
+	let mut planes = vec![];
+	match compression {
+		0 => {
+			for _ in 0..channel_count.min(color_channels + 1) {
+				planes.push(reader.take(plane_size)?.to_vec());
+			}
+		},
+		1 => {
+			// Every row of every channel is PackBits-compressed separately, and all of their lengths come first.
+			let mut row_lengths = vec![];
+			for _ in 0..channel_count * height as usize {
+				row_lengths.push(if header.is_psb { reader.u32()? as usize } else { reader.u16()? as usize });
+			}
+			for channel_rows in row_lengths.chunks(height.max(1) as usize).take(channel_count.min(color_channels + 1)) {
+				let mut plane = Vec::with_capacity(plane_size);
+				for &row_length in channel_rows {
+					unpack_bits(reader.take(row_length)?, row_size, &mut plane)?;
+				}
+				planes.push(plane);
+			}
+		},
+		_ => return Err(anyhow!("Unable to read PSD files with compression method {}.", compression)),
+	}
+	if planes.len() < color_channels || planes.iter().any(|p| p.len() != plane_size) {
+		return Err(anyhow!("The PSD is missing image data."));
+	}
+
+	// An extra channel after the colors is the flattened transparency.
+	let has_alpha = planes.len() > color_channels;
+	// 16-bit samples are big-endian, so the first byte of each is the one that matters.
+	let sample = |plane: usize, idx: usize| -> u8 { planes[plane][idx * bytes_per_sample] };
+	let mut img = RgbaImage::new(width, height);
+	for (idx, pixel) in img.pixels_mut().enumerate() {
+		let alpha = if has_alpha { sample(color_channels, idx) } else { 255 };
+		*pixel = match color_channels {
+			3 => Rgba([sample(0, idx), sample(1, idx), sample(2, idx), alpha]),
+			// CMYK is saved inverted, so 255 is no ink.  Black ink darkens whatever the other three leave.
+			4 => {
+				let black = sample(3, idx) as u16;
+				let ink = |plane: usize| (sample(plane, idx) as u16 * black / 255) as u8;
+				Rgba([ink(0), ink(1), ink(2), alpha])
+			},
+			_ => Rgba([sample(0, idx), sample(0, idx), sample(0, idx), alpha]),
+		};
+	}
+	Ok(img)
+}
+
+/// The JPEG thumbnail in a PSD's image resources, if it has one that decodes.
+fn read_psd_thumbnail(resources: &[u8]) -> Option<DynamicImage> {
+	const THUMBNAIL: u16 = 1036;
+	const OLD_THUMBNAIL: u16 = 1033; // Photoshop 4 saved BGR instead of RGB.
+	const THUMBNAIL_HEADER_SIZE: usize = 28;
+
+	let mut reader = BigEndianReader::new(resources, 0);
+	while reader.take(4).ok()? == b"8BIM" {
+		let id = reader.u16().ok()?;
+		// The name is a Pascal string, padded so it takes an even number of bytes with its length.
+		let name_length = reader.u8().ok()? as usize;
+		reader.take(name_length + (name_length + 1) % 2).ok()?;
+		let size = reader.u32().ok()? as usize;
+		let data = reader.take(size + size % 2).ok()?;
+		if id == THUMBNAIL || id == OLD_THUMBNAIL {
+			let jpeg = data.get(THUMBNAIL_HEADER_SIZE..size)?;
+			let mut thumbnail = image::load_from_memory_with_format(jpeg, image::ImageFormat::Jpeg).ok()?.to_rgba8();
+			if id == OLD_THUMBNAIL {
+				thumbnail.pixels_mut().for_each(|pixel| pixel.0.swap(0, 2));
+			}
+			return Some(DynamicImage::ImageRgba8(thumbnail));
+		}
+	}
+	None
+}
+
+fn is_one_color(img: &RgbaImage) -> bool {
+	img.pixels().all(|pixel| pixel == img.get_pixel(0, 0))
+}
+
+/// Decode one row of PackBits run-length encoding onto the end of out.
+fn unpack_bits(packed: &[u8], row_width: usize, out: &mut Vec<u8>) -> Result<()> {
+	let row_end = out.len() + row_width;
+	let mut idx = 0;
+	while idx < packed.len() && out.len() < row_end {
+		let header = packed[idx] as i8;
+		idx += 1;
+		if header >= 0 {
+			let count = header as usize + 1;
+			out.extend_from_slice(packed.get(idx..idx + count).ok_or_else(|| anyhow!("The PSD image data is cut off."))?);
+			idx += count;
+		} else if header != -128 {
+			let value = *packed.get(idx).ok_or_else(|| anyhow!("The PSD image data is cut off."))?;
+			out.resize(out.len() + (1 - header as isize) as usize, value);
+			idx += 1;
+		}
+	}
+	out.truncate(row_end);
+	if out.len() != row_end {
+		return Err(anyhow!("The PSD image data is cut off."));
+	}
+	Ok(())
+}
+
+/// GIMP files have no flattened copy, so the visible 8-bit layers are blended together in normal mode.
+/// Layer masks and blend modes are ignored.
+fn decode_xcf(bytes: &[u8]) -> Result<DynamicImage> {
+	const PROP_END: u32 = 0;
+	const PROP_OPACITY: u32 = 6;
+	const PROP_VISIBLE: u32 = 8;
+	const PROP_OFFSETS: u32 = 15;
+	const PROP_COMPRESSION: u32 = 17;
+	const PROP_GROUP_ITEM: u32 = 29;
+	const PROP_FLOAT_OPACITY: u32 = 33;
+
+	let mut reader = BigEndianReader::new(bytes, XCF_MAGIC.len());
+	let version = match reader.take(5)? {
+		b"file\0" => 0,
+		tag if tag[0] == b'v' => std::str::from_utf8(&tag[1..4]).ok().and_then(|v| v.parse::<u32>().ok()).ok_or_else(|| anyhow!("Unknown XCF version."))?,
+		_ => return Err(anyhow!("Unknown XCF version.")),
+	};
+	// Offsets grew to 64 bits in version 11.
+	let read_pointer = |reader: &mut BigEndianReader| -> Result<usize> {
+		Ok(if version >= 11 { reader.u64()? as usize } else { reader.u32()? as usize })
+	};
+	let width = reader.u32()?;
+	let height = reader.u32()?;
+	pixel_count(width, height, "XCF")?;
+	reader.u32()?; // Base type.  Layers say their own.
+	if version >= 4 {
+		reader.u32()?; // Precision.  Layers with more than 8 bits per channel are caught by their bytes per pixel.
+	}
+
+	let mut compression = 0;
+	loop {
+		let (property, length) = (reader.u32()?, reader.u32()? as usize);
+		let payload = reader.take(length)?;
+		match property {
+			PROP_END => break,
+			PROP_COMPRESSION => compression = *payload.first().unwrap_or(&0),
+			_ => {},
+		}
+	}
+	let mut layer_offsets = vec![];
+	loop {
+		match read_pointer(&mut reader)? {
+			0 => break,
+			offset => layer_offsets.push(offset),
+		}
+	}
+
+	// Layers are listed top first.
+	let mut canvas = RgbaImage::new(width, height);
+	for &layer_offset in layer_offsets.iter().rev() {
+		let mut layer = BigEndianReader::new(bytes, layer_offset);
+		let (layer_width, layer_height, layer_type) = (layer.u32()?, layer.u32()?, layer.u32()?);
+		let name_length = layer.u32()? as usize;
+		layer.take(name_length)?;
+		let mut opacity = 1.0f32;
+		let mut visible = true;
+		let mut is_group = false;
+		let mut offsets = (0i32, 0i32);
+		loop {
+			let (property, length) = (layer.u32()?, layer.u32()? as usize);
+			let mut payload = BigEndianReader::new(layer.take(length)?, 0);
+			match property {
+				PROP_END => break,
+				PROP_OPACITY => opacity = payload.u32()? as f32 / 255.0,
+				PROP_FLOAT_OPACITY => opacity = f32::from_bits(payload.u32()?),
+				PROP_VISIBLE => visible = payload.u32()? != 0,
+				PROP_OFFSETS => offsets = (payload.u32()? as i32, payload.u32()? as i32),
+				PROP_GROUP_ITEM => is_group = true,
+				_ => {},
+			}
+		}
+		// Groups have no pixels of their own.  Their children are listed as layers too.
+		if !visible || is_group || opacity <= 0.0 {
+			continue;
+		}
+		let channels = match layer_type {
+			0 => 3, // RGB
+			1 => 4, // RGBA
+			2 => 1, // Gray
+			3 => 2, // Gray with alpha
+			_ => continue, // Indexed layers are rare enough to leave out.
+		};
+
+		let hierarchy_offset = read_pointer(&mut layer)?;
+		let mut hierarchy = BigEndianReader::new(bytes, hierarchy_offset);
+		hierarchy.take(8)?; // Width and height again.
+		if hierarchy.u32()? != channels {
+			continue; // More than 8 bits per channel.
+		}
+		let level_offset = read_pointer(&mut hierarchy)?; // The first level is full size.
+		let mut level = BigEndianReader::new(bytes, level_offset + 8);
+		let mut tile_offsets = vec![];
+		loop {
+			match read_pointer(&mut level)? {
+				0 => break,
+				offset => tile_offsets.push(offset),
+			}
+		}
+
+		pixel_count(layer_width, layer_height, "XCF layer")?;
+		let tiles_across = layer_width.div_ceil(XCF_TILE_SIZE).max(1);
+		for (tile_idx, &tile_offset) in tile_offsets.iter().enumerate() {
+			let tile_x = (tile_idx as u32 % tiles_across) * XCF_TILE_SIZE;
+			let tile_y = (tile_idx as u32 / tiles_across) * XCF_TILE_SIZE;
+			let tile_width = XCF_TILE_SIZE.min(layer_width.saturating_sub(tile_x));
+			let tile_height = XCF_TILE_SIZE.min(layer_height.saturating_sub(tile_y));
+			let pixel_count = (tile_width * tile_height) as usize;
+			let tile_end = tile_offsets.get(tile_idx + 1).copied().unwrap_or(bytes.len());
+			let tile_data = bytes.get(tile_offset..tile_end.max(tile_offset)).ok_or_else(|| anyhow!("The XCF is cut off."))?;
+			let pixels = decode_xcf_tile(tile_data, compression, pixel_count, channels as usize)?;
+
+			for (idx, pixel) in pixels.chunks_exact(channels as usize).enumerate() {
+				let x = offsets.0 + (tile_x + idx as u32 % tile_width.max(1)) as i32;
+				let y = offsets.1 + (tile_y + idx as u32 / tile_width.max(1)) as i32;
+				if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 {
+					continue;
+				}
+				let rgba = match pixel {
+					[r, g, b, a] => [*r, *g, *b, *a],
+					[r, g, b] => [*r, *g, *b, 255],
+					[v, a] => [*v, *v, *v, *a],
+					[v] => [*v, *v, *v, 255],
+					_ => unreachable!("Layers have 1 to 4 channels."),
+				};
+				blend_over(canvas.get_pixel_mut(x as u32, y as u32), rgba, opacity);
+			}
+		}
+	}
+	Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+/// How many pixels a canvas of this size has, or an error if it's too many to decode.
+fn pixel_count(width: u32, height: u32, what: &str) -> Result<usize> {
+	(width as usize).checked_mul(height as usize).filter(|&pixels| pixels <= MAX_PIXELS).ok_or_else(|| anyhow!("The {} is too large: {}x{}.", what, width, height))
+}
+
+/// The interleaved pixels of a tile.
+fn decode_xcf_tile(data: &[u8], compression: u8, pixel_count: usize, channels: usize) -> Result<Vec<u8>> {
+	let size = pixel_count * channels;
+	match compression {
+		0 => Ok(data.get(..size).ok_or_else(|| anyhow!("The XCF is cut off."))?.to_vec()),
+		// Run-length encoded one channel at a time.
+		1 => {
+			let mut pixels = vec![0u8; size];
+			let mut reader = BigEndianReader::new(data, 0);
+			for channel in 0..channels {
+				let mut written = 0;
+				while written < pixel_count {
+					let opcode = reader.u8()?;
+					let (count, repeated) = match opcode {
+						0..=126 => (opcode as usize + 1, true),
+						127 => (reader.u16()? as usize, true),
+						128 => (reader.u16()? as usize, false),
+						_ => (256 - opcode as usize, false),
+					};
+					if written + count > pixel_count {
+						return Err(anyhow!("The XCF tile data is damaged."));
+					}
+					if repeated {
+						let value = reader.u8()?;
+						(written..written + count).for_each(|idx| pixels[idx * channels + channel] = value);
+					} else {
+						let values = reader.take(count)?;
+						(written..written + count).zip(values).for_each(|(idx, &value)| pixels[idx * channels + channel] = value);
+					}
+					written += count;
+				}
+			}
+			Ok(pixels)
+		},
+		2 => {
+			let mut pixels = Vec::with_capacity(size);
+			ZlibDecoder::new(data).take(size as u64).read_to_end(&mut pixels)?;
+			if pixels.len() != size {
+				return Err(anyhow!("The XCF tile data is damaged."));
+			}
+			Ok(pixels)
+		},
+		_ => Err(anyhow!("Unable to read XCF files with compression method {}.", compression)),
+	}
+}
+
+/// Porter-Duff "over", with straight alpha.
+fn blend_over(destination: &mut Rgba<u8>, source: [u8; 4], opacity: f32) {
+	let source_alpha = (source[3] as f32 / 255.0) * opacity.clamp(0.0, 1.0);
+	let destination_alpha = destination[3] as f32 / 255.0;
+	let out_alpha = source_alpha + destination_alpha * (1.0 - source_alpha);
+	if out_alpha <= 0.0 {
+		*destination = Rgba([0, 0, 0, 0]);
+		return;
+	}
+	for channel in 0..3 {
+		let blended = (source[channel] as f32 * source_alpha + destination[channel] as f32 * destination_alpha * (1.0 - source_alpha)) / out_alpha;
+		destination[channel] = blended.round() as u8;
+	}
+	destination[3] = (out_alpha * 255.0).round() as u8;
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::archive::make_test_zip;
+	use crate::design_files::*;
+	use image::ImageOutputFormat;
+	use std::io::Cursor;
+
+	// A 2x1 RGB PSD.  The layers section is skipped, so it's left empty.
+	fn make_psd(compression: u16, planes: &[&[u8]]) -> Vec<u8> {
+		make_psd_with(3, 8, &[], compression, planes)
+	}
+
+	fn make_psd_with(color_mode: u16, depth: u16, resources: &[u8], compression: u16, planes: &[&[u8]]) -> Vec<u8> {
+		let mut psd = PSD_MAGIC.to_vec();
+		psd.extend_from_slice(&1u16.to_be_bytes());
+		psd.extend_from_slice(&[0; 6]);
+		psd.extend_from_slice(&(planes.len() as u16).to_be_bytes());
+		psd.extend_from_slice(&1u32.to_be_bytes()); // Height.
+		psd.extend_from_slice(&2u32.to_be_bytes()); // Width.
+		psd.extend_from_slice(&depth.to_be_bytes());
+		psd.extend_from_slice(&color_mode.to_be_bytes());
+		psd.extend_from_slice(&[0; 4]); // Empty color mode data.
+		psd.extend_from_slice(&(resources.len() as u32).to_be_bytes());
+		psd.extend_from_slice(resources);
+		psd.extend_from_slice(&[0; 4]); // Empty layers.
+		psd.extend_from_slice(&compression.to_be_bytes());
+		if compression == 1 {
+			planes.iter().for_each(|p| psd.extend_from_slice(&(p.len() as u16).to_be_bytes()));
+		}
+		planes.iter().for_each(|p| psd.extend_from_slice(p));
+		psd
+	}
+
+	#[test]
+	fn test_psd() {
+		let raw = make_psd(0, &[&[255, 0], &[0, 255], &[0, 0]]);
+		assert!(is_design_file(&raw));
+		let img = decode_design_file(&raw).unwrap().to_rgba8();
+		assert_eq!(img.dimensions(), (2, 1));
+		assert_eq!(img.get_pixel(0, 0).0, [255, 0, 0, 255]);
+		assert_eq!(img.get_pixel(1, 0).0, [0, 255, 0, 255]);
+
+		// PackBits: a run of two 10s, then two literal bytes, then a run with an alpha channel.
+		let packed = make_psd(1, &[&[0xFF, 10], &[1, 20, 30], &[0xFF, 40], &[0xFF, 128]]);
+		let img = decode_design_file(&packed).unwrap().to_rgba8();
+		assert_eq!(img.get_pixel(0, 0).0, [10, 20, 40, 128]);
+		assert_eq!(img.get_pixel(1, 0).0, [10, 30, 40, 128]);
+
+		assert!(decode_design_file(&raw[..raw.len() - 1]).is_err());
+		let mut huge = raw.clone();
+		huge[14..22].copy_from_slice(&[0xFF; 8]); // Height and width.
+		assert!(decode_design_file(&huge).unwrap_err().to_string().contains("too large"));
+
+		// CMYK is inverted, and 16-bit samples are read by their high byte.
+		let cmyk = make_psd_with(4, 8, &[], 0, &[&[255, 0], &[255, 255], &[255, 255], &[128, 255]]);
+		let img = decode_design_file(&cmyk).unwrap().to_rgba8();
+		assert_eq!(img.get_pixel(0, 0).0, [128, 128, 128, 255]);
+		assert_eq!(img.get_pixel(1, 0).0, [0, 255, 255, 255]);
+		let deep = make_psd_with(1, 16, &[], 0, &[&[200, 1, 50, 2]]);
+		assert_eq!(decode_design_file(&deep).unwrap().to_rgba8().get_pixel(1, 0).0, [50, 50, 50, 255]);
+	}
+
+	#[test]
+	fn test_psd_thumbnail() {
+		let mut jpeg = vec![];
+		DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([0, 0, 250]))).write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(100)).unwrap();
+		let mut thumbnail = vec![0; 28];
+		thumbnail.extend(&jpeg);
+		let mut resources = b"8BIM".to_vec();
+		resources.extend(1036u16.to_be_bytes());
+		resources.extend([0, 0]); // An empty name, padded.
+		resources.extend((thumbnail.len() as u32).to_be_bytes());
+		resources.extend(&thumbnail);
+		if thumbnail.len() % 2 == 1 {
+			resources.push(0);
+		}
+
+		// Saved without a flattened copy, so it's all white.
+		let blank = make_psd_with(3, 8, &resources, 0, &[&[255, 255], &[255, 255], &[255, 255]]);
+		let img = decode_design_file(&blank).unwrap().to_rgba8();
+		assert_eq!(img.dimensions(), (8, 8));
+		assert!(img.get_pixel(4, 4).0[2] > 200);
+		// A color mode that can't be read.
+		let lab = make_psd_with(9, 8, &resources, 0, &[&[1, 2], &[3, 4], &[5, 6]]);
+		assert_eq!(decode_design_file(&lab).unwrap().width(), 8);
+		assert!(decode_design_file(&make_psd_with(9, 8, &[], 0, &[&[1, 2], &[3, 4], &[5, 6]])).is_err());
+		// A real flattened image still wins.
+		let flattened = make_psd_with(3, 8, &resources, 0, &[&[255, 0], &[0, 255], &[0, 0]]);
+		assert_eq!(decode_design_file(&flattened).unwrap().width(), 2);
+	}
+
+	// A 2x1 XCF with a red background and a half-transparent blue layer over its right pixel.
+	fn make_xcf() -> Vec<u8> {
+		let mut xcf = XCF_MAGIC.to_vec();
+		xcf.extend_from_slice(b"v003\0");
+		xcf.extend_from_slice(&2u32.to_be_bytes());
+		xcf.extend_from_slice(&1u32.to_be_bytes());
+		xcf.extend_from_slice(&0u32.to_be_bytes()); // RGB.
+		xcf.extend_from_slice(&[0, 0, 0, 17, 0, 0, 0, 1, 1]); // RLE compression.
+		xcf.extend_from_slice(&[0; 8]); // End of properties.
+		let pointers_at = xcf.len();
+		xcf.extend_from_slice(&[0; 12]); // Two layers, then the end of the list.
+		xcf.extend_from_slice(&[0; 4]); // No channels.
+
+		let mut layers = vec![];
+		// (Width, type, x offset, opacity, RLE pixel data.)
+		for (layer_width, layer_type, x_offset, opacity, tile) in [
+			(1u32, 1u32, 1u32, 128u32, vec![0, 0, 0, 0, 0, 255, 0, 255]), // Top: one blue pixel.
+			(2, 0, 0, 255, vec![1, 255, 1, 0, 1, 0]), // Bottom: two red pixels.
+		] {
+			let layer_at = xcf.len();
+			layers.push(layer_at as u32);
+			xcf.extend_from_slice(&layer_width.to_be_bytes());
+			xcf.extend_from_slice(&1u32.to_be_bytes());
+			xcf.extend_from_slice(&layer_type.to_be_bytes());
+			xcf.extend_from_slice(&[0, 0, 0, 2, b'L', 0]);
+			xcf.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 4]);
+			xcf.extend_from_slice(&opacity.to_be_bytes());
+			xcf.extend_from_slice(&[0, 0, 0, 15, 0, 0, 0, 8]);
+			xcf.extend_from_slice(&x_offset.to_be_bytes());
+			xcf.extend_from_slice(&0u32.to_be_bytes());
+			xcf.extend_from_slice(&[0; 8]);
+			let hierarchy_at = xcf.len() + 8;
+			xcf.extend_from_slice(&(hierarchy_at as u32).to_be_bytes());
+			xcf.extend_from_slice(&[0; 4]); // No mask.
+			let level_at = hierarchy_at + 20;
+			xcf.extend_from_slice(&layer_width.to_be_bytes());
+			xcf.extend_from_slice(&1u32.to_be_bytes());
+			xcf.extend_from_slice(&(if layer_type == 1 { 4u32 } else { 3 }).to_be_bytes());
+			xcf.extend_from_slice(&(level_at as u32).to_be_bytes());
+			xcf.extend_from_slice(&[0; 4]);
+			let tile_at = level_at + 16;
+			xcf.extend_from_slice(&layer_width.to_be_bytes());
+			xcf.extend_from_slice(&1u32.to_be_bytes());
+			xcf.extend_from_slice(&(tile_at as u32).to_be_bytes());
+			xcf.extend_from_slice(&[0; 4]);
+			xcf.extend_from_slice(&tile);
+		}
+		xcf[pointers_at..pointers_at + 4].copy_from_slice(&layers[0].to_be_bytes());
+		xcf[pointers_at + 4..pointers_at + 8].copy_from_slice(&layers[1].to_be_bytes());
+		xcf
+	}
+
+	#[test]
+	fn test_xcf() {
+		let xcf = make_xcf();
+		assert!(is_design_file(&xcf));
+		let img = decode_design_file(&xcf).unwrap().to_rgba8();
+		assert_eq!(img.dimensions(), (2, 1));
+		assert_eq!(img.get_pixel(0, 0).0, [255, 0, 0, 255]);
+		assert_eq!(img.get_pixel(1, 0).0, [127, 0, 128, 255]);
+
+		// A canvas too large to allocate is refused.
+		let mut huge = xcf.clone();
+		huge[XCF_MAGIC.len() + 5..XCF_MAGIC.len() + 13].copy_from_slice(&[0xFF; 8]);
+		assert!(decode_design_file(&huge).unwrap_err().to_string().contains("too large"));
+	}
+
+	#[test]
+	fn test_krita() {
+		let mut png = vec![];
+		DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 2, Rgba([1, 2, 3, 255]))).write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png).unwrap();
+		let kra = make_test_zip(&[("mimetype", KRITA_MIMETYPE), (KRITA_PREVIEW, &[]), (KRITA_MERGED_IMAGE, &png)]);
+		assert!(is_design_file(&kra));
+		assert_eq!(decode_design_file(&kra).unwrap().to_rgba8().get_pixel(2, 1).0, [1, 2, 3, 255]);
+
+		// Some other zip isn't a Krita file.
+		assert!(!is_design_file(&make_test_zip(&[("mimetype", b"application/epub+zip")])));
+		assert!(decode_design_file(&make_test_zip(&[("mimetype", KRITA_MIMETYPE)])).is_err());
+	}
+}
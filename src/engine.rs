@@ -8,26 +8,70 @@
 use anyhow::{anyhow, Result};
 use crossbeam::channel;
 //use rayon::prelude::*;
-use parking_lot::FairMutex;
-use rusqlite::{params, Connection, Error as SQLError, Result as SQLResult, Row, ToSql, OpenFlags};
+use parking_lot::{FairMutex, Mutex};
+use rusqlite::{params, Connection, Error as SQLError, InterruptHandle, OptionalExtension, Result as SQLResult, Row, ToSql};
+use rusqlite::backup::Backup;
 use rusqlite::functions::FunctionFlags;
-use serde_json::{Result as JSONResult, Value as JSONValue};
-use std::collections::HashMap;
+use ring::{digest, pbkdf2};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value as JSONValue};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
+use crate::archive;
+use crate::backup;
 use crate::crawler;
+use crate::image_hashes::{crop_to_content, dequantize_embedding, mlhash, mlhash_model_version, quantize_embedding, set_max_embedding_threads, COLOR_LAYOUT_SIZE, DEQUANTIZED_BYTES};
 use crate::indexed_image::*;
+use crate::raw;
+use crate::screenshot::{self, ScreenshotSource};
+use crate::video;
 
 type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
 type JSONMap = HashMap<String, JSONValue>;
+type Recompressor = fn(&[u8]) -> Result<Option<Vec<u8>>>; // Like recompress_thumbnail: the smaller thumbnail, or None if it isn't.
 
-const PARALLEL_FILE_PROCESSORS: usize = 8;
+const DEFAULT_CRAWLER_THREADS: usize = 8;
 const DEFAULT_MAX_QUERY_DISTANCE: f64 = 1e3; // f64 implements ToSql in SQLite. f32 doesn't.
 const DEFAULT_MAX_SEARCH_RESULTS: u64 = 100;
-const MAX_PENDING_FILEPATHS: usize = 1000;
+const DISTANCE_LANES: usize = 16; // How many elements the distance functions work on at once.
+const DEFAULT_TRASH_RETENTION_DAYS: u32 = 30;
+const DEFAULT_MAX_COLOR_DISTANCE: f64 = 0.15; // Colors are compared as normalized RGB distance in [0, 1].
+const DEFAULT_NEAR_DUPLICATE_DISTANCE: f64 = 0.05; // Fraction of phash bits that may differ.
+const MAX_SEARCH_HISTORY: usize = 200;
+const EARTH_RADIUS_KM: f64 = 6371.0; // The mean radius, for haversine_distance.
+const SEARCH_HISTORY_MERGE_SECONDS: u32 = 10; // Queries typed within this long of a shorter prefix replace it.
+pub const MAX_RATING: u8 = 5; // Stars.  Zero is unrated.
+pub const SENSITIVE_TAG: &'static str = "sensitive"; // Images with a tag of this name, from any source, are shown blurred until revealed.
+const SENSITIVE_BLUR_FRACTION: f32 = 0.04; // How much blurred thumbnails are blurred, as a fraction of their longer side.
+const MAX_SMART_COLLECTION_DEPTH: usize = 8; // How deeply smart collections can refer to other smart collections.
+const PASSPHRASE_ITERATIONS: u32 = 100_000; // PBKDF2 rounds for the passphrases of hidden collections.
+const BACKUP_PAGES_PER_STEP: i32 = 1024;
+const LARGEST_IMAGES_REPORTED: i64 = 10;
+const THUMBNAIL_SAVINGS_SAMPLE_SIZE: i64 = 32;
+// The upper ends of the resolution buckets in stats, by the longer side.  Anything bigger goes in a last bucket.
+const RESOLUTION_BUCKETS: [u32; 5] = [512, 1024, 2048, 4096, 8192];
+const RECOMPRESS_BATCH_SIZE: i64 = 256;
+const REEMBED_BATCH_SIZE: i64 = 64;
+const DRIFT_SAMPLE_SIZE: i64 = 100; // Stale embeddings redone in memory to see how much a new model changes searches.
+const DRIFT_NEIGHBORS: usize = 10;
+// Embedding indexes with at least this many images are split into lists of similar embeddings, and lookups only check the lists nearest the query.
+// Smaller ones are checked in full, which is quick enough and always exact.
+const ANN_MIN_ENTRIES: usize = 4096;
+const ANN_PROBES: usize = 8; // How many of the nearest lists a lookup checks.  More finds the true nearest images more often, and takes longer.
+const ANN_TRAINING_SAMPLES: usize = 32; // Embeddings per list used to place the lists.
+const ANN_TRAINING_ROUNDS: usize = 4; // Of k-means, when placing the lists.
+const DEFAULT_BACKUP_INTERVAL_HOURS: u32 = 24;
+const DEFAULT_BACKUP_CHAINS_TO_KEEP: u32 = 4;
+const BACKUP_CHECK_INTERVAL: Duration = Duration::from_secs(60); // How often backup_if_due looks at the backup directory.
+const BACKUP_SNAPSHOT_FILENAME: &'static str = "pixelbox_snapshot.db"; // Made in the backup directory, then diffed against the last backup.
+const INDEX_ARCHIVE_VERSION: u64 = 1; // Bump if export_index changes in a way import_index can't read.
+const SCREENSHOT_SESSION_GAP_SECONDS: i64 = 15 * 60; // Screenshots of a window this close together are one session.
 
 //
 // Schemas
@@ -39,17 +83,153 @@ const IMAGE_SCHEMA_V1: &'static str = "CREATE TABLE images (
 	path             TEXT NOT NULL,
 	image_width      INTEGER,
 	image_height     INTEGER,
+	file_size        INTEGER,
+	protected        INTEGER NOT NULL DEFAULT 0,
+	rating           INTEGER NOT NULL DEFAULT 0,
+	favorite         INTEGER NOT NULL DEFAULT 0,
 	thumbnail        BLOB,
 	created          DATETIME,
-	indexed          DATETIME
+	indexed          DATETIME,
+	trashed          DATETIME,
+	latitude         REAL,
+	longitude        REAL
 )";
+// Each file is indexed once.  Made by migrate, after folding together any images that were indexed twice before it existed.
+const IMAGE_PATH_INDEX_V1: &'static str = "CREATE UNIQUE INDEX IF NOT EXISTS images_path ON images (path)";
 const TAG_SCHEMA_V1: &'static str = "CREATE TABLE tags (
 	image_id		INTEGER,
 	name			TEXT NOT NULL,
-	value			TEXT
+	value			TEXT,
+	source			TEXT NOT NULL DEFAULT 'exif',
+	number			REAL
 )";
+// Where a tag came from.  Only user tags can be edited.  Every source is searched by tag:.
+const TAG_SOURCE_EXIF: &'static str = "exif";
+const TAG_SOURCE_USER: &'static str = "user";
+const TAG_SOURCE_RULE: &'static str = "rule";
+// The EXIF tags an image's latitude and longitude are read from, as kamadak-exif names them.
+const GPS_TAGS: [&'static str; 4] = ["GPSLatitude", "GPSLatitudeRef", "GPSLongitude", "GPSLongitudeRef"];
 const WATCHED_DIRECTORIES_SCHEMA_V1: &'static str = "CREATE TABLE watched_directories (glob TEXT PRIMARY KEY)";
+const SETTINGS_SCHEMA_V1: &'static str = "CREATE TABLE settings (name TEXT PRIMARY KEY, value TEXT)";
 const HASH_TABLE_SCHEMA_V1: &'static str = "CREATE TABLE $tablename$ (image_id INTEGER PRIMARY KEY, hash BLOB)";
+// Like the other hash tables, but with the mlhash_model_version that made each embedding.  NULL if that's unknown.
+const SEMANTIC_HASH_SCHEMA_V1: &'static str = "CREATE TABLE semantic_hashes (image_id INTEGER PRIMARY KEY, hash BLOB, model TEXT)";
+const RULES_SCHEMA_V1: &'static str = "CREATE TABLE rules (
+	id               INTEGER PRIMARY KEY,
+	name             TEXT NOT NULL,
+	enabled          INTEGER NOT NULL DEFAULT 1,
+	folder_contains  TEXT,
+	file_type        TEXT,
+	exif_name        TEXT,
+	exif_value       TEXT,
+	tag_name         TEXT NOT NULL,
+	tag_value        TEXT,
+	collection_name  TEXT NOT NULL DEFAULT ''
+)";
+const SAVED_SEARCHES_SCHEMA_V1: &'static str = "CREATE TABLE saved_searches (
+	name             TEXT PRIMARY KEY,
+	query            TEXT NOT NULL,
+	created          DATETIME
+)";
+const SEARCH_HISTORY_SCHEMA_V1: &'static str = "CREATE TABLE search_history (
+	query            TEXT PRIMARY KEY,
+	last_run         DATETIME NOT NULL,
+	run_count        INTEGER NOT NULL DEFAULT 1
+)";
+// Resolution is NULL while waiting for review, 'keep' to index it anyway, or 'skip' to never index it.
+const DUPLICATE_REVIEW_SCHEMA_V1: &'static str = "CREATE TABLE duplicate_reviews (
+	path              TEXT PRIMARY KEY,
+	existing_image_id INTEGER NOT NULL,
+	distance          REAL,
+	found             DATETIME,
+	resolution        TEXT
+)";
+// Smart collections have a query instead of members.
+// Hidden collections have a passphrase, from hash_passphrase.  Their images are left out of everything until it's given.
+const COLLECTIONS_SCHEMA_V1: &'static str = "CREATE TABLE collections (
+	id               INTEGER PRIMARY KEY,
+	name             TEXT NOT NULL UNIQUE COLLATE NOCASE,
+	created          DATETIME,
+	query            TEXT,
+	passphrase       TEXT
+)";
+const COLLECTION_MEMBERS_SCHEMA_V1: &'static str = "CREATE TABLE collection_members (
+	collection_id    INTEGER NOT NULL,
+	image_id         INTEGER NOT NULL,
+	added            DATETIME,
+	PRIMARY KEY (collection_id, image_id)
+)";
+// Images marked as duplicates of a canonical image, which stands in for them in searches.
+const DUPLICATE_OF_SCHEMA_V1: &'static str = "CREATE TABLE duplicate_of (image_id INTEGER PRIMARY KEY, canonical_id INTEGER NOT NULL)";
+// Left out of searches unless Engine::show_duplicates is set.
+const NOT_A_DUPLICATE: &'static str = "images.id NOT IN (SELECT image_id FROM duplicate_of)";
+// Embedding models besides the built-in one, whose embeddings are in semantic_hashes.  A new version or size is a separate model.
+const EMBEDDING_MODELS_SCHEMA_V1: &'static str = "CREATE TABLE embedding_models (
+	id               INTEGER PRIMARY KEY,
+	name             TEXT NOT NULL,
+	version          TEXT NOT NULL,
+	dimensions       INTEGER NOT NULL,
+	UNIQUE (name, version, dimensions)
+)";
+// Quantized like semantic_hashes, so any number of models' embeddings can sit side by side.
+const EMBEDDINGS_SCHEMA_V1: &'static str = "CREATE TABLE embeddings (
+	image_id         INTEGER NOT NULL,
+	model_id         INTEGER NOT NULL,
+	hash             BLOB,
+	PRIMARY KEY (image_id, model_id)
+)";
+// The window a screenshot was taken of, where screenshot::detect_screenshot_source could tell.
+// session is the id of the first screenshot in a run of screenshots of the same window.
+const SCREENSHOT_SOURCES_SCHEMA_V1: &'static str = "CREATE TABLE screenshot_sources (
+	image_id         INTEGER PRIMARY KEY,
+	app              TEXT NOT NULL,
+	window           TEXT NOT NULL,
+	captured         DATETIME,
+	session          INTEGER
+)";
+// Stand-ins for the thumbnails of images tagged SENSITIVE_TAG.  The real thumbnail is kept, so untagging brings it back.
+const BLURRED_THUMBNAILS_SCHEMA_V1: &'static str = "CREATE TABLE blurred_thumbnails (image_id INTEGER PRIMARY KEY, thumbnail BLOB)";
+// Files that couldn't be indexed, and why.  Only the latest failure for each path is kept.
+const FAILURES_SCHEMA_V1: &'static str = "CREATE TABLE failures (
+	path             TEXT PRIMARY KEY,
+	error            TEXT NOT NULL,
+	failed           DATETIME NOT NULL
+)";
+// Images that are never indexed, even in watched folders.  Files on disk are also matched by content, so copies and moved files stay out too.
+// content_hash is the SHA-256 of the file, or NULL for images in books, archives, and mail.
+const EXCLUSIONS_SCHEMA_V1: &'static str = "CREATE TABLE exclusions (
+	path             TEXT PRIMARY KEY,
+	content_hash     BLOB,
+	excluded         DATETIME NOT NULL
+)";
+// Only in DBs made by share_collection.
+const ORIGINALS_SCHEMA_V1: &'static str = "CREATE TABLE originals (image_id INTEGER PRIMARY KEY, data BLOB)";
+// Columns added to tables after those tables were first released, with their definitions.  migrate adds them to older DBs.
+const ADDED_COLUMNS: [(&'static str, &'static str, &'static str); 20] = [
+	("images", "file_size", "INTEGER"),
+	("images", "protected", "INTEGER NOT NULL DEFAULT 0"),
+	("images", "rating", "INTEGER NOT NULL DEFAULT 0"),
+	("images", "favorite", "INTEGER NOT NULL DEFAULT 0"),
+	("images", "trashed", "DATETIME"),
+	("tags", "source", "TEXT NOT NULL DEFAULT 'exif'"),
+	("collections", "query", "TEXT"),
+	("collections", "passphrase", "TEXT"),
+	("semantic_hashes", "model", "TEXT"),
+	("watched_directories", "priority", "INTEGER NOT NULL DEFAULT 0"),
+	("watched_directories", "ignore_patterns", "TEXT NOT NULL DEFAULT ''"), // One per line.
+	("watched_directories", "recursive", "INTEGER NOT NULL DEFAULT 1"),
+	("watched_directories", "extensions", "TEXT NOT NULL DEFAULT ''"), // Space separated.  Empty for all of them.
+	("watched_directories", "scan_archives", "INTEGER NOT NULL DEFAULT 1"),
+	("watched_directories", "follow_symlinks", "INTEGER NOT NULL DEFAULT 1"),
+	("watched_directories", "archive_password", "TEXT NOT NULL DEFAULT ''"), // As it was typed, since it's needed to open the archives.
+	("tags", "number", "REAL"), // The value as a number, from tag_number.  NULL if it isn't one.
+	("images", "latitude", "REAL"), // From the GPS tags, in degrees.  NULL if there aren't any.
+	("images", "longitude", "REAL"),
+	("rules", "collection_name", "TEXT NOT NULL DEFAULT ''"),
+];
+// Everything about an image but its id, for copying between databases.
+const IMAGE_COLUMNS: &'static str = "filename, path, image_width, image_height, file_size, protected, rating, favorite, thumbnail, created, indexed";
+const HASH_TABLES: [&'static str; 5] = ["phashes", "semantic_hashes", "palettes", "color_layouts", "cropped_hashes"];
 // These are all explicitly ordered so they work with indexed_image_from_row.
 // Does not include the trailing dist operation or tags.
 const SELECT_FIELDS: &'static str = "
@@ -58,267 +238,2216 @@ const SELECT_FIELDS: &'static str = "
 	images.path,
 	images.image_width,
 	images.image_height,
-	images.thumbnail
+	images.thumbnail,
+	images.file_size,
+	images.protected,
+	(SELECT palettes.hash FROM palettes WHERE palettes.image_id = images.id),
+	images.rating,
+	images.favorite,
+	(SELECT blurred_thumbnails.thumbnail FROM blurred_thumbnails WHERE blurred_thumbnails.image_id = images.id)
 ";
+const SELECT_FIELDS_COUNT: usize = 12; // Anything selected after SELECT_FIELDS starts at this index.
 // End Schemas
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortField {
+	Filename,
+	Path,
+	Resolution,
+	FileSize,
+	Indexed,
+	Rating,
+	Distance,
+}
+
+impl SortField {
+	pub const ALL: [SortField; 7] = [SortField::Filename, SortField::Path, SortField::Resolution, SortField::FileSize, SortField::Indexed, SortField::Rating, SortField::Distance];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			SortField::Filename => "filename",
+			SortField::Path => "path",
+			SortField::Resolution => "resolution",
+			SortField::FileSize => "size",
+			SortField::Indexed => "indexed",
+			SortField::Rating => "rating",
+			SortField::Distance => "distance",
+		}
+	}
+
+	fn from_name(name: &str) -> Option<SortField> {
+		match name.to_lowercase().as_str() {
+			"filename" | "name" => Some(SortField::Filename),
+			"path" => Some(SortField::Path),
+			"resolution" | "res" => Some(SortField::Resolution),
+			"size" | "filesize" => Some(SortField::FileSize),
+			"indexed" | "date" => Some(SortField::Indexed),
+			"rating" | "stars" => Some(SortField::Rating),
+			"distance" | "dist" => Some(SortField::Distance),
+			_ => None
+		}
+	}
+
+	// Expects 'dist' to be selected by the query.
+	fn to_sql(&self) -> &'static str {
+		match self {
+			SortField::Filename => "images.filename COLLATE NOCASE",
+			SortField::Path => "images.path COLLATE NOCASE",
+			SortField::Resolution => "(images.image_width * images.image_height)",
+			SortField::FileSize => "images.file_size",
+			SortField::Indexed => "images.indexed",
+			SortField::Rating => "images.rating",
+			SortField::Distance => "dist",
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SortOrder {
+	pub field: SortField,
+	pub descending: bool,
+}
+
+impl Default for SortOrder {
+	fn default() -> Self {
+		SortOrder { field: SortField::Distance, descending: false }
+	}
+}
+
+impl SortOrder {
+	/// Parse the value of a sort: prefix, like "size", "size:desc", or "filename:asc".
+	fn parse(value: &str) -> Result<SortOrder> {
+		let (field, direction) = value.split_once(':').unwrap_or((value, "asc"));
+		let field = SortField::from_name(field).ok_or_else(|| anyhow!("Unknown sort field '{}'.  Try one of: {}", field, SortField::ALL.map(|f| f.name()).join(", ")))?;
+		let descending = match direction.to_lowercase().as_str() {
+			"asc" => false,
+			"desc" => true,
+			_ => return Err(anyhow!("Unknown sort direction '{}'.  Use asc or desc.", direction)),
+		};
+		Ok(SortOrder { field, descending })
+	}
+
+	/// The inverse of parse, for storing in settings.
+	fn to_query_value(&self) -> String {
+		format!("{}:{}", self.field.name(), if self.descending { "desc" } else { "asc" })
+	}
+
+	fn to_sql(&self) -> String {
+		// The image ID is a tiebreaker so results don't shuffle between identical queries.
+		format!("{} {}, images.id ASC", self.field.to_sql(), if self.descending { "DESC" } else { "ASC" })
+	}
+}
+
+/// Which stored hash a similar: search compares.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SimilarityHash {
+	Semantic, // The model embedding.  Finds images with similar content and style.
+	Perceptual, // The phash.  Finds edits and re-encodes of the same image.
+	Cropped, // The model embedding of the picture without borders or edges.  Finds watermarked and matted copies.  Only images indexed with hash_cropped_frames have one.
+}
+
+impl SimilarityHash {
+	pub const ALL: [SimilarityHash; 3] = [SimilarityHash::Semantic, SimilarityHash::Perceptual, SimilarityHash::Cropped];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			SimilarityHash::Semantic => "semantic",
+			SimilarityHash::Perceptual => "phash",
+			SimilarityHash::Cropped => "cropped",
+		}
+	}
+
+	fn from_name(name: &str) -> Option<SimilarityHash> {
+		match name.to_lowercase().as_str() {
+			"semantic" | "visual" => Some(SimilarityHash::Semantic),
+			"phash" | "perceptual" => Some(SimilarityHash::Perceptual),
+			"cropped" | "crop" => Some(SimilarityHash::Cropped),
+			_ => None
+		}
+	}
+
+	fn table(&self) -> &'static str {
+		match self {
+			SimilarityHash::Semantic => "semantic_hashes",
+			SimilarityHash::Perceptual => "phashes",
+			SimilarityHash::Cropped => "cropped_hashes",
+		}
+	}
+
+	/// Embeddings are vectors, but a phash is a bit pattern.
+	fn default_metric(&self) -> DistanceMetric {
+		match self {
+			SimilarityHash::Semantic | SimilarityHash::Cropped => DistanceMetric::Cosine,
+			SimilarityHash::Perceptual => DistanceMetric::Hamming,
+		}
+	}
+
+	fn hash_of<'a>(&self, img: &'a IndexedImage) -> Option<&'a Vec<u8>> {
+		match self {
+			SimilarityHash::Semantic => img.visual_hash.as_ref(),
+			SimilarityHash::Perceptual => img.phash.as_ref(),
+			SimilarityHash::Cropped => img.cropped_hash.as_ref(),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DistanceMetric {
+	Hamming,
+	Byte,
+	Cosine,
+}
+
+impl DistanceMetric {
+	pub const ALL: [DistanceMetric; 3] = [DistanceMetric::Hamming, DistanceMetric::Byte, DistanceMetric::Cosine];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			DistanceMetric::Hamming => "hamming",
+			DistanceMetric::Byte => "byte",
+			DistanceMetric::Cosine => "cosine",
+		}
+	}
+
+	fn from_name(name: &str) -> Option<DistanceMetric> {
+		DistanceMetric::ALL.into_iter().find(|m| m.name().eq_ignore_ascii_case(name))
+	}
+
+	fn distance(&self, hash_a:&Vec<u8>, hash_b:&Vec<u8>) -> f32 {
+		match self {
+			DistanceMetric::Hamming => hamming_distance(hash_a, hash_b),
+			DistanceMetric::Byte => byte_distance(hash_a, hash_b),
+			DistanceMetric::Cosine => cosine_distance(hash_a, hash_b),
+		}
+	}
+
+	/// The DB function that computes it.
+	fn to_sql(&self) -> &'static str {
+		match self {
+			DistanceMetric::Hamming => "hamming_distance",
+			DistanceMetric::Byte => "byte_distance",
+			DistanceMetric::Cosine => "cosine_distance",
+		}
+	}
+}
+
+/// How a similar: search compares images, from the method: prefix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimilarityMethod {
+	pub hash: SimilarityHash,
+	pub metric: DistanceMetric,
+}
+
+impl Default for SimilarityMethod {
+	fn default() -> Self {
+		SimilarityMethod { hash: SimilarityHash::Semantic, metric: DistanceMetric::Cosine }
+	}
+}
+
+impl SimilarityMethod {
+	/// Parse the value of a method: prefix, like "phash", "phash:byte", or "cosine".
+	/// The hash defaults to semantic and the metric to whatever suits the hash.
+	fn parse(value: &str) -> Result<SimilarityMethod> {
+		let mut hash = None;
+		let mut metric = None;
+		for part in value.split(':').filter(|p| !p.is_empty()) {
+			if let Some(h) = SimilarityHash::from_name(part) {
+				hash = Some(h);
+			} else if let Some(m) = DistanceMetric::from_name(part) {
+				metric = Some(m);
+			} else {
+				return Err(anyhow!(
+					"Unknown similarity method '{}'.  Try a hash ({}) and/or a metric ({}), like method:phash:hamming.",
+					part, SimilarityHash::ALL.map(|h| h.name()).join(", "), DistanceMetric::ALL.map(|m| m.name()).join(", ")
+				));
+			}
+		}
+		let hash = hash.unwrap_or(SimilarityHash::Semantic);
+		Ok(SimilarityMethod { hash, metric: metric.unwrap_or(hash.default_metric()) })
+	}
+}
+
+/// How much visual distance and text relevance count when a query has both similar: and plain words.
+/// Only the ratio matters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RankingWeights {
+	pub visual: f64,
+	pub text: f64,
+}
+
+impl Default for RankingWeights {
+	fn default() -> Self {
+		RankingWeights { visual: 0.7, text: 0.3 }
+	}
+}
+
+impl RankingWeights {
+	/// Blend two distances in [0, 1] into one.  All visual if both weights are zero.
+	fn to_sql(&self, visual_distance: &str, text_distance: &str) -> String {
+		let total = self.visual + self.text;
+		if total <= 0.0 {
+			return visual_distance.to_string();
+		}
+		format!("(({:.6} * {}) + ({:.6} * {}))", self.visual / total, visual_distance, self.text / total, text_distance)
+	}
+}
+
 // We should implement try_from_row for this.
-// The last entry is seven, so tags or hashes start at row.get(6).
+// Tags or hashes start at row.get(SELECT_FIELDS_COUNT).
 fn indexed_image_from_row(row: &Row) -> SQLResult<IndexedImage> {
 	Ok(IndexedImage {
 		id: row.get(0)?,
 		filename: row.get(1)?,
 		path: row.get(2)?,
 		resolution: (row.get(3)?, row.get(4)?),
+		file_size: row.get::<_, Option<u64>>(6)?.unwrap_or(0),
+		protected: row.get(7)?,
+		rating: row.get(9)?,
+		favorite: row.get(10)?,
 		thumbnail: row.get(5)?,
+		blurred_thumbnail: row.get(11)?,
 		created: Instant::now(), //row.get(6)?
 		indexed: Instant::now(), //row.get(7)?
 		tags: HashMap::new(),
 		phash: None,
 		visual_hash: None,
+		cropped_hash: None,
+		palette: row.get(8)?,
+		color_layout: None,
 		distance_from_query: None,
 	})
 }
 
-pub struct Engine {
-	connection: Arc<FairMutex<Connection>>,
-
-	// Crawling and indexing:
-	files_crawled: Option<channel::Receiver<PathBuf>>,
-	files_processed: Option<channel::Receiver<IndexedImage>>, // What images have been loaded but are not stored.
-	files_completed: Option<channel::Receiver<String>>,
-	files_failed: Option<channel::Receiver<String>>,
-	last_indexed: Vec<String>, // A cache of the last n indexed items.
-	watched_directories_cache: Option<Vec<String>>, // Contains a list of the globs that we monitor.
-	cached_index_size: Option<usize>, // Number of indexed images.
-
-	// Searching and filtering.
-	pub max_search_results: u64,
-	pub max_distance_from_query: f64,
-	cached_search_results: Option<Vec<IndexedImage>>,  // For keeping track of the last time a query ran.
-	cached_image_search: Option<IndexedImage>, // If the user is searching for a similar image: "similar:abc", this is the path.  We should compare when the abc changes.
+/// Groups of file types that can be searched with the type: prefix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TypeFilter {
+	Photo,
+	Screenshot,
+	Gif,
+	Raw,
+	Vector,
+	Texture,
+	Design,
+	Font,
+	Model,
+	Audio,
+	Book,
+	Video,
 }
 
-impl Engine {
-	pub fn new(filename:&Path) -> Self {
-		let conn = Connection::open(filename).expect("Unable to open DB file.");
+const PHOTO_EXTENSIONS: &'static [&str] = &["jpg", "jpeg", "jfif", "heic", "heif", "tif", "tiff"];
+const VECTOR_EXTENSIONS: &'static [&str] = &["svg", "svgz", "eps", "ai"];
+const TEXTURE_EXTENSIONS: &'static [&str] = &["dds", "ktx2"];
+const DESIGN_EXTENSIONS: &'static [&str] = &["psd", "psb", "xcf", "kra"];
+const FONT_EXTENSIONS: &'static [&str] = &["ttf", "otf", "ttc"];
+const MODEL_EXTENSIONS: &'static [&str] = &["stl", "obj", "gltf", "glb"];
+const AUDIO_EXTENSIONS: &'static [&str] = &["mp3", "flac"];
+const BOOK_EXTENSIONS: &'static [&str] = &["epub", "cbz"];
 
-		// Initialize our image DB and our indices.
-		conn.execute(IMAGE_SCHEMA_V1, params![]).unwrap();
-		conn.execute(WATCHED_DIRECTORIES_SCHEMA_V1, []).unwrap();
-		conn.execute(TAG_SCHEMA_V1, []).unwrap();
+impl TypeFilter {
+	pub const ALL: [TypeFilter; 12] = [TypeFilter::Photo, TypeFilter::Screenshot, TypeFilter::Gif, TypeFilter::Raw, TypeFilter::Vector, TypeFilter::Texture, TypeFilter::Design, TypeFilter::Font, TypeFilter::Model, TypeFilter::Audio, TypeFilter::Book, TypeFilter::Video];
 
-		// phashes and semantic hashes should be identical instructure so we can swap them out.
-		// Can't use prepared statements for CREATE TABLE, so we have to substitute $tablename$.
-		conn.execute(&HASH_TABLE_SCHEMA_V1.replace("$tablename$", "phashes"), params![]).unwrap();
-		conn.execute(&HASH_TABLE_SCHEMA_V1.replace("$tablename$", "semantic_hashes"), params![]).unwrap();
-		if let Err((_, e)) = conn.close() {
-			eprintln!("Failed to close db after table creation: {}", e);
+	/// The value used after the type: prefix.
+	pub fn name(&self) -> &'static str {
+		match self {
+			TypeFilter::Photo => "photo",
+			TypeFilter::Screenshot => "screenshot",
+			TypeFilter::Gif => "gif",
+			TypeFilter::Raw => "raw",
+			TypeFilter::Vector => "vector",
+			TypeFilter::Texture => "texture",
+			TypeFilter::Design => "design",
+			TypeFilter::Font => "font",
+			TypeFilter::Model => "model",
+			TypeFilter::Audio => "audio",
+			TypeFilter::Book => "book",
+			TypeFilter::Video => "video",
 		}
-
-		Engine::open(filename)
 	}
 
-	pub fn open(filename:&Path) -> Self {
-		let mut conn = Connection::open(filename).expect("Unable to open filename.");
-
-		make_hamming_distance_db_function(&mut conn);
-		make_byte_distance_db_function(&mut conn);
-		make_cosine_distance_db_function(&mut conn);
-
-		Engine {
-			connection: Arc::new(FairMutex::new(conn)),
-			files_crawled: None,
-			files_processed: None,
-			files_completed: None,
-			files_failed: None,
-			last_indexed: vec![],
-			watched_directories_cache: None,
-			cached_index_size: None,
-
-			max_search_results: 100,
-			max_distance_from_query: DEFAULT_MAX_QUERY_DISTANCE,
-			cached_search_results: None,
-			cached_image_search: None,
+	pub fn label(&self) -> &'static str {
+		match self {
+			TypeFilter::Photo => "Photos",
+			TypeFilter::Screenshot => "Screenshots",
+			TypeFilter::Gif => "GIFs",
+			TypeFilter::Raw => "RAW",
+			TypeFilter::Vector => "Vector",
+			TypeFilter::Texture => "Textures",
+			TypeFilter::Design => "Design Files",
+			TypeFilter::Font => "Fonts",
+			TypeFilter::Model => "3D Models",
+			TypeFilter::Audio => "Album Art",
+			TypeFilter::Book => "Books",
+			TypeFilter::Video => "Videos",
 		}
 	}
 
-	pub fn is_indexing_active(&self) -> bool {
-		match (&self.files_crawled, &self.files_processed) {
-			(Some(f_rx), _) => f_rx.len() > 0,
-			(_, Some(img_rx)) => img_rx.len() > 0,
-			(None, None) => false
+	pub fn from_name(name: &str) -> Option<TypeFilter> {
+		let name = name.to_lowercase();
+		TypeFilter::ALL.into_iter().find(|t| t.name() == name || t.label().to_lowercase() == name)
+	}
+
+	fn to_sql(&self) -> String {
+		match self {
+			// Anything with a photo extension or with camera EXIF data.
+			TypeFilter::Photo => format!(
+				"({} OR EXISTS (SELECT 1 FROM tags AS camera_tags WHERE camera_tags.image_id = images.id AND camera_tags.name IN ('Make', 'Model')))",
+				extension_clause(PHOTO_EXTENSIONS)
+			),
+			// Screenshot tools are pretty consistent about naming.
+			TypeFilter::Screenshot => format!(
+				"({} AND (images.filename LIKE '%screenshot%' OR images.filename LIKE '%screen shot%' OR images.filename LIKE '%capture%' OR images.path LIKE '%screenshots%'))",
+				extension_clause(&["png", "jpg", "webp"])
+			),
+			TypeFilter::Gif => extension_clause(&["gif"]),
+			TypeFilter::Raw => extension_clause(raw::RAW_EXTENSIONS),
+			TypeFilter::Vector => extension_clause(VECTOR_EXTENSIONS),
+			TypeFilter::Texture => extension_clause(TEXTURE_EXTENSIONS),
+			TypeFilter::Design => extension_clause(DESIGN_EXTENSIONS),
+			TypeFilter::Font => extension_clause(FONT_EXTENSIONS),
+			TypeFilter::Model => extension_clause(MODEL_EXTENSIONS),
+			TypeFilter::Audio => extension_clause(AUDIO_EXTENSIONS),
+			// Pages indexed from inside a book count too.
+			TypeFilter::Book => {
+				let pages: Vec<String> = BOOK_EXTENSIONS.iter().map(|ext| format!("lower(images.path) LIKE '%.{}{}%'", ext, archive::ENTRY_SEPARATOR)).collect();
+				format!("({} OR {})", extension_clause(BOOK_EXTENSIONS), pages.join(" OR "))
+			},
+			TypeFilter::Video => extension_clause(video::VIDEO_EXTENSIONS),
 		}
 	}
+}
 
-	pub fn get_indexing_progress(&self) -> f32 {
-		let num_unread = if let Some(fname_rx) = &self.files_crawled { fname_rx.len() } else { 0 };
-		let num_unprocessed = if let Some(img_rx) = &self.files_processed { img_rx.len() } else { 0 };
-		let num_completed = if let Some(cmp) = &self.files_completed { cmp.len() } else { 0 };
-		let num_indexed = self.try_get_num_indexed_images().unwrap_or(0);
-		(num_indexed as f32) / 1.0f32.max((num_indexed + num_unread + num_unprocessed + num_completed) as f32)
+/// Match images whose filename ends in any of the given (lowercase) extensions.
+fn extension_clause(extensions: &[&str]) -> String {
+	let clauses: Vec<String> = extensions.iter().map(|ext| format!("lower(images.filename) LIKE '%.{}'", ext)).collect();
+	format!("({})", clauses.join(" OR "))
+}
+
+/// An action run on newly indexed images that match all of the rule's conditions.  Empty conditions match everything.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rule {
+	pub id: i64, // 0 until the rule is saved.
+	pub name: String,
+	pub enabled: bool,
+
+	// Conditions:
+	pub folder_contains: String,
+	pub file_type: Option<TypeFilter>,
+	pub exif_name: String,
+	pub exif_value: String,
+
+	// Actions.  Either can be left empty, but not both:
+	pub tag_name: String,
+	pub tag_value: String,
+	pub collection_name: String, // A collection that isn't smart.  save_rule makes it if there isn't one by that name.
+}
+
+impl Rule {
+	/// A WHERE clause on images (and nothing else) that's true when the image matches every condition.
+	fn to_sql(&self) -> String {
+		let mut clauses = vec![];
+		if !self.folder_contains.is_empty() {
+			clauses.push(format!("images.path LIKE '%{}%'", escape_sql_string(&self.folder_contains)));
+		}
+		if let Some(file_type) = self.file_type {
+			clauses.push(file_type.to_sql());
+		}
+		if !self.exif_name.is_empty() || !self.exif_value.is_empty() {
+			clauses.push(format!(
+				"EXISTS (SELECT 1 FROM tags AS rule_tags WHERE rule_tags.image_id = images.id AND rule_tags.name LIKE '%{}%' AND rule_tags.value LIKE '%{}%')",
+				escape_sql_string(&self.exif_name), escape_sql_string(&self.exif_value)
+			));
+		}
+		if clauses.is_empty() {
+			return "1".to_string();
+		}
+		clauses.join(" AND ")
 	}
 
-	pub fn try_get_num_indexed_images(&self) -> Option<usize> {
-		self.cached_index_size
+	fn to_json(&self) -> JSONValue {
+		json!({
+			"name": self.name,
+			"enabled": self.enabled,
+			"folder_contains": self.folder_contains,
+			"file_type": self.file_type.map(|t| t.name()),
+			"exif_name": self.exif_name,
+			"exif_value": self.exif_value,
+			"tag_name": self.tag_name,
+			"tag_value": self.tag_value,
+			"collection_name": self.collection_name,
+		})
 	}
 
-	/// Perform a count of the number of indexed images and cache the value.
-	pub fn get_num_indexed_images(&mut self) -> usize {
-		let conn = self.connection.lock();
-		let mut stmt = conn.prepare("SELECT COUNT(*) FROM images").unwrap();
-		let num_rows_iter = stmt.query_map([], |row|{
-			Ok(row.get(0)?)
-		}).expect("Unable to count rows in image database");
-		for nr in num_rows_iter {
-			self.cached_index_size = Some(nr.unwrap());
+	/// Missing fields are left empty.  The id is always 0 since ids don't carry between databases.
+	fn from_json(value: &JSONValue) -> Rule {
+		let text = |name: &str| value.get(name).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+		Rule {
+			id: 0,
+			name: text("name"),
+			enabled: value.get("enabled").and_then(|v| v.as_bool()).unwrap_or(true),
+			folder_contains: text("folder_contains"),
+			file_type: value.get("file_type").and_then(|v| v.as_str()).and_then(TypeFilter::from_name),
+			exif_name: text("exif_name"),
+			exif_value: text("exif_value"),
+			tag_name: text("tag_name"),
+			tag_value: text("tag_value"),
+			collection_name: text("collection_name"),
 		}
-		self.cached_index_size.unwrap()
 	}
 
-	pub fn get_last_indexed(&mut self) -> &Vec<String> {
-		if let Some(rx) = &self.files_completed {
-			while let Ok(msg) = rx.recv_timeout(Duration::from_nanos(1)) {
-				self.last_indexed.push(msg);
+	/// Run the rule's actions on the image if it matches.  Returns true if that changed anything.
+	fn apply(&self, conn: &Connection, image_id: i64) -> Result<bool> {
+		let mut applied = 0;
+		if !self.tag_name.is_empty() {
+			applied += conn.execute(
+				&format!(
+					"INSERT INTO tags (image_id, name, value, source, number)
+					SELECT images.id, ?1, ?2, ?4, ?5 FROM images
+					WHERE images.id = ?3 AND ({})
+					AND NOT EXISTS (SELECT 1 FROM tags WHERE tags.image_id = ?3 AND tags.name = ?1 AND tags.value IS ?2)",
+					self.to_sql()
+				),
+				params![&self.tag_name, &self.tag_value, image_id, TAG_SOURCE_RULE, tag_number(&self.tag_value)]
+			)?;
+			if applied > 0 {
+				update_blurred_thumbnail(conn, image_id)?;
 			}
 		}
-
-		// Cap last indexed to 10.
-		while self.last_indexed.len() > 10 {
-			self.last_indexed.remove(0);
+		if !self.collection_name.is_empty() {
+			applied += conn.execute(
+				&format!(
+					"INSERT OR IGNORE INTO collection_members (collection_id, image_id, added)
+					SELECT collections.id, images.id, datetime('now') FROM collections, images
+					WHERE collections.name = ?1 COLLATE NOCASE AND collections.query IS NULL AND images.id = ?2 AND ({})",
+					self.to_sql()
+				),
+				params![&self.collection_name, image_id]
+			)?;
 		}
-
-		&self.last_indexed
+		Ok(applied > 0)
 	}
+}
 
-	pub fn start_reindexing(&mut self) {
-		// How this works:
-		// We select all our tracked folders from the database, then open a multi-stage pipeline:
-		// The crawl_globs_async begins to parallel crawl the filenames.
-		// As filenames are read, they're sent to the files_pending_processing queue.
-		// As files are read and converted into images they're sent to the files_pending_storage queue.
-		// Another thread (here) checks if images are already in the database and, if not, inserts them.
-		// Successes/failures to insert are reported to failure_tx/success_tx.
-		
-		// Select all our monitored folders and, in parallel, dir walk them to grab new images.
-		let all_globs:Vec<String> = self.get_tracked_folders().clone();
+/// A named query that can be run again later.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedSearch {
+	pub name: String,
+	pub query: String,
+}
 
-		let (success_tx, success_rx) = crossbeam::channel::unbounded();
-		self.files_completed = Some(success_rx);
-		let (failure_tx, failure_rx) = crossbeam::channel::unbounded();
-		self.files_failed = Some(failure_rx);
+/// A named group of images.  Images can be in any number of collections.
+/// Smart collections hold whatever matches their query at the time they're browsed, so they have no fixed size.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Collection {
+	pub id: i64,
+	pub name: String,
+	pub size: Option<u64>, // Images in the collection that aren't in the trash.  None for smart collections.
+	pub query: Option<String>, // Only for smart collections.
+	pub hidden: bool, // Locked hidden collections aren't listed at all.
+}
 
-		// Image Processing Thread.
-		// file_rx / files_pending_processing
-		// img_rx / files_pending_storage
-		let (file_rx, img_rx) = crawler::crawl_globs_async(all_globs, PARALLEL_FILE_PROCESSORS);
-		self.files_crawled = Some(file_rx.clone());
-		self.files_processed = Some(img_rx.clone());
-		let w_conn = self.connection.clone();
-		std::thread::spawn(move || {
-			// To hold the lock as briefly as possible, we grab reads and writes very briefly.
-			// There is some overhead associated with getting the writes, so we might have to invert this pattern later.
-			while let Ok(img) = img_rx.recv() {
-				// Hold a short read lock and check if the image is already in our index.
-				let exists = {
-					let conn = w_conn.lock();
-					let mut stmt = conn.prepare("SELECT 1 FROM images WHERE path = ?").unwrap();
-					stmt.exists(params![&img.path]).unwrap()
-				};
-				// Image is not in our index.  Add it!
-				if !exists {
-					let fname = img.filename.clone();
-					// Quickly lock and unlock.
-					let insert_result = {
-						let mut rw_conn = w_conn.lock();
-						Engine::insert_image(&mut rw_conn, img)
-					};
-					if let Err(e) = insert_result {
-						eprintln!("Failed to track image: {}", &e);
-						failure_tx.send(format!("{}: {}", fname, e));
-					} else {
-						success_tx.send(fname);
-					}
-				};
-			}
-			//conn.flush_prepared_statement_cache();
-		});
+impl Collection {
+	pub fn is_smart(&self) -> bool {
+		self.query.is_some()
 	}
+}
 
-	//fn get_reindexing_status(&self) -> bool {}
+/// A query from the search history.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecentQuery {
+	pub query: String,
+	pub last_run: String, // UTC, as 'YYYY-MM-DD HH:MM:SS.SSS'.
+	pub run_count: u64,
+}
 
-	fn insert_image(conn: &mut Connection, mut img:IndexedImage) -> Result<()> {
-		// Update the images table first...
-		conn.execute(
-			"INSERT INTO images (filename, path, image_width, image_height, thumbnail) VALUES (?, ?, ?, ?, ?)",
-			params![img.filename, img.path, img.resolution.0, img.resolution.1, img.thumbnail,]
-		)?;
-		img.id = conn.last_insert_rowid();
+/// A file, or an image in one, that indexing couldn't load or store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexingFailure {
+	pub path: String,
+	pub error: String,
+	pub failed: String, // UTC, as 'YYYY-MM-DD HH:MM:SS'.
+}
 
-		// Insert the tags.
-		img.tags.iter().for_each(|(tag_name, tag_value)| {
-			conn.execute(
-				"INSERT INTO tags (image_id, name, value) VALUES (?, ?, ?)",
-				params![&img.id, tag_name, tag_value]
-			).expect(&format!("Failed to insert tag into database for image ID {}", &img.id));
-		});
+/// An image that's kept out of the index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exclusion {
+	pub path: String,
+	pub by_content: bool, // Copies of the file are kept out too.
+	pub excluded: String, // UTC, as 'YYYY-MM-DD HH:MM:SS'.
+}
 
-		// Add the hashes.
-		if let Some(hash) = img.phash {
-			conn.execute(
-				"INSERT INTO phashes (image_id, hash) VALUES (?, ?)",
-				params![img.id, hash]
-			)?;
+/// A newly crawled image that was held out of the index because it looks like one we already have.
+#[derive(Clone, Debug)]
+pub struct NearDuplicate {
+	pub path: String,
+	pub existing: IndexedImage,
+	pub distance: f64,
+}
+
+/// The window a screenshot was taken of, and how many screenshots are in its session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScreenshotInfo {
+	pub source: ScreenshotSource,
+	pub session: Option<i64>, // Search with session:N to see the whole session.
+	pub session_size: u64,
+}
+
+/// Where indexing is at, for anything other than the Folders tab that wants to show it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexingStatus {
+	pub active: bool,
+	pub paused: bool,
+	pub stopping: bool, // Cancelled, but still storing what was loaded.
+	pub progress: f32, // From 0 to 1.
+	pub details: Option<IndexingProgress>, // For the last run of start_reindexing or reindex_folder.
+	pub num_indexed: usize,
+	pub num_pending: usize, // Found or hashed, but not stored yet.
+	pub last_indexed: Vec<String>,
+	pub unreachable: Vec<String>, // See get_unreachable_folders.
+}
+
+impl IndexingStatus {
+	pub fn to_json(&self) -> JSONValue {
+		json!({
+			"active": self.active,
+			"paused": self.paused,
+			"stopping": self.stopping,
+			"progress": self.progress,
+			"details": self.details.map(|details| details.to_json()),
+			"num_indexed": self.num_indexed,
+			"num_pending": self.num_pending,
+			"last_indexed": self.last_indexed,
+			"unreachable": self.unreachable,
+		})
+	}
+}
+
+/// Counts of what an indexing run has found and done so far.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IndexingProgress {
+	pub files_found: u64,
+	pub finished_finding: bool, // False while folders are still being walked, so there may be more files than files_found.
+	pub files_processed: u64,
+	pub images_stored: u64, // Books, archives, and mail can have more than one image per file.
+	pub failed: u64, // Files that couldn't be loaded and images that couldn't be stored.
+	pub elapsed: Duration,
+	pub eta: Option<Duration>, // How much longer, going at the rate so far.  None until every file has been found and one has been processed.
+}
+
+impl IndexingProgress {
+	fn from_counters(counters: &crawler::CrawlCounters, elapsed: Duration) -> Self {
+		let files_found = counters.files_found.load(Ordering::Relaxed);
+		let finished_finding = counters.finished_finding.load(Ordering::Relaxed);
+		let files_processed = counters.files_processed.load(Ordering::Relaxed);
+		let eta = if finished_finding && files_processed > 0 {
+			Some(elapsed.mul_f64(files_found.saturating_sub(files_processed) as f64 / files_processed as f64))
+		} else {
+			None
+		};
+		IndexingProgress {
+			files_found,
+			finished_finding,
+			files_processed,
+			images_stored: counters.images_stored.load(Ordering::Relaxed),
+			failed: counters.failed.load(Ordering::Relaxed),
+			elapsed,
+			eta,
 		}
-		if let Some(hash) = img.visual_hash {
-			conn.execute(
-				"INSERT INTO semantic_hashes (image_id, hash) VALUES (?, ?)",
-				params![img.id, hash]
-			)?;
+	}
+
+	/// From 0 to 1.  Stays low while folders are still being walked.
+	pub fn fraction(&self) -> f32 {
+		if self.files_found == 0 {
+			return if self.finished_finding { 1.0 } else { 0.0 };
 		}
+		self.files_processed as f32 / self.files_found as f32
+	}
 
-		Ok(())
+	pub fn to_json(&self) -> JSONValue {
+		json!({
+			"files_found": self.files_found,
+			"finished_finding": self.finished_finding,
+			"files_processed": self.files_processed,
+			"images_stored": self.images_stored,
+			"failed": self.failed,
+			"elapsed_seconds": self.elapsed.as_secs_f64(),
+			"eta_seconds": self.eta.map(|eta| eta.as_secs_f64()),
+		})
 	}
+}
 
-	pub fn query(&mut self, user_input:&String) -> Result<()> {
-		// This will parse and process the full query.
-		// Magic phrases:
-		// filename: matches filename
-		// image: or file: does semantic matching
-		// tags: matches tags, comma-separated
-		// metadata: matches metadata
-		// min_width:, max_width:, min_height:, max_height:
-		// Absent all that, full-text search on all of these.
+/// What start_maintenance found and how much space it freed.
+#[derive(Clone, Debug)]
+pub struct MaintenanceReport {
+	pub problems: Vec<String>, // From PRAGMA integrity_check.  Empty if the DB is fine.
+	pub bytes_before: u64,
+	pub bytes_after: u64,
+}
 
-		if user_input.is_empty() {
-			return Ok(()); // Bail early!
-			// TODO: Should we clear results?
-		}
+impl MaintenanceReport {
+	pub fn bytes_reclaimed(&self) -> u64 {
+		self.bytes_before.saturating_sub(self.bytes_after)
+	}
+}
 
-		let mut parameters = params![];
-		let parsed_query = tokenize_query(user_input)?;
-		let where_clause = build_where_clause_from_parsed_query(&parsed_query, &mut self.cached_image_search);
+/// Where the space in the DB goes, from analyze_storage.
+#[derive(Clone, Debug, Default)]
+pub struct StorageReport {
+	pub file_bytes: u64,
+	pub table_bytes: Vec<(String, u64)>, // On-disk size of every table and index, largest first.
+	pub thumbnail_bytes: u64,
+	pub embedding_bytes: u64, // Semantic hashes and embeddings from other models.
+	pub other_hash_bytes: u64, // Perceptual hashes, palettes, and color layouts.
+	pub tag_bytes: u64,
+	pub largest_images: Vec<(i64, String, u64)>, // Id, path, and the bytes of its thumbnail and hashes.
+	pub free_bytes: u64, // Unused pages.  Only VACUUM gives them back.
+	pub orphaned_bytes: u64, // Hashes, tags, and collection entries for images that are no longer indexed.
+	pub thumbnail_savings_estimate: u64, // From CompactionJob::RecompressThumbnails, estimated from a sample.
+	pub webp_savings_estimate: u64, // From CompactionJob::WebpThumbnails, estimated from a sample.
+	pub embedding_reduction_estimate: u64, // From CompactionJob::ReduceEmbeddings.  Exact, since it's just the cropped embeddings.
+	pub stale_embeddings: u64, // Embeddings from a model other than the installed one.  See start_reembedding.
+}
 
-		self.cached_search_results = None;
+/// How differently a new model ranks a sample of images' nearest neighbors than the old one did.  From start_measuring_drift.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmbeddingDrift {
+	pub sample_size: usize, // Images compared.  Ones that couldn't be loaded are left out.
+	pub rank_correlation: f64, // Mean Spearman correlation of each image's ranking of the others.  1 means similarity searches come back in the same order.
+	pub neighbor_overlap: f64, // Mean fraction of each image's nearest neighbors that are still its nearest neighbors.
+}
 
-		let included_distance_hash = match &self.cached_image_search {
-			Some(img) => {
-				if let Some(hash) = &img.visual_hash {
-					parameters = params![hash];
-					"cosine_distance(?, semantic_hashes.hash)"
-				} else {
-					"0.0"
-				}
-			},
-			None => "0.0"
-		};
+/// What's in the library, from stats.  Trashed images aren't counted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LibraryStats {
+	pub num_images: u64,
+	pub num_trashed: u64,
+	pub total_file_bytes: u64,
+	pub thumbnail_bytes: u64,
+	pub by_extension: Vec<(String, u64)>, // Lowercase, most common first.  Files without one are under "".
+	pub by_folder: Vec<(String, u64)>, // Every watched folder, in the order of get_tracked_folders.
+	pub by_resolution: Vec<(String, u64)>, // Bucketed by the longer side, smallest first.  Empty buckets are kept.
+	pub indexed_by_day: Vec<(String, u64)>, // YYYY-MM-DD, oldest first.
+	pub first_indexed: Option<String>,
+	pub last_indexed: Option<String>,
+}
 
-		let mut statement = format!("
-			WITH grouped_tags AS (
-				SELECT tags.image_id, JSON(JSON_GROUP_OBJECT(
-					tags.name, tags.value
-				)) as tags
-				FROM tags
+impl LibraryStats {
+	pub fn to_json(&self) -> JSONValue {
+		let counts = |pairs: &Vec<(String, u64)>| pairs.iter().map(|(name, count)| json!({"name": name, "count": count})).collect::<Vec<JSONValue>>();
+		json!({
+			"num_images": self.num_images,
+			"num_trashed": self.num_trashed,
+			"total_file_bytes": self.total_file_bytes,
+			"thumbnail_bytes": self.thumbnail_bytes,
+			"by_extension": counts(&self.by_extension),
+			"by_folder": counts(&self.by_folder),
+			"by_resolution": counts(&self.by_resolution),
+			"indexed_by_day": counts(&self.indexed_by_day),
+			"first_indexed": self.first_indexed,
+			"last_indexed": self.last_indexed,
+		})
+	}
+}
+
+/// Jobs that shrink what's stored, started from the storage report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionJob {
+	RecompressThumbnails, // QOI thumbnails to PNG, where that's smaller.
+	WebpThumbnails, // Every thumbnail to lossless WebP, where that's smaller.
+	ReduceEmbeddings, // Drop the cropped embeddings, so each image keeps one.  method:cropped only finds images hashed with cropped frames again afterward.
+	RemoveOrphans,
+}
+
+impl CompactionJob {
+	/// The thumbnails the job would recompress, as SQL, and how.  None for jobs that don't touch thumbnails.
+	fn thumbnail_recompression(&self) -> Option<(&'static str, Recompressor)> {
+		match self {
+			CompactionJob::RecompressThumbnails => Some(("substr(thumbnail, 1, 4) = CAST('qoif' AS BLOB)", recompress_thumbnail)),
+			CompactionJob::WebpThumbnails => Some(("LENGTH(thumbnail) > 0 AND substr(thumbnail, 1, 4) != CAST('RIFF' AS BLOB)", webp_thumbnail)),
+			CompactionJob::ReduceEmbeddings | CompactionJob::RemoveOrphans => None,
+		}
+	}
+}
+
+/// Sorts out what changed in the watched folders on a thread of its own, so the UI never waits on the disk or the DB for it.
+/// The thread ends when this is dropped.
+struct ChangeWatcher {
+	to_index: channel::Receiver<WatchedChanges>,
+	_stop: channel::Sender<()>, // Dropping it stops the thread.
+}
+
+/// One batch of changes from a ChangeWatcher.  What was deleted has already been trashed.
+struct WatchedChanges {
+	num_changes: usize, // Every path that changed, including the ones that were trashed or left alone.
+	num_trashed: usize,
+	folders: Vec<crawler::CrawlFolder>, // Folders made or moved in, to walk like the watched folder they're in.
+	files: Vec<(PathBuf, crawler::CrawlFolder)>, // With the watched folder whose settings they're loaded with.
+}
+
+/// The engine's settings a query runs with, copied so it can run on a worker thread.
+struct QuerySettings {
+	max_search_results: u64,
+	default_sort_order: SortOrder,
+	ranking_weights: RankingWeights,
+	visibility_filter: String, // From Engine::visibility_filter.
+	prefilter_candidates: u64,
+}
+
+/// One page of results from a query, along with how many results there are across all pages.
+#[derive(Clone, Debug)]
+pub struct QueryPage {
+	pub results: Vec<IndexedImage>,
+	pub page: u64,
+	pub page_size: u64,
+	pub total_results: u64,
+}
+
+impl QueryPage {
+	pub fn num_pages(&self) -> u64 {
+		if self.page_size == 0 {
+			return 1;
+		}
+		self.total_results.div_ceil(self.page_size).max(1)
+	}
+}
+
+/// Stops a background query from start_query_page.  Cancelling a query that's already done does nothing.
+/// The DB connection is shared with indexing, so it's only interrupted while the query's own statements are running.
+#[derive(Clone)]
+pub struct QueryCancelHandle {
+	cancelled: Arc<AtomicBool>,
+	running: Arc<Mutex<bool>>, // True while the query holds the connection.
+	interrupt: Arc<InterruptHandle>,
+}
+
+impl QueryCancelHandle {
+	fn new(interrupt: Arc<InterruptHandle>) -> Self {
+		QueryCancelHandle { cancelled: Arc::new(AtomicBool::new(false)), running: Arc::new(Mutex::new(false)), interrupt }
+	}
+
+	pub fn cancel(&self) {
+		let running = self.running.lock();
+		self.cancelled.store(true, Ordering::Relaxed);
+		if *running {
+			self.interrupt.interrupt();
+		}
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::Relaxed)
+	}
+
+	/// Run the query on conn, which the caller has locked, unless it's already been cancelled.
+	fn run<T>(&self, conn: &Connection, query: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+		{
+			let mut running = self.running.lock();
+			if self.is_cancelled() {
+				return Err(anyhow!("The query was cancelled."));
+			}
+			*running = true;
+		}
+		let result = query(conn);
+		*self.running.lock() = false;
+		result
+	}
+}
+
+/// Images that are all within some distance of each other, from find_similar_groups.
+#[derive(Clone, Debug)]
+pub struct SimilarGroup {
+	pub images: Vec<IndexedImage>, // Largest file first.
+	pub total_bytes: u64,
+}
+
+impl SimilarGroup {
+	/// The images that could go: everything but the largest file, leaving out protected images.
+	pub fn deletable(&self) -> Vec<&IndexedImage> {
+		self.images.iter().skip(1).filter(|img| !img.protected).collect()
+	}
+
+	/// What deleting the deletable images would free.
+	pub fn reclaimable_bytes(&self) -> u64 {
+		self.deletable().iter().map(|img| img.file_size).sum()
+	}
+}
+
+/// How query_by_multiple_images combines the example images' embeddings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmbeddingPooling {
+	Mean, // Finds what the examples have in common.
+	Max, // Finds anything with a strong trait from any example.
+}
+
+impl EmbeddingPooling {
+	pub const ALL: [EmbeddingPooling; 2] = [EmbeddingPooling::Mean, EmbeddingPooling::Max];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			EmbeddingPooling::Mean => "mean",
+			EmbeddingPooling::Max => "max",
+		}
+	}
+
+	/// Combine the embeddings value by value.  They all have to be the same length.
+	fn combine(&self, embeddings: &[Vec<f32>]) -> Result<Vec<f32>> {
+		let first = embeddings.first().ok_or_else(|| anyhow!("Pick at least one image to search with."))?;
+		if embeddings.iter().any(|e| e.len() != first.len()) {
+			return Err(anyhow!("These images were hashed by different models and can't be combined.  Try reindexing them."));
+		}
+		Ok((0..first.len()).map(|idx| {
+			let values = embeddings.iter().map(|e| e[idx]);
+			match self {
+				EmbeddingPooling::Mean => values.sum::<f32>() / embeddings.len() as f32,
+				EmbeddingPooling::Max => values.fold(f32::NEG_INFINITY, f32::max),
+			}
+		}).collect())
+	}
+}
+
+/// The nearest images to one image by two embedding models, from compare_similarity_methods.
+#[derive(Clone, Debug)]
+pub struct MethodComparison {
+	pub a: Vec<IndexedImage>, // Closest first, with distance_from_query set.
+	pub b: Vec<IndexedImage>,
+	pub shared: usize, // How many images are in both lists.
+	pub jaccard: f64, // Shared over the number of distinct images in either list.
+	pub rank_correlation: Option<f64>, // Spearman's rho over the shared images.  None with fewer than two.
+}
+
+impl MethodComparison {
+	fn new(a: Vec<IndexedImage>, b: Vec<IndexedImage>) -> Self {
+		let b_ranks: HashMap<i64, usize> = b.iter().enumerate().map(|(rank, img)| (img.id, rank)).collect();
+		let shared_ranks: Vec<(usize, usize)> = a.iter().enumerate().filter_map(|(rank, img)| b_ranks.get(&img.id).map(|&b_rank| (rank, b_rank))).collect();
+		let shared = shared_ranks.len();
+		let union = a.len() + b.len() - shared;
+		let jaccard = if union == 0 { 0.0 } else { shared as f64 / union as f64 };
+
+		// Rank the shared images again among themselves so both sides run 0..shared.
+		let rerank = |ranks: Vec<usize>| -> Vec<usize> {
+			let mut order: Vec<usize> = (0..ranks.len()).collect();
+			order.sort_by_key(|&i| ranks[i]);
+			let mut reranked = vec![0; ranks.len()];
+			for (new_rank, i) in order.into_iter().enumerate() {
+				reranked[i] = new_rank;
+			}
+			reranked
+		};
+		let rank_correlation = if shared < 2 {
+			None
+		} else {
+			let a_ranks = rerank(shared_ranks.iter().map(|r| r.0).collect());
+			let b_ranks = rerank(shared_ranks.iter().map(|r| r.1).collect());
+			let squared_differences: f64 = a_ranks.iter().zip(b_ranks.iter()).map(|(&x, &y)| (x as f64 - y as f64).powi(2)).sum();
+			let n = shared as f64;
+			Some(1.0 - (6.0 * squared_differences) / (n * (n * n - 1.0)))
+		};
+
+		MethodComparison { a, b, shared, jaccard, rank_correlation }
+	}
+}
+
+/// An outside embedding model from register_embedding_model.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmbeddingModel {
+	pub id: i64,
+	pub name: String,
+	pub version: String,
+	pub dimensions: usize,
+	pub num_embeddings: u64, // How many images have an embedding from it.
+}
+
+/// Image ids and their embeddings, from load_embedding_index, for approximate nearest-neighbor lookups.
+/// The embeddings are split into lists of similar ones around a centroid each, an inverted file index.
+/// A lookup only checks the embeddings in the ANN_PROBES lists with centroids nearest the query, so it can miss a close image that landed in another list.
+/// Indexes with fewer than ANN_MIN_ENTRIES embeddings are kept as one list, so lookups in them are exact.
+#[derive(Clone, Debug, Default)]
+pub struct EmbeddingIndex {
+	entries: Vec<(i64, Vec<u8>)>,
+	centroids: Vec<Vec<u8>>, // Quantized like the embeddings.  Empty when everything is in one list.
+	lists: Vec<Vec<usize>>, // Indices into entries, one list per centroid.
+}
+
+impl EmbeddingIndex {
+	pub fn new(entries:Vec<(i64, Vec<u8>)>) -> Self {
+		let num_lists = if entries.len() >= ANN_MIN_ENTRIES { (entries.len() as f64).sqrt() as usize } else { 1 };
+		EmbeddingIndex::with_lists(entries, num_lists)
+	}
+
+	/// Split the entries into num_lists lists with k-means, placed using an evenly spaced sample of the entries.
+	fn with_lists(entries:Vec<(i64, Vec<u8>)>, num_lists:usize) -> Self {
+		let num_lists = num_lists.min(entries.len());
+		if num_lists <= 1 {
+			return EmbeddingIndex { entries, centroids: vec![], lists: vec![] };
+		}
+		// Evenly spaced rather than random, so the same DB always makes the same lists.
+		let step = (entries.len() / (num_lists * ANN_TRAINING_SAMPLES)).max(1);
+		let sample: Vec<&[u8]> = entries.iter().step_by(step).map(|(_, hash)| hash.as_slice()).collect();
+		// Each list starts at the sampled embedding farthest from the lists so far, so they start spread out.
+		let mut centroids: Vec<Vec<u8>> = vec![sample[0].to_vec()];
+		let mut gaps: Vec<f32> = sample.iter().map(|hash| cosine_distance(sample[0], hash)).collect();
+		while centroids.len() < num_lists {
+			let Some((farthest, _)) = gaps.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)) else {
+				break;
+			};
+			let centroid = QueryEmbedding::new(sample[farthest]);
+			for (gap, hash) in gaps.iter_mut().zip(&sample) {
+				*gap = gap.min(centroid.cosine_distance(hash));
+			}
+			centroids.push(sample[farthest].to_vec());
+		}
+		for _ in 0..ANN_TRAINING_ROUNDS {
+			let mut sums: Vec<Vec<f32>> = vec![vec![]; num_lists];
+			let mut counts = vec![0usize; num_lists];
+			for hash in &sample {
+				let list = nearest_centroid(&centroids, hash);
+				let sum = &mut sums[list];
+				sum.resize(sum.len().max(hash.len()), 0.0);
+				for (total, value) in sum.iter_mut().zip(dequantize_embedding(hash)) {
+					*total += value;
+				}
+				counts[list] += 1;
+			}
+			// Lists nothing was nearest keep where they were.
+			for (list, (sum, count)) in sums.into_iter().zip(counts).enumerate().filter(|(_, (_, count))| *count > 0) {
+				centroids[list] = quantize_embedding(&sum.iter().map(|total| total / count as f32).collect::<Vec<f32>>());
+			}
+		}
+		let mut lists = vec![vec![]; num_lists];
+		for (idx, (_, hash)) in entries.iter().enumerate() {
+			lists[nearest_centroid(&centroids, hash)].push(idx);
+		}
+		EmbeddingIndex { entries, centroids, lists }
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	/// Ids and cosine distances of the k images nearest to the embedding that were found, closest first.
+	pub fn nearest(&self, hash:&[u8], k:usize) -> Vec<(i64, f32)> {
+		let query = QueryEmbedding::new(hash);
+		let candidates: Vec<usize> = if self.centroids.is_empty() {
+			(0..self.entries.len()).collect()
+		} else {
+			let mut lists: Vec<(usize, f32)> = self.centroids.iter().map(|centroid| query.cosine_distance(centroid)).enumerate().collect();
+			lists.sort_by(|a, b| a.1.total_cmp(&b.1));
+			lists.iter().take(ANN_PROBES).flat_map(|(list, _)| self.lists[*list].iter().copied()).collect()
+		};
+		let mut distances: Vec<(i64, f32)> = candidates.into_iter().map(|idx| (self.entries[idx].0, query.cosine_distance(&self.entries[idx].1))).collect();
+		distances.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+		distances.truncate(k);
+		distances
+	}
+}
+
+/// Which of the centroids the embedding is closest to.
+fn nearest_centroid(centroids:&[Vec<u8>], hash:&[u8]) -> usize {
+	let query = QueryEmbedding::new(hash);
+	centroids.iter().map(|centroid| query.cosine_distance(centroid)).enumerate().min_by(|a, b| a.1.total_cmp(&b.1)).map(|(list, _)| list).unwrap_or(0)
+}
+
+/// The settings that are stored in the DB, as they're stored, for telling whether any have changed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings(Vec<(&'static str, String)>);
+
+/// File formats for export_results.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+	Csv,
+	Json,
+}
+
+impl ExportFormat {
+	/// Guess the format from a file extension, like "results.csv".
+	pub fn from_path(path: &Path) -> Option<ExportFormat> {
+		match path.extension()?.to_str()?.to_lowercase().as_str() {
+			"csv" => Some(ExportFormat::Csv),
+			"json" => Some(ExportFormat::Json),
+			_ => None,
+		}
+	}
+}
+
+/// What Engine::open_or_create may do with the file it's given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+	CreateNew, // Fail if the file exists.
+	OpenExisting, // Fail if the file doesn't exist or has no DB in it.
+	OpenOrCreate,
+}
+
+pub struct Engine {
+	connection: Arc<FairMutex<Connection>>,
+
+	// Crawling and indexing:
+	files_crawled: Option<channel::Receiver<(PathBuf, crawler::CrawlOptions)>>,
+	files_processed: Option<channel::Receiver<IndexedImage>>, // What images have been loaded but are not stored.
+	files_completed: Option<channel::Receiver<String>>,
+	files_failed: Option<channel::Receiver<String>>,
+	indexing_threads: Vec<JoinHandle<()>>, // Storage threads from start_reindexing.  Each one waits for its crawler to finish.
+	stop_indexing: Arc<AtomicBool>, // Set when shutting down so background jobs, like re-embedding, stop.
+	indexing_cancelled: Arc<AtomicBool>, // Set by cancel_indexing, or when shutting down, to stop the runs going now.  Runs started after a cancel get a new one.
+	indexing_paused: Arc<AtomicBool>, // Set to hold crawlers and storage where they are until it's cleared.
+	indexing_counters: Option<(Arc<crawler::CrawlCounters>, Instant)>, // For the last indexing run, and when it started.
+	folder_watcher: Option<ChangeWatcher>, // Made by index_watched_changes while watch_folders is on.
+	read_only: bool, // True for collections shared with share_collection.
+	backup_thread: Option<JoinHandle<()>>, // The last scheduled backup, which may still be running.
+	compaction_job: Option<channel::Receiver<Result<u64>>>, // Bytes saved, once the job finishes.
+	maintenance_job: Option<channel::Receiver<Result<MaintenanceReport>>>,
+	reembedding_job: Option<(channel::Receiver<Result<u64>>, Arc<AtomicU64>, u64)>, // Images re-embedded once it's done, how many are done so far, and how many there were to do.
+	drift_job: Option<channel::Receiver<Result<EmbeddingDrift>>>,
+	duplicate_scan: Option<channel::Receiver<Result<Vec<SimilarGroup>>>>,
+	next_backup_check: Instant,
+	last_indexed: Vec<String>, // A cache of the last n indexed items.
+	watched_directories_cache: Option<Vec<String>>, // Contains a list of the globs that we monitor.
+	folder_settings: HashMap<String, crawler::CrawlFolder>, // Filled in with watched_directories_cache.
+	rules_cache: Option<Vec<Rule>>,
+	saved_searches_cache: Option<Vec<SavedSearch>>,
+	collections_cache: Option<Vec<Collection>>,
+	unlocked_collections: HashSet<i64>, // Hidden collections whose passphrase was given this session.
+	cached_index_size: Option<usize>, // Number of indexed images.
+	trashed_images_cache: Option<Vec<IndexedImage>>,
+
+	// Searching and filtering.
+	pub max_search_results: u64,
+	pub max_distance_from_query: f64,
+	pub sort_order: SortOrder, // Used when a query doesn't have a sort: prefix.
+	pub trash_retention_days: u32,
+	pub warn_on_near_duplicates: bool, // Hold near-duplicates for review while indexing instead of adding them.
+	pub near_duplicate_distance: f64,
+	pub show_duplicates: bool, // Include images marked as duplicates of a canonical image in search results.
+	pub index_book_pages: bool, // Index every page of ebooks and comics, not just the cover.
+	pub max_archive_depth: u32, // How many archives deep to look for archives inside archives while indexing.
+	pub hash_cropped_frames: bool, // Also embed each image without its borders and edges while indexing, for method:cropped.
+	pub hash_animation_frames: bool, // Embed animations by several of their frames averaged, not just the middle one.
+	pub index_newest_first: bool, // Index the most recently modified files first, so new photos are searchable early in a long reindex.
+	pub skip_hidden_files: bool, // Don't index hidden files or anything in hidden folders, like .thumbnails, .cache, and .git.
+	pub min_file_size_kb: u64, // Don't index files smaller than this, like icons and tracking pixels.
+	pub max_file_size_mb: u64, // Don't index files bigger than this.  0 for no limit.
+	pub max_files_per_second: u32, // Index at most this many files a second, to keep the CPU and fans down.  0 for no limit.
+	pub low_priority_indexing: bool, // Let everything else on the computer have the CPU before indexing does.
+	pub crawler_threads: usize, // How many files are loaded at once while indexing.
+	pub embedding_threads: usize, // How many of those can run the embedding model at once.  0 for all of them.
+	pub network_timeout_seconds: u32, // Give up on a folder that hasn't answered in this long, like one on a NAS that's asleep.  0 to wait as long as it takes.
+	pub network_retries: u32, // How many more times to try a folder that timed out before skipping it until the next index.
+	pub watch_folders: bool, // Index files as they're added to or changed in watched folders, and trash the ones deleted from them.
+	pub ranking_weights: RankingWeights,
+	pub prefilter_candidates: u64, // Compare embeddings for only this many of the images with the closest phashes.  0 compares them all.
+	pub reembed_on_open: bool, // Redo embeddings from an old model as soon as the DB is opened.  Off by default, which leaves time to check start_measuring_drift first.
+
+	// Scheduled backups.
+	pub backup_directory: String, // Empty to turn off scheduled backups.
+	pub backup_interval_hours: u32,
+	pub backup_chains_to_keep: u32, // Full backups to keep, each with the incremental backups made after it.
+	cached_search_results: Option<Vec<IndexedImage>>,  // For keeping track of the last time a query ran.
+	cached_search_total: Option<u64>, // Total results across all pages of the last text query.  None if the last query wasn't paged.
+	running_query: Option<channel::Receiver<(Result<QueryPage>, Option<IndexedImage>)>>, // Results and the similar: image from a background query.
+	running_query_cancel: Option<QueryCancelHandle>,
+	interrupt_handle: Arc<InterruptHandle>,
+	query_error: Option<String>, // Why the last background query failed.
+	cached_image_search: Option<IndexedImage>, // If the user is searching for a similar image: "similar:abc", this is the path.  We should compare when the abc changes.
+}
+
+impl Drop for Engine {
+	fn drop(&mut self) {
+		if let Err(e) = self.finish_indexing() {
+			eprintln!("Failed to finish indexing: {}", e);
+		}
+		// Let a running backup finish rather than leave a half-written file behind.
+		if let Some(backup_thread) = self.backup_thread.take() {
+			let _ = backup_thread.join();
+		}
+	}
+}
+
+impl Engine {
+	/// Open or make the DB at filename, as mode allows.  DBs from older versions are brought up to date.
+	pub fn open_or_create(filename:&Path, mode: OpenMode) -> Result<Self> {
+		let exists = filename.exists();
+		match (mode, exists) {
+			(OpenMode::CreateNew, true) => return Err(anyhow!("{} already exists.", filename.display())),
+			(OpenMode::OpenExisting, false) => return Err(anyhow!("{} doesn't exist.", filename.display())),
+			_ => {},
+		}
+		let mut conn = Connection::open(filename).map_err(|e| anyhow!("Unable to open DB {}: {}", filename.display(), e))?;
+
+		// Catch files that aren't SQLite or aren't ours now, rather than on the first search.  An empty DB is fine to fill in.
+		let table_names: Vec<String> = conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")
+			.and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+			.map_err(|e| anyhow!("{} isn't a PixelBox DB: {}", filename.display(), e))?;
+		let is_new = table_names.is_empty() && mode != OpenMode::OpenExisting;
+		if !is_new && !table_names.iter().any(|t| t == "images") {
+			return Err(anyhow!("{} isn't a PixelBox DB.", filename.display()));
+		}
+		migrate(&mut conn)?;
+
+		make_hamming_distance_db_function(&mut conn)?;
+		make_byte_distance_db_function(&mut conn)?;
+		make_cosine_distance_db_function(&mut conn)?;
+		make_palette_distance_db_function(&mut conn)?;
+		make_layout_distance_db_function(&mut conn)?;
+		make_haversine_distance_db_function(&mut conn)?;
+		let read_only = conn.query_row("SELECT 1 FROM settings WHERE name = 'shared_collection'", [], |_| Ok(())).optional()?.is_some();
+		if read_only {
+			conn.pragma_update(None, "query_only", true)?;
+		}
+
+		let interrupt_handle = Arc::new(conn.get_interrupt_handle());
+		let mut engine = Engine {
+			connection: Arc::new(FairMutex::new(conn)),
+			files_crawled: None,
+			files_processed: None,
+			files_completed: None,
+			files_failed: None,
+			indexing_threads: vec![],
+			stop_indexing: Arc::new(AtomicBool::new(false)),
+			indexing_cancelled: Arc::new(AtomicBool::new(false)),
+			indexing_paused: Arc::new(AtomicBool::new(false)),
+			indexing_counters: None,
+			folder_watcher: None,
+			read_only,
+			backup_thread: None,
+			compaction_job: None,
+			maintenance_job: None,
+			reembedding_job: None,
+			drift_job: None,
+			duplicate_scan: None,
+			next_backup_check: Instant::now(),
+			last_indexed: vec![],
+			watched_directories_cache: None,
+			folder_settings: HashMap::new(),
+			rules_cache: None,
+			saved_searches_cache: None,
+			collections_cache: None,
+			unlocked_collections: HashSet::new(),
+			cached_index_size: None,
+			trashed_images_cache: None,
+
+			max_search_results: DEFAULT_MAX_SEARCH_RESULTS,
+			max_distance_from_query: DEFAULT_MAX_QUERY_DISTANCE,
+			sort_order: SortOrder::default(),
+			trash_retention_days: DEFAULT_TRASH_RETENTION_DAYS,
+			warn_on_near_duplicates: false,
+			near_duplicate_distance: DEFAULT_NEAR_DUPLICATE_DISTANCE,
+			show_duplicates: false,
+			index_book_pages: false,
+			max_archive_depth: 3,
+			hash_cropped_frames: false,
+			hash_animation_frames: false,
+			index_newest_first: false,
+			skip_hidden_files: true,
+			min_file_size_kb: 0,
+			max_file_size_mb: 0,
+			max_files_per_second: 0,
+			low_priority_indexing: false,
+			crawler_threads: DEFAULT_CRAWLER_THREADS,
+			embedding_threads: 0,
+			network_timeout_seconds: 30,
+			network_retries: 2,
+			watch_folders: false,
+			ranking_weights: RankingWeights::default(),
+			prefilter_candidates: 0,
+			reembed_on_open: false,
+			backup_directory: String::new(),
+			backup_interval_hours: DEFAULT_BACKUP_INTERVAL_HOURS,
+			backup_chains_to_keep: DEFAULT_BACKUP_CHAINS_TO_KEEP,
+			cached_search_results: None,
+			cached_search_total: None,
+			running_query: None,
+			running_query_cancel: None,
+			interrupt_handle,
+			query_error: None,
+			cached_image_search: None,
+		};
+
+		engine.load_settings();
+
+		if !engine.read_only {
+			if let Err(e) = engine.empty_trash(engine.trash_retention_days) {
+				eprintln!("Failed to remove expired images from the trash: {}", e);
+			}
+			// Searches can't compare embeddings from different models, so anything from an old model is redone right away if that's turned on.
+			let num_stale = count_stale_embeddings(&engine.connection.lock(), mlhash_model_version());
+			match num_stale {
+				Ok(n) if n > 0 && mlhash_model_version().is_some() && engine.reembed_on_open => {
+					if let Err(e) = engine.start_reembedding() {
+						eprintln!("Failed to start re-embedding: {}", e);
+					}
+				},
+				Ok(_) => {},
+				Err(e) => eprintln!("Failed to check for embeddings from an old model: {}", e),
+			}
+		}
+
+		Ok(engine)
+	}
+
+	/// Shared collections can be searched but not changed.
+	pub fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	/// Stop indexing, store whatever was already loaded, and close the DB.
+	/// Dropping the engine does the same, but can't say if anything went wrong.
+	pub fn close(mut self) -> Result<()> {
+		self.finish_indexing()?;
+		self.cancel_query();
+		let connection = self.connection.clone();
+		drop(self);
+		// A background query might still have the connection.  It'll be closed when that finishes instead.
+		if let Ok(connection) = Arc::try_unwrap(connection) {
+			connection.into_inner().close().map_err(|(_, e)| e)?;
+		}
+		Ok(())
+	}
+
+	/// Stop crawling and loading new files, then wait for the images that were already loaded to be stored.
+	fn finish_indexing(&mut self) -> Result<()> {
+		self.stop_indexing.store(true, Ordering::Relaxed);
+		self.indexing_cancelled.store(true, Ordering::Relaxed);
+		let panicked = self.indexing_threads.drain(..).map(|t| t.join()).filter(|r| r.is_err()).count();
+		if panicked > 0 {
+			return Err(anyhow!("An indexing thread crashed.  Some images may not have been stored."));
+		}
+		Ok(())
+	}
+
+	/// Read the per-database settings, keeping the defaults for anything missing or unreadable.
+	fn load_settings(&mut self) {
+		let stored: HashMap<String, String> = {
+			let conn = self.connection.lock();
+			let mut stmt = match conn.prepare("SELECT name, value FROM settings") {
+				Ok(stmt) => stmt,
+				Err(e) => {
+					eprintln!("Failed to read settings, using defaults: {}", e);
+					return;
+				}
+			};
+			let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)));
+			match rows {
+				Ok(rows) => rows.flatten().collect(),
+				Err(_) => HashMap::new(),
+			}
+		};
+
+		if let Some(v) = stored.get("max_search_results").and_then(|v| v.parse().ok()) {
+			self.max_search_results = v;
+		}
+		if let Some(v) = stored.get("max_distance_from_query").and_then(|v| v.parse().ok()) {
+			self.max_distance_from_query = v;
+		}
+		if let Some(v) = stored.get("sort_order").and_then(|v| SortOrder::parse(v).ok()) {
+			self.sort_order = v;
+		}
+		if let Some(v) = stored.get("trash_retention_days").and_then(|v| v.parse().ok()) {
+			self.trash_retention_days = v;
+		}
+		if let Some(v) = stored.get("warn_on_near_duplicates").and_then(|v| v.parse().ok()) {
+			self.warn_on_near_duplicates = v;
+		}
+		if let Some(v) = stored.get("near_duplicate_distance").and_then(|v| v.parse().ok()) {
+			self.near_duplicate_distance = v;
+		}
+		if let Some(v) = stored.get("show_duplicates").and_then(|v| v.parse().ok()) {
+			self.show_duplicates = v;
+		}
+		if let Some(v) = stored.get("index_book_pages").and_then(|v| v.parse().ok()) {
+			self.index_book_pages = v;
+		}
+		if let Some(v) = stored.get("max_archive_depth").and_then(|v| v.parse().ok()) {
+			self.max_archive_depth = v;
+		}
+		if let Some(v) = stored.get("hash_cropped_frames").and_then(|v| v.parse().ok()) {
+			self.hash_cropped_frames = v;
+		}
+		if let Some(v) = stored.get("hash_animation_frames").and_then(|v| v.parse().ok()) {
+			self.hash_animation_frames = v;
+		}
+		if let Some(v) = stored.get("index_newest_first").and_then(|v| v.parse().ok()) {
+			self.index_newest_first = v;
+		}
+		if let Some(v) = stored.get("skip_hidden_files").and_then(|v| v.parse().ok()) {
+			self.skip_hidden_files = v;
+		}
+		if let Some(v) = stored.get("min_file_size_kb").and_then(|v| v.parse().ok()) {
+			self.min_file_size_kb = v;
+		}
+		if let Some(v) = stored.get("max_file_size_mb").and_then(|v| v.parse().ok()) {
+			self.max_file_size_mb = v;
+		}
+		if let Some(v) = stored.get("max_files_per_second").and_then(|v| v.parse().ok()) {
+			self.max_files_per_second = v;
+		}
+		if let Some(v) = stored.get("low_priority_indexing").and_then(|v| v.parse().ok()) {
+			self.low_priority_indexing = v;
+		}
+		if let Some(v) = stored.get("crawler_threads").and_then(|v| v.parse().ok()) {
+			self.crawler_threads = v;
+		}
+		if let Some(v) = stored.get("embedding_threads").and_then(|v| v.parse().ok()) {
+			self.embedding_threads = v;
+		}
+		if let Some(v) = stored.get("network_timeout_seconds").and_then(|v| v.parse().ok()) {
+			self.network_timeout_seconds = v;
+		}
+		if let Some(v) = stored.get("network_retries").and_then(|v| v.parse().ok()) {
+			self.network_retries = v;
+		}
+		if let Some(v) = stored.get("watch_folders").and_then(|v| v.parse().ok()) {
+			self.watch_folders = v;
+		}
+		if let Some(v) = stored.get("visual_weight").and_then(|v| v.parse().ok()) {
+			self.ranking_weights.visual = v;
+		}
+		if let Some(v) = stored.get("text_weight").and_then(|v| v.parse().ok()) {
+			self.ranking_weights.text = v;
+		}
+		if let Some(v) = stored.get("prefilter_candidates").and_then(|v| v.parse().ok()) {
+			self.prefilter_candidates = v;
+		}
+		if let Some(v) = stored.get("reembed_on_open").and_then(|v| v.parse().ok()) {
+			self.reembed_on_open = v;
+		}
+		if let Some(v) = stored.get("backup_directory") {
+			self.backup_directory = v.clone();
+		}
+		if let Some(v) = stored.get("backup_interval_hours").and_then(|v| v.parse().ok()) {
+			self.backup_interval_hours = v;
+		}
+		if let Some(v) = stored.get("backup_chains_to_keep").and_then(|v| v.parse().ok()) {
+			self.backup_chains_to_keep = v;
+		}
+	}
+
+	/// The settings as they're stored in the settings table.
+	fn settings_values(&self) -> Vec<(&'static str, String)> {
+		vec![
+			("max_search_results", self.max_search_results.to_string()),
+			("max_distance_from_query", self.max_distance_from_query.to_string()),
+			("sort_order", self.sort_order.to_query_value()),
+			("trash_retention_days", self.trash_retention_days.to_string()),
+			("warn_on_near_duplicates", self.warn_on_near_duplicates.to_string()),
+			("near_duplicate_distance", self.near_duplicate_distance.to_string()),
+			("show_duplicates", self.show_duplicates.to_string()),
+			("index_book_pages", self.index_book_pages.to_string()),
+			("max_archive_depth", self.max_archive_depth.to_string()),
+			("hash_cropped_frames", self.hash_cropped_frames.to_string()),
+			("hash_animation_frames", self.hash_animation_frames.to_string()),
+			("index_newest_first", self.index_newest_first.to_string()),
+			("skip_hidden_files", self.skip_hidden_files.to_string()),
+			("min_file_size_kb", self.min_file_size_kb.to_string()),
+			("max_file_size_mb", self.max_file_size_mb.to_string()),
+			("max_files_per_second", self.max_files_per_second.to_string()),
+			("low_priority_indexing", self.low_priority_indexing.to_string()),
+			("crawler_threads", self.crawler_threads.to_string()),
+			("embedding_threads", self.embedding_threads.to_string()),
+			("network_timeout_seconds", self.network_timeout_seconds.to_string()),
+			("network_retries", self.network_retries.to_string()),
+			("watch_folders", self.watch_folders.to_string()),
+			("visual_weight", self.ranking_weights.visual.to_string()),
+			("text_weight", self.ranking_weights.text.to_string()),
+			("prefilter_candidates", self.prefilter_candidates.to_string()),
+			("reembed_on_open", self.reembed_on_open.to_string()),
+			("backup_directory", self.backup_directory.clone()),
+			("backup_interval_hours", self.backup_interval_hours.to_string()),
+			("backup_chains_to_keep", self.backup_chains_to_keep.to_string()),
+		]
+	}
+
+	pub fn settings(&self) -> Settings {
+		Settings(self.settings_values())
+	}
+
+	/// Store the search and retention settings in the database so they follow it around.
+	pub fn save_settings(&self) -> Result<()> {
+		let settings = self.settings_values();
+		let mut conn = self.connection.lock();
+		let tx = conn.transaction()?;
+		for (name, value) in settings {
+			tx.execute("INSERT OR REPLACE INTO settings (name, value) VALUES (?, ?)", params![name, value])?;
+		}
+		tx.commit()?;
+		Ok(())
+	}
+
+	/// True until everything found has been stored.  The storage threads end once the crawlers feeding them do.
+	pub fn is_indexing_active(&self) -> bool {
+		self.indexing_threads.iter().any(|t| !t.is_finished())
+	}
+
+	/// Hold indexing where it is to free up the CPU and disk.  Whatever was found or loaded stays queued until resume_indexing.
+	/// Runs started while paused wait too.
+	pub fn pause_indexing(&self) {
+		self.indexing_paused.store(true, Ordering::Relaxed);
+	}
+
+	pub fn resume_indexing(&self) {
+		self.indexing_paused.store(false, Ordering::Relaxed);
+	}
+
+	pub fn is_indexing_paused(&self) -> bool {
+		self.indexing_paused.load(Ordering::Relaxed)
+	}
+
+	/// Stop the indexing runs going now.  Nothing more is found or loaded, but what was already loaded is still stored, even while paused.
+	/// Returns right away.  is_indexing_active stays true until the threads have finished storing.
+	pub fn cancel_indexing(&self) {
+		self.indexing_cancelled.store(true, Ordering::Relaxed);
+	}
+
+	/// True while cancelled runs are still storing what they loaded.
+	pub fn is_indexing_cancelled(&self) -> bool {
+		self.indexing_cancelled.load(Ordering::Relaxed) && self.is_indexing_active()
+	}
+
+	/// From 0 to 1, for the last indexing run.  0 if nothing has been indexed since the DB was opened.
+	pub fn get_indexing_progress(&self) -> f32 {
+		self.get_indexing_progress_details().map(|progress| progress.fraction()).unwrap_or(0.0)
+	}
+
+	/// What the last indexing run has found and done, with an estimate of how long it has left.
+	pub fn get_indexing_progress_details(&self) -> Option<IndexingProgress> {
+		let (counters, started) = self.indexing_counters.as_ref()?;
+		Some(IndexingProgress::from_counters(counters, started.elapsed()))
+	}
+
+	/// The folders the last indexing run couldn't reach, like ones on a NAS that's asleep, by their globs.
+	/// They're skipped rather than failing file by file, and tried again the next time folders are indexed.
+	pub fn get_unreachable_folders(&self) -> Vec<String> {
+		self.indexing_counters.as_ref().map(|(counters, _)| counters.unreachable.lock().clone()).unwrap_or_default()
+	}
+
+	/// Everything that couldn't be indexed, most recent first.  Kept across runs until it's indexed or cleared.
+	pub fn get_indexing_failures(&self) -> Result<Vec<IndexingFailure>> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare("SELECT path, error, failed FROM failures ORDER BY failed DESC, path")?;
+		let failure_cursor = stmt.query_map([], |row| {
+			Ok(IndexingFailure { path: row.get(0)?, error: row.get(1)?, failed: row.get(2)? })
+		})?;
+		Ok(failure_cursor.collect::<SQLResult<Vec<IndexingFailure>>>()?)
+	}
+
+	/// Forget every recorded failure.  Returns how many there were.
+	pub fn clear_indexing_failures(&mut self) -> Result<usize> {
+		Ok(self.connection.lock().execute("DELETE FROM failures", [])?)
+	}
+
+	fn record_indexing_failure(conn: &Connection, path:&str, error:&str) {
+		let recorded = conn.execute("INSERT OR REPLACE INTO failures (path, error, failed) VALUES (?, ?, datetime('now'))", params![path, error])
+			.and_then(|_| Engine::clear_container_failures(conn, path));
+		if let Err(e) = recorded {
+			eprintln!("Failed to record that {} couldn't be indexed: {}", path, e);
+		}
+	}
+
+	/// Take the archives, books, and mail that the entry at path is inside out of the failure log.
+	/// Their entries can only be reached once they can be read, so anything logged for them is out of date.
+	fn clear_container_failures(conn: &Connection, path:&str) -> SQLResult<()> {
+		for (end, _) in path.match_indices(archive::ENTRY_SEPARATOR) {
+			conn.execute("DELETE FROM failures WHERE path = ?", params![&path[..end]])?;
+		}
+		Ok(())
+	}
+
+	pub fn try_get_num_indexed_images(&self) -> Option<usize> {
+		self.cached_index_size
+	}
+
+	/// Perform a count of the number of indexed images and cache the value.
+	pub fn get_num_indexed_images(&mut self) -> Result<usize> {
+		let num_images: usize = self.connection.lock().query_row("SELECT COUNT(*) FROM images WHERE trashed IS NULL", [], |row| row.get(0))?;
+		self.cached_index_size = Some(num_images);
+		Ok(num_images)
+	}
+
+	pub fn get_last_indexed(&mut self) -> &Vec<String> {
+		if let Some(rx) = &self.files_completed {
+			while let Ok(msg) = rx.recv_timeout(Duration::from_nanos(1)) {
+				self.last_indexed.push(msg);
+			}
+		}
+
+		// Cap last indexed to 10.
+		while self.last_indexed.len() > 10 {
+			self.last_indexed.remove(0);
+		}
+
+		&self.last_indexed
+	}
+
+	pub fn get_indexing_status(&mut self) -> IndexingStatus {
+		let num_unread = self.files_crawled.as_ref().map(|rx| rx.len()).unwrap_or(0);
+		let num_unprocessed = self.files_processed.as_ref().map(|rx| rx.len()).unwrap_or(0);
+		IndexingStatus {
+			active: self.is_indexing_active(),
+			paused: self.is_indexing_paused(),
+			stopping: self.is_indexing_cancelled(),
+			progress: self.get_indexing_progress(),
+			details: self.get_indexing_progress_details(),
+			num_indexed: self.try_get_num_indexed_images().unwrap_or(0),
+			num_pending: num_unread + num_unprocessed,
+			last_indexed: self.get_last_indexed().clone(),
+			unreachable: self.get_unreachable_folders(),
+		}
+	}
+
+	pub fn start_reindexing(&mut self) -> Result<()> {
+		// Select all our monitored folders and, in parallel, dir walk them to grab new images.
+		let all_globs:Vec<String> = self.get_tracked_folders()?.clone();
+		let folders = all_globs.iter().map(|g| self.get_folder_settings(g)).collect::<Result<Vec<crawler::CrawlFolder>>>()?;
+		self.start_indexing(folders, vec![]);
+		Ok(())
+	}
+
+	/// Like start_reindexing, but only walks one of the watched folders, so a folder that changes often can be refreshed without waiting on the others.
+	pub fn reindex_folder(&mut self, folder_glob:&str) -> Result<()> {
+		if !self.get_tracked_folders()?.iter().any(|glob| glob == folder_glob) {
+			return Err(anyhow!("'{}' isn't a watched folder.", folder_glob));
+		}
+		let folder = self.get_folder_settings(folder_glob)?;
+		self.start_indexing(vec![folder], vec![]);
+		Ok(())
+	}
+
+	/// Run everything in the failure log through indexing again.  What's stored this time is taken out of the log, and what fails again is logged with its new error.
+	/// Images in books, archives, and mail are retried by loading the whole file they're in, which is taken out of the log once its entries can be reached.
+	/// Returns how many files and folders were queued.
+	pub fn retry_failed(&mut self) -> Result<usize> {
+		let mut files: Vec<PathBuf> = vec![];
+		let mut folders: Vec<crawler::CrawlFolder> = vec![];
+		for failure in self.get_indexing_failures()? {
+			let path = filepath_from_string(archive::container_path(&failure.path));
+			if path.is_dir() {
+				// Folders fail when they can't be walked, and nothing gets stored under their path to clear them, so they're cleared once they can be read.
+				if std::fs::read_dir(&path).is_ok() {
+					self.connection.lock().execute("DELETE FROM failures WHERE path = ?", params![&failure.path])?;
+				}
+				folders.push(self.get_folder_settings(&glob::Pattern::escape(&failure.path))?);
+			} else if !files.contains(&path) {
+				files.push(path);
+			}
+		}
+		let queued = files.len() + folders.len();
+		if queued > 0 {
+			// Files are loaded with the options of the watched folder they're in, like its archive password.
+			let options = self.crawl_options();
+			let all_globs: Vec<String> = self.get_tracked_folders()?.clone();
+			let watched = all_globs.iter().map(|g| self.get_folder_settings(g)).collect::<Result<Vec<crawler::CrawlFolder>>>()?;
+			let files = files.into_iter().map(|file| {
+				let file_options = watched.iter().filter(|folder| folder.wants(&file)).max_by_key(|folder| folder.priority).map(|folder| folder.crawl_options(&options)).unwrap_or_else(|| options.clone());
+				(file, file_options)
+			}).collect();
+			self.start_indexing(folders, files);
+		}
+		Ok(queued)
+	}
+
+	/// While watch_folders is on, index what's been added to or changed in the watched folders since the last call, and trash the images of files deleted or moved out of them.
+	/// Changes are sorted out and trashed on a thread of their own, so this is cheap enough to call every frame.
+	/// What they add waits while indexing is running.  Returns how many changed files and folders were handled.
+	pub fn index_watched_changes(&mut self) -> Result<usize> {
+		if !self.watch_folders || self.read_only {
+			self.folder_watcher = None;
+			return Ok(0);
+		}
+		if self.folder_watcher.is_none() {
+			let all_globs:Vec<String> = self.get_tracked_folders()?.clone();
+			let watched = all_globs.iter().map(|g| self.get_folder_settings(g)).collect::<Result<Vec<crawler::CrawlFolder>>>()?;
+			match crawler::FolderWatcher::new(&watched) {
+				Ok(watcher) => self.watch_changes(watcher, watched),
+				Err(e) => {
+					// It won't work any better on the next frame.
+					self.watch_folders = false;
+					return Err(e);
+				},
+			}
+		}
+		if self.is_indexing_active() {
+			return Ok(0);
+		}
+		let batches: Vec<WatchedChanges> = self.folder_watcher.as_ref().map(|watcher| watcher.to_index.try_iter().collect()).unwrap_or_default();
+		if batches.iter().any(|batch| batch.num_trashed > 0) {
+			self.cached_index_size = None;
+			self.clear_query_results();
+			self.trashed_images_cache = None;
+			self.collections_cache = None;
+		}
+
+		let options = self.crawl_options();
+		let mut num_changes = 0;
+		let mut folders: Vec<crawler::CrawlFolder> = vec![];
+		let mut files: Vec<(PathBuf, crawler::CrawlOptions)> = vec![];
+		for batch in batches {
+			num_changes += batch.num_changes;
+			folders.extend(batch.folders);
+			files.extend(batch.files.into_iter().map(|(path, folder)| (path, folder.crawl_options(&options))));
+		}
+		if !folders.is_empty() || !files.is_empty() {
+			self.start_indexing(folders, files);
+		}
+		Ok(num_changes)
+	}
+
+	/// Start the thread that sorts out the changes the watcher sees in the watched folders.  See ChangeWatcher.
+	/// Hidden files are skipped or not as skip_hidden_files is now.  Changing the watched folders makes a new one.
+	fn watch_changes(&mut self, watcher:crawler::FolderWatcher, watched:Vec<crawler::CrawlFolder>) {
+		let (to_index_tx, to_index) = channel::unbounded();
+		let (stop, stopped) = channel::bounded::<()>(0);
+		let connection = self.connection.clone();
+		let skip_hidden = self.skip_hidden_files;
+		std::thread::spawn(move || loop {
+			let first = crossbeam::select! {
+				recv(watcher.changes()) -> changes => match changes {
+					Ok(changes) => changes,
+					Err(_) => return,
+				},
+				recv(stopped) -> _ => return,
+			};
+			let mut changes: Vec<PathBuf> = first.into_iter().chain(watcher.take_changes()).collect();
+			changes.sort();
+			changes.dedup();
+			match Engine::sort_out_changes(&connection, &watched, skip_hidden, changes) {
+				Ok(batch) => if to_index_tx.send(batch).is_err() {
+					return;
+				},
+				Err(e) => eprintln!("Failed to handle changes to the watched folders: {}", e),
+			}
+		});
+		self.folder_watcher = Some(ChangeWatcher { to_index, _stop: stop });
+	}
+
+	/// Trash what's gone from the watched folders, and work out how to index what's new.
+	fn sort_out_changes(connection:&FairMutex<Connection>, watched:&[crawler::CrawlFolder], skip_hidden:bool, changes:Vec<PathBuf>) -> Result<WatchedChanges> {
+		let mut batch = WatchedChanges { num_changes: changes.len(), num_trashed: 0, folders: vec![], files: vec![] };
+		for path in changes {
+			// A file on a share that's stopped answering isn't known to be gone, so it's left alone until it answers again.
+			let Ok(exists) = path.try_exists() else {
+				continue;
+			};
+			if !exists {
+				// Anything indexed from inside it goes too, whether it was a folder or a book, archive, or mail file.
+				let pathstring = stringify_filepath(&path);
+				batch.num_trashed += connection.lock().execute(
+					"UPDATE images SET trashed = datetime('now') WHERE trashed IS NULL AND protected = 0 AND (path = ?1 OR substr(path, 1, length(?2)) = ?2 OR substr(path, 1, length(?3)) = ?3)",
+					params![&pathstring, format!("{}{}", pathstring, std::path::MAIN_SEPARATOR), archive::entry_path(&pathstring, "")]
+				)?;
+				continue;
+			}
+			// Where folders overlap, the one with the highest priority says how.
+			let Some(folder) = watched.iter().filter(|folder| folder.wants(&path)).max_by_key(|folder| folder.priority) else {
+				continue;
+			};
+			if skip_hidden && crawler::is_hidden(&path, Path::new(&folder.glob)) {
+				continue;
+			}
+			if path.is_dir() {
+				// Folders moved in are walked like the watched folder they're now in.
+				batch.folders.push(crawler::CrawlFolder { glob: glob::Pattern::escape(&stringify_filepath(&path)), ..folder.clone() });
+			} else {
+				batch.files.push((path, folder.clone()));
+			}
+		}
+		Ok(batch)
+	}
+
+	fn start_indexing(&mut self, folders:Vec<crawler::CrawlFolder>,files:Vec<(PathBuf, crawler::CrawlOptions)>) {
+		// How this works:
+		// We select all our tracked folders from the database, then open a multi-stage pipeline:
+		// The crawl_globs_async begins to parallel crawl the filenames.
+		// As filenames are read, they're sent to the files_pending_processing queue.
+		// As files are read and converted into images they're sent to the files_pending_storage queue.
+		// Another thread (here) checks if images are already in the database and, if not, inserts them.
+		// Successes/failures to insert are reported to failure_tx/success_tx.
+		// Files that couldn't be loaded or stored are recorded in the failures table, and taken out of it once they're stored.
+
+		let (success_tx, success_rx) = crossbeam::channel::unbounded();
+		self.files_completed = Some(success_rx);
+		let (failure_tx, failure_rx) = crossbeam::channel::unbounded();
+		self.files_failed = Some(failure_rx);
+
+		// Image Processing Thread.
+		// file_rx / files_pending_processing
+		// img_rx / files_pending_storage
+		let counters = Arc::new(crawler::CrawlCounters::default());
+		self.indexing_counters = Some((counters.clone(), Instant::now()));
+		// Cancelled runs may still be storing what they loaded, but this one isn't cancelled with them.
+		if self.indexing_cancelled.load(Ordering::Relaxed) {
+			self.indexing_cancelled = Arc::new(AtomicBool::new(false));
+		}
+		set_max_embedding_threads(self.embedding_threads);
+		// Without a loader, nothing found would ever be loaded.
+		let (file_rx, img_rx, mut crawl_failure_rx) = crawler::crawl_async(folders, files, self.crawler_threads.max(1), self.crawl_options(), self.indexing_cancelled.clone(), self.indexing_paused.clone(), counters.clone());
+		self.files_crawled = Some(file_rx.clone());
+		self.files_processed = Some(img_rx.clone());
+		let w_conn = self.connection.clone();
+		let near_duplicate_distance = if self.warn_on_near_duplicates { Some(self.near_duplicate_distance) } else { None };
+		let rules: Vec<Rule> = match self.get_rules() {
+			Ok(rules) => rules.into_iter().filter(|r| r.enabled).collect(),
+			Err(e) => {
+				eprintln!("Failed to load rules.  Indexing without them: {}", e);
+				vec![]
+			}
+		};
+		let (stop, paused) = (self.indexing_cancelled.clone(), self.indexing_paused.clone());
+		// Finished runs don't need to be waited on at shutdown.
+		self.indexing_threads.retain(|t| !t.is_finished());
+		self.indexing_threads.push(std::thread::spawn(move || {
+			// To hold the lock as briefly as possible, we grab reads and writes very briefly.
+			// There is some overhead associated with getting the writes, so we might have to invert this pattern later.
+			loop {
+				let img = crossbeam::select! {
+					recv(crawl_failure_rx) -> failure => {
+						match failure {
+							Ok((path, error)) => Engine::record_indexing_failure(&w_conn.lock(), &path, &error),
+							// The loaders are done, but there may still be images queued.
+							Err(_) => crawl_failure_rx = crossbeam::channel::never(),
+						}
+						continue;
+					},
+					recv(img_rx) -> img => match img {
+						Ok(img) => img,
+						Err(_) => break,
+					},
+				};
+				// Shutting down while paused still stores what was loaded.
+				crawler::wait_while_paused(&paused, &stop);
+				let fname = img.filename.clone();
+				match Engine::is_excluded(&w_conn.lock(), &img.path) {
+					Ok(true) => {
+						let _ = success_tx.send(format!("{} (excluded)", fname));
+						continue;
+					},
+					Ok(false) => {},
+					Err(e) => eprintln!("Failed to check whether {} is excluded: {}", &img.path, &e),
+				}
+				let held = {
+					let conn = w_conn.lock();
+					Engine::hold_if_near_duplicate(&conn, &img, near_duplicate_distance)
+				};
+				match held {
+					Ok(true) => {
+						let _ = success_tx.send(format!("{} (held for duplicate review)", fname));
+						continue;
+					},
+					Ok(false) => {},
+					Err(e) => eprintln!("Failed to check {} for near-duplicates: {}", &img.path, &e),
+				}
+				// Quickly lock and unlock.
+				// Images already in our index are updated, and if they were trashed, finding them in a watched folder again restores them.
+				let path = img.path.clone();
+				let insert_result = {
+					let mut rw_conn = w_conn.lock();
+					Engine::insert_image(&mut rw_conn, img).and_then(|image_id| {
+						// If this was a near-duplicate that someone chose to keep, it no longer needs review.
+						rw_conn.execute("DELETE FROM duplicate_reviews WHERE path = ?", params![path])?;
+						rw_conn.execute("DELETE FROM failures WHERE path = ?", params![path])?;
+						Engine::clear_container_failures(&rw_conn, &path)?;
+						for rule in &rules {
+							if let Err(e) = rule.apply(&rw_conn, image_id) {
+								eprintln!("Failed to apply rule '{}' to {}: {}", &rule.name, &path, &e);
+							}
+						}
+						Ok(())
+					})
+				};
+				if let Err(e) = insert_result {
+					Engine::record_indexing_failure(&w_conn.lock(), &path, &e.to_string());
+					counters.failed.fetch_add(1, Ordering::Relaxed);
+					failure_tx.send(format!("{}: {}", fname, e));
+				} else {
+					counters.images_stored.fetch_add(1, Ordering::Relaxed);
+					success_tx.send(fname);
+				}
+			}
+			// Failures can still be queued when the last image is.
+			for (path, error) in crawl_failure_rx.try_iter() {
+				Engine::record_indexing_failure(&w_conn.lock(), &path, &error);
+			}
+			// The crawl's threads are done by now.  If one crashed, this one does too, so finish_indexing can report it.
+			if let Err(panic) = counters.join_threads() {
+				std::panic::resume_unwind(panic);
+			}
+			//conn.flush_prepared_statement_cache();
+		}));
+	}
+
+	//fn get_reindexing_status(&self) -> bool {}
+
+	/// Returns true if the image should stay out of the index for now.
+	/// That's the case if it was already held for review and not kept, or if max_distance is set and an indexed image is within it.
+	/// Images whose path is already indexed are never held.
+	/// Newly found near-duplicates are recorded in duplicate_reviews.
+	fn hold_if_near_duplicate(conn: &Connection, img: &IndexedImage, max_distance: Option<f64>) -> Result<bool> {
+		let resolution: Option<Option<String>> = conn.query_row(
+			"SELECT resolution FROM duplicate_reviews WHERE path = ?",
+			params![&img.path],
+			|row| row.get(0)
+		).optional()?;
+		if let Some(resolution) = resolution {
+			return Ok(resolution.as_deref() != Some("keep"));
+		}
+
+		let (max_distance, phash) = match (max_distance, &img.phash) {
+			(Some(d), Some(phash)) => (d, phash),
+			_ => return Ok(false),
+		};
+		let closest: Option<(i64, f64)> = conn.query_row(
+			"SELECT phashes.image_id, hamming_distance(?1, phashes.hash) AS dist
+			FROM phashes
+			INNER JOIN images ON images.id = phashes.image_id
+			WHERE images.trashed IS NULL AND dist <= ?2 AND NOT EXISTS (SELECT 1 FROM images WHERE path = ?3)
+			ORDER BY dist ASC
+			LIMIT 1",
+			params![phash, max_distance, &img.path],
+			|row| Ok((row.get(0)?, row.get(1)?))
+		).optional()?;
+		if let Some((existing_image_id, distance)) = closest {
+			conn.execute(
+				"INSERT INTO duplicate_reviews (path, existing_image_id, distance, found) VALUES (?, ?, ?, datetime('now'))",
+				params![&img.path, existing_image_id, distance]
+			)?;
+			return Ok(true);
+		}
+		Ok(false)
+	}
+
+	/// The options indexing crawls with, from the settings.
+	fn crawl_options(&self) -> crawler::CrawlOptions {
+		crawler::CrawlOptions {
+			index_book_pages: self.index_book_pages,
+			hash_cropped_frames: self.hash_cropped_frames,
+			hash_animation_frames: self.hash_animation_frames,
+			newest_first: self.index_newest_first,
+			skip_hidden: self.skip_hidden_files,
+			min_file_size: self.min_file_size_kb * 1024,
+			max_file_size: self.max_file_size_mb * 1024 * 1024,
+			max_files_per_second: self.max_files_per_second,
+			low_priority: self.low_priority_indexing,
+			io_timeout: Duration::from_secs(self.network_timeout_seconds as u64),
+			io_retries: self.network_retries,
+			skip_archives: false, // Set per folder.
+			max_archive_depth: self.max_archive_depth,
+			archive_password: None, // Set per folder.
+			exclusions: Arc::new(self.get_exclusion_set().unwrap_or_else(|e| {
+				eprintln!("Failed to load exclusions.  Only ones by path are checked, as images are stored: {}", e);
+				crawler::Exclusions::default()
+			})),
+		}
+	}
+
+	/// Add an image that's already been loaded, like one from IndexedImage::from_file_path, without crawling for it.
+	pub fn add_image(&mut self, img: IndexedImage) -> Result<i64> {
+		let image_id = Engine::insert_image(&mut self.connection.lock(), img)?;
+		self.cached_index_size = None;
+		Ok(image_id)
+	}
+
+	/// Returns the id of the image.  If its path is already indexed, that image is updated from this one and taken out of the trash.
+	/// Its id, rating, and user tags are kept, and so is its thumbnail unless the file's size changed.
+	fn insert_image(conn: &mut Connection, mut img:IndexedImage) -> Result<i64> {
+		// Update the images table first...
+		let location = gps_location(&img.tags);
+		img.id = conn.query_row(
+			"INSERT INTO images (filename, path, image_width, image_height, file_size, thumbnail, latitude, longitude, indexed) VALUES (?, ?, ?, ?, ?, ?, ?, ?, datetime('now'))
+			ON CONFLICT (path) DO UPDATE SET
+				filename = excluded.filename, image_width = excluded.image_width, image_height = excluded.image_height, file_size = excluded.file_size,
+				thumbnail = CASE WHEN file_size IS excluded.file_size THEN thumbnail ELSE excluded.thumbnail END,
+				latitude = excluded.latitude, longitude = excluded.longitude,
+				trashed = NULL
+			RETURNING id",
+			params![img.filename, img.path, img.resolution.0, img.resolution.1, img.file_size, img.thumbnail, location.map(|l| l.0), location.map(|l| l.1)],
+			|row| row.get(0)
+		)?;
+
+		// Insert the tags, replacing any read from the file before.
+		conn.execute("DELETE FROM tags WHERE image_id = ? AND source = ?", params![img.id, TAG_SOURCE_EXIF])?;
+		for (tag_name, tag_value) in img.tags.iter() {
+			conn.execute(
+				"INSERT INTO tags (image_id, name, value, source, number) VALUES (?, ?, ?, ?, ?)",
+				params![&img.id, tag_name, tag_value, TAG_SOURCE_EXIF, tag_number(tag_value)]
+			)?;
+		}
+
+		// Add the hashes.
+		if let Some(hash) = img.phash {
+			conn.execute(
+				"INSERT OR REPLACE INTO phashes (image_id, hash) VALUES (?, ?)",
+				params![img.id, hash]
+			)?;
+		}
+		if let Some(hash) = img.visual_hash {
+			conn.execute(
+				"INSERT OR REPLACE INTO semantic_hashes (image_id, hash, model) VALUES (?, ?, ?)",
+				params![img.id, hash, mlhash_model_version()]
+			)?;
+		}
+		if let Some(hash) = img.cropped_hash {
+			conn.execute(
+				"INSERT OR REPLACE INTO cropped_hashes (image_id, hash) VALUES (?, ?)",
+				params![img.id, hash]
+			)?;
+		}
+		if let Some(palette) = img.palette {
+			conn.execute(
+				"INSERT OR REPLACE INTO palettes (image_id, hash) VALUES (?, ?)",
+				params![img.id, palette]
+			)?;
+		}
+		if let Some(layout) = img.color_layout {
+			conn.execute(
+				"INSERT OR REPLACE INTO color_layouts (image_id, hash) VALUES (?, ?)",
+				params![img.id, layout]
+			)?;
+		}
+		record_screenshot_source(conn, img.id, &img.filename, img.tags.get(screenshot::WINDOW_TITLE_TAG).map(|title| title.as_str()))?;
+		// A changed file has a new thumbnail, so a blurred copy of the old one is made again.
+		conn.execute("DELETE FROM blurred_thumbnails WHERE image_id = ?", params![img.id])?;
+		update_blurred_thumbnail(conn, img.id)?;
+
+		Ok(img.id)
+	}
+
+	/// Start running the query in the background.  See start_query_page.
+	pub fn query(&mut self, user_input:&String) -> Result<()> {
+		if user_input.is_empty() {
+			return Ok(()); // Bail early!
+			// TODO: Should we clear results?
+		}
+		self.start_query_page(user_input, 0, self.max_search_results)
+	}
+
+	/// Start fetching the given (zero-indexed) page of results on a worker thread so the UI doesn't block on SQLite.
+	/// The last results stay available until the new ones are swapped in by is_query_running.
+	/// Starting a query cancels any query that's still running.  Syntax errors are returned immediately.
+	pub fn start_query_page(&mut self, user_input:&String, page:u64, page_size:u64) -> Result<()> {
+		tokenize_query(user_input)?;
+		self.cancel_query();
+		if page == 0 {
+			self.record_query(user_input);
+		}
+
+		let (result_tx, result_rx) = crossbeam::channel::bounded(1);
+		let cancel = QueryCancelHandle::new(self.interrupt_handle.clone());
+		self.running_query = Some(result_rx);
+		self.running_query_cancel = Some(cancel.clone());
+		self.query_error = None;
+
+		let conn = self.connection.clone();
+		let user_input = user_input.clone();
+		let settings = self.query_settings();
+		let mut image_search = self.cached_image_search.clone();
+		std::thread::spawn(move || {
+			let result = {
+				let conn = conn.lock();
+				cancel.run(&conn, |conn| {
+					Engine::run_query_page(conn, &user_input, page, page_size, &settings, &mut image_search)
+				})
+			};
+			// If this query was cancelled, nobody is listening any more.
+			let _ = result_tx.send((result, image_search));
+		});
+
+		Ok(())
+	}
+
+	/// Stop the query from start_query_page, if it's still running.  The last results stay as they were.
+	pub fn cancel_query(&mut self) {
+		if let Some(cancel) = self.running_query_cancel.take() {
+			cancel.cancel();
+		}
+		self.running_query = None;
+	}
+
+	/// For stopping the running query from another thread.  None if no query is running.
+	pub fn get_query_cancel_handle(&self) -> Option<QueryCancelHandle> {
+		self.running_query.as_ref().and(self.running_query_cancel.clone())
+	}
+
+	/// True while a query started with start_query_page is still running.
+	/// When the query finishes, its results replace the cached results and any error is available from take_query_error.
+	pub fn is_query_running(&mut self) -> bool {
+		let finished = match &self.running_query {
+			Some(rx) => match rx.try_recv() {
+				Ok(finished) => Some(finished),
+				Err(channel::TryRecvError::Empty) => return true,
+				Err(channel::TryRecvError::Disconnected) => None, // The worker died without sending anything.
+			},
+			None => return false,
+		};
+		self.running_query = None;
+		self.running_query_cancel = None;
+
+		match finished {
+			Some((Ok(query_page), image_search)) => {
+				self.cached_image_search = image_search;
+				self.cached_search_results = Some(query_page.results);
+				self.cached_search_total = Some(query_page.total_results);
+			},
+			Some((Err(e), image_search)) => {
+				self.cached_image_search = image_search;
+				self.cached_search_results = None;
+				self.cached_search_total = None;
+				self.query_error = Some(e.to_string());
+			},
+			None => {
+				self.query_error = Some("The query stopped unexpectedly.".to_string());
+			},
+		}
+		false
+	}
+
+	/// The error from the last background query, if it failed.  Cleared once taken.
+	pub fn take_query_error(&mut self) -> Option<String> {
+		self.query_error.take()
+	}
+
+	/// Run the query and fetch only the given (zero-indexed) page of results, blocking until it's done.
+	/// The page becomes the cached search results and the total is available from get_query_result_count.
+	pub fn query_page(&mut self, user_input:&String, page:u64, page_size:u64) -> Result<QueryPage> {
+		self.cancel_query(); // A background query finishing now would clobber these results.
+		self.cached_search_results = None;
+		self.cached_search_total = None;
+		if page == 0 {
+			self.record_query(user_input);
+		}
+
+		let query_page = {
+			let conn = self.connection.lock();
+			Engine::run_query_page(&conn, user_input, page, page_size, &self.query_settings(), &mut self.cached_image_search)?
+		};
+
+		self.cached_search_results = Some(query_page.results.clone());
+		self.cached_search_total = Some(query_page.total_results);
+		Ok(query_page)
+	}
+
+	/// Add the query to the search history.  Paging through results doesn't count as a new search.
+	/// Search-as-you-type runs "c", "ca", and "cat" in quick succession, so a recent one-off prefix of the query is replaced by it.
+	fn record_query(&mut self, user_input:&str) {
+		let query = user_input.trim();
+		if query.is_empty() || self.read_only {
+			return;
+		}
+		let mut conn = self.connection.lock();
+		let recorded = conn.transaction().and_then(|tx| {
+			tx.execute(
+				"DELETE FROM search_history
+				WHERE run_count = 1 AND query != ?1 AND substr(?1, 1, length(query)) = query
+				AND last_run >= strftime('%Y-%m-%d %H:%M:%f', 'now', ?2)",
+				params![query, format!("-{} seconds", SEARCH_HISTORY_MERGE_SECONDS)]
+			)?;
+			tx.execute(
+				"INSERT INTO search_history (query, last_run) VALUES (?, strftime('%Y-%m-%d %H:%M:%f', 'now'))
+				ON CONFLICT(query) DO UPDATE SET last_run = excluded.last_run, run_count = run_count + 1",
+				params![query]
+			)?;
+			tx.execute(
+				"DELETE FROM search_history WHERE query NOT IN (SELECT query FROM search_history ORDER BY last_run DESC LIMIT ?)",
+				params![MAX_SEARCH_HISTORY]
+			)?;
+			tx.commit()
+		});
+		if let Err(e) = recorded {
+			eprintln!("Failed to record search history: {}", e);
+		}
+	}
+
+	/// Up to n of the most recently run queries, newest first.
+	pub fn recent_queries(&self, n:usize) -> Result<Vec<RecentQuery>> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare("SELECT query, last_run, run_count FROM search_history ORDER BY last_run DESC LIMIT ?")?;
+		let query_cursor = stmt.query_map(params![n], |row| {
+			Ok(RecentQuery { query: row.get(0)?, last_run: row.get(1)?, run_count: row.get(2)? })
+		})?;
+		Ok(query_cursor.collect::<SQLResult<Vec<RecentQuery>>>()?)
+	}
+
+	pub fn clear_search_history(&mut self) -> Result<()> {
+		self.connection.lock().execute("DELETE FROM search_history", [])?;
+		Ok(())
+	}
+
+	/// Does the work of a query.  Kept separate from self so it can run on a worker thread.
+	/// image_search is the cached image for 'similar:' and is replaced if the query names a different image.
+	fn run_query_page(conn: &Connection, user_input:&String, page:u64, page_size:u64, settings:&QuerySettings, image_search:&mut Option<IndexedImage>) -> Result<QueryPage> {
+		// This will parse and process the full query.
+		// Magic phrases:
+		// filename: matches filename
+		// image: or file: does semantic matching
+		// tags: matches tags, comma-separated
+		// metadata: matches metadata
+		// min_width:, max_width:, min_height:, max_height:
+		// minsize:, maxsize: file size in bytes, with optional KB/MB/GB/TB suffix
+		// color: a hex color like #ff8800 that should be in the image's palette
+		// type: photo, screenshot, gif, raw, vector, texture, design, font, model, or audio.  Comma-separate or repeat to match any of several.
+		// rating: a number of stars, optionally after >=, <=, >, <, or !=
+		// fav: true or false
+		// collection: or album: the name of a collection, including smart collections
+		// sort: filename, path, resolution, size, indexed, rating, or distance, optionally followed by :asc or :desc
+		// method: which hash (semantic, phash, or cropped) and/or metric (cosine, hamming, or byte) similar: compares with, like method:phash:byte
+		// -term excludes results with term in the filename, path, or tags
+		// Absent all that, full-text search on all of these.
+
+		let parsed_query = tokenize_query(user_input)?;
+		let smart_collections = resolve_smart_collections(conn, &parsed_query, 0)?;
+		let mut where_clause = build_where_clause_from_parsed_query(&parsed_query, &smart_collections, image_search)?;
+		if where_clause.is_empty() {
+			where_clause = "1".to_string();
+		}
+		where_clause = format!("{} AND ({})", settings.visibility_filter, where_clause);
+		let sort_order = parse_sort_order_from_parsed_query(&parsed_query)?.unwrap_or(settings.default_sort_order);
+		let method = parse_similarity_method_from_parsed_query(&parsed_query)?.unwrap_or_default();
+		// Comparing embeddings is far slower than comparing phashes, so the phashes can narrow things down first.
+		// The candidates are picked from the images the rest of the query matches, so filters don't leave fewer than asked for.
+		if settings.prefilter_candidates > 0 && method.hash == SimilarityHash::Semantic {
+			if let Some(phash) = image_search.as_ref().and_then(|img| img.phash.as_ref()) {
+				let phash_hex: String = phash.iter().map(|b| format!("{:02x}", b)).collect();
+				where_clause = format!("
+					images.id IN (
+						SELECT images.id FROM images
+						INNER JOIN phashes ON images.id = phashes.image_id
+						INNER JOIN semantic_hashes ON images.id = semantic_hashes.image_id
+						LEFT JOIN tags ON images.id = tags.image_id
+						WHERE images.trashed IS NULL AND ({0})
+						GROUP BY images.id
+						ORDER BY hamming_distance(X'{1}', phashes.hash)
+						LIMIT {2}
+					) AND ({0})",
+					where_clause, phash_hex, settings.prefilter_candidates
+				);
+			}
+		}
+
+		let mut parameters: Vec<&dyn ToSql> = vec![];
+		let mut similar_hash_join = String::new();
+		let included_distance_hash = match image_search.as_ref().and_then(|img| method.hash.hash_of(img)) {
+			Some(hash) => {
+				parameters.push(hash);
+				// Images without this kind of hash can't be compared, so they're left out.
+				if method.hash != SimilarityHash::Semantic {
+					similar_hash_join = format!("INNER JOIN {0} ON images.id = {0}.image_id", method.hash.table());
+				}
+				let visual_distance = format!("{}(?, {}.hash)", method.metric.to_sql(), method.hash.table());
+				// With words in the query too, images whose names are mostly those words move up.
+				match text_relevance_sql(&parsed_query) {
+					Some(relevance) => settings.ranking_weights.to_sql(&visual_distance, &format!("(1.0 - {})", relevance)),
+					None => visual_distance,
+				}
+			},
+			None => "0.0".to_string()
+		};
+
+		// The page and the count share everything up to the ORDER BY.
+		let base_statement = format!("
+			WITH grouped_tags AS (
+				SELECT tags.image_id, JSON_GROUP_ARRAY(JSON_ARRAY(
+					tags.name, tags.value, tags.source
+				)) as tags
+				FROM tags
 				GROUP BY tags.image_id
 			)
 			SELECT
@@ -328,388 +2457,5232 @@ impl Engine {
 				{} AS dist
 			FROM images
 			INNER JOIN semantic_hashes ON images.id = semantic_hashes.image_id
+			{}
 			LEFT JOIN grouped_tags ON images.id = grouped_tags.image_id
 			LEFT JOIN tags ON images.id = tags.image_id
-			WHERE {}
+			WHERE images.trashed IS NULL AND ({})
 			GROUP BY images.id
-			ORDER BY dist ASC
-			LIMIT 100;
-		", SELECT_FIELDS, included_distance_hash, where_clause);
+		", SELECT_FIELDS, included_distance_hash, similar_hash_join, where_clause);
+		let count_statement = format!("SELECT COUNT(*) FROM ({})", base_statement);
+		let page_statement = format!("{} ORDER BY {} LIMIT ? OFFSET ?", base_statement, sort_order.to_sql());
+		// Nothing past max_search_results is ever shown, so cap the total and the final page.
+		let offset = page.saturating_mul(page_size);
+		let limit = page_size.min(settings.max_search_results.saturating_sub(offset)) as i64;
+		let offset = offset as i64;
+
+		let (results, total_results) = {
+			let total_results: u64 = conn.query_row(&count_statement, parameters.as_slice(), |row| row.get(0))?;
+			let total_results = total_results.min(settings.max_search_results);
+
+			// Try and perform the user's query (or some version of our assembled query).
+			let mut prepared_statement = conn.prepare(&page_statement)?;
+			parameters.push(&limit);
+			parameters.push(&offset);
+
+			// Parse and process results.
+			let result_cursor = prepared_statement.query_map(parameters.as_slice(), |row| {
+				let mut img = indexed_image_from_row(row)?;
+				img.visual_hash = row.get(SELECT_FIELDS_COUNT).ok();
+				let maybe_tag_data: SQLResult<JSONValue> = row.get(SELECT_FIELDS_COUNT+1);
+				img.tags = maybe_tag_data.map(|tag_data| tags_from_json(&tag_data)).unwrap_or_default();
+				img.distance_from_query = row.get(SELECT_FIELDS_COUNT+2).ok();
+				Ok(img)
+			})?;
+
+			(result_cursor.collect::<SQLResult<Vec<IndexedImage>>>()?, total_results)
+		};
+
+		Ok(QueryPage {
+			results,
+			page,
+			page_size,
+			total_results,
+		})
+	}
+
+	pub fn query_by_image_hash_from_file(&mut self, img:&Path) -> Result<()> {
+		self.cached_search_results = None;
+
+		let debug_start_load_image = Instant::now();
+		let indexed_image = IndexedImage::from_file_path(img)?;
+		let debug_end_load_image = Instant::now();
+		eprintln!("Time to compute image hash: {:?}", debug_end_load_image-debug_start_load_image);
+
+		self.query_by_image_hash_from_image(&indexed_image)
+	}
+
+	pub fn query_by_image_hash_from_image(&mut self, indexed_image:&IndexedImage) -> Result<()> {
+		match &indexed_image.visual_hash {
+			Some(hash) => self.query_by_embedding(hash),
+			None => Err(anyhow!("{} has no visual hash to search with.", indexed_image.path)),
+		}
+	}
+
+	/// Search with the embeddings of several example images combined into one, for when one example isn't enough.
+	/// Images from results that didn't fetch their embedding have it read from the DB.
+	pub fn query_by_multiple_images(&mut self, images:&[IndexedImage], pooling:EmbeddingPooling) -> Result<()> {
+		let mut embeddings = vec![];
+		{
+			let conn = self.connection.lock();
+			for img in images {
+				let hash: Vec<u8> = match &img.visual_hash {
+					Some(hash) => hash.clone(),
+					None => conn.query_row("SELECT hash FROM semantic_hashes WHERE image_id = ?", params![img.id], |row| row.get(0))
+						.optional()?
+						.ok_or_else(|| anyhow!("{} hasn't been hashed yet.", img.path))?,
+				};
+				embeddings.push(dequantize_embedding(&hash));
+			}
+		}
+		let combined = pooling.combine(&embeddings)?;
+		self.query_by_embedding(&quantize_embedding(&combined))
+	}
+
+	fn query_settings(&self) -> QuerySettings {
+		QuerySettings {
+			max_search_results: self.max_search_results,
+			default_sort_order: self.sort_order,
+			ranking_weights: self.ranking_weights,
+			visibility_filter: self.visibility_filter(),
+			prefilter_candidates: self.prefilter_candidates,
+		}
+	}
+
+	/// SQL for leaving images out of searches: duplicates, unless show_duplicates is set, and anything in a locked hidden collection.
+	fn visibility_filter(&self) -> String {
+		format!("{} AND {}", if self.show_duplicates { "1" } else { NOT_A_DUPLICATE }, self.hidden_filter())
+	}
+
+	/// SQL for leaving out images in hidden collections that haven't been unlocked.  Every listing of images should use it.
+	fn hidden_filter(&self) -> String {
+		let unlocked: Vec<String> = self.unlocked_collections.iter().map(|id| id.to_string()).collect();
+		format!(
+			"images.id NOT IN (SELECT collection_members.image_id FROM collection_members INNER JOIN collections ON collections.id = collection_members.collection_id WHERE collections.passphrase IS NOT NULL AND collections.id NOT IN ({}))",
+			unlocked.join(", ")
+		)
+	}
+
+	fn query_by_embedding(&mut self, hash:&Vec<u8>) -> Result<()> {
+		self.query_by_embedding_in(hash, "semantic_hashes")
+	}
+
+	/// hashes is semantic_hashes or a query aliased to it with the same columns.
+	fn query_by_embedding_in(&mut self, hash:&Vec<u8>, hashes:&str) -> Result<()> {
+		self.cancel_query();
+		self.cached_search_results = None;
+		self.cached_search_total = None;
+
+		let debug_start_db_query = Instant::now();
+		let conn = self.connection.lock();
+		// Take the nearest matches first, then sort those.  Sorting before the LIMIT would give the first N by name or date instead.
+		let mut stmt = conn.prepare(&format!(r#"
+			SELECT {}, nearest.hash, nearest.dist AS dist
+			FROM (
+				SELECT semantic_hashes.image_id AS image_id, semantic_hashes.hash AS hash, cosine_distance(?, semantic_hashes.hash) AS dist
+				FROM {}
+				INNER JOIN images images ON images.id = semantic_hashes.image_id
+				WHERE images.trashed IS NULL AND dist < ? AND {}
+				ORDER BY dist ASC, images.id ASC
+				LIMIT ?
+			) AS nearest
+			INNER JOIN images images ON images.id = nearest.image_id
+			ORDER BY {}"#, SELECT_FIELDS, hashes, self.visibility_filter(), self.sort_order.to_sql()
+		))?;
+		let img_cursor = stmt.query_map(params![hash, self.max_distance_from_query, self.max_search_results], |row|{
+			let mut img = indexed_image_from_row(row)?;
+			img.visual_hash = Some(row.get(SELECT_FIELDS_COUNT)?);
+			img.distance_from_query = Some(row.get(SELECT_FIELDS_COUNT+1)?);
+			Ok(img)
+		})?;
+
+		let results = img_cursor.collect::<SQLResult<Vec<IndexedImage>>>()?;
+		let debug_end_db_query = Instant::now();
+		eprintln!("Time to search DB: {:?}  Results: {:?}", debug_end_db_query-debug_start_db_query, results.len());
+		self.cached_search_results = Some(results);
+		Ok(())
+	}
+
+	/// Set the image's star rating, from 0 (unrated) to MAX_RATING.
+	/// Like the protected flag, it's stored on the images row so reindexing keeps it.
+	pub fn set_rating(&mut self, image_id: i64, rating: u8) -> Result<()> {
+		if rating > MAX_RATING {
+			return Err(anyhow!("Ratings go from 0 to {}, not {}.", MAX_RATING, rating));
+		}
+		self.connection.lock().execute("UPDATE images SET rating = ? WHERE id = ?", params![rating, image_id])?;
+
+		if let Some(results) = &mut self.cached_search_results {
+			results.iter_mut().filter(|img| img.id == image_id).for_each(|img| img.rating = rating);
+		}
+
+		Ok(())
+	}
+
+	/// Favorites are stored on the images row, so reindexing keeps them.
+	pub fn set_favorite(&mut self, image_id: i64, favorite: bool) -> Result<()> {
+		self.connection.lock().execute("UPDATE images SET favorite = ? WHERE id = ?", params![favorite, image_id])?;
+
+		if let Some(results) = &mut self.cached_search_results {
+			results.iter_mut().filter(|img| img.id == image_id).for_each(|img| img.favorite = favorite);
+		}
+
+		Ok(())
+	}
+
+	/// Protected images must never be picked up by bulk operations like purges or duplicate cleanup.
+	/// The flag lives on the images row, so it survives reindexing.
+	pub fn set_protected(&mut self, image_id: i64, protected: bool) -> Result<()> {
+		self.connection.lock().execute("UPDATE images SET protected = ? WHERE id = ?", params![protected, image_id])?;
+
+		// Keep the cached results in sync so the UI doesn't need to requery.
+		if let Some(results) = &mut self.cached_search_results {
+			results.iter_mut().filter(|img| img.id == image_id).for_each(|img| img.protected = protected);
+		}
+
+		Ok(())
+	}
+
+	/// Tags the user added to the image, sorted by name.  EXIF and rule tags aren't included.
+	pub fn get_user_tags(&self, image_id: i64) -> Result<Vec<(String, String)>> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare("SELECT name, value FROM tags WHERE image_id = ? AND source = ? ORDER BY name")?;
+		let tag_cursor = stmt.query_map(params![image_id, TAG_SOURCE_USER], |row| {
+			Ok((row.get(0)?, row.get::<_, Option<String>>(1)?.unwrap_or_default()))
+		})?;
+		Ok(tag_cursor.collect::<SQLResult<Vec<(String, String)>>>()?)
+	}
+
+	/// Add a user tag to the image, replacing the value if the image already has a user tag with that name.
+	/// User tags are stored alongside EXIF tags but are never touched by indexing.
+	pub fn set_user_tag(&mut self, image_id: i64, name: &str, value: &str) -> Result<()> {
+		let name = name.trim();
+		if name.is_empty() {
+			return Err(anyhow!("Tags need a name."));
+		}
+		{
+			let mut conn = self.connection.lock();
+			let tx = conn.transaction()?;
+			tx.execute("DELETE FROM tags WHERE image_id = ? AND name = ? AND source = ?", params![image_id, name, TAG_SOURCE_USER])?;
+			tx.execute("INSERT INTO tags (image_id, name, value, source, number) VALUES (?, ?, ?, ?, ?)", params![image_id, name, value, TAG_SOURCE_USER, tag_number(value)])?;
+			update_blurred_thumbnail(&tx, image_id)?;
+			tx.commit()?;
+		}
+		self.update_cached_tags(image_id)?;
+		self.update_cached_blur(image_id)
+	}
+
+	/// Rename a user tag and change its value.
+	pub fn edit_user_tag(&mut self, image_id: i64, old_name: &str, new_name: &str, new_value: &str) -> Result<()> {
+		if self.connection.lock().query_row(
+			"SELECT 1 FROM tags WHERE image_id = ? AND name = ? AND source = ?",
+			params![image_id, old_name, TAG_SOURCE_USER],
+			|_| Ok(())
+		).optional()?.is_none() {
+			return Err(anyhow!("Image {} has no user tag named '{}'.", image_id, old_name));
+		}
+		if old_name != new_name.trim() {
+			self.remove_user_tag(image_id, old_name)?;
+		}
+		self.set_user_tag(image_id, new_name, new_value)
+	}
+
+	pub fn remove_user_tag(&mut self, image_id: i64, name: &str) -> Result<()> {
+		{
+			let conn = self.connection.lock();
+			conn.execute("DELETE FROM tags WHERE image_id = ? AND name = ? AND source = ?", params![image_id, name, TAG_SOURCE_USER])?;
+			update_blurred_thumbnail(&conn, image_id)?;
+		}
+		self.update_cached_tags(image_id)?;
+		self.update_cached_blur(image_id)
+	}
+
+	/// Keep the blurred thumbnails of cached results in sync after tagging, so the UI doesn't need to requery.
+	fn update_cached_blur(&mut self, image_id: i64) -> Result<()> {
+		let blurred = self.get_blurred_thumbnail(image_id)?;
+		if let Some(results) = &mut self.cached_search_results {
+			results.iter_mut().filter(|img| img.id == image_id).for_each(|img| img.blurred_thumbnail = blurred.clone());
+		}
+		Ok(())
+	}
+
+	/// Keep the tags on cached results in sync after an edit so the UI doesn't need to requery.
+	fn update_cached_tags(&mut self, image_id: i64) -> Result<()> {
+		if self.cached_search_results.is_none() {
+			return Ok(());
+		}
+		let tag_data: JSONValue = self.connection.lock().query_row(
+			"SELECT JSON_GROUP_ARRAY(JSON_ARRAY(name, value, source)) FROM tags WHERE image_id = ?",
+			params![image_id],
+			|row| row.get(0)
+		)?;
+		let tags = tags_from_json(&tag_data);
+		if let Some(results) = &mut self.cached_search_results {
+			results.iter_mut().filter(|img| img.id == image_id).for_each(|img| img.tags = tags.clone());
+		}
+		Ok(())
+	}
+
+	/// Find images with a color close to the given one in their palette, closest first.
+	/// Like searching by image, this isn't paged.
+	pub fn query_by_color(&mut self, color:[u8; 3]) -> Result<()> {
+		self.cancel_query();
+		self.cached_search_results = None;
+		self.cached_search_total = None;
+
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare(&format!(r#"
+			SELECT {}, palette_distance(?, palettes.hash) AS dist
+			FROM palettes
+			INNER JOIN images ON images.id = palettes.image_id
+			WHERE images.trashed IS NULL AND dist < ? AND {}
+			ORDER BY dist ASC, images.id ASC
+			LIMIT ?"#, SELECT_FIELDS, self.visibility_filter()
+		))?;
+		let img_cursor = stmt.query_map(params![color.to_vec(), DEFAULT_MAX_COLOR_DISTANCE, self.max_search_results], |row|{
+			let mut img = indexed_image_from_row(row)?;
+			img.distance_from_query = Some(row.get(SELECT_FIELDS_COUNT)?);
+			Ok(img)
+		})?;
+		self.cached_search_results = Some(img_cursor.collect::<SQLResult<Vec<IndexedImage>>>()?);
+
+		Ok(())
+	}
+
+	/// The n nearest images to an indexed image by the embeddings from each of two registered models, for choosing which one to standardize on.
+	/// Only stored embeddings are compared, so nothing is re-embedded.  Images without an embedding from a model are left out of its list.
+	pub fn compare_similarity_methods(&self, image_id:i64, model_a:i64, model_b:i64, n:usize) -> Result<MethodComparison> {
+		let conn = self.connection.lock();
+		let nearest = |model_id: i64| -> Result<Vec<IndexedImage>> {
+			if !conn.prepare("SELECT 1 FROM embeddings WHERE image_id = ? AND model_id = ?")?.exists(params![image_id, model_id])? {
+				return Err(anyhow!("The image has no embedding from model {}.", model_id));
+			}
+			let mut stmt = conn.prepare(&format!(r#"
+				SELECT {}, cosine_distance(query_hash.hash, embeddings.hash) AS dist
+				FROM embeddings
+				INNER JOIN embeddings AS query_hash ON query_hash.image_id = ?1 AND query_hash.model_id = embeddings.model_id
+				INNER JOIN images ON images.id = embeddings.image_id
+				WHERE embeddings.model_id = ?2 AND images.trashed IS NULL AND images.id != query_hash.image_id AND {}
+				ORDER BY dist ASC, images.id ASC
+				LIMIT ?3"#, SELECT_FIELDS, self.hidden_filter()
+			))?;
+			let img_cursor = stmt.query_map(params![image_id, model_id, n as i64], |row|{
+				let mut img = indexed_image_from_row(row)?;
+				img.distance_from_query = Some(row.get(SELECT_FIELDS_COUNT)?);
+				Ok(img)
+			})?;
+			Ok(img_cursor.collect::<SQLResult<Vec<IndexedImage>>>()?)
+		};
+		Ok(MethodComparison::new(nearest(model_a)?, nearest(model_b)?))
+	}
+
+	/// Find images whose color layout best matches a rough sketch, closest first.
+	/// The sketch is an RGBA grid of COLOR_LAYOUT_SIZE x COLOR_LAYOUT_SIZE cells in row-major order.
+	/// Cells with zero alpha are "don't care" and are ignored.  Like searching by image, this isn't paged.
+	pub fn query_by_color_layout(&mut self, sketch_rgba:&Vec<u8>) -> Result<()> {
+		if sketch_rgba.len() != 4 * COLOR_LAYOUT_SIZE * COLOR_LAYOUT_SIZE {
+			return Err(anyhow!("Color layout sketch should have {} cells but has {}.", COLOR_LAYOUT_SIZE * COLOR_LAYOUT_SIZE, sketch_rgba.len() / 4));
+		}
+
+		self.cancel_query();
+		self.cached_search_results = None;
+		self.cached_search_total = None;
+
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare(&format!(r#"
+			SELECT {}, layout_distance(?, color_layouts.hash) AS dist
+			FROM color_layouts
+			INNER JOIN images ON images.id = color_layouts.image_id
+			WHERE images.trashed IS NULL AND {}
+			ORDER BY dist ASC, images.id ASC
+			LIMIT ?"#, SELECT_FIELDS, self.visibility_filter()
+		))?;
+		let img_cursor = stmt.query_map(params![sketch_rgba, self.max_search_results], |row|{
+			let mut img = indexed_image_from_row(row)?;
+			img.distance_from_query = Some(row.get(SELECT_FIELDS_COUNT)?);
+			Ok(img)
+		})?;
+		self.cached_search_results = Some(img_cursor.collect::<SQLResult<Vec<IndexedImage>>>()?);
+
+		Ok(())
+	}
+
+	/// The most common palette colors across the index, coarsely quantized so near-identical shades are merged.
+	/// Useful as a starting point for browsing by color.
+	pub fn get_common_palette_colors(&self, max_colors: usize) -> Result<Vec<[u8; 3]>> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare("SELECT palettes.hash FROM palettes INNER JOIN images ON images.id = palettes.image_id WHERE images.trashed IS NULL")?;
+		let palette_cursor = stmt.query_map([], |row| row.get::<_, Vec<u8>>(0))?;
+
+		// Bucket with 3 bits per channel, but report the average of each bucket.
+		let mut buckets: HashMap<[u8; 3], ([u64; 3], u64)> = HashMap::new();
+		for palette in palette_cursor {
+			for color in palette?.chunks_exact(3) {
+				let (sums, count) = buckets.entry([color[0] >> 5, color[1] >> 5, color[2] >> 5]).or_insert(([0; 3], 0));
+				for c in 0..3 {
+					sums[c] += color[c] as u64;
+				}
+				*count += 1;
+			}
+		}
+
+		let mut by_count: Vec<([u64; 3], u64)> = buckets.into_values().collect();
+		by_count.sort_by(|a, b| { b.1.cmp(&a.1) });
+		Ok(by_count.into_iter().take(max_colors).map(|(sums, count)| {
+			sums.map(|s| { (s / count) as u8 })
+		}).collect())
+	}
+
+	pub fn get_query_results(&self) -> Option<Vec<IndexedImage>> {
+		self.cached_search_results.clone()
+	}
+
+	/// Write the current results to a file for use in other programs.  Returns the number of results written.
+	/// CSV has one row per image with the tags as a JSON object in the last column.
+	pub fn export_results(&self, format: ExportFormat, path: &Path) -> Result<usize> {
+		let results = self.cached_search_results.as_ref().ok_or_else(|| anyhow!("There are no results to export.  Run a search first."))?;
+		let output = match format {
+			ExportFormat::Csv => {
+				let mut csv = String::from("path,filename,width,height,distance,tags\n");
+				for img in results {
+					let distance = img.distance_from_query.map(|d| d.to_string()).unwrap_or_default();
+					csv.push_str(&format!(
+						"{},{},{},{},{},{}\n",
+						csv_field(&img.path), csv_field(&img.filename), img.resolution.0, img.resolution.1, distance, csv_field(&img.summary_json()["tags"].to_string())
+					));
+				}
+				csv
+			},
+			ExportFormat::Json => {
+				let rows: Vec<JSONValue> = results.iter().map(IndexedImage::summary_json).collect();
+				serde_json::to_string_pretty(&rows)?
+			},
+		};
+		std::fs::write(path, output)?;
+		Ok(results.len())
+	}
+
+	/// The number of results across all pages of the last query_page call.
+	/// None if there are no results or the last search (like a search by image) wasn't paged.
+	pub fn get_query_result_count(&self) -> Option<u64> {
+		self.cached_search_total
+	}
+	
+	pub fn clear_query_results(&mut self) {
+		self.cancel_query();
+		self.cached_search_results = None;
+		self.cached_search_total = None;
+	}
+
+	pub fn add_tracked_folder(&mut self, folder_glob:String) -> Result<()> {
+		self.connection.lock().execute("INSERT INTO watched_directories (glob) VALUES (?1)", params![folder_glob])?;
+		self.watched_directories_cache = None; // Invalidate cache.
+		self.get_tracked_folders()?;
+		Ok(())
+	}
+
+	/// Folders with a higher priority are indexed first, and listed first.  Folders start at 0.
+	pub fn set_folder_priority(&mut self, folder_glob:&str, priority:i64) -> Result<()> {
+		let settings = crawler::CrawlFolder { priority, ..self.get_folder_settings(folder_glob)? };
+		self.set_folder_settings(&settings)
+	}
+
+	pub fn get_folder_priority(&mut self, folder_glob:&str) -> Result<i64> {
+		Ok(self.get_folder_settings(folder_glob)?.priority)
+	}
+
+	/// Skip files in the folder that match any of these globs, like **/node_modules/**, *.tmp, or .thumbnails.
+	/// Patterns without a slash match the name of any file or folder inside it.  Patterns with one match the path from the folder.
+	/// Images that were already indexed are kept.
+	pub fn set_ignore_patterns(&mut self, folder_glob:&str, patterns:&[String]) -> Result<()> {
+		let settings = crawler::CrawlFolder { ignore_patterns: patterns.to_vec(), ..self.get_folder_settings(folder_glob)? };
+		self.set_folder_settings(&settings)
+	}
+
+	pub fn get_ignore_patterns(&mut self, folder_glob:&str) -> Result<Vec<String>> {
+		Ok(self.get_folder_settings(folder_glob)?.ignore_patterns)
+	}
+
+	/// How the folder is crawled.  Folders that aren't watched get the defaults.
+	pub fn get_folder_settings(&mut self, folder_glob:&str) -> Result<crawler::CrawlFolder> {
+		self.get_tracked_folders()?;
+		Ok(self.folder_settings.get(folder_glob).cloned().unwrap_or_else(|| crawler::CrawlFolder { glob: folder_glob.to_string(), ..Default::default() }))
+	}
+
+	/// Change how a watched folder is crawled.  settings.glob says which folder.
+	/// Ignore patterns and extensions are trimmed, and extensions are lowercased without their dots.  Blank ones are dropped.
+	pub fn set_folder_settings(&mut self, settings:&crawler::CrawlFolder) -> Result<()> {
+		let patterns: Vec<&str> = settings.ignore_patterns.iter().map(|p| p.trim()).filter(|p| !p.is_empty()).collect();
+		for pattern in &patterns {
+			if let Err(e) = glob::Pattern::new(pattern) {
+				return Err(anyhow!("'{}' isn't a valid pattern: {}", pattern, e));
+			}
+		}
+		let extensions: Vec<String> = settings.extensions.iter().map(|e| e.trim().trim_start_matches('.').to_lowercase()).filter(|e| !e.is_empty()).collect();
+		let updated = self.connection.lock().execute(
+			"UPDATE watched_directories SET priority = ?, ignore_patterns = ?, recursive = ?, extensions = ?, scan_archives = ?, follow_symlinks = ?, archive_password = ? WHERE glob = ?",
+			params![settings.priority, patterns.join("\n"), settings.recursive, extensions.join(" "), settings.scan_archives, settings.follow_symlinks, &settings.archive_password, &settings.glob]
+		)?;
+		if updated == 0 {
+			return Err(anyhow!("'{}' isn't a watched folder.", &settings.glob));
+		}
+		self.watched_directories_cache = None; // Invalidate cache.
+		self.get_tracked_folders()?;
+		Ok(())
+	}
+
+	pub fn remove_tracked_folder(&mut self, folder_glob:String) -> Result<()> {
+		self.connection.lock().execute("DELETE FROM watched_directories WHERE glob=?1", params![folder_glob])?;
+		self.watched_directories_cache = None; // Invalidate cache.
+		self.get_tracked_folders()?;
+		Ok(())
+	}
+
+	/// Move every unprotected image under the given folder into the trash.
+	/// Trashed images are hidden from searches and permanently deleted after trash_retention_days.
+	/// Returns the number of images trashed.
+	pub fn trash_images_in_folder(&mut self, folder_glob:&str) -> Result<usize> {
+		let prefix = folder_prefix(folder_glob);
+		let num_trashed = self.connection.lock().execute(
+			"UPDATE images SET trashed = datetime('now') WHERE trashed IS NULL AND protected = 0 AND substr(path, 1, length(?1)) = ?1",
+			params![prefix]
+		)?;
+		self.cached_index_size = None;
+		self.clear_query_results();
+		self.trashed_images_cache = None;
+		self.collections_cache = None; // Sizes don't count trashed images.
+		Ok(num_trashed)
+	}
+
+	pub fn get_trashed_images(&mut self) -> Result<Vec<IndexedImage>> {
+		if self.trashed_images_cache.is_none() {
+			let conn = self.connection.lock();
+			let mut stmt = conn.prepare(&format!("SELECT {} FROM images WHERE trashed IS NOT NULL AND {} ORDER BY trashed DESC", SELECT_FIELDS, self.hidden_filter()))?;
+			let img_cursor = stmt.query_map([], indexed_image_from_row)?;
+			self.trashed_images_cache = Some(img_cursor.collect::<SQLResult<Vec<IndexedImage>>>()?);
+		}
+		Ok(self.trashed_images_cache.clone().unwrap_or_default())
+	}
+
+	/// Restoring an excluded image stops excluding it.
+	pub fn restore_from_trash(&mut self, image_id: i64) -> Result<()> {
+		let conn = self.connection.lock();
+		conn.execute("UPDATE images SET trashed = NULL WHERE id = ?", params![image_id])?;
+		conn.execute("DELETE FROM exclusions WHERE path IN (SELECT path FROM images WHERE id = ?)", params![image_id])?;
+		drop(conn);
+		self.cached_index_size = None;
+		self.trashed_images_cache = None;
+		self.collections_cache = None;
+		Ok(())
+	}
+
+	/// Move an image to the trash and keep it from being indexed again, even if it's in a watched folder.
+	/// Copies of the file, and the file if it's moved, are kept out too.
+	pub fn exclude_image(&mut self, image_id: i64) -> Result<()> {
+		let path: String = self.connection.lock().query_row("SELECT path FROM images WHERE id = ?", params![image_id], |row| row.get(0))
+			.optional()?.ok_or_else(|| anyhow!("There's no image with id {}.", image_id))?;
+		// Images in books and archives, and files that are already gone, can only be matched by path.
+		let content_hash = if path.contains(archive::ENTRY_SEPARATOR) { None } else { crawler::file_content_hash(&filepath_from_string(&path)).ok() };
+		{
+			let mut conn = self.connection.lock();
+			let tx = conn.transaction()?;
+			tx.execute("INSERT OR REPLACE INTO exclusions (path, content_hash, excluded) VALUES (?, ?, datetime('now'))", params![&path, content_hash])?;
+			tx.execute("UPDATE images SET trashed = datetime('now') WHERE id = ? AND trashed IS NULL", params![image_id])?;
+			tx.commit()?;
+		}
+		self.cached_index_size = None;
+		self.clear_query_results();
+		self.trashed_images_cache = None;
+		self.collections_cache = None;
+		Ok(())
+	}
+
+	/// Every excluded image, most recently excluded first.
+	pub fn get_exclusions(&self) -> Result<Vec<Exclusion>> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare("SELECT path, content_hash IS NOT NULL, excluded FROM exclusions ORDER BY excluded DESC, path")?;
+		let exclusion_cursor = stmt.query_map([], |row| {
+			Ok(Exclusion { path: row.get(0)?, by_content: row.get(1)?, excluded: row.get(2)? })
+		})?;
+		Ok(exclusion_cursor.collect::<SQLResult<Vec<Exclusion>>>()?)
+	}
+
+	/// Let an excluded image be indexed again.  It comes back on the next reindex, or can be restored from the trash until then.
+	pub fn remove_exclusion(&mut self, path: &str) -> Result<()> {
+		self.connection.lock().execute("DELETE FROM exclusions WHERE path = ?", params![path])?;
+		Ok(())
+	}
+
+	/// Every exclusion, for the crawler to skip excluded files before it loads them.
+	fn get_exclusion_set(&self) -> Result<crawler::Exclusions> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare("SELECT path, content_hash FROM exclusions")?;
+		let mut exclusions = crawler::Exclusions::default();
+		for row in stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<Vec<u8>>>(1)?)))? {
+			let (path, content_hash) = row?;
+			exclusions.paths.insert(path);
+			exclusions.content_hashes.extend(content_hash);
+		}
+		Ok(exclusions)
+	}
+
+	/// True if the image at path was excluded.  The crawler skips excluded files and their copies when it starts, so this only catches images excluded since.
+	fn is_excluded(conn: &Connection, path: &str) -> Result<bool> {
+		Ok(conn.query_row("SELECT EXISTS (SELECT 1 FROM exclusions WHERE path = ?)", params![path], |row| row.get(0))?)
+	}
+
+	/// Permanently delete images (and their tags and hashes) that have been in the trash for at least this many days.
+	/// Passing zero empties the trash entirely.  Returns the number of images deleted.
+	pub fn empty_trash(&mut self, older_than_days: u32) -> Result<usize> {
+		let cutoff = format!("-{} days", older_than_days);
+		let expired = "SELECT id FROM images WHERE trashed IS NOT NULL AND trashed <= datetime('now', ?1)";
+		let mut conn = self.connection.lock();
+		let tx = conn.transaction()?;
+		for table in ["tags", "phashes", "semantic_hashes", "palettes", "color_layouts", "cropped_hashes", "collection_members", "duplicate_of", "embeddings", "screenshot_sources", "blurred_thumbnails"] {
+			tx.execute(&format!("DELETE FROM {} WHERE image_id IN ({})", table, expired), params![cutoff])?;
+		}
+		// With the canonical image gone, its duplicates are searchable again.
+		tx.execute(&format!("DELETE FROM duplicate_of WHERE canonical_id IN ({})", expired), params![cutoff])?;
+		tx.execute(&format!("DELETE FROM duplicate_reviews WHERE existing_image_id IN ({})", expired), params![cutoff])?;
+		let num_deleted = tx.execute(&format!("DELETE FROM images WHERE id IN ({})", expired), params![cutoff])?;
+		tx.commit()?;
+		self.trashed_images_cache = None;
+		self.collections_cache = None;
+		Ok(num_deleted)
+	}
+
+	/// The QOI-encoded thumbnail for an image, or None if there's no such image or it's trashed or in a locked hidden collection.
+	pub fn get_thumbnail(&self, image_id: i64) -> Result<Option<Vec<u8>>> {
+		Ok(self.connection.lock().query_row(
+			&format!("SELECT thumbnail FROM images WHERE images.id = ? AND images.trashed IS NULL AND {}", self.hidden_filter()),
+			params![image_id],
+			|row| row.get(0)
+		).optional()?)
+	}
+
+	/// The blurred stand-in for the thumbnail of an image tagged SENSITIVE_TAG, or None if it isn't tagged.
+	pub fn get_blurred_thumbnail(&self, image_id: i64) -> Result<Option<Vec<u8>>> {
+		Ok(self.connection.lock().query_row("SELECT thumbnail FROM blurred_thumbnails WHERE image_id = ?", params![image_id], |row| row.get(0)).optional()?)
+	}
+
+	/// Every image's embedding, for answering nearest-neighbor lookups without going back to the DB.
+	pub fn load_embedding_index(&self) -> Result<EmbeddingIndex> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare(&format!("
+			SELECT images.id, semantic_hashes.hash
+			FROM semantic_hashes
+			INNER JOIN images ON images.id = semantic_hashes.image_id
+			WHERE images.trashed IS NULL AND {}", self.hidden_filter()
+		))?;
+		let entries = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<SQLResult<Vec<(i64, Vec<u8>)>>>()?;
+		Ok(EmbeddingIndex::new(entries))
+	}
+
+	/// Register an outside embedding model, like a CLIP variant, so embeddings from it can be stored with set_embedding.
+	/// Returns the model's id, which is the same one as before if it's already registered.
+	pub fn register_embedding_model(&mut self, name: &str, version: &str, dimensions: usize) -> Result<i64> {
+		let name = name.trim();
+		if name.is_empty() || dimensions == 0 {
+			return Err(anyhow!("Embedding models need a name and at least one dimension."));
+		}
+		let conn = self.connection.lock();
+		conn.execute(
+			"INSERT OR IGNORE INTO embedding_models (name, version, dimensions) VALUES (?, ?, ?)",
+			params![name, version.trim(), dimensions]
+		)?;
+		Ok(conn.query_row(
+			"SELECT id FROM embedding_models WHERE name = ? AND version = ? AND dimensions = ?",
+			params![name, version.trim(), dimensions], |row| row.get(0)
+		)?)
+	}
+
+	/// The registered models, by name and then version.  The built-in model isn't one of them.
+	pub fn get_embedding_models(&self) -> Result<Vec<EmbeddingModel>> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare("
+			SELECT id, name, version, dimensions, (SELECT COUNT(*) FROM embeddings WHERE model_id = embedding_models.id)
+			FROM embedding_models
+			ORDER BY name, version, dimensions"
+		)?;
+		let models = stmt.query_map([], |row| Ok(EmbeddingModel {
+			id: row.get(0)?,
+			name: row.get(1)?,
+			version: row.get(2)?,
+			dimensions: row.get(3)?,
+			num_embeddings: row.get(4)?,
+		}))?;
+		Ok(models.collect::<SQLResult<Vec<EmbeddingModel>>>()?)
+	}
+
+	/// Forget a model and every embedding from it.
+	pub fn remove_embedding_model(&mut self, model_id: i64) -> Result<()> {
+		let mut conn = self.connection.lock();
+		let tx = conn.transaction()?;
+		tx.execute("DELETE FROM embeddings WHERE model_id = ?", params![model_id])?;
+		tx.execute("DELETE FROM embedding_models WHERE id = ?", params![model_id])?;
+		tx.commit()?;
+		Ok(())
+	}
+
+	/// Store an image's embedding from a registered model, replacing any it already had from that model.
+	/// Values should be in [-1, 1], like a normalized embedding.  Anything outside that is clamped.
+	pub fn set_embedding(&mut self, image_id: i64, model_id: i64, embedding: &[f32]) -> Result<()> {
+		let conn = self.connection.lock();
+		check_embedding_dimensions(&conn, model_id, embedding)?;
+		if !conn.prepare("SELECT 1 FROM images WHERE id = ?")?.exists(params![image_id])? {
+			return Err(anyhow!("There's no image with id {}.", image_id));
+		}
+		conn.execute(
+			"INSERT OR REPLACE INTO embeddings (image_id, model_id, hash) VALUES (?, ?, ?)",
+			params![image_id, model_id, quantize_embedding(embedding)]
+		)?;
+		Ok(())
+	}
+
+	/// The image's embedding from a registered model, or None if it doesn't have one yet.
+	pub fn get_embedding(&self, image_id: i64, model_id: i64) -> Result<Option<Vec<f32>>> {
+		let hash: Option<Vec<u8>> = self.connection.lock().query_row(
+			"SELECT hash FROM embeddings WHERE image_id = ? AND model_id = ?", params![image_id, model_id], |row| row.get(0)
+		).optional()?;
+		Ok(hash.map(|h| dequantize_embedding(&h)))
+	}
+
+	/// Like query_by_image_hash_from_image, but compares embeddings from a registered model.
+	/// Only images with an embedding from that model can turn up.
+	pub fn query_by_model_embedding(&mut self, model_id: i64, embedding: &[f32]) -> Result<()> {
+		check_embedding_dimensions(&self.connection.lock(), model_id, embedding)?;
+		let hashes = format!("(SELECT image_id, hash FROM embeddings WHERE model_id = {}) AS semantic_hashes", model_id);
+		self.query_by_embedding_in(&quantize_embedding(embedding), &hashes)
+	}
+
+	/// Cluster the whole index into groups of near-duplicates, most reclaimable space first.
+	/// Images are grouped if they're within threshold of any other image in the group, so a chain of small edits ends up together.
+	/// Every pair is compared, so this takes a while on large libraries.  Images without the method's hash are left out.
+	pub fn find_similar_groups(&self, threshold:f64, method:SimilarityMethod) -> Result<Vec<SimilarGroup>> {
+		find_similar_groups(&self.connection, &self.hidden_filter(), threshold, method)
+	}
+
+	/// Images held out of the index while waiting for someone to decide if they're worth keeping.
+	pub fn get_near_duplicates(&self) -> Result<Vec<NearDuplicate>> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare(&format!(r#"
+			SELECT {}, duplicate_reviews.path, duplicate_reviews.distance
+			FROM duplicate_reviews
+			INNER JOIN images ON images.id = duplicate_reviews.existing_image_id
+			WHERE duplicate_reviews.resolution IS NULL AND {}
+			ORDER BY duplicate_reviews.found ASC"#, SELECT_FIELDS, self.hidden_filter()
+		))?;
+		let dupe_cursor = stmt.query_map([], |row|{
+			Ok(NearDuplicate {
+				existing: indexed_image_from_row(row)?,
+				path: row.get(SELECT_FIELDS_COUNT)?,
+				distance: row.get(SELECT_FIELDS_COUNT + 1)?,
+			})
+		})?;
+		Ok(dupe_cursor.collect::<SQLResult<Vec<NearDuplicate>>>()?)
+	}
+
+	/// Decide what to do with a held near-duplicate.  Kept images are added on the next reindex.  Skipped ones are never added.
+	pub fn resolve_near_duplicate(&mut self, path: &str, keep: bool) -> Result<()> {
+		let resolution = if keep { "keep" } else { "skip" };
+		let updated = self.connection.lock().execute(
+			"UPDATE duplicate_reviews SET resolution = ? WHERE path = ?",
+			params![resolution, path]
+		)?;
+		if updated == 0 {
+			return Err(anyhow!("{} is not waiting for duplicate review.", path));
+		}
+		Ok(())
+	}
+
+	/// Mark images as duplicates of a canonical image, like all but one of a SimilarGroup.
+	/// Duplicates are left out of searches unless show_duplicates is set.  Nothing is deleted.
+	/// Marking a canonical image as a duplicate moves its duplicates over to the new canonical image.
+	pub fn set_canonical_image(&mut self, canonical_id: i64, duplicate_ids: &[i64]) -> Result<()> {
+		let mut conn = self.connection.lock();
+		let tx = conn.transaction()?;
+		tx.execute("DELETE FROM duplicate_of WHERE image_id = ?", params![canonical_id])?;
+		for &duplicate_id in duplicate_ids.iter().filter(|&&id| id != canonical_id) {
+			tx.execute("UPDATE duplicate_of SET canonical_id = ?1 WHERE canonical_id = ?2", params![canonical_id, duplicate_id])?;
+			tx.execute("INSERT OR REPLACE INTO duplicate_of (image_id, canonical_id) VALUES (?, ?)", params![duplicate_id, canonical_id])?;
+		}
+		tx.commit()?;
+
+		if !self.show_duplicates {
+			if let Some(results) = &mut self.cached_search_results {
+				results.retain(|img| img.id == canonical_id || !duplicate_ids.contains(&img.id));
+			}
+		}
+		Ok(())
+	}
+
+	/// Mark everything from find_near_duplicates_of as a duplicate of this image, except protected images.  Returns how many were marked.
+	pub fn hide_near_duplicates_of(&mut self, image_id: i64) -> Result<usize> {
+		let duplicate_ids: Vec<i64> = self.find_near_duplicates_of(image_id)?.into_iter().filter(|img| !img.protected).map(|img| img.id).collect();
+		self.set_canonical_image(image_id, &duplicate_ids)?;
+		Ok(duplicate_ids.len())
+	}
+
+	/// Stop treating an image as a duplicate.  If it's a canonical image, all of its duplicates are released.
+	pub fn unmark_duplicate(&mut self, image_id: i64) -> Result<()> {
+		self.connection.lock().execute("DELETE FROM duplicate_of WHERE image_id = ?1 OR canonical_id = ?1", params![image_id])?;
+		Ok(())
+	}
+
+	/// The canonical image this one is a duplicate of, if it's been marked as one.
+	pub fn get_canonical_image(&self, image_id: i64) -> Result<Option<IndexedImage>> {
+		let conn = self.connection.lock();
+		Ok(conn.query_row(
+			&format!("SELECT {} FROM images INNER JOIN duplicate_of ON duplicate_of.canonical_id = images.id WHERE duplicate_of.image_id = ? AND {}", SELECT_FIELDS, self.hidden_filter()),
+			params![image_id],
+			indexed_image_from_row
+		).optional()?)
+	}
+
+	/// The images marked as duplicates of a canonical image.
+	pub fn get_duplicates(&self, canonical_id: i64) -> Result<Vec<IndexedImage>> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare(&format!(
+			"SELECT {} FROM images INNER JOIN duplicate_of ON duplicate_of.image_id = images.id WHERE duplicate_of.canonical_id = ? AND {} ORDER BY images.path",
+			SELECT_FIELDS, self.hidden_filter()
+		))?;
+		let img_cursor = stmt.query_map(params![canonical_id], indexed_image_from_row)?;
+		Ok(img_cursor.collect::<SQLResult<Vec<IndexedImage>>>()?)
+	}
+
+	/// The window this image is a screenshot of, if its filename said.
+	pub fn get_screenshot_info(&self, image_id: i64) -> Result<Option<ScreenshotInfo>> {
+		let conn = self.connection.lock();
+		Ok(conn.query_row(
+			"SELECT app, window, captured, session, (SELECT COUNT(*) FROM screenshot_sources AS s WHERE s.session = screenshot_sources.session) FROM screenshot_sources WHERE image_id = ?",
+			params![image_id],
+			|row| Ok(ScreenshotInfo {
+				source: ScreenshotSource { app: row.get(0)?, window: row.get(1)?, captured: row.get(2)? },
+				session: row.get(3)?,
+				session_size: row.get(4)?,
+			})
+		).optional()?)
+	}
+
+	/// Images whose perceptual hash is within near_duplicate_distance of this one's, closest first.
+	/// A quicker way to a duplicate group than find_similar_groups when only one image is of interest.
+	pub fn find_near_duplicates_of(&self, image_id: i64) -> Result<Vec<IndexedImage>> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare(&format!(r#"
+			SELECT {}, hamming_distance(query_hash.hash, phashes.hash) AS dist
+			FROM phashes
+			INNER JOIN phashes AS query_hash ON query_hash.image_id = ?
+			INNER JOIN images ON images.id = phashes.image_id
+			WHERE images.trashed IS NULL AND images.id != query_hash.image_id AND dist <= ? AND {}
+			ORDER BY dist ASC, images.id ASC"#, SELECT_FIELDS, self.hidden_filter()
+		))?;
+		let img_cursor = stmt.query_map(params![image_id, self.near_duplicate_distance], |row|{
+			let mut img = indexed_image_from_row(row)?;
+			img.distance_from_query = Some(row.get(SELECT_FIELDS_COUNT)?);
+			Ok(img)
+		})?;
+		Ok(img_cursor.collect::<SQLResult<Vec<IndexedImage>>>()?)
+	}
+
+	/// The settings and rules for this DB as JSON, so they can be moved to another DB.  Images are not included.
+	pub fn export_config(&mut self) -> Result<JSONValue> {
+		let settings: serde_json::Map<String, JSONValue> = self.settings_values().into_iter().map(|(name, value)| (name.to_string(), JSONValue::String(value))).collect();
+		let rules: Vec<JSONValue> = self.get_rules()?.iter().map(Rule::to_json).collect();
+		let saved_searches: Vec<JSONValue> = self.get_saved_searches()?.iter().map(|s| json!({"name": s.name, "query": s.query})).collect();
+		// Manual collections are lists of image ids, which mean nothing in another database.
+		let smart_collections: Vec<JSONValue> = self.get_collections()?.into_iter().filter_map(|c| c.query.map(|query| json!({"name": c.name, "query": query}))).collect();
+		Ok(json!({
+			"settings": settings,
+			"rules": rules,
+			"saved_searches": saved_searches,
+			"smart_collections": smart_collections,
+		}))
+	}
+
+	/// Apply settings, rules, saved searches, and smart collections from export_config.  Unknown settings are ignored.
+	/// Everything else is matched by name: existing ones are replaced and new ones are added.
+	pub fn import_config(&mut self, config: &JSONValue) -> Result<()> {
+		let config = config.as_object().ok_or_else(|| anyhow!("Expected the DB configuration to be a JSON object."))?;
+
+		if let Some(settings) = config.get("settings").and_then(|s| s.as_object()) {
+			{
+				let mut conn = self.connection.lock();
+				let tx = conn.transaction()?;
+				for (name, value) in settings {
+					// Values are stored as text, but be forgiving of hand-edited files with bare numbers.
+					let value = match value {
+						JSONValue::String(s) => s.clone(),
+						other => other.to_string(),
+					};
+					tx.execute("INSERT OR REPLACE INTO settings (name, value) VALUES (?, ?)", params![name, value])?;
+				}
+				tx.commit()?;
+			}
+			self.load_settings();
+		}
+
+		if let Some(rules) = config.get("rules").and_then(|r| r.as_array()) {
+			let existing = self.get_rules()?;
+			for rule in rules.iter().map(Rule::from_json) {
+				let id = existing.iter().find(|r| r.name == rule.name).map(|r| r.id).unwrap_or(0);
+				self.save_rule(&Rule { id, ..rule })?;
+			}
+		}
+
+		if let Some(saved_searches) = config.get("saved_searches").and_then(|s| s.as_array()) {
+			for saved in saved_searches {
+				let name = saved.get("name").and_then(|v| v.as_str());
+				let query = saved.get("query").and_then(|v| v.as_str());
+				if let (Some(name), Some(query)) = (name, query) {
+					self.save_search(name, query)?;
+				}
+			}
+		}
+
+		if let Some(smart_collections) = config.get("smart_collections").and_then(|s| s.as_array()) {
+			let existing = self.get_collections()?;
+			for smart in smart_collections {
+				let name = smart.get("name").and_then(|v| v.as_str());
+				let query = smart.get("query").and_then(|v| v.as_str());
+				if let (Some(name), Some(query)) = (name, query) {
+					match existing.iter().find(|c| c.name.eq_ignore_ascii_case(name.trim())) {
+						Some(collection) => self.set_collection_query(collection.id, query)?,
+						None => { self.create_smart_collection(name, query)?; },
+					}
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Copy the whole DB to path with SQLite's backup API.  Safe to call while indexing, unlike copying the file.
+	/// Anything already at path is replaced.
+	pub fn backup_to(&self, path: &Path) -> Result<()> {
+		let conn = self.connection.lock();
+		if let (Some(db_path), Ok(target)) = (conn.path(), path.canonicalize()) {
+			if Path::new(db_path).canonicalize().map(|p| p == target).unwrap_or(false) {
+				return Err(anyhow!("Can't back up the DB onto itself."));
+			}
+		}
+		// Write next to the target and then swap it in, so a failed backup doesn't cost us the last good one.
+		let mut partial_path = path.as_os_str().to_owned();
+		partial_path.push(".partial");
+		let partial_path = PathBuf::from(partial_path);
+		let _ = std::fs::remove_file(&partial_path);
+		let result = copy_db(&conn, &partial_path).and_then(|_| Ok(std::fs::rename(&partial_path, path)?));
+		if result.is_err() {
+			let _ = std::fs::remove_file(&partial_path);
+		}
+		result
+	}
+
+	/// Back up to a directory, storing only the pages that changed since the last backup there, then prune old backups.
+	/// Returns the new backup, or None if nothing changed.  Restore with backup::restore_backup.
+	pub fn backup_incremental(&self, directory: &Path) -> Result<Option<PathBuf>> {
+		incremental_backup(&self.connection, directory, self.backup_chains_to_keep as usize)
+	}
+
+	/// Start a backup in the background if backup_interval_hours have passed since the last one.
+	/// Cheap enough to call every frame.  Does nothing if backup_directory isn't set.
+	pub fn backup_if_due(&mut self) {
+		if self.backup_directory.is_empty() || self.backup_interval_hours == 0 || self.read_only || Instant::now() < self.next_backup_check {
+			return;
+		}
+		if self.backup_thread.as_ref().map(|t| !t.is_finished()).unwrap_or(false) {
+			return;
+		}
+		self.next_backup_check = Instant::now() + BACKUP_CHECK_INTERVAL;
+
+		let directory = PathBuf::from(&self.backup_directory);
+		let interval = Duration::from_secs(self.backup_interval_hours as u64 * 60 * 60);
+		match backup::time_since_last_backup(&directory) {
+			Ok(Some(age)) if age < interval => return,
+			Ok(_) => {},
+			Err(e) => {
+				eprintln!("Failed to check for backups in {}: {}", directory.display(), e);
+				return;
+			}
+		}
+		let connection = self.connection.clone();
+		let chains_to_keep = self.backup_chains_to_keep as usize;
+		self.backup_thread = Some(std::thread::spawn(move || {
+			if let Err(e) = incremental_backup(&connection, &directory, chains_to_keep) {
+				eprintln!("Scheduled backup to {} failed: {}", directory.display(), e);
+			}
+		}));
+	}
+
+	/// Check the DB for corruption, then update the query planner's statistics and compact the file, in the background.
+	/// This can take minutes on a big DB.  Check on it with poll_maintenance.
+	pub fn start_maintenance(&mut self) -> Result<()> {
+		if self.is_maintenance_running() {
+			return Err(anyhow!("Maintenance is already running."));
+		}
+		let connection = self.connection.clone();
+		let (result_tx, result_rx) = channel::bounded(1);
+		std::thread::spawn(move || {
+			let _ = result_tx.send(run_maintenance(&connection));
+		});
+		self.maintenance_job = Some(result_rx);
+		Ok(())
+	}
+
+	pub fn is_maintenance_running(&self) -> bool {
+		self.maintenance_job.as_ref().map(|rx| rx.is_empty()).unwrap_or(false)
+	}
+
+	/// What maintenance found, once start_maintenance is done.  Only returned once.
+	pub fn poll_maintenance(&mut self) -> Option<Result<MaintenanceReport>> {
+		let result = self.maintenance_job.as_ref()?.try_recv().ok()?;
+		self.maintenance_job = None;
+		Some(result)
+	}
+
+	/// Measure what's taking up space in the DB and estimate what each CompactionJob would save.
+	pub fn analyze_storage(&self) -> Result<StorageReport> {
+		let conn = self.connection.lock();
+		let sum = |sql: &str| -> Result<u64> { Ok(conn.query_row(sql, [], |row| row.get::<_, Option<i64>>(0))?.unwrap_or(0) as u64) };
+		let page_size = sum("PRAGMA page_size")?;
+		let mut report = StorageReport {
+			file_bytes: sum("PRAGMA page_count")? * page_size,
+			free_bytes: sum("PRAGMA freelist_count")? * page_size,
+			thumbnail_bytes: sum("SELECT SUM(LENGTH(thumbnail)) FROM images")?,
+			embedding_bytes: sum("SELECT SUM(LENGTH(hash)) FROM semantic_hashes")? + sum("SELECT SUM(LENGTH(hash)) FROM cropped_hashes")? + sum("SELECT SUM(LENGTH(hash)) FROM embeddings")?,
+			tag_bytes: sum("SELECT SUM(LENGTH(name) + LENGTH(value)) FROM tags")?,
+			stale_embeddings: count_stale_embeddings(&conn, mlhash_model_version())?,
+			..Default::default()
+		};
+		for table in HASH_TABLES.iter().filter(|&&t| t != "semantic_hashes" && t != "cropped_hashes") {
+			report.other_hash_bytes += sum(&format!("SELECT SUM(LENGTH(hash)) FROM {}", table))?;
+		}
+
+		{
+			let mut stmt = conn.prepare("SELECT name, SUM(pgsize) FROM dbstat GROUP BY name ORDER BY 2 DESC")?;
+			let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?;
+			report.table_bytes = rows.collect::<SQLResult<Vec<(String, u64)>>>()?;
+		}
+		{
+			let hash_lengths = HASH_TABLES.iter().map(|t| format!("IFNULL((SELECT LENGTH(hash) FROM {} WHERE image_id = images.id), 0)", t)).collect::<Vec<String>>().join(" + ");
+			let mut stmt = conn.prepare(&format!("SELECT id, path, IFNULL(LENGTH(thumbnail), 0) + {} AS size FROM images ORDER BY size DESC LIMIT ?", hash_lengths))?;
+			let rows = stmt.query_map([LARGEST_IMAGES_REPORTED], |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, i64>(2)? as u64)))?;
+			report.largest_images = rows.collect::<SQLResult<Vec<(i64, String, u64)>>>()?;
+		}
+
+		for (table, size) in orphaned_data_sizes() {
+			report.orphaned_bytes += sum(&format!("SELECT SUM({}) FROM {} WHERE image_id NOT IN (SELECT id FROM images)", size, table))?;
+		}
+
+		// Recompress a random sample and assume the rest of the thumbnails shrink the same.
+		let estimate_savings = |job: CompactionJob| -> Result<u64> {
+			let (which, recompress) = job.thumbnail_recompression().ok_or_else(|| anyhow!("{:?} doesn't recompress thumbnails.", job))?;
+			let total_bytes = sum(&format!("SELECT SUM(LENGTH(thumbnail)) FROM images WHERE {}", which))?;
+			let mut stmt = conn.prepare(&format!("SELECT thumbnail FROM images WHERE {} ORDER BY RANDOM() LIMIT ?", which))?;
+			let sample = stmt.query_map([THUMBNAIL_SAVINGS_SAMPLE_SIZE], |row| row.get::<_, Vec<u8>>(0))?.collect::<SQLResult<Vec<Vec<u8>>>>()?;
+			let (sample_bytes, sample_saved) = sample.iter().fold((0u64, 0u64), |(total, saved), thumbnail| {
+				let recompressed_size = match recompress(thumbnail) {
+					Ok(Some(smaller)) => smaller.len(),
+					_ => thumbnail.len(),
+				};
+				(total + thumbnail.len() as u64, saved + (thumbnail.len() - recompressed_size) as u64)
+			});
+			Ok(if sample_bytes == 0 { 0 } else { (total_bytes as f64 * sample_saved as f64 / sample_bytes as f64) as u64 })
+		};
+		report.thumbnail_savings_estimate = estimate_savings(CompactionJob::RecompressThumbnails)?;
+		report.webp_savings_estimate = estimate_savings(CompactionJob::WebpThumbnails)?;
+		report.embedding_reduction_estimate = sum("SELECT SUM(LENGTH(hash)) FROM cropped_hashes")?;
+
+		Ok(report)
+	}
+
+	/// Counts and sizes for a dashboard.  See LibraryStats.
+	pub fn stats(&mut self) -> Result<LibraryStats> {
+		let folders = self.get_tracked_folders()?.clone();
+		let conn = self.connection.lock();
+		let sum = |sql: &str| -> Result<u64> { Ok(conn.query_row(sql, [], |row| row.get::<_, Option<i64>>(0))?.unwrap_or(0) as u64) };
+		let mut stats = LibraryStats {
+			num_images: sum("SELECT COUNT(*) FROM images WHERE trashed IS NULL")?,
+			num_trashed: sum("SELECT COUNT(*) FROM images WHERE trashed IS NOT NULL")?,
+			total_file_bytes: sum("SELECT SUM(file_size) FROM images WHERE trashed IS NULL")?,
+			thumbnail_bytes: sum("SELECT SUM(LENGTH(thumbnail)) FROM images WHERE trashed IS NULL")?,
+			..Default::default()
+		};
+		(stats.first_indexed, stats.last_indexed) = conn.query_row("SELECT MIN(indexed), MAX(indexed) FROM images WHERE trashed IS NULL", [], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+		{
+			let mut extension_counts = HashMap::<String, u64>::new();
+			let mut stmt = conn.prepare("SELECT filename FROM images WHERE trashed IS NULL")?;
+			for filename in stmt.query_map([], |row| row.get::<_, String>(0))? {
+				let extension = Path::new(&filename?).extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+				*extension_counts.entry(extension).or_default() += 1;
+			}
+			stats.by_extension = extension_counts.into_iter().collect();
+			stats.by_extension.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+		}
+
+		let mut stmt = conn.prepare("SELECT COUNT(*) FROM images WHERE trashed IS NULL AND substr(path, 1, length(?1)) = ?1")?;
+		for folder in folders {
+			let count = stmt.query_row(params![folder_prefix(&folder)], |row| row.get::<_, i64>(0))?;
+			stats.by_folder.push((folder, count as u64));
+		}
+
+		{
+			let bucket_cases = RESOLUTION_BUCKETS.iter().enumerate().map(|(idx, size)| format!("WHEN long_side <= {} THEN {}", size, idx)).collect::<Vec<String>>().join(" ");
+			let mut stmt = conn.prepare(&format!(
+				"SELECT CASE {} ELSE {} END AS bucket, COUNT(*) FROM (SELECT IFNULL(MAX(image_width, image_height), 0) AS long_side FROM images WHERE trashed IS NULL) GROUP BY bucket",
+				bucket_cases, RESOLUTION_BUCKETS.len()
+			))?;
+			let mut bucket_counts = vec![0u64; RESOLUTION_BUCKETS.len() + 1];
+			for row in stmt.query_map([], |row| Ok((row.get::<_, usize>(0)?, row.get::<_, i64>(1)?)))? {
+				let (bucket, count) = row?;
+				bucket_counts[bucket] = count as u64;
+			}
+			stats.by_resolution = bucket_counts.into_iter().enumerate().map(|(idx, count)| {
+				let label = match idx {
+					0 => format!("Up to {}px", RESOLUTION_BUCKETS[0]),
+					_ if idx == RESOLUTION_BUCKETS.len() => format!("Over {}px", RESOLUTION_BUCKETS[idx - 1]),
+					_ => format!("{} to {}px", RESOLUTION_BUCKETS[idx - 1] + 1, RESOLUTION_BUCKETS[idx]),
+				};
+				(label, count)
+			}).collect();
+		}
+
+		{
+			let mut stmt = conn.prepare("SELECT date(indexed) AS day, COUNT(*) FROM images WHERE trashed IS NULL AND indexed IS NOT NULL GROUP BY day ORDER BY day")?;
+			let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?;
+			stats.indexed_by_day = rows.collect::<SQLResult<Vec<(String, u64)>>>()?;
+		}
+
+		Ok(stats)
+	}
+
+	/// Run a compaction job in the background.  Check on it with poll_compaction_job.
+	/// Freed pages stay in the file until start_maintenance compacts it.
+	pub fn start_compaction_job(&mut self, job: CompactionJob) -> Result<()> {
+		if self.is_compaction_running() {
+			return Err(anyhow!("A compaction job is already running."));
+		}
+		if self.read_only {
+			return Err(anyhow!("Shared collections can't be changed."));
+		}
+		let connection = self.connection.clone();
+		let (result_tx, result_rx) = channel::bounded(1);
+		std::thread::spawn(move || {
+			let result = match job {
+				CompactionJob::RecompressThumbnails | CompactionJob::WebpThumbnails => recompress_all_thumbnails(&connection, job),
+				CompactionJob::ReduceEmbeddings => drop_cropped_embeddings(&connection),
+				CompactionJob::RemoveOrphans => remove_orphaned_data(&connection),
+			};
+			let _ = result_tx.send(result);
+		});
+		self.compaction_job = Some(result_rx);
+		Ok(())
+	}
+
+	pub fn is_compaction_running(&self) -> bool {
+		self.compaction_job.as_ref().map(|rx| rx.is_empty()).unwrap_or(false)
+	}
+
+	/// The bytes saved by the last compaction job, once it's done.  Only returned once.
+	pub fn poll_compaction_job(&mut self) -> Option<Result<u64>> {
+		let result = self.compaction_job.as_ref()?.try_recv().ok()?;
+		self.compaction_job = None;
+		self.cached_search_results = None; // Thumbnails may have changed.
+		Some(result)
+	}
+
+	/// Redo the embeddings that came from a model other than the installed one, in the background.
+	/// Each image is embedded from its file if that's still around and from its thumbnail if not.
+	/// open_or_create starts this by itself when it finds any and reembed_on_open is set.  Check on it with get_reembedding_progress and poll_reembedding.
+	pub fn start_reembedding(&mut self) -> Result<()> {
+		if self.reembedding_job.is_some() {
+			return Err(anyhow!("Re-embedding is already running."));
+		}
+		if self.read_only {
+			return Err(anyhow!("Shared collections can't be changed."));
+		}
+		let model = mlhash_model_version().ok_or_else(|| anyhow!("The embedding model is missing."))?;
+		let total = count_stale_embeddings(&self.connection.lock(), Some(model))?;
+		let connection = self.connection.clone();
+		let done = Arc::new(AtomicU64::new(0));
+		let thread_done = done.clone();
+		let stop = self.stop_indexing.clone();
+		let (result_tx, result_rx) = channel::bounded(1);
+		std::thread::spawn(move || {
+			let _ = result_tx.send(reembed_stale_hashes(&connection, Some(model), mlhash, &thread_done, &stop));
+		});
+		self.reembedding_job = Some((result_rx, done, total));
+		Ok(())
+	}
+
+	/// How many stale embeddings have been redone and how many there are in all, while start_reembedding is running.
+	pub fn get_reembedding_progress(&self) -> Option<(u64, u64)> {
+		let (result_rx, done, total) = self.reembedding_job.as_ref()?;
+		if !result_rx.is_empty() {
+			return None;
+		}
+		Some((done.load(Ordering::Relaxed), *total))
+	}
+
+	/// How many images were re-embedded, once start_reembedding is done.  Only returned once.
+	pub fn poll_reembedding(&mut self) -> Option<Result<u64>> {
+		let result = self.reembedding_job.as_ref()?.0.try_recv().ok()?;
+		self.reembedding_job = None;
+		self.cached_search_results = None; // Distances may have changed.
+		Some(result)
+	}
+
+	/// Redo a sample of the stale embeddings in the background, without storing them, and compare each image's nearest neighbors before and after.
+	/// Shows how much similarity searches will change before start_reembedding commits to the new model.  Check on it with poll_drift.
+	pub fn start_measuring_drift(&mut self) -> Result<()> {
+		if self.drift_job.is_some() {
+			return Err(anyhow!("Drift is already being measured."));
+		}
+		let model = mlhash_model_version().ok_or_else(|| anyhow!("The embedding model is missing."))?;
+		let connection = self.connection.clone();
+		let (result_tx, result_rx) = channel::bounded(1);
+		std::thread::spawn(move || {
+			let _ = result_tx.send(measure_embedding_drift(&connection, Some(model), mlhash, DRIFT_SAMPLE_SIZE));
+		});
+		self.drift_job = Some(result_rx);
+		Ok(())
+	}
+
+	pub fn is_measuring_drift(&self) -> bool {
+		self.drift_job.as_ref().map(|rx| rx.is_empty()).unwrap_or(false)
+	}
+
+	/// The drift, once start_measuring_drift is done.  Only returned once.
+	pub fn poll_drift(&mut self) -> Option<Result<EmbeddingDrift>> {
+		let result = self.drift_job.as_ref()?.try_recv().ok()?;
+		self.drift_job = None;
+		Some(result)
+	}
+
+	/// Run find_similar_groups over the whole index in the background, grouping images whose perceptual hashes are within near_duplicate_distance.
+	pub fn start_duplicate_scan(&mut self) -> Result<()> {
+		if self.duplicate_scan.is_some() {
+			return Err(anyhow!("A duplicate scan is already running."));
+		}
+		let connection = self.connection.clone();
+		let hidden_filter = self.hidden_filter();
+		let threshold = self.near_duplicate_distance;
+		let method = SimilarityMethod { hash: SimilarityHash::Perceptual, metric: DistanceMetric::Hamming };
+		let (result_tx, result_rx) = channel::bounded(1);
+		std::thread::spawn(move || {
+			let _ = result_tx.send(find_similar_groups(&connection, &hidden_filter, threshold, method));
+		});
+		self.duplicate_scan = Some(result_rx);
+		Ok(())
+	}
+
+	pub fn is_scanning_for_duplicates(&self) -> bool {
+		self.duplicate_scan.as_ref().map(|rx| rx.is_empty()).unwrap_or(false)
+	}
+
+	/// The groups, once start_duplicate_scan is done.  Only returned once.
+	pub fn poll_duplicate_scan(&mut self) -> Option<Result<Vec<SimilarGroup>>> {
+		let result = self.duplicate_scan.as_ref()?.try_recv().ok()?;
+		self.duplicate_scan = None;
+		Some(result)
+	}
+
+	/// Write every image in the index, with its tags, hashes, and thumbnail, to a new SQLite file at path.
+	/// Collections come along too.  Trash, folders, rules, and settings don't: see export_config for those.
+	/// The archive can be loaded into another DB with import_index, so nothing has to be hashed again.
+	pub fn export_index(&self, path: &Path) -> Result<usize> {
+		self.export_images(path, "images.trashed IS NULL", |conn| {
+			conn.execute("INSERT INTO archive.collections SELECT * FROM collections", [])?;
+			conn.execute("INSERT INTO archive.collection_members SELECT * FROM collection_members WHERE image_id IN (SELECT id FROM archive.images)", [])?;
+			Ok(())
+		})
+	}
+
+	/// Package a collection into a new DB at path that someone else can open or import.
+	/// It opens read-only, since changes to it would never make it back to the sender.
+	/// Smart collections are saved as whatever they match right now.
+	/// With include_originals, the image files are stored too, so the images can be viewed at full size.
+	/// Returns how many images were shared.
+	pub fn share_collection(&self, collection_id: i64, path: &Path, include_originals: bool) -> Result<usize> {
+		let (name, filter) = {
+			let conn = self.connection.lock();
+			let name: String = conn.query_row("SELECT name FROM collections WHERE id = ?", params![collection_id], |row| row.get(0))
+				.optional()?.ok_or_else(|| anyhow!("There is no collection with id {}.", collection_id))?;
+			let tokens = vec![format!("collection:{}", name)];
+			let smart_collections = resolve_smart_collections(&conn, &tokens, 0)?;
+			let filter = build_where_clause_from_parsed_query(&tokens, &smart_collections, &mut None)?;
+			(name, filter)
+		};
+		let num_shared = self.export_images(path, &format!("images.trashed IS NULL AND {}", filter), |conn| {
+			conn.execute("INSERT INTO archive.settings (name, value) VALUES ('shared_collection', ?)", params![&name])?;
+			conn.execute("INSERT INTO archive.collections (id, name, created) VALUES (1, ?, datetime('now'))", params![&name])?;
+			conn.execute("INSERT INTO archive.collection_members (collection_id, image_id, added) SELECT 1, id, datetime('now') FROM archive.images", [])?;
+			if include_originals {
+				conn.execute(&ORIGINALS_SCHEMA_V1.replacen("CREATE TABLE ", "CREATE TABLE archive.", 1), [])?;
+				let images: Vec<(i64, String)> = {
+					let mut stmt = conn.prepare("SELECT id, path FROM archive.images")?;
+					let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<SQLResult<Vec<(i64, String)>>>()?;
+					rows
+				};
+				for (image_id, image_path) in images {
+					match std::fs::read(filepath_from_string(&image_path)) {
+						Ok(data) => { conn.execute("INSERT INTO archive.originals (image_id, data) VALUES (?, ?)", params![image_id, data])?; },
+						Err(e) => eprintln!("Sharing {} without its original: {}", image_path, e),
+					}
+				}
+			}
+			Ok(())
+		})?;
+		Ok(num_shared)
+	}
+
+	/// The image file stored in a shared collection, if this DB came from share_collection with originals.
+	pub fn get_original(&self, image_id: i64) -> Result<Option<Vec<u8>>> {
+		let conn = self.connection.lock();
+		let has_originals = conn.query_row("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'originals'", [], |_| Ok(())).optional()?.is_some();
+		if !has_originals {
+			return Ok(None);
+		}
+		Ok(conn.query_row("SELECT data FROM originals WHERE image_id = ?", params![image_id], |row| row.get(0)).optional()?)
+	}
+
+	/// Make a new DB at path with the images matching filter and their tags and hashes, keeping their ids.
+	/// The new DB is attached as 'archive' while finish runs, so it can copy anything else that should go along.
+	fn export_images(&self, path: &Path, filter: &str, finish: impl FnOnce(&Connection) -> Result<()>) -> Result<usize> {
+		if path.exists() {
+			return Err(anyhow!("{} already exists.  Pick a new file.", path.display()));
+		}
+		let conn = self.connection.lock();
+		conn.execute("ATTACH DATABASE ? AS archive", params![path.to_string_lossy()])?;
+		let result = (|| -> Result<usize> {
+			create_tables(&conn, "archive")?;
+			conn.execute("INSERT INTO archive.settings (name, value) VALUES ('index_archive_version', ?)", params![INDEX_ARCHIVE_VERSION.to_string()])?;
+
+			let num_images = conn.execute(&format!("INSERT INTO archive.images (id, {0}) SELECT id, {0} FROM images WHERE {1}", IMAGE_COLUMNS, filter), [])?;
+			// Locations aren't in IMAGE_COLUMNS, since older archives don't have them.  Importing reads them from the tags again.
+			conn.execute("UPDATE archive.images SET (latitude, longitude) = (SELECT latitude, longitude FROM main.images WHERE main.images.id = archive.images.id)", [])?;
+			conn.execute("INSERT INTO archive.tags SELECT * FROM tags WHERE image_id IN (SELECT id FROM archive.images)", [])?;
+			for table in HASH_TABLES {
+				conn.execute(&format!("INSERT INTO archive.{0} SELECT * FROM {0} WHERE image_id IN (SELECT id FROM archive.images)", table), [])?;
+			}
+			conn.execute("INSERT INTO archive.embedding_models SELECT * FROM embedding_models", [])?;
+			conn.execute("INSERT INTO archive.embeddings SELECT * FROM embeddings WHERE image_id IN (SELECT id FROM archive.images)", [])?;
+			finish(&conn)?;
+			Ok(num_images)
+		})();
+		conn.execute("DETACH DATABASE archive", [])?;
+		if result.is_err() {
+			let _ = std::fs::remove_file(path);
+		}
+		result
+	}
+
+	/// Add the images in an archive from export_index to this DB.  Image ids are reassigned, so this works on any DB.
+	/// Images whose path is already indexed are skipped.  Collections are merged by name.
+	/// Returns how many images were added.
+	pub fn import_index(&mut self, path: &Path) -> Result<usize> {
+		if !path.is_file() {
+			return Err(anyhow!("{} doesn't exist.", path.display()));
+		}
+		let num_imported = {
+			let mut conn = self.connection.lock();
+			conn.execute("ATTACH DATABASE ? AS archive", params![path.to_string_lossy()])?;
+			let result = Engine::import_attached_index(&mut conn);
+			conn.execute("DETACH DATABASE archive", [])?;
+			result?
+		};
+		self.cached_index_size = None;
+		self.collections_cache = None;
+		Ok(num_imported)
+	}
+
+	fn import_attached_index(conn: &mut Connection) -> Result<usize> {
+		// Any other SQLite file fails here, either because it has no settings table or no version in it.
+		let version: Option<String> = conn.query_row(
+			"SELECT value FROM archive.settings WHERE name = 'index_archive_version'", [], |row| row.get(0)
+		).ok();
+		match version.and_then(|v| v.parse::<u64>().ok()) {
+			None => return Err(anyhow!("This isn't an index archive from PixelBox.")),
+			Some(v) if v > INDEX_ARCHIVE_VERSION => return Err(anyhow!("This index archive is from a newer version of PixelBox (format {}).", v)),
+			Some(_) => {},
+		}
+
+		let tx = conn.transaction()?;
+		tx.execute("CREATE TEMP TABLE archive_ids (old_id INTEGER PRIMARY KEY, new_id INTEGER NOT NULL)", [])?;
+		let old_ids: Vec<i64> = {
+			let mut stmt = tx.prepare("SELECT MIN(id) FROM archive.images WHERE path NOT IN (SELECT path FROM main.images) GROUP BY path ORDER BY 1")?;
+			let ids = stmt.query_map([], |row| row.get(0))?.collect::<SQLResult<Vec<i64>>>()?;
+			ids
+		};
+		for old_id in &old_ids {
+			tx.execute(&format!("INSERT INTO main.images ({0}) SELECT {0} FROM archive.images WHERE id = ?", IMAGE_COLUMNS), params![old_id])?;
+			tx.execute("INSERT INTO archive_ids (old_id, new_id) VALUES (?, ?)", params![old_id, tx.last_insert_rowid()])?;
+		}
+
+		tx.execute(
+			"INSERT INTO main.tags (image_id, name, value, source)
+			SELECT archive_ids.new_id, tags.name, tags.value, tags.source FROM archive.tags AS tags INNER JOIN archive_ids ON archive_ids.old_id = tags.image_id",
+			[]
+		)?;
+		fill_tag_numbers(&tx, "image_id IN (SELECT new_id FROM archive_ids)")?;
+		fill_locations(&tx, "id IN (SELECT new_id FROM archive_ids)")?;
+		// Embeddings in archives from before models were recorded are left without one, so they're re-embedded.
+		let archive_has_models = tx.prepare("SELECT 1 FROM pragma_table_info('semantic_hashes', 'archive') WHERE name = 'model'")?.exists([])?;
+		for table in HASH_TABLES {
+			// Archives from before cropped embeddings don't have their table.
+			if !tx.prepare("SELECT 1 FROM archive.sqlite_master WHERE type = 'table' AND name = ?")?.exists([table])? {
+				continue;
+			}
+			let columns = if table == "semantic_hashes" && archive_has_models { ", model" } else { "" };
+			tx.execute(&format!(
+				"INSERT INTO main.{0} (image_id, hash{1}) SELECT archive_ids.new_id, hashes.hash{1} FROM archive.{0} AS hashes INNER JOIN archive_ids ON archive_ids.old_id = hashes.image_id",
+				table, columns
+			), [])?;
+		}
+		// Blurred thumbnails are made again from the tags rather than copied.
+		let flagged = tx.prepare("SELECT DISTINCT tags.image_id FROM main.tags AS tags INNER JOIN archive_ids ON archive_ids.new_id = tags.image_id WHERE tags.name = ? COLLATE NOCASE")?
+			.query_map([SENSITIVE_TAG], |row| row.get(0))?
+			.collect::<SQLResult<Vec<i64>>>()?;
+		for image_id in flagged {
+			update_blurred_thumbnail(&tx, image_id)?;
+		}
+		// Archives from before there were other embedding models don't have their tables.  Models are matched by name, version, and size.
+		if tx.prepare("SELECT 1 FROM archive.sqlite_master WHERE name = 'embeddings'")?.exists([])? {
+			tx.execute("INSERT OR IGNORE INTO main.embedding_models (name, version, dimensions) SELECT name, version, dimensions FROM archive.embedding_models", [])?;
+			tx.execute(
+				"INSERT OR IGNORE INTO main.embeddings (image_id, model_id, hash)
+				SELECT archive_ids.new_id, models.id, embeddings.hash
+				FROM archive.embeddings AS embeddings
+				INNER JOIN archive_ids ON archive_ids.old_id = embeddings.image_id
+				INNER JOIN archive.embedding_models AS archived ON archived.id = embeddings.model_id
+				INNER JOIN main.embedding_models AS models ON models.name = archived.name AND models.version = archived.version AND models.dimensions = archived.dimensions",
+				[]
+			)?;
+		}
+
+		// Names are unique ignoring case, so a collection that already exists keeps its own query.
+		// Hidden collections stay hidden, with the same passphrase.  Archives from before there were hidden collections don't have passphrases.
+		let has_passphrases = tx.prepare("SELECT 1 FROM pragma_table_info('collections', 'archive') WHERE name = 'passphrase'")?.exists([])?;
+		tx.execute(
+			&format!("INSERT OR IGNORE INTO main.collections (name, created, query, passphrase) SELECT name, created, query, {} FROM archive.collections", if has_passphrases { "passphrase" } else { "NULL" }),
+			[]
+		)?;
+		tx.execute(
+			"INSERT OR IGNORE INTO main.collection_members (collection_id, image_id, added)
+			SELECT collections.id, archive_ids.new_id, members.added
+			FROM archive.collection_members AS members
+			INNER JOIN archive.collections AS archived ON archived.id = members.collection_id
+			INNER JOIN main.collections AS collections ON collections.name = archived.name
+			INNER JOIN archive_ids ON archive_ids.old_id = members.image_id
+			WHERE collections.query IS NULL",
+			[]
+		)?;
+
+		tx.execute("DROP TABLE archive_ids", [])?;
+		tx.commit()?;
+		Ok(old_ids.len())
+	}
+
+	/// Save the query under the given name, replacing any saved search with the same name.
+	pub fn save_search(&mut self, name: &str, query: &str) -> Result<()> {
+		let name = name.trim();
+		if name.is_empty() {
+			return Err(anyhow!("Saved searches need a name."));
+		}
+		self.connection.lock().execute(
+			"INSERT OR REPLACE INTO saved_searches (name, query, created) VALUES (?, ?, datetime('now'))",
+			params![name, query]
+		)?;
+		self.saved_searches_cache = None;
+		Ok(())
+	}
+
+	/// All saved searches, sorted by name.
+	pub fn get_saved_searches(&mut self) -> Result<Vec<SavedSearch>> {
+		if self.saved_searches_cache.is_none() {
+			let conn = self.connection.lock();
+			let mut stmt = conn.prepare("SELECT name, query FROM saved_searches ORDER BY name COLLATE NOCASE")?;
+			let search_cursor = stmt.query_map([], |row| Ok(SavedSearch { name: row.get(0)?, query: row.get(1)? }))?;
+			self.saved_searches_cache = Some(search_cursor.collect::<SQLResult<Vec<SavedSearch>>>()?);
+		}
+		Ok(self.saved_searches_cache.clone().unwrap_or_default())
+	}
+
+	pub fn rename_saved_search(&mut self, old_name: &str, new_name: &str) -> Result<()> {
+		let new_name = new_name.trim();
+		if new_name.is_empty() {
+			return Err(anyhow!("Saved searches need a name."));
+		}
+		let renamed = self.connection.lock().execute("UPDATE saved_searches SET name = ? WHERE name = ?", params![new_name, old_name])?;
+		if renamed == 0 {
+			return Err(anyhow!("There is no saved search named '{}'.", old_name));
+		}
+		self.saved_searches_cache = None;
+		Ok(())
+	}
+
+	pub fn delete_saved_search(&mut self, name: &str) -> Result<()> {
+		self.connection.lock().execute("DELETE FROM saved_searches WHERE name = ?", params![name])?;
+		self.saved_searches_cache = None;
+		Ok(())
+	}
+
+	/// Make a new, empty collection.  Returns its id.
+	pub fn create_collection(&mut self, name: &str) -> Result<i64> {
+		self.insert_collection(name, None)
+	}
+
+	/// Make a collection of everything matching the query.  Returns its id.
+	pub fn create_smart_collection(&mut self, name: &str, query: &str) -> Result<i64> {
+		check_smart_collection_query(query)?;
+		self.insert_collection(name, Some(query))
+	}
+
+	/// Make a new, empty hidden collection.  It starts out unlocked for this session.  Returns its id.
+	pub fn create_hidden_collection(&mut self, name: &str, passphrase: &str) -> Result<i64> {
+		if passphrase.is_empty() {
+			return Err(anyhow!("Hidden collections need a passphrase."));
+		}
+		let id = self.insert_collection(name, None)?;
+		self.hide_collection(id, passphrase)?;
+		Ok(id)
+	}
+
+	/// Hide a collection behind a passphrase.  Its images are left out of searches and views, and it isn't listed, until unlock_hidden_collections is given the passphrase.
+	/// Giving a hidden collection a new passphrase needs it to be unlocked.
+	pub fn hide_collection(&mut self, collection_id: i64, passphrase: &str) -> Result<()> {
+		if passphrase.is_empty() {
+			return Err(anyhow!("Hidden collections need a passphrase."));
+		}
+		let collection = self.get_collections()?.into_iter().find(|c| c.id == collection_id).ok_or_else(|| anyhow!("There's no collection with id {}.", collection_id))?;
+		if collection.is_smart() {
+			return Err(anyhow!("Smart collections can't be hidden, since they don't hold their images."));
+		}
+		self.connection.lock().execute("UPDATE collections SET passphrase = ? WHERE id = ?", params![hash_passphrase(passphrase)?, collection_id])?;
+		self.unlocked_collections.insert(collection_id);
+		self.collections_cache = None;
+		Ok(())
+	}
+
+	/// Make a hidden collection ordinary again.  It has to be unlocked first.
+	pub fn unhide_collection(&mut self, collection_id: i64) -> Result<()> {
+		if !self.unlocked_collections.contains(&collection_id) {
+			return Err(anyhow!("Unlock the collection before unhiding it."));
+		}
+		self.connection.lock().execute("UPDATE collections SET passphrase = NULL WHERE id = ?", params![collection_id])?;
+		self.unlocked_collections.remove(&collection_id);
+		self.collections_cache = None;
+		Ok(())
+	}
+
+	/// Show the hidden collections with this passphrase, and their images, until lock_hidden_collections or the DB is closed.
+	/// Returns how many were unlocked.  Which ones exist isn't given away if none match.
+	pub fn unlock_hidden_collections(&mut self, passphrase: &str) -> Result<usize> {
+		let hidden: Vec<(i64, String)> = self.connection.lock()
+			.prepare("SELECT id, passphrase FROM collections WHERE passphrase IS NOT NULL")?
+			.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+			.collect::<SQLResult<Vec<(i64, String)>>>()?;
+		let matching: Vec<i64> = hidden.into_iter().filter(|(_, stored)| passphrase_matches(passphrase, stored)).map(|(id, _)| id).collect();
+		if matching.is_empty() {
+			return Err(anyhow!("No hidden collection has that passphrase."));
+		}
+		self.unlocked_collections.extend(&matching);
+		self.collections_cache = None;
+		self.trashed_images_cache = None;
+		Ok(matching.len())
+	}
+
+	/// Hide every hidden collection again.  Results already on screen that came from them are cleared.
+	pub fn lock_hidden_collections(&mut self) {
+		if self.unlocked_collections.is_empty() {
+			return;
+		}
+		self.unlocked_collections.clear();
+		self.cancel_query();
+		self.cached_search_results = None;
+		self.cached_search_total = None;
+		self.collections_cache = None;
+		self.trashed_images_cache = None;
+	}
+
+	/// True if any hidden collection is unlocked right now.
+	pub fn has_unlocked_collections(&self) -> bool {
+		!self.unlocked_collections.is_empty()
+	}
+
+	fn insert_collection(&mut self, name: &str, query: Option<&str>) -> Result<i64> {
+		let name = name.trim();
+		if name.is_empty() {
+			return Err(anyhow!("Collections need a name."));
+		}
+		let conn = self.connection.lock();
+		let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM collections WHERE name = ? COLLATE NOCASE)", params![name], |row| row.get(0))?;
+		if exists {
+			return Err(anyhow!("There is already a collection named '{}'.", name));
+		}
+		conn.execute("INSERT INTO collections (name, created, query) VALUES (?, datetime('now'), ?)", params![name, query])?;
+		let id = conn.last_insert_rowid();
+		drop(conn);
+		self.collections_cache = None;
+		Ok(id)
+	}
+
+	/// Change the query of a smart collection.
+	pub fn set_collection_query(&mut self, collection_id: i64, query: &str) -> Result<()> {
+		check_smart_collection_query(query)?;
+		let updated = self.connection.lock().execute("UPDATE collections SET query = ? WHERE id = ? AND query IS NOT NULL", params![query, collection_id])?;
+		if updated == 0 {
+			return Err(anyhow!("Only smart collections have a query."));
+		}
+		self.collections_cache = None;
+		Ok(())
+	}
+
+	/// All collections, sorted by name.
+	pub fn get_collections(&mut self) -> Result<Vec<Collection>> {
+		if self.collections_cache.is_none() {
+			let conn = self.connection.lock();
+			let mut stmt = conn.prepare(&format!(r#"
+				SELECT collections.id, collections.name, CASE WHEN collections.query IS NULL THEN COUNT(images.id) END, collections.query, collections.passphrase IS NOT NULL
+				FROM collections
+				LEFT JOIN collection_members ON collection_members.collection_id = collections.id
+				LEFT JOIN images ON images.id = collection_members.image_id AND images.trashed IS NULL AND {}
+				WHERE collections.passphrase IS NULL OR collections.id IN ({})
+				GROUP BY collections.id
+				ORDER BY collections.name COLLATE NOCASE"#,
+				self.hidden_filter(), self.unlocked_collections.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ")
+			))?;
+			let collection_cursor = stmt.query_map([], |row| Ok(Collection { id: row.get(0)?, name: row.get(1)?, size: row.get(2)?, query: row.get(3)?, hidden: row.get(4)? }))?;
+			self.collections_cache = Some(collection_cursor.collect::<SQLResult<Vec<Collection>>>()?);
+		}
+		Ok(self.collections_cache.clone().unwrap_or_default())
+	}
+
+	pub fn rename_collection(&mut self, collection_id: i64, new_name: &str) -> Result<()> {
+		let new_name = new_name.trim();
+		if new_name.is_empty() {
+			return Err(anyhow!("Collections need a name."));
+		}
+		let conn = self.connection.lock();
+		let taken: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM collections WHERE name = ? COLLATE NOCASE AND id != ?)", params![new_name, collection_id], |row| row.get(0))?;
+		if taken {
+			return Err(anyhow!("There is already a collection named '{}'.", new_name));
+		}
+		conn.execute("UPDATE collections SET name = ? WHERE id = ?", params![new_name, collection_id])?;
+		drop(conn);
+		self.collections_cache = None;
+		Ok(())
+	}
+
+	/// Delete the collection.  The images in it are left alone.
+	pub fn delete_collection(&mut self, collection_id: i64) -> Result<()> {
+		let mut conn = self.connection.lock();
+		let tx = conn.transaction()?;
+		tx.execute("DELETE FROM collection_members WHERE collection_id = ?", params![collection_id])?;
+		tx.execute("DELETE FROM collections WHERE id = ?", params![collection_id])?;
+		tx.commit()?;
+		drop(conn);
+		self.collections_cache = None;
+		Ok(())
+	}
+
+	/// Adding an image that's already in the collection does nothing.
+	pub fn add_to_collection(&mut self, collection_id: i64, image_id: i64) -> Result<()> {
+		let added = self.connection.lock().execute(
+			"INSERT OR IGNORE INTO collection_members (collection_id, image_id, added)
+			SELECT id, ?2, datetime('now') FROM collections WHERE id = ?1 AND query IS NULL",
+			params![collection_id, image_id]
+		)?;
+		if added == 0 && self.get_collections()?.iter().any(|c| c.id == collection_id && c.is_smart()) {
+			return Err(anyhow!("Smart collections are filled by their query, so images can't be added by hand."));
+		}
+		self.collections_cache = None;
+		Ok(())
+	}
+
+	pub fn remove_from_collection(&mut self, collection_id: i64, image_id: i64) -> Result<()> {
+		self.connection.lock().execute("DELETE FROM collection_members WHERE collection_id = ? AND image_id = ?", params![collection_id, image_id])?;
+		self.collections_cache = None;
+		Ok(())
+	}
+
+	/// The ids of every collection the image is in, in the order the collections were made.
+	pub fn get_image_collections(&self, image_id: i64) -> Result<Vec<i64>> {
+		let conn = self.connection.lock();
+		let mut stmt = conn.prepare("SELECT collection_id FROM collection_members WHERE image_id = ? ORDER BY collection_id")?;
+		let id_cursor = stmt.query_map(params![image_id], |row| row.get(0))?;
+		Ok(id_cursor.collect::<SQLResult<Vec<i64>>>()?)
+	}
+
+	/// All auto-organize rules, in the order they were created.
+	pub fn get_rules(&mut self) -> Result<Vec<Rule>> {
+		if self.rules_cache.is_none() {
+			let conn = self.connection.lock();
+			let mut stmt = conn.prepare("SELECT id, name, enabled, folder_contains, file_type, exif_name, exif_value, tag_name, tag_value, collection_name FROM rules ORDER BY id")?;
+			let rule_cursor = stmt.query_map([], |row|{
+				let file_type: Option<String> = row.get(4)?;
+				Ok(Rule {
+					id: row.get(0)?,
+					name: row.get(1)?,
+					enabled: row.get(2)?,
+					folder_contains: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+					file_type: file_type.as_deref().and_then(TypeFilter::from_name),
+					exif_name: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+					exif_value: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+					tag_name: row.get(7)?,
+					tag_value: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+					collection_name: row.get(9)?,
+				})
+			})?;
+			self.rules_cache = Some(rule_cursor.collect::<SQLResult<Vec<Rule>>>()?);
+		}
+		Ok(self.rules_cache.clone().unwrap_or_default())
+	}
+
+	/// Insert the rule if it hasn't been saved yet, otherwise update it.  Returns the rule's id.
+	pub fn save_rule(&mut self, rule: &Rule) -> Result<i64> {
+		if rule.tag_name.trim().is_empty() && rule.collection_name.trim().is_empty() {
+			return Err(anyhow!("Rule '{}' needs a tag to add or a collection to add to.", rule.name));
+		}
+		let collection_name = rule.collection_name.trim();
+		if !collection_name.is_empty() {
+			let is_smart: Option<bool> = self.connection.lock().query_row(
+				"SELECT query IS NOT NULL FROM collections WHERE name = ? COLLATE NOCASE",
+				params![collection_name],
+				|row| row.get(0)
+			).optional()?;
+			match is_smart {
+				Some(true) => return Err(anyhow!("'{}' is a smart collection, so it's filled by its query and rules can't add to it.", collection_name)),
+				Some(false) => {},
+				None => { self.create_collection(collection_name)?; },
+			}
+		}
+		let file_type = rule.file_type.map(|t| t.name());
+		let conn = self.connection.lock();
+		let rule_id = if rule.id == 0 {
+			conn.execute(
+				"INSERT INTO rules (name, enabled, folder_contains, file_type, exif_name, exif_value, tag_name, tag_value, collection_name) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+				params![&rule.name, rule.enabled, &rule.folder_contains, file_type, &rule.exif_name, &rule.exif_value, &rule.tag_name, &rule.tag_value, collection_name]
+			)?;
+			conn.last_insert_rowid()
+		} else {
+			conn.execute(
+				"UPDATE rules SET name = ?, enabled = ?, folder_contains = ?, file_type = ?, exif_name = ?, exif_value = ?, tag_name = ?, tag_value = ?, collection_name = ? WHERE id = ?",
+				params![&rule.name, rule.enabled, &rule.folder_contains, file_type, &rule.exif_name, &rule.exif_value, &rule.tag_name, &rule.tag_value, collection_name, rule.id]
+			)?;
+			rule.id
+		};
+		self.rules_cache = None;
+		Ok(rule_id)
+	}
+
+	pub fn delete_rule(&mut self, rule_id: i64) -> Result<()> {
+		self.connection.lock().execute("DELETE FROM rules WHERE id = ?", params![rule_id])?;
+		self.rules_cache = None;
+		Ok(())
+	}
+
+	pub fn get_tracked_folders(&mut self) -> Result<&Vec<String>> {
+		if self.watched_directories_cache.is_none() {
+			let conn = self.connection.lock();
+			let mut stmt = conn.prepare("SELECT glob, priority, ignore_patterns, recursive, extensions, scan_archives, follow_symlinks, archive_password FROM watched_directories ORDER BY priority DESC, rowid")?;
+			let glob_cursor = stmt.query_map([], |row|{
+				let ignore_patterns:String = row.get(2)?;
+				let extensions:String = row.get(4)?;
+				Ok(crawler::CrawlFolder {
+					glob: row.get(0)?,
+					priority: row.get(1)?,
+					ignore_patterns: ignore_patterns.lines().map(|p| p.to_string()).collect(),
+					recursive: row.get(3)?,
+					extensions: extensions.split_whitespace().map(|e| e.to_string()).collect(),
+					scan_archives: row.get(5)?,
+					follow_symlinks: row.get(6)?,
+					archive_password: row.get(7)?,
+				})
+			})?;
+			let all_folders = glob_cursor.collect::<SQLResult<Vec<crawler::CrawlFolder>>>()?;
+
+			self.watched_directories_cache = Some(all_folders.iter().map(|folder| folder.glob.clone()).collect());
+			self.folder_settings = all_folders.into_iter().map(|folder| (folder.glob.clone(), folder)).collect();
+			self.folder_watcher = None; // index_watched_changes watches the folders again as they are now.
+		}
+
+		Ok(self.watched_directories_cache.as_ref().expect("Filled in above."))
+	}
+}
+
+/// Bring a DB up to date by adding the tables and columns that were added after it was made.
+/// Does nothing to a DB that's already current, so it's safe to run on every open.
+fn migrate(conn: &mut Connection) -> Result<()> {
+	let tx = conn.transaction()?;
+	let has_table = |name: &str| tx.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")?.exists([name]);
+	let had_screenshot_sources = has_table("screenshot_sources")?;
+	let had_blurred_thumbnails = has_table("blurred_thumbnails")?;
+	create_tables(&tx, "main")?;
+	if !had_screenshot_sources {
+		let images = tx.prepare("SELECT id, filename FROM images ORDER BY id")?
+			.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+			.collect::<SQLResult<Vec<(i64, String)>>>()?;
+		for (image_id, filename) in images {
+			record_screenshot_source(&tx, image_id, &filename, None)?;
+		}
+	}
+	if !had_blurred_thumbnails {
+		let flagged = tx.prepare("SELECT DISTINCT image_id FROM tags WHERE name = ? COLLATE NOCASE")?
+			.query_map([SENSITIVE_TAG], |row| row.get(0))?
+			.collect::<SQLResult<Vec<i64>>>()?;
+		for image_id in flagged {
+			update_blurred_thumbnail(&tx, image_id)?;
+		}
+	}
+	for (table, column, definition) in ADDED_COLUMNS {
+		let has_column = tx.prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = ?", table))?.exists([column])?;
+		if !has_column {
+			tx.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+			// Until now there was only ever the one model, so existing embeddings are assumed to be from the one that's installed.
+			if (table, column) == ("semantic_hashes", "model") {
+				tx.execute("UPDATE semantic_hashes SET model = ?", params![mlhash_model_version()])?;
+			}
+			if (table, column) == ("tags", "number") {
+				fill_tag_numbers(&tx, "1")?;
+			}
+			if (table, column) == ("images", "longitude") {
+				fill_locations(&tx, "1")?;
+			}
+		}
+	}
+	let had_path_index = tx.prepare("SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'images_path'")?.exists([])?;
+	if !had_path_index {
+		merge_duplicate_paths(&tx)?;
+		tx.execute(IMAGE_PATH_INDEX_V1, [])?;
+	}
+	tx.commit()?;
+	Ok(())
+}
+
+/// Fold images that were indexed more than once under the same path into the first of them.
+/// Before paths were unique, two crawls of overlapping folders could both add a file.
+fn merge_duplicate_paths(conn: &Connection) -> Result<()> {
+	let duplicates = conn.prepare("
+		SELECT images.id, firsts.id FROM images
+		INNER JOIN (SELECT path, MIN(id) AS id FROM images GROUP BY path HAVING COUNT(*) > 1) AS firsts ON firsts.path = images.path
+		WHERE images.id != firsts.id"
+	)?.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<SQLResult<Vec<(i64, i64)>>>()?;
+	for (duplicate, first) in duplicates {
+		// Ratings and marks were set by hand on whichever copy turned up, so the first gets the highest of each.
+		conn.execute(
+			"UPDATE images SET
+				rating = MAX(rating, (SELECT rating FROM images WHERE id = ?1)),
+				favorite = MAX(favorite, (SELECT favorite FROM images WHERE id = ?1)),
+				protected = MAX(protected, (SELECT protected FROM images WHERE id = ?1))
+			WHERE id = ?2",
+			params![duplicate, first]
+		)?;
+		// What came from the file is on both.  What was added since is kept.
+		conn.execute(
+			"INSERT INTO tags (image_id, name, value, source, number)
+			SELECT ?2, name, value, source, number FROM tags AS added
+			WHERE image_id = ?1 AND source != ?3 AND NOT EXISTS (SELECT 1 FROM tags WHERE image_id = ?2 AND name = added.name AND value IS added.value)",
+			params![duplicate, first, TAG_SOURCE_EXIF]
+		)?;
+		conn.execute("INSERT OR IGNORE INTO collection_members (collection_id, image_id, added) SELECT collection_id, ?2, added FROM collection_members WHERE image_id = ?1", params![duplicate, first])?;
+		conn.execute("UPDATE duplicate_of SET canonical_id = ?2 WHERE canonical_id = ?1", params![duplicate, first])?;
+		conn.execute("DELETE FROM duplicate_of WHERE image_id = canonical_id", [])?;
+		conn.execute("UPDATE duplicate_reviews SET existing_image_id = ?2 WHERE existing_image_id = ?1", params![duplicate, first])?;
+		for (table, _) in orphaned_data_sizes() {
+			conn.execute(&format!("DELETE FROM {} WHERE image_id = ?", table), params![duplicate])?;
+		}
+		conn.execute("DELETE FROM images WHERE id = ?", params![duplicate])?;
+		update_blurred_thumbnail(conn, first)?;
+	}
+	Ok(())
+}
+
+/// Set the number of the tags matching filter, a WHERE clause on tags, from their values.
+fn fill_tag_numbers(conn: &Connection, filter: &str) -> Result<()> {
+	let numbers = conn.prepare(&format!("SELECT rowid, value FROM tags WHERE {}", filter))?
+		.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?)))?
+		.collect::<SQLResult<Vec<_>>>()?
+		.into_iter()
+		.filter_map(|(rowid, value)| Some((rowid, tag_number(&value?)?)));
+	let mut update = conn.prepare("UPDATE tags SET number = ? WHERE rowid = ?")?;
+	for (rowid, number) in numbers {
+		update.execute(params![number, rowid])?;
+	}
+	Ok(())
+}
+
+/// Set the location of the images matching filter, a WHERE clause on images, from their GPS tags.
+fn fill_locations(conn: &Connection, filter: &str) -> Result<()> {
+	let mut gps_tags = HashMap::<i64, HashMap<String, String>>::new();
+	let rows = conn.prepare(&format!(
+		"SELECT image_id, name, value FROM tags WHERE name IN ({}) AND value IS NOT NULL AND image_id IN (SELECT id FROM images WHERE {})",
+		GPS_TAGS.map(|name| format!("'{}'", name)).join(", "), filter
+	))?.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?.collect::<SQLResult<Vec<_>>>()?;
+	for (image_id, name, value) in rows {
+		gps_tags.entry(image_id).or_default().insert(name, value);
+	}
+	let mut update = conn.prepare("UPDATE images SET latitude = ?, longitude = ? WHERE id = ?")?;
+	for (image_id, tags) in gps_tags {
+		if let Some((latitude, longitude)) = gps_location(&tags) {
+			update.execute(params![latitude, longitude, image_id])?;
+		}
+	}
+	Ok(())
+}
+
+/// A salted PBKDF2 hash of a hidden collection's passphrase, as the salt and the hash in hex with a colon between.
+fn hash_passphrase(passphrase: &str) -> Result<String> {
+	let mut salt = [0u8; 16];
+	SystemRandom::new().fill(&mut salt).map_err(|_| anyhow!("Couldn't make a salt for the passphrase."))?;
+	let mut hash = [0u8; 32];
+	let iterations = PASSPHRASE_ITERATIONS.try_into().expect("PASSPHRASE_ITERATIONS is nonzero");
+	pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, passphrase.as_bytes(), &mut hash);
+	let to_hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+	Ok(format!("{}:{}", to_hex(&salt), to_hex(&hash)))
+}
+
+/// Whether the passphrase is the one hash_passphrase made stored from.
+fn passphrase_matches(passphrase: &str, stored: &str) -> bool {
+	let from_hex = |hex: &str| (0..hex.len()).step_by(2).map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok())).collect::<Option<Vec<u8>>>();
+	let (salt, hash) = match stored.split_once(':').and_then(|(salt, hash)| Some((from_hex(salt)?, from_hex(hash)?))) {
+		Some(parsed) => parsed,
+		None => return false,
+	};
+	let iterations = PASSPHRASE_ITERATIONS.try_into().expect("PASSPHRASE_ITERATIONS is nonzero");
+	pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, passphrase.as_bytes(), &hash).is_ok()
+}
+
+/// Errors unless the model is registered and makes embeddings the size of this one.
+fn check_embedding_dimensions(conn: &Connection, model_id: i64, embedding: &[f32]) -> Result<()> {
+	let dimensions: Option<usize> = conn.query_row("SELECT dimensions FROM embedding_models WHERE id = ?", params![model_id], |row| row.get(0)).optional()?;
+	match dimensions {
+		None => Err(anyhow!("There's no embedding model with id {}.", model_id)),
+		Some(d) if d != embedding.len() => Err(anyhow!("That model makes embeddings with {} values, not {}.", d, embedding.len())),
+		Some(_) => Ok(()),
+	}
+}
+
+/// If the filename or the title read off its title bar says which window the image is a screenshot of,
+/// record that and put it in a session with the screenshots of that window just before or after it.
+fn record_screenshot_source(conn: &Connection, image_id: i64, filename: &str, window_title: Option<&str>) -> Result<()> {
+	let source = match screenshot::detect_screenshot_source(filename).or_else(|| window_title.and_then(screenshot::source_from_window_title)) {
+		Some(source) => source,
+		None => return Ok(()),
+	};
+	let mut session = None;
+	if let Some(captured) = &source.captured {
+		// A screenshot can bridge two sessions, so it joins the earliest and the rest are folded into it.
+		let nearby: Vec<i64> = conn.prepare("
+			SELECT DISTINCT session FROM screenshot_sources
+			WHERE window = ?1 AND app = ?2 AND session IS NOT NULL AND ABS(strftime('%s', captured) - strftime('%s', ?3)) <= ?4
+			ORDER BY session"
+		)?.query_map(params![source.window, source.app, captured, SCREENSHOT_SESSION_GAP_SECONDS], |row| row.get(0))?.collect::<SQLResult<Vec<i64>>>()?;
+		let joined = nearby.first().copied().unwrap_or(image_id);
+		for other in nearby.iter().skip(1) {
+			conn.execute("UPDATE screenshot_sources SET session = ? WHERE session = ?", params![joined, other])?;
+		}
+		session = Some(joined);
+	}
+	conn.execute(
+		"INSERT OR REPLACE INTO screenshot_sources (image_id, app, window, captured, session) VALUES (?, ?, ?, ?, ?)",
+		params![image_id, source.app, source.window, source.captured, session]
+	)?;
+	Ok(())
+}
+
+/// Make or remove the image's blurred thumbnail, depending on whether it has a SENSITIVE_TAG tag now.
+fn update_blurred_thumbnail(conn: &Connection, image_id: i64) -> Result<()> {
+	let sensitive = conn.prepare("SELECT 1 FROM tags WHERE image_id = ? AND name = ? COLLATE NOCASE")?.exists(params![image_id, SENSITIVE_TAG])?;
+	if !sensitive {
+		conn.execute("DELETE FROM blurred_thumbnails WHERE image_id = ?", params![image_id])?;
+		return Ok(());
+	}
+	if conn.prepare("SELECT 1 FROM blurred_thumbnails WHERE image_id = ?")?.exists(params![image_id])? {
+		return Ok(());
+	}
+	let thumbnail: Option<Vec<u8>> = conn.query_row("SELECT thumbnail FROM images WHERE id = ?", params![image_id], |row| row.get(0)).optional()?.flatten();
+	// Without a thumbnail to blur, a blank one stands in.
+	let blurred = match thumbnail.filter(|t| !t.is_empty()) {
+		Some(thumbnail) => blur_thumbnail(&thumbnail, SENSITIVE_BLUR_FRACTION)?,
+		None => qoi::encode_to_vec([128u8; 3], 1, 1)?,
+	};
+	conn.execute("INSERT INTO blurred_thumbnails (image_id, thumbnail) VALUES (?, ?)", params![image_id, blurred])?;
+	Ok(())
+}
+
+/// Tables that hold per-image data, with an expression for how much each row stores.
+fn orphaned_data_sizes() -> Vec<(&'static str, &'static str)> {
+	let mut tables = vec![("tags", "LENGTH(name) + IFNULL(LENGTH(value), 0)"), ("collection_members", "16"), ("duplicate_of", "16"), ("embeddings", "IFNULL(LENGTH(hash), 0)"), ("screenshot_sources", "LENGTH(app) + LENGTH(window) + 24"), ("blurred_thumbnails", "IFNULL(LENGTH(thumbnail), 0)")];
+	tables.extend(HASH_TABLES.iter().map(|t| (*t, "IFNULL(LENGTH(hash), 0)")));
+	tables
+}
+
+/// Swap thumbnails for smaller PNGs or WebPs, depending on the job, where that saves space.  Done in batches so searches aren't held up.
+fn recompress_all_thumbnails(connection: &FairMutex<Connection>, job: CompactionJob) -> Result<u64> {
+	let (which, recompress) = job.thumbnail_recompression().ok_or_else(|| anyhow!("{:?} doesn't recompress thumbnails.", job))?;
+	let mut saved = 0;
+	let mut last_id = i64::MIN;
+	loop {
+		let batch: Vec<(i64, Vec<u8>)> = {
+			let conn = connection.lock();
+			let mut stmt = conn.prepare(&format!("SELECT id, thumbnail FROM images WHERE id > ? AND {} ORDER BY id LIMIT ?", which))?;
+			let rows = stmt.query_map(params![last_id, RECOMPRESS_BATCH_SIZE], |row| Ok((row.get(0)?, row.get(1)?)))?;
+			rows.collect::<SQLResult<Vec<(i64, Vec<u8>)>>>()?
+		};
+		match batch.last() {
+			Some((id, _)) => last_id = *id,
+			None => return Ok(saved),
+		}
+
+		let smaller: Vec<(i64, Vec<u8>, usize)> = batch.into_iter().filter_map(|(id, thumbnail)| {
+			recompress(&thumbnail).ok().flatten().map(|smaller| (id, smaller, thumbnail.len()))
+		}).collect();
+		let mut conn = connection.lock();
+		let tx = conn.transaction()?;
+		for (id, thumbnail, old_size) in smaller {
+			tx.execute("UPDATE images SET thumbnail = ? WHERE id = ?", params![thumbnail, id])?;
+			saved += (old_size - thumbnail.len()) as u64;
+		}
+		tx.commit()?;
+	}
+}
+
+/// How many embeddings weren't made by this model.
+fn count_stale_embeddings(conn: &Connection, model: Option<&str>) -> Result<u64> {
+	Ok(conn.query_row("SELECT COUNT(*) FROM semantic_hashes WHERE model IS NOT ?", params![model], |row| row.get(0))?)
+}
+
+/// Replace embeddings that weren't made by this model with ones from embed.  Done in batches so searches aren't held up.
+/// Images that can't be loaded from their file or thumbnail are left as they are.  Returns how many were redone.
+fn reembed_stale_hashes(connection: &FairMutex<Connection>, model: Option<&str>, embed: impl Fn(&image::DynamicImage) -> Vec<u8>, done: &AtomicU64, stop: &AtomicBool) -> Result<u64> {
+	let mut reembedded = 0;
+	let mut last_id = i64::MIN;
+	while !stop.load(Ordering::Relaxed) {
+		let batch: Vec<(i64, String, Vec<u8>, bool)> = {
+			let conn = connection.lock();
+			let mut stmt = conn.prepare("
+				SELECT semantic_hashes.image_id, images.path, IFNULL(images.thumbnail, x''), cropped_hashes.image_id IS NOT NULL
+				FROM semantic_hashes
+				INNER JOIN images ON images.id = semantic_hashes.image_id
+				LEFT JOIN cropped_hashes ON cropped_hashes.image_id = semantic_hashes.image_id
+				WHERE semantic_hashes.image_id > ? AND semantic_hashes.model IS NOT ?
+				ORDER BY semantic_hashes.image_id
+				LIMIT ?"
+			)?;
+			let rows = stmt.query_map(params![last_id, model, REEMBED_BATCH_SIZE], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+			rows.collect::<SQLResult<Vec<(i64, String, Vec<u8>, bool)>>>()?
+		};
+		match batch.last() {
+			Some((id, _, _, _)) => last_id = *id,
+			None => break,
+		}
+
+		// Cropped embeddings are from the same model, so they go stale along with the others.
+		let hashes: Vec<(i64, Vec<u8>, Option<Vec<u8>>)> = batch.into_iter().filter_map(|(id, path, thumbnail, has_cropped)| {
+			done.fetch_add(1, Ordering::Relaxed);
+			let img = load_image_or_thumbnail(&path, &thumbnail)?;
+			let cropped = if has_cropped { Some(embed(&crop_to_content(&img))) } else { None };
+			Some((id, embed(&img), cropped))
+		}).collect();
+		let mut conn = connection.lock();
+		let tx = conn.transaction()?;
+		for (id, hash, cropped) in hashes {
+			tx.execute("UPDATE semantic_hashes SET hash = ?, model = ? WHERE image_id = ?", params![hash, model, id])?;
+			if let Some(cropped) = cropped {
+				tx.execute("UPDATE cropped_hashes SET hash = ? WHERE image_id = ?", params![cropped, id])?;
+			}
+			reembedded += 1;
+		}
+		tx.commit()?;
+	}
+	Ok(reembedded)
+}
+
+/// The image from its file if that's still around, or from its thumbnail if not.
+fn load_image_or_thumbnail(path: &str, thumbnail: &[u8]) -> Option<image::DynamicImage> {
+	image::open(filepath_from_string(path)).ok().or_else(|| {
+		let (pixels, (width, height)) = decode_thumbnail(thumbnail).ok()?;
+		image::RgbImage::from_raw(width, height, pixels).map(image::DynamicImage::ImageRgb8)
+	})
+}
+
+/// Engine::find_similar_groups.  The DB is only locked to read, so indexing and searches can go on while the hashes are compared.
+fn find_similar_groups(connection: &FairMutex<Connection>, hidden_filter: &str, threshold:f64, method:SimilarityMethod) -> Result<Vec<SimilarGroup>> {
+	let hashes = {
+		let conn = connection.lock();
+		let mut stmt = conn.prepare(&format!(
+			"SELECT images.id, {0}.hash FROM {0} INNER JOIN images ON images.id = {0}.image_id WHERE images.trashed IS NULL AND {1} ORDER BY images.id",
+			method.hash.table(), hidden_filter
+		))?;
+		let hash_cursor = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+		hash_cursor.collect::<SQLResult<Vec<(i64, Vec<u8>)>>>()?
+	};
+
+	// Union-find over indices into hashes.
+	let mut parents: Vec<usize> = (0..hashes.len()).collect();
+	fn root(parents: &mut [usize], mut idx: usize) -> usize {
+		while parents[idx] != idx {
+			parents[idx] = parents[parents[idx]];
+			idx = parents[idx];
+		}
+		idx
+	}
+	for a in 0..hashes.len() {
+		for b in a+1..hashes.len() {
+			if (method.metric.distance(&hashes[a].1, &hashes[b].1) as f64) <= threshold {
+				let (root_a, root_b) = (root(&mut parents, a), root(&mut parents, b));
+				parents[root_b] = root_a;
+			}
+		}
+	}
+	let mut members: HashMap<usize, Vec<i64>> = HashMap::new();
+	for (idx, (id, _)) in hashes.iter().enumerate() {
+		let group = root(&mut parents, idx);
+		members.entry(group).or_default().push(*id);
+	}
+
+	let conn = connection.lock();
+	let mut stmt = conn.prepare(&format!("SELECT {} FROM images WHERE images.id = ?", SELECT_FIELDS))?;
+	let mut groups = vec![];
+	for ids in members.into_values().filter(|ids| ids.len() > 1) {
+		let mut images = ids.iter().map(|id| stmt.query_row(params![id], indexed_image_from_row)).collect::<SQLResult<Vec<IndexedImage>>>()?;
+		images.sort_by(|a, b| b.file_size.cmp(&a.file_size).then(a.id.cmp(&b.id)));
+		let total_bytes = images.iter().map(|img| img.file_size).sum();
+		groups.push(SimilarGroup { images, total_bytes });
+	}
+	groups.sort_by(|a, b| b.reclaimable_bytes().cmp(&a.reclaimable_bytes()).then(a.images[0].id.cmp(&b.images[0].id)));
+	Ok(groups)
+}
+
+/// Embed a random sample of the images whose embeddings weren't made by this model, and compare their nearest neighbors under both.  Nothing is stored.
+fn measure_embedding_drift(connection: &FairMutex<Connection>, model: Option<&str>, embed: impl Fn(&image::DynamicImage) -> Vec<u8>, sample_size: i64) -> Result<EmbeddingDrift> {
+	let sample: Vec<(Vec<u8>, String, Vec<u8>)> = {
+		let conn = connection.lock();
+		let mut stmt = conn.prepare("
+			SELECT semantic_hashes.hash, images.path, IFNULL(images.thumbnail, x'')
+			FROM semantic_hashes
+			INNER JOIN images ON images.id = semantic_hashes.image_id
+			WHERE semantic_hashes.model IS NOT ?
+			ORDER BY RANDOM()
+			LIMIT ?"
+		)?;
+		let rows = stmt.query_map(params![model, sample_size], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+		rows.collect::<SQLResult<Vec<(Vec<u8>, String, Vec<u8>)>>>()?
+	};
+	let (old, new): (Vec<Vec<u8>>, Vec<Vec<u8>>) = sample.into_iter()
+		.filter_map(|(hash, path, thumbnail)| Some((hash, embed(&load_image_or_thumbnail(&path, &thumbnail)?))))
+		.unzip();
+	if old.len() < 3 {
+		return Err(anyhow!("Only {} images with embeddings from another model could be loaded.  At least 3 are needed to compare neighbors.", old.len()));
+	}
+	Ok(embedding_drift(&old, &new))
+}
+
+/// Compare each embedding's ranking of the others under the old and new embeddings.  old and new are the same images in the same order.
+fn embedding_drift(old: &[Vec<u8>], new: &[Vec<u8>]) -> EmbeddingDrift {
+	let neighbors = DRIFT_NEIGHBORS.min(old.len() - 1);
+	let mut correlations = vec![];
+	let mut overlap = 0.0;
+	for i in 0..old.len() {
+		let distances = |hashes: &[Vec<u8>]| -> Vec<f32> {
+			let query = QueryEmbedding::new(&hashes[i]);
+			(0..hashes.len()).filter(|&j| j != i).map(|j| query.cosine_distance(&hashes[j])).collect()
+		};
+		let (old_ranks, new_ranks) = (ranks(&distances(old)), ranks(&distances(new)));
+		if let Some(correlation) = pearson_correlation(&old_ranks, &new_ranks) {
+			correlations.push(correlation);
+		}
+		let nearest = |ranks: &Vec<f64>| -> HashSet<usize> {
+			let mut order: Vec<usize> = (0..ranks.len()).collect();
+			order.sort_by(|&a, &b| ranks[a].total_cmp(&ranks[b]));
+			order.into_iter().take(neighbors).collect()
+		};
+		overlap += nearest(&old_ranks).intersection(&nearest(&new_ranks)).count() as f64 / neighbors as f64;
+	}
+	EmbeddingDrift {
+		sample_size: old.len(),
+		// Images that are the same distance from everything can't be ranked.  If none can, nothing has changed.
+		rank_correlation: if correlations.is_empty() { 1.0 } else { correlations.iter().sum::<f64>() / correlations.len() as f64 },
+		neighbor_overlap: overlap / old.len() as f64,
+	}
+}
+
+/// The rank of each value, from 0.  Ties get the average of the ranks they span.
+fn ranks(values: &[f32]) -> Vec<f64> {
+	let mut order: Vec<usize> = (0..values.len()).collect();
+	order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+	let mut ranks = vec![0.0; values.len()];
+	let mut start = 0;
+	while start < order.len() {
+		let mut end = start + 1;
+		while end < order.len() && values[order[end]] == values[order[start]] {
+			end += 1;
+		}
+		for &index in &order[start..end] {
+			ranks[index] = (start + end - 1) as f64 / 2.0;
+		}
+		start = end;
+	}
+	ranks
+}
+
+/// None if either side doesn't vary.
+fn pearson_correlation(a: &[f64], b: &[f64]) -> Option<f64> {
+	let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+	let (mean_a, mean_b) = (mean(a), mean(b));
+	let covariance: f64 = a.iter().zip(b).map(|(x, y)| (x - mean_a) * (y - mean_b)).sum();
+	let spread = |values: &[f64], m: f64| values.iter().map(|v| (v - m) * (v - m)).sum::<f64>().sqrt();
+	let (spread_a, spread_b) = (spread(a, mean_a), spread(b, mean_b));
+	if spread_a == 0.0 || spread_b == 0.0 {
+		return None;
+	}
+	Some(covariance / (spread_a * spread_b))
+}
+
+/// For Engine::start_maintenance.  Deleted thumbnails leave free pages behind, and only VACUUM gives that space back.
+/// If the integrity check finds problems nothing else is done, since rewriting a damaged DB can lose more of it.  Restore a backup instead.
+fn run_maintenance(connection: &FairMutex<Connection>) -> Result<MaintenanceReport> {
+	let conn = connection.lock();
+	let db_size = |conn: &Connection| -> Result<u64> {
+		let page_count: i64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+		let page_size: i64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+		Ok((page_count * page_size) as u64)
+	};
+	let bytes_before = db_size(&conn)?;
+	let problems: Vec<String> = {
+		let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+		let rows = stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<SQLResult<Vec<String>>>()?;
+		rows.into_iter().filter(|r| r != "ok").collect()
+	};
+	if problems.is_empty() {
+		conn.execute_batch("ANALYZE; VACUUM;")?;
+	}
+	Ok(MaintenanceReport { problems, bytes_before, bytes_after: db_size(&conn)? })
+}
+
+/// Delete per-image data left behind for images that are no longer indexed.
+fn remove_orphaned_data(connection: &FairMutex<Connection>) -> Result<u64> {
+	let mut conn = connection.lock();
+	let tx = conn.transaction()?;
+	let mut removed = 0;
+	for (table, size) in orphaned_data_sizes() {
+		let orphan_filter = format!("FROM {} WHERE image_id NOT IN (SELECT id FROM images)", table);
+		removed += tx.query_row(&format!("SELECT SUM({}) {}", size, orphan_filter), [], |row| row.get::<_, Option<i64>>(0))?.unwrap_or(0) as u64;
+		tx.execute(&format!("DELETE {}", orphan_filter), [])?;
+	}
+	tx.commit()?;
+	Ok(removed)
+}
+
+/// Delete every cropped embedding, for CompactionJob::ReduceEmbeddings.  Returns how many bytes they took.
+fn drop_cropped_embeddings(connection: &FairMutex<Connection>) -> Result<u64> {
+	let mut conn = connection.lock();
+	let tx = conn.transaction()?;
+	let removed = tx.query_row("SELECT SUM(LENGTH(hash)) FROM cropped_hashes", [], |row| row.get::<_, Option<i64>>(0))?.unwrap_or(0) as u64;
+	tx.execute("DELETE FROM cropped_hashes", [])?;
+	tx.commit()?;
+	Ok(removed)
+}
+
+/// Copy the whole DB to a new file at path.
+/// The caller holds the lock, which keeps the indexer from writing mid-copy and making SQLite start over.
+fn copy_db(conn: &Connection, path: &Path) -> Result<()> {
+	let mut backup_conn = Connection::open(path)?;
+	Backup::new(conn, &mut backup_conn)?.run_to_completion(BACKUP_PAGES_PER_STEP, Duration::ZERO, None)?;
+	backup_conn.close().map_err(|(_, e)| e)?;
+	Ok(())
+}
+
+/// Snapshot the DB into the backup directory, keep what changed, and prune old chains.
+/// The lock is only held while snapshotting, so searches and indexing can go on while the snapshot is diffed.
+fn incremental_backup(connection: &FairMutex<Connection>, directory: &Path, chains_to_keep: usize) -> Result<Option<PathBuf>> {
+	std::fs::create_dir_all(directory)?;
+	let snapshot = directory.join(BACKUP_SNAPSHOT_FILENAME);
+	let _ = std::fs::remove_file(&snapshot);
+	let result = copy_db(&connection.lock(), &snapshot).and_then(|_| backup::store_snapshot(&snapshot, directory));
+	let _ = std::fs::remove_file(&snapshot);
+	let stored = result?;
+	backup::prune_backups(directory, chains_to_keep)?;
+	Ok(stored)
+}
+
+/// Make every table for a new DB.  schema is "main" or the name of an attached DB.
+fn create_tables(conn: &Connection, schema: &str) -> Result<()> {
+	let in_schema = |sql: &str| sql.replacen("CREATE TABLE ", &format!("CREATE TABLE IF NOT EXISTS {}.", schema), 1);
+	for sql in [
+		IMAGE_SCHEMA_V1, WATCHED_DIRECTORIES_SCHEMA_V1, SETTINGS_SCHEMA_V1, TAG_SCHEMA_V1, DUPLICATE_REVIEW_SCHEMA_V1, RULES_SCHEMA_V1,
+		SAVED_SEARCHES_SCHEMA_V1, SEARCH_HISTORY_SCHEMA_V1, COLLECTIONS_SCHEMA_V1, COLLECTION_MEMBERS_SCHEMA_V1,
+		DUPLICATE_OF_SCHEMA_V1, EMBEDDING_MODELS_SCHEMA_V1, EMBEDDINGS_SCHEMA_V1, SCREENSHOT_SOURCES_SCHEMA_V1, BLURRED_THUMBNAILS_SCHEMA_V1,
+		FAILURES_SCHEMA_V1, EXCLUSIONS_SCHEMA_V1,
+	] {
+		conn.execute(&in_schema(sql), [])?;
+	}
+	// phashes and semantic hashes should be identical instructure so we can swap them out.
+	// Can't use prepared statements for CREATE TABLE, so we have to substitute $tablename$.
+	for table in HASH_TABLES {
+		let schema = if table == "semantic_hashes" { SEMANTIC_HASH_SCHEMA_V1.to_string() } else { HASH_TABLE_SCHEMA_V1.replace("$tablename$", table) };
+		conn.execute(&in_schema(&schema), [])?;
+	}
+	Ok(())
+}
+
+// Query utility functions:
+fn tokenize_query(query: &String) -> Result<Vec<String>> {
+	let mut spans = vec![];
+	let mut next_character_escaped = false;
+	let mut quote_active = false;
+	let mut active_string = String::new(); // We accumulate into this, stopping at a space if not quoted or stopping at an end-quote if quoted.
+	for character in query.chars() {
+		if next_character_escaped {
+			// An escaped leading minus is part of the name to look for, not a negation, so say so with the filename prefix.
+			if character == '-' && active_string.is_empty() {
+				active_string.push_str("filename:");
+			}
+			active_string.push(character);
+			next_character_escaped = false;
+		} else {
+			match character {
+				'"' => {
+					// This double is NOT quoted, so we are either starting or finishing a quote.
+					if !quote_active { // We are starting.
+						quote_active = true;
+					} else { // We are finishing a quote.
+						quote_active = false;
+						spans.push(active_string);
+						active_string = String::new();
+					}
+				},
+				'\\' => {
+					// A backtick!
+					next_character_escaped = true;
+				},
+				' ' => {
+					// If we are in a quote, continue.  Otherwise break the word.
+					if quote_active {
+						active_string.push(' ');
+					} else {
+						// We are at a breakpoint, but if the active word is empty there's no sense in pushing it.
+						if !active_string.is_empty() {
+							spans.push(active_string);
+							active_string = String::new();
+						}
+					}
+				},
+				_ => active_string.push(character)
+			}
+		}
+	};
+
+	if quote_active {
+		return Err(anyhow!("String tokenization failed: trailing open-quote.".to_string()));
+	} else if next_character_escaped {
+		return Err(anyhow!("String tokenization failed: trailing escape character.".to_string()));
+	}
+
+	// Push the last trailing active string into the spans.
+	if !active_string.is_empty() {
+		spans.push(active_string);
+	}
+
+	Ok(spans)
+}
+
+/// Build the WHERE clause of every smart collection named in the query, keyed by the name as written.
+fn resolve_smart_collections(conn: &Connection, tokens: &Vec<String>, depth: usize) -> Result<HashMap<String, String>> {
+	let mut smart_collections = HashMap::new();
+	for token in tokens {
+		let Some((magic_prefix, name)) = token.split_once(':') else { continue };
+		if !(magic_prefix.eq_ignore_ascii_case("collection") || magic_prefix.eq_ignore_ascii_case("album")) || smart_collections.contains_key(name) {
+			continue;
+		}
+		let query: Option<String> = conn.query_row("SELECT query FROM collections WHERE name = ? COLLATE NOCASE", params![name], |row| row.get(0)).optional()?.flatten();
+		if let Some(query) = query {
+			if depth >= MAX_SMART_COLLECTION_DEPTH {
+				return Err(anyhow!("Smart collection '{}' is nested too deeply.  Does it include itself?", name));
+			}
+			let inner_tokens = tokenize_query(&query)?;
+			let inner_smart_collections = resolve_smart_collections(conn, &inner_tokens, depth + 1)?;
+			// Similarity searches need an image loaded ahead of time, so they're ignored in smart collections.
+			let inner_clause = build_where_clause_from_parsed_query(&inner_tokens, &inner_smart_collections, &mut None)?;
+			smart_collections.insert(name.to_string(), if inner_clause.is_empty() { "1".to_string() } else { format!("({})", inner_clause) });
+		}
+	}
+	Ok(smart_collections)
+}
+
+/// Smart collection queries are checked when they're saved so browsing them doesn't fail later.
+fn check_smart_collection_query(query: &str) -> Result<()> {
+	let tokens = tokenize_query(&query.to_string())?;
+	if tokens.iter().any(|t| t.split_once(':').is_some_and(|(prefix, _)| prefix.eq_ignore_ascii_case("similar"))) {
+		return Err(anyhow!("Smart collections can't use similar: searches."));
+	}
+	build_where_clause_from_parsed_query(&tokens, &HashMap::new(), &mut None)?;
+	Ok(())
+}
+
+fn build_where_clause_from_parsed_query(tokens: &Vec<String>, smart_collections: &HashMap<String, String>, cached_similar_image: &mut Option<IndexedImage>) -> Result<String> {
+	// If there's a magic prefix like "similar", "filename", or a tag, add that to a 'where'.
+	// Otherwise, search all of the tags and exif data.
+
+	let mut and_where_clauses = vec![];
+	let mut type_filters = vec![]; // These are OR'ed together instead of AND'ed, since an image only has one type.
+	for token in tokens {
+		if let Some(negated) = token.strip_prefix('-').filter(|t| !t.is_empty()) {
+			// A leading minus excludes anything with the term in its filename, path, or tags.
+			// The rest of the token is taken literally, so "-a:b" excludes "a:b" rather than negating a prefix.
+			// Tags are joined one row per tag, so they need NOT EXISTS to exclude the whole image.
+			let negated = escape_sql_string(negated);
+			and_where_clauses.push(format!(
+				"NOT (images.filename LIKE '%{0}%' OR images.path LIKE '%{0}%' OR EXISTS (SELECT 1 FROM tags AS negated_tags WHERE negated_tags.image_id = images.id AND (negated_tags.name LIKE '%{0}%' OR negated_tags.value LIKE '%{0}%')))",
+				negated
+			));
+		} else if let Some((magic_prefix, remaining)) = token.split_once(':') {
+			match magic_prefix.to_lowercase().as_str() {
+				// SPECIAL CASE FOR VISUAL SIMILARITY!
+				// I hate that this is separate and would like to clean up this method.
+				// It's kinda' a different modality of searching.
+				"similar" => {
+					// If we already hashed this image and it is unchanged, don't recalculate.
+					// TODO: For case-sensitive operating systems this might need to change.
+					// The cropped embedding takes as long again, so it's only made for method:cropped.
+					let wants_cropped = parse_similarity_method_from_parsed_query(tokens).ok().flatten().is_some_and(|m| m.hash == SimilarityHash::Cropped);
+					let needs_recalculation = match cached_similar_image {
+						Some(img) => !img.path.eq_ignore_ascii_case(remaining) || (wants_cropped && img.cropped_hash.is_none()),
+						None => true,
+					};
+
+					if needs_recalculation {
+						let debug_start_load_image = Instant::now();
+						let indexed_image = IndexedImage::from_file_path_cropped(Path::new(remaining), wants_cropped, false);
+						let debug_end_load_image = Instant::now();
+						eprintln!("Time to compute image hash: {:?}", debug_end_load_image - debug_start_load_image);
+						*cached_similar_image = indexed_image.ok();
+					}
+					// The distance is computed in the SELECT, so there's nothing to add to the WHERE.
+				},
+				"exif" | "tag" => {
+					// Split the remaining into tag and target.
+					// If there's no ':' then search both.
+					if let Some(comparison) = parse_tag_comparison(remaining) {
+						and_where_clauses.push(comparison);
+					} else if let Some((tag, target)) = remaining.split_once(":") {
+						and_where_clauses.push(format!("(tags.name LIKE '%{}%' AND tags.value LIKE '%{}%')", escape_sql_string(tag), escape_sql_string(target)));
+					} else {
+						let remaining = escape_sql_string(remaining);
+						and_where_clauses.push(format!("(tags.name LIKE '%{}%' OR tags.value LIKE '%{}%')", &remaining, &remaining));
+					}
+				},
+				"all" => {
+					// Search for this value in EVERY field.
+					let remaining = escape_sql_string(remaining);
+					and_where_clauses.push(format!(" (tags.value LIKE '%{}%' OR images.filename LIKE '%{}%' OR images.path LIKE '%{}%') ", &remaining, &remaining, &remaining));
+				},
+				"type" => {
+					for type_name in remaining.split(',').filter(|t| !t.is_empty()) {
+						let type_filter = TypeFilter::from_name(type_name).ok_or_else(|| anyhow!("Unknown type '{}'.  Try one of: {}", type_name, TypeFilter::ALL.map(|t| t.name()).join(", ")))?;
+						type_filters.push(type_filter.to_sql());
+					}
+				},
+				"color" | "colour" => {
+					let color = parse_hex_color(remaining)?;
+					// The color is validated hex, so it's safe to inline as a blob literal.
+					and_where_clauses.push(format!(
+						"EXISTS (SELECT 1 FROM palettes WHERE palettes.image_id = images.id AND palette_distance(X'{:02x}{:02x}{:02x}', palettes.hash) < {})",
+						color[0], color[1], color[2], DEFAULT_MAX_COLOR_DISTANCE
+					));
+				},
+				"rating" => and_where_clauses.push(parse_rating_filter(remaining)?),
+				"near" => and_where_clauses.push(parse_near_filter(remaining)?),
+				// Collection names match exactly, ignoring case.  Quote names with spaces, like collection:"Summer 2023".
+				"collection" | "album" if smart_collections.contains_key(remaining) => and_where_clauses.push(smart_collections[remaining].clone()),
+				"collection" | "album" => and_where_clauses.push(format!(
+					"EXISTS (SELECT 1 FROM collection_members INNER JOIN collections ON collections.id = collection_members.collection_id WHERE collection_members.image_id = images.id AND collections.name = '{}' COLLATE NOCASE)",
+					escape_sql_string(remaining)
+				)),
+				"fav" | "favorite" => and_where_clauses.push(format!("images.favorite = {}", parse_bool(remaining)? as u8)),
+				// Matches the window title or the app, like window:Figma or window:"README.md".
+				"window" | "app" => and_where_clauses.push(format!(
+					"images.id IN (SELECT image_id FROM screenshot_sources WHERE window LIKE '%{0}%' ESCAPE '\\' OR app LIKE '%{0}%' ESCAPE '\\')",
+					escape_like_pattern(remaining)
+				)),
+				"session" => and_where_clauses.push(format!(
+					"images.id IN (SELECT image_id FROM screenshot_sources WHERE session = {})",
+					remaining.parse::<i64>().map_err(|_| anyhow!("Unable to parse session '{}': expected a number.", remaining))?
+				)),
+				"minsize" => and_where_clauses.push(format!("images.file_size >= {}", parse_file_size(remaining)?)),
+				"maxsize" => and_where_clauses.push(format!("images.file_size <= {}", parse_file_size(remaining)?)),
+				// We default to filename but want to handle the case where the person explicitly searches for it.
+				"filename" => and_where_clauses.push(format!("images.filename LIKE '%{}%'", escape_sql_string(remaining))),
+				// Handled by parse_sort_order_from_parsed_query.
+				"sort" | "method" => {},
+				// Not a prefix we know, so it's probably part of a name, like "12:30".
+				_ => and_where_clauses.push(format!("images.filename LIKE '%{}%'", escape_sql_string(token))),
+			}
+		} else {
+			and_where_clauses.push(format!("images.filename LIKE '%{}%'", escape_sql_string(token)));
+		}
+	}
+
+	if !type_filters.is_empty() {
+		and_where_clauses.push(format!("({})", type_filters.join(" OR ")));
+	}
+
+	Ok(and_where_clauses.join(" AND "))
+}
+
+/// Turn the value of a rating: prefix, like ">=4", "<2", or "5", into a WHERE clause.
+fn parse_rating_filter(value: &str) -> Result<String> {
+	let value = value.trim();
+	let (operator, number) = [">=", "<=", "!=", ">", "<", "="].into_iter()
+		.find_map(|op| value.strip_prefix(op).map(|rest| (op, rest)))
+		.unwrap_or(("=", value));
+	let rating: u8 = number.trim().parse().map_err(|_| anyhow!("Unable to parse rating '{}': expected something like rating:>=4.", value))?;
+	if rating > MAX_RATING {
+		return Err(anyhow!("Ratings go from 0 to {}, not {}.", MAX_RATING, rating));
+	}
+	Ok(format!("images.rating {} {}", operator, rating))
+}
+
+/// An image's tags from a JSON array of [name, value, source] triples.
+/// EXIF tags keep their names.  A user or rule tag with the same name as another tag is kept as "source:name", like "user:Model", so neither is lost.
+fn tags_from_json(tag_data: &JSONValue) -> HashMap<String, String> {
+	let mut triples: Vec<(String, String, String)> = tag_data.as_array().map(|rows| rows.iter().filter_map(|row| {
+		let text = |v: &JSONValue| match v {
+			// to_string would keep the JSON quotes around string values.
+			JSONValue::String(text) => text.clone(),
+			JSONValue::Null => String::new(),
+			other => other.to_string(),
+		};
+		match row.as_array()?.as_slice() {
+			[name, value, source] => Some((text(name), text(value), text(source))),
+			_ => None,
+		}
+	}).collect()).unwrap_or_default();
+	// EXIF first, so it's the one that keeps the plain name.
+	triples.sort_by_key(|(name, _, source)| (source != TAG_SOURCE_EXIF, source.clone(), name.clone()));
+
+	let mut tags = HashMap::new();
+	for (name, value, source) in triples {
+		if tags.contains_key(&name) {
+			tags.insert(format!("{}:{}", source, name), value);
+		} else {
+			tags.insert(name, value);
+		}
+	}
+	tags
+}
+
+/// A tag value as a number, for comparing in exif: queries.  None if it's anything but one number.
+/// Handles what EXIF values look like as text: 1600, 2.8, f/2.8, 1/125 for exposures, and 35 mm.
+fn tag_number(value: &str) -> Option<f64> {
+	let mut words = value.split_whitespace();
+	let first = words.next()?;
+	// A unit can follow, as its own word or stuck on the end, but nothing else.
+	if words.next().is_some_and(|unit| !unit.chars().all(char::is_alphabetic)) || words.next().is_some() {
+		return None;
+	}
+	let first = first.strip_prefix("f/").or_else(|| first.strip_prefix("F/")).unwrap_or(first);
+	let first = first.trim_end_matches(char::is_alphabetic);
+	let number = match first.split_once('/') {
+		Some((numerator, denominator)) => numerator.parse::<f64>().ok()? / denominator.parse::<f64>().ok()?,
+		None => first.parse::<f64>().ok()?,
+	};
+	Some(number).filter(|n| n.is_finite())
+}
+
+/// A numeric comparison on a tag, like exif:ISO>1600 or exif:ExposureTime<=1/60.  None if the text isn't one.
+/// The tag's whole name has to match, in any case, so FocalLength<35 doesn't compare FocalLengthIn35mmFilm too.
+fn parse_tag_comparison(text: &str) -> Option<String> {
+	let split_at = text.find(['<', '>', '=', '!'])?;
+	let (name, comparison) = text.split_at(split_at);
+	let (operator, number) = [">=", "<=", "!=", ">", "<", "="].into_iter()
+		.find_map(|op| comparison.strip_prefix(op).map(|rest| (op, rest)))?;
+	let number = tag_number(number)?;
+	// Cameras write ISO as PhotographicSensitivity, which nobody searches for.
+	let name = match name.trim() {
+		"" => return None,
+		n if n.eq_ignore_ascii_case("iso") => "PhotographicSensitivity".to_string(),
+		n => escape_sql_string(n),
+	};
+	Some(format!("(tags.name = '{}' COLLATE NOCASE AND tags.number {} {})", name, operator, number))
+}
+
+/// Where a photo was taken, as latitude and longitude in degrees, from its GPS tags.
+/// kamadak-exif shows coordinates like "51 deg 30 min 26.4 sec", with the hemisphere in a separate ref tag.
+fn gps_location(tags: &HashMap<String, String>) -> Option<(f64, f64)> {
+	let coordinate = |name: &str, reference: &str, negative: &str| -> Option<f64> {
+		let parts = tags.get(name)?.split_whitespace().step_by(2).map(|n| n.parse::<f64>().ok()).collect::<Option<Vec<f64>>>()?;
+		let degrees = match parts.as_slice() {
+			[degrees, minutes, seconds] => degrees + minutes / 60.0 + seconds / 3600.0,
+			_ => return None,
+		};
+		let is_negative = tags.get(reference).is_some_and(|r| r.trim().eq_ignore_ascii_case(negative));
+		Some(if is_negative { -degrees } else { degrees })
+	};
+	let latitude = coordinate(GPS_TAGS[0], GPS_TAGS[1], "S").filter(|l| l.abs() <= 90.0)?;
+	let longitude = coordinate(GPS_TAGS[2], GPS_TAGS[3], "W").filter(|l| l.abs() <= 180.0)?;
+	Some((latitude, longitude))
+}
+
+/// Parse the value of near:latitude,longitude,radius_km into a WHERE clause.
+fn parse_near_filter(value: &str) -> Result<String> {
+	let numbers = value.split(',').map(|n| n.trim().parse::<f64>().ok().filter(|n| n.is_finite())).collect::<Option<Vec<f64>>>();
+	let (latitude, longitude, radius_km) = match numbers.as_deref() {
+		Some(&[latitude, longitude, radius_km]) => (latitude, longitude, radius_km),
+		_ => return Err(anyhow!("Unable to parse '{}': expected near:latitude,longitude,radius_km, like near:51.5,-0.12,10.", value)),
+	};
+	if latitude.abs() > 90.0 || longitude.abs() > 180.0 {
+		return Err(anyhow!("{},{} isn't a place.  Latitudes go from -90 to 90 and longitudes from -180 to 180.", latitude, longitude));
+	}
+	if radius_km <= 0.0 {
+		return Err(anyhow!("The radius in near: has to be more than zero."));
+	}
+	Ok(format!("haversine_distance(images.latitude, images.longitude, {}, {}) <= {}", latitude, longitude, radius_km))
+}
+
+/// Parse the value of a yes-or-no prefix like fav:true.
+fn parse_bool(value: &str) -> Result<bool> {
+	match value.trim().to_lowercase().as_str() {
+		"true" | "yes" | "y" | "1" => Ok(true),
+		"false" | "no" | "n" | "0" => Ok(false),
+		_ => Err(anyhow!("Unable to parse '{}': expected true or false.", value)),
+	}
+}
+
+/// Quote a CSV field if it has anything that would break the row apart.
+fn csv_field(value: &str) -> String {
+	if value.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", value.replace('"', "\"\""))
+	} else {
+		value.to_string()
+	}
+}
+
+/// Double up single quotes so a value can be inlined in a SQL string literal.
+fn escape_sql_string(value: &str) -> String {
+	value.replace('\'', "''")
+}
+
+/// Escape a value for use inside a LIKE pattern with ESCAPE '\', so % and _ in it match themselves.
+fn escape_like_pattern(value: &str) -> String {
+	escape_sql_string(&value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+}
+
+/// Image paths are stored canonicalized, so a folder needs to be, too, before matching paths against it.
+fn folder_prefix(folder: &str) -> String {
+	let mut prefix = Path::new(folder).canonicalize().map(|p| stringify_filepath(&p)).unwrap_or(folder.to_string());
+	if !prefix.ends_with(std::path::MAIN_SEPARATOR) {
+		prefix.push(std::path::MAIN_SEPARATOR);
+	}
+	prefix
+}
+
+/// Parse a hex color like "#ff8800", "ff8800", or "#f80".
+pub fn parse_hex_color(color: &str) -> Result<[u8; 3]> {
+	let hex = color.trim().trim_start_matches('#');
+	let expanded: String = match hex.len() {
+		3 => hex.chars().flat_map(|c| [c, c]).collect(),
+		6 => hex.to_string(),
+		_ => return Err(anyhow!("Unable to parse color '{}': expected hex like #ff8800.", color)),
+	};
+	let channel = |idx: usize| {
+		u8::from_str_radix(&expanded[2*idx..2*idx+2], 16).map_err(|_| anyhow!("Unable to parse color '{}': expected hex like #ff8800.", color))
+	};
+	Ok([channel(0)?, channel(1)?, channel(2)?])
+}
+
+/// Find the last sort: prefix in the query, if there is one.
+fn parse_sort_order_from_parsed_query(tokens: &Vec<String>) -> Result<Option<SortOrder>> {
+	let mut sort_order = None;
+	for token in tokens {
+		if let Some((magic_prefix, remaining)) = token.split_once(':') {
+			if magic_prefix.eq_ignore_ascii_case("sort") {
+				sort_order = Some(SortOrder::parse(remaining)?);
+			}
+		}
+	}
+	Ok(sort_order)
+}
+
+/// How much of the filename the query's plain words cover, from 0 to 1.  None if there are no plain words.
+/// Plain words already have to be in the filename to match, so shorter names that are more of a match score higher.
+fn text_relevance_sql(tokens: &Vec<String>) -> Option<String> {
+	let words: Vec<&String> = tokens.iter().filter(|t| !t.contains(':') && !t.starts_with('-')).collect();
+	if words.is_empty() {
+		return None;
+	}
+	let matched_length = words.iter().map(|w| format!("(CASE WHEN images.filename LIKE '%{}%' ESCAPE '\\' THEN LENGTH('{}') ELSE 0 END)", escape_like_pattern(w), escape_sql_string(w))).collect::<Vec<String>>().join(" + ");
+	Some(format!("MIN(1.0, ({}) * 1.0 / MAX(LENGTH(images.filename), 1))", matched_length))
+}
+
+fn parse_similarity_method_from_parsed_query(tokens: &Vec<String>) -> Result<Option<SimilarityMethod>> {
+	let mut method = None;
+	for token in tokens {
+		if let Some((magic_prefix, remaining)) = token.split_once(':') {
+			if magic_prefix.eq_ignore_ascii_case("method") {
+				method = Some(SimilarityMethod::parse(remaining)?);
+			}
+		}
+	}
+	Ok(method)
+}
+
+/// Parse a human-readable file size like "500", "10KB", or "1.5gb" into a number of bytes.
+/// Suffixes are powers of 1024 and case-insensitive.  The trailing 'B' is optional.
+fn parse_file_size(size: &str) -> Result<u64> {
+	let size = size.trim();
+	let split_idx = size.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(size.len());
+	let (number, unit) = size.split_at(split_idx);
+	let number: f64 = number.parse().map_err(|_| anyhow!("Unable to parse file size '{}': expected a number like 10MB.", size))?;
+	let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+		"" | "b" => 1,
+		"k" | "kb" => 1 << 10,
+		"m" | "mb" => 1 << 20,
+		"g" | "gb" => 1 << 30,
+		"t" | "tb" => 1 << 40,
+		_ => return Err(anyhow!("Unknown file size unit '{}' in '{}'.  Use B, KB, MB, GB, or TB.", unit, size)),
+	};
+	Ok((number * multiplier as f64) as u64)
+}
+
+//
+// Distance Functions
+// Distance functions should return near zero for almost identical items and a large value for different ones.
+// All of these methods should take the encoded hash as a blob of u8's and return a single f32.
+//
+pub fn cosine_distance(hash_a:&[u8], hash_b:&[u8]) -> f32 {
+	QueryEmbedding::new(hash_a).cosine_distance(hash_b)
+}
+
+/// One side of a cosine distance, dequantized with its magnitude worked out, so it can be compared to many hashes.
+struct QueryEmbedding {
+	values: Vec<f32>,
+	magnitude: f32,
+}
+
+impl QueryEmbedding {
+	fn new(hash: &[u8]) -> Self {
+		let values = dequantize_embedding(hash);
+		// Summed the same way cosine_distance sums the other side, so a to b is exactly the same as b to a.
+		let mut squares = [0f32; DISTANCE_LANES];
+		for chunk in values.chunks_exact(DISTANCE_LANES) {
+			for lane in 0..DISTANCE_LANES {
+				squares[lane] += chunk[lane] * chunk[lane];
+			}
+		}
+		let mut sum_of_squares: f32 = squares.iter().sum();
+		for value in &values[values.len() - values.len() % DISTANCE_LANES..] {
+			sum_of_squares += value * value;
+		}
+		QueryEmbedding { values, magnitude: sum_of_squares.sqrt() }
+	}
+
+	fn cosine_distance(&self, hash: &[u8]) -> f32 {
+		// Cosine Similarity -> 1.0 is most similar, -1.0 is most different.
+		// We want 0.0 is most similar.
+		let table: &[f32; 256] = &DEQUANTIZED_BYTES;
+		let shared = self.values.len().min(hash.len());
+		// Separate running sums for each lane let the compiler vectorize the loop.
+		let mut dots = [0f32; DISTANCE_LANES];
+		let mut squares = [0f32; DISTANCE_LANES];
+		for (values, bytes) in self.values[..shared].chunks_exact(DISTANCE_LANES).zip(hash[..shared].chunks_exact(DISTANCE_LANES)) {
+			for lane in 0..DISTANCE_LANES {
+				let other = table[bytes[lane] as usize];
+				dots[lane] += values[lane] * other;
+				squares[lane] += other * other;
+			}
+		}
+		let mut dot: f32 = dots.iter().sum();
+		let mut other_squared: f32 = squares.iter().sum();
+		let tail = shared - shared % DISTANCE_LANES;
+		for (value, &byte) in self.values[tail..shared].iter().zip(&hash[tail..shared]) {
+			dot += value * table[byte as usize];
+		}
+		// Only the shared part goes into the dot product, but all of the hash counts toward its magnitude.
+		for &byte in &hash[tail..] {
+			other_squared += table[byte as usize] * table[byte as usize];
+		}
+
+		let magnitude = self.magnitude * other_squared.sqrt();
+		if magnitude < 1e-6 {
+			return 0.0;
+		}
+		// Rounding can push identical vectors a hair over 1.0, which would make the distance negative.
+		let cosine_similarity = (dot / magnitude).clamp(1e-6, 1.0);
+		(1.0 / cosine_similarity) - 1.0
+	}
+}
+
+/// Hashes of different lengths came from different models and can't be compared, so they're as far apart as possible.
+pub fn byte_distance(hash_a:&[u8], hash_b:&[u8]) -> f32 {
+	if hash_a.len() != hash_b.len() {
+		return 1.0;
+	} else if hash_a.is_empty() {
+		return 0.0;
+	}
+	// Summing in integers is exact, and like cosine_distance, a sum per lane vectorizes.
+	let mut sums = [0u32; DISTANCE_LANES];
+	for (a, b) in hash_a.chunks_exact(DISTANCE_LANES).zip(hash_b.chunks_exact(DISTANCE_LANES)) {
+		for lane in 0..DISTANCE_LANES {
+			sums[lane] += a[lane].abs_diff(b[lane]) as u32;
+		}
+	}
+	let tail = hash_a.len() - hash_a.len() % DISTANCE_LANES;
+	let total = sums.iter().map(|&s| s as u64).sum::<u64>() + hash_a[tail..].iter().zip(&hash_b[tail..]).map(|(&a, &b)| a.abs_diff(b) as u64).sum::<u64>();
+	total as f32 / (255f32 * hash_a.len() as f32)
+}
+
+/// Like byte_distance, mismatched lengths are as far apart as possible.
+pub fn hamming_distance(hash_a:&[u8], hash_b:&[u8]) -> f32 {
+	if hash_a.len() != hash_b.len() {
+		return 1.0;
+	} else if hash_a.is_empty() {
+		return 0.0;
+	}
+	// Eight bytes at a time, so each popcount covers a whole word.
+	let words = hash_a.chunks_exact(8).zip(hash_b.chunks_exact(8)).map(|(a, b)| {
+		(u64::from_le_bytes(a.try_into().unwrap()) ^ u64::from_le_bytes(b.try_into().unwrap())).count_ones() as u64
+	}).sum::<u64>();
+	let tail = hash_a.len() - hash_a.len() % 8;
+	let bytes = hash_a[tail..].iter().zip(&hash_b[tail..]).map(|(&a, &b)| (a ^ b).count_ones() as u64).sum::<u64>();
+	(words + bytes) as f32 / (8f32 * hash_a.len() as f32)
+}
+
+/// The distance from a single RGB color to the closest color in a palette of RGB triples.
+/// Normalized so 0 is an exact match and 1 is black vs. white.
+pub fn palette_distance(color:&Vec<u8>, palette:&Vec<u8>) -> f32 {
+	if color.len() < 3 {
+		return 1.0;
+	}
+	palette.chunks_exact(3).map(|p| {
+		let squared: f32 = (0..3).map(|c| { (color[c] as f32 - p[c] as f32).powi(2) }).sum();
+		squared.sqrt() / (255f32 * 3f32.sqrt())
+	}).fold(1.0f32, f32::min)
+}
+
+/// Compare an RGBA sketch to an RGB color layout, cell by cell, ignoring sketch cells with zero alpha.
+/// Returns the mean absolute channel difference over the painted cells, from 0 (identical) to 1.
+pub fn layout_distance(sketch_rgba:&Vec<u8>, layout_rgb:&Vec<u8>) -> f32 {
+	if sketch_rgba.len() / 4 != layout_rgb.len() / 3 {
+		return 1.0;
+	}
+	let mut total = 0f32;
+	let mut painted_cells = 0;
+	for (sketch, layout) in sketch_rgba.chunks_exact(4).zip(layout_rgb.chunks_exact(3)) {
+		if sketch[3] == 0 {
+			continue;
+		}
+		total += (0..3).map(|c| { (sketch[c] as f32 - layout[c] as f32).abs() }).sum::<f32>();
+		painted_cells += 1;
+	}
+	if painted_cells == 0 {
+		return 0.0;
+	}
+	total / (255f32 * 3f32 * painted_cells as f32)
+}
+
+/// Great-circle distance in kilometers between two points given in degrees.
+pub fn haversine_distance(latitude_a: f64, longitude_a: f64, latitude_b: f64, longitude_b: f64) -> f64 {
+	let (phi_a, phi_b) = (latitude_a.to_radians(), latitude_b.to_radians());
+	let delta_phi = phi_b - phi_a;
+	let delta_lambda = (longitude_b - longitude_a).to_radians();
+	let a = (delta_phi / 2.0).sin().powi(2) + phi_a.cos() * phi_b.cos() * (delta_lambda / 2.0).sin().powi(2);
+	2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+// Add all the wrappers to the SQLite functions so we can use them in the database.
+
+fn make_cosine_distance_db_function(db: &mut Connection) -> SQLResult<()> {
+	db.create_scalar_function(
+		"cosine_distance",
+		2,
+		FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+		move |ctx| {
+			// The query is the same for every row, so SQLite keeps it parsed for the whole statement.
+			let query = ctx.get_or_create_aux(0, |v| -> Result<QueryEmbedding, BoxError> { Ok(QueryEmbedding::new(v.as_blob()?)) })?;
+			let rhs = ctx.get_raw(1).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
+			Ok(query.cosine_distance(rhs) as f64)
+		}
+	)
+}
+
+fn make_palette_distance_db_function(db: &mut Connection) -> SQLResult<()> {
+	db.create_scalar_function(
+		"palette_distance",
+		2,
+		FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+		move |ctx| {
+			let dist = {
+				let lhs = ctx.get_raw(0).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
+				let rhs = ctx.get_raw(1).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
+				palette_distance(&lhs.to_vec(), &rhs.to_vec())
+			};
+			Ok(dist as f64)
+		}
+	)
+}
+
+fn make_layout_distance_db_function(db: &mut Connection) -> SQLResult<()> {
+	db.create_scalar_function(
+		"layout_distance",
+		2,
+		FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+		move |ctx| {
+			let dist = {
+				let lhs = ctx.get_raw(0).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
+				let rhs = ctx.get_raw(1).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
+				layout_distance(&lhs.to_vec(), &rhs.to_vec())
+			};
+			Ok(dist as f64)
+		}
+	)
+}
+
+fn make_byte_distance_db_function(db: &mut Connection) -> SQLResult<()> {
+	db.create_scalar_function(
+		"byte_distance",
+		2,
+		FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+		move |ctx| {
+			let dist = {
+				let lhs = ctx.get_raw(0).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
+				let rhs = ctx.get_raw(1).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
+				byte_distance(lhs, rhs)
+			};
+			Ok(dist as f64)
+		}
+	)
+}
+
+fn make_haversine_distance_db_function(db: &mut Connection) -> SQLResult<()> {
+	db.create_scalar_function(
+		"haversine_distance",
+		4,
+		FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+		move |ctx| {
+			// Images without a location are NULL, so they're never near anything.
+			let coordinates = (0..4).map(|i| ctx.get::<Option<f64>>(i)).collect::<SQLResult<Option<Vec<f64>>>>()?;
+			Ok(coordinates.map(|c| haversine_distance(c[0], c[1], c[2], c[3])))
+		}
+	)
+}
+
+fn make_hamming_distance_db_function(db: &mut Connection) -> SQLResult<()> {
+	db.create_scalar_function(
+		"hamming_distance",
+		2,
+		FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+		move |ctx| {
+			assert_eq!(ctx.len(), 2, "Called with incorrect number of arguments");
+			// Like cosine_distance, the query is only copied out once per statement.
+			let query = ctx.get_or_create_aux(0, |v| -> Result<Vec<u8>, BoxError> { Ok(v.as_blob()?.to_vec()) })?;
+			let rhs = ctx.get_raw(1).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
+			Ok(hamming_distance(&query, rhs) as f64)
+		},
+	)
+}
+
+// End Distance Functions
+
+#[cfg(test)]
+mod tests {
+	use crate::engine::hamming_distance;
+	use crate::engine::cosine_distance;
+	use crate::image_hashes::dequantize_embedding;
+	use crate::engine::{tokenize_query, build_where_clause_from_parsed_query, text_relevance_sql, DEFAULT_MAX_COLOR_DISTANCE};
+	use crate::engine::{parse_file_size, tag_number};
+	use crate::engine::{gps_location, haversine_distance, GPS_TAGS};
+	use crate::engine::{palette_distance, parse_hex_color};
+	use crate::engine::layout_distance;
+	use crate::engine::{parse_sort_order_from_parsed_query, SortField, SortOrder};
+	use crate::engine::{parse_similarity_method_from_parsed_query, DistanceMetric, EmbeddingIndex, EmbeddingPooling, SimilarGroup, RankingWeights, SimilarityHash, SimilarityMethod};
+	use crate::engine::{Engine, ExportFormat, Rule, SavedSearch, TypeFilter, DEFAULT_NEAR_DUPLICATE_DISTANCE, MAX_RATING, SENSITIVE_TAG};
+	use crate::engine::{CompactionJob, OpenMode, BACKUP_SNAPSHOT_FILENAME};
+	use crate::engine::{count_stale_embeddings, embedding_drift, measure_embedding_drift, migrate, reembed_stale_hashes, QueryCancelHandle};
+	use crate::engine::{make_cosine_distance_db_function, make_hamming_distance_db_function};
+	use rusqlite::{params, Connection};
+	use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+	use crate::indexed_image::{decode_thumbnail, stringify_filepath};
+	use crate::backup;
+	use crate::engine::byte_distance;
+	use crate::indexed_image::IndexedImage;
+	use std::collections::HashMap;
+	use proptest::collection::vec;
+	use proptest::prelude::*;
+	use serde_json::Value as JSONValue;
+	use std::path::{Path, PathBuf};
+	use std::time::{Duration, Instant};
+
+	/// Make a fresh, empty database in the temp directory.  Each test should use a unique name.
+	fn make_test_engine(name: &str) -> (Engine, PathBuf) {
+		let db_path = std::env::temp_dir().join(format!("pixelbox_test_{}_{}.db", name, std::process::id()));
+		let _ = std::fs::remove_file(&db_path);
+		(Engine::open_or_create(&db_path, OpenMode::CreateNew).unwrap(), db_path)
+	}
+
+	fn make_test_image(filename: &str, file_size: u64) -> IndexedImage {
+		IndexedImage {
+			id: 0,
+			filename: filename.to_string(),
+			path: format!("/test/{}", filename),
+			resolution: (64, 64),
+			file_size,
+			protected: false,
+			rating: 0,
+			favorite: false,
+			thumbnail: vec![],
+			blurred_thumbnail: None,
+			created: Instant::now(),
+			indexed: Instant::now(),
+			tags: HashMap::new(),
+			phash: Some(vec![0u8; 32]),
+			visual_hash: Some(vec![128u8; 8]),
+			cropped_hash: None,
+			palette: Some(vec![0u8; 15]),
+			color_layout: Some(vec![0u8; 192]),
+			distance_from_query: None,
+		}
+	}
+
+	fn add_test_images(engine: &mut Engine, images: Vec<IndexedImage>) {
+		let mut conn = engine.connection.lock();
+		for img in images {
+			Engine::insert_image(&mut conn, img).unwrap();
+		}
+	}
+
+	fn wait_for_query(engine: &mut Engine) {
+		let started = Instant::now();
+		while engine.is_query_running() {
+			assert!(started.elapsed().as_secs() < 10, "Background query never finished.");
+			std::thread::sleep(std::time::Duration::from_millis(1));
+		}
+	}
+
+	#[test]
+	fn test_tokenize_query() {
+		let mut tokens;
+
+		tokens = tokenize_query(&"abc".to_string()).unwrap();
+		assert_eq!(tokens, vec!["abc".to_string()]);
+
+		tokens = tokenize_query(&"abc def".to_string()).unwrap();
+		assert_eq!(tokens, vec!["abc".to_string(), "def".to_string()]);
+
+		tokens = tokenize_query(&r#"abc "def ghi""#.to_string()).unwrap();
+		assert_eq!(tokens, vec!["abc".to_string(), "def ghi".to_string()]);
+
+		tokens = tokenize_query(&r#"abc \"def ghi\""#.to_string()).unwrap();
+		assert_eq!(tokens, vec!["abc".to_string(), "\"def".to_string(), "ghi\"".to_string()]);
+
+		tokens = tokenize_query(&r#""the human torch was denied a bank loan" "the \"human torch\"""#.to_string()).unwrap();
+		assert_eq!(tokens, vec!["the human torch was denied a bank loan".to_string(), "the \"human torch\"".to_string()]);
+	}
+
+	#[test]
+	fn test_tokenize_query_table() {
+		// (query, expected tokens or None for an error)
+		let cases: Vec<(&str, Option<Vec<&str>>)> = vec![
+			("", Some(vec![])),
+			("   ", Some(vec![])),
+			("  abc   def  ", Some(vec!["abc", "def"])),
+			// Quotes.
+			(r#""abc def""#, Some(vec!["abc def"])),
+			(r#""""#, Some(vec![""])), // An explicitly empty quote is kept.
+			(r#"ab"cd ef"gh"#, Some(vec!["abcd ef", "gh"])), // A quote closes the token, even mid-word.
+			(r#"tag:"Camera Model":"Canon EOS""#, Some(vec!["tag:Camera Model", ":Canon EOS"])),
+			(r#""unterminated"#, None),
+			(r#"abc ""#, None),
+			// Escapes.
+			(r#"a\ b"#, Some(vec!["a b"])),
+			(r#"a\\b"#, Some(vec![r#"a\b"#])),
+			(r#""say \"hi\"""#, Some(vec![r#"say "hi""#])),
+			(r#"\-cat"#, Some(vec!["filename:-cat"])), // An escaped minus is searched for rather than negating.
+			(r#"a\-b"#, Some(vec!["a-b"])),
+			(r#"abc\"#, None),
+			// Unicode.
+			("café 東京", Some(vec!["café", "東京"])),
+			(r#""日本 の 写真" 🐈"#, Some(vec!["日本 の 写真", "🐈"])),
+			("tag:Künstler:Dürer", Some(vec!["tag:Künstler:Dürer"])),
+			// Filters and negation.
+			("-cat type:png,jpg sort:size:desc", Some(vec!["-cat", "type:png,jpg", "sort:size:desc"])),
+			(r#"-"cat pictures""#, Some(vec!["-cat pictures"])),
+		];
+		for (query, expected) in cases {
+			let tokens = tokenize_query(&query.to_string());
+			match expected {
+				Some(expected) => assert_eq!(tokens.unwrap(), expected, "Query: {}", query),
+				None => assert!(tokens.is_err(), "Query should fail to tokenize: {}", query),
+			}
+		}
+	}
+
+	#[test]
+	fn test_where_clause_golden() {
+		let names_like = |term: &str| format!("images.filename LIKE '%{}%'", term);
+		let negated = |term: &str| format!("NOT (images.filename LIKE '%{0}%' OR images.path LIKE '%{0}%' OR EXISTS (SELECT 1 FROM tags AS negated_tags WHERE negated_tags.image_id = images.id AND (negated_tags.name LIKE '%{0}%' OR negated_tags.value LIKE '%{0}%')))", term);
+		let color = |hex: &str| format!("EXISTS (SELECT 1 FROM palettes WHERE palettes.image_id = images.id AND palette_distance(X'{}', palettes.hash) < {})", hex, DEFAULT_MAX_COLOR_DISTANCE);
+		let photo = TypeFilter::Photo.to_sql();
+		let gif = TypeFilter::Gif.to_sql();
+
+		// (query, expected WHERE clause)
+		let cases: Vec<(&str, String)> = vec![
+			("", "".to_string()),
+			("cat", names_like("cat")),
+			// Terms are AND'ed in the order they're written.
+			("cat dog", format!("{} AND {}", names_like("cat"), names_like("dog"))),
+			(r#""cat dog""#, names_like("cat dog")),
+			// Quotes in terms are doubled so they can't end the string literal.
+			("it's", names_like("it''s")),
+			(r#"a'b' OR 1=1 --"#, format!("{} AND {} AND {} AND {}", names_like("a''b''"), names_like("OR"), names_like("1=1"), negated("-"))),
+			("café", names_like("café")),
+			// Prefixes.
+			("filename:cat", names_like("cat")),
+			("FILENAME:Cat", names_like("Cat")),
+			("tag:Model:Canon", "(tags.name LIKE '%Model%' AND tags.value LIKE '%Canon%')".to_string()),
+			("exif:Canon", "(tags.name LIKE '%Canon%' OR tags.value LIKE '%Canon%')".to_string()),
+			("tag:o'brien", "(tags.name LIKE '%o''brien%' OR tags.value LIKE '%o''brien%')".to_string()),
+			// Numbers compare against the value parsed when the tag was stored.  ISO goes by its EXIF name.
+			("exif:FocalLength<35", "(tags.name = 'FocalLength' COLLATE NOCASE AND tags.number < 35)".to_string()),
+			("exif:iso>=1600", "(tags.name = 'PhotographicSensitivity' COLLATE NOCASE AND tags.number >= 1600)".to_string()),
+			("exif:ExposureTime<1/100", "(tags.name = 'ExposureTime' COLLATE NOCASE AND tags.number < 0.01)".to_string()),
+			("exif:Model=Canon", "(tags.name LIKE '%Model=Canon%' OR tags.value LIKE '%Model=Canon%')".to_string()),
+			("all:cat", " (tags.value LIKE '%cat%' OR images.filename LIKE '%cat%' OR images.path LIKE '%cat%') ".to_string()),
+			("minsize:1k maxsize:2k", "images.file_size >= 1024 AND images.file_size <= 2048".to_string()),
+			("rating:>=4", "images.rating >= 4".to_string()),
+			("rating:<2 rating:!=0", "images.rating < 2 AND images.rating != 0".to_string()),
+			("rating:5", "images.rating = 5".to_string()),
+			("near:51.5,-0.12,10", "haversine_distance(images.latitude, images.longitude, 51.5, -0.12) <= 10".to_string()),
+			("fav:true", "images.favorite = 1".to_string()),
+			(r#"collection:"it's mine""#, "EXISTS (SELECT 1 FROM collection_members INNER JOIN collections ON collections.id = collection_members.collection_id WHERE collection_members.image_id = images.id AND collections.name = 'it''s mine' COLLATE NOCASE)".to_string()),
+			("favorite:No", "images.favorite = 0".to_string()),
+			("color:#f80", color("ff8800")),
+			("colour:FF8800", color("ff8800")),
+			("sort:size:desc", "".to_string()),
+			("cat sort:size", names_like("cat")),
+			// Unknown prefixes are part of the name.
+			("12:30", names_like("12:30")),
+			// Negation applies to a single token and takes the rest literally.
+			("cat -dog", format!("{} AND {}", names_like("cat"), negated("dog"))),
+			("-tag:Canon", negated("tag:Canon")),
+			(r#"-"cat pictures""#, negated("cat pictures")),
+			("-it's", negated("it''s")),
+			("-", names_like("-")), // A lone minus isn't a negation.
+			(r#"\-cat"#, names_like("-cat")),
+			// Types are OR'ed together and added after everything else, whatever order they're written in.
+			("type:photo,GIFs", format!("({} OR {})", photo, gif)),
+			("type:photo cat type:gif", format!("{} AND ({} OR {})", names_like("cat"), photo, gif)),
+			("type:gif,,", format!("({})", gif)),
+			("-cat type:gif dog", format!("{} AND {} AND ({})", negated("cat"), names_like("dog"), gif)),
+		];
+		for (query, expected) in cases {
+			let tokens = tokenize_query(&query.to_string()).unwrap();
+			let where_clause = build_where_clause_from_parsed_query(&tokens, &HashMap::new(), &mut None).unwrap();
+			assert_eq!(where_clause, expected, "Query: {}", query);
+		}
+
+		for bad_query in ["type:png", "color:orange", "minsize:lots", "maxsize:", "rating:>=6", "rating:=>4", "rating:-1", "rating:", "fav:maybe", "near:51.5,-0.12", "near:91,0,1", "near:0,0,0", "near:home"] {
+			let tokens = tokenize_query(&bad_query.to_string()).unwrap();
+			assert!(build_where_clause_from_parsed_query(&tokens, &HashMap::new(), &mut None).is_err(), "Query should fail: {}", bad_query);
+		}
+
+		// The golden clauses should all be valid SQL, too.
+		let (mut engine, db_path) = make_test_engine("where_clause_golden");
+		add_test_images(&mut engine, vec![make_test_image("it's.png", 0)]);
+		for query in ["it's", "a'b' OR 1=1 --", "tag:o'brien", "-it's type:photo cat type:gif", "12:30 color:#f80 minsize:1k"] {
+			assert!(engine.query_page(&query.to_string(), 0, 10).is_ok(), "Query: {}", query);
+		}
+		assert_eq!(engine.query_page(&"it's".to_string(), 0, 10).unwrap().total_results, 1);
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_text_relevance_escapes_wildcards() {
+		let sql = text_relevance_sql(&vec!["50%_off".to_string(), "o'brien".to_string()]).unwrap();
+		assert!(sql.contains("LIKE '%50\\%\\_off%' ESCAPE '\\' THEN LENGTH('50%_off')"), "{}", sql);
+		assert!(sql.contains("LIKE '%o''brien%' ESCAPE '\\' THEN LENGTH('o''brien')"), "{}", sql);
+		assert_eq!(text_relevance_sql(&vec!["tag:cat".to_string(), "-dog".to_string()]), None);
+	}
+
+	#[test]
+	fn test_parse_file_size() {
+		assert_eq!(parse_file_size("123").unwrap(), 123);
+		assert_eq!(parse_file_size("123b").unwrap(), 123);
+		assert_eq!(parse_file_size("10KB").unwrap(), 10 * 1024);
+		assert_eq!(parse_file_size("10k").unwrap(), 10 * 1024);
+		assert_eq!(parse_file_size("1.5MB").unwrap(), 1536 * 1024);
+		assert_eq!(parse_file_size("2GB").unwrap(), 2 * 1024 * 1024 * 1024);
+		assert!(parse_file_size("MB").is_err());
+		assert!(parse_file_size("10 furlongs").is_err());
+	}
+
+	#[test]
+	fn test_tag_numbers() {
+		assert_eq!(tag_number("1600"), Some(1600.0));
+		assert_eq!(tag_number("f/2.8"), Some(2.8));
+		assert_eq!(tag_number("1/125"), Some(0.008));
+		assert_eq!(tag_number("35 mm"), Some(35.0));
+		assert_eq!(tag_number("35mm"), Some(35.0));
+		assert_eq!(tag_number("-0.3"), Some(-0.3));
+		for not_a_number in ["", "Canon", "1, 2", "2023:01:01 12:00:00", "1/0", "NaN", "35 mm wide"] {
+			assert_eq!(tag_number(not_a_number), None, "{}", not_a_number);
+		}
+
+		// Numbers compare as numbers, where text would put 800 after 1600.
+		let (mut engine, db_path) = make_test_engine("tag_numbers");
+		let mut photos = vec![];
+		for (filename, iso, exposure, focal_length) in [("dim.jpg", "3200", "1/30", "50 mm"), ("bright.jpg", "800", "1/1000", "24 mm")] {
+			let mut photo = make_test_image(filename, 0);
+			photo.tags.insert("PhotographicSensitivity".to_string(), iso.to_string());
+			photo.tags.insert("ExposureTime".to_string(), exposure.to_string());
+			photo.tags.insert("FocalLength".to_string(), focal_length.to_string());
+			photo.tags.insert("FocalLengthIn35mmFilm".to_string(), "28".to_string());
+			photos.push(photo);
+		}
+		add_test_images(&mut engine, photos);
+		let filenames = |engine: &mut Engine, query: &str| engine.query_page(&query.to_string(), 0, 10).unwrap().results.into_iter().map(|img| img.filename).collect::<Vec<String>>();
+		assert_eq!(filenames(&mut engine, "exif:ISO>1600"), vec!["dim.jpg"]);
+		assert_eq!(filenames(&mut engine, "exif:ExposureTime<=1/500"), vec!["bright.jpg"]);
+		assert!(filenames(&mut engine, "exif:ISO<100").is_empty());
+		// Only the tag with exactly that name is compared, in any case.
+		assert_eq!(filenames(&mut engine, "exif:focallength<30"), vec!["bright.jpg"]);
+
+		// Tags from before there was a number column get one when the DB is opened.
+		engine.connection.lock().execute_batch("UPDATE tags SET number = NULL; ALTER TABLE tags DROP COLUMN number;").unwrap();
+		drop(engine);
+		let mut engine = Engine::open_or_create(&db_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!(filenames(&mut engine, "exif:ISO>1600"), vec!["dim.jpg"]);
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_near() {
+		// London to Paris.
+		assert!((haversine_distance(51.5074, -0.1278, 48.8566, 2.3522) - 343.5).abs() < 1.0);
+		assert_eq!(haversine_distance(10.0, 20.0, 10.0, 20.0), 0.0);
+
+		let gps = |latitude: &str, latitude_ref: &str, longitude: &str, longitude_ref: &str| {
+			GPS_TAGS.iter().zip([latitude, latitude_ref, longitude, longitude_ref]).map(|(name, value)| (name.to_string(), value.to_string())).collect::<HashMap<String, String>>()
+		};
+		let (latitude, longitude) = gps_location(&gps("33 deg 51 min 21.6 sec", "S", "151 deg 12 min 36 sec", "E")).unwrap();
+		assert!((latitude + 33.856).abs() < 1e-6 && (longitude - 151.21).abs() < 1e-6);
+		assert_eq!(gps_location(&gps("33 deg 51 min 21.6 sec", "S", "", "E")), None);
+		assert_eq!(gps_location(&HashMap::new()), None);
+
+		let (mut engine, db_path) = make_test_engine("near");
+		let mut london = make_test_image("london.jpg", 0);
+		london.tags = gps("51 deg 30 min 26.4 sec", "N", "0 deg 7 min 40.1 sec", "W");
+		let mut paris = make_test_image("paris.jpg", 0);
+		paris.tags = gps("48 deg 51 min 24 sec", "N", "2 deg 21 min 7.6 sec", "E");
+		add_test_images(&mut engine, vec![london, paris, make_test_image("nowhere.jpg", 0)]);
+		let filenames = |engine: &mut Engine, query: &str| engine.query_page(&query.to_string(), 0, 10).unwrap().results.into_iter().map(|img| img.filename).collect::<Vec<String>>();
+		assert_eq!(filenames(&mut engine, "near:51.5,-0.12,10"), vec!["london.jpg"]);
+		assert_eq!(filenames(&mut engine, "near:50,1,400").len(), 2);
+
+		// Images indexed before there were location columns get them when the DB is opened.
+		engine.connection.lock().execute_batch("ALTER TABLE images DROP COLUMN latitude; ALTER TABLE images DROP COLUMN longitude;").unwrap();
+		drop(engine);
+		let mut engine = Engine::open_or_create(&db_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!(filenames(&mut engine, "near:48.85,2.35,5"), vec!["paris.jpg"]);
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_parse_sort_order() {
+		let parse = |q: &str| parse_sort_order_from_parsed_query(&tokenize_query(&q.to_string()).unwrap());
+		assert_eq!(parse("cats").unwrap(), None);
+		assert_eq!(parse("cats sort:size").unwrap(), Some(SortOrder { field: SortField::FileSize, descending: false }));
+		assert_eq!(parse("sort:Filename:DESC cats").unwrap(), Some(SortOrder { field: SortField::Filename, descending: true }));
+		assert_eq!(parse("sort:path sort:indexed:desc").unwrap(), Some(SortOrder { field: SortField::Indexed, descending: true }));
+		assert!(parse("sort:color").is_err());
+		assert!(parse("sort:size:sideways").is_err());
+	}
+
+	#[test]
+	fn test_parse_similarity_method() {
+		let parse = |q: &str| parse_similarity_method_from_parsed_query(&tokenize_query(&q.to_string()).unwrap());
+		assert_eq!(parse("similar:/a.png").unwrap(), None);
+		assert_eq!(parse("method:phash").unwrap(), Some(SimilarityMethod { hash: SimilarityHash::Perceptual, metric: DistanceMetric::Hamming }));
+		assert_eq!(parse("method:PHASH:byte").unwrap(), Some(SimilarityMethod { hash: SimilarityHash::Perceptual, metric: DistanceMetric::Byte }));
+		assert_eq!(parse("method:hamming").unwrap(), Some(SimilarityMethod { hash: SimilarityHash::Semantic, metric: DistanceMetric::Hamming }));
+		assert_eq!(parse("method:semantic").unwrap(), Some(SimilarityMethod::default()));
+		assert!(parse("method:phash:euclidean").is_err());
+	}
+
+	#[test]
+	fn test_similar_by_method() {
+		let (mut engine, db_path) = make_test_engine("similar_by_method");
+		// The phashes and embeddings disagree about which image is closest.
+		let mut near_phash = make_test_image("near_phash.png", 1);
+		near_phash.phash = Some(vec![0b0000_0001; 32]);
+		near_phash.visual_hash = Some(vec![0, 255, 0, 255, 0, 255, 0, 255]);
+		let mut near_embedding = make_test_image("near_embedding.png", 2);
+		near_embedding.phash = Some(vec![0b0111_1111; 32]);
+		near_embedding.visual_hash = Some(vec![255, 0, 255, 0, 255, 0, 255, 0]);
+		let mut no_phash = make_test_image("no_phash.png", 3);
+		no_phash.phash = None;
+		add_test_images(&mut engine, vec![near_phash, near_embedding, no_phash]);
+
+		// Stands in for the hashed query image, so nothing has to be loaded.
+		let mut query_image = make_test_image("query.png", 0);
+		query_image.path = "/query.png".to_string();
+		query_image.phash = Some(vec![0; 32]);
+		query_image.visual_hash = Some(vec![255, 0, 255, 0, 255, 0, 255, 0]);
+		let filenames = |engine: &mut Engine, query: &str| -> Vec<String> {
+			engine.cached_image_search = Some(query_image.clone());
+			engine.query_page(&query.to_string(), 0, 10).unwrap().results.into_iter().map(|img| img.filename).collect()
+		};
+
+		assert_eq!(filenames(&mut engine, "similar:/query.png sort:distance")[0], "near_embedding.png");
+		assert_eq!(filenames(&mut engine, "similar:/query.png method:phash sort:distance"), vec!["near_phash.png", "near_embedding.png"]);
+		assert_eq!(filenames(&mut engine, "similar:/query.png method:phash:byte sort:distance"), vec!["near_phash.png", "near_embedding.png"]);
+		assert!(engine.query_page(&"similar:/query.png method:nope".to_string(), 0, 10).is_err());
+
+		// Only the closest phashes go on to have their embeddings compared.  Other methods aren't narrowed down.
+		engine.prefilter_candidates = 1;
+		assert_eq!(filenames(&mut engine, "similar:/query.png sort:distance"), vec!["near_phash.png"]);
+		assert_eq!(filenames(&mut engine, "similar:/query.png method:phash sort:distance").len(), 2);
+		engine.prefilter_candidates = 2;
+		assert_eq!(filenames(&mut engine, "similar:/query.png sort:distance"), vec!["near_embedding.png", "near_phash.png"]);
+		// Filters narrow down the candidates too, rather than the candidates being picked and then filtered away.
+		engine.prefilter_candidates = 1;
+		assert_eq!(filenames(&mut engine, "similar:/query.png near_embedding sort:distance"), vec!["near_embedding.png"]);
+		let near_embedding_id = engine.query_page(&"near_embedding".to_string(), 0, 1).unwrap().results[0].id;
+		engine.set_user_tag(near_embedding_id, "kind", "cat").unwrap();
+		assert_eq!(filenames(&mut engine, "similar:/query.png tag:kind:cat sort:distance"), vec!["near_embedding.png"]);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_query_by_multiple_images() {
+		let (mut engine, db_path) = make_test_engine("query_by_multiple_images");
+		let mut red = make_test_image("red.png", 1);
+		red.visual_hash = Some(vec![255, 255, 128, 128, 128, 128, 128, 128]);
+		let mut round = make_test_image("round.png", 2);
+		round.visual_hash = Some(vec![128, 128, 255, 255, 128, 128, 128, 128]);
+		let mut red_and_round = make_test_image("red_and_round.png", 3);
+		red_and_round.visual_hash = Some(vec![255, 255, 255, 255, 128, 128, 128, 128]);
+		let mut neither = make_test_image("neither.png", 4);
+		neither.visual_hash = Some(vec![128, 128, 128, 128, 255, 255, 255, 255]);
+		add_test_images(&mut engine, vec![red, round, red_and_round, neither]);
+
+		// Results from some searches don't carry their embeddings, so those are looked up.
+		let mut examples = engine.query_page(&"r".to_string(), 0, 10).unwrap().results;
+		examples.retain(|img| img.filename == "red.png" || img.filename == "round.png");
+		examples.iter_mut().for_each(|img| img.visual_hash = None);
+		assert_eq!(examples.len(), 2);
+
+		for pooling in EmbeddingPooling::ALL {
+			engine.query_by_multiple_images(&examples, pooling).unwrap();
+			let results = engine.get_query_results().unwrap();
+			assert_eq!(results[0].filename, "red_and_round.png", "{:?}", pooling);
+			assert_eq!(results.last().unwrap().filename, "neither.png", "{:?}", pooling);
+		}
+		assert!(engine.query_by_multiple_images(&[], EmbeddingPooling::Mean).is_err());
+
+		assert_eq!(EmbeddingPooling::Mean.combine(&[vec![1.0, -1.0], vec![-1.0, 0.5]]).unwrap(), vec![0.0, -0.25]);
+		assert_eq!(EmbeddingPooling::Max.combine(&[vec![1.0, -1.0], vec![-1.0, 0.5]]).unwrap(), vec![1.0, 0.5]);
+		assert!(EmbeddingPooling::Max.combine(&[vec![1.0], vec![1.0, 0.0]]).is_err());
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_embedding_index() {
+		let (mut engine, db_path) = make_test_engine("embedding_index");
+		let mut same = make_test_image("same.png", 1);
+		same.visual_hash = Some(vec![255, 0, 255, 0, 255, 0, 255, 0]);
+		let mut close = make_test_image("close.png", 2);
+		close.visual_hash = Some(vec![255, 0, 255, 0, 255, 0, 200, 50]);
+		let mut far = make_test_image("far.png", 3);
+		far.visual_hash = Some(vec![0, 255, 0, 255, 0, 255, 0, 255]);
+		add_test_images(&mut engine, vec![same, close, far]);
+		let trashed_id = engine.query_page(&"far".to_string(), 0, 10).unwrap().results[0].id;
+		engine.connection.lock().execute("UPDATE images SET trashed = datetime('now') WHERE id = ?", [trashed_id]).unwrap();
+
+		let index = engine.load_embedding_index().unwrap();
+		assert_eq!(index.len(), 2);
+		let nearest = index.nearest(&vec![255, 0, 255, 0, 255, 0, 255, 0], 10);
+		let ids = engine.query_page(&"png sort:filename:desc".to_string(), 0, 10).unwrap().results.into_iter().map(|img| img.id).collect::<Vec<i64>>();
+		assert_eq!(nearest.iter().map(|n| n.0).collect::<Vec<i64>>(), ids); // "same" then "close".
+		assert_eq!(nearest[0].1, 0.0);
+		assert_eq!(index.nearest(&vec![0; 8], 1).len(), 1);
+
+		// Split into lists, a lookup only checks the lists nearest it.
+		let clusters: [[u8; 8]; 4] = [[255, 0, 255, 0, 255, 0, 255, 0], [0, 255, 0, 255, 0, 255, 0, 255], [255, 255, 0, 0, 255, 255, 0, 0], [0, 0, 255, 255, 0, 0, 255, 255]];
+		let entries: Vec<(i64, Vec<u8>)> = (0..400).map(|id| {
+			let mut hash = clusters[id % 4].to_vec();
+			hash[id % 8] = hash[id % 8].abs_diff((id / 4) as u8);
+			(id as i64, hash)
+		}).collect();
+		let split = EmbeddingIndex::with_lists(entries.clone(), 4);
+		assert_eq!((split.centroids.len(), split.lists.iter().map(Vec::len).sum::<usize>()), (4, 400));
+		assert!(split.lists.iter().all(|list| list.len() == 100));
+		let nearest = split.nearest(&clusters[2], 100);
+		assert!(nearest.iter().all(|(id, _)| id % 4 == 2));
+		// Checking every list finds the same as checking everything.
+		let exact = EmbeddingIndex::with_lists(entries, 1);
+		assert!(exact.centroids.is_empty());
+		assert_eq!(exact.nearest(&clusters[2], 5), split.nearest(&clusters[2], 5));
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_cancel_query() {
+		let (mut engine, db_path) = make_test_engine("cancel_query");
+		add_test_images(&mut engine, vec![make_test_image("cat.png", 0)]);
+
+		let cancel = QueryCancelHandle::new(engine.interrupt_handle.clone());
+		cancel.cancel();
+		assert!(cancel.run(&engine.connection.lock(), |_| Ok(())).is_err()); // Cancelled before it started.
+
+		// A query that would take ages is interrupted partway through.
+		let cancel = QueryCancelHandle::new(engine.interrupt_handle.clone());
+		let connection = engine.connection.clone();
+		let thread_cancel = cancel.clone();
+		let slow = std::thread::spawn(move || {
+			thread_cancel.run(&connection.lock(), |conn| {
+				Ok(conn.query_row("WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n LIMIT 1000000000) SELECT COUNT(*) FROM n", [], |row| row.get::<_, i64>(0))?)
+			})
+		});
+		std::thread::sleep(Duration::from_millis(100));
+		cancel.cancel();
+		assert!(slow.join().unwrap().is_err());
+		assert_eq!(engine.query_page(&"cat".to_string(), 0, 10).unwrap().results.len(), 1); // The connection still works.
+
+		engine.start_query_page(&"cat".to_string(), 0, 10).unwrap();
+		assert!(engine.get_query_cancel_handle().is_some());
+		engine.cancel_query();
+		assert!(!engine.is_query_running());
+		assert!(engine.get_query_cancel_handle().is_none());
+		assert_eq!(engine.get_query_results().unwrap().len(), 1); // The last results are kept.
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_embedding_models() {
+		let (mut engine, db_path) = make_test_engine("embedding_models");
+		add_test_images(&mut engine, vec![make_test_image("a.png", 1), make_test_image("b.png", 2), make_test_image("c.png", 3)]);
+		let ids: Vec<i64> = engine.query_page(&"png sort:filename".to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect();
+
+		let nomic = engine.register_embedding_model("nomic-embed-vision", "1.5", 3).unwrap();
+		let blip = engine.register_embedding_model("blip", "base", 2).unwrap();
+		assert_ne!(nomic, blip);
+		assert_eq!(engine.register_embedding_model("nomic-embed-vision", "1.5", 3).unwrap(), nomic);
+		assert!(engine.register_embedding_model(" ", "1", 3).is_err());
+
+		// Both models' embeddings are kept for the same images, and the built-in one is left alone.
+		engine.set_embedding(ids[0], nomic, &[1.0, 0.0, 0.0]).unwrap();
+		engine.set_embedding(ids[1], nomic, &[0.0, 1.0, 0.0]).unwrap();
+		engine.set_embedding(ids[0], blip, &[0.0, 1.0]).unwrap();
+		engine.set_embedding(ids[1], blip, &[1.0, 0.0]).unwrap();
+		assert!(engine.set_embedding(ids[2], blip, &[1.0, 0.0, 0.0]).is_err()); // Wrong size.
+		assert!(engine.set_embedding(ids[2], blip + nomic, &[1.0, 0.0]).is_err()); // No such model.
+		assert!(engine.set_embedding(-1, blip, &[1.0, 0.0]).is_err());
+		assert_eq!(engine.get_embedding(ids[0], blip).unwrap().map(|e| e[1]), Some(1.0));
+		assert_eq!(engine.get_embedding(ids[2], blip).unwrap(), None);
+		assert!(engine.connection.lock().query_row("SELECT hash FROM semantic_hashes WHERE image_id = ?", [ids[0]], |row| row.get::<_, Vec<u8>>(0)).is_ok());
+
+		let models = engine.get_embedding_models().unwrap();
+		assert_eq!(models.iter().map(|m| (m.name.as_str(), m.dimensions, m.num_embeddings)).collect::<Vec<_>>(), vec![("blip", 2, 2), ("nomic-embed-vision", 3, 2)]);
+
+		let nearest = |engine: &mut Engine, model_id: i64, embedding: &[f32]| -> Vec<String> {
+			engine.query_by_model_embedding(model_id, embedding).unwrap();
+			engine.get_query_results().unwrap().into_iter().map(|img| img.filename).collect()
+		};
+		engine.sort_order = SortOrder { field: SortField::Distance, descending: false };
+		assert_eq!(nearest(&mut engine, nomic, &[1.0, 0.0, 0.0]), vec!["a.png", "b.png"]);
+		assert_eq!(nearest(&mut engine, blip, &[1.0, 0.0]), vec!["b.png", "a.png"]);
+		assert!(engine.query_by_model_embedding(nomic, &[1.0, 0.0]).is_err());
+
+		// The result limit keeps the nearest images, even when the sort would put others first.
+		engine.set_embedding(ids[2], nomic, &[0.0, 1.0, 0.2]).unwrap();
+		engine.max_search_results = 2;
+		engine.sort_order = SortOrder { field: SortField::Filename, descending: false };
+		assert_eq!(nearest(&mut engine, nomic, &[0.0, 1.0, 0.0]), vec!["b.png", "c.png"]);
+
+		engine.remove_embedding_model(nomic).unwrap();
+		assert_eq!(engine.get_embedding_models().unwrap().len(), 1);
+		assert_eq!(engine.get_embedding(ids[0], nomic).unwrap(), None);
+		assert!(engine.get_embedding(ids[0], blip).unwrap().is_some());
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_find_similar_groups() {
+		let (mut engine, db_path) = make_test_engine("find_similar_groups");
+		let with_phash = |filename: &str, file_size: u64, phash: Vec<u8>| {
+			let mut img = make_test_image(filename, file_size);
+			img.phash = Some(phash);
+			img
+		};
+		// One differing bit in 256 between neighbors, so a, b, and c chain together but d is far off.
+		let mut b_hash = vec![0u8; 32];
+		b_hash[0] = 1;
+		let mut c_hash = b_hash.clone();
+		c_hash[1] = 1;
+		add_test_images(&mut engine, vec![
+			with_phash("a.png", 100, vec![0; 32]),
+			with_phash("b.png", 300, b_hash),
+			with_phash("c.png", 200, c_hash),
+			with_phash("d.png", 1000, vec![255; 32]),
+			with_phash("e.png", 50, vec![255; 32]),
+			with_phash("alone.png", 10, vec![0b1010_1010; 32]),
+		]);
+
+		let phash = SimilarityMethod { hash: SimilarityHash::Perceptual, metric: DistanceMetric::Hamming };
+		let groups = engine.find_similar_groups(1.0 / 256.0, phash).unwrap();
+		let filenames = |group: &SimilarGroup| group.images.iter().map(|img| img.filename.clone()).collect::<Vec<String>>();
+		assert_eq!(groups.len(), 2);
+		assert_eq!(filenames(&groups[0]), vec!["b.png", "c.png", "a.png"]); // 300 reclaimable beats 50.
+		assert_eq!((groups[0].total_bytes, groups[0].reclaimable_bytes()), (600, 300));
+		assert_eq!(filenames(&groups[1]), vec!["d.png", "e.png"]);
+
+		// Protected images are never counted as deletable.
+		engine.set_protected(groups[0].images[1].id, true).unwrap();
+		let groups = engine.find_similar_groups(1.0 / 256.0, phash).unwrap();
+		assert_eq!(groups[0].deletable().iter().map(|img| img.filename.as_str()).collect::<Vec<&str>>(), vec!["a.png"]);
+		assert_eq!((groups[0].total_bytes, groups[0].reclaimable_bytes()), (600, 100));
+
+		assert!(engine.find_similar_groups(0.0, phash).unwrap().iter().all(|g| filenames(g) == vec!["d.png", "e.png"]));
+		assert_eq!(engine.find_similar_groups(1.0, phash).unwrap()[0].images.len(), 6);
+
+		engine.near_duplicate_distance = 1.0 / 256.0;
+		engine.start_duplicate_scan().unwrap();
+		assert!(engine.start_duplicate_scan().is_err());
+		let scanned = loop {
+			match engine.poll_duplicate_scan() {
+				Some(result) => break result.unwrap(),
+				None => std::thread::sleep(std::time::Duration::from_millis(10)),
+			}
+		};
+		assert_eq!(scanned.iter().map(filenames).collect::<Vec<_>>(), groups.iter().map(filenames).collect::<Vec<_>>());
+		assert!(!engine.is_scanning_for_duplicates());
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+
+		// A watermarked copy's full frame is far off, but without its edges it matches.  Images without a cropped hash are left out.
+		let (mut engine, db_path) = make_test_engine("find_similar_groups_cropped");
+		let with_hashes = |filename: &str, visual_hash: Vec<u8>, cropped_hash: Option<Vec<u8>>| {
+			let mut img = make_test_image(filename, 0);
+			img.visual_hash = Some(visual_hash);
+			img.cropped_hash = cropped_hash;
+			img
+		};
+		add_test_images(&mut engine, vec![
+			with_hashes("original.png", vec![255, 0, 255, 0, 255, 0, 255, 0], Some(vec![0, 255, 0, 255, 0, 255, 0, 255])),
+			with_hashes("watermarked.png", vec![0, 0, 0, 0, 255, 255, 255, 255], Some(vec![0, 255, 0, 255, 0, 255, 0, 250])),
+			with_hashes("uncropped.png", vec![255, 0, 255, 0, 255, 0, 255, 0], None),
+		]);
+		let semantic = SimilarityMethod::default();
+		let cropped = SimilarityMethod { hash: SimilarityHash::Cropped, metric: DistanceMetric::Cosine };
+		assert_eq!(engine.find_similar_groups(0.01, semantic).unwrap().iter().map(filenames).collect::<Vec<_>>(), vec![vec!["original.png", "uncropped.png"]]);
+		assert_eq!(engine.find_similar_groups(0.01, cropped).unwrap().iter().map(filenames).collect::<Vec<_>>(), vec![vec!["original.png", "watermarked.png"]]);
+		assert_eq!(SimilarityMethod::parse("crop").unwrap(), cropped);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_canonical_duplicates() {
+		let (mut engine, db_path) = make_test_engine("canonical_duplicates");
+		let mut add = |filename: &str, phash: Vec<u8>| {
+			let mut img = make_test_image(filename, 0);
+			img.phash = Some(phash);
+			engine.add_image(img).unwrap()
+		};
+		let a = add("a.png", vec![0; 32]);
+		let b = add("b.png", vec![0; 32]);
+		let c = add("c.png", vec![0; 32]);
+		add("d.png", vec![255; 32]);
+
+		let ids = |images: Vec<IndexedImage>| images.into_iter().map(|img| img.id).collect::<Vec<i64>>();
+		let near = ids(engine.find_near_duplicates_of(a).unwrap());
+		assert_eq!(near, vec![b, c]);
+		let searched = |engine: &mut Engine| {
+			let mut names: Vec<String> = engine.query_page(&"png".to_string(), 0, 100).unwrap().results.into_iter().map(|img| img.filename).collect();
+			names.sort();
+			names
+		};
+
+		engine.set_canonical_image(a, &near).unwrap();
+		assert_eq!(searched(&mut engine), vec!["a.png", "d.png"]);
+		assert_eq!(engine.get_canonical_image(b).unwrap().map(|img| img.id), Some(a));
+		assert_eq!(ids(engine.get_duplicates(a).unwrap()), vec![b, c]);
+		engine.show_duplicates = true;
+		assert_eq!(searched(&mut engine), vec!["a.png", "b.png", "c.png", "d.png"]);
+		engine.show_duplicates = false;
+
+		// Changing which image is canonical takes the whole group along.
+		engine.set_canonical_image(b, &[a]).unwrap();
+		assert_eq!(searched(&mut engine), vec!["b.png", "d.png"]);
+		assert_eq!(ids(engine.get_duplicates(b).unwrap()), vec![a, c]);
+		assert!(engine.get_canonical_image(b).unwrap().is_none());
+
+		engine.unmark_duplicate(c).unwrap();
+		assert_eq!(searched(&mut engine), vec!["b.png", "c.png", "d.png"]);
+		engine.unmark_duplicate(b).unwrap();
+		assert_eq!(searched(&mut engine).len(), 4);
+
+		// Hiding near-duplicates never hides a protected image.
+		engine.set_protected(c, true).unwrap();
+		assert_eq!(engine.hide_near_duplicates_of(a).unwrap(), 1);
+		assert_eq!(ids(engine.get_duplicates(a).unwrap()), vec![b]);
+		assert_eq!(searched(&mut engine), vec!["a.png", "c.png", "d.png"]);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_compare_similarity_methods() {
+		let (mut engine, db_path) = make_test_engine("compare_similarity_methods");
+		add_test_images(&mut engine, vec![make_test_image("query.png", 0), make_test_image("x.png", 1), make_test_image("y.png", 2), make_test_image("z.png", 3)]);
+		let ids: Vec<i64> = engine.query_page(&"png sort:size".to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect();
+		let nomic = engine.register_embedding_model("nomic-embed-vision", "1.5", 3).unwrap();
+		let blip = engine.register_embedding_model("blip", "base", 2).unwrap();
+		assert_eq!(engine.register_embedding_model("blip", "base", 2).unwrap(), blip);
+		assert!(engine.register_embedding_model(" ", "1", 3).is_err());
+		engine.set_embedding(ids[0], nomic, &[1.0, 0.0, 0.0]).unwrap();
+		engine.set_embedding(ids[1], nomic, &[0.9, 0.1, 0.0]).unwrap();
+		engine.set_embedding(ids[2], nomic, &[0.5, 0.5, 0.0]).unwrap();
+		engine.set_embedding(ids[0], blip, &[1.0, 0.0]).unwrap();
+		engine.set_embedding(ids[1], blip, &[0.0, 1.0]).unwrap();
+		engine.set_embedding(ids[2], blip, &[0.9, 0.1]).unwrap();
+		engine.set_embedding(ids[3], blip, &[0.7, 0.3]).unwrap();
+		assert!(engine.set_embedding(ids[3], blip, &[1.0, 0.0, 0.0]).is_err()); // Wrong size.
+		assert_eq!(engine.get_embedding(ids[1], blip).unwrap().map(|e| e[1]), Some(1.0));
+		assert_eq!(engine.get_embedding(ids[3], nomic).unwrap(), None);
+		assert_eq!(engine.get_embedding_models().unwrap().iter().map(|m| (m.name.as_str(), m.num_embeddings)).collect::<Vec<_>>(), vec![("blip", 4), ("nomic-embed-vision", 3)]);
+
+		let comparison = engine.compare_similarity_methods(ids[0], nomic, blip, 20).unwrap();
+		let filenames = |images: &Vec<IndexedImage>| images.iter().map(|img| img.filename.clone()).collect::<Vec<String>>();
+		assert_eq!(filenames(&comparison.a), vec!["x.png", "y.png"]);
+		assert_eq!(filenames(&comparison.b), vec!["y.png", "z.png", "x.png"]);
+		assert_eq!(comparison.shared, 2);
+		assert!((comparison.jaccard - 2.0 / 3.0).abs() < 1e-9);
+		assert_eq!(comparison.rank_correlation, Some(-1.0)); // The two shared images swap places.
+
+		let same = engine.compare_similarity_methods(ids[0], blip, blip, 1).unwrap();
+		assert_eq!((same.shared, same.jaccard, same.rank_correlation), (1, 1.0, None));
+		assert!(engine.compare_similarity_methods(ids[3], nomic, blip, 20).is_err()); // No embedding from the first model.
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_hybrid_ranking() {
+		let (mut engine, db_path) = make_test_engine("hybrid_ranking");
+		let mut looks_closer = make_test_image("cat_sitting_in_a_cardboard_box.png", 1);
+		looks_closer.visual_hash = Some(vec![255, 0, 255, 0, 255, 0, 255, 0]);
+		let mut named_closer = make_test_image("cat.png", 2);
+		named_closer.visual_hash = Some(vec![255, 0, 255, 0, 255, 0, 200, 50]);
+		add_test_images(&mut engine, vec![looks_closer, named_closer, make_test_image("dog.png", 3)]);
+
+		let mut query_image = make_test_image("query.png", 0);
+		query_image.path = "/query.png".to_string();
+		query_image.visual_hash = Some(vec![255, 0, 255, 0, 255, 0, 255, 0]);
+		let filenames = |engine: &mut Engine, query: &str| -> Vec<String> {
+			engine.cached_image_search = Some(query_image.clone());
+			engine.query_page(&query.to_string(), 0, 10).unwrap().results.into_iter().map(|img| img.filename).collect()
+		};
+
+		engine.ranking_weights = RankingWeights { visual: 1.0, text: 0.0 };
+		assert_eq!(filenames(&mut engine, "cat similar:/query.png sort:distance"), vec!["cat_sitting_in_a_cardboard_box.png", "cat.png"]);
+		engine.ranking_weights = RankingWeights { visual: 0.5, text: 0.5 };
+		assert_eq!(filenames(&mut engine, "cat similar:/query.png sort:distance"), vec!["cat.png", "cat_sitting_in_a_cardboard_box.png"]);
+		// Without words there's nothing to weigh against.
+		assert_eq!(filenames(&mut engine, "similar:/query.png sort:distance")[0], "cat_sitting_in_a_cardboard_box.png");
+
+		engine.save_settings().unwrap();
+		drop(engine);
+		let reopened = Engine::open_or_create(&db_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!(reopened.ranking_weights, RankingWeights { visual: 0.5, text: 0.5 });
+
+		drop(reopened);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_query_page() {
+		let (mut engine, db_path) = make_test_engine("query_page");
+		add_test_images(&mut engine, (0..5).map(|i| make_test_image(&format!("img_{}.png", i), i)).collect());
+
+		let page = engine.query_page(&"img sort:size".to_string(), 1, 2).unwrap();
+		assert_eq!(page.total_results, 5);
+		assert_eq!(page.num_pages(), 3);
+		assert_eq!(page.results.iter().map(|img| img.file_size).collect::<Vec<u64>>(), vec![2, 3]);
+		assert_eq!(engine.get_query_result_count(), Some(5));
+
+		let last_page = engine.query_page(&"img sort:size".to_string(), 2, 2).unwrap();
+		assert_eq!(last_page.results.len(), 1);
+
+		let empty = engine.query_page(&"nothing_matches".to_string(), 0, 2).unwrap();
+		assert_eq!(empty.total_results, 0);
+		assert_eq!(empty.num_pages(), 1);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_tag_values_unquoted() {
+		let (mut engine, db_path) = make_test_engine("tag_values_unquoted");
+		let mut tagged = make_test_image("tagged.png", 1);
+		tagged.tags.insert("Model".to_string(), "Canon".to_string());
+		add_test_images(&mut engine, vec![tagged]);
+		let page = engine.query_page(&"tagged".to_string(), 0, 10).unwrap();
+		assert_eq!(page.results[0].tags.get("Model").map(String::as_str), Some("Canon"));
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_type_filter() {
+		let (mut engine, db_path) = make_test_engine("type_filter");
+		let mut photo = make_test_image("holiday.png", 0);
+		photo.tags.insert("Make".to_string(), "Canon".to_string());
+		let mut comic_page = make_test_image("page2.png", 0);
+		comic_page.path = "/test/Bone.cbz!/page2.png".to_string();
+		add_test_images(&mut engine, vec![
+			make_test_image("cat.gif", 0),
+			make_test_image("dog.JPG", 0),
+			make_test_image("Screenshot 2024-01-01.png", 0),
+			make_test_image("logo.svg", 0),
+			make_test_image("plain.png", 0),
+			make_test_image("rock_albedo.KTX2", 0),
+			make_test_image("poster.psd", 0),
+			make_test_image("Brushy Script.OTF", 0),
+			make_test_image("teapot.glb", 0),
+			make_test_image("Blue Monday.flac", 0),
+			make_test_image("Dune.EPUB", 0),
+			make_test_image("beach.MKV", 0),
+			comic_page,
+			photo,
+		]);
+
+		let matching = |engine: &mut Engine, q: &str| {
+			let mut names: Vec<String> = engine.query_page(&q.to_string(), 0, 100).unwrap().results.into_iter().map(|img| img.filename).collect();
+			names.sort();
+			names
+		};
+		assert_eq!(matching(&mut engine, "type:gif"), vec!["cat.gif"]);
+		assert_eq!(matching(&mut engine, "type:photo"), vec!["dog.JPG", "holiday.png"]);
+		assert_eq!(matching(&mut engine, "type:screenshots"), vec!["Screenshot 2024-01-01.png"]);
+		assert_eq!(matching(&mut engine, "type:gif,vector"), vec!["cat.gif", "logo.svg"]);
+		assert_eq!(matching(&mut engine, "type:texture"), vec!["rock_albedo.KTX2"]);
+		assert_eq!(matching(&mut engine, "type:design"), vec!["poster.psd"]);
+		assert_eq!(matching(&mut engine, "type:fonts"), vec!["Brushy Script.OTF"]);
+		assert_eq!(matching(&mut engine, "type:model"), vec!["teapot.glb"]);
+		assert_eq!(matching(&mut engine, "type:audio"), vec!["Blue Monday.flac"]);
+		assert_eq!(matching(&mut engine, "type:book"), vec!["Dune.EPUB", "page2.png"]);
+		assert_eq!(matching(&mut engine, "type:videos"), vec!["beach.MKV"]);
+		assert_eq!(matching(&mut engine, "type:gif type:vector"), vec!["cat.gif", "logo.svg"]);
+		assert!(engine.query_page(&"type:spreadsheet".to_string(), 0, 100).is_err());
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_parse_hex_color() {
+		assert_eq!(parse_hex_color("#ff8800").unwrap(), [255, 136, 0]);
+		assert_eq!(parse_hex_color("FF8800").unwrap(), [255, 136, 0]);
+		assert_eq!(parse_hex_color("#f80").unwrap(), [255, 136, 0]);
+		assert!(parse_hex_color("#ff88").is_err());
+		assert!(parse_hex_color("#gg8800").is_err());
+	}
+
+	#[test]
+	fn test_palette_distance() {
+		let palette = vec![255u8, 0, 0, 0, 0, 255];
+		assert_eq!(palette_distance(&vec![255, 0, 0], &palette), 0.0);
+		assert_eq!(palette_distance(&vec![0, 0, 255], &palette), 0.0);
+		assert!(palette_distance(&vec![0, 255, 0], &palette) > 0.5);
+		assert!((palette_distance(&vec![0, 0, 0], &vec![255, 255, 255]) - 1.0).abs() < 1e-6);
+	}
+
+	#[test]
+	fn test_query_by_color() {
+		let (mut engine, db_path) = make_test_engine("query_by_color");
+		let mut red = make_test_image("red.png", 0);
+		red.palette = Some([[250u8, 10, 10]; 5].concat());
+		let mut blue = make_test_image("blue.png", 0);
+		blue.palette = Some([[10u8, 10, 250]; 5].concat());
+		add_test_images(&mut engine, vec![red, blue]);
+
+		engine.query_by_color([255, 0, 0]).unwrap();
+		let results = engine.get_query_results().unwrap();
+		assert_eq!(results.len(), 1);
+		assert_eq!(results[0].filename, "red.png");
+
+		let page = engine.query_page(&"color:#00f".to_string(), 0, 10).unwrap();
+		assert_eq!(page.results.len(), 1);
+		assert_eq!(page.results[0].filename, "blue.png");
+
+		let common = engine.get_common_palette_colors(10).unwrap();
+		assert_eq!(common.len(), 2);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_max_search_results() {
+		let (mut engine, db_path) = make_test_engine("max_search_results");
+		add_test_images(&mut engine, (0..5).map(|i| make_test_image(&format!("img_{}.png", i), i)).collect());
+		engine.max_search_results = 3;
+
+		let first = engine.query_page(&"img".to_string(), 0, 2).unwrap();
+		assert_eq!(first.total_results, 3);
+		assert_eq!(first.results.len(), 2);
+		let second = engine.query_page(&"img".to_string(), 1, 2).unwrap();
+		assert_eq!(second.results.len(), 1);
+
+		engine.query(&"img".to_string()).unwrap();
+		wait_for_query(&mut engine);
+		assert_eq!(engine.get_query_results().unwrap().len(), 3);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_settings_persist() {
+		let (mut engine, db_path) = make_test_engine("settings_persist");
+		engine.max_search_results = 42;
+		engine.max_distance_from_query = 0.25;
+		engine.sort_order = SortOrder { field: SortField::FileSize, descending: true };
+		engine.trash_retention_days = 7;
+		engine.index_newest_first = true;
+		engine.max_archive_depth = 0;
+		engine.hash_animation_frames = true;
+		engine.skip_hidden_files = false;
+		engine.min_file_size_kb = 10;
+		engine.max_file_size_mb = 500;
+		engine.max_files_per_second = 5;
+		engine.low_priority_indexing = true;
+		engine.crawler_threads = 2;
+		engine.embedding_threads = 1;
+		engine.network_timeout_seconds = 5;
+		engine.network_retries = 0;
+		engine.watch_folders = true;
+		engine.reembed_on_open = true;
+		engine.save_settings().unwrap();
+		drop(engine);
+
+		let reopened = Engine::open_or_create(&db_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!(reopened.max_search_results, 42);
+		assert_eq!(reopened.max_distance_from_query, 0.25);
+		assert_eq!(reopened.sort_order, SortOrder { field: SortField::FileSize, descending: true });
+		assert_eq!(reopened.trash_retention_days, 7);
+		assert!(reopened.index_newest_first);
+		assert_eq!(reopened.max_archive_depth, 0);
+		assert!(reopened.hash_animation_frames);
+		assert!(!reopened.skip_hidden_files);
+		assert_eq!((reopened.min_file_size_kb, reopened.max_file_size_mb), (10, 500));
+		assert_eq!(reopened.max_files_per_second, 5);
+		assert!(reopened.low_priority_indexing);
+		assert_eq!((reopened.crawler_threads, reopened.embedding_threads), (2, 1));
+		assert_eq!((reopened.network_timeout_seconds, reopened.network_retries), (5, 0));
+		assert!(reopened.watch_folders);
+		assert!(reopened.reembed_on_open);
+
+		drop(reopened);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_ratings() {
+		let (mut engine, db_path) = make_test_engine("ratings");
+		add_test_images(&mut engine, (0..4).map(|i| make_test_image(&format!("img_{}.png", i), i)).collect());
+		let ids: Vec<i64> = engine.query_page(&"img sort:size".to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect();
+		assert!(engine.get_query_results().unwrap().iter().all(|img| img.rating == 0));
+
+		engine.set_rating(ids[1], 4).unwrap();
+		engine.set_rating(ids[2], 5).unwrap();
+		engine.set_rating(ids[3], 2).unwrap();
+		assert!(engine.set_rating(ids[0], MAX_RATING + 1).is_err());
+		// The cached results are updated in place.
+		assert_eq!(engine.get_query_results().unwrap().iter().map(|img| img.rating).collect::<Vec<u8>>(), vec![0, 4, 5, 2]);
+
+		let rated = |engine: &mut Engine, q: &str| -> Vec<i64> {
+			engine.query_page(&q.to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect()
+		};
+		assert_eq!(rated(&mut engine, "img rating:>=4 sort:size"), vec![ids[1], ids[2]]);
+		assert_eq!(rated(&mut engine, "img rating:0"), vec![ids[0]]);
+		assert_eq!(rated(&mut engine, "img sort:rating:desc"), vec![ids[2], ids[1], ids[3], ids[0]]);
+
+		// Clearing a rating puts it back to unrated.
+		engine.set_rating(ids[2], 0).unwrap();
+		assert_eq!(rated(&mut engine, "img rating:>0 sort:size"), vec![ids[1], ids[3]]);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_favorites() {
+		let (mut engine, db_path) = make_test_engine("favorites");
+		add_test_images(&mut engine, (0..3).map(|i| make_test_image(&format!("img_{}.png", i), i)).collect());
+		let ids: Vec<i64> = engine.query_page(&"img sort:size".to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect();
+
+		engine.set_favorite(ids[0], true).unwrap();
+		engine.set_favorite(ids[2], true).unwrap();
+		assert_eq!(engine.get_query_results().unwrap().iter().map(|img| img.favorite).collect::<Vec<bool>>(), vec![true, false, true]);
+		engine.set_favorite(ids[2], false).unwrap();
+
+		let found = |engine: &mut Engine, q: &str| -> Vec<i64> {
+			engine.query_page(&q.to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect()
+		};
+		assert_eq!(found(&mut engine, "img fav:true"), vec![ids[0]]);
+		assert_eq!(found(&mut engine, "img fav:false sort:size"), vec![ids[1], ids[2]]);
+		assert_eq!(found(&mut engine, "img fav:yes"), vec![ids[0]]);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_screenshot_sessions() {
+		let (mut engine, db_path) = make_test_engine("screenshot_sessions");
+		add_test_images(&mut engine, vec![
+			make_test_image("Figma 3_14_2024 10_00_00 AM.png", 0),
+			make_test_image("README.md - Visual Studio Code 3_14_2024 10_05_00 AM.png", 1),
+			make_test_image("Figma 3_14_2024 10_30_00 AM.png", 2), // Too long after the first to be in its session...
+			make_test_image("Figma 3_14_2024 10_15_00 AM.png", 3), // ...until this one bridges the gap.
+			make_test_image("Figma 3_14_2024 4_00_00 PM.png", 4),
+			make_test_image("figma_export.png", 5),
+		]);
+		let found = |engine: &mut Engine, q: &str| -> Vec<String> {
+			engine.query_page(&q.to_string(), 0, 10).unwrap().results.iter().map(|img| img.filename.clone()).collect()
+		};
+		assert_eq!(found(&mut engine, "window:figma sort:size").len(), 4);
+		assert_eq!(found(&mut engine, "window:\"Visual Studio\""), vec!["README.md - Visual Studio Code 3_14_2024 10_05_00 AM.png"]);
+
+		let first = engine.query_page(&"window:figma sort:size".to_string(), 0, 1).unwrap().results[0].id;
+		let info = engine.get_screenshot_info(first).unwrap().unwrap();
+		assert_eq!(info.source.app, "Figma");
+		assert_eq!(info.source.captured.as_deref(), Some("2024-03-14 10:00:00"));
+		assert_eq!(info.session, Some(first));
+		assert_eq!(info.session_size, 3);
+		assert_eq!(found(&mut engine, &format!("session:{} sort:size", first)), vec!["Figma 3_14_2024 10_00_00 AM.png", "Figma 3_14_2024 10_30_00 AM.png", "Figma 3_14_2024 10_15_00 AM.png"]);
+		let last = engine.query_page(&"sort:size".to_string(), 4, 1).unwrap().results[0].id;
+		assert_eq!(engine.get_screenshot_info(last).unwrap().unwrap().session_size, 1);
+		assert!(engine.query_page(&"session:figma".to_string(), 0, 10).is_err());
+
+		// Titles read off title bars count too.  % and _ in a search are matched as they are, not as wildcards.
+		let mut read_title = make_test_image("Screenshot 7.png", 6);
+		read_title.tags.insert(crate::screenshot::WINDOW_TITLE_TAG.to_string(), "build_log 100% — Terminal".to_string());
+		add_test_images(&mut engine, vec![read_title]);
+		assert_eq!(found(&mut engine, "window:terminal"), vec!["Screenshot 7.png"]);
+		assert_eq!(found(&mut engine, "window:%"), vec!["Screenshot 7.png"]);
+		assert!(found(&mut engine, "window:o_C").is_empty());
+
+		// Older DBs are filled in when they're migrated.
+		{
+			let mut conn = engine.connection.lock();
+			conn.execute("DROP TABLE screenshot_sources", []).unwrap();
+			migrate(&mut conn).unwrap();
+		}
+		assert_eq!(engine.get_screenshot_info(first).unwrap().unwrap().session_size, 3);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_sensitive_blur() {
+		let (mut engine, db_path) = make_test_engine("sensitive_blur");
+		let checkerboard: Vec<u8> = (0..16 * 16).flat_map(|i| if (i % 16 + i / 16) % 2 == 0 { [255u8; 3] } else { [0u8; 3] }).collect();
+		let mut photo = make_test_image("photo.png", 0);
+		photo.thumbnail = qoi::encode_to_vec(&checkerboard, 16, 16).unwrap();
+		let mut flagged = make_test_image("flagged.png", 1);
+		flagged.tags.insert("Sensitive".to_string(), "".to_string()); // Tags from indexing count too.
+		add_test_images(&mut engine, vec![photo, flagged]);
+
+		let results = engine.query_page(&"png sort:size".to_string(), 0, 10).unwrap().results;
+		let photo_id = results[0].id;
+		assert!(results[0].blurred_thumbnail.is_none());
+		assert!(results[1].blurred_thumbnail.is_some());
+
+		// Reindexing a changed file blurs its new thumbnail rather than keeping the old blur.
+		let mut changed = make_test_image("flagged.png", 2);
+		changed.thumbnail = qoi::encode_to_vec(&checkerboard, 16, 16).unwrap();
+		changed.tags.insert("Sensitive".to_string(), "".to_string());
+		add_test_images(&mut engine, vec![changed]);
+		let reblurred = engine.query_page(&"flagged".to_string(), 0, 10).unwrap().results[0].blurred_thumbnail.clone().unwrap();
+		assert_ne!(Some(&reblurred), results[1].blurred_thumbnail.as_ref());
+		assert_eq!(decode_thumbnail(&reblurred).unwrap().1, (16, 16));
+
+		engine.set_user_tag(photo_id, SENSITIVE_TAG, "").unwrap();
+		let blurred = engine.get_query_results().unwrap()[0].blurred_thumbnail.clone().unwrap();
+		let (pixels, resolution) = decode_thumbnail(&blurred).unwrap();
+		assert_eq!(resolution, (16, 16));
+		assert!(pixels.iter().all(|&p| p > 64 && p < 192), "The checkerboard should be blurred to grey.");
+		assert_eq!(engine.query_page(&"photo".to_string(), 0, 10).unwrap().results[0].blurred_thumbnail, Some(blurred));
+
+		engine.remove_user_tag(photo_id, SENSITIVE_TAG).unwrap();
+		assert!(engine.get_query_results().unwrap()[0].blurred_thumbnail.is_none());
+
+		// Older DBs are filled in when they're migrated.
+		{
+			let mut conn = engine.connection.lock();
+			conn.execute("DROP TABLE blurred_thumbnails", []).unwrap();
+			migrate(&mut conn).unwrap();
+		}
+		let results = engine.query_page(&"png sort:size".to_string(), 0, 10).unwrap().results;
+		assert_eq!(results.iter().map(|img| img.blurred_thumbnail.is_some()).collect::<Vec<bool>>(), vec![false, true]);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_unique_paths() {
+		let (mut engine, db_path) = make_test_engine("unique_paths");
+		let mut first = make_test_image("photo.png", 100);
+		first.tags.insert("Model".to_string(), "A".to_string());
+		first.thumbnail = vec![1];
+		let image_id = engine.add_image(first).unwrap();
+		engine.set_user_tag(image_id, "trip", "").unwrap();
+		engine.connection.lock().execute("UPDATE images SET trashed = datetime('now')", []).unwrap();
+
+		// Finding the file again updates it, takes it out of the trash, and keeps what the user added.
+		let mut again = make_test_image("photo.png", 100);
+		again.tags.insert("Model".to_string(), "B".to_string());
+		again.thumbnail = vec![2];
+		assert_eq!(engine.add_image(again).unwrap(), image_id);
+		let connection = engine.connection.clone();
+		let count = |sql: &str| -> i64 { connection.lock().query_row(sql, [], |row| row.get(0)).unwrap() };
+		assert_eq!(count("SELECT COUNT(*) FROM images WHERE trashed IS NULL"), 1);
+		assert_eq!(count("SELECT COUNT(*) FROM tags WHERE name = 'trip'"), 1);
+		assert_eq!(count("SELECT COUNT(*) FROM tags WHERE name = 'Model' AND value = 'B'"), 1);
+		assert_eq!(count("SELECT COUNT(*) FROM tags WHERE name = 'Model'"), 1);
+		assert_eq!(engine.get_thumbnail(image_id).unwrap(), Some(vec![1])); // The file is the same size, so the thumbnail is kept.
+		engine.add_image(make_test_image("photo.png", 200)).unwrap();
+		assert_eq!(engine.get_thumbnail(image_id).unwrap(), Some(vec![]));
+
+		// Older DBs could have the same file twice.  Migrating folds the copies together.
+		let collection_id = engine.create_collection("trips").unwrap();
+		{
+			let mut conn = engine.connection.lock();
+			conn.execute("DROP INDEX images_path", []).unwrap();
+			conn.execute("INSERT INTO images (filename, path, thumbnail, rating, favorite, protected) VALUES ('photo.png', '/test/photo.png', x'', 4, 1, 1)", []).unwrap();
+			let copy_id = conn.last_insert_rowid();
+			conn.execute("INSERT INTO tags (image_id, name, value, source) VALUES (?1, 'trip', '', 'user'), (?1, 'beach', '', 'user')", params![copy_id]).unwrap();
+			conn.execute("INSERT INTO collection_members (collection_id, image_id) VALUES (?, ?)", params![collection_id, copy_id]).unwrap();
+			migrate(&mut conn).unwrap();
+		}
+		assert_eq!(count("SELECT COUNT(*) FROM images"), 1);
+		assert_eq!(engine.get_user_tags(image_id).unwrap().len(), 2);
+		assert_eq!(count("SELECT image_id FROM collection_members"), image_id);
+		assert_eq!(count("SELECT COUNT(*) FROM images WHERE rating = 4 AND favorite = 1 AND protected = 1"), 1);
+		assert!(engine.connection.lock().execute("INSERT INTO images (filename, path) VALUES ('photo.png', '/test/photo.png')", []).is_err());
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_collections() {
+		let (mut engine, db_path) = make_test_engine("collections");
+		add_test_images(&mut engine, (0..3).map(|i| make_test_image(&format!("img_{}.png", i), i)).collect());
+		let ids: Vec<i64> = engine.query_page(&"img sort:size".to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect();
+
+		let summer = engine.create_collection("Summer 2023").unwrap();
+		let cats = engine.create_collection(" cats ").unwrap();
+		assert!(engine.create_collection("Cats").is_err()); // Searching ignores case, so names must too.
+		assert!(engine.create_collection("  ").is_err());
+
+		engine.add_to_collection(summer, ids[0]).unwrap();
+		engine.add_to_collection(summer, ids[1]).unwrap();
+		engine.add_to_collection(summer, ids[1]).unwrap(); // Already there.
+		engine.add_to_collection(cats, ids[1]).unwrap();
+		let sizes: Vec<(String, Option<u64>)> = engine.get_collections().unwrap().into_iter().map(|c| (c.name, c.size)).collect();
+		assert_eq!(sizes, vec![("cats".to_string(), Some(1)), ("Summer 2023".to_string(), Some(2))]);
+		assert_eq!(engine.get_image_collections(ids[1]).unwrap(), vec![summer, cats]);
+
+		let found = |engine: &mut Engine, q: &str| -> Vec<i64> {
+			engine.query_page(&q.to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect()
+		};
+		assert_eq!(found(&mut engine, r#"collection:"summer 2023" sort:size"#), vec![ids[0], ids[1]]);
+		assert_eq!(found(&mut engine, r#"album:"Summer 2023" collection:cats"#), vec![ids[1]]);
+		assert_eq!(found(&mut engine, "collection:summer"), Vec::<i64>::new()); // Not a prefix match.
+
+		engine.remove_from_collection(summer, ids[0]).unwrap();
+		engine.rename_collection(summer, "Summer").unwrap();
+		engine.rename_collection(summer, "summer").unwrap(); // Only the case changed.
+		assert!(engine.rename_collection(summer, "CATS").is_err());
+		assert_eq!(found(&mut engine, "collection:summer"), vec![ids[1]]);
+
+		// Deleting a collection leaves its images alone.
+		engine.delete_collection(summer).unwrap();
+		assert_eq!(found(&mut engine, "collection:summer"), Vec::<i64>::new());
+		assert_eq!(engine.get_collections().unwrap().len(), 1);
+		assert_eq!(found(&mut engine, "img").len(), 3);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_hidden_collections() {
+		let (mut engine, db_path) = make_test_engine("hidden_collections");
+		add_test_images(&mut engine, (0..3).map(|i| make_test_image(&format!("img_{}.png", i), i)).collect());
+		let ids: Vec<i64> = engine.query_page(&"img sort:size".to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect();
+		let found = |engine: &mut Engine, q: &str| -> Vec<i64> {
+			engine.query_page(&q.to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect()
+		};
+
+		assert!(engine.create_hidden_collection("private", "").is_err());
+		let private = engine.create_hidden_collection("private", "hunter2").unwrap();
+		let album = engine.create_collection("album").unwrap();
+		engine.add_to_collection(private, ids[0]).unwrap();
+		engine.add_to_collection(album, ids[0]).unwrap();
+		engine.add_to_collection(album, ids[1]).unwrap();
+		assert_eq!(found(&mut engine, "img sort:size"), ids); // Unlocked when it's made.
+
+		// Locked, its images are left out everywhere, even from other collections, and it isn't listed.
+		engine.lock_hidden_collections();
+		assert_eq!(found(&mut engine, "img sort:size"), vec![ids[1], ids[2]]);
+		assert_eq!(found(&mut engine, "collection:private"), Vec::<i64>::new());
+		let listed: Vec<(String, Option<u64>)> = engine.get_collections().unwrap().into_iter().map(|c| (c.name, c.size)).collect();
+		assert_eq!(listed, vec![("album".to_string(), Some(1))]);
+		assert_eq!(engine.load_embedding_index().unwrap().entries.len(), 2);
+		assert!(engine.find_similar_groups(0.5, SimilarityMethod::default()).unwrap().iter().all(|group| group.images.iter().all(|img| img.id != ids[0])));
+		assert_eq!(engine.get_thumbnail(ids[0]).unwrap(), None);
+		assert!(engine.get_thumbnail(ids[1]).unwrap().is_some());
+		assert_eq!(engine.find_near_duplicates_of(ids[1]).unwrap().iter().map(|img| img.id).collect::<Vec<i64>>(), vec![ids[2]]);
+		let model = engine.register_embedding_model("blip", "base", 2).unwrap();
+		ids.iter().for_each(|&id| engine.set_embedding(id, model, &[1.0, 0.0]).unwrap());
+		let comparison = engine.compare_similarity_methods(ids[1], model, model, 10).unwrap();
+		assert_eq!(comparison.a.iter().map(|img| img.id).collect::<Vec<i64>>(), vec![ids[2]]);
+		engine.connection.lock().execute("INSERT INTO duplicate_reviews (path, existing_image_id, distance, found) VALUES ('/test/copy.png', ?, 0, datetime('now'))", params![ids[0]]).unwrap();
+		assert!(engine.get_near_duplicates().unwrap().is_empty());
+
+		assert!(engine.unlock_hidden_collections("Hunter2").is_err());
+		assert_eq!(engine.unlock_hidden_collections("hunter2").unwrap(), 1);
+		assert_eq!(found(&mut engine, "collection:private"), vec![ids[0]]);
+		assert!(engine.get_collections().unwrap().iter().any(|c| c.id == private && c.hidden));
+		assert_eq!(engine.get_near_duplicates().unwrap().len(), 1);
+
+		let smart = engine.create_smart_collection("smart", "img").unwrap();
+		assert!(engine.hide_collection(smart, "hunter2").is_err());
+		engine.unhide_collection(private).unwrap();
+		engine.lock_hidden_collections();
+		assert_eq!(found(&mut engine, "img sort:size"), ids);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_smart_collections() {
+		let (mut engine, db_path) = make_test_engine("smart_collections");
+		add_test_images(&mut engine, vec![
+			make_test_image("screenshot_1.png", 10),
+			make_test_image("screenshot_2.png", 20),
+			make_test_image("holiday.png", 30),
+		]);
+		let ids: Vec<i64> = engine.query_page(&"png sort:size".to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect();
+
+		let screenshots = engine.create_smart_collection("Recent Screenshots", "type:screenshot sort:indexed:desc").unwrap();
+		let big = engine.create_smart_collection("big", r#"minsize:15 collection:"recent screenshots""#).unwrap();
+		assert!(engine.create_smart_collection("broken", "type:nonsense").is_err());
+		assert!(engine.create_smart_collection("similar", "similar:/tmp/cat.png").is_err());
+		assert!(engine.add_to_collection(screenshots, ids[2]).is_err());
+
+		let collections = engine.get_collections().unwrap();
+		assert_eq!(collections.len(), 2);
+		assert!(collections.iter().all(|c| c.is_smart() && c.size.is_none()));
+
+		let found = |engine: &mut Engine, q: &str| -> Vec<i64> {
+			engine.query_page(&q.to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect()
+		};
+		// The smart collection's own sort is ignored in favor of the outer query's.
+		assert_eq!(found(&mut engine, r#"collection:"Recent Screenshots" sort:size"#), vec![ids[0], ids[1]]);
+		assert_eq!(found(&mut engine, "album:big"), vec![ids[1]]); // Nested.
+
+		// Membership is evaluated when browsed, so new images show up.
+		add_test_images(&mut engine, vec![make_test_image("screenshot_3.png", 40)]);
+		assert_eq!(found(&mut engine, "album:big").len(), 2);
+
+		engine.set_collection_query(screenshots, "holiday").unwrap();
+		assert_eq!(found(&mut engine, "album:big"), vec![ids[2]]);
+		let manual = engine.create_collection("manual").unwrap();
+		assert!(engine.set_collection_query(manual, "cats").is_err());
+
+		// A collection that includes itself fails instead of looping forever.
+		engine.set_collection_query(screenshots, "collection:big").unwrap();
+		assert!(engine.query_page(&"album:big".to_string(), 0, 10).is_err());
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_export_results() {
+		let (mut engine, db_path) = make_test_engine("export_results");
+		let out_dir = std::env::temp_dir();
+		let csv_path = out_dir.join("pixelbox_test_export_results.csv");
+		let json_path = out_dir.join("pixelbox_test_export_results.json");
+		assert!(engine.export_results(ExportFormat::Csv, &csv_path).is_err()); // No search yet.
+
+		let mut tagged = make_test_image("a, \"quoted\" name.png", 1);
+		tagged.tags.insert("Model".to_string(), "Canon".to_string());
+		add_test_images(&mut engine, vec![tagged, make_test_image("plain.png", 2)]);
+		engine.query_page(&"png sort:size".to_string(), 0, 10).unwrap();
+
+		assert_eq!(engine.export_results(ExportFormat::Csv, &csv_path).unwrap(), 2);
+		let csv = std::fs::read_to_string(&csv_path).unwrap();
+		let lines: Vec<&str> = csv.lines().collect();
+		assert_eq!(lines[0], "path,filename,width,height,distance,tags");
+		assert_eq!(lines[1], r#""/test/a, ""quoted"" name.png","a, ""quoted"" name.png",64,64,0,"{""Model"":""Canon""}""#);
+		assert_eq!(lines[2], "/test/plain.png,plain.png,64,64,0,{}");
+
+		assert_eq!(engine.export_results(ExportFormat::Json, &json_path).unwrap(), 2);
+		let json: JSONValue = serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+		assert_eq!(json[0]["filename"], "a, \"quoted\" name.png");
+		assert_eq!(json[0]["tags"]["Model"], "Canon");
+		assert_eq!(json[1]["width"], 64);
+
+		assert_eq!(ExportFormat::from_path(Path::new("out/Results.CSV")), Some(ExportFormat::Csv));
+		assert_eq!(ExportFormat::from_path(Path::new("results.txt")), None);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+		let _ = std::fs::remove_file(csv_path);
+		let _ = std::fs::remove_file(json_path);
+	}
+
+	#[test]
+	fn test_open_and_close() {
+		let (mut engine, db_path) = make_test_engine("open_and_close");
+		add_test_images(&mut engine, vec![make_test_image("kept.png", 0)]);
+		// Nothing to crawl, but the storage thread still has to be waited on.
+		engine.add_tracked_folder(std::env::temp_dir().join("pixelbox_test_no_such_folder").display().to_string()).unwrap();
+		engine.start_reindexing().unwrap();
+		assert!(engine.reindex_folder("/not/watched").is_err());
+		engine.pause_indexing();
+		assert!(engine.get_indexing_status().paused);
+		engine.resume_indexing();
+		assert!(!engine.is_indexing_paused());
+		// Closing while paused doesn't wait for a resume.
+		engine.pause_indexing();
+		engine.close().unwrap();
+
+		let mut reopened = Engine::open_or_create(&db_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!(reopened.get_num_indexed_images().unwrap(), 1);
+		drop(reopened);
+
+		let not_a_db = std::env::temp_dir().join(format!("pixelbox_test_not_a_db_{}.db", std::process::id()));
+		std::fs::write(&not_a_db, b"Just some text.").unwrap();
+		assert!(Engine::open_or_create(&not_a_db, OpenMode::OpenExisting).is_err());
+		assert!(Engine::open_or_create(&db_path, OpenMode::CreateNew).is_err());
+		assert!(Engine::open_or_create(&std::env::temp_dir().join("pixelbox_no_such_dir").join("x.db"), OpenMode::OpenExisting).is_err());
+		assert!(Engine::open_or_create(&std::env::temp_dir().join("pixelbox_no_such_dir").join("x.db"), OpenMode::OpenOrCreate).is_err());
+		assert!(Engine::open_or_create(&db_path, OpenMode::OpenOrCreate).is_ok());
+
+		// An empty file can be made into a DB, but isn't one yet.
+		std::fs::write(&not_a_db, b"").unwrap();
+		assert!(Engine::open_or_create(&not_a_db, OpenMode::OpenExisting).is_err());
+		assert_eq!(Engine::open_or_create(&not_a_db, OpenMode::OpenOrCreate).unwrap().get_num_indexed_images().unwrap(), 0);
+
+		let _ = std::fs::remove_file(db_path);
+		let _ = std::fs::remove_file(not_a_db);
+	}
+
+	#[test]
+	fn test_migrate_old_db() {
+		// The schema from before ratings, tag sources, and the trash.
+		let db_path = std::env::temp_dir().join(format!("pixelbox_test_migrate_old_db_{}.db", std::process::id()));
+		let _ = std::fs::remove_file(&db_path);
+		let conn = rusqlite::Connection::open(&db_path).unwrap();
+		conn.execute_batch("
+			CREATE TABLE images (id INTEGER PRIMARY KEY, filename TEXT NOT NULL, path TEXT NOT NULL, image_width INTEGER, image_height INTEGER, thumbnail BLOB, created DATETIME, indexed DATETIME);
+			CREATE TABLE tags (image_id INTEGER, name TEXT NOT NULL, value TEXT);
+			CREATE TABLE watched_directories (glob TEXT PRIMARY KEY);
+			CREATE TABLE phashes (image_id INTEGER PRIMARY KEY, hash BLOB);
+			CREATE TABLE semantic_hashes (image_id INTEGER PRIMARY KEY, hash BLOB);
+			INSERT INTO images (id, filename, path, image_width, image_height, thumbnail) VALUES (1, 'old.png', '/old/old.png', 8, 8, x'');
+			INSERT INTO tags (image_id, name, value) VALUES (1, 'Make', 'Camera');
+			INSERT INTO semantic_hashes (image_id, hash) VALUES (1, x'00');
+		").unwrap();
+		drop(conn);
+
+		let mut engine = Engine::open_or_create(&db_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!(engine.get_num_indexed_images().unwrap(), 1);
+		engine.set_rating(1, 4).unwrap();
+		engine.set_user_tag(1, "mood", "cozy").unwrap();
+		assert_eq!(engine.query_page(&"rating:4".to_string(), 0, 10).unwrap().results.len(), 1);
+		assert_eq!(engine.query_page(&"tag:make:camera".to_string(), 0, 10).unwrap().results.len(), 1);
+		engine.create_collection("Old Favorites").unwrap();
+		drop(engine);
+
+		// Already current, so opening again changes nothing.
+		let reopened = Engine::open_or_create(&db_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!(reopened.get_user_tags(1).unwrap().len(), 1);
+		assert_eq!(reopened.analyze_storage().unwrap().stale_embeddings, 0); // Taken to be from the installed model.
+
+		drop(reopened);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_reembed_stale_hashes() {
+		let (mut engine, db_path) = make_test_engine("reembed_stale_hashes");
+		let mut with_thumbnail = make_test_image("with_thumbnail.png", 0);
+		with_thumbnail.thumbnail = qoi::encode_to_vec(&[255u8; 4 * 4 * 3], 4, 4).unwrap();
+		add_test_images(&mut engine, vec![with_thumbnail, make_test_image("no_thumbnail.png", 0), make_test_image("current.png", 0)]);
+		// Pretend two were made by an older model.
+		engine.connection.lock().execute("UPDATE semantic_hashes SET model = CASE WHEN image_id = (SELECT id FROM images WHERE filename = 'current.png') THEN 'v2' ELSE 'v1' END", []).unwrap();
+		assert_eq!(count_stale_embeddings(&engine.connection.lock(), Some("v2")).unwrap(), 2);
+		assert_eq!(engine.analyze_storage().unwrap().stale_embeddings, 3); // None are from the installed model.
+
+		let done = AtomicU64::new(0);
+		let reembedded = reembed_stale_hashes(&engine.connection, Some("v2"), |img| vec![img.width() as u8; 8], &done, &AtomicBool::new(false)).unwrap();
+		assert_eq!(reembedded, 1); // The other has no file or thumbnail to embed.
+		assert_eq!(done.load(Ordering::Relaxed), 2);
+		assert_eq!(count_stale_embeddings(&engine.connection.lock(), Some("v2")).unwrap(), 1);
+		let hash: Vec<u8> = engine.connection.lock().query_row(
+			"SELECT hash FROM semantic_hashes INNER JOIN images ON images.id = image_id WHERE filename = 'with_thumbnail.png'", [], |row| row.get(0)
+		).unwrap();
+		assert_eq!(hash, vec![4u8; 8]);
+
+		// Stopping leaves the rest for next time.
+		engine.connection.lock().execute("UPDATE semantic_hashes SET model = 'v1'", []).unwrap();
+		assert_eq!(reembed_stale_hashes(&engine.connection, Some("v2"), |_| vec![0; 8], &AtomicU64::new(0), &AtomicBool::new(true)).unwrap(), 0);
+		assert_eq!(count_stale_embeddings(&engine.connection.lock(), Some("v2")).unwrap(), 3);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_indexing_progress() {
+		let (mut engine, db_path) = make_test_engine("indexing_progress");
+		assert_eq!(engine.get_indexing_progress_details(), None);
+		let folder = std::env::temp_dir().join(format!("pixelbox_test_indexing_progress_{}", std::process::id()));
+		std::fs::create_dir_all(&folder).unwrap();
+		std::fs::write(folder.join("broken.png"), b"Not a PNG.").unwrap();
+		std::fs::write(folder.join("notes.txt"), b"Not an image at all.").unwrap();
+		engine.add_tracked_folder(folder.display().to_string()).unwrap();
+		engine.start_reindexing().unwrap();
+		while engine.is_indexing_active() {
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+
+		let progress = engine.get_indexing_progress_details().unwrap();
+		assert!(progress.finished_finding);
+		assert_eq!((progress.files_found, progress.files_processed, progress.images_stored, progress.failed), (2, 2, 0, 1));
+		assert_eq!(progress.eta, Some(std::time::Duration::ZERO));
+		assert_eq!(engine.get_indexing_progress(), 1.0);
+		assert_eq!(engine.get_indexing_status().to_json()["details"]["failed"], 1);
+
+		// The failure outlasts the run.
+		let failures = engine.get_indexing_failures().unwrap();
+		assert_eq!(failures.len(), 1);
+		assert_eq!(failures[0].path, crate::indexed_image::stringify_filepath(&folder.join("broken.png")));
+		assert!(!failures[0].error.is_empty() && !failures[0].failed.is_empty());
+		assert_eq!(engine.clear_indexing_failures().unwrap(), 1);
+		assert!(engine.get_indexing_failures().unwrap().is_empty());
+
+		drop(engine);
+		let _ = std::fs::remove_dir_all(folder);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_exclusions() {
+		let (mut engine, db_path) = make_test_engine("exclusions");
+		let folder = std::env::temp_dir().join(format!("pixelbox_test_exclusions_{}", std::process::id()));
+		std::fs::create_dir_all(&folder).unwrap();
+		let (junk, copy, other) = (folder.join("junk.png"), folder.join("copy of junk.png"), folder.join("other.png"));
+		std::fs::write(&junk, b"Junk.").unwrap();
+		std::fs::write(&copy, b"Junk.").unwrap();
+		std::fs::write(&other, b"Not junk.").unwrap();
+		let junk_path = stringify_filepath(&junk);
+		let mut img = make_test_image("junk.png", 5);
+		img.path = junk_path.clone();
+		let mut in_book = make_test_image("page.png", 5);
+		in_book.path = crate::archive::entry_path("/books/a.cbz", "page.png");
+		add_test_images(&mut engine, vec![img, in_book]);
+		let id_of = |engine: &Engine, path: &str| engine.connection.lock().query_row("SELECT id FROM images WHERE path = ?", [path], |row| row.get::<_, i64>(0)).unwrap();
+		let (junk_id, page_id) = (id_of(&engine, &junk_path), id_of(&engine, "/books/a.cbz!/page.png"));
+		assert!(engine.exclude_image(-1).is_err());
+
+		// Excluded images go to the trash, and copies of them are excluded too.
+		engine.exclude_image(junk_id).unwrap();
+		engine.exclude_image(page_id).unwrap();
+		assert_eq!(engine.get_trashed_images().unwrap().len(), 2);
+		let by_content: Vec<(String, bool)> = engine.get_exclusions().unwrap().into_iter().map(|e| (e.path, e.by_content)).collect();
+		assert!(by_content.contains(&(junk_path.clone(), true)) && by_content.contains(&("/books/a.cbz!/page.png".to_string(), false)));
+		let excluded = |engine: &Engine, path: &Path| engine.crawl_options().exclusions.excludes(path);
+		assert!(excluded(&engine, &junk) && excluded(&engine, &copy) && !excluded(&engine, &other));
+		assert!(engine.crawl_options().exclusions.excludes_entry("/books/a.cbz!/page.png"));
+		assert!(Engine::is_excluded(&engine.connection.lock(), "/books/a.cbz!/page.png").unwrap());
+
+		// The crawler skips them without loading them, so they don't fail to load like other.png does.
+		engine.add_tracked_folder(folder.display().to_string()).unwrap();
+		engine.start_reindexing().unwrap();
+		while engine.is_indexing_active() {
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		let failures: Vec<String> = engine.get_indexing_failures().unwrap().into_iter().map(|f| f.path).collect();
+		assert_eq!(failures, vec![stringify_filepath(&other)]);
+
+		// Restoring or removing the exclusion lets them back in.
+		engine.remove_exclusion(&junk_path).unwrap();
+		assert!(!excluded(&engine, &junk) && !excluded(&engine, &copy));
+		engine.restore_from_trash(page_id).unwrap();
+		assert!(engine.get_exclusions().unwrap().is_empty());
+
+		drop(engine);
+		let _ = std::fs::remove_dir_all(folder);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_container_failures() {
+		let (engine, db_path) = make_test_engine("container_failures");
+		let failed_paths = |engine: &Engine| {
+			let mut paths: Vec<String> = engine.get_indexing_failures().unwrap().into_iter().map(|f| f.path).collect();
+			paths.sort();
+			paths
+		};
+		{
+			let conn = engine.connection.lock();
+			for path in ["/a.zip", "/a.zip!/b.zip", "/a.zip!/b.zip!/c.png", "/d.cbz", "/mail.mbox"] {
+				Engine::record_indexing_failure(&conn, path, "It couldn't be read.");
+			}
+		}
+		// c.png was reached, so the archives it's inside were read this time.
+		assert_eq!(failed_paths(&engine), vec!["/a.zip!/b.zip!/c.png", "/d.cbz", "/mail.mbox"]);
+
+		// The same goes for images that are stored.
+		Engine::record_indexing_failure(&engine.connection.lock(), "/d.cbz!/page.png", "It isn't a PNG.");
+		Engine::clear_container_failures(&engine.connection.lock(), "/mail.mbox!/photo.jpg").unwrap();
+		assert_eq!(failed_paths(&engine), vec!["/a.zip!/b.zip!/c.png", "/d.cbz!/page.png"]);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_retry_failed() {
+		let (mut engine, db_path) = make_test_engine("retry_failed");
+		assert_eq!(engine.retry_failed().unwrap(), 0);
+		let folder = std::env::temp_dir().join(format!("pixelbox_test_retry_failed_{}", std::process::id()));
+		std::fs::create_dir_all(&folder).unwrap();
+		let (broken, gone, pages) = (folder.join("broken.png"), folder.join("gone.png"), folder.join("pages.zip"));
+		std::fs::write(&broken, b"Not a PNG.").unwrap();
+		std::fs::write(&gone, b"Not a PNG either.").unwrap();
+		std::fs::write(&pages, b"Not a zip.").unwrap();
+		let wait = |engine: &Engine| while engine.is_indexing_active() {
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		};
+		engine.add_tracked_folder(folder.display().to_string()).unwrap();
+		engine.start_reindexing().unwrap();
+		wait(&engine);
+		assert_eq!(engine.get_indexing_failures().unwrap().len(), 3);
+
+		// Files that still fail stay in the log, with why they failed this time.
+		// An archive that can be read now is taken out of it, and only the entries in it that fail are logged.
+		std::fs::remove_file(&gone).unwrap();
+		std::fs::write(&pages, crate::archive::make_test_zip(&[("page.png", b"Not a PNG.")])).unwrap();
+		assert_eq!(engine.retry_failed().unwrap(), 3);
+		wait(&engine);
+		let failures = engine.get_indexing_failures().unwrap();
+		assert_eq!(failures.len(), 3);
+		assert!(failures.iter().any(|f| f.path.ends_with("pages.zip!/page.png")) && !failures.iter().any(|f| f.path.ends_with("pages.zip")));
+		let gone_failure = failures.iter().find(|f| f.path.ends_with("gone.png")).unwrap();
+		assert!(gone_failure.error.contains("No such file") || gone_failure.error.contains("cannot find"), "{}", gone_failure.error);
+
+		// Files that can be indexed now are taken out of it.
+		if crate::image_hashes::mlhash_model_version().is_some() {
+			image::RgbImage::from_pixel(16, 16, image::Rgb([200, 30, 30])).save(&broken).unwrap();
+			engine.retry_failed().unwrap();
+			wait(&engine);
+			assert!(!engine.get_indexing_failures().unwrap().iter().any(|f| f.path.ends_with("broken.png")));
+		}
+
+		drop(engine);
+		let _ = std::fs::remove_dir_all(folder);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_cancel_indexing() {
+		let (mut engine, db_path) = make_test_engine("cancel_indexing");
+		let folder = std::env::temp_dir().join(format!("pixelbox_test_cancel_indexing_{}", std::process::id()));
+		std::fs::create_dir_all(&folder).unwrap();
+		for idx in 0..3 {
+			std::fs::write(folder.join(format!("broken{}.png", idx)), b"Not a PNG.").unwrap();
+		}
+		let wait = |engine: &Engine| while engine.is_indexing_active() {
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		};
+		engine.add_tracked_folder(folder.display().to_string()).unwrap();
+
+		// Cancelling a paused run ends it without loading anything, and doesn't wait for a resume.
+		engine.pause_indexing();
+		engine.start_reindexing().unwrap();
+		engine.cancel_indexing();
+		wait(&engine);
+		assert!(!engine.is_indexing_cancelled());
+		assert!(engine.get_indexing_failures().unwrap().is_empty());
+
+		// The next run isn't cancelled with it.
+		engine.resume_indexing();
+		engine.start_reindexing().unwrap();
+		wait(&engine);
+		assert_eq!(engine.get_indexing_failures().unwrap().len(), 3);
+		assert!(!engine.get_indexing_status().stopping);
+
+		drop(engine);
+		let _ = std::fs::remove_dir_all(folder);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_watched_changes() {
+		let (mut engine, db_path) = make_test_engine("watched_changes");
+		let folder = std::env::temp_dir().join(format!("pixelbox_test_watched_changes_{}", std::process::id()));
+		std::fs::create_dir_all(folder.join("moved in")).unwrap();
+		engine.add_tracked_folder(folder.display().to_string()).unwrap();
+		engine.set_ignore_patterns(&folder.display().to_string(), &["*.tmp".to_string()]).unwrap();
+		let mut deleted = make_test_image("deleted.png", 5);
+		deleted.path = crate::indexed_image::stringify_filepath(&folder.join("deleted.png"));
+		let mut in_book = make_test_image("page.png", 5);
+		in_book.path = crate::archive::entry_path(&crate::indexed_image::stringify_filepath(&folder.join("deleted.cbz")), "page.png");
+		add_test_images(&mut engine, vec![deleted, in_book, make_test_image("elsewhere.png", 5)]);
+		let wait = |engine: &Engine| while engine.is_indexing_active() {
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		};
+
+		let watched = vec![engine.get_folder_settings(&folder.display().to_string()).unwrap()];
+
+		// Nothing is watched with watching off.
+		let (_, nothing) = crossbeam::channel::unbounded();
+		engine.watch_changes(crate::crawler::FolderWatcher::from_events(nothing, Duration::ZERO), watched.clone());
+		assert_eq!(engine.index_watched_changes().unwrap(), 0);
+		assert!(engine.folder_watcher.is_none());
+
+		engine.watch_folders = true;
+		let (events, changes) = crossbeam::channel::unbounded();
+		engine.watch_changes(crate::crawler::FolderWatcher::from_events(changes, Duration::ZERO), watched);
+		std::fs::write(folder.join("new.png"), b"Not a PNG.").unwrap();
+		std::fs::write(folder.join("partial.tmp"), b"Still downloading.").unwrap();
+		std::fs::write(folder.join("moved in").join("inside.png"), b"Not a PNG either.").unwrap();
+		events.send(vec![folder.join("new.png"), folder.join("partial.tmp"), folder.join("moved in"), folder.join("deleted.png"), folder.join("deleted.cbz")]).unwrap();
+		// The changes are sorted out on the watcher's thread, and handled on the first call after they are.
+		let started = Instant::now();
+		let mut handled = 0;
+		while handled == 0 && started.elapsed() < Duration::from_secs(10) {
+			std::thread::sleep(Duration::from_millis(10));
+			handled = engine.index_watched_changes().unwrap();
+		}
+		assert_eq!(handled, 5);
+		wait(&engine);
+
+		// New files and folders are indexed, leaving out ignored ones, and deleted ones are trashed along with what was inside them.
+		let progress = engine.get_indexing_progress_details().unwrap();
+		assert_eq!(progress.files_found, 2);
+		let trashed: Vec<String> = engine.get_trashed_images().unwrap().into_iter().map(|img| img.filename).collect();
+		assert_eq!(trashed.len(), 2);
+		assert!(trashed.contains(&"deleted.png".to_string()) && trashed.contains(&"page.png".to_string()));
+
+		// Changing the folders makes a new watcher for them.
+		engine.set_folder_priority(&folder.display().to_string(), 1).unwrap();
+		assert!(engine.folder_watcher.is_none());
+		drop(events);
+
+		drop(engine);
+		let _ = std::fs::remove_dir_all(folder);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_embedding_drift() {
+		// Points around a circle, so each one's nearest neighbors are the ones next to it.
+		let circle = |order: &[usize]| -> Vec<Vec<u8>> {
+			order.iter().map(|&i| {
+				let angle = i as f32 * 0.35;
+				crate::image_hashes::quantize_embedding(&[angle.cos(), angle.sin()])
+			}).collect()
+		};
+		let old = circle(&(0..16).collect::<Vec<usize>>());
+		let unchanged = embedding_drift(&old, &old);
+		assert_eq!(unchanged.sample_size, 16);
+		assert!(unchanged.rank_correlation > 0.99);
+		assert_eq!(unchanged.neighbor_overlap, 1.0);
+		let shuffled = embedding_drift(&old, &circle(&(0..16).map(|i| i * 7 % 16).collect::<Vec<usize>>()));
+		assert!(shuffled.rank_correlation < 0.5);
+		assert!(shuffled.neighbor_overlap < 1.0);
+
+		let (mut engine, db_path) = make_test_engine("embedding_drift");
+		let images = (1..=4).map(|width| {
+			let mut img = make_test_image(&format!("img_{}.png", width), 0);
+			img.thumbnail = qoi::encode_to_vec(&vec![200u8; width as usize * 3], width, 1).unwrap();
+			img
+		}).collect();
+		add_test_images(&mut engine, images);
+		engine.connection.lock().execute("UPDATE semantic_hashes SET model = 'v1'", []).unwrap();
+		let embed = |img: &image::DynamicImage| crate::image_hashes::quantize_embedding(&[1.0, img.width() as f32 / 4.0]);
+		let drift = measure_embedding_drift(&engine.connection, Some("v2"), embed, 10).unwrap();
+		assert_eq!(drift.sample_size, 4);
+		// Nothing was stored.
+		assert_eq!(count_stale_embeddings(&engine.connection.lock(), Some("v2")).unwrap(), 4);
+		// Too few to compare.
+		assert!(measure_embedding_drift(&engine.connection, Some("v2"), embed, 2).is_err());
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_stats() {
+		let (mut engine, db_path) = make_test_engine("stats");
+		assert_eq!(engine.stats().unwrap().num_images, 0);
+
+		let mut big = make_test_image("big.JPG", 3000);
+		big.resolution = (6000, 4000);
+		let mut other_folder = make_test_image("elsewhere.png", 5);
+		other_folder.path = "/elsewhere/elsewhere.png".to_string();
+		add_test_images(&mut engine, vec![big, make_test_image("a.png", 10), make_test_image("b.png", 20), make_test_image("README", 1), make_test_image("trashed.gif", 7), other_folder]);
+		engine.connection.lock().execute("UPDATE images SET trashed = datetime('now') WHERE filename = 'trashed.gif'", []).unwrap();
+		engine.add_tracked_folder("/test".to_string()).unwrap();
+		engine.add_tracked_folder("/empty".to_string()).unwrap();
+
+		let stats = engine.stats().unwrap();
+		assert_eq!(stats.num_images, 5);
+		assert_eq!(stats.num_trashed, 1);
+		assert_eq!(stats.total_file_bytes, 3036);
+		assert_eq!(stats.by_extension, vec![("png".to_string(), 3), ("".to_string(), 1), ("jpg".to_string(), 1)]);
+		assert_eq!(stats.by_folder, vec![("/test".to_string(), 4), ("/empty".to_string(), 0)]);
+		assert_eq!(stats.by_resolution.len(), 6);
+		assert_eq!(stats.by_resolution[0], ("Up to 512px".to_string(), 4));
+		assert_eq!(stats.by_resolution[3], ("2049 to 4096px".to_string(), 0));
+		assert_eq!(stats.by_resolution[4], ("4097 to 8192px".to_string(), 1));
+		assert_eq!(stats.indexed_by_day.len(), 1);
+		assert_eq!(stats.indexed_by_day[0].1, 5);
+		assert!(stats.first_indexed.is_some() && stats.first_indexed <= stats.last_indexed);
+		assert_eq!(stats.to_json()["by_extension"][0]["count"], 3);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_analyze_storage_and_compaction() {
+		let (mut engine, db_path) = make_test_engine("analyze_storage");
+		let mut flat = make_test_image("flat.png", 1);
+		flat.thumbnail = qoi::encode_to_vec(vec![200u8; 256 * 256 * 3], 256, 256).unwrap();
+		flat.cropped_hash = Some(vec![128u8; 8]);
+		let original_thumbnail = flat.thumbnail.clone();
+		add_test_images(&mut engine, vec![flat, make_test_image("empty.png", 2)]);
+		engine.connection.lock().execute_batch("
+			INSERT INTO tags (image_id, name, value) VALUES (999, 'left', 'behind');
+			INSERT INTO phashes (image_id, hash) VALUES (999, x'00112233');
+		").unwrap();
+
+		let report = engine.analyze_storage().unwrap();
+		assert_eq!(report.thumbnail_bytes, original_thumbnail.len() as u64);
+		assert!(report.table_bytes.iter().any(|(name, size)| name == "images" && *size > 0));
+		assert_eq!(report.largest_images.len(), 2);
+		assert!(report.largest_images[0].1.ends_with("flat.png"));
+		assert_eq!(report.orphaned_bytes, 10 + 4);
+		assert!(report.thumbnail_savings_estimate > 0);
+		assert!(report.webp_savings_estimate > 0);
+		assert_eq!(report.embedding_reduction_estimate, 8);
+
+		let wait_for_job = |engine: &mut Engine| loop {
+			if let Some(result) = engine.poll_compaction_job() {
+				return result.unwrap();
+			}
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		};
+		engine.start_compaction_job(CompactionJob::RemoveOrphans).unwrap();
+		assert_eq!(wait_for_job(&mut engine), 14);
+		engine.start_compaction_job(CompactionJob::RecompressThumbnails).unwrap();
+		assert_eq!(wait_for_job(&mut engine), report.thumbnail_savings_estimate);
+
+		let report = engine.analyze_storage().unwrap();
+		assert_eq!(report.orphaned_bytes, 0);
+		assert_eq!(report.thumbnail_savings_estimate, 0);
+		let id = report.largest_images[0].0;
+		let recompressed = engine.get_thumbnail(id).unwrap().unwrap();
+		assert!(recompressed.len() < original_thumbnail.len());
+		assert_eq!(decode_thumbnail(&recompressed).unwrap(), decode_thumbnail(&original_thumbnail).unwrap());
+
+		// The PNG can go to WebP too, and the one image is the whole sample, so the estimate is exact.
+		assert!(report.webp_savings_estimate > 0);
+		engine.start_compaction_job(CompactionJob::WebpThumbnails).unwrap();
+		assert_eq!(wait_for_job(&mut engine), report.webp_savings_estimate);
+		let webp = engine.get_thumbnail(id).unwrap().unwrap();
+		assert!(webp.starts_with(b"RIFF") && webp.len() < recompressed.len());
+		assert_eq!(decode_thumbnail(&webp).unwrap(), decode_thumbnail(&original_thumbnail).unwrap());
+		engine.start_compaction_job(CompactionJob::ReduceEmbeddings).unwrap();
+		assert_eq!(wait_for_job(&mut engine), 8);
+		let report = engine.analyze_storage().unwrap();
+		assert_eq!((report.webp_savings_estimate, report.embedding_reduction_estimate), (0, 0));
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_backup_to() {
+		let (mut engine, db_path) = make_test_engine("backup_to");
+		add_test_images(&mut engine, (0..3).map(|i| make_test_image(&format!("img_{}.png", i), i)).collect());
+		engine.add_tracked_folder("/test/*".to_string()).unwrap();
+		engine.add_tracked_folder("/first/*".to_string()).unwrap();
+		// Higher priority folders are listed, and so indexed, first.
+		engine.set_folder_priority("/first/*", 2).unwrap();
+		assert!(engine.set_folder_priority("/not/watched", 1).is_err());
+		assert_eq!(engine.get_tracked_folders().unwrap(), &vec!["/first/*".to_string(), "/test/*".to_string()]);
+		engine.set_ignore_patterns("/test/*", &["**/node_modules/**".to_string(), " ".to_string(), "*.tmp".to_string()]).unwrap();
+		assert!(engine.set_ignore_patterns("/test/*", &["[unclosed".to_string()]).is_err());
+		assert!(engine.set_ignore_patterns("/not/watched", &[]).is_err());
+		assert!(engine.backup_to(&db_path).is_err());
+
+		let backup_path = std::env::temp_dir().join(format!("pixelbox_test_backup_to_copy_{}.db", std::process::id()));
+		std::fs::write(&backup_path, b"Replaced by the backup.").unwrap();
+		engine.backup_to(&backup_path).unwrap();
 
-		// Grab a read lock.
-		self.cached_search_results = {
-			let conn = self.connection.lock();
+		let mut backup = Engine::open_or_create(&backup_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!(backup.get_num_indexed_images().unwrap(), 3);
+		assert_eq!(backup.get_tracked_folders().unwrap(), &vec!["/first/*".to_string(), "/test/*".to_string()]);
+		assert_eq!(backup.get_folder_priority("/first/*").unwrap(), 2);
+		assert_eq!(backup.get_ignore_patterns("/test/*").unwrap(), vec!["**/node_modules/**", "*.tmp"]);
+		assert!(backup.get_ignore_patterns("/first/*").unwrap().is_empty());
 
-			// Try and perform the user's query (or some version of our assembled query).
-			let mut prepared_statement = conn.prepare(&statement)?;
+		drop(engine);
+		drop(backup);
+		let _ = std::fs::remove_file(db_path);
+		let _ = std::fs::remove_file(backup_path);
+	}
 
-			// Parse and process results.
-			let result_cursor = prepared_statement.query_map(params![], |row| {
-				let mut img = indexed_image_from_row(row).expect("Unable to decode image in database.");
-				img.visual_hash = row.get(6).ok();
-				img.tags = HashMap::new();
-				let maybe_tag_data: SQLResult<JSONValue> = row.get(7);
-				if let Ok(tag_data) = maybe_tag_data {
-					if let Some(map_obj) = tag_data.as_object() {
-						for (k, v) in map_obj.iter() {
-							img.tags.insert(k.to_string(), v.to_string());
-						}
-					}
-				}
-				img.distance_from_query = row.get(8).ok();
-				Ok(img)
-			})?;
+	#[test]
+	fn test_folder_settings() {
+		let (mut engine, db_path) = make_test_engine("folder_settings");
+		engine.add_tracked_folder("/photos".to_string()).unwrap();
+		let defaults = engine.get_folder_settings("/photos").unwrap();
+		assert!(defaults.recursive && defaults.scan_archives && defaults.follow_symlinks && defaults.extensions.is_empty());
 
-			result_cursor.map(|item| {
-				Some(item.unwrap())
-			}).collect()
-		};
-		
-		println!("{} results", &self.cached_search_results.as_ref().unwrap().len());
+		let settings = crate::crawler::CrawlFolder { recursive: false, extensions: vec![" .PNG".to_string(), "jpg".to_string(), "".to_string()], scan_archives: false, follow_symlinks: false, archive_password: "hunter2".to_string(), ..defaults };
+		engine.set_folder_settings(&settings).unwrap();
+		assert!(engine.set_folder_settings(&crate::crawler::CrawlFolder { glob: "/not/watched".to_string(), ..Default::default() }).is_err());
+		// Changing one setting leaves the rest alone.
+		engine.set_folder_priority("/photos", 3).unwrap();
+		drop(engine);
 
-		Ok(())
+		let mut engine = Engine::open_or_create(&db_path, OpenMode::OpenExisting).unwrap();
+		let saved = engine.get_folder_settings("/photos").unwrap();
+		assert_eq!(saved, crate::crawler::CrawlFolder { priority: 3, extensions: vec!["png".to_string(), "jpg".to_string()], ..settings });
+		assert_eq!(engine.get_folder_settings("/elsewhere").unwrap(), crate::crawler::CrawlFolder { glob: "/elsewhere".to_string(), ..Default::default() });
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
 	}
 
-	pub fn query_by_image_hash_from_file(&mut self, img:&Path) {
-		self.cached_search_results = None;
+	#[test]
+	fn test_backup_incremental() {
+		let (mut engine, db_path) = make_test_engine("backup_incremental");
+		let backup_dir = std::env::temp_dir().join(format!("pixelbox_test_backup_incremental_{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&backup_dir);
+		add_test_images(&mut engine, (0..3).map(|i| make_test_image(&format!("img_{}.png", i), i)).collect());
+		let first = engine.backup_incremental(&backup_dir).unwrap().unwrap();
+		add_test_images(&mut engine, vec![make_test_image("img_3.png", 3)]);
+		let second = engine.backup_incremental(&backup_dir).unwrap().unwrap();
+		assert_ne!(first, second);
+		assert!(!backup_dir.join(BACKUP_SNAPSHOT_FILENAME).exists());
 
-		let debug_start_load_image = Instant::now();
-		let indexed_image = IndexedImage::from_file_path(img).unwrap();
-		let debug_end_load_image = Instant::now();
-		eprintln!("Time to compute image hash: {:?}", debug_end_load_image-debug_start_load_image);
+		let restored_path = backup_dir.join("restored.db");
+		backup::restore_backup(&second, &restored_path).unwrap();
+		assert_eq!(Engine::open_or_create(&restored_path, OpenMode::OpenExisting).unwrap().get_num_indexed_images().unwrap(), 4);
+		backup::restore_backup(&first, &restored_path).unwrap();
+		assert_eq!(Engine::open_or_create(&restored_path, OpenMode::OpenExisting).unwrap().get_num_indexed_images().unwrap(), 3);
+
+		// Scheduling settings follow the DB.
+		engine.backup_directory = backup_dir.display().to_string();
+		engine.backup_interval_hours = 6;
+		engine.save_settings().unwrap();
+		drop(engine);
+		let reopened = Engine::open_or_create(&db_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!((reopened.backup_directory.as_str(), reopened.backup_interval_hours), (backup_dir.to_str().unwrap(), 6));
 
-		self.query_by_image_hash_from_image(&indexed_image);
+		drop(reopened);
+		let _ = std::fs::remove_file(db_path);
+		let _ = std::fs::remove_dir_all(backup_dir);
 	}
 
-	pub fn query_by_image_hash_from_image(&mut self, indexed_image:&IndexedImage) {
-		if indexed_image.visual_hash.is_none() {
-			// TODO: Error-handling here.
-			eprintln!("TODO: IndexedImage is somehow missing a hash!");
-			return;
+	#[test]
+	fn test_share_collection() {
+		let (mut engine, db_path) = make_test_engine("share_collection");
+		let original_path = std::env::temp_dir().join(format!("pixelbox_test_share_original_{}.png", std::process::id()));
+		std::fs::write(&original_path, b"Pretend this is a PNG.").unwrap();
+		let mut on_disk = make_test_image("on_disk.png", 1);
+		on_disk.path = original_path.display().to_string();
+		add_test_images(&mut engine, vec![on_disk, make_test_image("missing.png", 2), make_test_image("unshared.png", 3)]);
+		let ids: Vec<i64> = engine.query_page(&"png sort:size".to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect();
+		let picks = engine.create_collection("Picks for Sam").unwrap();
+		engine.add_to_collection(picks, ids[0]).unwrap();
+		engine.add_to_collection(picks, ids[1]).unwrap();
+		let small = engine.create_smart_collection("Small", "maxsize:1").unwrap();
+
+		let bundle_path = std::env::temp_dir().join(format!("pixelbox_test_share_bundle_{}.db", std::process::id()));
+		let smart_bundle_path = std::env::temp_dir().join(format!("pixelbox_test_share_smart_bundle_{}.db", std::process::id()));
+		let _ = std::fs::remove_file(&bundle_path);
+		let _ = std::fs::remove_file(&smart_bundle_path);
+		assert!(engine.share_collection(12345, &bundle_path, false).is_err());
+		assert_eq!(engine.share_collection(picks, &bundle_path, true).unwrap(), 2);
+		assert_eq!(engine.share_collection(small, &smart_bundle_path, false).unwrap(), 1);
+
+		let mut bundle = Engine::open_or_create(&bundle_path, OpenMode::OpenExisting).unwrap();
+		let shared = bundle.query_page(&r#"collection:"picks for sam" sort:size"#.to_string(), 0, 10).unwrap().results;
+		assert_eq!(shared.iter().map(|img| img.id).collect::<Vec<i64>>(), vec![ids[0], ids[1]]);
+		assert_eq!(bundle.get_original(ids[0]).unwrap(), Some(b"Pretend this is a PNG.".to_vec()));
+		assert_eq!(bundle.get_original(ids[1]).unwrap(), None); // The file was never there.
+		assert!(bundle.is_read_only() && !engine.is_read_only());
+		assert!(bundle.set_rating(ids[0], 3).is_err());
+		assert_eq!(engine.get_original(ids[0]).unwrap(), None);
+
+		// Bundles can be imported like an exported index, too.
+		let mut smart_bundle = Engine::open_or_create(&smart_bundle_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!(smart_bundle.get_collections().unwrap().iter().map(|c| (c.name.clone(), c.is_smart())).collect::<Vec<(String, bool)>>(), vec![("Small".to_string(), false)]);
+		drop(smart_bundle);
+		let (mut other, other_path) = make_test_engine("share_collection_other");
+		assert_eq!(other.import_index(&smart_bundle_path).unwrap(), 1);
+
+		drop(engine);
+		drop(bundle);
+		drop(other);
+		for path in [db_path, other_path, original_path, bundle_path, smart_bundle_path] {
+			let _ = std::fs::remove_file(path);
 		}
+	}
 
-		self.cached_search_results = None;
+	#[test]
+	fn test_run_maintenance() {
+		let (mut engine, db_path) = make_test_engine("run_maintenance");
+		let mut images: Vec<IndexedImage> = (0..20).map(|i| make_test_image(&format!("img_{}.png", i), i)).collect();
+		images.iter_mut().for_each(|img| img.thumbnail = vec![7u8; 20_000]);
+		add_test_images(&mut engine, images);
+		engine.connection.lock().execute("DELETE FROM images", []).unwrap();
 
-		let debug_start_db_query = Instant::now();
-		let conn = self.connection.lock();
-		let mut stmt = conn.prepare(&format!(r#"
-			SELECT {}, semantic_hashes.hash, cosine_distance(?, semantic_hashes.hash) AS dist
-			FROM semantic_hashes
-			INNER JOIN images images ON images.id = semantic_hashes.image_id
-			WHERE dist < ?
-			ORDER BY dist ASC
-			LIMIT 100"#, SELECT_FIELDS
-		)).expect("The query for query_by_image_hash_from_image is wrong! The developer messed up!");
-		let img_cursor = stmt.query_map(params![indexed_image.visual_hash, self.max_distance_from_query], |row|{
-			let mut img = indexed_image_from_row(row).expect("Unable to unwrap result from database");
-			img.visual_hash = Some(row.get(6)?);
-			img.distance_from_query = Some(row.get(7)?);
-			Ok(img)
-		}).unwrap();
+		let wait_for_maintenance = |engine: &mut Engine| loop {
+			if let Some(result) = engine.poll_maintenance() {
+				return result.unwrap();
+			}
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		};
+		engine.start_maintenance().unwrap();
+		let report = wait_for_maintenance(&mut engine);
+		assert!(!engine.is_maintenance_running());
+		assert!(report.problems.is_empty(), "{:?}", report.problems);
+		assert!(report.bytes_reclaimed() > 20 * 20_000 / 2, "{:?}", report);
+		assert_eq!(report.bytes_after, std::fs::metadata(&db_path).unwrap().len());
 
-		self.cached_search_results = Some(img_cursor.flat_map(|item| item).collect());
-		let debug_end_db_query = Instant::now();
-		
-		let result_count = self.cached_search_results.as_ref().unwrap().len();
+		engine.start_maintenance().unwrap();
+		assert_eq!(wait_for_maintenance(&mut engine).bytes_reclaimed(), 0);
 
-		eprintln!("Time to search DB: {:?}  Results: {:?}", debug_end_db_query-debug_start_db_query, result_count);
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
 	}
 
-	pub fn get_query_results(&self) -> Option<Vec<IndexedImage>> {
-		self.cached_search_results.clone()
+	#[test]
+	fn test_index_archive() {
+		let (mut source, source_path) = make_test_engine("index_archive_source");
+		let mut tagged = make_test_image("img_0.png", 0);
+		tagged.tags.insert("Model".to_string(), "Canon".to_string());
+		add_test_images(&mut source, vec![tagged, make_test_image("img_1.png", 1), make_test_image("img_2.png", 2)]);
+		let ids: Vec<i64> = source.query_page(&"img sort:size".to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect();
+		source.set_rating(ids[1], 4).unwrap();
+		let best = source.create_collection("Best").unwrap();
+		source.add_to_collection(best, ids[1]).unwrap();
+		source.create_smart_collection("Small", "maxsize:0").unwrap();
+		source.connection.lock().execute("UPDATE images SET trashed = datetime('now') WHERE id = ?", [ids[2]]).unwrap();
+		let blip = source.register_embedding_model("blip", "base", 2).unwrap();
+		source.set_embedding(ids[1], blip, &[1.0, -1.0]).unwrap();
+
+		let archive_path = std::env::temp_dir().join(format!("pixelbox_test_index_archive_{}.db", std::process::id()));
+		let _ = std::fs::remove_file(&archive_path);
+		assert_eq!(source.export_index(&archive_path).unwrap(), 2); // Not the trashed one.
+		assert!(source.export_index(&archive_path).is_err()); // Never overwrites.
+
+		// Give the target an image first so the ids can't line up by accident.
+		let (mut target, target_path) = make_test_engine("index_archive_target");
+		add_test_images(&mut target, vec![make_test_image("other.png", 5)]);
+		target.register_embedding_model("nomic", "1", 2).unwrap();
+		assert!(target.import_index(&source_path).is_err()); // A DB, but not an archive.
+		assert_eq!(target.import_index(&archive_path).unwrap(), 2);
+		assert_eq!(target.import_index(&archive_path).unwrap(), 0); // Everything is already there.
+
+		let imported = target.query_page(&"img sort:size".to_string(), 0, 10).unwrap().results;
+		assert_eq!(imported.iter().map(|img| img.filename.as_str()).collect::<Vec<&str>>(), vec!["img_0.png", "img_1.png"]);
+		assert_ne!(imported[1].id, ids[1]);
+		assert_eq!(imported[0].tags.get("Model").map(|v| v.as_str()), Some("Canon"));
+		assert_eq!(imported[1].rating, 4);
+		assert_eq!(imported[0].palette, Some(vec![0u8; 15]));
+		let target_blip = target.register_embedding_model("blip", "base", 2).unwrap();
+		assert_ne!(target_blip, blip);
+		assert_eq!(target.get_embedding(imported[1].id, target_blip).unwrap(), Some(vec![1.0, -1.0]));
+
+		let collection_ids = |engine: &mut Engine, q: &str| -> Vec<i64> {
+			engine.query_page(&q.to_string(), 0, 10).unwrap().results.iter().map(|img| img.id).collect()
+		};
+		assert_eq!(collection_ids(&mut target, "collection:best"), vec![imported[1].id]);
+		assert_eq!(collection_ids(&mut target, "collection:small"), vec![imported[0].id]);
+
+		drop(source);
+		drop(target);
+		let _ = std::fs::remove_file(source_path);
+		let _ = std::fs::remove_file(target_path);
+		let _ = std::fs::remove_file(archive_path);
 	}
-	
-	pub fn clear_query_results(&mut self) { self.cached_search_results = None; }
 
-	pub fn add_tracked_folder(&mut self, folder_glob:String) {
-		{
-			self.connection.lock().execute("INSERT INTO watched_directories (glob) VALUES (?1)", params![folder_glob]).unwrap();
-		}
-		self.watched_directories_cache = None; // Invalidate cache.
-		self.get_tracked_folders();
+	#[test]
+	fn test_negated_terms() {
+		let (mut engine, db_path) = make_test_engine("negated_terms");
+		let mut tagged = make_test_image("tagged.png", 0);
+		tagged.tags.insert("Software".to_string(), "ThumbsMaker".to_string());
+		let mut cached = make_test_image("in_cache.png", 0);
+		cached.path = "/test/cache/in_cache.png".to_string();
+		add_test_images(&mut engine, vec![
+			make_test_image("cat.png", 0),
+			make_test_image("cat_screenshot.png", 0),
+			make_test_image("it's.png", 0),
+			tagged,
+			cached,
+		]);
+
+		let matching = |engine: &mut Engine, q: &str| {
+			let mut names: Vec<String> = engine.query_page(&q.to_string(), 0, 100).unwrap().results.into_iter().map(|img| img.filename).collect();
+			names.sort();
+			names
+		};
+		assert_eq!(matching(&mut engine, "cat -screenshot"), vec!["cat.png"]);
+		assert_eq!(matching(&mut engine, "png -thumbs -cache -cat"), vec!["it's.png"]);
+		assert_eq!(matching(&mut engine, "png -it's -thumbs -cache -cat"), Vec::<String>::new());
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
 	}
 
-	pub fn remove_tracked_folder(&mut self, folder_glob:String) {
-		{
-			self.connection.lock().execute("DELETE FROM watched_directories WHERE glob=?1", params![folder_glob]).unwrap();
-		}
-		self.watched_directories_cache = None; // Invalidate cache.
-		self.get_tracked_folders();
+	#[test]
+	fn test_layout_distance() {
+		let layout = vec![0u8, 0, 255, 0, 255, 0]; // Blue over green.
+		assert_eq!(layout_distance(&vec![0, 0, 255, 255, 0, 255, 0, 255], &layout), 0.0);
+		assert_eq!(layout_distance(&vec![0, 0, 255, 255, 255, 0, 0, 0], &layout), 0.0); // Bottom is "don't care".
+		assert_eq!(layout_distance(&vec![0, 0, 0, 0, 0, 0, 0, 0], &layout), 0.0); // Nothing painted.
+		assert!(layout_distance(&vec![0, 255, 0, 255, 0, 0, 255, 255], &layout) > 0.5); // Upside down.
+		assert_eq!(layout_distance(&vec![0, 0, 255, 255], &layout), 1.0); // Mismatched sizes.
 	}
 
-	pub fn get_tracked_folders(&mut self) -> &Vec<String> {
-		if self.watched_directories_cache.is_none() {
-			let conn = self.connection.lock();
-			let mut stmt = conn.prepare("SELECT glob FROM watched_directories").unwrap();
-			let glob_cursor = stmt.query_map([], |row|{
-				let dir:String = row.get(0)?;
-				Ok(dir)
-			}).unwrap();
+	#[test]
+	fn test_query_by_color_layout() {
+		let (mut engine, db_path) = make_test_engine("query_by_color_layout");
+		let cells = 8 * 8;
+		let mut landscape = make_test_image("landscape.png", 0);
+		landscape.color_layout = Some((0..cells).flat_map(|i| if i < cells / 2 { [0u8, 0, 255] } else { [0u8, 255, 0] }).collect());
+		let mut upside_down = make_test_image("upside_down.png", 0);
+		upside_down.color_layout = Some((0..cells).flat_map(|i| if i < cells / 2 { [0u8, 255, 0] } else { [0u8, 0, 255] }).collect());
+		add_test_images(&mut engine, vec![upside_down, landscape]);
 
-			let all_globs:Vec<String> = glob_cursor.map(|item|{
-				item.unwrap()
-			}).collect();
+		// Only paint the top row blue.
+		let sketch: Vec<u8> = (0..cells).flat_map(|i| if i < 8 { [0u8, 0, 255, 255] } else { [0u8, 0, 0, 0] }).collect();
+		engine.query_by_color_layout(&sketch).unwrap();
+		let results = engine.get_query_results().unwrap();
+		assert_eq!(results[0].filename, "landscape.png");
+		assert_eq!(results[0].distance_from_query, Some(0.0));
 
-			self.watched_directories_cache = Some(all_globs);
-		}
+		assert!(engine.query_by_color_layout(&vec![0u8; 4]).is_err());
 
-		if let Some(watched) = &self.watched_directories_cache {
-			watched
-		} else {
-			unreachable!()
-		}
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
 	}
-}
 
-// Query utility functions:
-fn tokenize_query(query: &String) -> Result<Vec<String>> {
-	let mut spans = vec![];
-	let mut next_character_escaped = false;
-	let mut quote_active = false;
-	let mut active_string = String::new(); // We accumulate into this, stopping at a space if not quoted or stopping at an end-quote if quoted.
-	for character in query.chars() {
-		if next_character_escaped {
-			active_string.push(character);
-			next_character_escaped = false;
-		} else {
-			match character {
-				'"' => {
-					// This double is NOT quoted, so we are either starting or finishing a quote.
-					if !quote_active { // We are starting.
-						quote_active = true;
-					} else { // We are finishing a quote.
-						quote_active = false;
-						spans.push(active_string);
-						active_string = String::new();
-					}
-				},
-				'\\' => {
-					// A backtick!
-					next_character_escaped = true;
-				},
-				' ' => {
-					// If we are in a quote, continue.  Otherwise break the word.
-					if quote_active {
-						active_string.push(' ');
-					} else {
-						// We are at a breakpoint, but if the active word is empty there's no sense in pushing it.
-						if !active_string.is_empty() {
-							spans.push(active_string);
-							active_string = String::new();
-						}
-					}
-				},
-				_ => active_string.push(character)
-			}
+	#[test]
+	fn test_near_duplicate_review() {
+		let (mut engine, db_path) = make_test_engine("near_duplicate_review");
+		add_test_images(&mut engine, vec![make_test_image("original.png", 0)]);
+
+		let copy = make_test_image("copy.png", 0);
+		let mut different = make_test_image("different.png", 0);
+		different.phash = Some(vec![0xFFu8; 32]);
+		{
+			let conn = engine.connection.lock();
+			assert!(!Engine::hold_if_near_duplicate(&conn, &copy, None).unwrap()); // Guard is off.
+			assert!(!Engine::hold_if_near_duplicate(&conn, &different, Some(DEFAULT_NEAR_DUPLICATE_DISTANCE)).unwrap());
+			assert!(Engine::hold_if_near_duplicate(&conn, &copy, Some(DEFAULT_NEAR_DUPLICATE_DISTANCE)).unwrap());
+			// Still held on the next crawl, even if the guard has been turned off since.
+			assert!(Engine::hold_if_near_duplicate(&conn, &copy, None).unwrap());
 		}
-	};
 
-	if quote_active {
-		return Err(anyhow!("String tokenization failed: trailing open-quote.".to_string()));
-	} else if next_character_escaped {
-		return Err(anyhow!("String tokenization failed: trailing escape character.".to_string()));
-	}
+		let held = engine.get_near_duplicates().unwrap();
+		assert_eq!(held.len(), 1);
+		assert_eq!(held[0].path, copy.path);
+		assert_eq!(held[0].existing.filename, "original.png");
+		assert_eq!(held[0].distance, 0.0);
 
-	// Push the last trailing active string into the spans.
-	if !active_string.is_empty() {
-		spans.push(active_string);
+		engine.resolve_near_duplicate(&copy.path, true).unwrap();
+		assert!(engine.get_near_duplicates().unwrap().is_empty());
+		assert!(!Engine::hold_if_near_duplicate(&engine.connection.lock(), &copy, Some(DEFAULT_NEAR_DUPLICATE_DISTANCE)).unwrap());
+		engine.resolve_near_duplicate(&copy.path, false).unwrap();
+		assert!(Engine::hold_if_near_duplicate(&engine.connection.lock(), &copy, None).unwrap());
+		assert!(engine.resolve_near_duplicate("not_held.png", true).is_err());
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
 	}
 
-	Ok(spans)
-}
+	#[test]
+	fn test_background_query() {
+		let (mut engine, db_path) = make_test_engine("background_query");
+		add_test_images(&mut engine, vec![make_test_image("cat.png", 0), make_test_image("dog.png", 0)]);
 
-fn build_where_clause_from_parsed_query(tokens: &Vec<String>, mut cached_similar_image: &mut Option<IndexedImage>) -> String {
-	// If there's a magic prefix like "similar", "filename", or a tag, add that to a 'where'.
-	// Otherwise, search all of the tags and exif data.
+		engine.start_query_page(&"cat".to_string(), 0, 10).unwrap();
+		wait_for_query(&mut engine);
+		assert!(engine.take_query_error().is_none());
+		assert_eq!(engine.get_query_result_count(), Some(1));
+		assert_eq!(engine.get_query_results().unwrap()[0].filename, "cat.png");
 
-	let mut and_where_clauses = vec![];
-	for token in tokens {
-		if let Some((magic_prefix, remaining)) = token.split_once(':') {
-			let magic_prefix = magic_prefix.to_string().to_lowercase();
-			// SPECIAL CASE FOR VISUAL SIMILARITY!
-			// I hate that this is separate and would like to clean up this method.
-			// It's kinda' a different modality of searching.
-			if magic_prefix.eq("similar") {
-				// If we already hashed this image and it is unchanged, don't recalculate.
-				let mut needs_recalculation = false;
-
-				// If there's no cached image, obviously we need to recalculate.
-				if cached_similar_image.is_none() {
-					needs_recalculation = true;
-				}
+		// A blocking query abandons the background one so stale results don't land afterwards.
+		engine.start_query_page(&"cat".to_string(), 0, 10).unwrap();
+		engine.query_page(&"dog".to_string(), 0, 10).unwrap();
+		assert!(!engine.is_query_running());
+		assert_eq!(engine.get_query_results().unwrap()[0].filename, "dog.png");
 
-				// If the cached image is different to the last one, we need to recalc.
-				if let Some(img) = cached_similar_image {
-					// TODO: For case-sensitive operating systems this might need to change.
-					if !img.path.eq_ignore_ascii_case(remaining) {
-						needs_recalculation = true;
-					}
-				}
+		// Bad queries fail right away.
+		assert!(engine.start_query_page(&"\"unterminated".to_string(), 0, 10).is_err());
 
-				if needs_recalculation {
-					let debug_start_load_image = Instant::now();
-					let indexed_image = IndexedImage::from_file_path(Path::new(remaining));
-					let debug_end_load_image = Instant::now();
-					eprintln!("Time to compute image hash: {:?}", debug_end_load_image - debug_start_load_image);
-					*cached_similar_image = indexed_image.ok();
-				}
-				
-				//and_where_clauses.push(format!("()"));
-				// TODO: Do we need to append this actually?
-			}
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
 
-			if magic_prefix.eq("exif") || magic_prefix.eq("tag") {
-				// Split the remaining into tag and target.
-				// If there's no ':' then search both.
-				if let Some((tag, target)) = remaining.split_once(":") {
-					and_where_clauses.push(format!("(tags.name LIKE '%{}%' AND tags.value LIKE '%{}%')", tag, target));
-				} else {
-					and_where_clauses.push(format!("(tags.name LIKE '%{}%' OR tags.value LIKE '%{}%')", &remaining, &remaining));
-				}
-			}
+	#[test]
+	fn test_rules() {
+		let (mut engine, db_path) = make_test_engine("rules");
+		let screenshot_rule = Rule {
+			name: "Downloaded screenshots".to_string(),
+			enabled: true,
+			folder_contains: "Downloads".to_string(),
+			file_type: Some(TypeFilter::Screenshot),
+			tag_name: "category".to_string(),
+			tag_value: "screenshot".to_string(),
+			..Default::default()
+		};
+		let camera_rule = Rule {
+			name: "Canon photos".to_string(),
+			enabled: true,
+			exif_name: "Model".to_string(),
+			exif_value: "Canon".to_string(),
+			tag_name: "camera".to_string(),
+			tag_value: "canon".to_string(),
+			collection_name: "Canon".to_string(),
+			..Default::default()
+		};
+		let screenshot_rule_id = engine.save_rule(&screenshot_rule).unwrap();
+		engine.save_rule(&camera_rule).unwrap();
+		let rules = engine.get_rules().unwrap();
+		assert_eq!(rules.len(), 2);
+		assert_eq!(rules[0].id, screenshot_rule_id);
+		assert_eq!(rules[0].file_type, Some(TypeFilter::Screenshot));
+		let canon = engine.get_collections().unwrap().into_iter().find(|c| c.name == "Canon").unwrap(); // Made by save_rule.
 
-			if magic_prefix.eq("all") {
-				// Search for this value in EVERY field.
-				// TODO: We should use '?', though it's not a security vulnerability because it's a strictly local DB.
-				and_where_clauses.push(format!(" (tags.value LIKE '%{}%' OR images.filename LIKE '%{}%' OR images.path LIKE '%{}%') ", &remaining, &remaining, &remaining));
-			}
+		let mut screenshot = make_test_image("Screenshot 2024.png", 0);
+		screenshot.path = "/home/me/Downloads/Screenshot 2024.png".to_string();
+		let mut photo = make_test_image("IMG_0001.jpg", 0);
+		photo.tags.insert("Model".to_string(), "\"Canon EOS R\"".to_string());
+		let (screenshot_id, photo_id) = {
+			let mut conn = engine.connection.lock();
+			let screenshot_id = Engine::insert_image(&mut conn, screenshot).unwrap();
+			let photo_id = Engine::insert_image(&mut conn, photo).unwrap();
+			assert!(rules[0].apply(&conn, screenshot_id).unwrap());
+			assert!(!rules[0].apply(&conn, screenshot_id).unwrap()); // Already tagged.
+			assert!(!rules[0].apply(&conn, photo_id).unwrap());
+			assert!(rules[1].apply(&conn, photo_id).unwrap());
+			assert!(!rules[1].apply(&conn, photo_id).unwrap()); // Already tagged and in the collection.
+			assert!(!rules[1].apply(&conn, screenshot_id).unwrap());
+			(screenshot_id, photo_id)
+		};
+		assert_eq!(engine.get_image_collections(photo_id).unwrap(), vec![canon.id]);
+		assert!(engine.get_image_collections(screenshot_id).unwrap().is_empty());
 
-			// We default to filename but want to handle the case where the person explicitly searches for it.
-			if magic_prefix.eq("filename") {
-				and_where_clauses.push(format!("images.filename LIKE '%{}%'", &token));
-			}
-		} else {
-			and_where_clauses.push(format!("images.filename LIKE '%{}%'", &token));
-		}
+		let mut disabled = rules[1].clone();
+		disabled.enabled = false;
+		engine.save_rule(&disabled).unwrap();
+		assert!(!engine.get_rules().unwrap()[1].enabled);
+
+		assert!(engine.save_rule(&Rule { name: "No action".to_string(), ..Default::default() }).is_err());
+		engine.create_smart_collection("Small", "maxsize:0").unwrap();
+		assert!(engine.save_rule(&Rule { name: "Into a smart collection".to_string(), collection_name: "small".to_string(), ..Default::default() }).is_err());
+		engine.delete_rule(screenshot_rule_id).unwrap();
+		assert_eq!(engine.get_rules().unwrap().len(), 1);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
 	}
 
-	and_where_clauses.join(" AND ")
-}
+	#[test]
+	fn test_export_import_config() {
+		let (mut source, source_path) = make_test_engine("export_config");
+		source.max_search_results = 42;
+		source.sort_order = SortOrder { field: SortField::FileSize, descending: true };
+		source.save_settings().unwrap();
+		source.save_rule(&Rule { name: "Tag everything".to_string(), enabled: true, tag_name: "new".to_string(), ..Default::default() }).unwrap();
+		source.save_search("Screenshots", "type:screenshot").unwrap();
+		source.create_collection("Only here").unwrap();
+		source.create_smart_collection("Wallpapers", "minsize:1MB").unwrap();
+		let config = source.export_config().unwrap();
 
-//
-// Distance Functions
-// Distance functions should return near zero for almost identical items and a large value for different ones.
-// All of these methods should take the encoded hash as a blob of u8's and return a single f32.
-//
-pub fn cosine_distance(hash_a:&Vec<u8>, hash_b:&Vec<u8>) -> f32 {
-	// Cosine Similarity -> 1.0 is most similar, -1.0 is most different.
-	// We want 0.0 is most similar.
-	let u8_to_float = |u8s: &[u8]| {
-		u8s.iter().map(|v| { ((*v as f32 / 255.0) * 2.0) - 1.0 }).collect::<Vec<f32>>()
-	};
-	let hash_a = u8_to_float(hash_a);
-	let hash_b = u8_to_float(hash_b);
-	let mag_op = |initial, x| { initial + x*x };
-	let magnitude = hash_a.iter().fold(0f32, mag_op).sqrt() * hash_b.iter().fold(0f32, mag_op).sqrt();
-	if magnitude < 1e-6 {
-		return 0.0;
+		let (mut destination, destination_path) = make_test_engine("import_config");
+		destination.save_rule(&Rule { name: "Tag everything".to_string(), enabled: true, tag_name: "old".to_string(), ..Default::default() }).unwrap();
+		destination.import_config(&config).unwrap();
+		assert_eq!(destination.max_search_results, 42);
+		assert_eq!(destination.sort_order, source.sort_order);
+		let rules = destination.get_rules().unwrap();
+		assert_eq!(rules.len(), 1); // Replaced by name rather than duplicated.
+		assert_eq!(rules[0].tag_name, "new");
+		assert_eq!(destination.get_saved_searches().unwrap()[0].query, "type:screenshot");
+		let collections = destination.get_collections().unwrap();
+		assert_eq!(collections.len(), 1); // Only smart collections carry over.
+		assert_eq!(collections[0].query.as_deref(), Some("minsize:1MB"));
+
+		// Settings survive reopening the destination.
+		drop(destination);
+		let destination = Engine::open_or_create(&destination_path, OpenMode::OpenExisting).unwrap();
+		assert_eq!(destination.max_search_results, 42);
+
+		assert!(Engine::open_or_create(&source_path, OpenMode::OpenExisting).unwrap().import_config(&serde_json::json!([1, 2])).is_err());
+
+		drop(source);
+		drop(destination);
+		let _ = std::fs::remove_file(source_path);
+		let _ = std::fs::remove_file(destination_path);
 	}
-	let dot = hash_a.iter().zip(&hash_b).fold(0f32, |initial, (&a, &b)| { initial + (a*b) });
-	let cosine_similarity = dot / magnitude;
-	(1.0 / cosine_similarity.max(1e-6)) - 1.0
-}
 
-pub fn byte_distance(hash_a:&Vec<u8>, hash_b:&Vec<u8>) -> f32 {
-	hash_a.iter().zip(hash_b).fold(0f32, |init, (&a, &b)|{init + (a as f32 - b as f32).abs()}) / (255f32 * hash_a.len() as f32)
-}
+	#[test]
+	fn test_saved_searches() {
+		let (mut engine, db_path) = make_test_engine("saved_searches");
+		engine.save_search("Big photos", "type:photo minsize:10MB").unwrap();
+		engine.save_search("anime", "tag:anime").unwrap();
+		engine.save_search("Big photos", "type:photo minsize:20MB").unwrap(); // Replaces.
+		assert!(engine.save_search("  ", "cat").is_err());
 
-pub fn hamming_distance(hash_a:&Vec<u8>, hash_b:&Vec<u8>) -> f32 {
-	hash_a.iter().zip(hash_b).map(|(&a, &b)|{
-		let mut diff = a ^ b;
-		let mut bits_set = 0;
-		while diff != 0 {
-			bits_set += diff & 1;
-			diff >>= 1;
-		}
-		bits_set
-	}).sum::<u8>() as f32 / (8f32 * hash_a.len() as f32)
-}
+		let saved = engine.get_saved_searches().unwrap();
+		assert_eq!(saved.iter().map(|s| s.name.as_str()).collect::<Vec<&str>>(), vec!["anime", "Big photos"]);
+		assert_eq!(saved[1].query, "type:photo minsize:20MB");
 
-// Add all the wrappers to the SQLite functions so we can use them in the database.
+		engine.rename_saved_search("anime", "Anime").unwrap();
+		assert!(engine.rename_saved_search("missing", "whatever").is_err());
+		assert!(engine.rename_saved_search("Anime", "Big photos").is_err()); // Names are unique.
+		engine.delete_saved_search("Big photos").unwrap();
+		assert_eq!(engine.get_saved_searches().unwrap(), vec![SavedSearch { name: "Anime".to_string(), query: "tag:anime".to_string() }]);
 
-fn make_cosine_distance_db_function(db: &mut Connection) -> SQLResult<()> {
-	db.create_scalar_function(
-		"cosine_distance",
-		2,
-		FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
-		move |ctx| {
-			let dist = {
-				let lhs = ctx.get_raw(0).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
-				let rhs = ctx.get_raw(1).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
-				cosine_distance(&lhs.to_vec(), &rhs.to_vec())
-			};
-			Ok(dist as f64)
-		}
-	)
-}
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
 
-fn make_byte_distance_db_function(db: &mut Connection) -> SQLResult<()> {
-	db.create_scalar_function(
-		"byte_distance",
-		2,
-		FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
-		move |ctx| {
-			let dist = {
-				let lhs = ctx.get_raw(0).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
-				let rhs = ctx.get_raw(1).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
-				byte_distance(&lhs.to_vec(), &rhs.to_vec())
-			};
-			Ok(dist as f64)
+	#[test]
+	fn test_search_history() {
+		let (mut engine, db_path) = make_test_engine("search_history");
+		add_test_images(&mut engine, vec![make_test_image("cat.png", 0)]);
+
+		// Typing one letter at a time leaves only the finished query.
+		for query in ["ca", "cat", "cat type:photo"] {
+			engine.query_page(&query.to_string(), 0, 10).unwrap();
 		}
-	)
-}
+		engine.query_page(&"dog".to_string(), 0, 10).unwrap();
+		engine.query_page(&"dog".to_string(), 1, 10).unwrap(); // Paging doesn't count.
+		std::thread::sleep(std::time::Duration::from_millis(5));
+		engine.query_page(&" cat type:photo ".to_string(), 0, 10).unwrap();
 
-fn make_hamming_distance_db_function(db: &mut Connection) -> SQLResult<()> {
-	//fn make_hamming_distance_db_function(db: &Connection) -> Result<()> {
-	db.create_scalar_function(
-		"hamming_distance",
-		2,
-		FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
-		move |ctx| {
-			assert_eq!(ctx.len(), 2, "Called with incorrect number of arguments");
-			/*
-			let base_blob: Arc<Vec<u8>> = ctx
-				.get_or_create_aux(0, |vr| -> Result<_, BoxError> {
-					Ok(Vec::new(vr.as_blob()?)?)
-				})?;
-			*/
-			// This repeatedly grabs and regenerates the LHS.  We should change it.
-			let distance = {
-				let lhs = ctx.get_raw(0).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
-				let rhs = ctx.get_raw(1).as_blob().map_err(|e| SQLError::UserFunctionError(e.into()))?;
-				hamming_distance(&lhs.to_vec(), &rhs.to_vec())
-			};
-			Ok(distance as f64)
-		},
-	)
-}
+		let recent = engine.recent_queries(10).unwrap();
+		assert_eq!(recent.iter().map(|q| q.query.as_str()).collect::<Vec<&str>>(), vec!["cat type:photo", "dog"]);
+		assert_eq!(recent[0].run_count, 2);
+		assert_eq!(recent[1].run_count, 1);
+		assert_eq!(engine.recent_queries(1).unwrap().len(), 1);
 
-// End Distance Functions
+		// A repeated prefix isn't a one-off, so it's kept.
+		engine.query_page(&"dog".to_string(), 0, 10).unwrap();
+		engine.query_page(&"dogs".to_string(), 0, 10).unwrap();
+		assert_eq!(engine.recent_queries(10).unwrap().len(), 3);
 
-#[cfg(test)]
-mod tests {
-	use crate::engine::hamming_distance;
-	use crate::engine::cosine_distance;
-	use crate::engine::tokenize_query;
+		engine.clear_search_history().unwrap();
+		assert!(engine.recent_queries(10).unwrap().is_empty());
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
 
 	#[test]
-	fn test_tokenize_query() {
-		let mut tokens;
+	fn test_user_tags() {
+		let (mut engine, db_path) = make_test_engine("user_tags");
+		let mut photo = make_test_image("photo.jpg", 0);
+		photo.tags.insert("Model".to_string(), "Canon".to_string());
+		add_test_images(&mut engine, vec![photo, make_test_image("other.jpg", 0)]);
+		let photo_id = engine.query_page(&"photo".to_string(), 0, 10).unwrap().results[0].id;
 
-		tokens = tokenize_query(&"abc".to_string()).unwrap();
-		assert_eq!(tokens, vec!["abc".to_string()]);
+		engine.set_user_tag(photo_id, "person", "alice").unwrap();
+		engine.set_user_tag(photo_id, "event", "birthday").unwrap();
+		engine.set_user_tag(photo_id, "person", "bob").unwrap(); // Replaces.
+		assert!(engine.set_user_tag(photo_id, " ", "nameless").is_err());
+		assert_eq!(engine.get_user_tags(photo_id).unwrap(), vec![
+			("event".to_string(), "birthday".to_string()),
+			("person".to_string(), "bob".to_string()),
+		]);
 
-		tokens = tokenize_query(&"abc def".to_string()).unwrap();
-		assert_eq!(tokens, vec!["abc".to_string(), "def".to_string()]);
+		// EXIF tags can't be edited as user tags, and removing a user tag leaves EXIF tags with the same name.
+		assert!(engine.edit_user_tag(photo_id, "Model", "Model", "Nikon").is_err());
+		engine.set_user_tag(photo_id, "Model", "My camera").unwrap();
+		engine.remove_user_tag(photo_id, "Model").unwrap();
+		let page = engine.query_page(&"tag:Model:Canon".to_string(), 0, 10).unwrap();
+		assert_eq!(page.total_results, 1);
 
-		tokens = tokenize_query(&r#"abc "def ghi""#.to_string()).unwrap();
-		assert_eq!(tokens, vec!["abc".to_string(), "def ghi".to_string()]);
+		// tag: searches user tags too.
+		engine.edit_user_tag(photo_id, "person", "people", "bob").unwrap();
+		let page = engine.query_page(&"tag:people:bob".to_string(), 0, 10).unwrap();
+		assert_eq!(page.results.len(), 1);
+		assert_eq!(page.results[0].id, photo_id);
+		assert_eq!(engine.query_page(&"tag:person".to_string(), 0, 10).unwrap().total_results, 0);
 
-		tokens = tokenize_query(&r#"abc \"def ghi\""#.to_string()).unwrap();
-		assert_eq!(tokens, vec!["abc".to_string(), "\"def".to_string(), "ghi\"".to_string()]);
+		// Cached results follow edits.
+		engine.query_page(&"photo".to_string(), 0, 10).unwrap();
+		engine.set_user_tag(photo_id, "mood", "happy").unwrap();
+		assert_eq!(engine.get_query_results().unwrap()[0].tags.get("mood"), Some(&"happy".to_string()));
 
-		tokens = tokenize_query(&r#""the human torch was denied a bank loan" "the \"human torch\"""#.to_string()).unwrap();
-		assert_eq!(tokens, vec!["the human torch was denied a bank loan".to_string(), "the \"human torch\"".to_string()]);
+		// A user tag named like an EXIF tag is kept beside it, and removing it leaves the EXIF one.
+		engine.set_user_tag(photo_id, "Model", "My camera").unwrap();
+		let tags = &engine.get_query_results().unwrap()[0].tags;
+		assert_eq!(tags.get("Model"), Some(&"Canon".to_string()));
+		assert_eq!(tags.get("user:Model"), Some(&"My camera".to_string()));
+		let page = engine.query_page(&"photo".to_string(), 0, 10).unwrap();
+		assert_eq!(page.results[0].tags.get("Model"), Some(&"Canon".to_string()));
+		assert_eq!(page.results[0].tags.get("user:Model"), Some(&"My camera".to_string()));
+		engine.remove_user_tag(photo_id, "Model").unwrap();
+		let tags = &engine.get_query_results().unwrap()[0].tags;
+		assert_eq!(tags.get("Model"), Some(&"Canon".to_string()));
+		assert_eq!(tags.get("user:Model"), None);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
 	}
 
 	#[test]
@@ -720,6 +7693,10 @@ mod tests {
 		assert_eq!(hamming_distance(&vec![0b10101010u8], &vec![0b01010101u8]), 1f32);
 		assert_eq!(hamming_distance(&vec![0b10101010u8, 0b01010101u8], &vec![0b01010101u8, 0b10101010u8]), 1f32);
 		assert_eq!(hamming_distance(&vec![0xFFu8, 0x0Fu8], &vec![0x0Fu8, 0x0Fu8]), 0.25f32); // 4 bits are different.
+		assert_eq!(hamming_distance(&vec![0u8; 32], &vec![0xFFu8; 32]), 1f32); // More differing bits than fit in a u8.
+		assert_eq!(hamming_distance(&[0xFFu8; 13], &[0x01u8; 13]), 7.0 / 8.0); // A whole word and some bytes after it.
+		assert_eq!(byte_distance(&[255u8; 37], &[0u8; 37]), 1.0);
+		assert_eq!(byte_distance(&[10u8; 37], &[20u8; 37]), 10.0 / 255.0);
 	}
 	
 	#[test]
@@ -728,4 +7705,112 @@ mod tests {
 		assert!(cosine_distance(&vec![0, 255], &vec![0, 255]) < 1e-6f32);
 		assert!(cosine_distance(&vec![255, 0], &vec![0, 255]) > 2.0f32);
 	}
+
+	#[test]
+	fn test_distance_functions_in_sql() {
+		// The query side is cached for the statement, so every row should still be compared to it, and a new statement should get a new query.
+		let mut conn = Connection::open_in_memory().unwrap();
+		make_hamming_distance_db_function(&mut conn).unwrap();
+		make_cosine_distance_db_function(&mut conn).unwrap();
+		let rows: Vec<Vec<u8>> = vec![vec![0, 255], vec![255, 0], vec![200, 10], vec![0, 0]];
+		conn.execute("CREATE TABLE hashes (hash BLOB)", []).unwrap();
+		for row in &rows {
+			conn.execute("INSERT INTO hashes (hash) VALUES (?)", params![row]).unwrap();
+		}
+		for query in [vec![255u8, 0], vec![15u8, 240]] {
+			let mut stmt = conn.prepare("SELECT hamming_distance(?1, hash), cosine_distance(?1, hash) FROM hashes ORDER BY rowid").unwrap();
+			let distances: Vec<(f64, f64)> = stmt.query_map(params![query], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(|r| r.unwrap()).collect();
+			let expected: Vec<(f64, f64)> = rows.iter().map(|row| (hamming_distance(&query, row) as f64, cosine_distance(&query, row) as f64)).collect();
+			assert_eq!(distances, expected);
+		}
+	}
+
+	// Hashes are compared with whichever distance function fits, so they all need to behave like distances.
+	fn hash_pair() -> impl Strategy<Value = (Vec<u8>, Vec<u8>)> {
+		(1usize..64).prop_flat_map(|len| (vec(any::<u8>(), len), vec(any::<u8>(), len)))
+	}
+
+	proptest! {
+		#[test]
+		fn prop_hamming_distance((a, b) in hash_pair(), flip_byte in any::<prop::sample::Index>(), flip_bit in 0u8..8) {
+			let d = hamming_distance(&a, &b);
+			prop_assert_eq!(hamming_distance(&a, &a), 0.0);
+			prop_assert_eq!(d, hamming_distance(&b, &a));
+			prop_assert!((0.0..=1.0).contains(&d));
+
+			// Flipping one more bit of b changes the distance by exactly one bit.
+			let mut c = b.clone();
+			let idx = flip_byte.index(c.len());
+			c[idx] ^= 1 << flip_bit;
+			let bit = 1.0 / (8.0 * a.len() as f32);
+			prop_assert!(((hamming_distance(&a, &c) - d).abs() - bit).abs() < 1e-6);
+		}
+
+		#[test]
+		fn prop_byte_distance((a, b) in hash_pair(), nudge_byte in any::<prop::sample::Index>()) {
+			let d = byte_distance(&a, &b);
+			prop_assert_eq!(byte_distance(&a, &a), 0.0);
+			prop_assert_eq!(d, byte_distance(&b, &a));
+			prop_assert!((0.0..=1.0).contains(&d));
+
+			// Moving a byte of b further from a never brings b closer.
+			let mut c = b.clone();
+			let idx = nudge_byte.index(c.len());
+			c[idx] = if c[idx] >= a[idx] { c[idx].saturating_add(1) } else { c[idx].saturating_sub(1) };
+			prop_assert!(byte_distance(&a, &c) >= d);
+		}
+
+		#[test]
+		fn prop_cosine_distance((a, b) in hash_pair()) {
+			let d = cosine_distance(&a, &b);
+			prop_assert!(d >= 0.0, "Negative distance {} for {:?} and {:?}", d, a, b);
+			prop_assert!(d.is_finite());
+			prop_assert!(cosine_distance(&a, &a) < 1e-5);
+			prop_assert_eq!(d, cosine_distance(&b, &a));
+		}
+
+		#[test]
+		fn prop_cosine_distance_matches_dequantized((a, b) in hash_pair()) {
+			// The unrolled version should agree with doing it the obvious way.
+			let (a_values, b_values) = (dequantize_embedding(&a), dequantize_embedding(&b));
+			let dot: f32 = a_values.iter().zip(&b_values).map(|(x, y)| x * y).sum();
+			let magnitude = a_values.iter().map(|x| x * x).sum::<f32>().sqrt() * b_values.iter().map(|x| x * x).sum::<f32>().sqrt();
+			prop_assume!(magnitude > 1e-3);
+			let expected = 1.0 / (dot / magnitude).clamp(1e-6, 1.0) - 1.0;
+			prop_assume!(expected < 100.0);
+			let d = cosine_distance(&a, &b);
+			prop_assert!((d - expected).abs() <= 1e-3 * (1.0 + expected), "{} vs {}", d, expected);
+		}
+
+		#[test]
+		fn prop_mismatched_lengths(a in vec(any::<u8>(), 0..32), b in vec(any::<u8>(), 0..32)) {
+			prop_assume!(a.len() != b.len());
+			prop_assert_eq!(hamming_distance(&a, &b), 1.0);
+			prop_assert_eq!(byte_distance(&a, &b), 1.0);
+		}
+
+		#[test]
+		fn prop_palette_distance(color in vec(any::<u8>(), 3), palette in vec(any::<[u8; 3]>(), 0..10)) {
+			let palette = palette.concat();
+			let d = palette_distance(&color, &palette);
+			prop_assert!((0.0..=1.0).contains(&d));
+
+			// Adding the color to the palette makes it an exact match.
+			let mut with_color = palette.clone();
+			with_color.extend(&color);
+			prop_assert_eq!(palette_distance(&color, &with_color), 0.0);
+			// Adding more colors can only bring the closest one closer.
+			prop_assert!(palette_distance(&color, &with_color) <= d);
+		}
+
+		#[test]
+		fn prop_layout_distance(layout in vec(any::<u8>(), 3 * 16), alphas in vec(any::<bool>(), 16), sketch_colors in vec(any::<u8>(), 3 * 16)) {
+			let as_sketch = |colors: &[u8]| -> Vec<u8> {
+				colors.chunks_exact(3).zip(&alphas).flat_map(|(c, &painted)| [c[0], c[1], c[2], if painted { 255 } else { 0 }]).collect()
+			};
+			prop_assert_eq!(layout_distance(&as_sketch(&layout), &layout), 0.0);
+			let d = layout_distance(&as_sketch(&sketch_colors), &layout);
+			prop_assert!((0.0..=1.0).contains(&d));
+		}
+	}
 }
\ No newline at end of file
@@ -0,0 +1,187 @@
+// A small C API so other languages can open a database and search it without going through the UI.
+// Results come back as JSON strings, which must be handed back to pixelbox_free_string.
+// Panics are caught here because unwinding into C is undefined behavior.
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value as JSONValue};
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+use crate::engine::{Engine, OpenMode};
+use crate::indexed_image::IndexedImage;
+
+/// Open an existing database.  Returns null if it doesn't exist or can't be opened.
+///
+/// # Safety
+/// db_path must be null or a NUL-terminated string.  The engine must be closed with pixelbox_close.
+#[no_mangle]
+pub unsafe extern "C" fn pixelbox_open(db_path: *const c_char) -> *mut Engine {
+	let path = match c_str(db_path) {
+		Ok(path) => path,
+		Err(_) => return ptr::null_mut(),
+	};
+	match catch_unwind(|| Engine::open_or_create(Path::new(&path), OpenMode::OpenExisting)) {
+		Ok(Ok(engine)) => Box::into_raw(Box::new(engine)),
+		Ok(Err(e)) => {
+			eprintln!("{}", e);
+			ptr::null_mut()
+		},
+		Err(_) => ptr::null_mut(),
+	}
+}
+
+/// Waits for images that were already loaded by indexing to be stored.
+///
+/// # Safety
+/// engine must be null or from pixelbox_open, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pixelbox_close(engine: *mut Engine) {
+	if !engine.is_null() {
+		if let Err(e) = Box::from_raw(engine).close() {
+			eprintln!("Failed to close the DB: {}", e);
+		}
+	}
+}
+
+/// Run a search using the same query language as the search box.
+/// Returns {"total_results": n, "results": [...]} or {"error": "..."}.
+///
+/// # Safety
+/// engine must be from pixelbox_open and query must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pixelbox_query_json(engine: *mut Engine, query: *const c_char) -> *mut c_char {
+	json_response(|| {
+		let engine = engine_ref(engine)?;
+		let query = c_str(query)?;
+		let page = engine.query_page(&query, 0, engine.max_search_results)?;
+		Ok(results_json(page.total_results, &page.results))
+	})
+}
+
+/// Find images that look like the one at image_path.  The image doesn't need to be indexed.
+/// Returns the same JSON as pixelbox_query_json.
+///
+/// # Safety
+/// engine must be from pixelbox_open and image_path must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pixelbox_similar_file(engine: *mut Engine, image_path: *const c_char) -> *mut c_char {
+	json_response(|| {
+		let engine = engine_ref(engine)?;
+		let image_path = c_str(image_path)?;
+		let image = IndexedImage::from_file_path(Path::new(&image_path))?;
+		if image.visual_hash.is_none() {
+			return Err(anyhow!("Unable to compute a visual hash for {}.", image_path));
+		}
+		engine.query_by_image_hash_from_image(&image)?;
+		let results = engine.get_query_results().unwrap_or_default();
+		Ok(results_json(results.len() as u64, &results))
+	})
+}
+
+/// Start indexing the tracked folders in the background.  Watch it with pixelbox_indexing_status_json.
+///
+/// # Safety
+/// engine must be null or from pixelbox_open.
+#[no_mangle]
+pub unsafe extern "C" fn pixelbox_start_reindexing(engine: *mut Engine) {
+	if let Ok(engine) = engine_ref(engine) {
+		if let Err(e) = engine.start_reindexing() {
+			eprintln!("Failed to start indexing: {}", e);
+		}
+	}
+}
+
+/// Returns {"active", "paused", "stopping", "progress", "details", "num_indexed", "num_pending", "last_indexed", "unreachable"} or {"error": "..."}.
+/// details is null until something has been indexed, and has the counts and "eta_seconds" from IndexingProgress otherwise.
+/// unreachable lists the folders the last run skipped because they couldn't be reached.  They're tried again next time.
+/// Cheap enough to call every frame or from a timer.
+///
+/// # Safety
+/// engine must be from pixelbox_open.
+#[no_mangle]
+pub unsafe extern "C" fn pixelbox_indexing_status_json(engine: *mut Engine) -> *mut c_char {
+	json_response(|| Ok(engine_ref(engine)?.get_indexing_status().to_json()))
+}
+
+/// # Safety
+/// text must be null or a string returned by one of the functions above, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pixelbox_free_string(text: *mut c_char) {
+	if !text.is_null() {
+		drop(CString::from_raw(text));
+	}
+}
+
+unsafe fn c_str(text: *const c_char) -> Result<String> {
+	if text.is_null() {
+		return Err(anyhow!("Expected a string but got a null pointer."));
+	}
+	Ok(CStr::from_ptr(text).to_str()?.to_string())
+}
+
+unsafe fn engine_ref<'a>(engine: *mut Engine) -> Result<&'a mut Engine> {
+	engine.as_mut().ok_or_else(|| anyhow!("Expected an engine from pixelbox_open but got a null pointer."))
+}
+
+fn results_json(total_results: u64, results: &[IndexedImage]) -> JSONValue {
+	json!({
+		"total_results": total_results,
+		"results": results.iter().map(IndexedImage::summary_json).collect::<Vec<JSONValue>>(),
+	})
+}
+
+fn json_response(run: impl FnOnce() -> Result<JSONValue>) -> *mut c_char {
+	let response = match catch_unwind(AssertUnwindSafe(run)) {
+		Ok(Ok(value)) => value,
+		Ok(Err(e)) => json!({"error": e.to_string()}),
+		Err(_) => json!({"error": "PixelBox panicked.  See stderr for details."}),
+	};
+	// serde_json escapes control characters, so there's never a NUL in the middle.
+	CString::new(response.to_string()).map(CString::into_raw).unwrap_or(ptr::null_mut())
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::engine::{Engine, OpenMode};
+	use crate::ffi::*;
+
+	fn call_json(response: *mut c_char) -> JSONValue {
+		assert!(!response.is_null());
+		let value = serde_json::from_str(unsafe { CStr::from_ptr(response) }.to_str().unwrap()).unwrap();
+		unsafe { pixelbox_free_string(response) };
+		value
+	}
+
+	#[test]
+	fn test_ffi_round_trip() {
+		let db_path = std::env::temp_dir().join("pixelbox_test_ffi.db");
+		let _ = std::fs::remove_file(&db_path);
+		let missing = CString::new(db_path.to_str().unwrap()).unwrap();
+		assert!(unsafe { pixelbox_open(missing.as_ptr()) }.is_null());
+
+		drop(Engine::open_or_create(&db_path, OpenMode::CreateNew).unwrap());
+		let engine = unsafe { pixelbox_open(missing.as_ptr()) };
+		assert!(!engine.is_null());
+
+		let query = CString::new("cat sort:size").unwrap();
+		let response = call_json(unsafe { pixelbox_query_json(engine, query.as_ptr()) });
+		assert_eq!(response["total_results"], 0);
+		assert!(response["results"].as_array().unwrap().is_empty());
+
+		let bad_query = CString::new("type:nonsense").unwrap();
+		assert!(call_json(unsafe { pixelbox_query_json(engine, bad_query.as_ptr()) })["error"].is_string());
+		assert!(call_json(unsafe { pixelbox_query_json(ptr::null_mut(), query.as_ptr()) })["error"].is_string());
+
+		let status = call_json(unsafe { pixelbox_indexing_status_json(engine) });
+		assert_eq!(status["active"], false);
+		assert_eq!(status["num_pending"], 0);
+
+		let no_image = CString::new("/no/such/image.png").unwrap();
+		assert!(call_json(unsafe { pixelbox_similar_file(engine, no_image.as_ptr()) })["error"].is_string());
+
+		unsafe { pixelbox_close(engine) };
+		let _ = std::fs::remove_file(&db_path);
+	}
+}
@@ -0,0 +1,124 @@
+// Fonts are indexed by a specimen rendered from them, so a font collection can be searched by how the letters look.
+// TrueType, OpenType, and collections (where only the first font is shown) are supported.  WOFF isn't.
+
+use ab_glyph::{point, Font, FontRef, GlyphId, PxScale, ScaleFont};
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, GrayImage, Luma};
+use std::collections::HashMap;
+
+const FONT_MAGICS: [&[u8]; 4] = [&[0, 1, 0, 0], b"true", b"OTTO", b"ttcf"];
+const SPECIMEN_SIZE: (u32, u32) = (768, 288);
+const SPECIMEN_MARGIN: f32 = 16.0;
+// Each line and its height in pixels.
+const SPECIMEN_LINES: [(&'static str, f32); 3] = [
+	("Aa Bb Gg Qq Rr", 120.0),
+	("The quick brown fox jumps over the lazy dog.", 40.0),
+	("ABCDEFGHIJKLM 0123456789 &?!", 40.0),
+];
+
+/// What the font calls itself, from its name table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FontInfo {
+	pub family: String,
+	pub style: String, // Like "Bold Italic".
+	pub weight: u16, // 100 to 900.  400 is regular.
+	pub monospaced: bool,
+	pub glyph_count: u16,
+}
+
+impl FontInfo {
+	/// Tags to index the font under, like FontFamily:Lobster.
+	pub fn to_tags(&self) -> HashMap<String, String> {
+		let mut tags = HashMap::new();
+		tags.insert("FontFamily".to_string(), self.family.clone());
+		tags.insert("FontStyle".to_string(), self.style.clone());
+		tags.insert("FontWeight".to_string(), self.weight.to_string());
+		tags.insert("GlyphCount".to_string(), self.glyph_count.to_string());
+		if self.monospaced {
+			tags.insert("Monospaced".to_string(), "true".to_string());
+		}
+		tags
+	}
+}
+
+pub fn is_font(bytes: &[u8]) -> bool {
+	FONT_MAGICS.iter().any(|magic| bytes.starts_with(magic))
+}
+
+pub fn read_font_info(bytes: &[u8]) -> Result<FontInfo> {
+	let face = ttf_parser::Face::parse(bytes, 0).map_err(|e| anyhow!("Unable to read font: {}", e))?;
+	// The typographic names group a family's many weights together, where fonts have them.
+	let name = |ids: [u16; 2]| ids.iter().find_map(|&id| {
+		face.names().into_iter().filter(|n| n.name_id == id).find_map(|n| n.to_string())
+	});
+	Ok(FontInfo {
+		family: name([ttf_parser::name_id::TYPOGRAPHIC_FAMILY, ttf_parser::name_id::FAMILY]).unwrap_or_default(),
+		style: name([ttf_parser::name_id::TYPOGRAPHIC_SUBFAMILY, ttf_parser::name_id::SUBFAMILY]).unwrap_or_default(),
+		weight: face.weight().to_number(),
+		monospaced: face.is_monospaced(),
+		glyph_count: face.number_of_glyphs(),
+	})
+}
+
+/// Black text on white showing off the font's letters.
+pub fn render_specimen(bytes: &[u8]) -> Result<DynamicImage> {
+	let font = FontRef::try_from_slice(bytes).map_err(|e| anyhow!("Unable to read font: {}", e))?;
+	let mut canvas = GrayImage::from_pixel(SPECIMEN_SIZE.0, SPECIMEN_SIZE.1, Luma([255]));
+
+	// Symbol and non-Latin fonts would be a row of empty boxes, so they show their first glyphs instead.
+	let has_latin = "Aa".chars().all(|c| font.glyph_id(c) != GlyphId(0));
+	let mut top = SPECIMEN_MARGIN;
+	for (line_idx, (text, height)) in SPECIMEN_LINES.iter().enumerate() {
+		let glyphs: Vec<GlyphId> = if has_latin {
+			text.chars().map(|c| font.glyph_id(c)).collect()
+		} else {
+			let first = line_idx * 32 + 1;
+			(first..first + 32).filter(|&id| id < font.glyph_count()).map(|id| GlyphId(id as u16)).collect()
+		};
+		let scaled = font.as_scaled(PxScale::from(*height));
+		let baseline = top + scaled.ascent();
+		draw_glyphs(&mut canvas, &font, &glyphs, *height, baseline);
+		top = baseline - scaled.descent() + scaled.line_gap();
+	}
+	Ok(DynamicImage::ImageLuma8(canvas))
+}
+
+/// Draw a line of glyphs from the left margin.  Anything past the right edge is cut off.
+fn draw_glyphs(canvas: &mut GrayImage, font: &FontRef, glyphs: &[GlyphId], height: f32, baseline: f32) {
+	let scaled = font.as_scaled(PxScale::from(height));
+	let mut x = SPECIMEN_MARGIN;
+	let mut previous = None;
+	for &id in glyphs {
+		if let Some(previous) = previous {
+			x += scaled.kern(previous, id);
+		}
+		previous = Some(id);
+		let glyph = id.with_scale_and_position(height, point(x, baseline));
+		x += scaled.h_advance(id);
+		if let Some(outline) = font.outline_glyph(glyph) {
+			let bounds = outline.px_bounds();
+			outline.draw(|glyph_x, glyph_y, coverage| {
+				let (px, py) = (bounds.min.x as i64 + glyph_x as i64, bounds.min.y as i64 + glyph_y as i64);
+				if px >= 0 && py >= 0 && px < canvas.width() as i64 && py < canvas.height() as i64 {
+					let pixel = canvas.get_pixel_mut(px as u32, py as u32);
+					pixel.0[0] = pixel.0[0].min((255.0 * (1.0 - coverage.clamp(0.0, 1.0))) as u8);
+				}
+			});
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::font_specimen::*;
+
+	#[test]
+	fn test_not_a_font() {
+		assert!(is_font(b"OTTO\x00\x0a"));
+		assert!(is_font(&[0, 1, 0, 0, 0, 12]));
+		assert!(!is_font(b"\x89PNG"));
+		// Right magic, but nothing after it.
+		assert!(read_font_info(b"OTTO").is_err());
+		assert!(render_specimen(&[0, 1, 0, 0]).is_err());
+	}
+}
@@ -0,0 +1,26 @@
+use image::{DynamicImage, imageops::FilterType};
+
+pub const COLOR_LAYOUT_SIZE: usize = 8;
+
+/// A coarse map of where colors are in the image: the image squashed down to a tiny grid.
+/// Returns COLOR_LAYOUT_SIZE x COLOR_LAYOUT_SIZE RGB triples in row-major order.
+/// The aspect ratio is deliberately discarded so 'blue top, green bottom' matches any shape of image.
+pub fn color_layout(img:&DynamicImage) -> Vec<u8> {
+	img.resize_exact(COLOR_LAYOUT_SIZE as u32, COLOR_LAYOUT_SIZE as u32, FilterType::Triangle).to_rgb8().into_raw()
+}
+
+#[cfg(test)]
+mod test {
+	use image::{DynamicImage, Rgb, RgbImage};
+	use crate::image_hashes::color_layout::*;
+
+	#[test]
+	fn test_color_layout_halves() {
+		// Blue sky over green grass.
+		let img = RgbImage::from_fn(64, 32, |_x, y| { if y < 16 { Rgb([0u8, 0, 255]) } else { Rgb([0u8, 255, 0]) } });
+		let layout = color_layout(&DynamicImage::ImageRgb8(img));
+		assert_eq!(layout.len(), 3 * COLOR_LAYOUT_SIZE * COLOR_LAYOUT_SIZE);
+		assert_eq!(&layout[0..3], &[0u8, 0, 255]); // Top left.
+		assert_eq!(&layout[layout.len()-3..], &[0u8, 255, 0]); // Bottom right.
+	}
+}
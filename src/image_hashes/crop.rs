@@ -0,0 +1,73 @@
+use image::{DynamicImage, GenericImageView, Rgba};
+
+// Watermarks, captions, and logos mostly sit along the edges, so only the middle of the picture is kept.
+const CENTER_CROP_FRACTION: f32 = 0.8;
+// A row or column is border if almost all of it is within this much of the border color, per channel.
+const BORDER_TOLERANCE: u8 = 16;
+const BORDER_MIN_MATCHING: f32 = 0.98;
+// Never strip more than this much from any one side, so a picture with a plain background isn't cropped away to nothing.
+const MAX_BORDER_FRACTION: f32 = 0.25;
+
+/// The part of the image that's the picture itself: plain borders stripped, then the middle kept.
+/// Bordered, letterboxed, and watermarked copies of a picture look more alike after this than before.
+pub fn crop_to_content(img:&DynamicImage) -> DynamicImage {
+	let (left, top, right, bottom) = find_content_bounds(img);
+	let (width, height) = (right - left, bottom - top);
+	let crop_width = ((width as f32 * CENTER_CROP_FRACTION) as u32).max(1);
+	let crop_height = ((height as f32 * CENTER_CROP_FRACTION) as u32).max(1);
+	img.crop_imm(left + (width - crop_width) / 2, top + (height - crop_height) / 2, crop_width, crop_height)
+}
+
+/// Left, top, right, and bottom of what's inside the borders.  Right and bottom are exclusive.
+fn find_content_bounds(img:&DynamicImage) -> (u32, u32, u32, u32) {
+	let (width, height) = img.dimensions();
+	if width < 3 || height < 3 {
+		return (0, 0, width, height);
+	}
+	let border_color = img.get_pixel(0, 0);
+	let is_border = |pixels: &mut dyn Iterator<Item=Rgba<u8>>, count: u32| {
+		let matching = pixels.filter(|p| p.0.iter().zip(border_color.0.iter()).all(|(&a, &b)| a.abs_diff(b) <= BORDER_TOLERANCE)).count();
+		matching as f32 >= count as f32 * BORDER_MIN_MATCHING
+	};
+	let is_border_row = |y: u32| is_border(&mut (0..width).map(|x| img.get_pixel(x, y)), width);
+	let is_border_column = |x: u32| is_border(&mut (0..height).map(|y| img.get_pixel(x, y)), height);
+
+	let max_rows = (height as f32 * MAX_BORDER_FRACTION) as u32;
+	let max_columns = (width as f32 * MAX_BORDER_FRACTION) as u32;
+	let top = (0..max_rows).take_while(|&y| is_border_row(y)).count() as u32;
+	let bottom = height - (0..max_rows).take_while(|&y| is_border_row(height - 1 - y)).count() as u32;
+	let left = (0..max_columns).take_while(|&x| is_border_column(x)).count() as u32;
+	let right = width - (0..max_columns).take_while(|&x| is_border_column(width - 1 - x)).count() as u32;
+	(left, top, right, bottom)
+}
+
+#[cfg(test)]
+mod test {
+	use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+	use crate::image_hashes::crop::*;
+
+	fn make_picture(width: u32, height: u32) -> RgbaImage {
+		RgbaImage::from_fn(width, height, |x, y| Rgba([(x * 255 / width) as u8, (y * 255 / height) as u8, 128, 255]))
+	}
+
+	#[test]
+	fn test_crop_to_content() {
+		let picture = make_picture(100, 60);
+		// The same picture matted in white, with a logo in its corner.
+		let mut framed = RgbaImage::from_pixel(140, 100, Rgba([255, 255, 255, 255]));
+		image::imageops::overlay(&mut framed, &picture, 20, 20);
+		image::imageops::overlay(&mut framed, &RgbaImage::from_pixel(8, 4, Rgba([0, 0, 0, 255])), 21, 75);
+
+		let picture = DynamicImage::ImageRgba8(picture);
+		let framed = DynamicImage::ImageRgba8(framed);
+		assert_eq!(find_content_bounds(&framed), (20, 20, 120, 80));
+		let (cropped_picture, cropped_framed) = (crop_to_content(&picture), crop_to_content(&framed));
+		assert_eq!(cropped_picture.dimensions(), (80, 48));
+		assert_eq!(cropped_picture.to_rgba8(), cropped_framed.to_rgba8());
+
+		// A plain image is mostly border, but only so much is taken from each side.
+		let plain = DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 40, Rgba([9, 9, 9, 255])));
+		assert_eq!(find_content_bounds(&plain), (10, 10, 30, 30));
+		assert_eq!(crop_to_content(&DynamicImage::new_rgba8(1, 1)).dimensions(), (1, 1));
+	}
+}
@@ -1,6 +1,8 @@
 use image::{DynamicImage, GenericImageView, imageops::FilterType};
 use lazy_static::lazy_static;
+use parking_lot::{Condvar, Mutex};
 use tract_onnx::prelude::*;
+use crate::image_hashes::{fnv1a_hash, quantize_embedding};
 
 const SIMILARITY_MODEL_PATH:&'static str = "models/image_similarity.onnx";
 const MODEL_INPUT_WIDTH:u32 = 224;
@@ -11,6 +13,53 @@ lazy_static! {
 	static ref MODEL: RunnableModel<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>> = {
 		tract_onnx::onnx().model_for_path(SIMILARITY_MODEL_PATH).expect("Unable to load similarity model from disk!").into_optimized().unwrap().into_runnable().unwrap()
 	};
+	// Embeddings from different model files can't be compared, so each file gets its own version, from an FNV-1a hash of it.
+	static ref MODEL_VERSION: Option<String> = std::fs::read(SIMILARITY_MODEL_PATH).ok().map(|bytes| {
+		format!("image_similarity-{:016x}", fnv1a_hash(&bytes))
+	});
+	// How many threads are running the model, and how many may at once.  Freed is signalled as they finish.
+	static ref EMBEDDING_SLOTS: (Mutex<EmbeddingSlots>, Condvar) = (Mutex::new(EmbeddingSlots { running: 0, max: usize::MAX }), Condvar::new());
+}
+
+struct EmbeddingSlots {
+	running: usize,
+	max: usize,
+}
+
+/// A turn running the model.  It's given back when it's dropped, so a panicking embedding doesn't keep it.
+struct EmbeddingSlot<'a>(&'a (Mutex<EmbeddingSlots>, Condvar));
+
+impl<'a> EmbeddingSlot<'a> {
+	/// Block until fewer than the most threads allowed are running the model.  Outside of tests, the slots are always EMBEDDING_SLOTS.
+	fn take(slots_and_freed: &'a (Mutex<EmbeddingSlots>, Condvar)) -> Self {
+		let (slots, freed) = slots_and_freed;
+		let mut slots = slots.lock();
+		while slots.running >= slots.max {
+			freed.wait(&mut slots);
+		}
+		slots.running += 1;
+		EmbeddingSlot(slots_and_freed)
+	}
+}
+
+impl Drop for EmbeddingSlot<'_> {
+	fn drop(&mut self) {
+		let (slots, freed) = self.0;
+		slots.lock().running -= 1;
+		freed.notify_one();
+	}
+}
+
+/// Let at most this many threads run the model at once, whichever threads call mlhash.  The others wait their turn.  0 for no limit.
+pub fn set_max_embedding_threads(max_threads: usize) {
+	let (slots, freed) = &*EMBEDDING_SLOTS;
+	slots.lock().max = if max_threads == 0 { usize::MAX } else { max_threads };
+	freed.notify_all();
+}
+
+/// Which model file mlhash uses, or None if it's missing.  Stored with each embedding so a swapped model can be detected.
+pub fn mlhash_model_version() -> Option<&'static str> {
+	MODEL_VERSION.as_deref()
 }
 
 /// Loads an image from disk using the image crate, this returns a tensor with shape
@@ -31,14 +80,15 @@ fn image_to_tensor(img: &DynamicImage) -> Tensor {
 pub fn mlhash(img:&DynamicImage) -> Vec<u8> {
 	//let model = tract_onnx::onnx().model_for_path(SIMILARITY_MODEL_PATH).expect("Unable to load similarity model from disk!").into_optimized().unwrap().into_runnable().unwrap();
 	let img_tensor = image_to_tensor(img);
+	let _slot = EmbeddingSlot::take(&EMBEDDING_SLOTS);
 	let output = MODEL.run(tvec!(img_tensor.into())).unwrap();
 	let float_embed = output[0]
 		.to_array_view::<f32>()
 		.unwrap()
 		.iter()
-		.map(|f| { 128u8.saturating_add_signed((f*128.0f32).max(-128.0f32).min(128.0f32) as i8) })
-		.collect::<Vec<u8>>();
-	float_embed
+		.copied()
+		.collect::<Vec<f32>>();
+	quantize_embedding(&float_embed)
 }
 
 #[cfg(test)]
@@ -46,7 +96,8 @@ mod test {
 	use std::env;
 	use std::path::Path;
 	use crate::engine::hamming_distance;
-	use super::mlhash;
+	use super::{mlhash, EmbeddingSlot, EmbeddingSlots};
+	use parking_lot::{Condvar, Mutex};
 
 	const SRC_FILE: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/", file!());
 	const TEST_IMAGE_DIRECTORY: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/", "test_resources");
@@ -65,4 +116,23 @@ mod test {
 		assert_eq!(diff, 0f32);
 		//assert!(hamming_distance(&flat_hash, &img_rot_hash) > 0.5);
 	}
+
+	#[test]
+	fn test_embedding_slots() {
+		// Slots of its own, so mlhash in other tests running at the same time isn't held up, and can't take the slot.
+		let slots = (Mutex::new(EmbeddingSlots { running: 0, max: 1 }), Condvar::new());
+		let slot = EmbeddingSlot::take(&slots);
+		let (taken_tx, taken_rx) = crossbeam::channel::unbounded();
+		std::thread::scope(|scope| {
+			scope.spawn(|| {
+				let _slot = EmbeddingSlot::take(&slots);
+				taken_tx.send(()).unwrap();
+			});
+			// The second thread waits until the first is done.
+			assert!(taken_rx.recv_timeout(std::time::Duration::from_millis(100)).is_err());
+			drop(slot);
+			assert!(taken_rx.recv_timeout(std::time::Duration::from_secs(5)).is_ok());
+		});
+		assert_eq!(slots.0.lock().running, 0);
+	}
 }
@@ -1,6 +1,18 @@
-
+mod color_layout;
+mod crop;
 mod efficientnet;
+mod palette;
 mod phash;
+mod quantize;
 
 pub use phash::phash;
-pub use efficientnet::mlhash;
\ No newline at end of file
+pub use efficientnet::{mlhash, mlhash_model_version, set_max_embedding_threads};
+pub use palette::{palette, PALETTE_SIZE};
+pub use color_layout::{color_layout, COLOR_LAYOUT_SIZE};
+pub use crop::crop_to_content;
+pub use quantize::{quantize_embedding, dequantize_embedding, DEQUANTIZED_BYTES};
+
+/// A quick 64-bit FNV-1a hash of some bytes.  Not for anything that needs to resist collisions.
+pub(crate) fn fnv1a_hash(bytes: &[u8]) -> u64 {
+	bytes.iter().fold(0xcbf29ce484222325u64, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
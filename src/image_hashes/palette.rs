@@ -0,0 +1,86 @@
+use image::{DynamicImage, imageops::FilterType};
+
+pub const PALETTE_SIZE: usize = 5;
+const PALETTE_SAMPLE_SIZE: u32 = 32;
+const KMEANS_ITERATIONS: usize = 10;
+
+/// Extract the dominant colors of an image with a small k-means over a downsampled copy.
+/// Returns PALETTE_SIZE RGB triples flattened into one vec, most common color first.
+pub fn palette(img:&DynamicImage) -> Vec<u8> {
+	let small = img.resize_exact(PALETTE_SAMPLE_SIZE, PALETTE_SAMPLE_SIZE, FilterType::Triangle).to_rgb8();
+	let pixels: Vec<[f32; 3]> = small.pixels().map(|p| { [p[0] as f32, p[1] as f32, p[2] as f32] }).collect();
+
+	// Seed the centroids with evenly spaced pixels by brightness so they start spread out.
+	let mut by_brightness = pixels.clone();
+	by_brightness.sort_by(|a, b| { luma(a).total_cmp(&luma(b)) });
+	let mut centroids: Vec<[f32; 3]> = (0..PALETTE_SIZE).map(|i| {
+		by_brightness[(2*i + 1) * by_brightness.len() / (2*PALETTE_SIZE)]
+	}).collect();
+
+	let mut counts = vec![0usize; PALETTE_SIZE];
+	for _ in 0..KMEANS_ITERATIONS {
+		let mut sums = vec![[0f32; 3]; PALETTE_SIZE];
+		counts = vec![0usize; PALETTE_SIZE];
+		for p in &pixels {
+			let nearest = nearest_centroid(&centroids, p);
+			for c in 0..3 {
+				sums[nearest][c] += p[c];
+			}
+			counts[nearest] += 1;
+		}
+		for (idx, centroid) in centroids.iter_mut().enumerate() {
+			// Empty clusters keep their old position.  Flat images will have duplicate colors, which is fine.
+			if counts[idx] > 0 {
+				for c in 0..3 {
+					centroid[c] = sums[idx][c] / counts[idx] as f32;
+				}
+			}
+		}
+	}
+
+	let mut order: Vec<usize> = (0..PALETTE_SIZE).collect();
+	order.sort_by(|&a, &b| { counts[b].cmp(&counts[a]) });
+	order.iter().flat_map(|&idx| {
+		centroids[idx].map(|c| { c.round().clamp(0.0, 255.0) as u8 })
+	}).collect()
+}
+
+fn luma(p: &[f32; 3]) -> f32 {
+	0.299*p[0] + 0.587*p[1] + 0.114*p[2]
+}
+
+fn nearest_centroid(centroids: &[[f32; 3]], p: &[f32; 3]) -> usize {
+	let dist = |c: &[f32; 3]| { (c[0]-p[0]).powi(2) + (c[1]-p[1]).powi(2) + (c[2]-p[2]).powi(2) };
+	let mut best = 0;
+	for idx in 1..centroids.len() {
+		if dist(&centroids[idx]) < dist(&centroids[best]) {
+			best = idx;
+		}
+	}
+	best
+}
+
+#[cfg(test)]
+mod test {
+	use image::{DynamicImage, Rgb, RgbImage};
+	use crate::image_hashes::palette::*;
+
+	#[test]
+	fn test_palette_two_colors() {
+		// Three quarters red, one quarter blue.
+		let img = RgbImage::from_fn(64, 64, |x, _y| { if x < 48 { Rgb([255u8, 0, 0]) } else { Rgb([0u8, 0, 255]) } });
+		let colors = palette(&DynamicImage::ImageRgb8(img));
+		assert_eq!(colors.len(), 3*PALETTE_SIZE);
+		// Resizing blends the border a little, so allow some slack.
+		let near = |c: &[u8], target: [u8; 3]| { c.iter().zip(target).all(|(&a, b)| { (a as i32 - b as i32).abs() < 32 }) };
+		assert!(near(&colors[0..3], [255, 0, 0])); // Most common first.
+		assert!(colors.chunks(3).any(|c| { near(c, [0, 0, 255]) }));
+	}
+
+	#[test]
+	fn test_palette_flat() {
+		let img = RgbImage::from_pixel(16, 16, Rgb([10u8, 200, 30]));
+		let colors = palette(&DynamicImage::ImageRgb8(img));
+		assert!(colors.chunks(3).all(|c| { c == [10u8, 200, 30] }));
+	}
+}
@@ -4,6 +4,8 @@ pub fn phash(img:&DynamicImage) -> Vec<u8> {
 	// Each pixel becomes one bit.  16x16 pixels = 256 bits = 32 bytes
 	let img_width = 16;
 	let img_height = 16;
+	// resize() keeps the aspect ratio, so non-square images get a shorter hash.
+	// Every stored phash was made this way, so changing it would mean rehashing every image to keep them comparable.
 	let small = img.resize(img_width, img_height, image::imageops::Gaussian);
 	let grey = imageops::grayscale(&small).to_vec();
 	let total_hash_bytes = grey.len() / 8;
@@ -29,6 +31,8 @@ mod test {
 	use std::path::Path;
 	use crate::engine::hamming_distance;
 	use crate::image_hashes::phash::*;
+	use proptest::collection::vec;
+	use proptest::prelude::*;
 
 	const SRC_FILE: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/", file!());
 	const TEST_IMAGE_DIRECTORY: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/", "test_resources");
@@ -77,6 +81,26 @@ mod test {
 		assert!(hamming_distance(&flat_hash, &img_rot_hash) > 0.5);
 	}
 	
+	proptest! {
+		#[test]
+		fn prop_phash_flat(size in 1u32..64, color in any::<[u8; 3]>()) {
+			// Nothing is brighter than the mean of a flat square image, whatever its size or color.
+			let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(size, size, image::Rgb(color)));
+			prop_assert_eq!(phash(&img), vec![0u8; 32]);
+		}
+
+		#[test]
+		fn prop_phash_identity(width in 1u32..48, height in 1u32..48, pixels in vec(any::<u8>(), 48 * 48)) {
+			let img = DynamicImage::ImageLuma8(image::GrayImage::from_fn(width, height, |x, y| image::Luma([pixels[(x + y * 48) as usize]])));
+			let hash = phash(&img);
+			prop_assert!(hash.len() <= 32);
+			if width == height {
+				prop_assert_eq!(hash.len(), 32);
+			}
+			prop_assert_eq!(hamming_distance(&hash, &phash(&img.clone())), 0.0);
+		}
+	}
+
 	//#[bench]
 	fn bench_phash(b: &mut criterion::Criterion) {
 		let img = image::open("test_resources/flat_white.png").unwrap();
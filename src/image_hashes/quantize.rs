@@ -0,0 +1,82 @@
+// Embeddings are stored one byte per value so they stay small in the DB.
+// Every model should go through these so stored hashes decode the same way cosine_distance expects.
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+	/// What each byte dequantizes to, so distance functions can look values up instead of converting every one.
+	pub static ref DEQUANTIZED_BYTES: [f32; 256] = {
+		let mut table = [0f32; 256];
+		for (byte, value) in table.iter_mut().enumerate() {
+			*value = (byte as f32 / 127.5) - 1.0;
+		}
+		table
+	};
+}
+
+/// Map each value from [-1, 1] onto [0, 255], rounding to the nearest step.
+/// Values outside the range are clamped and NaN is treated as zero.
+pub fn quantize_embedding(values: &[f32]) -> Vec<u8> {
+	values.iter().map(|&v| {
+		let v = if v.is_nan() { 0.0 } else { v.clamp(-1.0, 1.0) };
+		((v + 1.0) * 127.5).round() as u8
+	}).collect()
+}
+
+/// The inverse of quantize_embedding.  Values come back within half a step (1/255) of where they started.
+pub fn dequantize_embedding(bytes: &[u8]) -> Vec<f32> {
+	bytes.iter().map(|&b| (b as f32 / 127.5) - 1.0).collect()
+}
+
+#[cfg(test)]
+mod test {
+	use proptest::prelude::*;
+	use crate::image_hashes::quantize::*;
+
+	const HALF_STEP: f32 = 1.0 / 255.0;
+
+	#[test]
+	fn test_quantize_endpoints() {
+		assert_eq!(quantize_embedding(&[-1.0, 0.0, 1.0]), vec![0, 128, 255]);
+		assert_eq!(quantize_embedding(&[-5.0, 5.0, f32::NEG_INFINITY, f32::INFINITY, f32::NAN]), vec![0, 255, 0, 255, 128]);
+		assert_eq!(dequantize_embedding(&[0, 255]), vec![-1.0, 1.0]);
+		assert!(dequantize_embedding(&[128])[0].abs() <= HALF_STEP + 1e-6);
+		assert_eq!(dequantize_embedding(&(0..=255).collect::<Vec<u8>>()), DEQUANTIZED_BYTES.to_vec());
+	}
+
+	#[test]
+	fn test_quantize_keeps_signal() {
+		// Small values near zero should stay distinguishable and in order.
+		let quantized = quantize_embedding(&[-0.5, -0.1, -0.02, 0.02, 0.1, 0.5]);
+		let mut deduped = quantized.clone();
+		deduped.dedup();
+		assert_eq!(deduped, quantized);
+		assert!(quantized.windows(2).all(|w| w[0] < w[1]));
+	}
+
+	proptest! {
+		#[test]
+		fn prop_round_trip(values in proptest::collection::vec(-1.0f32..=1.0, 0..64)) {
+			let round_trip = dequantize_embedding(&quantize_embedding(&values));
+			prop_assert_eq!(round_trip.len(), values.len());
+			for (before, after) in values.iter().zip(&round_trip) {
+				prop_assert!((before - after).abs() <= HALF_STEP + 1e-6, "{} came back as {}", before, after);
+			}
+		}
+
+		#[test]
+		fn prop_monotonic(a in -2.0f32..2.0, b in -2.0f32..2.0) {
+			let q = quantize_embedding(&[a, b]);
+			if a <= b {
+				prop_assert!(q[0] <= q[1]);
+			} else {
+				prop_assert!(q[0] >= q[1]);
+			}
+		}
+
+		#[test]
+		fn prop_bytes_survive(bytes in proptest::collection::vec(any::<u8>(), 0..64)) {
+			prop_assert_eq!(quantize_embedding(&dequantize_embedding(&bytes)), bytes);
+		}
+	}
+}
@@ -1,15 +1,33 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::{BufReader, Cursor, Read, BufRead, Seek};
 use std::time::Instant;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 //use exif::{Field, Exif, };
-use image::{ImageError, GenericImageView, DynamicImage, ImageFormat};
+use image::{ColorType, ImageEncoder, DynamicImage};
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::codecs::webp::WebPEncoder;
+use serde_json::{json, Value as JSONValue};
 
 use crate::image_hashes::phash;
 use crate::image_hashes::mlhash;
+use crate::image_hashes::palette;
+use crate::image_hashes::color_layout;
+use crate::image_hashes::crop_to_content;
+use crate::album_art;
+use crate::animation;
+use crate::book;
+use crate::design_files;
+use crate::font_specimen;
+use crate::mesh;
+use crate::provenance;
+use crate::raw;
+use crate::screenshot;
+use crate::svg;
+use crate::texture;
+use crate::video;
 
 pub const THUMBNAIL_SIZE: (u32, u32) = (256, 256);
 
@@ -19,7 +37,12 @@ pub struct IndexedImage {
 	pub filename: String,
 	pub path: String,
 	pub resolution: (u32, u32),
+	pub file_size: u64, // In bytes.
+	pub protected: bool, // Protected images are excluded from bulk operations.
+	pub rating: u8, // Zero is unrated.
+	pub favorite: bool,
 	pub thumbnail: Vec<u8>,
+	pub blurred_thumbnail: Option<Vec<u8>>, // Only for images tagged sensitive.  Shown instead of the thumbnail until they're revealed.
 	pub created: Instant,
 	pub indexed: Instant,
 
@@ -27,6 +50,9 @@ pub struct IndexedImage {
 
 	pub phash: Option<Vec<u8>>,
 	pub visual_hash: Option<Vec<u8>>, // For visual-similarity, like style and structure.  Not for content.
+	pub cropped_hash: Option<Vec<u8>>, // Like visual_hash, but of crop_to_content, so borders and watermarks matter less.  Only made when asked for.
+	pub palette: Option<Vec<u8>>, // Dominant colors as RGB triples, most common first.
+	pub color_layout: Option<Vec<u8>>, // A tiny RGB grid of where the colors are.  Not loaded by searches.
 	//pub content_hash: Option<Vec<u8>>, //
 
 	pub distance_from_query: Option<f64>,
@@ -34,28 +60,84 @@ pub struct IndexedImage {
 
 impl IndexedImage {
 	pub fn from_file_path(path:&Path) -> Result<Self> {
-		let mut file = File::open(path)?;
-		let mut bytes = vec![];
-		let _bytes_read = file.read_to_end(&mut bytes)?;
-		//let mut img = image::io::Reader::new(&mut image_buffer).decode()?;
+		IndexedImage::from_file_path_cropped(path, false, false)
+	}
 
-		let filename:String = path.file_name().unwrap().to_str().unwrap().to_string();
-		let pathstring:String = stringify_filepath(path);
+	/// With hash_cropped_frame, the embedding of the picture without its borders and edges is made too.  That takes about as long again.
+	/// With hash_animation_frames, an animation's embedding is the average of several of its frames, rather than just its middle frame's.
+	pub fn from_file_path_cropped(path:&Path, hash_cropped_frame:bool, hash_animation_frames:bool) -> Result<Self> {
+		let filename:String = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+		if !video::is_video_file(&filename) {
+			let mut bytes = std::fs::read(path)?;
+			return IndexedImage::from_file_bytes(path, &mut bytes, hash_cropped_frame, hash_animation_frames);
+		}
+		// Videos stand in with a frame.  They're decoded straight from the file rather than read in, since they can be huge.
+		let info = video::read_video_info(path)?;
+		let file_size = std::fs::metadata(path)?.len();
+		let img = IndexedImage::from_decoded(&info.frame, &[], filename, stringify_filepath(path), file_size, info.to_tags(), hash_cropped_frame);
+		Ok(img.with_source_url(path))
+	}
 
-		IndexedImage::from_memory(&mut bytes, filename, pathstring)
+	/// Like from_file_path_cropped, for a file at path that's already been read in, so the reading and the decoding can be done separately.  Not for videos.
+	pub fn from_file_bytes(path:&Path, bytes:&mut Vec<u8>, hash_cropped_frame:bool, hash_animation_frames:bool) -> Result<Self> {
+		let filename:String = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+		let img = IndexedImage::from_memory_cropped(bytes, filename, stringify_filepath(path), hash_cropped_frame, hash_animation_frames)?;
+		Ok(img.with_source_url(path))
+	}
+
+	/// Tag a file on disk with where it was downloaded from, if that was noted.
+	fn with_source_url(mut self, path:&Path) -> Self {
+		if let Some(url) = provenance::source_url(path) {
+			self.tags.insert(provenance::SOURCE_URL_TAG.to_string(), url);
+		}
+		self
 	}
 
 	pub fn from_memory(bytes:&mut Vec<u8>, filename:String, path:String) -> Result<Self> {
+		IndexedImage::from_memory_cropped(bytes, filename, path, false, false)
+	}
+
+	pub fn from_memory_cropped(bytes:&mut Vec<u8>, filename:String, path:String, hash_cropped_frame:bool, hash_animation_frames:bool) -> Result<Self> {
+		let file_size = bytes.len() as u64;
 		let mut cursor = Cursor::new(bytes);
 
 		//let mut img = image::open(path)?;
 		//let mut img:DynamicImage = image::load_from_memory(bytes)?;
 		//let mut img:DynamicImage = image::load_from_memory_with_format(bytes.as_slice(), ImageFormat::from_path(&path)?)?;
-		let mut img:DynamicImage = image::io::Reader::new(&mut cursor).with_guessed_format()?.decode()?;
-		let thumb = img.thumbnail(THUMBNAIL_SIZE.0, THUMBNAIL_SIZE.1).to_rgb8();
-		let thumbnail_width = thumb.width();
-		let thumbnail_height = thumb.height();
-		let qoi_thumb = qoi::encode_to_vec(&thumb.into_raw(), thumbnail_width, thumbnail_height).expect("Unable to generate compressed thumbnail.");
+		// Game textures are mostly formats the image crate can't read, so they have their own decoder.
+		let texture_header = if texture::is_texture(cursor.get_ref()) { Some(texture::read_texture_header(cursor.get_ref())?) } else { None };
+		// Fonts stand in with a rendered specimen.
+		let font_info = if font_specimen::is_font(cursor.get_ref()) { Some(font_specimen::read_font_info(cursor.get_ref())?) } else { None };
+		// Songs stand in with their cover art.  The indexed path is still the song's, so opening it plays the song.
+		let audio_info = if album_art::is_audio_file(cursor.get_ref()) { Some(album_art::read_audio_info(cursor.get_ref())?) } else { None };
+		// Books stand in with their cover.
+		let book_info = match book::BookFormat::from_filename(&filename) {
+			Some(format) => Some(book::read_book_info(format, cursor.get_ref())?),
+			None => None,
+		};
+		// So do 3D models, with a rendering.  They're mostly told apart by extension.
+		let model = match mesh::MeshFormat::sniff(&filename, cursor.get_ref()) {
+			Some(format) => Some((format, mesh::read_mesh(format, cursor.get_ref(), &filepath_from_string(&path))?)),
+			None => None,
+		};
+		// Animations stand in with their middle frame.  One that can't be stepped through is still indexed by its first, if that decodes.
+		let animation = animation::read_animation(cursor.get_ref(), if hash_animation_frames { animation::SAMPLE_FRAMES } else { 1 }).unwrap_or(None);
+		// Camera RAWs can look like TIFFs, so they're told apart by extension.
+		let is_raw = raw::is_raw_file(&filename);
+		// SVGs are drawn.  Their size is a tag, since the resolution is the drawing's.
+		let svg_info = if svg::is_svg_file(&filename) { Some(svg::render_svg(cursor.get_ref())?) } else { None };
+		let img:DynamicImage = match &texture_header {
+			Some(header) => texture::decode_texture(cursor.get_ref(), header)?,
+			None if font_info.is_some() => font_specimen::render_specimen(cursor.get_ref())?,
+			None if audio_info.is_some() => image::load_from_memory(&audio_info.as_ref().unwrap().cover)?,
+			None if book_info.is_some() => image::load_from_memory(&book::read_page(cursor.get_ref(), &book_info.as_ref().unwrap().cover)?)?,
+			None if model.is_some() => mesh::render_mesh(&model.as_ref().unwrap().1),
+			None if is_raw => raw::decode_raw(cursor.get_ref())?,
+			None if svg_info.is_some() => svg_info.as_ref().unwrap().image.clone(),
+			None if design_files::is_design_file(cursor.get_ref()) => design_files::decode_design_file(cursor.get_ref())?,
+			None if animation.is_some() => animation.as_ref().unwrap().frames[0].clone(),
+			None => image::io::Reader::new(&mut cursor).with_guessed_format()?.decode()?,
+		};
 
 		// Also parse the EXIF data.
 		cursor.seek(std::io::SeekFrom::Start(0));
@@ -66,40 +148,234 @@ impl IndexedImage {
 				tags.insert(field.tag.to_string(), field.display_value().to_string());
 			}
 		}
+		if let Some(header) = &texture_header {
+			tags.extend(header.to_tags());
+		}
+		if let Some(info) = &font_info {
+			tags.extend(info.to_tags());
+		}
+		if let Some(info) = &audio_info {
+			tags.extend(info.to_tags());
+		}
+		if let Some(info) = &book_info {
+			tags.extend(info.to_tags());
+		}
+		if let Some((format, model)) = &model {
+			tags.extend(model.to_tags(*format));
+		}
+		if let Some(info) = &animation {
+			tags.extend(info.to_tags());
+		}
+		if let Some(info) = &svg_info {
+			tags.extend(info.to_tags());
+		}
+		// Screenshots whose names don't say which window they're of have their title bar read instead, if this build can.
+		if screenshot::is_screenshot_path(&path) && screenshot::detect_screenshot_source(&filename).is_none() {
+			if let Some(title) = screenshot::read_title_bar(&img) {
+				tags.insert(screenshot::WINDOW_TITLE_TAG.to_string(), title);
+			}
+		}
+
+		let other_frames = animation.as_ref().map(|info| &info.frames[1..]).unwrap_or(&[]);
+		Ok(IndexedImage::from_decoded(&img, other_frames, filename, path, file_size, tags, hash_cropped_frame))
+	}
+
+	/// The thumbnail and hashes of an image that's already been decoded, with the tags read from its file.
+	/// If there are other_frames, the embedding is the average of theirs and the image's.
+	fn from_decoded(img:&DynamicImage, other_frames:&[DynamicImage], filename:String, path:String, file_size:u64, tags:HashMap<String, String>, hash_cropped_frame:bool) -> Self {
+		let thumb = img.thumbnail(THUMBNAIL_SIZE.0, THUMBNAIL_SIZE.1).to_rgb8();
+		let thumbnail_width = thumb.width();
+		let thumbnail_height = thumb.height();
+		let qoi_thumb = qoi::encode_to_vec(thumb.into_raw(), thumbnail_width, thumbnail_height).expect("Unable to generate compressed thumbnail.");
 
 		// And generate a perceptual hash.
-		let hash = Some(mlhash(&img));
+		let hash = if other_frames.is_empty() {
+			Some(mlhash(img))
+		} else {
+			Some(animation::mean_embedding(&std::iter::once(img).chain(other_frames).map(mlhash).collect::<Vec<_>>()))
+		};
+		let cropped_hash = if hash_cropped_frame { Some(mlhash(&crop_to_content(img))) } else { None };
 
-		Ok(
-			IndexedImage {
-				id: 0,
-				filename: filename,
-				path: path,
-				resolution: (img.width(), img.height()),
-				thumbnail: qoi_thumb,
-				created: Instant::now(),
-				indexed: Instant::now(),
+		IndexedImage {
+			id: 0,
+			filename,
+			path,
+			resolution: (img.width(), img.height()),
+			file_size,
+			protected: false,
+			rating: 0,
+			favorite: false,
+			thumbnail: qoi_thumb,
+			blurred_thumbnail: None,
+			created: Instant::now(),
+			indexed: Instant::now(),
 
-				tags: tags,
+			tags,
 
-				phash: Some(phash(&img)),  // Disable for a little while to check performance.
-				visual_hash: hash,
+			phash: Some(phash(img)),  // Disable for a little while to check performance.
+			visual_hash: hash,
+			cropped_hash,
+			palette: Some(palette(img)),
+			color_layout: Some(color_layout(img)),
 
-				distance_from_query: None,
-			}
-		)
+			distance_from_query: None,
+		}
+	}
+
+	/// The fields other programs care about, for exports and the C API.  Hashes and the thumbnail are left out.
+	pub fn summary_json(&self) -> JSONValue {
+		json!({
+			"id": self.id,
+			"path": self.path,
+			"filename": self.filename,
+			"width": self.resolution.0,
+			"height": self.resolution.1,
+			"distance": self.distance_from_query,
+			"tags": self.tags,
+		})
 	}
 
 	pub fn get_thumbnail(&self) -> (Vec<u8>, (u32, u32)) {
-		let (header, data) = qoi::decode_to_vec(&self.thumbnail).expect("Failed to decode thumbnail.");
-		(data, (header.width, header.height))
+		decode_thumbnail(&self.thumbnail).expect("Failed to decode thumbnail.")
 	}
 }
 
+/// RGB pixels and resolution of a stored thumbnail.
+/// Thumbnails are made as QOI, but recompress_thumbnail or webp_thumbnail may have swapped in another format.
+pub fn decode_thumbnail(thumbnail: &[u8]) -> Result<(Vec<u8>, (u32, u32))> {
+	if thumbnail.starts_with(b"qoif") {
+		let (header, data) = qoi::decode_to_vec(thumbnail)?;
+		return Ok((data, (header.width, header.height)));
+	}
+	let img = image::load_from_memory(thumbnail)?.to_rgb8();
+	let resolution = img.dimensions();
+	Ok((img.into_raw(), resolution))
+}
+
+/// A QOI copy of the thumbnail blurred past recognition.  blur_fraction is the blur radius as a fraction of the longer side.
+pub fn blur_thumbnail(thumbnail: &[u8], blur_fraction: f32) -> Result<Vec<u8>> {
+	let (data, (width, height)) = decode_thumbnail(thumbnail)?;
+	let img = image::RgbImage::from_raw(width, height, data).ok_or_else(|| anyhow!("The thumbnail is cut off."))?;
+	let blurred = image::imageops::blur(&img, (width.max(height) as f32 * blur_fraction).max(1.0));
+	Ok(qoi::encode_to_vec(blurred.as_raw(), width, height)?)
+}
+
+/// The thumbnail as a PNG, if that's smaller.  Slower to make and read than QOI, but usually smaller for photos.
+pub fn recompress_thumbnail(thumbnail: &[u8]) -> Result<Option<Vec<u8>>> {
+	let (data, (width, height)) = decode_thumbnail(thumbnail)?;
+	let mut png = vec![];
+	PngEncoder::new_with_quality(&mut png, CompressionType::Best, PngFilterType::Adaptive).write_image(&data, width, height, ColorType::Rgb8)?;
+	Ok(if png.len() < thumbnail.len() { Some(png) } else { None })
+}
+
+/// The thumbnail as a lossless WebP, if that's smaller.  Usually smaller than the PNG too, and just as exact.
+pub fn webp_thumbnail(thumbnail: &[u8]) -> Result<Option<Vec<u8>>> {
+	let (data, (width, height)) = decode_thumbnail(thumbnail)?;
+	let mut webp = vec![];
+	WebPEncoder::new_lossless(&mut webp).encode(&data, width, height, ColorType::Rgb8)?;
+	Ok(if webp.len() < thumbnail.len() { Some(webp) } else { None })
+}
+
+// Parts of paths that aren't Unicode are stored as characters from the end of the last private use plane, so the file can still be found from its stored path.
+// On Unix, each byte that isn't UTF-8 is ESCAPE_START plus the byte.  On Windows, each unpaired UTF-16 surrogate is ESCAPE_START plus how far it is past 0xD800.
+// A real name with one of those characters in it would be read back wrong, but nobody names files in that plane.
+#[cfg(unix)]
+const ESCAPE_START: u32 = 0x10FF00;
+#[cfg(windows)]
+const ESCAPE_START: u32 = 0x10F800;
+
 /// Convert a path into a canonical string.
 /// We could do a few different things to a path, but to ensure we're doing the same thing everywhere we reference a path as a string, have one method.
+/// The string is what's stored in the DB.  filepath_from_string turns it back into the path, even if it wasn't Unicode.
 pub fn stringify_filepath(path: &Path) -> String {
-	path.canonicalize().unwrap().display().to_string()
+	// Dangling links, files deleted mid-crawl, and some network shares can't be canonicalized.  They're written the way canonical paths are, as near as can be.
+	let path = path.canonicalize().unwrap_or_else(|_| verbatim(std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())));
+	escape_filepath(&path)
+}
+
+/// The path a string from stringify_filepath stands for.  Use this rather than Path::new to open files from the DB.
+pub fn filepath_from_string(stored: &str) -> PathBuf {
+	#[cfg(any(unix, windows))]
+	if stored.chars().any(|c| c as u32 >= ESCAPE_START) {
+		return unescape_filepath(stored);
+	}
+	PathBuf::from(stored)
+}
+
+#[cfg(unix)]
+fn escape_filepath(path: &Path) -> String {
+	use std::os::unix::ffi::OsStrExt;
+	let mut escaped = String::new();
+	for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+		escaped.push_str(chunk.valid());
+		escaped.extend(chunk.invalid().iter().filter_map(|&byte| char::from_u32(ESCAPE_START + byte as u32)));
+	}
+	escaped
+}
+
+#[cfg(unix)]
+fn unescape_filepath(stored: &str) -> PathBuf {
+	use std::os::unix::ffi::OsStringExt;
+	let mut bytes = vec![];
+	for c in stored.chars() {
+		match (c as u32).checked_sub(ESCAPE_START) {
+			Some(byte) => bytes.push(byte as u8),
+			None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+		}
+	}
+	PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(windows)]
+fn escape_filepath(path: &Path) -> String {
+	use std::os::windows::ffi::OsStrExt;
+	char::decode_utf16(path.as_os_str().encode_wide()).filter_map(|c| match c {
+		Ok(c) => Some(c),
+		Err(e) => char::from_u32(ESCAPE_START + (e.unpaired_surrogate() - 0xD800) as u32),
+	}).collect()
+}
+
+#[cfg(windows)]
+fn unescape_filepath(stored: &str) -> PathBuf {
+	use std::os::windows::ffi::OsStringExt;
+	let mut wide = vec![];
+	for c in stored.chars() {
+		match (c as u32).checked_sub(ESCAPE_START) {
+			Some(offset) => wide.push(0xD800 + offset as u16),
+			None => wide.extend_from_slice(c.encode_utf16(&mut [0; 2])),
+		}
+	}
+	PathBuf::from(std::ffi::OsString::from_wide(&wide))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn escape_filepath(path: &Path) -> String {
+	path.to_string_lossy().to_string()
+}
+
+/// An absolute path the way canonicalize writes it on Windows, with the \\?\ prefix that lets it be longer than MAX_PATH.  Elsewhere, the path as it is.
+fn verbatim(path: PathBuf) -> PathBuf {
+	#[cfg(windows)]
+	{
+		use std::path::{Component, Prefix};
+		let Some(Component::Prefix(prefix)) = path.components().next() else {
+			return path;
+		};
+		let mut verbatim = match prefix.kind() {
+			Prefix::Disk(letter) => PathBuf::from(format!(r"\\?\{}:\", letter as char)),
+			Prefix::UNC(server, share) => {
+				let mut unc = PathBuf::from(r"\\?\UNC\");
+				unc.push(server);
+				unc.push(share);
+				unc
+			},
+			_ => return path,
+		};
+		verbatim.extend(path.components().filter(|c| matches!(c, Component::Normal(_))));
+		verbatim
+	}
+	#[cfg(not(windows))]
+	path
 }
 
 #[cfg(test)]
@@ -112,4 +388,31 @@ mod tests {
 		let img = IndexedImage::from_file_path(Path::new("test_resources/flat_white.png"));
 		//assert_eq!(add(1, 2), 3);
 	}
+
+	#[test]
+	fn test_stringify_filepath() {
+		// Paths that can't be canonicalized are still made absolute.
+		let missing = Path::new("no such folder").join("photo.png");
+		assert_eq!(PathBuf::from(stringify_filepath(&missing)), verbatim(std::env::current_dir().unwrap().join(&missing)));
+
+		let dir = std::env::temp_dir().join(format!("pixelbox_test_stringify_filepath_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let unicode = dir.join("写真 🎉.png");
+		std::fs::write(&unicode, b"").unwrap();
+		assert_eq!(filepath_from_string(&stringify_filepath(&unicode)), unicode.canonicalize().unwrap());
+
+		// Names that aren't UTF-8 can be found again from their string.
+		#[cfg(unix)]
+		{
+			use std::os::unix::ffi::OsStrExt;
+			let not_utf8 = dir.join(std::ffi::OsStr::from_bytes(b"scan \xff\xfe.png"));
+			std::fs::write(&not_utf8, b"").unwrap();
+			let stored = stringify_filepath(&not_utf8);
+			assert!(!stored.contains(char::REPLACEMENT_CHARACTER));
+			assert_eq!(std::fs::metadata(filepath_from_string(&stored)).unwrap().len(), 0);
+			assert_eq!(filepath_from_string(&stored), not_utf8.canonicalize().unwrap());
+		}
+		assert_eq!(filepath_from_string("/plain/path.png"), PathBuf::from("/plain/path.png"));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
 }
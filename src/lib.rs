@@ -0,0 +1,27 @@
+// The indexing and search core, without the UI.
+// The desktop app in main.rs is built on top of this, and ffi and python expose it to other languages.
+pub mod album_art;
+pub mod animation;
+pub mod archive;
+pub mod backup;
+pub mod book;
+pub mod crawler;
+pub mod design_files;
+pub mod engine;
+pub mod ffi;
+pub mod font_specimen;
+pub mod image_hashes;
+pub mod indexed_image;
+pub mod mail;
+pub mod mesh;
+pub mod provenance;
+pub mod raw;
+pub mod screenshot;
+pub mod server;
+pub mod sniff;
+pub mod stress;
+pub mod svg;
+pub mod texture;
+pub mod video;
+#[cfg(feature = "python")]
+mod python;
@@ -0,0 +1,440 @@
+// Images attached to emails and chat exports, with who sent them and when.
+// Reads .eml and .mbox files, and WhatsApp's "Export Chat" zips.
+
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+
+use crate::archive;
+
+const IMAGE_EXTENSIONS: &'static [&str] = &["png", "jpg", "jpeg", "gif", "webp", "bmp", "heic", "tif", "tiff"];
+
+/// An image found in a message.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MailImage {
+	pub entry: String, // Unique within the file it came from, for the indexed path.
+	pub filename: String,
+	pub bytes: Vec<u8>,
+	pub sender: Option<String>,
+	pub date: Option<String>,
+	pub subject: Option<String>, // The chat's name for chat exports.
+}
+
+impl MailImage {
+	pub fn to_tags(&self) -> HashMap<String, String> {
+		let mut tags = HashMap::new();
+		for (name, value) in [("Sender", &self.sender), ("Date", &self.date), ("Subject", &self.subject)] {
+			if let Some(value) = value {
+				tags.insert(name.to_string(), value.clone());
+			}
+		}
+		tags
+	}
+}
+
+/// Emails are told apart by extension.  Chat exports are plain zips, so they're told apart by the name WhatsApp gives them.
+pub fn is_mail_file(filename: &str) -> bool {
+	let extension = extension_of(filename);
+	extension == "eml" || extension == "mbox" || (extension == "zip" && filename.to_lowercase().starts_with("whatsapp chat"))
+}
+
+/// Every image attached to or inlined in the messages in the file,
+/// and the entries of the attachments that couldn't be decoded, with why.
+pub fn read_mail_images(filename: &str, bytes: &[u8]) -> Result<(Vec<MailImage>, Vec<(String, String)>)> {
+	let (mut images, failures) = match extension_of(filename).as_str() {
+		"eml" => read_message(bytes),
+		"mbox" => {
+			let mut images = vec![];
+			let mut failures = vec![];
+			for (idx, message) in split_mbox(bytes).into_iter().enumerate() {
+				let (message_images, message_failures) = read_message(message);
+				images.extend(message_images.into_iter().map(|img| MailImage { entry: format!("{}/{}", idx+1, img.entry), ..img }));
+				failures.extend(message_failures.into_iter().map(|(entry, e)| (format!("{}/{}", idx+1, entry), e)));
+			}
+			(images, failures)
+		},
+		"zip" => (read_chat_export(filename, bytes)?, vec![]),
+		_ => return Err(anyhow!("{} isn't an email or chat export.", filename)),
+	};
+
+	// Two attachments can have the same name, but they need different paths.
+	let mut seen = HashSet::new();
+	for img in images.iter_mut() {
+		let mut entry = img.entry.clone();
+		let mut copy = 1;
+		while !seen.insert(entry.clone()) {
+			copy += 1;
+			entry = format!("{}~{}", img.entry, copy);
+		}
+		img.entry = entry;
+	}
+	Ok((images, failures))
+}
+
+fn extension_of(filename: &str) -> String {
+	filename.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).unwrap_or_default()
+}
+
+/// Where the needle first appears in the haystack.
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// The bytes without one line ending at the end, either \n or \r\n.
+fn strip_newline(bytes: &[u8]) -> &[u8] {
+	let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+	bytes.strip_suffix(b"\r").unwrap_or(bytes)
+}
+
+/// An mbox is messages one after another, each starting with a "From " line.
+fn split_mbox(bytes: &[u8]) -> Vec<&[u8]> {
+	let mut messages = vec![];
+	let mut start = None;
+	let mut offset = 0;
+	for line in bytes.split_inclusive(|&b| b == b'\n') {
+		if line.starts_with(b"From ") {
+			if let Some(start) = start {
+				messages.push(&bytes[start..offset]);
+			}
+			start = Some(offset + line.len());
+		}
+		offset += line.len();
+	}
+	if let Some(start) = start {
+		messages.push(&bytes[start..]);
+	}
+	messages
+}
+
+/// The images in a message, and the names of the ones that couldn't be decoded, with why.
+fn read_message(message: &[u8]) -> (Vec<MailImage>, Vec<(String, String)>) {
+	let (headers, body) = split_headers(message);
+	let mut images = vec![];
+	let mut failures = vec![];
+	collect_images(&headers, body, &mut images, &mut failures);
+	let header = |name: &str| headers.get(name).map(|v| decode_header(v)).filter(|v| !v.is_empty());
+	let images = images.into_iter().map(|(filename, bytes)| MailImage {
+		entry: filename.clone(),
+		filename,
+		bytes,
+		sender: header("from"),
+		date: header("date"),
+		subject: header("subject"),
+	}).collect();
+	(images, failures)
+}
+
+/// Headers, by lowercase name, and the body after them.  Only the first of a repeated header is kept.
+/// Headers are text, but bodies are left as bytes, since attachments can be sent unencoded.
+fn split_headers(part: &[u8]) -> (HashMap<String, String>, &[u8]) {
+	let (head, body) = if let Some(body) = part.strip_prefix(b"\n").or_else(|| part.strip_prefix(b"\r\n")) {
+		(&part[..0], body)
+	} else {
+		// The blank line after the headers ends either way, whatever the lines before it end with.
+		match (find_bytes(part, b"\n\n"), find_bytes(part, b"\n\r\n")) {
+			(Some(lf), Some(crlf)) if crlf < lf => (&part[..crlf], &part[crlf+3..]),
+			(Some(lf), _) => (&part[..lf], &part[lf+2..]),
+			(None, Some(crlf)) => (&part[..crlf], &part[crlf+3..]),
+			(None, None) => (part, &part[part.len()..]),
+		}
+	};
+	let mut headers: Vec<(String, String)> = vec![];
+	for line in String::from_utf8_lossy(head).lines() {
+		if line.starts_with(' ') || line.starts_with('\t') {
+			// A folded line continues the header before it.
+			if let Some((_, value)) = headers.last_mut() {
+				value.push(' ');
+				value.push_str(line.trim());
+			}
+		} else if let Some((name, value)) = line.split_once(':') {
+			headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+		}
+	}
+	let mut header_map = HashMap::new();
+	for (name, value) in headers {
+		header_map.entry(name).or_insert(value);
+	}
+	(header_map, body)
+}
+
+/// Walk the MIME tree and decode every image part.  Image parts that can't be decoded are added to failures.
+fn collect_images(headers: &HashMap<String, String>, body: &[u8], images: &mut Vec<(String, Vec<u8>)>, failures: &mut Vec<(String, String)>) {
+	let content_type = headers.get("content-type").map(|s| s.as_str()).unwrap_or("text/plain");
+	let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+	if mime.starts_with("multipart/") {
+		let boundary = match header_param(content_type, "boundary") {
+			Some(boundary) => boundary,
+			None => return,
+		};
+		let delimiter = format!("--{}", boundary);
+		let delimiter = delimiter.as_bytes();
+		// Everything before the first delimiter is a preamble, and a delimiter followed by -- ends the parts.
+		let mut rest = match find_bytes(body, delimiter) {
+			Some(first) => &body[first + delimiter.len()..],
+			None => return,
+		};
+		loop {
+			if rest.starts_with(b"--") {
+				break;
+			}
+			let (section, next) = match find_bytes(rest, delimiter) {
+				Some(end) => (&rest[..end], Some(&rest[end + delimiter.len()..])),
+				None => (rest, None),
+			};
+			// The newline before a delimiter belongs to the delimiter, and the rest of the delimiter's line is ignored.
+			let section = strip_newline(section);
+			let section = section.iter().position(|&b| b == b'\n').map(|newline| &section[newline+1..]).unwrap_or(&[]);
+			let (part_headers, part_body) = split_headers(section);
+			collect_images(&part_headers, part_body, images, failures);
+			match next {
+				Some(next) => rest = next,
+				None => break,
+			}
+		}
+	} else if mime == "message/rfc822" {
+		// A forwarded message.
+		let (inner_headers, inner_body) = split_headers(body);
+		collect_images(&inner_headers, inner_body, images, failures);
+	} else {
+		let name = headers.get("content-disposition").and_then(|v| header_param(v, "filename"))
+			.or_else(|| header_param(content_type, "name"))
+			.map(|name| decode_header(&name));
+		let named_image = name.as_ref().map(|n| IMAGE_EXTENSIONS.contains(&extension_of(n).as_str())).unwrap_or(false);
+		if !mime.starts_with("image/") && (mime != "application/octet-stream" || !named_image) {
+			return;
+		}
+		let filename = name.unwrap_or_else(|| format!("image{}.{}", images.len()+failures.len()+1, mime.trim_start_matches("image/")));
+		let bytes = match headers.get("content-transfer-encoding").map(|v| v.to_lowercase()).as_deref() {
+			Some("base64") => {
+				let encoded: Vec<u8> = body.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+				match base64::decode(encoded) {
+					Ok(bytes) => bytes,
+					Err(e) => {
+						failures.push((filename, format!("The attachment couldn't be decoded: {}", e)));
+						return;
+					}
+				}
+			},
+			Some("quoted-printable") => decode_quoted_printable(body),
+			// 8bit and binary parts are the bytes themselves, less the newline before the next delimiter.
+			_ => body.to_vec(),
+		};
+		images.push((filename, bytes));
+	}
+}
+
+/// A parameter from a header like Content-Type: multipart/mixed; boundary="abc"
+fn header_param(value: &str, name: &str) -> Option<String> {
+	value.split(';').skip(1)
+		.filter_map(|param| param.split_once('='))
+		.find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+		.map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+/// Decode the =?charset?B?...?= and =?charset?Q?...?= words that let headers hold more than ASCII.
+fn decode_header(value: &str) -> String {
+	let mut decoded = String::new();
+	let mut rest = value;
+	let mut after_word = false;
+	while let Some(start) = rest.find("=?") {
+		let word = rest[start+2..].split_once('?')
+			.and_then(|(charset, word)| Some((charset, word.split_once('?')?)))
+			.and_then(|(charset, (encoding, word))| Some((charset, encoding, word.split_once("?=")?)))
+			.and_then(|(charset, encoding, (text, after))| Some((decode_word(charset, encoding, text)?, after)));
+		match word {
+			Some((word, after)) => {
+				// Whitespace between two encoded words is only there for folding.
+				let between = &rest[..start];
+				if !(after_word && between.trim().is_empty()) {
+					decoded.push_str(between);
+				}
+				decoded.push_str(&word);
+				rest = after;
+				after_word = true;
+			},
+			None => {
+				decoded.push_str(&rest[..start+2]);
+				rest = &rest[start+2..];
+				after_word = false;
+			}
+		}
+	}
+	decoded.push_str(rest);
+	decoded
+}
+
+fn decode_word(charset: &str, encoding: &str, text: &str) -> Option<String> {
+	let bytes = match encoding {
+		"B" | "b" => base64::decode(text).ok()?,
+		"Q" | "q" => decode_quoted_printable(text.replace('_', " ").as_bytes()),
+		_ => return None,
+	};
+	// Latin-1 maps straight to the first 256 code points.  Everything else is treated as UTF-8.
+	let charset = charset.to_lowercase();
+	if charset == "iso-8859-1" || charset == "latin1" || charset == "windows-1252" {
+		Some(bytes.iter().map(|&b| b as char).collect())
+	} else {
+		Some(String::from_utf8_lossy(&bytes).to_string())
+	}
+}
+
+fn decode_quoted_printable(bytes: &[u8]) -> Vec<u8> {
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut idx = 0;
+	while idx < bytes.len() {
+		if bytes[idx] == b'=' {
+			// A = at the end of a line joins it to the next.
+			if bytes.get(idx+1) == Some(&b'\n') {
+				idx += 2;
+				continue;
+			}
+			if bytes.get(idx+1..idx+3) == Some(b"\r\n") {
+				idx += 3;
+				continue;
+			}
+			if let Some(byte) = bytes.get(idx+1..idx+3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+				decoded.push(byte);
+				idx += 3;
+				continue;
+			}
+		}
+		decoded.push(bytes[idx]);
+		idx += 1;
+	}
+	decoded
+}
+
+/// WhatsApp exports a chat as a zip of the conversation as text and every attachment.
+fn read_chat_export(filename: &str, bytes: &[u8]) -> Result<Vec<MailImage>> {
+	let entries = archive::zip_entries(bytes)?;
+	let chat_entry = entries.iter()
+		.find(|e| e.name == "_chat.txt" || (e.name.starts_with("WhatsApp Chat") && e.name.ends_with(".txt")))
+		.ok_or_else(|| anyhow!("{} has no chat in it.", filename))?;
+	let chat = String::from_utf8_lossy(&archive::read_zip_entry(bytes, chat_entry)?).to_string();
+	let chat_name = filename.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(filename).to_string();
+
+	let mut images = vec![];
+	for entry in entries.iter().filter(|e| IMAGE_EXTENSIONS.contains(&extension_of(&e.name).as_str())) {
+		let (date, sender) = chat.lines()
+			.map(|line| line.trim_start_matches('\u{200e}'))
+			.find(|line| line.contains(entry.name.as_str()))
+			.map(parse_chat_line)
+			.unwrap_or((None, None));
+		images.push(MailImage {
+			entry: entry.name.clone(),
+			filename: entry.name.rsplit('/').next().unwrap_or(&entry.name).to_string(),
+			bytes: archive::read_zip_entry(bytes, entry)?,
+			sender,
+			date,
+			subject: Some(chat_name.clone()),
+		});
+	}
+	Ok(images)
+}
+
+/// The date and sender of a chat line.  The iPhone app writes
+/// [14/03/2024, 10:22:13] Alice: <attached: 00000012-PHOTO-2024-03-14-10-22-13.jpg>
+/// and the Android app writes
+/// 14/03/2024, 10:22 - Alice: IMG-20240314-WA0003.jpg (file attached)
+fn parse_chat_line(line: &str) -> (Option<String>, Option<String>) {
+	let split = match line.strip_prefix('[') {
+		Some(line) => line.split_once("] "),
+		None => line.split_once(" - "),
+	};
+	match split {
+		Some((date, rest)) => (Some(date.to_string()), rest.split_once(": ").map(|(sender, _)| sender.to_string())),
+		None => (None, None),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::mail::*;
+	use crate::archive::make_test_zip;
+
+	const DIAGRAM_MESSAGE: &'static str = "From: =?UTF-8?B?Sm9zw6k=?= <jose@example.com>\r
+Date: Thu, 14 Mar 2024 10:22:13 +0000\r
+Subject: =?utf-8?Q?Caf=C3=A9_floor_plan?=\r
+Content-Type: multipart/mixed;\r
+ boundary=\"outer\"\r
+\r
+This is a multi-part message.\r
+--outer\r
+Content-Type: text/plain\r
+\r
+See attached.\r
+--outer\r
+Content-Type: image/png; name=\"diagram.png\"\r
+Content-Disposition: attachment; filename=\"diagram.png\"\r
+Content-Transfer-Encoding: base64\r
+\r
+iVBORw0K\r
+GgoAAAA=\r
+--outer\r
+Content-Type: application/octet-stream; name=\"photo.jpg\"\r
+Content-Transfer-Encoding: quoted-printable\r
+\r
+=FF=D8=\r
+=FFjpeg\r
+--outer--\r
+";
+
+	#[test]
+	fn test_eml() {
+		let (images, failures) = read_mail_images("plans.eml", DIAGRAM_MESSAGE.as_bytes()).unwrap();
+		assert!(failures.is_empty());
+		assert_eq!(images.len(), 2);
+		assert_eq!(images[0].filename, "diagram.png");
+		assert_eq!(images[0].bytes, b"\x89PNG\r\n\x1a\n\0\0\0");
+		assert_eq!(images[1].filename, "photo.jpg");
+		assert_eq!(images[1].bytes, b"\xFF\xD8\xFFjpeg");
+		let tags = images[0].to_tags();
+		assert_eq!(tags.get("Sender").map(|s| s.as_str()), Some("José <jose@example.com>"));
+		assert_eq!(tags.get("Date").map(|s| s.as_str()), Some("Thu, 14 Mar 2024 10:22:13 +0000"));
+		assert_eq!(tags.get("Subject").map(|s| s.as_str()), Some("Café floor plan"));
+
+		assert!(is_mail_file("plans.EML"));
+		assert!(!is_mail_file("plans.txt"));
+		assert_eq!(read_mail_images("notes.eml", b"Subject: No pictures\n\nJust text.").unwrap(), (vec![], vec![]));
+	}
+
+	#[test]
+	fn test_mbox() {
+		let mbox = format!("From jose@example.com Thu Mar 14 10:22:13 2024\n{}\nFrom jose@example.com Fri Mar 15 09:00:00 2024\n{}", DIAGRAM_MESSAGE, DIAGRAM_MESSAGE);
+		let (images, _) = read_mail_images("Inbox.mbox", mbox.as_bytes()).unwrap();
+		let entries: Vec<&str> = images.iter().map(|img| img.entry.as_str()).collect();
+		assert_eq!(entries, vec!["1/diagram.png", "1/photo.jpg", "2/diagram.png", "2/photo.jpg"]);
+
+		assert_eq!(decode_header("=?UTF-8?Q?a?= =?UTF-8?Q?b?= and =?bogus"), "ab and =?bogus");
+	}
+
+	#[test]
+	fn test_binary_attachments() {
+		// Unencoded parts keep their bytes, even ones that aren't UTF-8 or look like line endings.
+		let png = b"\x89PNG\r\n\x1a\n\xff\xfe\r\n";
+		let mut message = b"Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n--b\r\nContent-Type: image/png\r\nContent-Transfer-Encoding: binary\r\n\r\n".to_vec();
+		message.extend_from_slice(png);
+		message.extend_from_slice(b"\r\n--b\r\nContent-Type: image/jpeg; name=\"broken.jpg\"\r\nContent-Transfer-Encoding: base64\r\n\r\n!!!\r\n--b--\r\n");
+		let (images, failures) = read_mail_images("raw.eml", &message).unwrap();
+		assert_eq!(images.len(), 1);
+		assert_eq!(images[0].bytes, png);
+		// Ones that can't be decoded are reported rather than dropped.
+		assert_eq!(failures.len(), 1);
+		assert_eq!(failures[0].0, "broken.jpg");
+	}
+
+	#[test]
+	fn test_chat_export() {
+		let chat = "\u{200e}[14/03/2024, 10:22:13] Alice: \u{200e}<attached: 00000012-PHOTO-2024-03-14-10-22-13.jpg>\n14/03/2024, 10:25 - Bob: IMG-20240314-WA0003.jpg (file attached)\n";
+		let export = make_test_zip(&[("_chat.txt", chat.as_bytes()), ("00000012-PHOTO-2024-03-14-10-22-13.jpg", b"a"), ("IMG-20240314-WA0003.jpg", b"b"), ("voice.opus", b"c")]);
+		assert!(is_mail_file("WhatsApp Chat - Book Club.zip"));
+		assert!(!is_mail_file("Book Club.zip"));
+		let (images, _) = read_mail_images("WhatsApp Chat - Book Club.zip", &export).unwrap();
+		assert_eq!(images.len(), 2);
+		assert_eq!(images[0].sender.as_deref(), Some("Alice"));
+		assert_eq!(images[0].date.as_deref(), Some("14/03/2024, 10:22:13"));
+		assert_eq!(images[1].sender.as_deref(), Some("Bob"));
+		assert_eq!(images[1].date.as_deref(), Some("14/03/2024, 10:25"));
+		assert_eq!(images[1].subject.as_deref(), Some("WhatsApp Chat - Book Club"));
+		assert_eq!(images[1].bytes, b"b");
+	}
+}
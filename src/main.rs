@@ -1,24 +1,25 @@
-mod crawler;
-mod engine;
-mod image_hashes;
-mod indexed_image;
 mod ui;
 
+use pixelbox::{backup, crawler, engine, image_hashes, indexed_image, provenance, server, stress};
 use crate::indexed_image::{IndexedImage, THUMBNAIL_SIZE};
 use eframe::{egui, self, NativeOptions};
-use engine::Engine;
-use std::collections::HashMap;
+use engine::{Engine, OpenMode};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::time::Duration;
 use egui_extras::RetainedImage;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum AppTab {
 	Start,
 	Search,
 	View,
+	Colors,
 	Folders,
+	Trash,
+	Review,
+	Rules,
 	Settings,
 }
 
@@ -26,10 +27,17 @@ pub enum AppTab {
 pub struct MainApp {
 	engine: Option<Engine>,
 	active_tab: AppTab,
-	image_id_to_texture_handle: HashMap::<i64, egui::TextureHandle>,  // For storing the thumbnails loaded.
+	image_id_to_texture_handle: HashMap::<(i64, bool), egui::TextureHandle>,  // For storing the thumbnails loaded, keyed by image ID and whether it's the blurred one.
+	revealed_images: HashSet<i64>, // Sensitive images the user chose to see unblurred.  Forgotten when the app closes.
+
+	// Command Palette (Ctrl+Shift+P):
+	command_palette_open: bool,
+	command_palette_text: String,
+	command_palette_selected: usize,
 
 	// Start Tab:
-	
+	db_error: String, // Why the last DB couldn't be created or opened.
+
 	// Search Tab:
 	thumbnail_size: u8,
 	search_text_min_length: u8,
@@ -37,17 +45,48 @@ pub struct MainApp {
 	query_error: String,
 	some_value: f32,
 	current_page: u64,
+	results_per_page: u64,
+	saved_search_name: String,
+	collection_name: String,
+	collection_passphrase: String, // For making, hiding, and unlocking hidden collections.
+	share_include_originals: bool,
+	more_like_these: Vec<IndexedImage>, // Example images picked from results to search with together.
+	more_like_these_pooling: engine::EmbeddingPooling,
 
 	// View Tab:
 	selected_image: Option<IndexedImage>, // Should we move this into the enum?
 	full_image_path: String,
 	full_image: Option<egui::TextureHandle>,
 	zoom_level: f32,
+	selected_image_user_tags: Option<Vec<(String, String)>>, // Loaded when the selected image changes.
+	new_tag_name: String,
+	new_tag_value: String,
+	embedding_models: Option<Vec<engine::EmbeddingModel>>, // Loaded when the selected image changes.
+	comparison_models: (Option<i64>, Option<i64>), // Ids of the registered embedding models to compare.
+	method_comparison: Option<engine::MethodComparison>, // For the selected image.  Cleared when it changes.
+	selected_image_duplicates: Option<(Option<IndexedImage>, Vec<IndexedImage>)>, // Its canonical image, if it's a duplicate, and its own duplicates.
+	selected_image_screenshot: Option<Option<engine::ScreenshotInfo>>, // Loaded when the selected image changes.  Some(None) if it isn't a screenshot we know the window of.
+
+	// Colors Tab:
+	picked_color: [u8; 3],
+	common_palette_colors: Option<Vec<[u8; 3]>>,
+	layout_sketch: Vec<Option<[u8; 3]>>, // COLOR_LAYOUT_SIZE^2 cells, row-major.  None is "don't care".
+
+	// Rules Tab:
+	rule_draft: engine::Rule,
+
+	// Review Tab:
+	duplicate_groups: Option<Vec<engine::SimilarGroup>>, // From the last duplicate scan.
 
 	// Explore Tab:
 
 	// Settings Tab:
 	dark_mode: bool,
+	high_contrast: bool,
+	keymap: ui::keymap::Keymap,
+	keymap_capture: Option<ui::keymap::Action>, // The action waiting for a new shortcut to be pressed.
+	maintenance_report: Option<String>, // What the last Check and Compact DB found.
+	storage_report: Option<engine::StorageReport>,
 
 }
 
@@ -57,6 +96,13 @@ impl Default for MainApp {
 			engine: None,
 			active_tab: AppTab::Start,
 			image_id_to_texture_handle: HashMap::new(),
+			revealed_images: HashSet::new(),
+
+			command_palette_open: false,
+			command_palette_text: "".to_string(),
+			command_palette_selected: 0,
+
+			db_error: "".to_string(),
 
 			thumbnail_size: 128,
 			search_text_min_length: 2,
@@ -64,21 +110,54 @@ impl Default for MainApp {
 			query_error: "".to_string(),
 			some_value: 1.0f32,
 			current_page: 0u64,
+			results_per_page: 50u64,
+			saved_search_name: "".to_string(),
+			collection_name: "".to_string(),
+			collection_passphrase: "".to_string(),
+			share_include_originals: false,
+			more_like_these: vec![],
+			more_like_these_pooling: engine::EmbeddingPooling::Mean,
 
 			selected_image: None,
 			full_image_path: "".to_string(),
 			full_image: None,
 			zoom_level: 1.0f32,
+			selected_image_user_tags: None,
+			new_tag_name: "".to_string(),
+			new_tag_value: "".to_string(),
+			embedding_models: None,
+			comparison_models: (None, None),
+			method_comparison: None,
+			selected_image_duplicates: None,
+			selected_image_screenshot: None,
+
+			picked_color: [128u8, 128, 128],
+			common_palette_colors: None,
+			layout_sketch: vec![None; image_hashes::COLOR_LAYOUT_SIZE * image_hashes::COLOR_LAYOUT_SIZE],
+
+			rule_draft: engine::Rule::default(),
+
+			duplicate_groups: None,
 
 			dark_mode: true,
+			high_contrast: false,
+			keymap: ui::keymap::Keymap::default(),
+			keymap_capture: None,
+			maintenance_report: None,
+			storage_report: None,
 		}
 	}
 }
 
 impl eframe::App for MainApp {
 	fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
-		// Enforce dark mode.
-		ctx.set_visuals(if self.dark_mode { egui::Visuals::dark() } else { egui::Visuals::light() });
+		// Apply the chosen theme.
+		ctx.set_visuals(ui::theme_visuals(self.dark_mode, self.high_contrast));
+
+		ui::handle_shortcuts(self, ctx);
+		if self.command_palette_open {
+			ui::command_palette::command_palette(self, ctx);
+		}
 
 		// Display UI tabs:
 		egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
@@ -88,22 +167,96 @@ impl eframe::App for MainApp {
 		egui::CentralPanel::default().show(ctx, |ui| {
 			match (&mut self.engine, &self.active_tab) {
 				// If the engine is unloaded or we somehow get back to the start tab...
-				(None, _) => ui::start::start_panel(ui),
-				(_, AppTab::Start) => ui::start::start_panel(ui),
+				(None, _) => ui::start::start_panel(&self.db_error, ui),
+				(_, AppTab::Start) => ui::start::start_panel(&self.db_error, ui),
 				// If the engine is loaded...
 				(Some(_), AppTab::Search) => ui::search::search_panel(self, ui),
 				(Some(engine), AppTab::Folders) => ui::folders::folder_panel(engine, ctx, ui),
+				(Some(_), AppTab::Trash) => ui::trash::trash_panel(self, ui),
+				(Some(_), AppTab::Review) => ui::review::review_panel(self, ui),
+				(Some(_), AppTab::Rules) => ui::rules::rules_panel(self, ui),
 				(Some(_), AppTab::View) => ui::view::view_panel(self, ui),
+				(Some(_), AppTab::Colors) => ui::colors::colors_panel(self, ui),
 				(Some(_), AppTab::Settings) => ui::settings::settings_panel(self, ui),
 				(Some(_), _) => ()
 			}
 		});
+
+		if let Some(engine) = self.engine.as_mut() {
+			engine.backup_if_due();
+			if let Err(e) = engine.index_watched_changes() {
+				eprintln!("Stopped watching folders: {}", e);
+			}
+			// Changes are only looked for when a frame is drawn.
+			if engine.watch_folders {
+				ctx.request_repaint_after(crawler::WATCH_DEBOUNCE);
+			}
+		}
+	}
+
+	fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+		if let Err(e) = ui::settings::save_app_config(self) {
+			eprintln!("Failed to save app settings: {}", e);
+		}
+		if let Some(engine) = self.engine.take() {
+			if let Err(e) = engine.close() {
+				eprintln!("Failed to close the DB: {}", e);
+			}
+		}
 	}
 }
 
 
 fn main() {
-	let app = MainApp::default();
+	// `pixelbox --serve library.db [address]` runs the HTTP API instead of the app.  See server.rs for the options.
+	let args: Vec<String> = std::env::args().collect();
+	if args.get(1).map(|a| a == "--serve").unwrap_or(false) {
+		let (db_path, config) = match server::ServerConfig::from_args(&args[2..], std::env::var(server::TOKEN_ENV_VAR).ok()) {
+			Ok(parsed) => parsed,
+			Err(e) => {
+				eprintln!("{}", e);
+				std::process::exit(2);
+			},
+		};
+		if !db_path.is_file() {
+			eprintln!("{} doesn't exist.", db_path.display());
+			std::process::exit(1);
+		}
+		if let Err(e) = Engine::open_or_create(&db_path, OpenMode::OpenExisting).and_then(|engine| server::serve(engine, &config)) {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+		return;
+	}
+	// `pixelbox --stress [directory]` indexes a synthetic tree of awkward files to check nothing crashes.  See stress.rs for the options.
+	if args.get(1).map(|a| a == "--stress").unwrap_or(false) {
+		let config = match stress::StressConfig::from_args(&args[2..]) {
+			Ok(config) => config,
+			Err(e) => {
+				eprintln!("{}", e);
+				std::process::exit(2);
+			},
+		};
+		match stress::run(&config) {
+			Ok(report) => {
+				println!("{}", report.summary());
+				let problems = report.problems(&config);
+				for problem in &problems {
+					eprintln!("{}", problem);
+				}
+				std::process::exit(if problems.is_empty() { 0 } else { 1 });
+			},
+			Err(e) => {
+				eprintln!("The stress run couldn't finish: {}", e);
+				std::process::exit(1);
+			},
+		}
+	}
+
+	let mut app = MainApp::default();
+	if let Err(e) = ui::settings::load_app_config(&mut app) {
+		eprintln!("Failed to load app settings: {}", e);
+	}
 	let options = eframe::NativeOptions {
 		..Default::default()
 	};
@@ -0,0 +1,544 @@
+// 3D models are indexed by a thumbnail of them rendered from above and to one side, like a product shot on a turntable.
+// STL, OBJ, and glTF (.gltf and .glb) are read.  Only the shape is drawn: materials and textures are ignored.
+
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, Rgb, RgbImage};
+use serde_json::Value as JSONValue;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::sniff;
+
+const RENDER_SIZE: u32 = 512;
+const BACKGROUND: [u8; 3] = [232, 232, 232];
+const MODEL_COLOR: [f32; 3] = [150.0, 170.0, 200.0];
+const AMBIENT_LIGHT: f32 = 0.3;
+const CAMERA_YAW_DEGREES: f32 = 35.0;
+const CAMERA_PITCH_DEGREES: f32 = 25.0;
+const GLB_MAGIC: &[u8] = b"glTF";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MeshFormat {
+	Stl,
+	Obj,
+	Gltf,
+	Glb,
+}
+
+impl MeshFormat {
+	/// STL and OBJ have no magic bytes, so models are recognized by their extension.
+	pub fn from_filename(filename: &str) -> Option<MeshFormat> {
+		let extension = Path::new(filename).extension()?.to_string_lossy().to_lowercase();
+		match extension.as_str() {
+			"stl" => Some(MeshFormat::Stl),
+			"obj" => Some(MeshFormat::Obj),
+			"gltf" => Some(MeshFormat::Gltf),
+			"glb" => Some(MeshFormat::Glb),
+			_ => None,
+		}
+	}
+
+	/// Like from_filename, except an .obj has to start like an OBJ, since compiled object files share the extension.
+	pub fn sniff(filename: &str, bytes: &[u8]) -> Option<MeshFormat> {
+		match MeshFormat::from_filename(filename)? {
+			MeshFormat::Obj if !looks_like_obj(bytes) => None,
+			format => Some(format),
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			MeshFormat::Stl => "STL",
+			MeshFormat::Obj => "OBJ",
+			MeshFormat::Gltf | MeshFormat::Glb => "glTF",
+		}
+	}
+}
+
+/// Triangles in a Y-up coordinate system.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mesh {
+	pub triangles: Vec<[[f32; 3]; 3]>,
+}
+
+impl Mesh {
+	pub fn to_tags(&self, format: MeshFormat) -> HashMap<String, String> {
+		let mut tags = HashMap::new();
+		tags.insert("ModelFormat".to_string(), format.name().to_string());
+		tags.insert("TriangleCount".to_string(), self.triangles.len().to_string());
+		tags
+	}
+}
+
+/// True if the bytes start like text, as OBJ models do.  Compiled object files start with binary headers.
+pub fn looks_like_obj(bytes: &[u8]) -> bool {
+	!bytes.is_empty() && bytes.iter().take(sniff::SNIFF_BYTES as usize).all(|&b| (b >= 0x20 && b != 0x7F) || b == b'\t' || b == b'\r' || b == b'\n')
+}
+
+/// Read a model's triangles.  The path is only used to find the external buffers of .gltf files.
+pub fn read_mesh(format: MeshFormat, bytes: &[u8], path: &Path) -> Result<Mesh> {
+	let mesh = match format {
+		MeshFormat::Stl => read_stl(bytes)?,
+		MeshFormat::Obj => read_obj(bytes)?,
+		MeshFormat::Gltf => read_gltf(serde_json::from_slice(bytes)?, None, path)?,
+		MeshFormat::Glb => {
+			let (json, binary) = split_glb(bytes)?;
+			read_gltf(serde_json::from_slice(json)?, binary, path)?
+		},
+	};
+	if mesh.triangles.is_empty() {
+		return Err(anyhow!("The model has no triangles."));
+	}
+	Ok(mesh)
+}
+
+fn read_f32_le(bytes: &[u8], offset: usize) -> Result<f32> {
+	let b = bytes.get(offset..offset + 4).ok_or_else(|| anyhow!("The model is cut off."))?;
+	Ok(f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_u32_le(bytes: &[u8], offset: usize) -> Result<u32> {
+	let b = bytes.get(offset..offset + 4).ok_or_else(|| anyhow!("The model is cut off."))?;
+	Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Binary STL is a count and then 50 bytes per triangle.  Text STL starts with "solid", but some binary files do too.
+fn read_stl(bytes: &[u8]) -> Result<Mesh> {
+	let binary_count = read_u32_le(bytes, 80).ok().map(|count| count as usize);
+	let is_binary = binary_count.map(|count| 84 + count * 50 == bytes.len()).unwrap_or(false);
+	// STL is usually Z-up.
+	let y_up = |[x, y, z]: [f32; 3]| [x, z, -y];
+
+	let mut mesh = Mesh::default();
+	if is_binary {
+		for triangle_idx in 0..binary_count.unwrap_or(0) {
+			let offset = 84 + triangle_idx * 50 + 12; // Skip the stored normal.
+			let mut triangle = [[0f32; 3]; 3];
+			for (vertex_idx, vertex) in triangle.iter_mut().enumerate() {
+				for (axis, value) in vertex.iter_mut().enumerate() {
+					*value = read_f32_le(bytes, offset + vertex_idx * 12 + axis * 4)?;
+				}
+			}
+			mesh.triangles.push(triangle.map(y_up));
+		}
+	} else {
+		let text = String::from_utf8_lossy(bytes);
+		let mut vertices = vec![];
+		for line in text.lines() {
+			let mut words = line.split_whitespace();
+			if words.next() == Some("vertex") {
+				vertices.push(parse_vector(&mut words)?);
+			} else if line.trim() == "endfacet" || line.trim() == "endloop" {
+				// Facets are always triangles, but be forgiving of stray vertices.
+				if vertices.len() >= 3 {
+					mesh.triangles.push([vertices[0], vertices[1], vertices[2]].map(y_up));
+				}
+				vertices.clear();
+			}
+		}
+	}
+	Ok(mesh)
+}
+
+fn parse_vector<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<[f32; 3]> {
+	let mut vector = [0f32; 3];
+	for value in vector.iter_mut() {
+		*value = words.next().ok_or_else(|| anyhow!("A vertex is missing a coordinate."))?.parse()?;
+	}
+	Ok(vector)
+}
+
+/// Vertices and faces of an OBJ.  Faces with more than three corners are split into triangles.
+fn read_obj(bytes: &[u8]) -> Result<Mesh> {
+	let text = String::from_utf8_lossy(bytes);
+	let mut vertices: Vec<[f32; 3]> = vec![];
+	let mut mesh = Mesh::default();
+	for line in text.lines() {
+		let mut words = line.split_whitespace();
+		match words.next() {
+			Some("v") => vertices.push(parse_vector(&mut words)?),
+			Some("f") => {
+				// Corners look like 3, 3/1, or 3/1/2.  Negative indices count back from the latest vertex.
+				let corners = words.map(|corner| {
+					let index: i64 = corner.split('/').next().unwrap_or("").parse()?;
+					let index = if index < 0 { vertices.len() as i64 + index } else { index - 1 };
+					vertices.get(index as usize).copied().ok_or_else(|| anyhow!("A face refers to vertex {} of {}.", index + 1, vertices.len()))
+				}).collect::<Result<Vec<[f32; 3]>>>()?;
+				for idx in 1..corners.len().saturating_sub(1) {
+					mesh.triangles.push([corners[0], corners[idx], corners[idx + 1]]);
+				}
+			},
+			_ => {},
+		}
+	}
+	Ok(mesh)
+}
+
+/// The JSON and binary chunks of a .glb.
+fn split_glb(bytes: &[u8]) -> Result<(&[u8], Option<&[u8]>)> {
+	if !bytes.starts_with(GLB_MAGIC) {
+		return Err(anyhow!("Not a binary glTF file."));
+	}
+	let mut offset = 12;
+	let mut json = None;
+	let mut binary = None;
+	while offset + 8 <= bytes.len() {
+		let length = read_u32_le(bytes, offset)? as usize;
+		let chunk = bytes.get(offset + 8..offset + 8 + length).ok_or_else(|| anyhow!("The model is cut off."))?;
+		match &bytes[offset + 4..offset + 8] {
+			b"JSON" => json = Some(chunk),
+			b"BIN\0" => binary = Some(chunk),
+			_ => {},
+		}
+		offset += 8 + length;
+	}
+	Ok((json.ok_or_else(|| anyhow!("The glTF has no JSON chunk."))?, binary))
+}
+
+/// A column-major 4x4 matrix, as glTF stores them.
+type Matrix = [f32; 16];
+const IDENTITY: Matrix = [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+	let mut product = [0f32; 16];
+	for column in 0..4 {
+		for row in 0..4 {
+			product[column * 4 + row] = (0..4).map(|k| a[k * 4 + row] * b[column * 4 + k]).sum();
+		}
+	}
+	product
+}
+
+fn transform_point(m: &Matrix, [x, y, z]: [f32; 3]) -> [f32; 3] {
+	[
+		m[0] * x + m[4] * y + m[8] * z + m[12],
+		m[1] * x + m[5] * y + m[9] * z + m[13],
+		m[2] * x + m[6] * y + m[10] * z + m[14],
+	]
+}
+
+/// A node's transform, from either its matrix or its translation, rotation, and scale.
+fn node_matrix(node: &JSONValue) -> Matrix {
+	let numbers = |key: &str| node[key].as_array().map(|values| values.iter().map(|v| v.as_f64().unwrap_or(0.0) as f32).collect::<Vec<f32>>());
+	if let Some(matrix) = numbers("matrix").filter(|m| m.len() == 16) {
+		return matrix.try_into().unwrap_or(IDENTITY);
+	}
+	let [tx, ty, tz] = numbers("translation").filter(|t| t.len() == 3).map(|t| [t[0], t[1], t[2]]).unwrap_or([0.0; 3]);
+	let [x, y, z, w] = numbers("rotation").filter(|r| r.len() == 4).map(|r| [r[0], r[1], r[2], r[3]]).unwrap_or([0.0, 0.0, 0.0, 1.0]);
+	let [sx, sy, sz] = numbers("scale").filter(|s| s.len() == 3).map(|s| [s[0], s[1], s[2]]).unwrap_or([1.0; 3]);
+	[
+		(1.0 - 2.0 * (y * y + z * z)) * sx, (2.0 * (x * y + z * w)) * sx, (2.0 * (x * z - y * w)) * sx, 0.0,
+		(2.0 * (x * y - z * w)) * sy, (1.0 - 2.0 * (x * x + z * z)) * sy, (2.0 * (y * z + x * w)) * sy, 0.0,
+		(2.0 * (x * z + y * w)) * sz, (2.0 * (y * z - x * w)) * sz, (1.0 - 2.0 * (x * x + y * y)) * sz, 0.0,
+		tx, ty, tz, 1.0,
+	]
+}
+
+/// The triangles of every mesh in the default scene, placed by the nodes they hang from.
+fn read_gltf(gltf: JSONValue, glb_binary: Option<&[u8]>, path: &Path) -> Result<Mesh> {
+	let empty = vec![];
+	let mut buffers = vec![];
+	for buffer in gltf["buffers"].as_array().unwrap_or(&empty) {
+		let data = match buffer["uri"].as_str() {
+			None => glb_binary.ok_or_else(|| anyhow!("The glTF's binary buffer is missing."))?.to_vec(),
+			Some(uri) if uri.starts_with("data:") => {
+				let (_, encoded) = uri.split_once(";base64,").ok_or_else(|| anyhow!("Only base64 data URIs are supported."))?;
+				base64::decode(encoded)?
+			},
+			Some(uri) => std::fs::read(path.parent().unwrap_or(Path::new(".")).join(uri))?,
+		};
+		buffers.push(data);
+	}
+
+	let mut mesh = Mesh::default();
+	let nodes = gltf["nodes"].as_array().unwrap_or(&empty);
+	let scene = &gltf["scenes"][gltf["scene"].as_u64().unwrap_or(0) as usize];
+	if scene.is_null() {
+		// No scene means no placement, so the meshes are drawn as they are.
+		for mesh_idx in 0..gltf["meshes"].as_array().map(|m| m.len()).unwrap_or(0) {
+			add_gltf_mesh(&gltf, &buffers, mesh_idx, &IDENTITY, &mut mesh)?;
+		}
+		return Ok(mesh);
+	}
+
+	// Nodes can nest.  Visited nodes are tracked so a malformed file with a cycle doesn't hang.
+	let mut visited = vec![false; nodes.len()];
+	let mut pending: Vec<(usize, Matrix)> = scene["nodes"].as_array().unwrap_or(&empty).iter().filter_map(|n| n.as_u64()).map(|n| (n as usize, IDENTITY)).collect();
+	while let Some((node_idx, parent_matrix)) = pending.pop() {
+		let node = match nodes.get(node_idx) {
+			Some(node) if !visited[node_idx] => node,
+			_ => continue,
+		};
+		visited[node_idx] = true;
+		let matrix = multiply(&parent_matrix, &node_matrix(node));
+		if let Some(mesh_idx) = node["mesh"].as_u64() {
+			add_gltf_mesh(&gltf, &buffers, mesh_idx as usize, &matrix, &mut mesh)?;
+		}
+		for child in node["children"].as_array().unwrap_or(&empty).iter().filter_map(|c| c.as_u64()) {
+			pending.push((child as usize, matrix));
+		}
+	}
+	Ok(mesh)
+}
+
+fn add_gltf_mesh(gltf: &JSONValue, buffers: &[Vec<u8>], mesh_idx: usize, matrix: &Matrix, mesh: &mut Mesh) -> Result<()> {
+	const TRIANGLES: u64 = 4;
+	let empty = vec![];
+	for primitive in gltf["meshes"][mesh_idx]["primitives"].as_array().unwrap_or(&empty) {
+		// Points, lines, strips, and fans have nothing much to show.
+		if primitive["mode"].as_u64().unwrap_or(TRIANGLES) != TRIANGLES {
+			continue;
+		}
+		if !primitive["extensions"]["KHR_draco_mesh_compression"].is_null() {
+			return Err(anyhow!("Draco-compressed glTF meshes aren't supported."));
+		}
+		let position_accessor = primitive["attributes"]["POSITION"].as_u64().ok_or_else(|| anyhow!("A glTF mesh has no positions."))?;
+		let positions: Vec<[f32; 3]> = read_accessor(gltf, buffers, position_accessor as usize)?
+			.chunks_exact(3)
+			.map(|p| transform_point(matrix, [p[0] as f32, p[1] as f32, p[2] as f32]))
+			.collect();
+		let indices: Vec<usize> = match primitive["indices"].as_u64() {
+			Some(accessor) => read_accessor(gltf, buffers, accessor as usize)?.into_iter().map(|i| i as usize).collect(),
+			None => (0..positions.len()).collect(),
+		};
+		for corners in indices.chunks_exact(3) {
+			let corner = |i: usize| positions.get(corners[i]).copied().ok_or_else(|| anyhow!("A glTF mesh index is out of range."));
+			mesh.triangles.push([corner(0)?, corner(1)?, corner(2)?]);
+		}
+	}
+	Ok(())
+}
+
+/// Every component of an accessor, flattened.  Positions are floats and indices are unsigned ints.
+fn read_accessor(gltf: &JSONValue, buffers: &[Vec<u8>], accessor_idx: usize) -> Result<Vec<f64>> {
+	let accessor = &gltf["accessors"][accessor_idx];
+	let view = &gltf["bufferViews"][accessor["bufferView"].as_u64().ok_or_else(|| anyhow!("Sparse glTF accessors aren't supported."))? as usize];
+	let buffer = buffers.get(view["buffer"].as_u64().unwrap_or(0) as usize).ok_or_else(|| anyhow!("A glTF buffer is missing."))?;
+	let components = match accessor["type"].as_str() {
+		Some("SCALAR") => 1,
+		Some("VEC2") => 2,
+		Some("VEC3") => 3,
+		Some("VEC4") => 4,
+		other => return Err(anyhow!("Unsupported glTF accessor type {:?}.", other)),
+	};
+	let component_size = match accessor["componentType"].as_u64() {
+		Some(5121) => 1, // Unsigned byte.
+		Some(5123) => 2, // Unsigned short.
+		Some(5125) | Some(5126) => 4, // Unsigned int or float.
+		other => return Err(anyhow!("Unsupported glTF component type {:?}.", other)),
+	};
+	let is_float = accessor["componentType"].as_u64() == Some(5126);
+	let count = accessor["count"].as_u64().unwrap_or(0) as usize;
+	let start = view["byteOffset"].as_u64().unwrap_or(0) as usize + accessor["byteOffset"].as_u64().unwrap_or(0) as usize;
+	let stride = view["byteStride"].as_u64().map(|s| s as usize).unwrap_or(components * component_size);
+
+	// The count is untrusted, so no more is reserved than the buffer could hold.
+	let mut values = Vec::with_capacity(count.min(buffer.len() / stride.max(1) + 1) * components);
+	for element in 0..count {
+		for component in 0..components {
+			let offset = element.checked_mul(stride).and_then(|o| o.checked_add(start)).and_then(|o| o.checked_add(component * component_size));
+			let b = offset.and_then(|offset| buffer.get(offset..offset + component_size)).ok_or_else(|| anyhow!("A glTF accessor runs past its buffer."))?;
+			values.push(match (component_size, is_float) {
+				(1, _) => b[0] as f64,
+				(2, _) => u16::from_le_bytes([b[0], b[1]]) as f64,
+				(_, false) => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+				(_, true) => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+			});
+		}
+	}
+	Ok(values)
+}
+
+/// Draw the model shaded and centered, seen from above and to the front right.
+pub fn render_mesh(mesh: &Mesh) -> DynamicImage {
+	let mut img = RgbImage::from_pixel(RENDER_SIZE, RENDER_SIZE, Rgb(BACKGROUND));
+	let mut depth = vec![f32::NEG_INFINITY; (RENDER_SIZE * RENDER_SIZE) as usize];
+
+	// Center the model and fit its bounding sphere to the image.
+	let (mut min, mut max) = ([f32::MAX; 3], [f32::MIN; 3]);
+	for vertex in mesh.triangles.iter().flatten().filter(|v| v.iter().all(|c| c.is_finite())) {
+		for axis in 0..3 {
+			min[axis] = min[axis].min(vertex[axis]);
+			max[axis] = max[axis].max(vertex[axis]);
+		}
+	}
+	let center = [(min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0, (min[2] + max[2]) / 2.0];
+	let radius = mesh.triangles.iter().flatten()
+		.map(|v| ((v[0] - center[0]).powi(2) + (v[1] - center[1]).powi(2) + (v[2] - center[2]).powi(2)).sqrt())
+		.filter(|r| r.is_finite())
+		.fold(0f32, f32::max)
+		.max(f32::EPSILON);
+	let scale = RENDER_SIZE as f32 * 0.45 / radius;
+
+	// Turn the model, then tilt it toward the camera, which looks down -Z.
+	let (yaw_sin, yaw_cos) = CAMERA_YAW_DEGREES.to_radians().sin_cos();
+	let (pitch_sin, pitch_cos) = CAMERA_PITCH_DEGREES.to_radians().sin_cos();
+	let to_view = |v: &[f32; 3]| {
+		let (x, y, z) = (v[0] - center[0], v[1] - center[1], v[2] - center[2]);
+		let (x, z) = (x * yaw_cos - z * yaw_sin, x * yaw_sin + z * yaw_cos);
+		let (y, z) = (y * pitch_cos - z * pitch_sin, y * pitch_sin + z * pitch_cos);
+		[RENDER_SIZE as f32 / 2.0 + x * scale, RENDER_SIZE as f32 / 2.0 - y * scale, z * scale]
+	};
+	let light = normalize([-0.4, -0.6, 0.7]); // From the upper left, in screen space.
+
+	for triangle in &mesh.triangles {
+		let [a, b, c] = [to_view(&triangle[0]), to_view(&triangle[1]), to_view(&triangle[2])];
+		if [a, b, c].iter().flatten().any(|v| !v.is_finite()) {
+			continue;
+		}
+		// Winding isn't reliable across formats, so both sides are lit.
+		let normal = normalize(cross(sub(b, a), sub(c, a)));
+		let brightness = AMBIENT_LIGHT + (1.0 - AMBIENT_LIGHT) * (normal[0] * light[0] + normal[1] * light[1] + normal[2] * light[2]).abs();
+		let color = Rgb(MODEL_COLOR.map(|channel| (channel * brightness).min(255.0) as u8));
+
+		let area = (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0]);
+		if area.abs() < f32::EPSILON {
+			continue;
+		}
+		let min_x = a[0].min(b[0]).min(c[0]).floor().max(0.0) as u32;
+		let max_x = a[0].max(b[0]).max(c[0]).ceil().min(RENDER_SIZE as f32 - 1.0) as u32;
+		let min_y = a[1].min(b[1]).min(c[1]).floor().max(0.0) as u32;
+		let max_y = a[1].max(b[1]).max(c[1]).ceil().min(RENDER_SIZE as f32 - 1.0) as u32;
+		for y in min_y..=max_y {
+			for x in min_x..=max_x {
+				let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+				let wa = ((b[0] - px) * (c[1] - py) - (b[1] - py) * (c[0] - px)) / area;
+				let wb = ((c[0] - px) * (a[1] - py) - (c[1] - py) * (a[0] - px)) / area;
+				let wc = 1.0 - wa - wb;
+				if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+					continue;
+				}
+				let z = wa * a[2] + wb * b[2] + wc * c[2];
+				let depth_idx = (y * RENDER_SIZE + x) as usize;
+				if z > depth[depth_idx] {
+					depth[depth_idx] = z;
+					img.put_pixel(x, y, color);
+				}
+			}
+		}
+	}
+	DynamicImage::ImageRgb8(img)
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+	[a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+	[a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+	let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt().max(f32::EPSILON);
+	[v[0] / length, v[1] / length, v[2] / length]
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::mesh::*;
+
+	const CUBE_OBJ: &[u8] = b"# A unit cube.
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+v 0 0 1
+v 1 0 1
+v 1 1 1
+v 0 1 1
+f 1 2 3 4
+f 5/1 6/1 7/1 8/1
+f 1//1 2//1 6//1 5//1
+f -5 -6 -2 -1
+f 1 4 8 5
+f 2 3 7 6
+";
+
+	#[test]
+	fn test_obj_and_render() {
+		assert_eq!(MeshFormat::from_filename("cube.OBJ"), Some(MeshFormat::Obj));
+		assert_eq!(MeshFormat::from_filename("cube.png"), None);
+		assert_eq!(MeshFormat::sniff("cube.obj", CUBE_OBJ), Some(MeshFormat::Obj));
+		assert_eq!(MeshFormat::sniff("main.obj", b"\x64\x86\x05\0\0\0\0\0"), None); // A compiled object file.
+		assert_eq!(MeshFormat::sniff("part.stl", b"\0\0\0"), Some(MeshFormat::Stl));
+		let mesh = read_mesh(MeshFormat::Obj, CUBE_OBJ, Path::new("cube.obj")).unwrap();
+		assert_eq!(mesh.triangles.len(), 12);
+		assert_eq!(mesh.triangles[6], [[0.0, 1.0, 0.0], [1.0, 1.0, 0.0], [1.0, 1.0, 1.0]]); // From negative indices.
+		assert!(read_mesh(MeshFormat::Obj, b"v 0 0 0\nf 1 2 3\n", Path::new("bad.obj")).is_err());
+		assert!(read_mesh(MeshFormat::Obj, b"v 0 0 0\n", Path::new("empty.obj")).is_err());
+
+		// The cube fills the middle and leaves the corners empty.
+		let img = render_mesh(&mesh).to_rgb8();
+		assert_ne!(img.get_pixel(RENDER_SIZE / 2, RENDER_SIZE / 2).0, BACKGROUND);
+		assert_eq!(img.get_pixel(0, 0).0, BACKGROUND);
+	}
+
+	#[test]
+	fn test_stl() {
+		let mut binary = vec![0u8; 80];
+		binary.extend_from_slice(&1u32.to_le_bytes());
+		for value in [0.0f32, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 2.0] {
+			binary.extend_from_slice(&value.to_le_bytes());
+		}
+		binary.extend_from_slice(&[0, 0]);
+		let mesh = read_mesh(MeshFormat::Stl, &binary, Path::new("a.stl")).unwrap();
+		assert_eq!(mesh.triangles, vec![[[0.0, 0.0, -0.0], [1.0, 0.0, -0.0], [0.0, 2.0, -1.0]]]);
+
+		let text = b"solid test
+facet normal 0 0 1
+  outer loop
+    vertex 0 0 0
+    vertex 1 0 0
+    vertex 0 1 2
+  endloop
+endfacet
+endsolid test";
+		assert_eq!(read_mesh(MeshFormat::Stl, text, Path::new("a.stl")).unwrap(), mesh);
+	}
+
+	#[test]
+	fn test_gltf() {
+		// One triangle, indexed, in a node moved one unit along X.
+		let mut buffer = vec![];
+		for value in [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+			buffer.extend_from_slice(&value.to_le_bytes());
+		}
+		buffer.extend_from_slice(&[0, 0, 1, 0, 2, 0, 0, 0]);
+		let json = |uri: Option<String>| serde_json::json!({
+			"scene": 0,
+			"scenes": [{"nodes": [0]}],
+			"nodes": [{"children": [1], "translation": [1.0, 0.0, 0.0]}, {"mesh": 0}],
+			"meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1}]}],
+			"accessors": [
+				{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"},
+				{"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"},
+			],
+			"bufferViews": [{"buffer": 0, "byteLength": 36}, {"buffer": 0, "byteOffset": 36, "byteLength": 6}],
+			"buffers": [match uri { Some(uri) => serde_json::json!({"uri": uri, "byteLength": 44}), None => serde_json::json!({"byteLength": 44}) }],
+		}).to_string();
+		let expected = vec![[[1.0, 0.0, 0.0], [2.0, 0.0, 0.0], [1.0, 1.0, 0.0]]];
+
+		let embedded = json(Some(format!("data:application/octet-stream;base64,{}", base64::encode(&buffer))));
+		assert_eq!(read_mesh(MeshFormat::Gltf, embedded.as_bytes(), Path::new("a.gltf")).unwrap().triangles, expected);
+
+		let mut json_chunk = json(None).into_bytes();
+		json_chunk.resize(json_chunk.len().div_ceil(4) * 4, b' ');
+		let mut glb = GLB_MAGIC.to_vec();
+		glb.extend_from_slice(&2u32.to_le_bytes());
+		glb.extend_from_slice(&((12 + 8 + json_chunk.len() + 8 + buffer.len()) as u32).to_le_bytes());
+		glb.extend_from_slice(&(json_chunk.len() as u32).to_le_bytes());
+		glb.extend_from_slice(b"JSON");
+		glb.extend_from_slice(&json_chunk);
+		glb.extend_from_slice(&(buffer.len() as u32).to_le_bytes());
+		glb.extend_from_slice(b"BIN\0");
+		glb.extend_from_slice(&buffer);
+		assert_eq!(read_mesh(MeshFormat::Glb, &glb, Path::new("a.glb")).unwrap().triangles, expected);
+
+		assert!(read_mesh(MeshFormat::Gltf, json(Some("missing.bin".to_string())).as_bytes(), Path::new("/nowhere/a.gltf")).is_err());
+
+		// A count far past the end of the buffer fails without reserving room for it all.
+		let huge = embedded.replace("\"count\":3,\"type\":\"VEC3\"", "\"count\":4611686018427387904,\"type\":\"VEC3\"");
+		assert_ne!(huge, embedded);
+		assert!(read_mesh(MeshFormat::Gltf, huge.as_bytes(), Path::new("a.gltf")).is_err());
+	}
+}
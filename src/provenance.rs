@@ -0,0 +1,172 @@
+// Where a downloaded file came from, as recorded by the browser or OS.
+// Windows keeps it in an NTFS stream, macOS and Linux browsers in extended attributes.
+
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+/// The tag a downloaded image's URL is stored under.
+pub const SOURCE_URL_TAG: &'static str = "source_url";
+
+/// Chromium and Firefox set this on Linux.
+#[cfg(unix)]
+const XDG_ORIGIN_ATTR: &'static str = "user.xdg.origin.url";
+/// Safari, Chrome, and Mail set this on macOS.  It's a binary plist of the file's URL and the page it was linked from.
+#[cfg(unix)]
+const WHERE_FROMS_ATTR: &'static str = "com.apple.metadata:kMDItemWhereFroms";
+
+/// The URL the file was downloaded from, if one was recorded.
+#[cfg(windows)]
+pub fn source_url(path: &Path) -> Option<String> {
+	// The Zone.Identifier stream is read like a file named path:Zone.Identifier.
+	let mut stream_path = path.as_os_str().to_owned();
+	stream_path.push(":Zone.Identifier");
+	std::fs::read_to_string(stream_path).ok().and_then(|text| parse_zone_identifier(&text))
+}
+
+/// The URL the file was downloaded from, if one was recorded.
+#[cfg(unix)]
+pub fn source_url(path: &Path) -> Option<String> {
+	if let Some(url) = xattr::get(path, XDG_ORIGIN_ATTR).ok().flatten().and_then(|v| String::from_utf8(v).ok()) {
+		return Some(url);
+	}
+	let where_froms = xattr::get(path, WHERE_FROMS_ATTR).ok().flatten()?;
+	read_plist_strings(&where_froms).ok()?.into_iter().next()
+}
+
+#[cfg(not(any(windows, unix)))]
+pub fn source_url(_path: &Path) -> Option<String> {
+	None
+}
+
+/// The HostUrl from a Zone.Identifier, like
+/// [ZoneTransfer]
+/// ZoneId=3
+/// HostUrl=https://example.com/cat.png
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_zone_identifier(text: &str) -> Option<String> {
+	text.lines()
+		.filter_map(|line| line.trim().split_once('='))
+		.find(|(key, _)| key.eq_ignore_ascii_case("HostUrl"))
+		.map(|(_, url)| url.to_string())
+		// Browsers write about:internet when they don't know where the file came from.
+		.filter(|url| !url.is_empty() && url != "about:internet")
+}
+
+/// The strings in a binary plist that's a string or an array of strings.  Anything else in the array is skipped.
+/// Attributes can be written by anything, so every offset and size is checked.  A malformed plist is an error, not a panic.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn read_plist_strings(bytes: &[u8]) -> Result<Vec<String>> {
+	if !bytes.starts_with(b"bplist00") || bytes.len() < 8 + 32 {
+		return Err(anyhow!("Not a binary plist."));
+	}
+	let cut_off = || anyhow!("The plist is cut off.");
+	let trailer = &bytes[bytes.len() - 32..];
+	let offset_size = trailer[6] as usize;
+	let ref_size = trailer[7] as usize;
+	if offset_size == 0 || ref_size == 0 {
+		return Err(anyhow!("The plist's trailer is damaged."));
+	}
+	let read_int = |at: usize, size: usize| -> Result<usize> {
+		let int_bytes = at.checked_add(size).and_then(|end| bytes.get(at..end)).ok_or_else(cut_off)?;
+		Ok(int_bytes.iter().fold(0usize, |value, &b| (value << 8) | b as usize))
+	};
+	let num_objects = read_int(bytes.len() - 24, 8)?;
+	let top_object = read_int(bytes.len() - 16, 8)?;
+	let offset_table = read_int(bytes.len() - 8, 8)?;
+	let object_offset = |object: usize| -> Result<usize> {
+		if object >= num_objects {
+			return Err(anyhow!("The plist refers to an object that isn't there."));
+		}
+		read_int(object.checked_mul(offset_size).and_then(|o| o.checked_add(offset_table)).ok_or_else(cut_off)?, offset_size)
+	};
+	let marker_at = |at: usize| -> Result<u8> {
+		bytes.get(at).copied().ok_or_else(cut_off)
+	};
+	// Counts of 15 or more are stored in an int object after the marker.
+	let read_count = |at: usize| -> Result<(usize, usize)> {
+		let marker = marker_at(at)?;
+		if marker & 0x0F != 0x0F {
+			return Ok(((marker & 0x0F) as usize, at + 1));
+		}
+		let int_size = 1 << (marker_at(at + 1)? & 0x0F);
+		Ok((read_int(at + 2, int_size)?, at + 2 + int_size))
+	};
+	let read_string = |object: usize| -> Result<Option<String>> {
+		let at = object_offset(object)?;
+		let (count, start) = read_count(at)?;
+		let string_bytes = |length: Option<usize>| length.and_then(|length| start.checked_add(length)).and_then(|end| bytes.get(start..end)).ok_or_else(cut_off);
+		Ok(Some(match marker_at(at)? >> 4 {
+			0x5 => String::from_utf8_lossy(string_bytes(Some(count))?).to_string(),
+			0x6 => {
+				let units: Vec<u16> = string_bytes(count.checked_mul(2))?.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+				String::from_utf16_lossy(&units)
+			},
+			_ => return Ok(None),
+		}))
+	};
+
+	let top_offset = object_offset(top_object)?;
+	if marker_at(top_offset)? >> 4 != 0xA {
+		return Ok(read_string(top_object)?.into_iter().collect());
+	}
+	let (count, start) = read_count(top_offset)?;
+	let mut strings = vec![];
+	for idx in 0..count {
+		let reference = idx.checked_mul(ref_size).and_then(|r| r.checked_add(start)).ok_or_else(cut_off)?;
+		if let Some(string) = read_string(read_int(reference, ref_size)?)? {
+			strings.push(string);
+		}
+	}
+	Ok(strings)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::provenance::*;
+
+	#[test]
+	fn test_zone_identifier() {
+		let zone = "[ZoneTransfer]\r\nZoneId=3\r\nReferrerUrl=https://example.com/gallery\r\nHostUrl=https://example.com/cat.png\r\n";
+		assert_eq!(parse_zone_identifier(zone).as_deref(), Some("https://example.com/cat.png"));
+		assert_eq!(parse_zone_identifier("[ZoneTransfer]\nZoneId=3\nHostUrl=about:internet\n"), None);
+		assert_eq!(parse_zone_identifier("[ZoneTransfer]\nZoneId=3\n"), None);
+	}
+
+	#[test]
+	fn test_where_froms() {
+		// An array of two strings, the way Safari writes it.
+		let url = "https://example.com/cat.png";
+		let page = "https://a.io/";
+		let mut plist = b"bplist00".to_vec();
+		let mut offsets = vec![plist.len()];
+		plist.extend_from_slice(&[0xA2, 1, 2]);
+		offsets.push(plist.len());
+		plist.extend_from_slice(&[0x5F, 0x10, url.len() as u8]); // Long strings have their length in an int after the marker.
+		plist.extend_from_slice(url.as_bytes());
+		offsets.push(plist.len());
+		plist.push(0x50 | page.len() as u8);
+		plist.extend_from_slice(page.as_bytes());
+		let offset_table = plist.len();
+		plist.extend(offsets.iter().map(|&o| o as u8));
+		plist.extend_from_slice(&[0, 0, 0, 0, 0, 0, 1, 1]);
+		plist.extend_from_slice(&3u64.to_be_bytes());
+		plist.extend_from_slice(&0u64.to_be_bytes());
+		plist.extend_from_slice(&(offset_table as u64).to_be_bytes());
+		assert_eq!(read_plist_strings(&plist).unwrap(), vec![url.to_string(), page.to_string()]);
+
+		assert!(read_plist_strings(b"bplist00").is_err());
+		assert!(read_plist_strings(b"<?xml version=\"1.0\"?><plist></plist>").is_err());
+
+		// Offsets and counts that point far past the end are errors, not panics.
+		let mut damaged = plist.clone();
+		let table_at = damaged.len() - 8;
+		damaged[table_at..].copy_from_slice(&u64::MAX.to_be_bytes());
+		assert!(read_plist_strings(&damaged).is_err());
+		let mut damaged = plist.clone();
+		damaged[8..11].copy_from_slice(&[0xAF, 0x13, 0xFF]); // An array claiming far more references than there are bytes.
+		assert!(read_plist_strings(&damaged).is_err());
+		let mut damaged = plist.clone();
+		damaged[11..14].copy_from_slice(&[0x6F, 0x13, 0xFF]); // A UTF-16 string far longer than the file.
+		assert!(read_plist_strings(&damaged).is_err());
+	}
+}
@@ -0,0 +1,363 @@
+// Python bindings, built with `maturin build --features python`.
+// Images come back as IndexedImage objects.  Hashes are bytes, and embedding() gives floats for numpy.
+
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::engine::{self, Engine, OpenMode};
+use crate::image_hashes;
+use crate::indexed_image::IndexedImage;
+
+fn to_py_err(e: anyhow::Error) -> PyErr {
+	PyValueError::new_err(e.to_string())
+}
+
+fn open_image(path: &str) -> PyResult<image::DynamicImage> {
+	image::open(path).map_err(|e| PyIOError::new_err(format!("Unable to open {}: {}", path, e)))
+}
+
+#[pyclass(name = "IndexedImage")]
+#[derive(Clone)]
+struct PyIndexedImage {
+	inner: IndexedImage,
+}
+
+#[pymethods]
+impl PyIndexedImage {
+	/// Load and hash an image that isn't in any index.
+	#[staticmethod]
+	fn from_file(path: &str) -> PyResult<Self> {
+		let inner = IndexedImage::from_file_path(Path::new(path)).map_err(to_py_err)?;
+		Ok(PyIndexedImage { inner })
+	}
+
+	#[getter]
+	fn id(&self) -> i64 { self.inner.id }
+	#[getter]
+	fn path(&self) -> &str { &self.inner.path }
+	#[getter]
+	fn filename(&self) -> &str { &self.inner.filename }
+	#[getter]
+	fn width(&self) -> u32 { self.inner.resolution.0 }
+	#[getter]
+	fn height(&self) -> u32 { self.inner.resolution.1 }
+	#[getter]
+	fn file_size(&self) -> u64 { self.inner.file_size }
+	#[getter]
+	fn rating(&self) -> u8 { self.inner.rating }
+	#[getter]
+	fn favorite(&self) -> bool { self.inner.favorite }
+	#[getter]
+	fn tags(&self) -> HashMap<String, String> { self.inner.tags.clone() }
+	/// How far this was from the query, if the query was by similarity.
+	#[getter]
+	fn distance(&self) -> Option<f64> { self.inner.distance_from_query }
+	#[getter]
+	fn phash<'py>(&self, py: Python<'py>) -> Option<&'py PyBytes> {
+		self.inner.phash.as_ref().map(|h| PyBytes::new(py, h))
+	}
+	#[getter]
+	fn visual_hash<'py>(&self, py: Python<'py>) -> Option<&'py PyBytes> {
+		self.inner.visual_hash.as_ref().map(|h| PyBytes::new(py, h))
+	}
+	#[getter]
+	fn palette<'py>(&self, py: Python<'py>) -> Option<&'py PyBytes> {
+		self.inner.palette.as_ref().map(|h| PyBytes::new(py, h))
+	}
+	/// The visual hash as floats in [-1, 1].
+	#[getter]
+	fn embedding(&self) -> Option<Vec<f32>> {
+		self.inner.visual_hash.as_ref().map(|h| image_hashes::dequantize_embedding(h))
+	}
+
+	fn __repr__(&self) -> String {
+		format!("IndexedImage(id={}, path={:?})", self.inner.id, self.inner.path)
+	}
+}
+
+fn wrap_images(images: Vec<IndexedImage>) -> Vec<PyIndexedImage> {
+	images.into_iter().map(|inner| PyIndexedImage { inner }).collect()
+}
+
+// The engine keeps channels to its background threads, so it stays on the thread that made it.
+#[pyclass(name = "Engine", unsendable)]
+struct PyEngine {
+	inner: Engine,
+}
+
+#[pymethods]
+impl PyEngine {
+	/// Open an existing database.  Use Engine.create for a new one.
+	#[new]
+	fn new(db_path: &str) -> PyResult<Self> {
+		Ok(PyEngine { inner: Engine::open_or_create(Path::new(db_path), OpenMode::OpenExisting).map_err(to_py_err)? })
+	}
+
+	#[staticmethod]
+	fn create(db_path: &str) -> PyResult<Self> {
+		Ok(PyEngine { inner: Engine::open_or_create(Path::new(db_path), OpenMode::CreateNew).map_err(to_py_err)? })
+	}
+
+	/// Run a search using the same query language as the search box.
+	#[pyo3(signature = (query, page=0, page_size=None))]
+	fn query(&mut self, query: String, page: u64, page_size: Option<u64>) -> PyResult<Vec<PyIndexedImage>> {
+		let page_size = page_size.unwrap_or(self.inner.max_search_results);
+		let results = self.inner.query_page(&query, page, page_size).map_err(to_py_err)?;
+		Ok(wrap_images(results.results))
+	}
+
+	/// Find indexed images that look like the one at image_path.
+	fn similar_to_file(&mut self, image_path: &str) -> PyResult<Vec<PyIndexedImage>> {
+		let image = IndexedImage::from_file_path(Path::new(image_path)).map_err(to_py_err)?;
+		self.similar_to(&PyIndexedImage { inner: image })
+	}
+
+	fn similar_to(&mut self, image: &PyIndexedImage) -> PyResult<Vec<PyIndexedImage>> {
+		if image.inner.visual_hash.is_none() {
+			return Err(PyValueError::new_err(format!("{} has no visual hash.", image.inner.path)));
+		}
+		self.inner.query_by_image_hash_from_image(&image.inner).map_err(to_py_err)?;
+		Ok(wrap_images(self.inner.get_query_results().unwrap_or_default()))
+	}
+
+	/// Register another embedding model, like nomic or BLIP, by name, version, and number of dimensions.  Returns its id.
+	fn register_embedding_model(&mut self, name: &str, version: &str, dimensions: usize) -> PyResult<i64> {
+		self.inner.register_embedding_model(name, version, dimensions).map_err(to_py_err)
+	}
+
+	/// (id, name, version, dimensions, number of images embedded) for each registered model.
+	fn embedding_models(&self) -> PyResult<Vec<(i64, String, String, usize, u64)>> {
+		let models = self.inner.get_embedding_models().map_err(to_py_err)?;
+		Ok(models.into_iter().map(|m| (m.id, m.name, m.version, m.dimensions, m.num_embeddings)).collect())
+	}
+
+	/// Store an embedding from a registered model.  Values should be in [-1, 1].
+	fn set_embedding(&mut self, image: &PyIndexedImage, model_id: i64, embedding: Vec<f32>) -> PyResult<()> {
+		self.inner.set_embedding(image.inner.id, model_id, &embedding).map_err(to_py_err)
+	}
+
+	fn get_embedding(&self, image: &PyIndexedImage, model_id: i64) -> PyResult<Option<Vec<f32>>> {
+		self.inner.get_embedding(image.inner.id, model_id).map_err(to_py_err)
+	}
+
+	/// Find indexed images with embeddings from the model close to this one, like a text embedding from the same model.
+	fn similar_to_embedding(&mut self, model_id: i64, embedding: Vec<f32>) -> PyResult<Vec<PyIndexedImage>> {
+		self.inner.query_by_model_embedding(model_id, &embedding).map_err(to_py_err)?;
+		Ok(wrap_images(self.inner.get_query_results().unwrap_or_default()))
+	}
+
+	fn add_tracked_folder(&mut self, folder_glob: String) -> PyResult<()> {
+		self.inner.add_tracked_folder(folder_glob).map_err(to_py_err)
+	}
+
+	fn tracked_folders(&mut self) -> PyResult<Vec<String>> {
+		Ok(self.inner.get_tracked_folders().map_err(to_py_err)?.clone())
+	}
+
+	/// Folders with a higher priority are indexed first.  Folders start at 0.
+	fn set_folder_priority(&mut self, folder_glob: &str, priority: i64) -> PyResult<()> {
+		self.inner.set_folder_priority(folder_glob, priority).map_err(to_py_err)
+	}
+
+	/// Skip files in a tracked folder matching any of these globs, like "**/node_modules/**" or "*.tmp".  Replaces the folder's old patterns.
+	fn set_ignore_patterns(&mut self, folder_glob: &str, patterns: Vec<String>) -> PyResult<()> {
+		self.inner.set_ignore_patterns(folder_glob, &patterns).map_err(to_py_err)
+	}
+
+	fn ignore_patterns(&mut self, folder_glob: &str) -> PyResult<Vec<String>> {
+		self.inner.get_ignore_patterns(folder_glob).map_err(to_py_err)
+	}
+
+	/// Change how a tracked folder is crawled.  Options that aren't given are left as they are.
+	/// extensions limits it to files with those extensions.  An empty list allows every kind.  archive_password opens the encrypted archives in it.
+	#[pyo3(signature = (folder_glob, recursive=None, extensions=None, scan_archives=None, follow_symlinks=None, archive_password=None))]
+	fn set_folder_options(&mut self, folder_glob: &str, recursive: Option<bool>, extensions: Option<Vec<String>>, scan_archives: Option<bool>, follow_symlinks: Option<bool>, archive_password: Option<String>) -> PyResult<()> {
+		let mut settings = self.inner.get_folder_settings(folder_glob).map_err(to_py_err)?;
+		settings.recursive = recursive.unwrap_or(settings.recursive);
+		settings.extensions = extensions.unwrap_or(settings.extensions);
+		settings.scan_archives = scan_archives.unwrap_or(settings.scan_archives);
+		settings.follow_symlinks = follow_symlinks.unwrap_or(settings.follow_symlinks);
+		settings.archive_password = archive_password.unwrap_or(settings.archive_password);
+		self.inner.set_folder_settings(&settings).map_err(to_py_err)
+	}
+
+	/// Start indexing the tracked folders in the background.  Poll is_indexing or indexing_progress to see when it's done.
+	fn start_reindexing(&mut self) -> PyResult<()> {
+		self.inner.start_reindexing().map_err(to_py_err)
+	}
+
+	/// Like start_reindexing, but for just one of the tracked folders.
+	fn reindex_folder(&mut self, folder_glob: &str) -> PyResult<()> {
+		self.inner.reindex_folder(folder_glob).map_err(to_py_err)
+	}
+
+	/// Hold indexing where it is without losing anything queued.  resume_indexing picks it back up.
+	fn pause_indexing(&self) {
+		self.inner.pause_indexing();
+	}
+
+	fn resume_indexing(&self) {
+		self.inner.resume_indexing();
+	}
+
+	fn is_indexing_paused(&self) -> bool {
+		self.inner.is_indexing_paused()
+	}
+
+	/// Stop indexing.  What was already loaded is still stored, so is_indexing stays true for a moment.
+	fn cancel_indexing(&self) {
+		self.inner.cancel_indexing();
+	}
+
+	fn is_indexing(&self) -> bool {
+		self.inner.is_indexing_active()
+	}
+
+	fn indexing_progress(&self) -> f32 {
+		self.inner.get_indexing_progress()
+	}
+
+	/// Seconds the last indexing run has left, going at the rate so far.  None while it's still looking for files.
+	fn indexing_eta(&self) -> Option<f64> {
+		self.inner.get_indexing_progress_details()?.eta.map(|eta| eta.as_secs_f64())
+	}
+
+	/// The folders the last indexing run skipped because they couldn't be reached, like ones on a NAS that's asleep.
+	fn unreachable_folders(&self) -> Vec<String> {
+		self.inner.get_unreachable_folders()
+	}
+
+	/// (path, error, when) for every file that couldn't be indexed, most recent first.
+	fn indexing_failures(&self) -> PyResult<Vec<(String, String, String)>> {
+		let failures = self.inner.get_indexing_failures().map_err(to_py_err)?;
+		Ok(failures.into_iter().map(|f| (f.path, f.error, f.failed)).collect())
+	}
+
+	/// Index everything in indexing_failures again in the background.  Returns how many files and folders were queued.
+	fn retry_failed(&mut self) -> PyResult<usize> {
+		self.inner.retry_failed().map_err(to_py_err)
+	}
+
+	/// Forget the recorded failures.  Returns how many there were.
+	fn clear_indexing_failures(&mut self) -> PyResult<usize> {
+		self.inner.clear_indexing_failures().map_err(to_py_err)
+	}
+
+	/// Trash an image and keep it, and copies of its file, from being indexed again.
+	fn exclude_image(&mut self, image_id: i64) -> PyResult<()> {
+		self.inner.exclude_image(image_id).map_err(to_py_err)
+	}
+
+	/// (path, matched by content, when) for every excluded image.
+	fn exclusions(&self) -> PyResult<Vec<(String, bool, String)>> {
+		let exclusions = self.inner.get_exclusions().map_err(to_py_err)?;
+		Ok(exclusions.into_iter().map(|e| (e.path, e.by_content, e.excluded)).collect())
+	}
+
+	fn remove_exclusion(&mut self, path: &str) -> PyResult<()> {
+		self.inner.remove_exclusion(path).map_err(to_py_err)
+	}
+
+	fn __len__(&mut self) -> PyResult<usize> {
+		self.inner.get_num_indexed_images().map_err(to_py_err)
+	}
+}
+
+#[pyfunction]
+fn phash<'py>(py: Python<'py>, image_path: &str) -> PyResult<&'py PyBytes> {
+	Ok(PyBytes::new(py, &image_hashes::phash(&open_image(image_path)?)))
+}
+
+#[pyfunction]
+fn visual_hash<'py>(py: Python<'py>, image_path: &str) -> PyResult<&'py PyBytes> {
+	Ok(PyBytes::new(py, &image_hashes::mlhash(&open_image(image_path)?)))
+}
+
+/// The visual hash as floats in [-1, 1], ready for numpy.
+#[pyfunction]
+fn embedding(image_path: &str) -> PyResult<Vec<f32>> {
+	Ok(image_hashes::dequantize_embedding(&image_hashes::mlhash(&open_image(image_path)?)))
+}
+
+#[pyfunction]
+fn palette<'py>(py: Python<'py>, image_path: &str) -> PyResult<&'py PyBytes> {
+	Ok(PyBytes::new(py, &image_hashes::palette(&open_image(image_path)?)))
+}
+
+#[pyfunction]
+fn cosine_distance(hash_a: Vec<u8>, hash_b: Vec<u8>) -> f32 {
+	engine::cosine_distance(&hash_a, &hash_b)
+}
+
+#[pyfunction]
+fn hamming_distance(hash_a: Vec<u8>, hash_b: Vec<u8>) -> f32 {
+	engine::hamming_distance(&hash_a, &hash_b)
+}
+
+#[pymodule]
+fn pixelbox(_py: Python, m: &PyModule) -> PyResult<()> {
+	m.add_class::<PyEngine>()?;
+	m.add_class::<PyIndexedImage>()?;
+	m.add_function(wrap_pyfunction!(phash, m)?)?;
+	m.add_function(wrap_pyfunction!(visual_hash, m)?)?;
+	m.add_function(wrap_pyfunction!(embedding, m)?)?;
+	m.add_function(wrap_pyfunction!(palette, m)?)?;
+	m.add_function(wrap_pyfunction!(cosine_distance, m)?)?;
+	m.add_function(wrap_pyfunction!(hamming_distance, m)?)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::python::*;
+
+	#[test]
+	fn test_engine_bindings() {
+		pyo3::prepare_freethreaded_python();
+		let db_path = std::env::temp_dir().join(format!("pixelbox_test_python_engine_{}.db", std::process::id()));
+		let _ = std::fs::remove_file(&db_path);
+		let db_path_text = db_path.to_str().unwrap();
+
+		assert!(PyEngine::new(db_path_text).is_err()); // Only create makes a new DB.
+		let mut engine = PyEngine::create(db_path_text).unwrap();
+		assert!(PyEngine::create(db_path_text).is_err());
+		assert_eq!(engine.__len__().unwrap(), 0);
+		assert!(engine.query("cat".to_string(), 0, None).unwrap().is_empty());
+		assert!(engine.query("type:nonsense".to_string(), 0, None).is_err());
+
+		let model_id = engine.register_embedding_model("test-model", "1", 4).unwrap();
+		let models = engine.embedding_models().unwrap();
+		assert_eq!(models, vec![(model_id, "test-model".to_string(), "1".to_string(), 4, 0)]);
+
+		engine.add_tracked_folder("/nowhere/**".to_string()).unwrap();
+		assert_eq!(engine.tracked_folders().unwrap(), vec!["/nowhere/**".to_string()]);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_hash_functions() {
+		pyo3::prepare_freethreaded_python();
+		let image_path = std::env::temp_dir().join(format!("pixelbox_test_python_hash_{}.png", std::process::id()));
+		image::RgbImage::from_fn(32, 32, |x, _| image::Rgb([(x * 8) as u8, 0, 0])).save(&image_path).unwrap();
+		let image_path_text = image_path.to_str().unwrap();
+
+		Python::with_gil(|py| {
+			let hash = phash(py, image_path_text).unwrap().as_bytes().to_vec();
+			assert_eq!(hash.len(), 32);
+			assert_eq!(hamming_distance(hash.clone(), hash.clone()), 0.0);
+			assert_eq!(hamming_distance(hash.clone(), hash.iter().map(|b| !b).collect()), 1.0);
+			assert!(!palette(py, image_path_text).unwrap().as_bytes().is_empty());
+
+			let error = phash(py, "/nowhere/missing.png").unwrap_err();
+			assert!(error.is_instance_of::<PyIOError>(py));
+		});
+		assert_eq!(cosine_distance(vec![255, 0], vec![255, 0]), 0.0);
+
+		let _ = std::fs::remove_file(image_path);
+	}
+}
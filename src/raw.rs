@@ -0,0 +1,249 @@
+// Camera RAW files: CR2, CR3, NEF, ARW, DNG, and the rest of RAW_EXTENSIONS.
+// Almost all of them carry a full-size or near full-size JPEG preview, which is much faster to decode than the sensor data, so that's used when there is one.
+// Otherwise the sensor data is demosaiced, which needs the raw feature.
+
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, ImageFormat};
+use std::collections::HashSet;
+
+pub const RAW_EXTENSIONS: &[&str] = &["cr2", "cr3", "nef", "arw", "dng", "orf", "rw2", "raf", "srw", "pef"];
+
+const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW ";
+const JPEG_MAGIC: &[u8] = &[0xFF, 0xD8, 0xFF];
+const MAX_IFDS: usize = 64; // More than any camera writes.  Stops loops in broken files.
+
+// TIFF tags that say where the JPEGs are.
+const COMPRESSION: u16 = 0x103;
+const STRIP_OFFSETS: u16 = 0x111;
+const STRIP_BYTE_COUNTS: u16 = 0x117;
+const SUB_IFDS: u16 = 0x14A;
+const JPEG_OFFSET: u16 = 0x201;
+const JPEG_LENGTH: u16 = 0x202;
+const JPEG_COMPRESSION: [u32; 2] = [6, 7]; // Old-style and new-style.  Lossless JPEGs are new-style too, but the decoder turns those down.
+
+/// True if the filename ends in one of the RAW_EXTENSIONS, in any case.
+pub fn is_raw_file(filename: &str) -> bool {
+	filename.rsplit_once('.').map(|(_, extension)| RAW_EXTENSIONS.iter().any(|ext| extension.eq_ignore_ascii_case(ext))).unwrap_or(false)
+}
+
+/// The largest embedded preview that decodes, or else the demosaiced sensor data.
+pub fn decode_raw(bytes: &[u8]) -> Result<DynamicImage> {
+	let mut previews = if bytes.starts_with(RAF_MAGIC) {
+		raf_previews(bytes)?
+	} else if bytes.get(4..12) == Some(b"ftypcrx ") {
+		cr3_previews(bytes)
+	} else {
+		tiff_previews(bytes)?
+	};
+	previews.sort_by_key(|preview| std::cmp::Reverse(preview.len()));
+	for preview in previews {
+		if let Ok(img) = image::load_from_memory_with_format(preview, ImageFormat::Jpeg) {
+			return Ok(img);
+		}
+	}
+	demosaic(bytes)
+}
+
+/// Fujifilm puts one preview right after its own header.
+fn raf_previews(bytes: &[u8]) -> Result<Vec<&[u8]>> {
+	let offset = read_u32(bytes, 84, true)? as usize;
+	let length = read_u32(bytes, 88, true)? as usize;
+	Ok(jpeg_at(bytes, offset, length).into_iter().collect())
+}
+
+/// Canon's CR3s are ISO media files, with a preview in a PRVW box and a thumbnail in a THMB box.
+/// Both have a short header of their own before the JPEG starts.
+fn cr3_previews(bytes: &[u8]) -> Vec<&[u8]> {
+	[b"PRVW", b"THMB"].iter().filter_map(|name| {
+		let start = bytes.windows(4).position(|window| window == *name)?;
+		let header = bytes.get(start..(start + 64).min(bytes.len()))?;
+		let jpeg_start = start + header.windows(3).position(|window| window == JPEG_MAGIC)?;
+		Some(&bytes[jpeg_start..])
+	}).collect()
+}
+
+/// The JPEGs every IFD of a TIFF-based RAW points to, including SubIFDs and the IFDs chained after each.
+fn tiff_previews(bytes: &[u8]) -> Result<Vec<&[u8]>> {
+	// Some makers swap the 42 for their own number, so only the byte order is checked.
+	let big_endian = match bytes.get(0..2) {
+		Some(b"II") => false,
+		Some(b"MM") => true,
+		_ => return Err(anyhow!("It isn't a TIFF-based RAW file.")),
+	};
+	let mut previews = vec![];
+	let mut pending = vec![read_u32(bytes, 4, big_endian)? as usize];
+	let mut visited = HashSet::new();
+	while let Some(ifd) = pending.pop() {
+		if ifd == 0 || visited.len() >= MAX_IFDS || !visited.insert(ifd) {
+			continue;
+		}
+		let count = read_u16(bytes, ifd, big_endian)? as usize;
+		// A field's value is in the entry itself when it fits, which it does for the single offsets and lengths we want.
+		let field = |tag: u16| -> Option<(u32, u32, usize)> {
+			(0..count).map(|i| ifd + 2 + i * 12).find(|&entry| read_u16(bytes, entry, big_endian).ok() == Some(tag)).and_then(|entry| {
+				Some((read_u16(bytes, entry + 2, big_endian).ok()? as u32, read_u32(bytes, entry + 4, big_endian).ok()?, entry + 8))
+			})
+		};
+		let value = |tag: u16| -> Option<u32> {
+			match field(tag)? {
+				(3, 1, at) => read_u16(bytes, at, big_endian).ok().map(u32::from),
+				(4 | 13, 1, at) => read_u32(bytes, at, big_endian).ok(),
+				_ => None,
+			}
+		};
+
+		if let (Some(offset), Some(length)) = (value(JPEG_OFFSET), value(JPEG_LENGTH)) {
+			previews.extend(jpeg_at(bytes, offset as usize, length as usize));
+		}
+		// Only JPEGs stored as one strip can be decoded as they are.
+		if value(COMPRESSION).map(|compression| JPEG_COMPRESSION.contains(&compression)).unwrap_or(false) {
+			if let (Some(offset), Some(length)) = (value(STRIP_OFFSETS), value(STRIP_BYTE_COUNTS)) {
+				previews.extend(jpeg_at(bytes, offset as usize, length as usize));
+			}
+		}
+		if let Some((4 | 13, sub_ifd_count, at)) = field(SUB_IFDS) {
+			// More than one and the entry holds where the list of them is.
+			let list = if sub_ifd_count == 1 { at } else { read_u32(bytes, at, big_endian)? as usize };
+			for i in 0..(sub_ifd_count as usize).min(MAX_IFDS) {
+				pending.push(read_u32(bytes, list + i * 4, big_endian)? as usize);
+			}
+		}
+		pending.push(read_u32(bytes, ifd + 2 + count * 12, big_endian)? as usize);
+	}
+	Ok(previews)
+}
+
+/// The bytes at offset, if they're there and look like a JPEG.
+fn jpeg_at(bytes: &[u8], offset: usize, length: usize) -> Option<&[u8]> {
+	bytes.get(offset..offset.checked_add(length)?).filter(|jpeg| jpeg.starts_with(JPEG_MAGIC))
+}
+
+fn read_u16(bytes: &[u8], offset: usize, big_endian: bool) -> Result<u16> {
+	let field = bytes.get(offset..offset+2).ok_or_else(|| anyhow!("The RAW file is cut off."))?;
+	let field = [field[0], field[1]];
+	Ok(if big_endian { u16::from_be_bytes(field) } else { u16::from_le_bytes(field) })
+}
+
+fn read_u32(bytes: &[u8], offset: usize, big_endian: bool) -> Result<u32> {
+	let field = bytes.get(offset..offset+4).ok_or_else(|| anyhow!("The RAW file is cut off."))?;
+	let field = [field[0], field[1], field[2], field[3]];
+	Ok(if big_endian { u32::from_be_bytes(field) } else { u32::from_le_bytes(field) })
+}
+
+/// The sensor data as an image half the size of the sensor.  Each 2x2 block of the color filter becomes one pixel, which is plenty for a thumbnail and hashes.
+#[cfg(feature = "raw")]
+fn demosaic(bytes: &[u8]) -> Result<DynamicImage> {
+	let raw = rawloader::decode(&mut std::io::Cursor::new(bytes)).map_err(|e| anyhow!("It has no preview, and the sensor data couldn't be read: {:?}", e))?;
+	let rawloader::RawImageData::Integer(data) = &raw.data else {
+		return Err(anyhow!("It has no preview, and floating point sensor data isn't supported."));
+	};
+	let level = |value: u16, channel: usize| -> f32 {
+		let black = raw.blacklevels[channel] as f32;
+		let white = (raw.whitelevels[channel] as f32).max(black + 1.0);
+		((value as f32 - black) / (white - black)).clamp(0.0, 1.0)
+	};
+	// The white balance the camera was set to, relative to green.
+	let balance: Vec<f32> = (0..3).map(|channel| {
+		let coefficient = raw.wb_coeffs[channel] / raw.wb_coeffs[1];
+		if coefficient.is_finite() && coefficient > 0.0 { coefficient } else { 1.0 }
+	}).collect();
+	let to_srgb = |linear: f32| -> u8 { (linear.clamp(0.0, 1.0).powf(1.0 / 2.2) * 255.0).round() as u8 };
+
+	let img = match raw.cpp {
+		// Some DNGs are already RGB.
+		3 => image::RgbImage::from_fn(raw.width as u32, raw.height as u32, |x, y| {
+			let at = (y as usize * raw.width + x as usize) * 3;
+			image::Rgb([0, 1, 2].map(|channel| to_srgb(level(data[at + channel], channel))))
+		}),
+		1 => image::RgbImage::from_fn((raw.width / 2) as u32, (raw.height / 2) as u32, |x, y| {
+			let mut sums = [0.0f32; 3];
+			let mut counts = [0.0f32; 3];
+			for (dy, dx) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+				let (row, col) = (y as usize * 2 + dy, x as usize * 2 + dx);
+				// Four color filters count their second green as its own color.
+				let color = match raw.cfa.color_at(row, col) { 3 => 1, color => color.min(2) };
+				sums[color] += level(data[row * raw.width + col], color);
+				counts[color] += 1.0;
+			}
+			image::Rgb([0, 1, 2].map(|channel| if counts[channel] > 0.0 { to_srgb(sums[channel] / counts[channel] * balance[channel]) } else { 0 }))
+		}),
+		cpp => return Err(anyhow!("It has no preview, and sensor data with {} values per pixel isn't supported.", cpp)),
+	};
+	Ok(DynamicImage::ImageRgb8(img))
+}
+
+#[cfg(not(feature = "raw"))]
+fn demosaic(_bytes: &[u8]) -> Result<DynamicImage> {
+	Err(anyhow!("It has no preview this build can read.  Rebuild with --features raw to demosaic it."))
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::raw::*;
+	use std::io::Cursor;
+
+	fn make_test_jpeg(width: u32, height: u32) -> Vec<u8> {
+		let mut jpeg = vec![];
+		DynamicImage::new_rgb8(width, height).write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg).unwrap();
+		jpeg
+	}
+
+	/// A little-endian TIFF with one IFD per JPEG, each pointing to it with JPEGInterchangeFormat.
+	fn make_test_tiff(jpegs: &[&[u8]]) -> Vec<u8> {
+		let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+		let data_start = 8 + jpegs.len() * 30;
+		let mut data_at = data_start;
+		for (i, jpeg) in jpegs.iter().enumerate() {
+			let next_ifd = if i + 1 < jpegs.len() { 8 + (i + 1) * 30 } else { 0 };
+			tiff.extend(2u16.to_le_bytes());
+			for (tag, value) in [(JPEG_OFFSET, data_at), (JPEG_LENGTH, jpeg.len())] {
+				tiff.extend(tag.to_le_bytes());
+				tiff.extend(4u16.to_le_bytes());
+				tiff.extend(1u32.to_le_bytes());
+				tiff.extend((value as u32).to_le_bytes());
+			}
+			tiff.extend((next_ifd as u32).to_le_bytes());
+			data_at += jpeg.len();
+		}
+		for jpeg in jpegs {
+			tiff.extend(*jpeg);
+		}
+		tiff
+	}
+
+	#[test]
+	fn test_raw_previews() {
+		assert!(is_raw_file("IMG_0001.CR2"));
+		assert!(is_raw_file("DSC_1234.nef"));
+		assert!(!is_raw_file("IMG_0001.jpg"));
+
+		// The biggest preview wins, wherever it is in the chain.
+		let thumbnail = make_test_jpeg(16, 12);
+		let preview = make_test_jpeg(64, 48);
+		let tiff = make_test_tiff(&[&thumbnail, &preview]);
+		assert_eq!(tiff_previews(&tiff).unwrap().len(), 2);
+		let img = decode_raw(&tiff).unwrap();
+		assert_eq!((img.width(), img.height()), (64, 48));
+		// Ones that don't decode are passed over.
+		let mut broken = preview.clone();
+		broken.truncate(40);
+		let img = decode_raw(&make_test_tiff(&[&thumbnail, &broken])).unwrap();
+		assert_eq!((img.width(), img.height()), (16, 12));
+
+		let mut raf = RAF_MAGIC.to_vec();
+		raf.resize(84, 0);
+		raf.extend(100u32.to_be_bytes());
+		raf.extend((preview.len() as u32).to_be_bytes());
+		raf.resize(100, 0);
+		raf.extend(&preview);
+		assert_eq!(decode_raw(&raf).unwrap().width(), 64);
+
+		let mut cr3 = b"\0\0\0\x18ftypcrx ".to_vec();
+		cr3.extend(b"\0\0\0\0PRVW\0\0\0\0");
+		cr3.extend(&preview);
+		assert_eq!(decode_raw(&cr3).unwrap().width(), 64);
+
+		assert!(decode_raw(b"not a raw file").is_err());
+		assert!(decode_raw(&tiff[..20]).is_err());
+	}
+}
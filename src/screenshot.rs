@@ -0,0 +1,178 @@
+// Which window a screenshot is of, for the capture tools that name files after the window.
+// Screenshots from tools that don't can have their title bar read instead, with the ocr feature and Tesseract installed.
+
+use image::DynamicImage;
+
+/// The tag the title read off a screenshot's title bar is stored under.
+pub const WINDOW_TITLE_TAG: &'static str = "WindowTitle";
+#[cfg(feature = "ocr")]
+const TITLE_BAR_MIN_HEIGHT: u32 = 24;
+#[cfg(feature = "ocr")]
+const TITLE_BAR_MAX_HEIGHT: u32 = 96; // Title bars on high DPI screens.
+
+/// The window a screenshot was taken of.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScreenshotSource {
+	pub app: String,
+	pub window: String,
+	pub captured: Option<String>, // Like 2024-03-14 10:22:13, so SQLite can compare it.
+}
+
+/// Find the window title and capture time in a screenshot's filename.
+/// Knows Xbox Game Bar's "Figma 3_14_2024 10_22_13 AM.png" and older "Figma 2024-03-14 10-22-13.png", and Greenshot's "2024-03-14 10_22_13-Figma.png".
+pub fn detect_screenshot_source(filename: &str) -> Option<ScreenshotSource> {
+	let stem = filename.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(filename);
+	let (window, captured) = parse_game_bar_name(stem).or_else(|| parse_greenshot_name(stem))?;
+	let window = window.trim().to_string();
+	if window.is_empty() {
+		return None;
+	}
+	Some(ScreenshotSource { app: app_from_title(&window), window, captured: Some(captured) })
+}
+
+/// The window a screenshot is of, from the title read off its title bar.  When it was taken isn't known.
+pub fn source_from_window_title(title: &str) -> Option<ScreenshotSource> {
+	let window = title.trim().to_string();
+	if window.is_empty() {
+		return None;
+	}
+	Some(ScreenshotSource { app: app_from_title(&window), window, captured: None })
+}
+
+/// True if the path looks like a screenshot's, the way type:screenshot decides.
+pub fn is_screenshot_path(path: &str) -> bool {
+	let path = path.to_lowercase();
+	let filename = path.rsplit(['/', '\\']).next().unwrap_or(&path);
+	["screenshot", "screen shot", "capture"].iter().any(|word| filename.contains(word)) || path.contains("screenshots")
+}
+
+/// Read the window title from the top strip of a screenshot with Tesseract.
+/// None if Tesseract isn't installed or nothing like a title could be read.
+#[cfg(feature = "ocr")]
+pub fn read_title_bar(img: &DynamicImage) -> Option<String> {
+	use std::io::Write;
+	use std::process::{Command, Stdio};
+
+	let strip_height = (img.height() / 16).clamp(TITLE_BAR_MIN_HEIGHT, TITLE_BAR_MAX_HEIGHT).min(img.height());
+	let mut png = vec![];
+	DynamicImage::ImageLuma8(img.crop_imm(0, 0, img.width(), strip_height).to_luma8()).write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png).ok()?;
+	// Page segmentation mode 6 reads the strip as one block of text instead of looking for columns.
+	let mut tesseract = Command::new("tesseract").args(["stdin", "stdout", "--psm", "6"])
+		.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null())
+		.spawn().ok()?;
+	let written = tesseract.stdin.take().map(|mut stdin| stdin.write_all(&png).is_ok()).unwrap_or(false);
+	let output = tesseract.wait_with_output().ok()?;
+	if !written || !output.status.success() {
+		return None;
+	}
+	title_from_text(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Builds without the ocr feature don't read title bars.
+#[cfg(not(feature = "ocr"))]
+pub fn read_title_bar(_img: &DynamicImage) -> Option<String> {
+	None
+}
+
+/// The title in the text read off a title bar.  It's the longest line.  The rest are tabs, menus, and the window's buttons.
+#[cfg_attr(not(feature = "ocr"), allow(dead_code))]
+fn title_from_text(text: &str) -> Option<String> {
+	text.lines()
+		.map(|line| line.trim_matches(|c: char| !c.is_alphanumeric()))
+		.filter(|line| line.chars().filter(|c| c.is_alphanumeric()).count() >= 3)
+		.max_by_key(|line| line.chars().count())
+		.map(|line| line.to_string())
+}
+
+/// Titles usually end with the app's name, like "README.md - Visual Studio Code".  Otherwise the title is taken to be the app.
+fn app_from_title(title: &str) -> String {
+	[" - ", " — ", " | "].iter()
+		.filter_map(|separator| title.rsplit_once(separator).map(|(_, app)| app.trim()))
+		.filter(|app| !app.is_empty())
+		.min_by_key(|app| app.len())
+		.unwrap_or(title)
+		.to_string()
+}
+
+fn parse_game_bar_name(stem: &str) -> Option<(&str, String)> {
+	let parts: Vec<&str> = stem.rsplitn(4, ' ').collect();
+	// Newer versions: Title M_D_YYYY h_mm_ss AM
+	if parts.len() == 4 && (parts[0] == "AM" || parts[0] == "PM") {
+		let date = split_numbers(parts[2], '_')?;
+		let time = split_numbers(parts[1], '_')?;
+		if date.len() == 3 && time.len() == 3 && (1..=12).contains(&time[0]) {
+			let hour = time[0] % 12 + if parts[0] == "PM" { 12 } else { 0 };
+			return Some((parts[3], format_timestamp(date[2], date[0], date[1], hour, time[1], time[2])?));
+		}
+	}
+	// Older versions: Title YYYY-MM-DD HH-mm-ss
+	let parts: Vec<&str> = stem.rsplitn(3, ' ').collect();
+	if parts.len() == 3 {
+		let date = split_numbers(parts[1], '-')?;
+		let time = split_numbers(parts[0], '-')?;
+		if date.len() == 3 && time.len() == 3 {
+			return Some((parts[2], format_timestamp(date[0], date[1], date[2], time[0], time[1], time[2])?));
+		}
+	}
+	None
+}
+
+/// YYYY-MM-DD HH_mm_ss-Title
+fn parse_greenshot_name(stem: &str) -> Option<(&str, String)> {
+	let timestamp = stem.get(..19)?;
+	let title = stem.get(19..)?.strip_prefix('-')?;
+	let (date, time) = timestamp.split_once(' ')?;
+	let date = split_numbers(date, '-')?;
+	let time = split_numbers(time, '_')?;
+	if date.len() != 3 || time.len() != 3 {
+		return None;
+	}
+	Some((title, format_timestamp(date[0], date[1], date[2], time[0], time[1], time[2])?))
+}
+
+fn split_numbers(text: &str, separator: char) -> Option<Vec<u32>> {
+	text.split(separator).map(|n| if n.is_empty() || !n.chars().all(|c| c.is_ascii_digit()) { None } else { n.parse().ok() }).collect()
+}
+
+fn format_timestamp(year: u32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> Option<String> {
+	let valid = (1970..=9999).contains(&year) && (1..=12).contains(&month) && (1..=31).contains(&day) && hour < 24 && minute < 60 && second < 60;
+	valid.then(|| format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second))
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::screenshot::*;
+
+	#[test]
+	fn test_detect_screenshot_source() {
+		let game_bar = detect_screenshot_source("Figma 3_14_2024 10_22_13 PM.png").unwrap();
+		assert_eq!(game_bar, ScreenshotSource { app: "Figma".to_string(), window: "Figma".to_string(), captured: Some("2024-03-14 22:22:13".to_string()) });
+		let old_game_bar = detect_screenshot_source("README.md - Visual Studio Code 2024-03-14 10-22-13.png").unwrap();
+		assert_eq!(old_game_bar.app, "Visual Studio Code");
+		assert_eq!(old_game_bar.window, "README.md - Visual Studio Code");
+		assert_eq!(old_game_bar.captured.as_deref(), Some("2024-03-14 10:22:13"));
+		let greenshot = detect_screenshot_source("2024-03-14 00_05_09-Inbox — Mozilla Firefox.png").unwrap();
+		assert_eq!(greenshot.app, "Mozilla Firefox");
+		assert_eq!(greenshot.captured.as_deref(), Some("2024-03-14 00:05:09"));
+		assert_eq!(detect_screenshot_source("Figma 12_1_2024 12_00_00 AM.png").unwrap().captured.as_deref(), Some("2024-12-01 00:00:00"));
+
+		assert_eq!(detect_screenshot_source("Screenshot 2024-03-14 at 10.22.13.png"), None);
+		assert_eq!(detect_screenshot_source("holiday.jpg"), None);
+		assert_eq!(detect_screenshot_source("Figma 13_40_2024 10_22_13 AM.png"), None);
+		assert_eq!(detect_screenshot_source(" 2024-03-14 10-22-13.png"), None);
+	}
+
+	#[test]
+	fn test_window_titles() {
+		assert_eq!(title_from_text("File Edit View\n  README.md - Visual Studio Code  — □ ×\n\n").as_deref(), Some("README.md - Visual Studio Code"));
+		assert_eq!(title_from_text("— □ ×\n\n"), None);
+		let source = source_from_window_title("Inbox — Mozilla Firefox").unwrap();
+		assert_eq!(source, ScreenshotSource { app: "Mozilla Firefox".to_string(), window: "Inbox — Mozilla Firefox".to_string(), captured: None });
+		assert_eq!(source_from_window_title("  "), None);
+
+		assert!(is_screenshot_path("/home/me/Pictures/Screenshot from 2024-03-14.png"));
+		assert!(is_screenshot_path("C:\\Users\\me\\Pictures\\Screenshots\\Figma.png"));
+		assert!(is_screenshot_path("/home/me/Screenshots/a.png"));
+		assert!(!is_screenshot_path("/home/me/capture-the-flag/holiday.jpg"));
+	}
+}
@@ -0,0 +1,648 @@
+// A small HTTP API over an index, for the web UI and other programs on the network.
+// Run with `pixelbox --serve library.db [address]`.  Requests are handled one at a time.
+// Set PIXELBOX_TOKEN to require a token, and pass --tls-cert and --tls-key to serve HTTPS.
+// With --similarity-only, the only endpoint is /api/nearest, which takes an image and returns the ids of the closest indexed images.
+// POST /api/indexing/start reindexes the tracked folders, and /api/indexing/events streams start, progress, and done events as server-sent events.
+
+use anyhow::{anyhow, Result};
+use image::{imageops::FilterType, DynamicImage, ImageOutputFormat, RgbImage};
+use serde_json::{json, Value as JSONValue};
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::engine::{EmbeddingIndex, Engine};
+use crate::image_hashes::{fnv1a_hash, mlhash};
+use crate::indexed_image::{decode_thumbnail, IndexedImage, THUMBNAIL_SIZE};
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+pub const TOKEN_ENV_VAR: &str = "PIXELBOX_TOKEN"; // Read from the environment so it doesn't show up in process lists.
+const THUMBNAIL_REQUESTS_PER_SECOND: u32 = 200; // Per client.  A page of results is well under this.
+const MIN_THUMBNAIL_WIDTH: u32 = 16;
+const EMBEDDING_INDEX_MAX_AGE: Duration = Duration::from_secs(60); // So images indexed by the app while serving show up.
+const MAX_QUERY_IMAGE_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_NEAREST_COUNT: usize = 10;
+const MAX_NEAREST_COUNT: usize = 1000;
+const INDEXING_EVENTS_PATH: &str = "/api/indexing/events";
+const INDEXING_EVENT_INTERVAL: Duration = Duration::from_secs(1); // Between progress events.  Also the longest the server waits for a request before sending them.
+const EVENT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15); // So proxies don't close idle streams, and listeners that have gone away are noticed.
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerConfig {
+	pub address: String,
+	pub token: Option<String>, // When set, every request needs it, either as a bearer token or as ?token= for <img> tags.
+	pub tls_certificate: Option<PathBuf>, // PEM files.  Serve HTTPS when both are set.
+	pub tls_private_key: Option<PathBuf>,
+	pub similarity_only: bool, // Only serve /api/nearest, for other local apps that want to reuse the index.
+}
+
+impl ServerConfig {
+	/// Read `<db> [address] [--tls-cert cert.pem --tls-key key.pem] [--similarity-only]`, the arguments after --serve.
+	pub fn from_args(args: &[String], token: Option<String>) -> Result<(PathBuf, ServerConfig)> {
+		let mut positional = vec![];
+		let mut config = ServerConfig { address: DEFAULT_ADDRESS.to_string(), token: token.filter(|t| !t.is_empty()), ..Default::default() };
+		let mut args = args.iter();
+		while let Some(arg) = args.next() {
+			match arg.as_str() {
+				"--tls-cert" => config.tls_certificate = Some(args.next().ok_or_else(|| anyhow!("--tls-cert needs a file."))?.into()),
+				"--tls-key" => config.tls_private_key = Some(args.next().ok_or_else(|| anyhow!("--tls-key needs a file."))?.into()),
+				"--similarity-only" => config.similarity_only = true,
+				flag if flag.starts_with("--") => return Err(anyhow!("Unknown option {}.", flag)),
+				_ => positional.push(arg.clone()),
+			}
+		}
+		if config.tls_certificate.is_some() != config.tls_private_key.is_some() {
+			return Err(anyhow!("HTTPS needs both --tls-cert and --tls-key."));
+		}
+		match positional.as_slice() {
+			[db_path] => Ok((db_path.into(), config)),
+			[db_path, address] => Ok((db_path.into(), ServerConfig { address: address.clone(), ..config })),
+			_ => Err(anyhow!("Usage: pixelbox --serve <db> [address, default {}] [--tls-cert cert.pem --tls-key key.pem] [--similarity-only]", DEFAULT_ADDRESS)),
+		}
+	}
+
+	fn is_loopback(&self) -> bool {
+		self.address.parse::<SocketAddr>().map(|a| a.ip().is_loopback()).unwrap_or_else(|_| self.address.starts_with("localhost:"))
+	}
+}
+
+pub fn serve(engine: Engine, config: &ServerConfig) -> Result<()> {
+	let server = match (&config.tls_certificate, &config.tls_private_key) {
+		(Some(certificate), Some(private_key)) => https_server(config, certificate, private_key)?,
+		_ => Server::http(&config.address).map_err(|e| anyhow!("Unable to listen on {}: {}", config.address, e))?,
+	};
+	let scheme = if config.tls_certificate.is_some() { "https" } else { "http" };
+	println!("Serving on {}://{}", scheme, config.address);
+	if config.token.is_none() && !config.is_loopback() {
+		eprintln!("Warning: anyone who can reach {} can browse every image.  Set {} to require a token.", config.address, TOKEN_ENV_VAR);
+	}
+
+	serve_requests(&server, engine, config)
+}
+
+/// Answer requests as they come in, and keep /api/indexing/events listeners up to date between them.
+fn serve_requests(server: &Server, mut engine: Engine, config: &ServerConfig) -> Result<()> {
+	let mut embedding_index = None;
+	let mut embedding_index_loaded = Instant::now();
+	let mut thumbnail_limiter = RateLimiter::new(THUMBNAIL_REQUESTS_PER_SECOND, Duration::from_secs(1));
+	let mut indexing_events = IndexingEvents::default();
+	loop {
+		if let Some(mut request) = server.recv_timeout(INDEXING_EVENT_INTERVAL)? {
+			let is_event_stream = !config.similarity_only && *request.method() == Method::Get && request.url().split('?').next() == Some(INDEXING_EVENTS_PATH);
+			match unauthorized(config, &request) {
+				None if is_event_stream => indexing_events.listen(request, &mut engine),
+				Some(response) => respond(request, response),
+				None => {
+					// Only after the token check, so those without one can't keep the server busy reloading.
+					if config.similarity_only && (embedding_index.is_none() || embedding_index_loaded.elapsed() > EMBEDDING_INDEX_MAX_AGE) {
+						match engine.load_embedding_index() {
+							Ok(index) => {
+								embedding_index = Some(index);
+								embedding_index_loaded = Instant::now();
+							},
+							// Keep answering from the old one.  The DB may just be busy.
+							Err(e) => eprintln!("Failed to load embeddings: {}", e),
+						}
+					}
+					let response = handle_request(&mut engine, &mut thumbnail_limiter, &mut indexing_events, embedding_index.as_ref(), config, &mut request);
+					respond(request, response);
+				},
+			}
+		}
+		indexing_events.send_updates(&mut engine);
+	}
+}
+
+fn respond(request: Request, response: HttpResponse) {
+	if let Err(e) = request.respond(response) {
+		eprintln!("Failed to send response: {}", e);
+	}
+}
+
+#[cfg(feature = "tls")]
+fn https_server(config: &ServerConfig, certificate: &PathBuf, private_key: &PathBuf) -> Result<Server> {
+	let ssl_config = tiny_http::SslConfig {
+		certificate: std::fs::read(certificate).map_err(|e| anyhow!("Unable to read {}: {}", certificate.display(), e))?,
+		private_key: std::fs::read(private_key).map_err(|e| anyhow!("Unable to read {}: {}", private_key.display(), e))?,
+	};
+	Server::https(&config.address, ssl_config).map_err(|e| anyhow!("Unable to listen on {}: {}", config.address, e))
+}
+
+#[cfg(not(feature = "tls"))]
+fn https_server(_config: &ServerConfig, _certificate: &PathBuf, _private_key: &PathBuf) -> Result<Server> {
+	Err(anyhow!("This build doesn't support HTTPS.  Rebuild with --features tls."))
+}
+
+/// The 401 to send back if the server wants a token and the request doesn't have it.
+fn unauthorized(config: &ServerConfig, request: &Request) -> Option<HttpResponse> {
+	let token = config.token.as_ref()?;
+	let authorization = request.headers().iter().find(|h| h.field.equiv("Authorization")).map(|h| h.value.as_str());
+	if is_authorized(token, authorization, request.url()) {
+		None
+	} else {
+		Some(error_response(401, "A token is required.").with_header(header("WWW-Authenticate", "Bearer")))
+	}
+}
+
+fn handle_request(engine: &mut Engine, thumbnail_limiter: &mut RateLimiter, indexing_events: &mut IndexingEvents, embedding_index: Option<&EmbeddingIndex>, config: &ServerConfig, request: &mut Request) -> HttpResponse {
+	let header_value = |name: &'static str| request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str());
+	if config.similarity_only {
+		let Some(index) = embedding_index else {
+			return error_response(503, "The index couldn't be loaded.");
+		};
+		let mut body = vec![];
+		if let Err(e) = request.as_reader().take(MAX_QUERY_IMAGE_BYTES + 1).read_to_end(&mut body) {
+			return error_response(400, &format!("Unable to read the request: {}", e));
+		}
+		if body.len() as u64 > MAX_QUERY_IMAGE_BYTES {
+			return error_response(413, "The image is too large.");
+		}
+		return similarity_route(index, request.method(), request.url(), &body);
+	}
+	if request.url() == "/api/indexing/start" {
+		return match request.method() {
+			Method::Post => start_indexing(engine, indexing_events),
+			_ => error_response(405, "POST to /api/indexing/start."),
+		};
+	}
+	if *request.method() != Method::Get {
+		return error_response(405, "Only GET is supported.");
+	}
+	let client = request.remote_addr().map(|addr| addr.ip());
+	route(engine, thumbnail_limiter, client, request.url(), header_value("If-None-Match"))
+}
+
+/// Check for an `Authorization: Bearer <token>` header or a ?token= parameter.
+fn is_authorized(token: &str, authorization: Option<&str>, url: &str) -> bool {
+	let from_header = authorization.and_then(|a| a.strip_prefix("Bearer ")).map(|a| a.trim().to_string());
+	let from_url = url.split_once('?').and_then(|(_, query_string)| parse_query_string(query_string).remove("token"));
+	[from_header, from_url].into_iter().flatten().any(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+/// So how long a check takes doesn't give away how much of the token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn route(engine: &mut Engine, thumbnail_limiter: &mut RateLimiter, client: Option<IpAddr>, url: &str, if_none_match: Option<&str>) -> HttpResponse {
+	let (path, query_string) = url.split_once('?').unwrap_or((url, ""));
+	let params = parse_query_string(query_string);
+	if path == "/api/search" {
+		search(engine, &params)
+	} else if path == "/api/stats" {
+		match engine.stats() {
+			Ok(stats) => json_response(200, stats.to_json()),
+			Err(e) => error_response(500, &e.to_string()),
+		}
+	} else if let Some(id) = path.strip_prefix("/api/thumbnails/") {
+		if let Some(client) = client {
+			if !thumbnail_limiter.allow(client) {
+				return error_response(429, "Too many thumbnail requests.  Slow down.").with_header(header("Retry-After", "1"));
+			}
+		}
+		match id.parse::<i64>() {
+			Ok(id) => thumbnail(engine, id, &params, if_none_match),
+			Err(_) => error_response(400, "Thumbnail ids are numbers."),
+		}
+	} else {
+		error_response(404, "Not found.")
+	}
+}
+
+/// The only route in --similarity-only mode.
+fn similarity_route(index: &EmbeddingIndex, method: &Method, url: &str, body: &[u8]) -> HttpResponse {
+	let (path, query_string) = url.split_once('?').unwrap_or((url, ""));
+	if path != "/api/nearest" {
+		return error_response(404, "Only /api/nearest is served in similarity-only mode.");
+	}
+	if *method != Method::Post {
+		return error_response(405, "POST the image to /api/nearest.");
+	}
+	match parse_query_string(query_string).get("k").map(|k| k.parse::<usize>()) {
+		None => nearest(index, body, DEFAULT_NEAREST_COUNT),
+		Some(Ok(k)) => nearest(index, body, k.clamp(1, MAX_NEAREST_COUNT)),
+		Some(Err(_)) => error_response(400, "k must be a number of results."),
+	}
+}
+
+/// POST /api/nearest?k=10 with the image's bytes as the body.
+fn nearest(index: &EmbeddingIndex, image_bytes: &[u8], k: usize) -> HttpResponse {
+	let img = match image::load_from_memory(image_bytes) {
+		Ok(img) => img,
+		Err(e) => return error_response(400, &format!("Unable to read the image: {}", e)),
+	};
+	let results = index.nearest(&mlhash(&img), k).into_iter().map(|(id, distance)| json!({"id": id, "distance": distance})).collect::<Vec<JSONValue>>();
+	json_response(200, json!({"results": results}))
+}
+
+/// /api/search?q=cat&page=0&page_size=50
+fn search(engine: &mut Engine, params: &HashMap<String, String>) -> HttpResponse {
+	let query = params.get("q").cloned().unwrap_or_default();
+	let page = params.get("page").and_then(|p| p.parse().ok()).unwrap_or(0);
+	let page_size = params.get("page_size").and_then(|p| p.parse().ok()).unwrap_or(engine.max_search_results);
+	match engine.query_page(&query, page, page_size) {
+		Ok(page) => json_response(200, json!({
+			"total_results": page.total_results,
+			"results": page.results.iter().map(IndexedImage::summary_json).collect::<Vec<JSONValue>>(),
+		})),
+		Err(e) => error_response(400, &e.to_string()),
+	}
+}
+
+/// /api/thumbnails/123?w=128 as a PNG.  Thumbnails are only ever scaled down.
+/// Images tagged SENSITIVE_TAG get their blurred thumbnail, like in the UI, unless the request has a reveal parameter.
+fn thumbnail(engine: &Engine, image_id: i64, params: &HashMap<String, String>, if_none_match: Option<&str>) -> HttpResponse {
+	let width = match params.get("w").map(|w| w.parse::<u32>()) {
+		None => THUMBNAIL_SIZE.0,
+		Some(Ok(w)) => w.clamp(MIN_THUMBNAIL_WIDTH, THUMBNAIL_SIZE.0),
+		Some(Err(_)) => return error_response(400, "w must be a number of pixels."),
+	};
+	let thumbnail = match engine.get_thumbnail(image_id) {
+		Ok(Some(t)) => t,
+		Ok(None) => return error_response(404, "No image with that id."),
+		Err(e) => return error_response(500, &e.to_string()),
+	};
+	let thumbnail = if params.contains_key("reveal") {
+		thumbnail
+	} else {
+		match engine.get_blurred_thumbnail(image_id) {
+			Ok(blurred) => blurred.unwrap_or(thumbnail),
+			Err(e) => return error_response(500, &e.to_string()),
+		}
+	};
+
+	// The stored thumbnail and the width are all that go into the PNG, so they're all that go into the tag.
+	// FNV-1a is stable across runs and versions, unlike DefaultHasher, so tags stay good after a restart.
+	let etag = format!("\"{:016x}-{}\"", fnv1a_hash(&thumbnail), width);
+	let cache_headers = [header("ETag", &etag), header("Cache-Control", "private, max-age=60")];
+	if if_none_match.map(|tags| etag_matches(tags, &etag)).unwrap_or(false) {
+		let mut response = Response::from_data(vec![]).with_status_code(304);
+		cache_headers.into_iter().for_each(|h| response.add_header(h));
+		return response;
+	}
+
+	match encode_thumbnail_png(&thumbnail, width) {
+		Ok(png) => {
+			let mut response = Response::from_data(png).with_header(header("Content-Type", "image/png"));
+			cache_headers.into_iter().for_each(|h| response.add_header(h));
+			response
+		},
+		Err(e) => error_response(500, &e.to_string()),
+	}
+}
+
+/// POST /api/indexing/start reindexes the tracked folders, like Reindex in the app.  Follow along on /api/indexing/events.
+fn start_indexing(engine: &mut Engine, indexing_events: &mut IndexingEvents) -> HttpResponse {
+	if engine.is_indexing_active() {
+		return error_response(409, "Indexing is already running.");
+	}
+	if let Err(e) = engine.start_reindexing() {
+		return error_response(500, &e.to_string());
+	}
+	// Sent now rather than on the next check, which a small run could finish before.
+	indexing_events.send(engine, "start");
+	json_response(202, engine.get_indexing_status().to_json())
+}
+
+/// The open /api/indexing/events streams.  Each event's data is the indexing status as JSON.
+#[derive(Default)]
+struct IndexingEvents {
+	listeners: Vec<Box<dyn Write + Send>>,
+	was_active: bool,
+	last_sent: Option<Instant>,
+}
+
+impl IndexingEvents {
+	/// Take over the request's connection and keep it open for events.  Listeners who join mid-run are sent a start event right away.
+	fn listen(&mut self, request: Request, engine: &mut Engine) {
+		let mut writer = request.into_writer();
+		let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+		let mut sent = writer.write_all(head.as_bytes()).and_then(|_| writer.flush());
+		if sent.is_ok() && engine.is_indexing_active() {
+			sent = write_event(&mut writer, "start", &engine.get_indexing_status().to_json());
+		}
+		if sent.is_ok() {
+			if self.listeners.is_empty() {
+				self.was_active = engine.is_indexing_active();
+			}
+			self.listeners.push(writer);
+		}
+	}
+
+	/// Send start when a run begins, progress every INDEXING_EVENT_INTERVAL while it goes, and done when it's over.
+	/// In between runs, listeners get a comment now and then.
+	fn send_updates(&mut self, engine: &mut Engine) {
+		if self.listeners.is_empty() {
+			return;
+		}
+		let since_last_sent = self.last_sent.map(|sent| sent.elapsed());
+		match (self.was_active, engine.is_indexing_active()) {
+			(false, true) => self.send(engine, "start"),
+			(true, true) if since_last_sent.is_none_or(|elapsed| elapsed >= INDEXING_EVENT_INTERVAL) => self.send(engine, "progress"),
+			(true, false) => self.send(engine, "done"),
+			(false, false) if since_last_sent.is_none_or(|elapsed| elapsed >= EVENT_KEEPALIVE_INTERVAL) => self.send_to_all(b": keep-alive\n\n"),
+			_ => {},
+		}
+	}
+
+	fn send(&mut self, engine: &mut Engine, event: &str) {
+		self.was_active = event != "done";
+		if !self.listeners.is_empty() {
+			let status = engine.get_indexing_status().to_json();
+			self.send_to_all(format_event(event, &status).as_bytes());
+		}
+	}
+
+	/// Listeners that can't be written to have hung up, and are dropped.
+	fn send_to_all(&mut self, message: &[u8]) {
+		self.last_sent = Some(Instant::now());
+		self.listeners.retain_mut(|writer| writer.write_all(message).and_then(|_| writer.flush()).is_ok());
+	}
+}
+
+fn format_event(event: &str, data: &JSONValue) -> String {
+	format!("event: {}\ndata: {}\n\n", event, data)
+}
+
+fn write_event(writer: &mut impl Write, event: &str, data: &JSONValue) -> std::io::Result<()> {
+	writer.write_all(format_event(event, data).as_bytes())?;
+	writer.flush()
+}
+
+fn encode_thumbnail_png(thumbnail: &[u8], max_width: u32) -> Result<Vec<u8>> {
+	let (data, (width, height)) = decode_thumbnail(thumbnail)?;
+	let mut img = RgbImage::from_raw(width, height, data).ok_or_else(|| anyhow!("Thumbnail isn't RGB."))?;
+	if img.width() > max_width {
+		let height = (img.height() * max_width / img.width()).max(1);
+		img = image::imageops::resize(&img, max_width, height, FilterType::Triangle);
+	}
+	let mut png = vec![];
+	DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+	Ok(png)
+}
+
+/// Allows a fixed number of requests per client in each window.
+struct RateLimiter {
+	max_requests: u32,
+	window: Duration,
+	clients: HashMap<IpAddr, (Instant, u32)>, // When the client's window started and how many requests it's made in it.
+}
+
+impl RateLimiter {
+	fn new(max_requests: u32, window: Duration) -> Self {
+		RateLimiter { max_requests, window, clients: HashMap::new() }
+	}
+
+	fn allow(&mut self, client: IpAddr) -> bool {
+		let now = Instant::now();
+		let window = self.window;
+		// Forget clients that have gone quiet so this doesn't grow forever.
+		self.clients.retain(|_, (started, _)| now.duration_since(*started) < window);
+		let (_, count) = self.clients.entry(client).or_insert((now, 0));
+		*count += 1;
+		*count <= self.max_requests
+	}
+}
+
+fn header(name: &str, value: &str) -> Header {
+	Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("Header names and values are ASCII.")
+}
+
+fn json_response(status: u16, value: JSONValue) -> HttpResponse {
+	Response::from_data(value.to_string().into_bytes()).with_status_code(status).with_header(header("Content-Type", "application/json"))
+}
+
+fn error_response(status: u16, message: &str) -> HttpResponse {
+	json_response(status, json!({"error": message}))
+}
+
+/// If-None-Match can be a list of tags, weak tags, or *.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+	if_none_match.split(',').map(|t| t.trim().trim_start_matches("W/")).any(|t| t == etag || t == "*")
+}
+
+fn parse_query_string(query_string: &str) -> HashMap<String, String> {
+	query_string.split('&').filter(|pair| !pair.is_empty()).map(|pair| {
+		let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+		(percent_decode(name), percent_decode(value))
+	}).collect()
+}
+
+/// Decode %XX escapes and + for spaces.  Bad escapes are left as they are.
+fn percent_decode(text: &str) -> String {
+	let bytes = text.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut idx = 0;
+	while idx < bytes.len() {
+		match bytes[idx] {
+			b'+' => decoded.push(b' '),
+			b'%' => match bytes.get(idx+1..idx+3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+				Some(b) => {
+					decoded.push(b);
+					idx += 2;
+				},
+				None => decoded.push(b'%'),
+			},
+			b => decoded.push(b),
+		}
+		idx += 1;
+	}
+	String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::engine::{Engine, OpenMode, SENSITIVE_TAG};
+	use crate::indexed_image::IndexedImage;
+	use crate::server::*;
+	use std::io::{BufRead, BufReader, Read};
+	use std::net::{Ipv4Addr, TcpStream};
+
+	#[test]
+	fn test_parse_query_string() {
+		let params = parse_query_string("q=cat+rating%3A%3E%3D4&page=2&bad=100%&empty");
+		assert_eq!(params["q"], "cat rating:>=4");
+		assert_eq!(params["page"], "2");
+		assert_eq!(params["bad"], "100%");
+		assert_eq!(params["empty"], "");
+		assert!(parse_query_string("").is_empty());
+	}
+
+	#[test]
+	fn test_server_config_from_args() {
+		let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+		let (db_path, config) = ServerConfig::from_args(&args(&["lib.db"]), Some("".to_string())).unwrap();
+		assert_eq!(db_path, PathBuf::from("lib.db"));
+		assert_eq!(config, ServerConfig { address: DEFAULT_ADDRESS.to_string(), ..Default::default() });
+		assert!(config.is_loopback());
+
+		let (_, config) = ServerConfig::from_args(&args(&["--tls-cert", "c.pem", "lib.db", "0.0.0.0:443", "--tls-key", "k.pem"]), Some("secret".to_string())).unwrap();
+		assert_eq!(config.address, "0.0.0.0:443");
+		assert_eq!(config.token.as_deref(), Some("secret"));
+		assert_eq!(config.tls_private_key, Some(PathBuf::from("k.pem")));
+		assert!(!config.is_loopback());
+
+		assert!(ServerConfig::from_args(&args(&[]), None).is_err());
+		assert!(ServerConfig::from_args(&args(&["lib.db", "--tls-cert", "c.pem"]), None).is_err()); // No key.
+		assert!(ServerConfig::from_args(&args(&["lib.db", "--tls-key"]), None).is_err());
+		assert!(ServerConfig::from_args(&args(&["lib.db", "--verbose"]), None).is_err());
+
+		let (_, config) = ServerConfig::from_args(&args(&["lib.db", "--similarity-only"]), None).unwrap();
+		assert!(config.similarity_only);
+	}
+
+	#[test]
+	fn test_is_authorized() {
+		assert!(is_authorized("s3cret", Some("Bearer s3cret"), "/api/search?q=cat"));
+		assert!(is_authorized("s3cret", None, "/api/thumbnails/1?w=64&token=s3cret"));
+		assert!(is_authorized("a b", None, "/api/search?token=a+b"));
+		assert!(!is_authorized("s3cret", None, "/api/search?q=cat"));
+		assert!(!is_authorized("s3cret", Some("Bearer s3cre"), "/api/search"));
+		assert!(!is_authorized("s3cret", Some("s3cret"), "/api/search")); // Not a bearer token.
+		assert!(!is_authorized("s3cret", Some("Bearer wrong"), "/api/search?token=also_wrong"));
+	}
+
+	#[test]
+	fn test_rate_limiter() {
+		let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+		let a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+		let b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+		assert!(limiter.allow(a));
+		assert!(limiter.allow(a));
+		assert!(!limiter.allow(a));
+		assert!(limiter.allow(b)); // Each client gets its own budget.
+
+		let mut limiter = RateLimiter::new(1, Duration::ZERO);
+		assert!(limiter.allow(a));
+		assert!(limiter.allow(a)); // The window is already over.
+	}
+
+	#[test]
+	fn test_similarity_route() {
+		let index = EmbeddingIndex::default();
+		assert_eq!(similarity_route(&index, &Method::Post, "/api/search?q=cat", &[]).status_code().0, 404);
+		assert_eq!(similarity_route(&index, &Method::Get, "/api/nearest", &[]).status_code().0, 405);
+		assert_eq!(similarity_route(&index, &Method::Post, "/api/nearest?k=many", &[]).status_code().0, 400);
+		assert_eq!(similarity_route(&index, &Method::Post, "/api/nearest?k=5", b"not an image").status_code().0, 400);
+	}
+
+	#[test]
+	fn test_thumbnails() {
+		let db_path = std::env::temp_dir().join(format!("pixelbox_test_server_{}.db", std::process::id()));
+		let _ = std::fs::remove_file(&db_path);
+		let mut engine = Engine::open_or_create(&db_path, OpenMode::CreateNew).unwrap();
+		let image_id = engine.add_image(IndexedImage {
+			id: 0,
+			filename: "wide.png".to_string(),
+			path: "/test/wide.png".to_string(),
+			resolution: (512, 256),
+			file_size: 0,
+			protected: false,
+			rating: 0,
+			favorite: false,
+			thumbnail: qoi::encode_to_vec(RgbImage::from_fn(256, 128, |x, y| image::Rgb([((x ^ y) & 1) as u8 * 255; 3])).into_raw(), 256, 128).unwrap(),
+			blurred_thumbnail: None,
+			created: Instant::now(),
+			indexed: Instant::now(),
+			tags: HashMap::new(),
+			phash: None,
+			visual_hash: None,
+			cropped_hash: None,
+			palette: None,
+			color_layout: None,
+			distance_from_query: None,
+		}).unwrap();
+		let mut limiter = RateLimiter::new(3, Duration::from_secs(60));
+		let client = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+		let etag_of = |response: &HttpResponse| response.headers().iter().find(|h| h.field.equiv("ETag")).map(|h| h.value.to_string());
+
+		let url = format!("/api/thumbnails/{}?w=128", image_id);
+		let response = route(&mut engine, &mut limiter, client, &url, None);
+		assert_eq!(response.status_code().0, 200);
+		let etag = etag_of(&response).unwrap();
+		let png = image::load_from_memory(&encode_thumbnail_png(&engine.get_thumbnail(image_id).unwrap().unwrap(), 128).unwrap()).unwrap();
+		assert_eq!((png.width(), png.height()), (128, 64));
+
+		let unchanged = route(&mut engine, &mut limiter, client, &url, Some(&format!("\"other\", W/{}", etag)));
+		assert_eq!(unchanged.status_code().0, 304);
+		assert_eq!(etag_of(&unchanged), Some(etag.clone()));
+
+		// Another size is another tag.
+		let full_size = route(&mut engine, &mut limiter, client, &format!("/api/thumbnails/{}", image_id), Some(&etag));
+		assert_eq!(full_size.status_code().0, 200);
+		assert_ne!(etag_of(&full_size), Some(etag));
+
+		assert_eq!(route(&mut engine, &mut limiter, client, &url, None).status_code().0, 429);
+		assert_eq!(route(&mut engine, &mut limiter, None, "/api/thumbnails/999", None).status_code().0, 404);
+		assert_eq!(route(&mut engine, &mut limiter, None, "/api/thumbnails/abc", None).status_code().0, 400);
+		assert_eq!(route(&mut engine, &mut limiter, None, "/api/search?q=wide", None).status_code().0, 200);
+		assert_eq!(route(&mut engine, &mut limiter, None, "/api/stats", None).status_code().0, 200);
+		assert_eq!(route(&mut engine, &mut limiter, None, "/api/search?q=type%3Anonsense", None).status_code().0, 400);
+		assert_eq!(route(&mut engine, &mut limiter, None, "/nothing", None).status_code().0, 404);
+
+		// Sensitive images are blurred unless they're asked for with reveal.
+		engine.set_user_tag(image_id, SENSITIVE_TAG, "").unwrap();
+		let body_of = |response: HttpResponse| response.into_reader().into_inner();
+		let blurred = body_of(route(&mut engine, &mut limiter, None, &format!("/api/thumbnails/{}", image_id), None));
+		let revealed = body_of(route(&mut engine, &mut limiter, None, &format!("/api/thumbnails/{}?reveal", image_id), None));
+		assert_eq!(blurred, encode_thumbnail_png(&engine.get_blurred_thumbnail(image_id).unwrap().unwrap(), THUMBNAIL_SIZE.0).unwrap());
+		assert_eq!(revealed, encode_thumbnail_png(&engine.get_thumbnail(image_id).unwrap().unwrap(), THUMBNAIL_SIZE.0).unwrap());
+		assert_ne!(blurred, revealed);
+
+		// Trashed images are gone as far as the server is concerned.
+		engine.trash_images_in_folder("/test/").unwrap();
+		assert_eq!(route(&mut engine, &mut limiter, None, &format!("/api/thumbnails/{}", image_id), None).status_code().0, 404);
+
+		drop(engine);
+		let _ = std::fs::remove_file(db_path);
+	}
+
+	#[test]
+	fn test_indexing_events() {
+		let db_path = std::env::temp_dir().join(format!("pixelbox_test_server_events_{}.db", std::process::id()));
+		let folder = std::env::temp_dir().join(format!("pixelbox_test_server_events_{}", std::process::id()));
+		let _ = std::fs::remove_file(&db_path);
+		std::fs::create_dir_all(&folder).unwrap();
+		let mut engine = Engine::open_or_create(&db_path, OpenMode::CreateNew).unwrap();
+		engine.add_tracked_folder(folder.display().to_string()).unwrap();
+		let server = Server::http("127.0.0.1:0").unwrap();
+		let address = server.server_addr().to_ip().unwrap();
+		std::thread::spawn(move || serve_requests(&server, engine, &ServerConfig::default()));
+
+		let mut stream = TcpStream::connect(address).unwrap();
+		stream.set_read_timeout(Some(Duration::from_secs(60))).unwrap();
+		stream.write_all(b"GET /api/indexing/events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+		let mut lines = BufReader::new(stream).lines().map(|line| line.unwrap());
+		let head = lines.by_ref().take_while(|line| !line.is_empty()).collect::<Vec<String>>();
+		assert!(head[0].starts_with("HTTP/1.1 200"));
+		assert!(head.contains(&"Content-Type: text/event-stream".to_string()));
+
+		let mut start = TcpStream::connect(address).unwrap();
+		start.write_all(b"POST /api/indexing/start HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+		let mut response = String::new();
+		start.read_to_string(&mut response).unwrap();
+		assert!(response.starts_with("HTTP/1.1 202"));
+
+		// The folder is empty, so the run is over as soon as it's started.
+		let mut events = vec![];
+		while events.last().map(String::as_str) != Some("done") {
+			let line = lines.next().unwrap();
+			if let Some(event) = line.strip_prefix("event: ") {
+				events.push(event.to_string());
+			} else if let Some(data) = line.strip_prefix("data: ") {
+				let status: JSONValue = serde_json::from_str(data).unwrap();
+				assert!(status["num_pending"].is_number());
+			}
+		}
+		assert_eq!(events.first().map(String::as_str), Some("start"));
+		assert!(events[1..events.len()-1].iter().all(|event| event == "progress"));
+
+		let _ = std::fs::remove_file(db_path);
+		let _ = std::fs::remove_dir_all(folder);
+	}
+}
@@ -0,0 +1,74 @@
+// Files are told apart by their first bytes as well as their names.
+// Images without an extension are still indexed, and junk named like an image is skipped after a few bytes instead of being read whole.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+pub const SNIFF_BYTES: u64 = 16; // Enough for every signature below.
+
+// The extensions a format goes by, then the bytes its files start with at each offset.
+type Signature = (&'static [&'static str], &'static [(usize, &'static [u8])]);
+
+// The image formats that always start the same way.
+// Formats whose files can start any which way, like TGA, ICO, and SVG, aren't here, so they're only ever known by name.
+const SIGNATURES: &[Signature] = &[
+	(&["png"], &[(0, b"\x89PNG\r\n\x1a\n")]),
+	(&["jpg", "jpeg", "jfif"], &[(0, b"\xff\xd8\xff")]),
+	(&["gif"], &[(0, b"GIF87a")]),
+	(&["gif"], &[(0, b"GIF89a")]),
+	(&["bmp"], &[(0, b"BM")]),
+	(&["webp"], &[(0, b"RIFF"), (8, b"WEBP")]),
+	(&["tiff"], &[(0, b"II*\0")]),
+	(&["tiff"], &[(0, b"MM\0*")]),
+	(&["psd", "psb"], &[(0, b"8BPS")]),
+	(&["exr"], &[(0, b"v/1\x01")]),
+	(&["dds"], &[(0, b"DDS ")]),
+	(&["ktx2"], &[(0, b"\xabKTX 20\xbb\r\n\x1a\n")]),
+	(&["xcf"], &[(0, b"gimp xcf")]),
+];
+
+/// The usual extension of the image format the bytes start like, or None if they don't start like any of the SIGNATURES.
+pub fn sniff_image_type(head: &[u8]) -> Option<&'static str> {
+	SIGNATURES.iter().find(|(_, parts)| parts.iter().all(|(offset, bytes)| head.get(*offset..*offset + bytes.len()) == Some(*bytes))).map(|(extensions, _)| extensions[0])
+}
+
+/// True if files with this extension always start with one of the SIGNATURES, so one that doesn't isn't really that kind of image.
+pub fn has_signature(extension: &str) -> bool {
+	SIGNATURES.iter().any(|(extensions, _)| extensions.iter().any(|ext| extension.eq_ignore_ascii_case(ext)))
+}
+
+/// The first SNIFF_BYTES of the file, or all of it if it's shorter.
+pub fn read_head(path: &Path) -> std::io::Result<Vec<u8>> {
+	let mut head = vec![];
+	File::open(path)?.take(SNIFF_BYTES).read_to_end(&mut head)?;
+	Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::sniff::*;
+
+	#[test]
+	fn test_sniff_image_type() {
+		let mut png = vec![];
+		image::DynamicImage::new_rgb8(2, 2).write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+		assert_eq!(sniff_image_type(&png), Some("png"));
+		assert_eq!(sniff_image_type(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("jpg"));
+		assert_eq!(sniff_image_type(b"RIFF\x24\0\0\0WEBPVP8 "), Some("webp"));
+		// RIFF alone could be a WAV or an AVI.
+		assert_eq!(sniff_image_type(b"RIFF\x24\0\0\0WAVEfmt "), None);
+		assert_eq!(sniff_image_type(b"Just some text."), None);
+		assert_eq!(sniff_image_type(b""), None);
+
+		assert!(has_signature("JPEG") && has_signature("psb"));
+		assert!(!has_signature("tga") && !has_signature("svg") && !has_signature(""));
+
+		let path = std::env::temp_dir().join(format!("pixelbox_test_sniff_{}", std::process::id()));
+		std::fs::write(&path, &png).unwrap();
+		assert_eq!(read_head(&path).unwrap(), png[..SNIFF_BYTES as usize]);
+		std::fs::write(&path, b"GIF").unwrap();
+		assert_eq!(read_head(&path).unwrap(), b"GIF");
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,380 @@
+// A stress run for indexing: `pixelbox --stress [directory] [--files N] [--seed N] [--max-memory-mb N] [--keep]`.
+// Makes a tree of synthetic files (odd sizes, corrupt files, deep folders, strange names, nested zips), indexes all of it, and searches it.
+// Fails if anything panicked, if any file was left unprocessed, or if memory went over the limit.
+
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use std::time::{Duration, Instant};
+
+use crate::engine::{Engine, IndexingProgress, OpenMode};
+
+const DEFAULT_NUM_FILES: usize = 2000;
+const DEFAULT_MAX_MEMORY_MB: u64 = 2048;
+const NESTING_DEPTH: usize = 40;
+const MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+const GARBAGE_EXTENSIONS: [&'static str; 15] = ["png", "jpg", "gif", "webp", "tiff", "bmp", "psd", "ttf", "stl", "epub", "mp3", "eml", "zip", "tar", "7z"];
+const QUERIES: [&'static str; 4] = ["png", "写真", "sort:size", "rating:>=0"];
+
+static PANICS: AtomicUsize = AtomicUsize::new(0);
+static COUNT_PANICS: Once = Once::new();
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StressConfig {
+	pub directory: PathBuf, // Must be empty or missing.  The tree goes in library/ and the DB beside it.
+	pub num_files: usize,
+	pub seed: u64, // The same seed makes the same tree.
+	pub max_memory_bytes: u64,
+	pub keep_files: bool, // Leave the tree and DB behind to look at.
+}
+
+impl StressConfig {
+	/// Read `[directory] [--files N] [--seed N] [--max-memory-mb N] [--keep]`, the arguments after --stress.
+	pub fn from_args(args: &[String]) -> Result<StressConfig> {
+		let mut config = StressConfig {
+			directory: std::env::temp_dir().join(format!("pixelbox_stress_{}", std::process::id())),
+			num_files: DEFAULT_NUM_FILES,
+			seed: 1,
+			max_memory_bytes: DEFAULT_MAX_MEMORY_MB * 1024 * 1024,
+			keep_files: false,
+		};
+		let mut args = args.iter();
+		let number = |flag: &str, value: Option<&String>| -> Result<u64> {
+			value.and_then(|v| v.parse().ok()).ok_or_else(|| anyhow!("{} needs a number.", flag))
+		};
+		while let Some(arg) = args.next() {
+			match arg.as_str() {
+				"--files" => config.num_files = number(arg, args.next())? as usize,
+				"--seed" => config.seed = number(arg, args.next())?,
+				"--max-memory-mb" => config.max_memory_bytes = number(arg, args.next())? * 1024 * 1024,
+				"--keep" => config.keep_files = true,
+				flag if flag.starts_with("--") => return Err(anyhow!("Unknown option {}.  Usage: pixelbox --stress [directory] [--files N] [--seed N] [--max-memory-mb N] [--keep]", flag)),
+				_ => config.directory = arg.into(),
+			}
+		}
+		Ok(config)
+	}
+}
+
+/// What a stress run did.  See problems for whether it passed.
+#[derive(Clone, Debug)]
+pub struct StressReport {
+	pub files_written: usize,
+	pub progress: IndexingProgress,
+	pub panics: usize, // In any thread while indexing and searching.
+	pub close_error: Option<String>, // Why the DB couldn't be closed.  Closing joins the indexing threads, so this is set if any of them panicked.
+	pub peak_memory_bytes: Option<u64>, // Resident memory.  Only measured on Linux.
+	pub elapsed: Duration,
+}
+
+impl StressReport {
+	/// Everything that went wrong.  Files that couldn't be loaded are expected.  Crashes and lost files aren't.
+	pub fn problems(&self, config: &StressConfig) -> Vec<String> {
+		let mut problems = vec![];
+		if self.panics > 0 {
+			problems.push(format!("{} panics.", self.panics));
+		}
+		if let Some(close_error) = &self.close_error {
+			problems.push(close_error.clone());
+		}
+		if !self.progress.finished_finding || self.progress.files_processed != self.progress.files_found {
+			problems.push(format!("Only {} of the {} files found were processed.", self.progress.files_processed, self.progress.files_found));
+		}
+		if let Some(peak) = self.peak_memory_bytes.filter(|&peak| peak > config.max_memory_bytes) {
+			problems.push(format!("Memory peaked at {} MB, over the limit of {} MB.", peak / 1024 / 1024, config.max_memory_bytes / 1024 / 1024));
+		}
+		problems
+	}
+
+	pub fn summary(&self) -> String {
+		let memory = self.peak_memory_bytes.map(|peak| format!("{} MB", peak / 1024 / 1024)).unwrap_or_else(|| "unknown".to_string());
+		format!(
+			"Wrote {} files.  Found {}, processed {}, stored {} images, {} failed.  {} panics.  Peak memory: {}.  Took {:.1}s.",
+			self.files_written, self.progress.files_found, self.progress.files_processed, self.progress.images_stored, self.progress.failed, self.panics, memory, self.elapsed.as_secs_f64()
+		)
+	}
+}
+
+/// Make the tree, index it with every crawl option on, run some searches, and close the DB.
+/// Only errors if the run couldn't be done.  Check the report's problems for whether it passed.
+pub fn run(config: &StressConfig) -> Result<StressReport> {
+	if config.directory.read_dir().map(|mut entries| entries.next().is_some()).unwrap_or(false) {
+		return Err(anyhow!("{} isn't empty.", config.directory.display()));
+	}
+	let library = config.directory.join("library");
+	std::fs::create_dir_all(&library)?;
+	let files_written = generate_tree(&library, config.num_files, config.seed)?;
+
+	COUNT_PANICS.call_once(|| {
+		let previous_hook = std::panic::take_hook();
+		std::panic::set_hook(Box::new(move |info| {
+			PANICS.fetch_add(1, Ordering::Relaxed);
+			previous_hook(info);
+		}));
+	});
+	let panics_before = PANICS.load(Ordering::Relaxed);
+	let started = Instant::now();
+
+	let mut engine = Engine::open_or_create(&config.directory.join("stress.db"), OpenMode::CreateNew)?;
+	engine.index_book_pages = true;
+	engine.hash_cropped_frames = true;
+	engine.add_tracked_folder(library.display().to_string())?;
+	engine.start_reindexing()?;
+	let mut peak_memory_bytes = resident_memory_bytes();
+	while engine.is_indexing_active() {
+		std::thread::sleep(MEMORY_SAMPLE_INTERVAL);
+		peak_memory_bytes = peak_memory_bytes.max(resident_memory_bytes());
+	}
+	let progress = engine.get_indexing_progress_details().ok_or_else(|| anyhow!("Indexing never started."))?;
+	for query in QUERIES {
+		engine.query_page(&query.to_string(), 0, 100)?;
+	}
+	let close_error = engine.close().err().map(|e| e.to_string());
+
+	let report = StressReport {
+		files_written,
+		progress,
+		panics: PANICS.load(Ordering::Relaxed) - panics_before,
+		close_error,
+		peak_memory_bytes,
+		elapsed: started.elapsed(),
+	};
+	if !config.keep_files {
+		std::fs::remove_dir_all(&config.directory)?;
+	}
+	Ok(report)
+}
+
+/// Write num_files synthetic files under dir and return how many there are.
+pub fn generate_tree(dir: &Path, num_files: usize, seed: u64) -> Result<usize> {
+	let mut rng = SplitMix64(seed);
+	// One very deep folder, some oddly named ones, and the top.
+	let mut folders = vec![dir.to_path_buf()];
+	let mut deep = dir.to_path_buf();
+	for level in 0..NESTING_DEPTH {
+		deep = deep.join(format!("level {}", level));
+		folders.push(deep.clone());
+	}
+	for name in ["spaces  in  name", "ünïcødé 写真", "emoji 🎉", "#hash%20!sep", ".hidden", "trailing dot."] {
+		folders.push(dir.join(name));
+	}
+	for folder in &folders {
+		std::fs::create_dir_all(folder)?;
+	}
+
+	for i in 0..num_files {
+		let folder = &folders[rng.below(folders.len() as u64) as usize];
+		let stem = odd_name(&mut rng, i);
+		let (extension, bytes) = match rng.below(10) {
+			0..=3 => ("png", encode(&synthetic_image(&mut rng), ImageOutputFormat::Png)?),
+			4 => ("jpg", encode(&synthetic_image(&mut rng), ImageOutputFormat::Jpeg(80))?),
+			5 => {
+				let mut bytes = encode(&synthetic_image(&mut rng), ImageOutputFormat::Png)?;
+				bytes.truncate(bytes.len() / 2);
+				("png", bytes)
+			},
+			6 => (GARBAGE_EXTENSIONS[rng.below(GARBAGE_EXTENSIONS.len() as u64) as usize], (0..rng.below(4096)).map(|_| rng.next() as u8).collect()),
+			7 => ("gif", vec![]),
+			8 => ("cbz", comic(&mut rng)?),
+			_ => ("txt", format!("Not an image, number {}.", i).into_bytes()),
+		};
+		std::fs::write(folder.join(format!("{}.{}", stem, extension)), bytes)?;
+	}
+
+	std::fs::write(dir.join("README"), b"No extension at all.")?;
+	let mut written = num_files + 1;
+	#[cfg(unix)]
+	{
+		use std::ffi::OsStr;
+		use std::os::unix::ffi::OsStrExt;
+		std::fs::write(dir.join(OsStr::from_bytes(b"not utf-8 \xff\xfe.png")), encode(&synthetic_image(&mut rng), ImageOutputFormat::Png)?)?;
+		std::os::unix::fs::symlink(dir.join("nowhere.png"), dir.join("dangling.png"))?;
+		written += 2;
+	}
+	Ok(written)
+}
+
+/// Filenames that have caused trouble somewhere: unicode, emoji, lots of spaces, URL escapes, the archive entry separator, and long names.
+fn odd_name(rng: &mut SplitMix64, i: usize) -> String {
+	match rng.below(8) {
+		0 => format!("写真_{}", i),
+		1 => format!("party 🎉 {}", i),
+		2 => format!("  spaced   out {} ", i),
+		3 => format!("percent%20and#hash!{}", i),
+		4 => format!("{}_{}", "long".repeat(50), i),
+		5 => format!(".dotfile_{}", i),
+		6 => format!("double.extension.{}.jpg", i),
+		_ => format!("img_{}", i),
+	}
+}
+
+/// Mostly small pictures, with some one pixel wide or tall ones and some big ones.
+fn synthetic_image(rng: &mut SplitMix64) -> DynamicImage {
+	let (width, height) = match rng.below(10) {
+		0 => (1, 1),
+		1 => (1 + rng.below(4096) as u32, 1),
+		2 => (1, 1 + rng.below(4096) as u32),
+		3 => (1024 + rng.below(1024) as u32, 768 + rng.below(768) as u32),
+		_ => (8 + rng.below(505) as u32, 8 + rng.below(505) as u32),
+	};
+	let (r, g) = (rng.next() as u8, rng.next() as u8);
+	// Gradients compress well, so thousands of these don't fill the disk.
+	DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| image::Rgb([r ^ (x as u8), g ^ (y as u8), (x + y) as u8])))
+}
+
+fn encode(img: &DynamicImage, format: ImageOutputFormat) -> Result<Vec<u8>> {
+	let mut bytes = Cursor::new(vec![]);
+	img.write_to(&mut bytes, format)?;
+	Ok(bytes.into_inner())
+}
+
+/// A comic archive with a few pages, a corrupt page, and zips inside it.
+fn comic(rng: &mut SplitMix64) -> Result<Vec<u8>> {
+	let pages: Vec<Vec<u8>> = (0..1 + rng.below(3)).map(|_| encode(&synthetic_image(rng), ImageOutputFormat::Png)).collect::<Result<_>>()?;
+	let inner = stored_zip(&[("page.png", pages[0].as_slice()), ("deeper.zip", stored_zip(&[("page.png", pages[0].as_slice())]).as_slice())]);
+	let mut files: Vec<(String, &[u8])> = pages.iter().enumerate().map(|(i, page)| (format!("{:03}.png", i), page.as_slice())).collect();
+	files.push(("999.png".to_string(), b"corrupt page".as_slice()));
+	files.push(("extras.zip".to_string(), inner.as_slice()));
+	files.push(("inner.cbz".to_string(), inner.as_slice()));
+	Ok(stored_zip(&files.iter().map(|(name, bytes)| (name.as_str(), *bytes)).collect::<Vec<_>>()))
+}
+
+/// An uncompressed zip of files.  Stored entries are all the archive reader needs to be exercised.
+fn stored_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+	let mut zip = vec![];
+	let mut central = vec![];
+	for (name, contents) in files {
+		let offset = zip.len() as u32;
+		let header = |signature: u32| {
+			let mut header = signature.to_le_bytes().to_vec();
+			header.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]); // Versions, flags, method, time, date, and crc.
+			header.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+			header.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+			header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+			header
+		};
+		zip.extend(header(0x04034b50));
+		zip.extend_from_slice(&[0, 0]);
+		zip.extend_from_slice(name.as_bytes());
+		zip.extend_from_slice(contents);
+
+		let mut entry = header(0x02014b50);
+		entry.splice(4..4, [20, 0]); // The central header has an extra "version made by" field.
+		entry.extend_from_slice(&[0; 12]); // Extra and comment lengths, disk, and attributes.
+		entry.extend_from_slice(&offset.to_le_bytes());
+		entry.extend_from_slice(name.as_bytes());
+		central.extend(entry);
+	}
+	let central_offset = zip.len() as u32;
+	zip.extend_from_slice(&central);
+	zip.extend_from_slice(&0x06054b50u32.to_le_bytes());
+	zip.extend_from_slice(&[0, 0, 0, 0]);
+	zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+	zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+	zip.extend_from_slice(&(central.len() as u32).to_le_bytes());
+	zip.extend_from_slice(&central_offset.to_le_bytes());
+	zip.extend_from_slice(&[0, 0]);
+	zip
+}
+
+/// Resident memory, from /proc.
+fn resident_memory_bytes() -> Option<u64> {
+	let status = std::fs::read_to_string("/proc/self/status").ok()?;
+	let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+	let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+	Some(kilobytes * 1024)
+}
+
+/// Small and seedable, so a failing tree can be made again.  Not for anything that needs real randomness.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+	fn next(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+		z ^ (z >> 31)
+	}
+
+	/// From 0 up to but not including n.
+	fn below(&mut self, n: u64) -> u64 {
+		self.next() % n.max(1)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::crawler::{crawl_globs_async, CrawlCounters, CrawlFolder, CrawlOptions};
+	use crate::stress::*;
+
+	#[test]
+	fn test_stress_config() {
+		let args = |args: &[&str]| StressConfig::from_args(&args.iter().map(|a| a.to_string()).collect::<Vec<String>>());
+		let config = args(&["/tmp/stress", "--files", "10", "--max-memory-mb", "64", "--keep"]).unwrap();
+		assert_eq!(config.directory, PathBuf::from("/tmp/stress"));
+		assert_eq!((config.num_files, config.max_memory_bytes, config.keep_files), (10, 64 * 1024 * 1024, true));
+		assert_eq!(args(&[]).unwrap().num_files, DEFAULT_NUM_FILES);
+		assert!(args(&["--files"]).is_err());
+		assert!(args(&["--files", "many"]).is_err());
+		assert!(args(&["--fast"]).is_err());
+	}
+
+	#[test]
+	fn test_generate_tree() {
+		let directory = std::env::temp_dir().join(format!("pixelbox_test_stress_tree_{}", std::process::id()));
+		let written = generate_tree(&directory, 80, 3).unwrap();
+		assert!(written >= 80);
+		assert!(directory.join((0..NESTING_DEPTH).map(|level| format!("level {}", level)).collect::<Vec<String>>().join("/")).is_dir());
+
+		// Crawling it without loading anything finds every file, and the dangling link on Unix doesn't stop the crawl.
+		// README has no extension and the name that isn't UTF-8 can't be shown as text, but both are still found.
+		let counters = std::sync::Arc::new(CrawlCounters::default());
+		let never = || std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+		let (file_rx, _image_rx, _failure_rx) = crawl_globs_async(vec![CrawlFolder { glob: directory.display().to_string(), ..Default::default() }], 0, CrawlOptions::default(), never(), never(), counters.clone());
+		let unix_only = if cfg!(unix) { 1 } else { 0 };
+		assert_eq!(file_rx.iter().count(), written - unix_only);
+		assert!(counters.finished_finding.load(Ordering::Relaxed));
+		assert!(counters.join_threads().is_ok());
+
+		// The same seed makes the same tree.
+		let again = std::env::temp_dir().join(format!("pixelbox_test_stress_tree_again_{}", std::process::id()));
+		generate_tree(&again, 80, 3).unwrap();
+		assert_eq!(std::fs::read_dir(&directory).unwrap().count(), std::fs::read_dir(&again).unwrap().count());
+		std::fs::remove_dir_all(&directory).unwrap();
+		std::fs::remove_dir_all(&again).unwrap();
+	}
+
+	#[test]
+	fn test_stored_zip() {
+		let files: [(&str, &[u8]); 2] = [("page.png", b"not really a png"), ("deeper.zip", b"")];
+		assert_eq!(stored_zip(&files), crate::archive::make_test_zip(&files));
+	}
+
+	#[test]
+	fn test_stress_run() {
+		// Loading images needs the similarity model, like the efficientnet tests.
+		if crate::image_hashes::mlhash_model_version().is_none() {
+			return;
+		}
+		let directory = std::env::temp_dir().join(format!("pixelbox_test_stress_{}", std::process::id()));
+		let config = StressConfig { directory: directory.clone(), num_files: 60, seed: 7, max_memory_bytes: u64::MAX, keep_files: false };
+		let report = run(&config).unwrap();
+		assert!(report.files_written >= 60);
+		assert!(report.progress.finished_finding);
+		assert_eq!(report.progress.files_processed, report.progress.files_found);
+		// The panic hook counts panics across the whole process, so other tests' failures could show up in report.panics.
+		// The run's own threads are joined, so whether they panicked is known for sure.
+		assert_eq!(report.close_error, None);
+		assert!(report.problems(&config).iter().all(|problem| problem.ends_with("panics.")));
+		assert!(!directory.exists());
+
+		// Running again over an existing tree isn't allowed.
+		std::fs::create_dir_all(directory.join("something")).unwrap();
+		assert!(run(&config).is_err());
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+}
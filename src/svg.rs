@@ -0,0 +1,83 @@
+// SVGs are rasterized so they get thumbnails and hashes like any other image.
+// They're drawn on white, since most icons are dark shapes on nothing and would vanish into the black a transparent thumbnail turns into.
+
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, RgbaImage};
+use lazy_static::lazy_static;
+use resvg::{tiny_skia, usvg};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub const SVG_EXTENSIONS: &[&str] = &["svg", "svgz"];
+const RENDER_SIZE: f32 = 1024.0; // The longer side, in pixels.  Well past the thumbnail and model sizes, so fine lines survive the downscale.
+
+lazy_static! {
+	// Finding the system fonts takes a while, so it's only done once, the first time an SVG is drawn.
+	static ref FONTS: Arc<usvg::fontdb::Database> = {
+		let mut fonts = usvg::fontdb::Database::new();
+		fonts.load_system_fonts();
+		Arc::new(fonts)
+	};
+}
+
+#[derive(Clone, Debug)]
+pub struct SvgInfo {
+	pub image: DynamicImage,
+	pub size: (f32, f32), // The size the SVG says it is, before it was scaled to RENDER_SIZE.
+}
+
+impl SvgInfo {
+	pub fn to_tags(&self) -> HashMap<String, String> {
+		let mut tags = HashMap::new();
+		tags.insert("SVG Size".to_string(), format!("{}x{}", self.size.0, self.size.1));
+		tags
+	}
+}
+
+/// True if the filename ends in one of the SVG_EXTENSIONS, in any case.
+pub fn is_svg_file(filename: &str) -> bool {
+	filename.rsplit_once('.').map(|(_, extension)| SVG_EXTENSIONS.iter().any(|ext| extension.eq_ignore_ascii_case(ext))).unwrap_or(false)
+}
+
+/// Draw an SVG or gzipped SVGZ with its longer side RENDER_SIZE pixels long.
+pub fn render_svg(bytes: &[u8]) -> Result<SvgInfo> {
+	let options = usvg::Options { fontdb: FONTS.clone(), ..Default::default() };
+	let tree = usvg::Tree::from_data(bytes, &options)?;
+	let size = (tree.size().width(), tree.size().height());
+	let scale = RENDER_SIZE / size.0.max(size.1);
+	let width = (size.0 * scale).round().max(1.0) as u32;
+	let height = (size.1 * scale).round().max(1.0) as u32;
+	let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or_else(|| anyhow!("It's too big to draw."))?;
+	pixmap.fill(tiny_skia::Color::WHITE);
+	resvg::render(&tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+	// The background is opaque, so the pixels don't need to be un-premultiplied.
+	let image = RgbaImage::from_raw(width, height, pixmap.take()).ok_or_else(|| anyhow!("The drawing is cut off."))?;
+	Ok(SvgInfo { image: DynamicImage::ImageRgba8(image), size })
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::svg::*;
+
+	#[test]
+	fn test_render_svg() {
+		assert!(is_svg_file("logo.SVG"));
+		assert!(is_svg_file("icons.svgz"));
+		assert!(!is_svg_file("logo.png"));
+
+		let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="20"><rect x="20" width="20" height="20" fill="red"/></svg>"#;
+		let info = render_svg(svg).unwrap();
+		assert_eq!((info.image.width(), info.image.height()), (1024, 512));
+		let rgba = info.image.to_rgba8();
+		assert_eq!(rgba.get_pixel(100, 256).0, [255, 255, 255, 255]);
+		assert_eq!(rgba.get_pixel(900, 256).0, [255, 0, 0, 255]);
+		assert_eq!(info.to_tags().get("SVG Size").map(String::as_str), Some("40x20"));
+
+		let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+		std::io::Write::write_all(&mut encoder, svg).unwrap();
+		assert_eq!(render_svg(&encoder.finish().unwrap()).unwrap().size, (40.0, 20.0));
+
+		assert!(render_svg(b"<svg").is_err());
+		assert!(render_svg(b"not an svg").is_err());
+	}
+}
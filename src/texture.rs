@@ -0,0 +1,427 @@
+// Game texture containers: DDS and KTX2.
+// The image crate can't read most of these, so the headers are parsed here for their metadata,
+// and the largest mip level is decoded when it's uncompressed 8-bit or BC1-BC3.
+
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, GrayImage, RgbImage, RgbaImage};
+#[allow(deprecated)] // The only BC decoder we have.  It's still in image 0.24.
+use image::codecs::dxt::{DxtDecoder, DxtVariant};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+const DDS_MAGIC: &[u8] = b"DDS ";
+const DDS_HEADER_END: usize = 128;
+const DDS_DX10_HEADER_END: usize = 148;
+const KTX2_MAGIC: &[u8] = &[0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+const KTX2_LEVEL_INDEX_START: usize = 80;
+const MAX_PIXELS: usize = 16384 * 16384; // Larger textures aren't decoded.  The header's size is untrusted.
+
+/// Tag names for what's read from the headers, so searches like tag:TextureFormat:BC7 work.
+pub const TEXTURE_CONTAINER_TAG: &'static str = "TextureContainer";
+pub const TEXTURE_FORMAT_TAG: &'static str = "TextureFormat";
+pub const MIP_COUNT_TAG: &'static str = "MipCount";
+pub const COLOR_SPACE_TAG: &'static str = "ColorSpace";
+pub const LAYER_COUNT_TAG: &'static str = "LayerCount";
+pub const CUBEMAP_TAG: &'static str = "Cubemap";
+pub const SUPERCOMPRESSION_TAG: &'static str = "Supercompression";
+
+/// How the pixels of a mip level are laid out, for the formats we can decode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PixelLayout {
+	Rgba8,
+	Bgra8,
+	Rgb8,
+	Bgr8,
+	R8,
+	Bc1,
+	Bc2,
+	Bc3,
+}
+
+/// What the header of a texture says, before any pixels are read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextureHeader {
+	pub container: &'static str,
+	pub format: String,
+	pub width: u32,
+	pub height: u32,
+	pub mip_count: u32,
+	pub layer_count: u32,
+	pub cubemap: bool,
+	pub srgb: Option<bool>, // None if the container doesn't say.
+	pub supercompression: Option<&'static str>,
+	layout: Option<PixelLayout>,
+	data_start: usize, // Where the largest mip level starts.
+}
+
+impl TextureHeader {
+	pub fn to_tags(&self) -> HashMap<String, String> {
+		let mut tags = HashMap::new();
+		tags.insert(TEXTURE_CONTAINER_TAG.to_string(), self.container.to_string());
+		tags.insert(TEXTURE_FORMAT_TAG.to_string(), self.format.clone());
+		tags.insert(MIP_COUNT_TAG.to_string(), self.mip_count.to_string());
+		if let Some(srgb) = self.srgb {
+			tags.insert(COLOR_SPACE_TAG.to_string(), if srgb { "sRGB" } else { "Linear" }.to_string());
+		}
+		if self.layer_count > 1 {
+			tags.insert(LAYER_COUNT_TAG.to_string(), self.layer_count.to_string());
+		}
+		if self.cubemap {
+			tags.insert(CUBEMAP_TAG.to_string(), "true".to_string());
+		}
+		if let Some(scheme) = self.supercompression {
+			tags.insert(SUPERCOMPRESSION_TAG.to_string(), scheme.to_string());
+		}
+		tags
+	}
+}
+
+/// True if the bytes start like a DDS or KTX2 file.
+pub fn is_texture(bytes: &[u8]) -> bool {
+	bytes.starts_with(DDS_MAGIC) || bytes.starts_with(KTX2_MAGIC)
+}
+
+pub fn read_texture_header(bytes: &[u8]) -> Result<TextureHeader> {
+	if bytes.starts_with(DDS_MAGIC) {
+		read_dds_header(bytes)
+	} else if bytes.starts_with(KTX2_MAGIC) {
+		read_ktx2_header(bytes)
+	} else {
+		Err(anyhow!("Not a DDS or KTX2 texture."))
+	}
+}
+
+/// Decode the largest mip level of the first layer.
+pub fn decode_texture(bytes: &[u8], header: &TextureHeader) -> Result<DynamicImage> {
+	if let Some(scheme) = header.supercompression {
+		return Err(anyhow!("Unable to decode {} textures with {} supercompression.", header.container, scheme));
+	}
+	let layout = header.layout.ok_or_else(|| anyhow!("Unable to decode {} textures in {}.", header.container, header.format))?;
+	let too_large = || anyhow!("The texture is too large.");
+	let (width, height) = (header.width as usize, header.height as usize);
+	let pixels = width.checked_mul(height).filter(|&pixels| pixels <= MAX_PIXELS).ok_or_else(too_large)?;
+	let blocks = width.div_ceil(4) * height.div_ceil(4);
+	let length = match layout {
+		PixelLayout::Rgba8 | PixelLayout::Bgra8 => pixels * 4,
+		PixelLayout::Rgb8 | PixelLayout::Bgr8 => pixels * 3,
+		PixelLayout::R8 => pixels,
+		PixelLayout::Bc1 => blocks * 8,
+		PixelLayout::Bc2 | PixelLayout::Bc3 => blocks * 16,
+	};
+	let data = bytes.get(header.data_start..header.data_start.saturating_add(length)).ok_or_else(|| anyhow!("The texture is cut off."))?;
+
+	let swap_red_blue = |mut pixels: Vec<u8>, channels: usize| {
+		pixels.chunks_exact_mut(channels).for_each(|p| p.swap(0, 2));
+		pixels
+	};
+	Ok(match layout {
+		PixelLayout::Rgba8 => DynamicImage::ImageRgba8(RgbaImage::from_raw(header.width, header.height, data.to_vec()).ok_or_else(too_large)?),
+		PixelLayout::Bgra8 => DynamicImage::ImageRgba8(RgbaImage::from_raw(header.width, header.height, swap_red_blue(data.to_vec(), 4)).ok_or_else(too_large)?),
+		PixelLayout::Rgb8 => DynamicImage::ImageRgb8(RgbImage::from_raw(header.width, header.height, data.to_vec()).ok_or_else(too_large)?),
+		PixelLayout::Bgr8 => DynamicImage::ImageRgb8(RgbImage::from_raw(header.width, header.height, swap_red_blue(data.to_vec(), 3)).ok_or_else(too_large)?),
+		PixelLayout::R8 => DynamicImage::ImageLuma8(GrayImage::from_raw(header.width, header.height, data.to_vec()).ok_or_else(too_large)?),
+		PixelLayout::Bc1 | PixelLayout::Bc2 | PixelLayout::Bc3 => decode_block_compressed(layout, header.width, header.height, data)?,
+	})
+}
+
+#[allow(deprecated)]
+fn decode_block_compressed(layout: PixelLayout, width: u32, height: u32, data: &[u8]) -> Result<DynamicImage> {
+	let variant = match layout {
+		PixelLayout::Bc1 => DxtVariant::DXT1,
+		PixelLayout::Bc2 => DxtVariant::DXT3,
+		_ => DxtVariant::DXT5,
+	};
+	// The decoder works in whole blocks, so sizes that aren't a multiple of four are decoded padded and cropped.
+	let padded_width = width.div_ceil(4) * 4;
+	let padded_height = height.div_ceil(4) * 4;
+	let img = DynamicImage::from_decoder(DxtDecoder::new(Cursor::new(data), padded_width, padded_height, variant)?)?;
+	Ok(img.crop_imm(0, 0, width, height))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32> {
+	let le_bytes = bytes.get(offset..offset+4).ok_or_else(|| anyhow!("The texture header is cut off."))?;
+	Ok(u32::from_le_bytes([le_bytes[0], le_bytes[1], le_bytes[2], le_bytes[3]]))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64> {
+	Ok(read_u32(bytes, offset)? as u64 | ((read_u32(bytes, offset+4)? as u64) << 32))
+}
+
+fn read_dds_header(bytes: &[u8]) -> Result<TextureHeader> {
+	const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+	const DDPF_FOURCC: u32 = 0x4;
+	const DDPF_RGB: u32 = 0x40;
+	const DDPF_LUMINANCE: u32 = 0x20000;
+	const DDSCAPS2_CUBEMAP: u32 = 0x200;
+	const DDS_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+
+	let flags = read_u32(bytes, 8)?;
+	let height = read_u32(bytes, 12)?;
+	let width = read_u32(bytes, 16)?;
+	let mip_count = if flags & DDSD_MIPMAPCOUNT != 0 { read_u32(bytes, 28)?.max(1) } else { 1 };
+	let pixel_flags = read_u32(bytes, 80)?;
+	let four_cc = bytes.get(84..88).ok_or_else(|| anyhow!("The texture header is cut off."))?;
+	let bit_count = read_u32(bytes, 88)?;
+	let red_mask = read_u32(bytes, 92)?;
+	let mut cubemap = read_u32(bytes, 112)? & DDSCAPS2_CUBEMAP != 0;
+
+	let mut header = TextureHeader {
+		container: "DDS",
+		format: String::new(),
+		width,
+		height,
+		mip_count,
+		layer_count: 1,
+		cubemap,
+		srgb: None, // Only the DX10 header says.
+		supercompression: None,
+		layout: None,
+		data_start: DDS_HEADER_END,
+	};
+
+	if pixel_flags & DDPF_FOURCC != 0 && four_cc == b"DX10" {
+		let dxgi_format = read_u32(bytes, 128)?;
+		cubemap |= read_u32(bytes, 136)? & DDS_RESOURCE_MISC_TEXTURECUBE != 0;
+		let (format, layout) = dxgi_format_name(dxgi_format);
+		header.format = format;
+		header.layout = layout;
+		header.srgb = Some(header.format.ends_with("_SRGB"));
+		header.layer_count = read_u32(bytes, 140)?.max(1);
+		header.cubemap = cubemap;
+		header.data_start = DDS_DX10_HEADER_END;
+	} else if pixel_flags & DDPF_FOURCC != 0 {
+		(header.format, header.layout) = match four_cc {
+			b"DXT1" => ("BC1".to_string(), Some(PixelLayout::Bc1)),
+			b"DXT2" | b"DXT3" => ("BC2".to_string(), Some(PixelLayout::Bc2)),
+			b"DXT4" | b"DXT5" => ("BC3".to_string(), Some(PixelLayout::Bc3)),
+			b"ATI1" | b"BC4U" => ("BC4".to_string(), None),
+			b"ATI2" | b"BC5U" => ("BC5".to_string(), None),
+			other => (String::from_utf8_lossy(other).trim().to_string(), None),
+		};
+	} else if pixel_flags & DDPF_RGB != 0 {
+		(header.format, header.layout) = match (bit_count, red_mask) {
+			(32, 0xff) => ("R8G8B8A8".to_string(), Some(PixelLayout::Rgba8)),
+			(32, 0xff0000) => ("B8G8R8A8".to_string(), Some(PixelLayout::Bgra8)),
+			(24, 0xff) => ("R8G8B8".to_string(), Some(PixelLayout::Rgb8)),
+			(24, 0xff0000) => ("B8G8R8".to_string(), Some(PixelLayout::Bgr8)),
+			(bits, _) => (format!("RGB{}", bits), None),
+		};
+	} else if pixel_flags & DDPF_LUMINANCE != 0 && bit_count == 8 {
+		(header.format, header.layout) = ("L8".to_string(), Some(PixelLayout::R8));
+	} else {
+		header.format = "Unknown".to_string();
+	}
+	Ok(header)
+}
+
+/// From https://learn.microsoft.com/en-us/windows/win32/api/dxgiformat/ne-dxgiformat-dxgi_format
+fn dxgi_format_name(dxgi_format: u32) -> (String, Option<PixelLayout>) {
+	let (name, layout) = match dxgi_format {
+		2 => ("R32G32B32A32_FLOAT", None),
+		10 => ("R16G16B16A16_FLOAT", None),
+		28 => ("R8G8B8A8_UNORM", Some(PixelLayout::Rgba8)),
+		29 => ("R8G8B8A8_UNORM_SRGB", Some(PixelLayout::Rgba8)),
+		61 => ("R8_UNORM", Some(PixelLayout::R8)),
+		71 => ("BC1_UNORM", Some(PixelLayout::Bc1)),
+		72 => ("BC1_UNORM_SRGB", Some(PixelLayout::Bc1)),
+		74 => ("BC2_UNORM", Some(PixelLayout::Bc2)),
+		75 => ("BC2_UNORM_SRGB", Some(PixelLayout::Bc2)),
+		77 => ("BC3_UNORM", Some(PixelLayout::Bc3)),
+		78 => ("BC3_UNORM_SRGB", Some(PixelLayout::Bc3)),
+		80 => ("BC4_UNORM", None),
+		81 => ("BC4_SNORM", None),
+		83 => ("BC5_UNORM", None),
+		84 => ("BC5_SNORM", None),
+		87 => ("B8G8R8A8_UNORM", Some(PixelLayout::Bgra8)),
+		91 => ("B8G8R8A8_UNORM_SRGB", Some(PixelLayout::Bgra8)),
+		95 => ("BC6H_UF16", None),
+		96 => ("BC6H_SF16", None),
+		98 => ("BC7_UNORM", None),
+		99 => ("BC7_UNORM_SRGB", None),
+		other => return (format!("DXGI_{}", other), None),
+	};
+	(name.to_string(), layout)
+}
+
+fn read_ktx2_header(bytes: &[u8]) -> Result<TextureHeader> {
+	const KTX_DF_TRANSFER_LINEAR: u8 = 1;
+	const KTX_DF_TRANSFER_SRGB: u8 = 2;
+
+	let vk_format = read_u32(bytes, 12)?;
+	let width = read_u32(bytes, 20)?;
+	let height = read_u32(bytes, 24)?.max(1); // Zero for 1D textures.
+	let layer_count = read_u32(bytes, 32)?.max(1);
+	let face_count = read_u32(bytes, 36)?;
+	let mip_count = read_u32(bytes, 40)?.max(1); // Zero asks the loader to generate them.
+	let supercompression = match read_u32(bytes, 44)? {
+		0 => None,
+		1 => Some("BasisLZ"),
+		2 => Some("Zstandard"),
+		3 => Some("ZLIB"),
+		_ => Some("Unknown"),
+	};
+	let dfd_offset = read_u32(bytes, 48)? as usize;
+	// Level 0 is the largest.
+	let data_start = usize::try_from(read_u64(bytes, KTX2_LEVEL_INDEX_START)?).map_err(|_| anyhow!("The texture is too large."))?;
+
+	let (format, layout) = match vk_format {
+		// Basis Universal textures don't have a Vulkan format until they're transcoded.
+		0 => ("Basis Universal".to_string(), None),
+		other => vk_format_name(other),
+	};
+	// The data format descriptor's basic block has the transfer function 14 bytes in.
+	let srgb = match bytes.get(dfd_offset + 14) {
+		Some(&KTX_DF_TRANSFER_SRGB) => Some(true),
+		Some(&KTX_DF_TRANSFER_LINEAR) => Some(false),
+		_ if vk_format != 0 => Some(format.ends_with("_SRGB")),
+		_ => None,
+	};
+
+	Ok(TextureHeader {
+		container: "KTX2",
+		format,
+		width,
+		height,
+		mip_count,
+		layer_count,
+		cubemap: face_count == 6,
+		srgb,
+		supercompression,
+		layout,
+		data_start,
+	})
+}
+
+/// From the Vulkan spec's VkFormat.  Only the formats textures are commonly stored in.
+fn vk_format_name(vk_format: u32) -> (String, Option<PixelLayout>) {
+	let (name, layout) = match vk_format {
+		9 => ("R8_UNORM", Some(PixelLayout::R8)),
+		15 => ("R8_SRGB", Some(PixelLayout::R8)),
+		23 => ("R8G8B8_UNORM", Some(PixelLayout::Rgb8)),
+		29 => ("R8G8B8_SRGB", Some(PixelLayout::Rgb8)),
+		37 => ("R8G8B8A8_UNORM", Some(PixelLayout::Rgba8)),
+		43 => ("R8G8B8A8_SRGB", Some(PixelLayout::Rgba8)),
+		44 => ("B8G8R8A8_UNORM", Some(PixelLayout::Bgra8)),
+		50 => ("B8G8R8A8_SRGB", Some(PixelLayout::Bgra8)),
+		97 => ("R16G16B16A16_SFLOAT", None),
+		109 => ("R32G32B32A32_SFLOAT", None),
+		131 => ("BC1_RGB_UNORM", Some(PixelLayout::Bc1)),
+		132 => ("BC1_RGB_SRGB", Some(PixelLayout::Bc1)),
+		133 => ("BC1_RGBA_UNORM", Some(PixelLayout::Bc1)),
+		134 => ("BC1_RGBA_SRGB", Some(PixelLayout::Bc1)),
+		135 => ("BC2_UNORM", Some(PixelLayout::Bc2)),
+		136 => ("BC2_SRGB", Some(PixelLayout::Bc2)),
+		137 => ("BC3_UNORM", Some(PixelLayout::Bc3)),
+		138 => ("BC3_SRGB", Some(PixelLayout::Bc3)),
+		139 => ("BC4_UNORM", None),
+		140 => ("BC4_SNORM", None),
+		141 => ("BC5_UNORM", None),
+		142 => ("BC5_SNORM", None),
+		143 => ("BC6H_UFLOAT", None),
+		144 => ("BC6H_SFLOAT", None),
+		145 => ("BC7_UNORM", None),
+		146 => ("BC7_SRGB", None),
+		147 => ("ETC2_R8G8B8_UNORM", None),
+		148 => ("ETC2_R8G8B8_SRGB", None),
+		157 => ("ASTC_4x4_UNORM", None),
+		158 => ("ASTC_4x4_SRGB", None),
+		other => return (format!("VK_FORMAT_{}", other), None),
+	};
+	(name.to_string(), layout)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::texture::*;
+
+	fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+		bytes[offset..offset+4].copy_from_slice(&value.to_le_bytes());
+	}
+
+	// A 2x2 B8G8R8A8 DDS with three mips.
+	fn make_dds() -> Vec<u8> {
+		let mut dds = vec![0u8; DDS_HEADER_END];
+		dds[0..4].copy_from_slice(DDS_MAGIC);
+		put_u32(&mut dds, 4, 124);
+		put_u32(&mut dds, 8, 0x1 | 0x2 | 0x4 | 0x1000 | 0x20000);
+		put_u32(&mut dds, 12, 2);
+		put_u32(&mut dds, 16, 2);
+		put_u32(&mut dds, 28, 3);
+		put_u32(&mut dds, 76, 32);
+		put_u32(&mut dds, 80, 0x40 | 0x1);
+		put_u32(&mut dds, 88, 32);
+		put_u32(&mut dds, 92, 0xff0000);
+		// Blue, green, red, and white.
+		dds.extend_from_slice(&[255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 255, 255, 255, 255]);
+		dds
+	}
+
+	// A 4x4 BC1 sRGB KTX2 with one mip.
+	fn make_ktx2() -> Vec<u8> {
+		let dfd_offset = KTX2_LEVEL_INDEX_START + 24;
+		let data_start = dfd_offset + 44;
+		let mut ktx2 = vec![0u8; data_start];
+		ktx2[0..12].copy_from_slice(KTX2_MAGIC);
+		put_u32(&mut ktx2, 12, 132);
+		put_u32(&mut ktx2, 20, 4);
+		put_u32(&mut ktx2, 24, 4);
+		put_u32(&mut ktx2, 36, 1);
+		put_u32(&mut ktx2, 40, 1);
+		put_u32(&mut ktx2, 48, dfd_offset as u32);
+		put_u32(&mut ktx2, 52, 44);
+		put_u32(&mut ktx2, KTX2_LEVEL_INDEX_START, data_start as u32);
+		put_u32(&mut ktx2, KTX2_LEVEL_INDEX_START + 8, 8);
+		ktx2[dfd_offset + 14] = 2; // sRGB transfer.
+		// Both endpoints pure red in RGB565, so every pixel is red.
+		ktx2.extend_from_slice(&[0x00, 0xF8, 0x00, 0xF8, 0, 0, 0, 0]);
+		ktx2
+	}
+
+	#[test]
+	fn test_dds() {
+		let dds = make_dds();
+		assert!(is_texture(&dds));
+		let header = read_texture_header(&dds).unwrap();
+		assert_eq!((header.width, header.height, header.mip_count), (2, 2, 3));
+		let tags = header.to_tags();
+		assert_eq!(tags[TEXTURE_CONTAINER_TAG], "DDS");
+		assert_eq!(tags[TEXTURE_FORMAT_TAG], "B8G8R8A8");
+		assert_eq!(tags[MIP_COUNT_TAG], "3");
+		assert!(!tags.contains_key(COLOR_SPACE_TAG));
+
+		let img = decode_texture(&dds, &header).unwrap().to_rgba8();
+		assert_eq!(img.get_pixel(0, 0).0, [0, 0, 255, 255]);
+		assert_eq!(img.get_pixel(0, 1).0, [255, 0, 0, 255]);
+
+		assert!(decode_texture(&dds[..DDS_HEADER_END + 4], &header).is_err()); // Cut off.
+
+		// Sizes that overflow or are too large to allocate are refused before anything is read.
+		let huge = TextureHeader { width: u32::MAX, height: u32::MAX, ..header };
+		assert_eq!(decode_texture(&dds, &huge).unwrap_err().to_string(), "The texture is too large.");
+	}
+
+	#[test]
+	fn test_ktx2() {
+		let mut ktx2 = make_ktx2();
+		assert!(is_texture(&ktx2));
+		let header = read_texture_header(&ktx2).unwrap();
+		let tags = header.to_tags();
+		assert_eq!(tags[TEXTURE_CONTAINER_TAG], "KTX2");
+		assert_eq!(tags[TEXTURE_FORMAT_TAG], "BC1_RGB_SRGB");
+		assert_eq!(tags[COLOR_SPACE_TAG], "sRGB");
+		assert_eq!(tags[MIP_COUNT_TAG], "1");
+
+		let img = decode_texture(&ktx2, &header).unwrap().to_rgb8();
+		assert_eq!(img.dimensions(), (4, 4));
+		assert_eq!(img.get_pixel(3, 3).0, [255, 0, 0]);
+
+		// Formats we can't decode still have their metadata read.
+		put_u32(&mut ktx2, 12, 145);
+		let header = read_texture_header(&ktx2).unwrap();
+		assert_eq!(header.format, "BC7_UNORM");
+		assert!(decode_texture(&ktx2, &header).is_err());
+
+		assert!(!is_texture(b"\x89PNG"));
+		assert!(read_texture_header(&ktx2[..20]).is_err());
+	}
+}
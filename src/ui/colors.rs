@@ -0,0 +1,132 @@
+use crate::{AppTab, MainApp};
+use crate::image_hashes::COLOR_LAYOUT_SIZE;
+use crate::ui::color_swatch;
+use eframe::egui;
+
+const MAX_COMMON_COLORS: usize = 64;
+const LAYOUT_CELL_SIZE: f32 = 24.0;
+
+pub fn colors_panel(
+	app_state: &mut MainApp,
+	ui: &mut egui::Ui
+) {
+	if app_state.engine.is_none() {
+		ui.label("To browse by color, make sure a DB is loaded and folders have been indexed.");
+		return;
+	}
+
+	let mut search_color: Option<[u8; 3]> = None;
+
+	ui.heading("Search by Color");
+	ui.horizontal(|ui|{
+		ui.color_edit_button_srgb(&mut app_state.picked_color);
+		if ui.button("Search").clicked() {
+			search_color = Some(app_state.picked_color);
+		}
+	});
+
+	ui.separator();
+	ui.heading("Search by Layout");
+	ui.label("Paint rough blocks of color with the picked color.  Right-click to erase.  Blank cells match anything.");
+	let mut search_layout = false;
+	ui.horizontal(|ui|{
+		layout_canvas(ui, &mut app_state.layout_sketch, app_state.picked_color);
+		ui.vertical(|ui|{
+			if ui.button("Fill Blank").clicked() {
+				for cell in app_state.layout_sketch.iter_mut().filter(|c| c.is_none()) {
+					*cell = Some(app_state.picked_color);
+				}
+			}
+			if ui.button("Clear").clicked() {
+				app_state.layout_sketch.iter_mut().for_each(|c| *c = None);
+			}
+			if ui.add_enabled(app_state.layout_sketch.iter().any(|c| c.is_some()), egui::Button::new("Search Layout")).clicked() {
+				search_layout = true;
+			}
+		});
+	});
+
+	ui.separator();
+	ui.horizontal(|ui|{
+		ui.heading("Common Colors in the Library");
+		if ui.button("Refresh").clicked() {
+			app_state.common_palette_colors = None;
+		}
+	});
+
+	// Scanning every palette isn't free, so only do it when asked.
+	if app_state.common_palette_colors.is_none() {
+		match app_state.engine.as_ref().unwrap().get_common_palette_colors(MAX_COMMON_COLORS) {
+			Ok(colors) => app_state.common_palette_colors = Some(colors),
+			Err(e) => {
+				ui.label(format!("Failed to load colors: {}", e));
+				return;
+			}
+		}
+	}
+
+	egui::ScrollArea::vertical()
+		.auto_shrink([false, false])
+		.show(ui, |ui| {
+			ui.horizontal_wrapped(|ui|{
+				for &color in app_state.common_palette_colors.as_ref().unwrap() {
+					if color_swatch(ui, color, 32.0).clicked() {
+						search_color = Some(color);
+					}
+				}
+			});
+		});
+
+	if let Some(color) = search_color {
+		app_state.picked_color = color;
+		if let Err(e) = app_state.engine.as_mut().unwrap().query_by_color(color) {
+			app_state.query_error = e.to_string();
+		}
+		app_state.active_tab = AppTab::Search;
+	}
+
+	if search_layout {
+		let sketch: Vec<u8> = app_state.layout_sketch.iter().flat_map(|cell| {
+			match cell {
+				Some([r, g, b]) => [*r, *g, *b, 255],
+				None => [0, 0, 0, 0],
+			}
+		}).collect();
+		if let Err(e) = app_state.engine.as_mut().unwrap().query_by_color_layout(&sketch) {
+			app_state.query_error = e.to_string();
+		}
+		app_state.active_tab = AppTab::Search;
+	}
+}
+
+/// A grid of cells that can be painted by clicking or dragging.  Blank cells show a checkerboard.
+fn layout_canvas(ui: &mut egui::Ui, cells: &mut Vec<Option<[u8; 3]>>, brush: [u8; 3]) {
+	let side = LAYOUT_CELL_SIZE * COLOR_LAYOUT_SIZE as f32;
+	let (response, painter) = ui.allocate_painter(egui::vec2(side, side), egui::Sense::click_and_drag());
+	let rect = response.rect;
+
+	if let Some(pos) = response.interact_pointer_pos() {
+		if rect.contains(pos) {
+			let x = ((pos.x - rect.min.x) / LAYOUT_CELL_SIZE) as usize;
+			let y = ((pos.y - rect.min.y) / LAYOUT_CELL_SIZE) as usize;
+			let idx = y.min(COLOR_LAYOUT_SIZE - 1) * COLOR_LAYOUT_SIZE + x.min(COLOR_LAYOUT_SIZE - 1);
+			let erasing = ui.input(|i| i.pointer.secondary_down());
+			cells[idx] = if erasing { None } else { Some(brush) };
+		}
+	}
+
+	for (idx, cell) in cells.iter().enumerate() {
+		let min = rect.min + egui::vec2((idx % COLOR_LAYOUT_SIZE) as f32, (idx / COLOR_LAYOUT_SIZE) as f32) * LAYOUT_CELL_SIZE;
+		let cell_rect = egui::Rect::from_min_size(min, egui::vec2(LAYOUT_CELL_SIZE, LAYOUT_CELL_SIZE));
+		match cell {
+			Some([r, g, b]) => painter.rect_filled(cell_rect, 0.0, egui::Color32::from_rgb(*r, *g, *b)),
+			None => {
+				let half = LAYOUT_CELL_SIZE / 2.0;
+				painter.rect_filled(cell_rect, 0.0, egui::Color32::from_gray(200));
+				painter.rect_filled(egui::Rect::from_min_size(min, egui::vec2(half, half)), 0.0, egui::Color32::from_gray(160));
+				painter.rect_filled(egui::Rect::from_min_size(min + egui::vec2(half, half), egui::vec2(half, half)), 0.0, egui::Color32::from_gray(160));
+			}
+		}
+	}
+	painter.rect_stroke(rect, 0.0, ui.visuals().widgets.noninteractive.bg_stroke);
+}
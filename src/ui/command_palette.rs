@@ -0,0 +1,179 @@
+use crate::{AppTab, MainApp};
+use crate::ui::{menutabs, search};
+use eframe::egui;
+
+const MAX_SHOWN_COMMANDS: usize = 12;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Command {
+	NewDb,
+	OpenDb,
+	Reindex,
+	ScanForDuplicates,
+	ShowTab(AppTab, &'static str),
+	RunSavedSearch(String, String), // Name, query.
+	ToggleDarkMode,
+	ToggleHighContrast,
+}
+
+impl Command {
+	fn label(&self) -> String {
+		match self {
+			Command::NewDb => "New DB".to_string(),
+			Command::OpenDb => "Open DB".to_string(),
+			Command::Reindex => "Reindex Tracked Folders".to_string(),
+			Command::ScanForDuplicates => "Scan for Duplicates".to_string(),
+			Command::ShowTab(_, label) => format!("Go to {}", label),
+			Command::RunSavedSearch(name, _) => format!("Run Saved Search: {}", name),
+			Command::ToggleDarkMode => "Toggle Dark Mode".to_string(),
+			Command::ToggleHighContrast => "Toggle High Contrast".to_string(),
+		}
+	}
+}
+
+/// Everything that can be run from the palette right now.  Most commands need an open DB.
+fn available_commands(app_state: &mut MainApp) -> Vec<Command> {
+	let mut commands = vec![Command::NewDb, Command::OpenDb, Command::ToggleDarkMode, Command::ToggleHighContrast];
+	if let Some(engine) = app_state.engine.as_mut() {
+		commands.extend([Command::Reindex, Command::ScanForDuplicates]);
+		commands.extend([
+			Command::ShowTab(AppTab::Search, "Search"),
+			Command::ShowTab(AppTab::View, "View"),
+			Command::ShowTab(AppTab::Colors, "Colors"),
+			Command::ShowTab(AppTab::Folders, "Folders"),
+			Command::ShowTab(AppTab::Trash, "Trash"),
+			Command::ShowTab(AppTab::Review, "Review (Near-Duplicates)"),
+			Command::ShowTab(AppTab::Rules, "Rules"),
+			Command::ShowTab(AppTab::Settings, "Settings"),
+		]);
+		match engine.get_saved_searches() {
+			Ok(saved) => commands.extend(saved.into_iter().map(|s| Command::RunSavedSearch(s.name, s.query))),
+			Err(e) => eprintln!("Failed to load saved searches for the command palette: {}", e),
+		}
+	}
+	commands
+}
+
+pub fn command_palette(app_state: &mut MainApp, ctx: &egui::Context) {
+	let mut matches: Vec<(i32, Command)> = available_commands(app_state).into_iter().filter_map(|command| {
+		fuzzy_score(&app_state.command_palette_text, &command.label()).map(|score| (score, command))
+	}).collect();
+	// Stable, so ties keep their natural order.
+	matches.sort_by(|a, b| b.0.cmp(&a.0));
+	matches.truncate(MAX_SHOWN_COMMANDS);
+
+	let (up, down, enter, escape) = ctx.input_mut(|i| (
+		i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp),
+		i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown),
+		i.consume_key(egui::Modifiers::NONE, egui::Key::Enter),
+		i.consume_key(egui::Modifiers::NONE, egui::Key::Escape),
+	));
+	if up {
+		app_state.command_palette_selected = app_state.command_palette_selected.saturating_sub(1);
+	}
+	if down {
+		app_state.command_palette_selected += 1;
+	}
+	app_state.command_palette_selected = app_state.command_palette_selected.min(matches.len().saturating_sub(1));
+
+	let mut to_run: Option<Command> = if enter { matches.get(app_state.command_palette_selected).map(|(_, c)| c.clone()) } else { None };
+
+	egui::Window::new("Command Palette")
+		.title_bar(false)
+		.collapsible(false)
+		.resizable(false)
+		.anchor(egui::Align2::CENTER_TOP, [0.0, 40.0])
+		.show(ctx, |ui| {
+			let text_response = ui.add(egui::TextEdit::singleline(&mut app_state.command_palette_text).hint_text("Type a command...").desired_width(400.0));
+			text_response.request_focus();
+			if text_response.changed() {
+				app_state.command_palette_selected = 0;
+			}
+			for (idx, (_, command)) in matches.iter().enumerate() {
+				if ui.selectable_label(idx == app_state.command_palette_selected, command.label()).clicked() {
+					to_run = Some(command.clone());
+				}
+			}
+			if matches.is_empty() {
+				ui.weak("No matching commands.");
+			}
+		});
+
+	if escape || to_run.is_some() {
+		app_state.command_palette_open = false;
+	}
+	if let Some(command) = to_run {
+		run_command(app_state, command);
+	}
+}
+
+fn run_command(app_state: &mut MainApp, command: Command) {
+	match command {
+		Command::NewDb => menutabs::new_db(app_state),
+		Command::OpenDb => menutabs::open_db(app_state),
+		Command::Reindex => {
+			if let Some(engine) = app_state.engine.as_mut() {
+				if let Err(e) = engine.start_reindexing() {
+					eprintln!("Failed to start indexing: {}", e);
+				}
+				app_state.active_tab = AppTab::Folders; // Progress is shown there.
+			}
+		},
+		Command::ScanForDuplicates => {
+			if let Some(engine) = app_state.engine.as_mut() {
+				if let Err(e) = engine.start_duplicate_scan() {
+					eprintln!("Failed to start a duplicate scan: {}", e);
+				}
+				app_state.active_tab = AppTab::Review; // Results are shown there.
+			}
+		},
+		Command::ShowTab(tab, _) => app_state.active_tab = tab,
+		Command::RunSavedSearch(_, query) => {
+			app_state.search_text = query;
+			app_state.current_page = 0;
+			app_state.active_tab = AppTab::Search;
+			search::run_search_page(app_state);
+		},
+		Command::ToggleDarkMode => app_state.dark_mode = !app_state.dark_mode,
+		Command::ToggleHighContrast => app_state.high_contrast = !app_state.high_contrast,
+	}
+}
+
+/// How well the query matches the candidate as an in-order, case-insensitive subsequence.  None if it doesn't.
+/// Runs of consecutive letters and letters at the start of words score higher, so "os" prefers "Open Search" to "Close".
+fn fuzzy_score(query: &str, candidate: &str) -> Option<i32> {
+	let candidate: Vec<char> = candidate.to_lowercase().chars().collect();
+	let mut score = 0;
+	let mut next_idx = 0;
+	let mut last_match: Option<usize> = None;
+	for c in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+		let idx = (next_idx..candidate.len()).find(|&i| candidate[i] == c)?;
+		score += 1;
+		if idx > 0 && last_match == Some(idx - 1) {
+			score += 4;
+		}
+		if idx == 0 || !candidate[idx - 1].is_alphanumeric() {
+			score += 3;
+		}
+		last_match = Some(idx);
+		next_idx = idx + 1;
+	}
+	Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::ui::command_palette::fuzzy_score;
+
+	#[test]
+	fn test_fuzzy_score() {
+		assert_eq!(fuzzy_score("", "Open DB"), Some(0));
+		assert!(fuzzy_score("odb", "Open DB").is_some());
+		assert!(fuzzy_score("OPEN", "Open DB").is_some());
+		assert!(fuzzy_score("bdo", "Open DB").is_none()); // Out of order.
+		assert!(fuzzy_score("x", "Open DB").is_none());
+
+		// Word starts and runs beat scattered letters.
+		assert!(fuzzy_score("os", "Open Search").unwrap() > fuzzy_score("os", "Close").unwrap());
+	}
+}
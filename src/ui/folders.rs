@@ -1,11 +1,14 @@
+use crate::crawler::CrawlFolder;
 use crate::engine::Engine;
-use crate::ui::paginate;
+use crate::ui::{format_duration, paginate};
 use eframe::{egui, NativeOptions};
 use rfd;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+const MAX_FAILURES_SHOWN: usize = 200;
+
 pub fn folder_panel(
 		engine: &mut Engine,
 		ctx: &egui::Context,
@@ -14,30 +17,166 @@ pub fn folder_panel(
 	// We are kinda' assuming that these things can't happen in the same frame.
 	let mut new_tracked_folder: Option<String> = None;
 	let mut to_remove:Option<String> = None;
+	let mut to_purge:Option<String> = None;
+	let mut to_reindex:Option<String> = None;
+	let mut new_settings:Option<CrawlFolder> = None;
+	let mut clear_failures = false;
+	let mut retry_failures = false;
+	let mut to_include:Option<String> = None;
 	
 	//ui.heading("Watched Directories");
 	//ui.collapsing("Watched Directories", |ui| {
 	let scroll_area = egui::ScrollArea::vertical();
 	scroll_area.max_height(ui.available_rect_before_wrap().height()).show(ui, |ui| {
-		let folders = engine.get_tracked_folders();
+		let indexing = engine.is_indexing_active();
+		let folders = match engine.get_tracked_folders() {
+			Ok(folders) => folders.clone(),
+			Err(e) => {
+				ui.label(format!("Failed to load the watched folders: {}", e));
+				return;
+			}
+		};
+		let unreachable = engine.get_unreachable_folders();
 		
 		// New folder to add...
 		if ui.button("Add Directory").clicked() {
 			if let Some(new_path) = rfd::FileDialog::new().pick_folder() {
 				//fs::canonicalize() should get us from a relative path to this, but I've not had problems so far without it and it adds a funky \\X?\ to Windows paths.
-				new_tracked_folder = Some(new_path.as_path().to_str().unwrap().parse().unwrap());
+				// Folders are tracked as glob patterns, which have to be text.
+				match new_path.to_str() {
+					Some(path) => new_tracked_folder = Some(path.to_string()),
+					None => eprintln!("Can't track {}: its name isn't valid Unicode.", new_path.display()),
+				}
 			}
 		}
 		
 		// Old folder to remove.
-		for dir in folders {
+		for dir in &folders {
+			let settings = match engine.get_folder_settings(dir) {
+				Ok(settings) => settings,
+				Err(e) => {
+					ui.label(format!("Failed to load the options of {}: {}", dir, e));
+					continue;
+				}
+			};
+			let mut edited = settings.clone();
 			ui.horizontal(|ui|{
 				ui.label(dir);
-				if ui.button("x").clicked() {
+				if unreachable.contains(dir) {
+					ui.colored_label(ui.visuals().warn_fg_color, "Unreachable").on_hover_text("This folder didn't answer the last time it was indexed, so it was skipped.  If it's on a NAS or network share, it may be asleep or disconnected.  It's tried again the next time folders are indexed.");
+				}
+				ui.add(egui::DragValue::new(&mut edited.priority).prefix("Priority: ")).on_hover_text("Folders with a higher priority are indexed first.");
+				if ui.add_enabled(!indexing, egui::Button::new("Reindex")).on_hover_text("Look for new images in just this folder.").clicked() {
+					to_reindex = Some(dir.clone());
+				}
+				if ui.button("x").on_hover_text("Stop tracking this folder.  Indexed images are kept.").clicked() {
 					to_remove = Some(dir.clone());
 				}
+				if ui.button("Purge").on_hover_text("Stop tracking this folder and move its images to the trash.  Protected images are kept.").clicked() {
+					to_purge = Some(dir.clone());
+				}
+			});
+			egui::CollapsingHeader::new("Options").id_source(dir).show(ui, |ui| {
+				ui.horizontal(|ui| {
+					ui.checkbox(&mut edited.recursive, "Subfolders");
+					ui.checkbox(&mut edited.scan_archives, "Look Inside Archives, Books, and Mail").on_hover_text("Index the images in zip, tar, and 7z archives, the images attached to emails, and the pages of books if Index Book Pages is on in Settings.");
+					ui.checkbox(&mut edited.follow_symlinks, "Follow Symlinks");
+				});
+
+				// Text being typed is kept in egui's memory, since there are boxes for every folder.
+				let extensions_id = ui.id().with("extensions_draft");
+				let mut extensions = ui.data_mut(|d| d.get_temp::<String>(extensions_id)).unwrap_or_else(|| settings.extensions.join(" "));
+				ui.horizontal(|ui| {
+					ui.label("Only:");
+					let response = ui.add(egui::TextEdit::singleline(&mut extensions).hint_text("png jpg (all kinds if empty)").desired_width(200.0));
+					if response.lost_focus() {
+						edited.extensions = extensions.split(|c: char| c.is_whitespace() || c == ',').map(|e| e.to_string()).collect();
+						ui.data_mut(|d| d.remove::<String>(extensions_id));
+					} else if response.has_focus() {
+						ui.data_mut(|d| d.insert_temp(extensions_id, extensions));
+					}
+				});
+				let password_id = ui.id().with("archive_password_draft");
+				let mut password = ui.data_mut(|d| d.get_temp::<String>(password_id)).unwrap_or_else(|| settings.archive_password.clone());
+				ui.horizontal(|ui| {
+					ui.label("Archive Password:");
+					let response = ui.add(egui::TextEdit::singleline(&mut password).password(true).hint_text("For encrypted zips and 7zs").desired_width(200.0));
+					response.clone().on_hover_text("Used to open the encrypted zip and 7z archives in this folder.  It's kept in the DB as it's typed, so anyone with a copy of the DB can read it.");
+					if response.lost_focus() {
+						edited.archive_password = password;
+						ui.data_mut(|d| d.remove::<String>(password_id));
+					} else if response.has_focus() {
+						ui.data_mut(|d| d.insert_temp(password_id, password));
+					}
+				});
+
+				ui.label("Ignored:");
+				for (i, pattern) in settings.ignore_patterns.iter().enumerate() {
+					ui.horizontal(|ui| {
+						ui.label(pattern);
+						if ui.small_button("x").on_hover_text("Stop ignoring these files.").clicked() {
+							edited.ignore_patterns.remove(i);
+						}
+					});
+				}
+				let draft_id = ui.id().with("ignore_pattern_draft");
+				let mut draft = ui.data_mut(|d| d.get_temp::<String>(draft_id)).unwrap_or_default();
+				ui.horizontal(|ui| {
+					ui.add(egui::TextEdit::singleline(&mut draft).hint_text("**/node_modules/**, *.tmp, .thumbnails").desired_width(200.0));
+					if ui.add_enabled(!draft.trim().is_empty(), egui::Button::new("Ignore")).on_hover_text("Skip files matching this when indexing.  Patterns without a slash match any file or folder name.  Images already indexed are kept.").clicked() {
+						edited.ignore_patterns.push(draft.trim().to_string());
+						draft.clear();
+					}
+				});
+				ui.data_mut(|d| d.insert_temp(draft_id, draft));
 			});
+			if edited != settings {
+				new_settings = Some(edited);
+			}
 		}
+
+		// Only looked up while it's open, since there can be a lot of them.
+		ui.collapsing("Failed Files", |ui| {
+			match engine.get_indexing_failures() {
+				Ok(failures) if failures.is_empty() => { ui.label("Everything found so far could be indexed."); },
+				Ok(failures) => {
+					ui.horizontal(|ui| {
+						if ui.add_enabled(!indexing, egui::Button::new("Retry")).on_hover_text("Try indexing these again.  The ones that work are taken off the list.").clicked() {
+							retry_failures = true;
+						}
+						if ui.button("Clear").on_hover_text("Forget these failures.  Files that still can't be indexed will be listed again next time.").clicked() {
+							clear_failures = true;
+						}
+					});
+					for failure in failures.iter().take(MAX_FAILURES_SHOWN) {
+						ui.label(format!("{}  {}: {}", failure.failed, failure.path, failure.error));
+					}
+					if failures.len() > MAX_FAILURES_SHOWN {
+						ui.label(format!("And {} more.", failures.len() - MAX_FAILURES_SHOWN));
+					}
+				},
+				Err(e) => { ui.label(format!("Couldn't load the failures: {}", e)); },
+			}
+		});
+		ui.collapsing("Excluded Images", |ui| {
+			match engine.get_exclusions() {
+				Ok(exclusions) if exclusions.is_empty() => { ui.label("Nothing's excluded.  Images can be excluded from the View tab."); },
+				Ok(exclusions) => {
+					for exclusion in &exclusions {
+						ui.horizontal(|ui| {
+							let label = ui.label(&exclusion.path);
+							if exclusion.by_content {
+								label.on_hover_text("Copies of this file are excluded too.");
+							}
+							if ui.small_button("x").on_hover_text("Stop excluding this.  It's indexed again on the next reindex.").clicked() {
+								to_include = Some(exclusion.path.clone());
+							}
+						});
+					}
+				},
+				Err(e) => { ui.label(format!("Couldn't load the exclusions: {}", e)); },
+			}
+		});
 	});
 
 	// Status Areas:
@@ -46,27 +185,94 @@ pub fn folder_panel(
 		.min_height(0.0)
 		.show(ctx, |ui| {
 			// Show Reindexing Button
-			if engine.is_indexing_active() {
-				engine.get_num_indexed_images();
-				ui.label(format!("Reindexing.  Progress: {}%", 100.0*engine.get_indexing_progress()));
+			// Pausing outlasts the run it paused, so Resume has to stay reachable after one ends.
+			if engine.is_indexing_active() || engine.is_indexing_paused() {
+				if let Err(e) = engine.get_num_indexed_images() {
+					eprintln!("Failed to count the indexed images: {}", e);
+				}
+				ui.horizontal(|ui| {
+					if engine.is_indexing_cancelled() {
+						ui.label("Stopping.  Storing what was already loaded.");
+					} else if engine.is_indexing_paused() {
+						ui.label("Indexing paused.");
+						if ui.button("Resume").clicked() {
+							engine.resume_indexing();
+						}
+					} else {
+						ui.label("Reindexing.");
+						if ui.button("Pause").on_hover_text("Free up the CPU and disk for now.  Nothing found so far is lost.").clicked() {
+							engine.pause_indexing();
+						}
+					}
+					if engine.is_indexing_active() && !engine.is_indexing_cancelled() && ui.button("Stop Indexing").on_hover_text("Stop looking for and loading files.  Images already loaded are still stored.  Reindex picks up the rest later.").clicked() {
+						engine.cancel_indexing();
+					}
+				});
+				if let Some(progress) = engine.get_indexing_progress_details() {
+					let found = if progress.finished_finding { progress.files_found.to_string() } else { format!("{}+", progress.files_found) };
+					let eta = match (progress.eta, progress.finished_finding) {
+						(Some(eta), _) => format!("About {} left.", format_duration(eta)),
+						(None, false) => "Still looking for files.".to_string(),
+						(None, true) => "".to_string(),
+					};
+					ui.add(egui::ProgressBar::new(progress.fraction()).text(format!("{} of {} files", progress.files_processed, found)));
+					ui.label(format!("{} images stored, {} failed.  {}", progress.images_stored, progress.failed, eta));
+				}
 				//ui.vertical_centered(|ui| {});
 				for file in engine.get_last_indexed() {
 					ui.label(file);
 				}
 			} else {
 				if ui.button("Reindex").clicked() {
-					engine.start_reindexing();
+					if let Err(e) = engine.start_reindexing() {
+						eprintln!("Failed to start indexing: {}", e);
+					}
 				}
 			}
 		});
 
+	if let Some(settings) = new_settings {
+		if let Err(e) = engine.set_folder_settings(&settings) {
+			eprintln!("Failed to change the options of {}: {}", &settings.glob, e);
+		}
+	}
+	if let Some(path) = to_include {
+		if let Err(e) = engine.remove_exclusion(&path) {
+			eprintln!("Failed to stop excluding {}: {}", &path, e);
+		}
+	}
+	if clear_failures {
+		if let Err(e) = engine.clear_indexing_failures() {
+			eprintln!("Failed to clear the indexing failures: {}", e);
+		}
+	}
 	if !engine.is_indexing_active() {
 		if let Some(new_folder) = new_tracked_folder {
 			// New Folder Addition
-			engine.add_tracked_folder(new_folder);
+			if let Err(e) = engine.add_tracked_folder(new_folder) {
+				eprintln!("Failed to add the folder: {}", e);
+			}
 		} else if let Some(dir_to_remove) = to_remove {
 			// Folder Removal
-			engine.remove_tracked_folder(dir_to_remove);
+			if let Err(e) = engine.remove_tracked_folder(dir_to_remove) {
+				eprintln!("Failed to remove the folder: {}", e);
+			}
+		} else if retry_failures {
+			if let Err(e) = engine.retry_failed() {
+				eprintln!("Failed to retry the failed files: {}", e);
+			}
+		} else if let Some(dir_to_reindex) = to_reindex {
+			if let Err(e) = engine.reindex_folder(&dir_to_reindex) {
+				eprintln!("Failed to reindex {}: {}", &dir_to_reindex, e);
+			}
+		} else if let Some(dir_to_purge) = to_purge {
+			// Folder Removal + Trashing
+			if let Err(e) = engine.trash_images_in_folder(&dir_to_purge) {
+				eprintln!("Failed to move images in {} to the trash: {}", &dir_to_purge, e);
+			}
+			if let Err(e) = engine.remove_tracked_folder(dir_to_purge) {
+				eprintln!("Failed to remove the folder: {}", e);
+			}
 		}
 	}
 }
\ No newline at end of file
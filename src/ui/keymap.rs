@@ -0,0 +1,261 @@
+use eframe::egui::{self, Key, KeyboardShortcut, ModifierNames, Modifiers};
+use serde_json::{Map as JSONMap, Value as JSONValue};
+use std::collections::HashMap;
+
+// Every key that can be bound, so shortcuts can be read back from their names.
+const BINDABLE_KEYS: &'static [Key] = &[
+	Key::ArrowDown, Key::ArrowLeft, Key::ArrowRight, Key::ArrowUp,
+	Key::Escape, Key::Tab, Key::Backspace, Key::Enter, Key::Space,
+	Key::Insert, Key::Delete, Key::Home, Key::End, Key::PageUp, Key::PageDown,
+	Key::Minus, Key::PlusEquals,
+	Key::Num0, Key::Num1, Key::Num2, Key::Num3, Key::Num4, Key::Num5, Key::Num6, Key::Num7, Key::Num8, Key::Num9,
+	Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+	Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+	Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10,
+	Key::F11, Key::F12, Key::F13, Key::F14, Key::F15, Key::F16, Key::F17, Key::F18, Key::F19, Key::F20,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+	CommandPalette,
+	FocusSearch,
+	NextPage,
+	PreviousPage,
+	ShowSearch,
+	ShowView,
+	ShowColors,
+	ShowFolders,
+	Reindex,
+	RateZero,
+	RateOne,
+	RateTwo,
+	RateThree,
+	RateFour,
+	RateFive,
+	ToggleFavorite,
+}
+
+impl Action {
+	pub const ALL: [Action; 16] = [
+		Action::CommandPalette, Action::FocusSearch, Action::NextPage, Action::PreviousPage,
+		Action::ShowSearch, Action::ShowView, Action::ShowColors, Action::ShowFolders, Action::Reindex,
+		Action::RateZero, Action::RateOne, Action::RateTwo, Action::RateThree, Action::RateFour, Action::RateFive,
+		Action::ToggleFavorite,
+	];
+
+	/// The rating actions, in order of the stars they give.
+	pub const RATINGS: [Action; 6] = [Action::RateZero, Action::RateOne, Action::RateTwo, Action::RateThree, Action::RateFour, Action::RateFive];
+
+	/// Used as the key in exported configs, so these shouldn't change.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Action::CommandPalette => "command_palette",
+			Action::FocusSearch => "focus_search",
+			Action::NextPage => "next_page",
+			Action::PreviousPage => "previous_page",
+			Action::ShowSearch => "show_search",
+			Action::ShowView => "show_view",
+			Action::ShowColors => "show_colors",
+			Action::ShowFolders => "show_folders",
+			Action::Reindex => "reindex",
+			Action::RateZero => "rate_0",
+			Action::RateOne => "rate_1",
+			Action::RateTwo => "rate_2",
+			Action::RateThree => "rate_3",
+			Action::RateFour => "rate_4",
+			Action::RateFive => "rate_5",
+			Action::ToggleFavorite => "toggle_favorite",
+		}
+	}
+
+	pub fn label(&self) -> &'static str {
+		match self {
+			Action::CommandPalette => "Command Palette",
+			Action::FocusSearch => "Focus Search Box",
+			Action::NextPage => "Next Page of Results",
+			Action::PreviousPage => "Previous Page of Results",
+			Action::ShowSearch => "Go to Search",
+			Action::ShowView => "Go to View",
+			Action::ShowColors => "Go to Colors",
+			Action::ShowFolders => "Go to Folders",
+			Action::Reindex => "Reindex Tracked Folders",
+			Action::RateZero => "Clear Rating",
+			Action::RateOne => "Rate 1 Star",
+			Action::RateTwo => "Rate 2 Stars",
+			Action::RateThree => "Rate 3 Stars",
+			Action::RateFour => "Rate 4 Stars",
+			Action::RateFive => "Rate 5 Stars",
+			Action::ToggleFavorite => "Toggle Favorite",
+		}
+	}
+
+	fn default_shortcut(&self) -> KeyboardShortcut {
+		match self {
+			Action::CommandPalette => KeyboardShortcut::new(Modifiers::COMMAND | Modifiers::SHIFT, Key::P),
+			Action::FocusSearch => KeyboardShortcut::new(Modifiers::COMMAND, Key::F),
+			Action::NextPage => KeyboardShortcut::new(Modifiers::NONE, Key::PageDown),
+			Action::PreviousPage => KeyboardShortcut::new(Modifiers::NONE, Key::PageUp),
+			Action::ShowSearch => KeyboardShortcut::new(Modifiers::COMMAND, Key::Num1),
+			Action::ShowView => KeyboardShortcut::new(Modifiers::COMMAND, Key::Num2),
+			Action::ShowColors => KeyboardShortcut::new(Modifiers::COMMAND, Key::Num3),
+			Action::ShowFolders => KeyboardShortcut::new(Modifiers::COMMAND, Key::Num4),
+			Action::Reindex => KeyboardShortcut::new(Modifiers::NONE, Key::F5),
+			Action::RateZero => KeyboardShortcut::new(Modifiers::NONE, Key::Num0),
+			Action::RateOne => KeyboardShortcut::new(Modifiers::NONE, Key::Num1),
+			Action::RateTwo => KeyboardShortcut::new(Modifiers::NONE, Key::Num2),
+			Action::RateThree => KeyboardShortcut::new(Modifiers::NONE, Key::Num3),
+			Action::RateFour => KeyboardShortcut::new(Modifiers::NONE, Key::Num4),
+			Action::RateFive => KeyboardShortcut::new(Modifiers::NONE, Key::Num5),
+			Action::ToggleFavorite => KeyboardShortcut::new(Modifiers::NONE, Key::F),
+		}
+	}
+
+	fn from_name(name: &str) -> Option<Action> {
+		Action::ALL.into_iter().find(|a| a.name() == name)
+	}
+}
+
+/// Which shortcut runs each action.  Every action always has exactly one shortcut.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+	bindings: HashMap<Action, KeyboardShortcut>,
+}
+
+impl Default for Keymap {
+	fn default() -> Self {
+		Keymap {
+			bindings: Action::ALL.into_iter().map(|a| (a, a.default_shortcut())).collect(),
+		}
+	}
+}
+
+impl Keymap {
+	pub fn get(&self, action: Action) -> KeyboardShortcut {
+		self.bindings.get(&action).copied().unwrap_or_else(|| action.default_shortcut())
+	}
+
+	pub fn set(&mut self, action: Action, shortcut: KeyboardShortcut) {
+		self.bindings.insert(action, shortcut);
+	}
+
+	pub fn reset(&mut self, action: Action) {
+		self.bindings.insert(action, action.default_shortcut());
+	}
+
+	/// Other actions bound to the same shortcut as this one.  Only the first of them will ever run.
+	pub fn conflicts(&self, action: Action) -> Vec<Action> {
+		let shortcut = self.get(action);
+		Action::ALL.into_iter().filter(|&a| a != action && self.get(a) == shortcut).collect()
+	}
+
+	/// Consume the action's shortcut if it was pressed this frame.
+	/// Shortcuts without Ctrl/Cmd or Alt are ignored while typing so they don't steal keys from text boxes.
+	pub fn pressed(&self, ctx: &egui::Context, action: Action) -> bool {
+		let shortcut = self.get(action);
+		if !shortcut.modifiers.command && !shortcut.modifiers.alt && ctx.wants_keyboard_input() {
+			return false;
+		}
+		ctx.input_mut(|i| i.consume_shortcut(&shortcut))
+	}
+
+	/// Action names to shortcuts like "Ctrl+Shift+P".
+	pub fn to_json(&self) -> JSONValue {
+		let bindings: JSONMap<String, JSONValue> = Action::ALL.into_iter().map(|a| (a.name().to_string(), JSONValue::String(format_shortcut(&self.get(a))))).collect();
+		JSONValue::Object(bindings)
+	}
+
+	/// Read bindings from to_json.  Unknown actions and unreadable shortcuts are skipped, leaving those actions as they were.
+	pub fn update_from_json(&mut self, value: &JSONValue) {
+		if let Some(bindings) = value.as_object() {
+			for (name, shortcut) in bindings {
+				let action = Action::from_name(name);
+				let shortcut = shortcut.as_str().and_then(parse_shortcut);
+				if let (Some(action), Some(shortcut)) = (action, shortcut) {
+					self.set(action, shortcut);
+				}
+			}
+		}
+	}
+}
+
+/// Make a shortcut from a key press.  Ctrl and Cmd are both treated as 'command' so bindings work across platforms.
+pub fn shortcut_from_key_press(key: Key, pressed_modifiers: Modifiers) -> KeyboardShortcut {
+	let mut modifiers = Modifiers::NONE;
+	if pressed_modifiers.command || pressed_modifiers.ctrl || pressed_modifiers.mac_cmd {
+		modifiers = modifiers | Modifiers::COMMAND;
+	}
+	if pressed_modifiers.alt {
+		modifiers = modifiers | Modifiers::ALT;
+	}
+	if pressed_modifiers.shift {
+		modifiers = modifiers | Modifiers::SHIFT;
+	}
+	KeyboardShortcut::new(modifiers, key)
+}
+
+/// Like "Ctrl+Shift+P".  Command is always written as Ctrl so configs move between platforms.
+pub fn format_shortcut(shortcut: &KeyboardShortcut) -> String {
+	shortcut.format(&ModifierNames::NAMES, false)
+}
+
+/// The reverse of format_shortcut.  Cmd is accepted as a synonym for Ctrl.
+pub fn parse_shortcut(text: &str) -> Option<KeyboardShortcut> {
+	let mut parts: Vec<&str> = text.split('+').map(|p| p.trim()).collect();
+	// "Ctrl+Plus" is written with the key name, but be forgiving of "Ctrl++".
+	if text.ends_with("++") {
+		parts.truncate(parts.len() - 2);
+		parts.push("Plus");
+	}
+	let key_name = parts.pop()?;
+	let key = BINDABLE_KEYS.iter().copied().find(|k| k.name().eq_ignore_ascii_case(key_name))?;
+
+	let mut modifiers = Modifiers::NONE;
+	for part in parts {
+		modifiers = modifiers | match part.to_lowercase().as_str() {
+			"ctrl" | "cmd" | "command" => Modifiers::COMMAND,
+			"alt" | "option" => Modifiers::ALT,
+			"shift" => Modifiers::SHIFT,
+			_ => return None,
+		};
+	}
+	Some(KeyboardShortcut::new(modifiers, key))
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::ui::keymap::*;
+
+	#[test]
+	fn test_shortcut_round_trip() {
+		for action in Action::ALL {
+			let shortcut = action.default_shortcut();
+			assert_eq!(parse_shortcut(&format_shortcut(&shortcut)), Some(shortcut), "{:?}", action);
+		}
+		assert_eq!(format_shortcut(&Action::CommandPalette.default_shortcut()), "Ctrl+Shift+P");
+		assert_eq!(parse_shortcut("cmd+shift+p"), Some(Action::CommandPalette.default_shortcut()));
+		assert_eq!(parse_shortcut("Ctrl++"), Some(KeyboardShortcut::new(Modifiers::COMMAND, Key::PlusEquals)));
+		assert_eq!(parse_shortcut("Hyper+P"), None);
+		assert_eq!(parse_shortcut("Ctrl+"), None);
+		assert_eq!(parse_shortcut(""), None);
+	}
+
+	#[test]
+	fn test_keymap_json_and_conflicts() {
+		let mut keymap = Keymap::default();
+		assert!(Action::ALL.into_iter().all(|a| keymap.conflicts(a).is_empty()));
+
+		keymap.set(Action::NextPage, KeyboardShortcut::new(Modifiers::NONE, Key::F5));
+		assert_eq!(keymap.conflicts(Action::NextPage), vec![Action::Reindex]);
+
+		let mut loaded = Keymap::default();
+		loaded.update_from_json(&keymap.to_json());
+		assert_eq!(loaded, keymap);
+
+		// Bad entries leave the old binding alone.
+		loaded.update_from_json(&serde_json::json!({"next_page": "Nonsense+Q", "no_such_action": "F1"}));
+		assert_eq!(loaded, keymap);
+
+		loaded.reset(Action::NextPage);
+		assert_eq!(loaded.get(Action::NextPage), Action::NextPage.default_shortcut());
+	}
+}
@@ -1,5 +1,5 @@
 use std::path::Path;
-use crate::Engine;
+use crate::engine::{Engine, OpenMode};
 use crate::AppTab;
 use crate::MainApp;
 use eframe::egui;
@@ -9,20 +9,11 @@ pub fn navigation(app_state: &mut MainApp, ui: &mut egui::Ui) {
 	egui::menu::bar(ui, |ui| {
 		ui.menu_button("File", |ui| {
 			if ui.button("New DB").clicked() {
-				if let Some(file_path) = rfd::FileDialog::new().add_filter("SQLite DB", &["db", "sqlite"]).save_file() {
-					// TODO: Shutdown old engine.
-					app_state.image_id_to_texture_handle.clear();
-					app_state.engine = Some(Engine::new(Path::new(&file_path)));
-					app_state.active_tab = AppTab::Folders;  // Transition right away to tracking new folders.
-				}
+				new_db(app_state);
 				ui.close_menu();
 			}
 			if ui.button("Open DB").clicked() {
-				if let Some(file_path) = rfd::FileDialog::new().add_filter("SQLite DB", &["db", "sqlite"]).pick_file() {
-					app_state.engine = Some(Engine::open(Path::new(&file_path)));
-					app_state.active_tab = AppTab::Search;
-					app_state.image_id_to_texture_handle.clear();
-				}
+				open_db(app_state);
 				ui.close_menu();
 			}
 			//if ui.button("Quit").clicked() { frame.quit(); }
@@ -30,7 +21,50 @@ pub fn navigation(app_state: &mut MainApp, ui: &mut egui::Ui) {
 
 		ui.selectable_value(&mut app_state.active_tab, AppTab::Search, "Search");
 		ui.selectable_value(&mut app_state.active_tab, AppTab::View, "View");
+		ui.selectable_value(&mut app_state.active_tab, AppTab::Colors, "Colors");
 		ui.selectable_value(&mut app_state.active_tab, AppTab::Folders, "Folders");
+		ui.selectable_value(&mut app_state.active_tab, AppTab::Trash, "Trash");
+		ui.selectable_value(&mut app_state.active_tab, AppTab::Review, "Review");
+		ui.selectable_value(&mut app_state.active_tab, AppTab::Rules, "Rules");
 		ui.selectable_value(&mut app_state.active_tab, AppTab::Settings, "Settings");
 	});
-}
\ No newline at end of file
+}
+
+pub fn new_db(app_state: &mut MainApp) {
+	if let Some(file_path) = rfd::FileDialog::new().add_filter("SQLite DB", &["db", "sqlite"]).save_file() {
+		close_db(app_state);
+		match Engine::open_or_create(Path::new(&file_path), OpenMode::CreateNew) {
+			Ok(engine) => {
+				app_state.engine = Some(engine);
+				app_state.active_tab = AppTab::Folders;  // Transition right away to tracking new folders.
+			},
+			Err(e) => app_state.db_error = e.to_string(),
+		}
+	}
+}
+
+pub fn open_db(app_state: &mut MainApp) {
+	if let Some(file_path) = rfd::FileDialog::new().add_filter("SQLite DB", &["db", "sqlite"]).pick_file() {
+		close_db(app_state);
+		match Engine::open_or_create(Path::new(&file_path), OpenMode::OpenExisting) {
+			Ok(engine) => {
+				app_state.engine = Some(engine);
+				app_state.active_tab = AppTab::Search;
+			},
+			Err(e) => app_state.db_error = e.to_string(),
+		}
+	}
+}
+
+/// Finish up with the current DB, if there is one, and forget anything loaded from it.
+fn close_db(app_state: &mut MainApp) {
+	if let Some(engine) = app_state.engine.take() {
+		if let Err(e) = engine.close() {
+			eprintln!("Failed to close the DB: {}", e);
+		}
+	}
+	app_state.db_error.clear();
+	app_state.image_id_to_texture_handle.clear();
+	app_state.common_palette_colors = None;
+	app_state.active_tab = AppTab::Start;
+}
@@ -1,19 +1,28 @@
+pub mod colors;
+pub mod command_palette;
 pub mod menutabs;
 pub mod search;
 pub mod settings;
 pub mod start;
 pub mod folders;
+pub mod keymap;
+pub mod trash;
+pub mod review;
+pub mod rules;
 pub mod view;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use eframe::egui;
 use eframe::egui::{ColorImage, TextureOptions};
 use eframe::egui::Ui;
 use egui_extras::RetainedImage;
 use image;
 
+use crate::engine;
 use crate::indexed_image;
 use crate::indexed_image::IndexedImage;
+use crate::{AppTab, MainApp};
+use crate::ui::keymap::{Action, Keymap};
 
 fn load_image_from_path(path: &std::path::Path) -> Result<ColorImage, image::ImageError> {
 	let image = image::io::Reader::open(path)?.decode()?;
@@ -48,28 +57,169 @@ fn indexed_image_to_egui_colorimage(indexed_image: &IndexedImage, alpha_fill:u8)
 /// Given the thumbnail cache and an image ID, will attempt to load the TextureID from the cache.
 /// On a cache hit, will return the TextureID.
 /// On a cache miss, will take the RGB enumeration and generate a new thumbnail, then return the ID.
-pub fn fetch_or_generate_thumbnail(res: &IndexedImage, thumbnail_cache: &mut HashMap::<i64, egui::TextureHandle>, ctx: &egui::Context) -> egui::TextureHandle {
-	match thumbnail_cache.get(&res.id) {
+/// Sensitive images get their blurred thumbnail unless they're in revealed_images.
+pub fn fetch_or_generate_thumbnail(res: &IndexedImage, revealed_images: &HashSet<i64>, thumbnail_cache: &mut HashMap::<(i64, bool), egui::TextureHandle>, ctx: &egui::Context) -> egui::TextureHandle {
+	let blurred = res.blurred_thumbnail.as_ref().filter(|_| !revealed_images.contains(&res.id));
+	match thumbnail_cache.get(&(res.id, blurred.is_some())) {
 		Some(tid) => tid.clone(),
 		None => {
-			let texture = ctx.load_texture(res.path.clone(), indexed_image_to_egui_colorimage(res, 255u8), TextureOptions::LINEAR);
-			thumbnail_cache.insert(res.id, texture.clone());
+			let image = match blurred.map(|b| indexed_image::decode_thumbnail(b)) {
+				Some(Ok((pixels, (width, height)))) => ColorImage::from_rgb([width as usize, height as usize], &pixels),
+				Some(Err(_)) => ColorImage::new([1, 1], egui::Color32::GRAY), // Never fall back to the unblurred thumbnail.
+				None => indexed_image_to_egui_colorimage(res, 255u8),
+			};
+			let texture = ctx.load_texture(res.path.clone(), image, TextureOptions::LINEAR);
+			thumbnail_cache.insert((res.id, blurred.is_some()), texture.clone());
 			texture
 		}
 	}
 }
 
+// Tags, from the user or the file, that describe what's in an image.  The first one an image has is its alt text.
+const CAPTION_TAGS: [&'static str; 4] = ["caption", "alt", "description", "ImageDescription"];
+
+/// What a screen reader says for a thumbnail: its caption if it has one, otherwise its filename.
+/// Blurred thumbnails say so, and don't give the caption away.
+pub fn thumbnail_alt_text(img: &IndexedImage, revealed_images: &HashSet<i64>) -> String {
+	if img.blurred_thumbnail.is_some() && !revealed_images.contains(&img.id) {
+		return format!("Blurred sensitive image, {}", img.filename);
+	}
+	let caption = CAPTION_TAGS.iter()
+		.filter_map(|name| img.tags.iter().find(|(tag, _)| tag.eq_ignore_ascii_case(name)))
+		.map(|(_, value)| value.trim().trim_matches('"').trim())
+		.find(|value| !value.is_empty());
+	match caption {
+		Some(caption) => format!("{}, {}", caption, img.filename),
+		None => img.filename.clone(),
+	}
+}
+
+/// Draw an image's thumbnail, labeled with thumbnail_alt_text for screen readers.
+pub fn show_thumbnail(ui: &mut Ui, img: &IndexedImage, revealed_images: &HashSet<i64>, thumbnail_cache: &mut HashMap::<(i64, bool), egui::TextureHandle>) -> egui::Response {
+	let tex_id = fetch_or_generate_thumbnail(img, revealed_images, thumbnail_cache, ui.ctx());
+	let alt_text = thumbnail_alt_text(img, revealed_images);
+	let response = ui.image(&tex_id);
+	response.widget_info(|| egui::WidgetInfo::labeled(egui::WidgetType::Label, &alt_text));
+	response
+}
+
+/// The theme to draw with.  High contrast is pure black and white with heavy outlines and a bright focus color.
+pub fn theme_visuals(dark_mode: bool, high_contrast: bool) -> egui::Visuals {
+	let mut visuals = if dark_mode { egui::Visuals::dark() } else { egui::Visuals::light() };
+	if high_contrast {
+		let (background, foreground, accent) = if dark_mode {
+			(egui::Color32::BLACK, egui::Color32::WHITE, egui::Color32::YELLOW)
+		} else {
+			(egui::Color32::WHITE, egui::Color32::BLACK, egui::Color32::from_rgb(0, 0, 170))
+		};
+		visuals.override_text_color = Some(foreground);
+		visuals.panel_fill = background;
+		visuals.window_fill = background;
+		visuals.extreme_bg_color = background;
+		visuals.faint_bg_color = background;
+		visuals.window_stroke = egui::Stroke::new(2.0, foreground);
+		visuals.hyperlink_color = accent;
+		visuals.selection.bg_fill = accent;
+		visuals.selection.stroke = egui::Stroke::new(2.0, background);
+		let widgets = &mut visuals.widgets;
+		for widget in [&mut widgets.noninteractive, &mut widgets.inactive, &mut widgets.hovered, &mut widgets.active, &mut widgets.open] {
+			widget.bg_fill = background;
+			widget.weak_bg_fill = background;
+			widget.bg_stroke = egui::Stroke::new(2.0, foreground);
+			widget.fg_stroke = egui::Stroke::new(2.0, foreground);
+		}
+		// Focused widgets are drawn as active, so this is also the keyboard focus ring.
+		widgets.hovered.bg_stroke = egui::Stroke::new(3.0, accent);
+		widgets.active.bg_stroke = egui::Stroke::new(3.0, accent);
+	}
+	visuals
+}
+
+/// Draw a row of color swatches from a palette of RGB triples.
+/// Returns the color that was clicked, if any.
+pub fn palette_swatches(ui: &mut Ui, palette: &[u8], swatch_size: f32) -> Option<[u8; 3]> {
+	let mut clicked = None;
+	ui.horizontal(|ui|{
+		ui.spacing_mut().item_spacing.x = 2.0;
+		for color in palette.chunks_exact(3) {
+			if color_swatch(ui, [color[0], color[1], color[2]], swatch_size).clicked() {
+				clicked = Some([color[0], color[1], color[2]]);
+			}
+		}
+	});
+	clicked
+}
+
+pub fn color_swatch(ui: &mut Ui, color: [u8; 3], swatch_size: f32) -> egui::Response {
+	let (rect, response) = ui.allocate_exact_size(egui::vec2(swatch_size, swatch_size), egui::Sense::click());
+	ui.painter().rect_filled(rect, 2.0, egui::Color32::from_rgb(color[0], color[1], color[2]));
+	if response.has_focus() {
+		ui.painter().rect_stroke(rect, 2.0, ui.visuals().widgets.active.bg_stroke);
+	}
+	let hex = format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2]);
+	response.widget_info(|| egui::WidgetInfo::labeled(egui::WidgetType::Button, format!("Search by color {}", hex)));
+	response.on_hover_text(format!("{} - Click to search by this color", hex))
+}
+
+/// Draw a row of clickable stars for a rating.
+/// Returns the new rating if one was clicked.  Clicking the current rating clears it.
+pub fn rating_stars(ui: &mut Ui, rating: u8) -> Option<u8> {
+	let mut clicked = None;
+	ui.horizontal(|ui|{
+		ui.spacing_mut().item_spacing.x = 0.0;
+		for star in 1..=engine::MAX_RATING {
+			let text = egui::RichText::new(if star <= rating { "★" } else { "☆" }).color(egui::Color32::GOLD);
+			let response = ui.add(egui::Label::new(text).sense(egui::Sense::click()));
+			response.widget_info(|| egui::WidgetInfo::labeled(egui::WidgetType::Button, format!("Rate {} of {} stars, rated {} now", star, engine::MAX_RATING, rating)));
+			if response.has_focus() {
+				ui.painter().rect_stroke(response.rect, 0.0, ui.visuals().widgets.active.bg_stroke);
+			}
+			if response.on_hover_text(format!("Rate {} (rating:{})", star, star)).clicked() {
+				clicked = Some(if star == rating { 0 } else { star });
+			}
+		}
+	});
+	clicked
+}
+
+/// Format a duration for display, like "1h 5m" or "42s".
+pub fn format_duration(duration: std::time::Duration) -> String {
+	let seconds = duration.as_secs();
+	match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+		(0, 0, s) => format!("{}s", s),
+		(0, m, s) => format!("{}m {}s", m, s),
+		(h, m, _) => format!("{}h {}m", h, m),
+	}
+}
+
+/// Format a byte count for display, like "1.5 MB".
+pub fn format_file_size(bytes: u64) -> String {
+	const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+	let mut size = bytes as f64;
+	let mut unit_idx = 0;
+	while size >= 1024.0 && unit_idx < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit_idx += 1;
+	}
+	if unit_idx == 0 {
+		format!("{} {}", bytes, UNITS[0])
+	} else {
+		format!("{:.1} {}", size, UNITS[unit_idx])
+	}
+}
+
+/// Pages are zero-indexed but displayed starting from one.  max_page is the last valid page index.
 pub fn paginate(ui: &mut Ui, current_page: &mut u64, max_page: u64) {
 	ui.horizontal(|ui|{
 		if ui.button("<<").clicked() {
 			*current_page = 0;
 		}
 		if ui.button("<").clicked() {
-			if *current_page > 1 {
+			if *current_page > 0 {
 				*current_page -= 1;
 			}
 		}
-		ui.label(format!("Page {} of {}", *current_page, max_page));
+		ui.label(format!("Page {} of {}", *current_page + 1, max_page + 1));
 		if ui.button(">").clicked() {
 			if *current_page < max_page {
 				*current_page += 1;
@@ -150,4 +300,71 @@ impl Painting {
 
 		response
 	}
-}
\ No newline at end of file
+}
+
+/// Run the actions whose shortcuts were pressed this frame.
+pub fn handle_shortcuts(app_state: &mut MainApp, ctx: &egui::Context) {
+	if app_state.keymap_capture.is_some() {
+		if app_state.active_tab == AppTab::Settings {
+			return; // Settings is waiting for a key to bind, so don't run anything.
+		}
+		app_state.keymap_capture = None;
+	}
+	let keymap = app_state.keymap.clone();
+
+	if keymap.pressed(ctx, Action::CommandPalette) {
+		app_state.command_palette_open = !app_state.command_palette_open;
+		app_state.command_palette_text.clear();
+		app_state.command_palette_selected = 0;
+	}
+	if app_state.command_palette_open || app_state.engine.is_none() {
+		return;
+	}
+
+	for (action, tab) in [(Action::ShowSearch, AppTab::Search), (Action::ShowView, AppTab::View), (Action::ShowColors, AppTab::Colors), (Action::ShowFolders, AppTab::Folders)] {
+		if keymap.pressed(ctx, action) {
+			app_state.active_tab = tab;
+		}
+	}
+	if keymap.pressed(ctx, Action::FocusSearch) {
+		app_state.active_tab = AppTab::Search;
+		ctx.memory_mut(|m| m.request_focus(search::search_box_id()));
+	}
+	if keymap.pressed(ctx, Action::Reindex) {
+		if let Err(e) = app_state.engine.as_mut().unwrap().start_reindexing() {
+			eprintln!("Failed to start indexing: {}", e);
+		}
+		app_state.active_tab = AppTab::Folders;
+	}
+	if app_state.active_tab == AppTab::View && app_state.selected_image.is_some() {
+		rate_selected_image(app_state, ctx, &keymap);
+	}
+	if app_state.active_tab == AppTab::Search {
+		if keymap.pressed(ctx, Action::NextPage) {
+			search::step_page(app_state, true);
+		}
+		if keymap.pressed(ctx, Action::PreviousPage) {
+			search::step_page(app_state, false);
+		}
+	}
+}
+
+/// Rating and favorite shortcuts for the image open in the View tab.
+fn rate_selected_image(app_state: &mut MainApp, ctx: &egui::Context, keymap: &Keymap) {
+	let engine = app_state.engine.as_mut().unwrap();
+	let selected_image = app_state.selected_image.as_mut().unwrap();
+	for (rating, action) in Action::RATINGS.into_iter().enumerate() {
+		if keymap.pressed(ctx, action) {
+			match engine.set_rating(selected_image.id, rating as u8) {
+				Ok(_) => selected_image.rating = rating as u8,
+				Err(e) => eprintln!("Failed to set rating: {}", e),
+			}
+		}
+	}
+	if keymap.pressed(ctx, Action::ToggleFavorite) {
+		match engine.set_favorite(selected_image.id, !selected_image.favorite) {
+			Ok(_) => selected_image.favorite = !selected_image.favorite,
+			Err(e) => eprintln!("Failed to set favorite: {}", e),
+		}
+	}
+}
@@ -0,0 +1,127 @@
+use crate::MainApp;
+use crate::ui::{format_file_size, show_thumbnail};
+use eframe::egui;
+
+pub fn review_panel(
+	app_state: &mut MainApp,
+	ui: &mut egui::Ui
+) {
+	let engine = match app_state.engine.as_mut() {
+		Some(engine) => engine,
+		None => {
+			ui.label("To review near-duplicates, make sure a DB is loaded.");
+			return;
+		}
+	};
+
+	let held = match engine.get_near_duplicates() {
+		Ok(held) => held,
+		Err(e) => {
+			ui.label(format!("Failed to load images held for review: {}", e));
+			return;
+		}
+	};
+
+	let mut resolution: Option<(String, bool)> = None;
+
+	ui.heading("Near-Duplicate Review");
+	if !engine.warn_on_near_duplicates {
+		ui.label("Holding near-duplicates for review is turned off.  It can be enabled in Settings.");
+	}
+	ui.label(format!("{} image(s) were found while indexing that look like images already in the index.  Kept images are added on the next reindex.", held.len()));
+
+	egui::ScrollArea::vertical()
+		.auto_shrink([false, true])
+		.max_height(ui.available_height() * 0.5)
+		.show(ui, |ui| {
+			for dupe in &held {
+				ui.horizontal(|ui|{
+					show_thumbnail(ui, &dupe.existing, &app_state.revealed_images, &mut app_state.image_id_to_texture_handle);
+					ui.vertical(|ui|{
+						ui.label(format!("New: {}", dupe.path));
+						ui.label(format!("Looks like: {}", dupe.existing.path));
+						ui.label(format!("Distance: {:.3}", dupe.distance));
+						ui.horizontal(|ui|{
+							if ui.button("Keep").on_hover_text("Add this image to the index on the next reindex.").clicked() {
+								resolution = Some((dupe.path.clone(), true));
+							}
+							if ui.button("Skip").on_hover_text("Never add this image to the index.  The file on disk is not touched.").clicked() {
+								resolution = Some((dupe.path.clone(), false));
+							}
+						});
+					});
+				});
+			}
+		});
+
+	if let Some((path, keep)) = resolution {
+		if let Err(e) = app_state.engine.as_mut().unwrap().resolve_near_duplicate(&path, keep) {
+			eprintln!("Failed to resolve near-duplicate {}: {}", path, e);
+		}
+	}
+
+	ui.separator();
+	duplicate_scan(app_state, ui);
+}
+
+/// Groups of near-duplicates already in the index, from Engine::start_duplicate_scan.
+fn duplicate_scan(app_state: &mut MainApp, ui: &mut egui::Ui) {
+	let engine = match app_state.engine.as_mut() {
+		Some(engine) => engine,
+		None => return,
+	};
+	match engine.poll_duplicate_scan() {
+		Some(Ok(groups)) => app_state.duplicate_groups = Some(groups),
+		Some(Err(e)) => eprintln!("Failed to scan for duplicates: {}", e),
+		None => {},
+	}
+
+	ui.heading("Duplicate Scan");
+	ui.horizontal(|ui|{
+		let scanning = engine.is_scanning_for_duplicates();
+		if ui.add_enabled(!scanning, egui::Button::new("Scan for Duplicates")).on_hover_text("Look through the whole index for images that look almost exactly alike, by the Near-Duplicate Distance in Settings.  Takes a while on large libraries.").clicked() {
+			if let Err(e) = engine.start_duplicate_scan() {
+				eprintln!("Failed to start a duplicate scan: {}", e);
+			}
+		}
+		if scanning {
+			ui.spinner();
+		}
+	});
+
+	let groups = match &app_state.duplicate_groups {
+		Some(groups) => groups,
+		None => return,
+	};
+	let mut to_hide: Option<(i64, Vec<i64>)> = None;
+	ui.label(format!("{} group(s) of near-duplicates.  The largest file of each is first.", groups.len()));
+	egui::ScrollArea::vertical()
+		.id_source("duplicate_groups")
+		.auto_shrink([false, false])
+		.show(ui, |ui| {
+			for group in groups {
+				ui.horizontal(|ui|{
+					for img in &group.images {
+						show_thumbnail(ui, img, &app_state.revealed_images, &mut app_state.image_id_to_texture_handle).on_hover_text(&img.path);
+					}
+					ui.vertical(|ui|{
+						ui.label(format!("{} images.  Deleting the rest would free {}.", group.images.len(), format_file_size(group.reclaimable_bytes())));
+						if ui.button("Hide All but the First").on_hover_text("Mark the rest as duplicates of the first, so only it shows up in searches.  Nothing is deleted.").clicked() {
+							to_hide = Some((group.images[0].id, group.images[1..].iter().map(|img| img.id).collect()));
+						}
+					});
+				});
+			}
+		});
+
+	if let Some((canonical_id, duplicate_ids)) = to_hide {
+		match engine.set_canonical_image(canonical_id, &duplicate_ids) {
+			Ok(()) => {
+				if let Some(groups) = &mut app_state.duplicate_groups {
+					groups.retain(|group| group.images[0].id != canonical_id);
+				}
+			},
+			Err(e) => eprintln!("Failed to mark duplicates of {}: {}", canonical_id, e),
+		}
+	}
+}
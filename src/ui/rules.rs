@@ -0,0 +1,141 @@
+use crate::MainApp;
+use crate::engine::{Rule, TypeFilter};
+use eframe::egui;
+
+pub fn rules_panel(
+	app_state: &mut MainApp,
+	ui: &mut egui::Ui
+) {
+	let engine = match app_state.engine.as_mut() {
+		Some(engine) => engine,
+		None => {
+			ui.label("To set up rules, make sure a DB is loaded.");
+			return;
+		}
+	};
+
+	let rules = match engine.get_rules() {
+		Ok(rules) => rules,
+		Err(e) => {
+			ui.label(format!("Failed to load rules: {}", e));
+			return;
+		}
+	};
+
+	let mut to_save: Option<Rule> = None;
+	let mut to_delete: Option<i64> = None;
+	let mut saving_draft = false;
+
+	ui.heading("Rules");
+	ui.label("Rules run on images as they're added to the index.  Every condition that's filled in must match.");
+
+	egui::ScrollArea::vertical()
+		.auto_shrink([false, true])
+		.max_height(ui.available_height() * 0.5)
+		.show(ui, |ui| {
+			for rule in &rules {
+				ui.horizontal(|ui|{
+					let mut enabled = rule.enabled;
+					if ui.checkbox(&mut enabled, &rule.name).changed() {
+						to_save = Some(Rule { enabled, ..rule.clone() });
+					}
+					ui.label(describe_rule(rule));
+					if ui.button("Edit").clicked() {
+						app_state.rule_draft = rule.clone();
+					}
+					if ui.button("x").clicked() {
+						to_delete = Some(rule.id);
+					}
+				});
+			}
+		});
+
+	ui.separator();
+	let draft = &mut app_state.rule_draft;
+	ui.heading(if draft.id == 0 { "New Rule" } else { "Edit Rule" });
+	egui::Grid::new("rule_draft").num_columns(2).show(ui, |ui|{
+		ui.label("Name");
+		ui.text_edit_singleline(&mut draft.name);
+		ui.end_row();
+
+		ui.label("Folder Contains");
+		ui.text_edit_singleline(&mut draft.folder_contains);
+		ui.end_row();
+
+		ui.label("File Type");
+		egui::ComboBox::from_id_source("rule_file_type")
+			.selected_text(draft.file_type.map(|t| t.label()).unwrap_or("Any"))
+			.show_ui(ui, |ui| {
+				ui.selectable_value(&mut draft.file_type, None, "Any");
+				for file_type in TypeFilter::ALL {
+					ui.selectable_value(&mut draft.file_type, Some(file_type), file_type.label());
+				}
+			});
+		ui.end_row();
+
+		ui.label("EXIF Field");
+		ui.horizontal(|ui|{
+			ui.add(egui::TextEdit::singleline(&mut draft.exif_name).hint_text("Model"));
+			ui.label("contains");
+			ui.add(egui::TextEdit::singleline(&mut draft.exif_value).hint_text("Canon"));
+		});
+		ui.end_row();
+
+		ui.label("Then Add Tag");
+		ui.horizontal(|ui|{
+			ui.add(egui::TextEdit::singleline(&mut draft.tag_name).hint_text("name"));
+			ui.label("=");
+			ui.add(egui::TextEdit::singleline(&mut draft.tag_value).hint_text("value"));
+		});
+		ui.end_row();
+
+		ui.label("And Add to Collection");
+		ui.add(egui::TextEdit::singleline(&mut draft.collection_name).hint_text("Made if it doesn't exist"));
+		ui.end_row();
+	});
+	ui.horizontal(|ui|{
+		if ui.button("Save Rule").clicked() {
+			to_save = Some(Rule { enabled: true, ..draft.clone() });
+			saving_draft = true;
+		}
+		if ui.button("Reset").clicked() {
+			*draft = Rule::default();
+		}
+	});
+
+	let engine = app_state.engine.as_mut().unwrap();
+	if let Some(rule) = to_save {
+		match engine.save_rule(&rule) {
+			Ok(_) if saving_draft => app_state.rule_draft = Rule::default(),
+			Ok(_) => {},
+			Err(e) => eprintln!("Failed to save rule '{}': {}", rule.name, e),
+		}
+	}
+	if let Some(rule_id) = to_delete {
+		if let Err(e) = engine.delete_rule(rule_id) {
+			eprintln!("Failed to delete rule {}: {}", rule_id, e);
+		}
+	}
+}
+
+fn describe_rule(rule: &Rule) -> String {
+	let mut conditions = vec![];
+	if !rule.folder_contains.is_empty() {
+		conditions.push(format!("path contains '{}'", rule.folder_contains));
+	}
+	if let Some(file_type) = rule.file_type {
+		conditions.push(format!("is {}", file_type.label()));
+	}
+	if !rule.exif_name.is_empty() || !rule.exif_value.is_empty() {
+		conditions.push(format!("{} contains '{}'", rule.exif_name, rule.exif_value));
+	}
+	let conditions = if conditions.is_empty() { "any image".to_string() } else { conditions.join(" and ") };
+	let mut actions = vec![];
+	if !rule.tag_name.is_empty() {
+		actions.push(format!("tag {}={}", rule.tag_name, rule.tag_value));
+	}
+	if !rule.collection_name.is_empty() {
+		actions.push(format!("add to '{}'", rule.collection_name));
+	}
+	format!("If {} then {}", conditions, actions.join(" and "))
+}
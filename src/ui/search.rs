@@ -1,6 +1,8 @@
 use crate::{AppTab, MainApp};
 //use crate::engine::Engine;
-use crate::ui::{fetch_or_generate_thumbnail, paginate};
+use crate::engine::{EmbeddingPooling, ExportFormat, TypeFilter, SENSITIVE_TAG};
+use crate::indexed_image::{filepath_from_string, IndexedImage};
+use crate::ui::{format_file_size, paginate, palette_swatches, rating_stars, show_thumbnail};
 use eframe::{egui, NativeOptions};
 use eframe::egui::{Context, DroppedFile, TextureHandle, Ui};
 use rfd;
@@ -8,6 +10,8 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::time::Duration;
 
+const MAX_RECENT_QUERIES: usize = 15;
+
 pub fn search_panel(
 	app_state: &mut MainApp,
 	ui: &mut egui::Ui
@@ -17,31 +21,112 @@ pub fn search_panel(
 		return;
 	}
 
+	// Queries run in the background.  Keep repainting until they land so the results show up.
+	let engine = app_state.engine.as_mut().unwrap();
+	let query_running = engine.is_query_running();
+	if let Some(e) = engine.take_query_error() {
+		app_state.query_error = e;
+	}
+	if query_running {
+		ui.ctx().request_repaint_after(Duration::from_millis(50));
+	}
+
 	ui.horizontal(|ui|{
 		// Search by image _buttons_.
 		if ui.button("Search by Image").clicked() {
 			if let Some(file_path) = rfd::FileDialog::new().pick_file() {
-				app_state.engine.as_mut().unwrap().query_by_image_hash_from_file(Path::new(&file_path))
+				if let Err(e) = app_state.engine.as_mut().unwrap().query_by_image_hash_from_file(Path::new(&file_path)) {
+					app_state.query_error = e.to_string();
+				}
 				//app_state.engine.as_mut().unwrap().query(&format!("similar:{}", file_path.to_str().unwrap()));
 			}
 		}
 
 		// Search by image drag+drop support.
 		if let Some(images) = detect_files_being_dropped(ui.ctx()) {
-			app_state.engine.as_mut().unwrap().query_by_image_hash_from_file(images.first().unwrap().path.as_ref().unwrap())
+			if let Err(e) = app_state.engine.as_mut().unwrap().query_by_image_hash_from_file(images.first().unwrap().path.as_ref().unwrap()) {
+				app_state.query_error = e.to_string();
+			}
 			//app_state.engine.as_mut().unwrap().query(&format!("similar:{}", images.first().unwrap().path.unwrap().to_str().unwrap()));
 		}
 		
 		// Universal Search
-		if ui.text_edit_singleline(&mut app_state.search_text).changed() && app_state.search_text.len() > app_state.search_text_min_length as usize {
-			let query_success = app_state.engine.as_mut().unwrap().query(&app_state.search_text.clone());
-			if let Err(q) = query_success {
-				app_state.query_error = q.to_string();
-			} else {
-				app_state.query_error = "".to_string();
-			}
+		if ui.add(egui::TextEdit::singleline(&mut app_state.search_text).id(search_box_id())).changed() && app_state.search_text.len() > app_state.search_text_min_length as usize {
+			app_state.current_page = 0;
+			run_search_page(app_state);
 			//app_state.engine.as_mut().unwrap().query_by_image_name(&app_state.search_text.clone())
 		}
+
+		// Only read the history when the menu is open.
+		let mut recent_query: Option<String> = None;
+		ui.menu_button("Recent", |ui|{
+			match app_state.engine.as_ref().unwrap().recent_queries(MAX_RECENT_QUERIES) {
+				Ok(recent) if recent.is_empty() => { ui.weak("No searches yet."); },
+				Ok(recent) => {
+					for q in recent {
+						if ui.button(&q.query).on_hover_text(format!("Last run {} UTC.  Run {} time(s).", q.last_run, q.run_count)).clicked() {
+							recent_query = Some(q.query);
+							ui.close_menu();
+						}
+					}
+					ui.separator();
+					if ui.button("Clear History").clicked() {
+						if let Err(e) = app_state.engine.as_mut().unwrap().clear_search_history() {
+							app_state.query_error = e.to_string();
+						}
+						ui.close_menu();
+					}
+				},
+				Err(e) => { ui.label(format!("Failed to load search history: {}", e)); },
+			}
+		});
+		if let Some(query) = recent_query {
+			app_state.search_text = query;
+			app_state.current_page = 0;
+			run_search_page(app_state);
+		}
+
+		if query_running {
+			ui.spinner();
+			if ui.small_button("Cancel").on_hover_text("Stop the search.  The last results stay up.").clicked() {
+				app_state.engine.as_mut().unwrap().cancel_query();
+			}
+		}
+	});
+
+	// Paging only applies to text searches.  Searches by image report no total.
+	if let Some(total_results) = app_state.engine.as_ref().unwrap().get_query_result_count() {
+		let per_page = app_state.results_per_page.max(1);
+		let max_page = total_results.saturating_sub(1) / per_page;
+		let mut page = app_state.current_page;
+		ui.horizontal(|ui|{
+			paginate(ui, &mut page, max_page);
+			ui.label(format!("{} results", total_results));
+		});
+		if page != app_state.current_page {
+			app_state.current_page = page;
+			run_search_page(app_state);
+		}
+	}
+
+	saved_searches(app_state, ui);
+	collections(app_state, ui);
+
+	// Quick filters toggle a type: token in the search text.
+	ui.horizontal(|ui|{
+		for type_filter in TypeFilter::ALL {
+			let token = format!("type:{}", type_filter.name());
+			let active = app_state.search_text.split_whitespace().any(|t| t.eq_ignore_ascii_case(&token));
+			if ui.selectable_label(active, type_filter.label()).clicked() {
+				if active {
+					app_state.search_text = remove_query_token(&app_state.search_text, &token);
+				} else {
+					app_state.search_text = format!("{} {}", app_state.search_text.trim_end(), token).trim_start().to_string();
+				}
+				app_state.current_page = 0;
+				run_search_page(app_state);
+			}
+		}
 	});
 
 	// Show parsing errors in query.
@@ -50,7 +135,32 @@ pub fn search_panel(
 	}
 
 	if let Some(results) = app_state.engine.as_ref().unwrap().get_query_results() {
-		ui.heading("Results");
+		ui.horizontal(|ui|{
+			ui.heading("Results");
+			if ui.add_enabled(!results.is_empty(), egui::Button::new("Export...")).on_hover_text("Save these results as CSV or JSON.").clicked() {
+				export_results(app_state);
+			}
+			if !app_state.more_like_these.is_empty() {
+				ui.separator();
+				egui::ComboBox::from_id_source("more_like_these_pooling")
+					.selected_text(app_state.more_like_these_pooling.name())
+					.show_ui(ui, |ui| {
+						for pooling in EmbeddingPooling::ALL {
+							ui.selectable_value(&mut app_state.more_like_these_pooling, pooling, pooling.name());
+						}
+					}).response.on_hover_text("Mean finds what the examples have in common.  Max finds anything with a strong trait from any of them.");
+				let examples = app_state.more_like_these.iter().map(|img| img.filename.as_str()).collect::<Vec<&str>>().join("\n");
+				if ui.button(format!("More Like These ({})", app_state.more_like_these.len())).on_hover_text(examples).clicked() {
+					let engine = app_state.engine.as_mut().unwrap();
+					if let Err(e) = engine.query_by_multiple_images(&app_state.more_like_these, app_state.more_like_these_pooling) {
+						app_state.query_error = e.to_string();
+					}
+				}
+				if ui.small_button("x").on_hover_text("Clear the examples.").clicked() {
+					app_state.more_like_these.clear();
+				}
+			}
+		});
 		//ui.add(egui::Image::new(my_texture_id, [640.0, 480.0]));
 
 		egui::ScrollArea::vertical()
@@ -59,33 +169,40 @@ pub fn search_panel(
 				ui.vertical(|ui|{
 					results.iter().for_each(|res|{
 						ui.horizontal(|ui|{
-							let tex_id = fetch_or_generate_thumbnail(res, &mut app_state.image_id_to_texture_handle, ui.ctx());
-							
 							// Note: thumbnail size != image size.  We might want to show them off as larger or smaller.
-							ui.image(&tex_id).context_menu(|ui|{
-								if ui.button("Open").clicked() {
-									//let _ = std::process::Command::new("open").arg(&res.path).output();
-									open::that(&res.path);
-									ui.close_menu();
-								}
-								if ui.button("Open in View Tab").clicked() {
-									//let _ = std::process::Command::new("open").arg(&res.path).output();
-									app_state.selected_image = Some(res.clone());
-									app_state.active_tab = AppTab::View;
-									ui.close_menu();
-								}
-								if ui.button("Search for Similar").clicked() {
-									app_state.engine.as_mut().unwrap().query_by_image_hash_from_image(res);
-									ui.close_menu();
-								}
+							show_thumbnail(ui, res, &app_state.revealed_images, &mut app_state.image_id_to_texture_handle).context_menu(|ui|{
+								result_actions(app_state, ui, res);
 							});
 
 							ui.vertical(|ui|{
+								// The same actions, for the keyboard and screen readers.
+								ui.menu_button("Actions", |ui|{
+									result_actions(app_state, ui, res);
+								}).response.on_hover_text("Everything in the right click menu.");
+								if res.protected {
+									ui.label("🔒 Protected").on_hover_text("This image is excluded from bulk operations.");
+								}
+								if res.favorite {
+									ui.label("♥ Favorite");
+								}
+								if let Some(rating) = rating_stars(ui, res.rating) {
+									if let Err(e) = app_state.engine.as_mut().unwrap().set_rating(res.id, rating) {
+										app_state.query_error = e.to_string();
+									}
+								}
 								ui.label(format!("Filename: {}", res.filename));
 								ui.label(format!("Path: {}", res.path));
 								ui.label(format!("Similarity: {}", 1.0f64 / (1.0f64+res.distance_from_query.unwrap_or(1e10f64))));
 								ui.label(format!("Distance: {}", res.distance_from_query.unwrap_or(1e3f64)));
 								ui.label(format!("Size: {}x{}", res.resolution.0, res.resolution.1));
+								ui.label(format!("File Size: {}", format_file_size(res.file_size)));
+								if let Some(palette) = &res.palette {
+									if let Some(color) = palette_swatches(ui, palette, 12.0) {
+										if let Err(e) = app_state.engine.as_mut().unwrap().query_by_color(color) {
+											app_state.query_error = e.to_string();
+										}
+									}
+								}
 							});
 						});
 					});
@@ -94,6 +211,168 @@ pub fn search_panel(
 	}
 }
 
+/// What can be done with a search result.  Shown in its right click menu and its Actions menu.
+fn result_actions(app_state: &mut MainApp, ui: &mut egui::Ui, res: &IndexedImage) {
+	if ui.button("Open").clicked() {
+		//let _ = std::process::Command::new("open").arg(&res.path).output();
+		open::that(filepath_from_string(&res.path));
+		ui.close_menu();
+	}
+	if ui.button("Open in View Tab").clicked() {
+		//let _ = std::process::Command::new("open").arg(&res.path).output();
+		app_state.selected_image = Some(res.clone());
+		app_state.active_tab = AppTab::View;
+		ui.close_menu();
+	}
+	if ui.button("Search for Similar").clicked() {
+		if let Err(e) = app_state.engine.as_mut().unwrap().query_by_image_hash_from_image(res) {
+			app_state.query_error = e.to_string();
+		}
+		ui.close_menu();
+	}
+	let is_example = app_state.more_like_these.iter().any(|img| img.id == res.id);
+	if ui.button(if is_example { "Remove from More Like These" } else { "Add to More Like These" }).on_hover_text("Search with several examples at once.").clicked() {
+		if is_example {
+			app_state.more_like_these.retain(|img| img.id != res.id);
+		} else {
+			app_state.more_like_these.push(res.clone());
+		}
+		ui.close_menu();
+	}
+	ui.menu_button("Collections", |ui|{
+		collection_toggles(app_state, ui, res.id);
+	});
+	if ui.button(if res.favorite { "Unfavorite" } else { "Favorite" }).clicked() {
+		if let Err(e) = app_state.engine.as_mut().unwrap().set_favorite(res.id, !res.favorite) {
+			app_state.query_error = e.to_string();
+		}
+		ui.close_menu();
+	}
+	if ui.button(if res.protected { "Unprotect" } else { "Protect" }).clicked() {
+		if let Err(e) = app_state.engine.as_mut().unwrap().set_protected(res.id, !res.protected) {
+			app_state.query_error = e.to_string();
+		}
+		ui.close_menu();
+	}
+	if res.blurred_thumbnail.is_none() {
+		if ui.button("Mark Sensitive").on_hover_text(format!("Tag the image '{}' so it's blurred until revealed.", SENSITIVE_TAG)).clicked() {
+			if let Err(e) = app_state.engine.as_mut().unwrap().set_user_tag(res.id, SENSITIVE_TAG, "") {
+				app_state.query_error = e.to_string();
+			}
+			ui.close_menu();
+		}
+	} else if app_state.revealed_images.contains(&res.id) {
+		if ui.button("Blur").clicked() {
+			app_state.revealed_images.remove(&res.id);
+			ui.close_menu();
+		}
+	} else if ui.button("Reveal").on_hover_text("Show the image unblurred until PixelBox is closed.").clicked() {
+		app_state.revealed_images.insert(res.id);
+		ui.close_menu();
+	}
+}
+
+fn export_results(app_state: &mut MainApp) {
+	let Some(file_path) = rfd::FileDialog::new().add_filter("CSV", &["csv"]).add_filter("JSON", &["json"]).set_file_name("results.csv").save_file() else {
+		return;
+	};
+	let Some(format) = ExportFormat::from_path(&file_path) else {
+		app_state.query_error = "Results can only be exported as .csv or .json.".to_string();
+		return;
+	};
+	if let Err(e) = app_state.engine.as_ref().unwrap().export_results(format, &file_path) {
+		app_state.query_error = format!("Failed to export results: {}", e);
+	}
+}
+
+fn saved_searches(app_state: &mut MainApp, ui: &mut egui::Ui) {
+	let saved = match app_state.engine.as_mut().unwrap().get_saved_searches() {
+		Ok(saved) => saved,
+		Err(e) => {
+			ui.label(format!("Failed to load saved searches: {}", e));
+			return;
+		}
+	};
+
+	let mut to_run: Option<String> = None;
+	let mut to_save = false;
+	let mut to_rename: Option<String> = None;
+	let mut to_delete: Option<String> = None;
+
+	ui.collapsing(format!("Saved Searches ({})", saved.len()), |ui|{
+		ui.horizontal(|ui|{
+			ui.add(egui::TextEdit::singleline(&mut app_state.saved_search_name).hint_text("Name"));
+			if ui.add_enabled(!app_state.search_text.is_empty(), egui::Button::new("Save Current Search")).clicked() {
+				to_save = true;
+			}
+		});
+		for search in &saved {
+			ui.horizontal(|ui|{
+				if ui.button(&search.name).on_hover_text("Run this search.").clicked() {
+					to_run = Some(search.query.clone());
+				}
+				ui.weak(&search.query);
+				if ui.small_button("Rename").on_hover_text("Rename to the name in the box above.").clicked() {
+					to_rename = Some(search.name.clone());
+				}
+				if ui.small_button("x").clicked() {
+					to_delete = Some(search.name.clone());
+				}
+			});
+		}
+	});
+
+	let engine = app_state.engine.as_mut().unwrap();
+	let result = if to_save {
+		engine.save_search(&app_state.saved_search_name, &app_state.search_text)
+	} else if let Some(old_name) = to_rename {
+		engine.rename_saved_search(&old_name, &app_state.saved_search_name)
+	} else if let Some(name) = to_delete {
+		engine.delete_saved_search(&name)
+	} else {
+		Ok(())
+	};
+	match result {
+		Ok(_) if to_save => app_state.saved_search_name.clear(),
+		Ok(_) => {},
+		Err(e) => app_state.query_error = e.to_string(),
+	}
+
+	if let Some(query) = to_run {
+		app_state.search_text = query;
+		app_state.current_page = 0;
+		run_search_page(app_state);
+	}
+}
+
+pub fn search_box_id() -> egui::Id {
+	egui::Id::new("search_text")
+}
+
+/// Move one page forward or back through the results of the last text search, if there's a page there.
+pub fn step_page(app_state: &mut MainApp, forward: bool) {
+	let total_results = match app_state.engine.as_ref().and_then(|e| e.get_query_result_count()) {
+		Some(total) => total,
+		None => return,
+	};
+	let max_page = total_results.saturating_sub(1) / app_state.results_per_page.max(1);
+	let page = if forward { (app_state.current_page + 1).min(max_page) } else { app_state.current_page.saturating_sub(1) };
+	if page != app_state.current_page {
+		app_state.current_page = page;
+		run_search_page(app_state);
+	}
+}
+
+pub fn run_search_page(app_state: &mut MainApp) {
+	let search_text = app_state.search_text.clone();
+	let query_success = app_state.engine.as_mut().unwrap().start_query_page(&search_text, app_state.current_page, app_state.results_per_page.max(1));
+	if let Err(q) = query_success {
+		app_state.query_error = q.to_string();
+	} else {
+		app_state.query_error = "".to_string();
+	}
+}
+
 // Flagrantly stolen from the drag-and-drop documentation:
 // https://github.com/emilk/egui/blob/master/eframe/examples/file_dialog.rs#L67
 fn detect_files_being_dropped(ctx: &egui::Context) -> Option<Vec<DroppedFile>> {
@@ -139,4 +418,206 @@ fn detect_files_being_dropped(ctx: &egui::Context) -> Option<Vec<DroppedFile>> {
 	}
 	
 	None
-}
\ No newline at end of file
+}
+
+/// Take a chip's token out of the search text, leaving the rest of it (like spacing inside quotes) as it was.
+fn remove_query_token(text: &str, token: &str) -> String {
+	let mut result = text.to_string();
+	loop {
+		let span = result.split_whitespace()
+			.find(|t| t.eq_ignore_ascii_case(token))
+			.map(|t| t.as_ptr() as usize - result.as_ptr() as usize)
+			.map(|start| (start, start + token.len()));
+		let Some((start, end)) = span else {
+			return result;
+		};
+		// Take the spaces after it too, or the ones before it if it's last.
+		let after = result[end..].len() - result[end..].trim_start().len();
+		let before = if after == 0 { start - result[..start].trim_end().len() } else { 0 };
+		result.replace_range(start - before..end + after, "");
+	}
+}
+
+/// A query that finds everything in the collection.
+fn collection_query(name: &str) -> String {
+	format!("collection:\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn collections(app_state: &mut MainApp, ui: &mut egui::Ui) {
+	let collections = match app_state.engine.as_mut().unwrap().get_collections() {
+		Ok(collections) => collections,
+		Err(e) => {
+			ui.label(format!("Failed to load collections: {}", e));
+			return;
+		}
+	};
+
+	let mut to_show: Option<String> = None;
+	let mut to_create = false;
+	let mut to_create_smart = false;
+	let mut to_create_hidden = false;
+	let mut to_unlock = false;
+	let mut to_lock = false;
+	let mut to_hide: Option<i64> = None;
+	let mut to_unhide: Option<i64> = None;
+	let mut to_rename: Option<i64> = None;
+	let mut to_delete: Option<i64> = None;
+	let mut to_share: Option<i64> = None;
+
+	ui.collapsing(format!("Collections ({})", collections.len()), |ui|{
+		ui.horizontal(|ui|{
+			ui.add(egui::TextEdit::singleline(&mut app_state.collection_name).hint_text("Name"));
+			if ui.add_enabled(!app_state.collection_name.trim().is_empty(), egui::Button::new("New Collection")).clicked() {
+				to_create = true;
+			}
+			let can_create_smart = !app_state.collection_name.trim().is_empty() && !app_state.search_text.is_empty();
+			if ui.add_enabled(can_create_smart, egui::Button::new("New Smart Collection")).on_hover_text("Always shows whatever matches the current search.").clicked() {
+				to_create_smart = true;
+			}
+			ui.checkbox(&mut app_state.share_include_originals, "Share originals").on_hover_text("Put the full-size images in shared collections, not just thumbnails.  Shared files will be much larger.");
+		});
+		ui.horizontal(|ui|{
+			ui.add(egui::TextEdit::singleline(&mut app_state.collection_passphrase).password(true).hint_text("Passphrase"));
+			let has_passphrase = !app_state.collection_passphrase.is_empty();
+			let can_create_hidden = has_passphrase && !app_state.collection_name.trim().is_empty();
+			if ui.add_enabled(can_create_hidden, egui::Button::new("New Hidden Collection")).on_hover_text("Its images are left out of everything until it's unlocked with the passphrase.").clicked() {
+				to_create_hidden = true;
+			}
+			if ui.add_enabled(has_passphrase, egui::Button::new("Unlock")).on_hover_text("Show the hidden collections with this passphrase until they're locked or the DB is closed.").clicked() {
+				to_unlock = true;
+			}
+			if ui.add_enabled(app_state.engine.as_ref().unwrap().has_unlocked_collections(), egui::Button::new("Lock")).on_hover_text("Hide every hidden collection again.").clicked() {
+				to_lock = true;
+			}
+		});
+		if collections.is_empty() {
+			ui.weak("Right click a result to add it to a collection.");
+		}
+		for collection in &collections {
+			ui.horizontal(|ui|{
+				if ui.button(&collection.name).on_hover_text("Show the images in this collection.").clicked() {
+					to_show = Some(collection.name.clone());
+				}
+				match (&collection.query, collection.size) {
+					(Some(query), _) => { ui.weak(format!("Smart: {}", query)); },
+					(None, Some(size)) => { ui.weak(format!("{} images", size)); },
+					(None, None) => {},
+				}
+				if collection.hidden {
+					ui.weak("🔒 Hidden");
+					if ui.small_button("Unhide").on_hover_text("Stop hiding this collection.").clicked() {
+						to_unhide = Some(collection.id);
+					}
+				} else if !collection.is_smart() && ui.add_enabled(!app_state.collection_passphrase.is_empty(), egui::Button::new("Hide").small()).on_hover_text("Hide this collection behind the passphrase in the box above.").clicked() {
+					to_hide = Some(collection.id);
+				}
+				if ui.small_button("Rename").on_hover_text("Rename to the name in the box above.").clicked() {
+					to_rename = Some(collection.id);
+				}
+				if ui.small_button("Share").on_hover_text("Save this collection as a read-only DB someone else can open or import.").clicked() {
+					to_share = Some(collection.id);
+				}
+				if ui.small_button("x").on_hover_text("Delete this collection.  The images in it are kept.").clicked() {
+					to_delete = Some(collection.id);
+				}
+			});
+		}
+	});
+
+	let engine = app_state.engine.as_mut().unwrap();
+	let result = if to_create {
+		engine.create_collection(&app_state.collection_name).map(|_| ())
+	} else if to_create_smart {
+		engine.create_smart_collection(&app_state.collection_name, &app_state.search_text).map(|_| ())
+	} else if to_create_hidden {
+		engine.create_hidden_collection(&app_state.collection_name, &app_state.collection_passphrase).map(|_| ())
+	} else if to_unlock {
+		engine.unlock_hidden_collections(&app_state.collection_passphrase).map(|_| ())
+	} else if to_lock {
+		engine.lock_hidden_collections();
+		// Whatever was open might have been from a hidden collection.
+		app_state.selected_image = None;
+		app_state.full_image = None;
+		app_state.full_image_path.clear();
+		Ok(())
+	} else if let Some(id) = to_hide {
+		engine.hide_collection(id, &app_state.collection_passphrase)
+	} else if let Some(id) = to_unhide {
+		engine.unhide_collection(id)
+	} else if let Some(id) = to_rename {
+		engine.rename_collection(id, &app_state.collection_name)
+	} else if let Some(id) = to_delete {
+		engine.delete_collection(id)
+	} else if let Some(id) = to_share {
+		match rfd::FileDialog::new().add_filter("SQLite DB", &["db", "sqlite"]).set_file_name("shared_collection.db").save_file() {
+			Some(file_path) => {
+				// The save dialog already asked before replacing the file.
+				let _ = std::fs::remove_file(&file_path);
+				engine.share_collection(id, &file_path, app_state.share_include_originals).map(|_| ())
+			},
+			None => Ok(()),
+		}
+	} else {
+		Ok(())
+	};
+	match result {
+		Ok(_) if to_create || to_create_smart || to_rename.is_some() => app_state.collection_name.clear(),
+		Ok(_) if to_create_hidden => {
+			app_state.collection_name.clear();
+			app_state.collection_passphrase.clear();
+		},
+		Ok(_) if to_unlock || to_hide.is_some() => app_state.collection_passphrase.clear(),
+		Ok(_) => {},
+		Err(e) => app_state.query_error = e.to_string(),
+	}
+
+	if let Some(name) = to_show {
+		app_state.search_text = collection_query(&name);
+		app_state.current_page = 0;
+		run_search_page(app_state);
+	}
+}
+
+/// A checkbox per collection for adding or removing the image.
+fn collection_toggles(app_state: &mut MainApp, ui: &mut egui::Ui, image_id: i64) {
+	let engine = app_state.engine.as_mut().unwrap();
+	let (collections, member_of) = match (engine.get_collections(), engine.get_image_collections(image_id)) {
+		(Ok(collections), Ok(member_of)) => (collections, member_of),
+		(Err(e), _) | (_, Err(e)) => {
+			ui.label(format!("Failed to load collections: {}", e));
+			return;
+		}
+	};
+	// Smart collections fill themselves.
+	let collections: Vec<_> = collections.into_iter().filter(|c| !c.is_smart()).collect();
+	if collections.is_empty() {
+		ui.weak("No collections yet.  Make one in the Collections section.");
+	}
+	for collection in collections {
+		let mut is_member = member_of.contains(&collection.id);
+		if ui.checkbox(&mut is_member, &collection.name).changed() {
+			let result = if is_member {
+				engine.add_to_collection(collection.id, image_id)
+			} else {
+				engine.remove_from_collection(collection.id, image_id)
+			};
+			if let Err(e) = result {
+				app_state.query_error = e.to_string();
+			}
+			return;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::ui::search::remove_query_token;
+
+	#[test]
+	fn test_remove_query_token() {
+		assert_eq!(remove_query_token("cat  \"two  words\" type:image dog", "type:image"), "cat  \"two  words\" dog");
+		assert_eq!(remove_query_token("cat TYPE:Image", "type:image"), "cat");
+		assert_eq!(remove_query_token("type:image", "type:image"), "");
+		assert_eq!(remove_query_token("type:images cat", "type:image"), "type:images cat");
+	}
+}
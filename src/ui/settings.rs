@@ -1,6 +1,15 @@
 use crate::{AppTab, MainApp};
+use crate::engine::{CompactionJob, SortField};
+use crate::ui::format_file_size;
+use crate::backup;
+use crate::ui::keymap::{format_shortcut, shortcut_from_key_press, Action, Keymap};
+use anyhow::{anyhow, Result};
 use eframe::{egui, NativeOptions};
 use eframe::egui::{Context, DroppedFile, TextureHandle, Ui};
+use serde_json::{json, Value as JSONValue};
+use std::path::{Path, PathBuf};
+
+const CONFIG_VERSION: u64 = 1;
 
 pub fn settings_panel(
 	app_state: &mut MainApp,  // We will need this eventually.
@@ -8,17 +17,157 @@ pub fn settings_panel(
 ) {
 	ui.vertical(|ui|{
 		ui.checkbox(&mut app_state.dark_mode, "Dark Mode");
+		ui.checkbox(&mut app_state.high_contrast, "High Contrast").on_hover_text("Pure black and white with heavy outlines, and a bright outline on whatever has keyboard focus.");
 		ui.add(egui::Slider::new(&mut app_state.search_text_min_length, 0..=255).text("Minimum Search Length")).on_hover_text("A search is automatically run when at least this many characters are entered into the search bar.  Be wary that 0 (match any letter) could slow down performance.");
 		ui.add(egui::Slider::new(&mut app_state.thumbnail_size, 0..=255).text("Thumbnail Size"));
+		ui.add(egui::Slider::new(&mut app_state.results_per_page, 1..=500).text("Results Per Page"));
 
 		if let Some(engine) = &mut app_state.engine {
-			ui.add(egui::Slider::new(&mut engine.max_search_results, 0..=10000).text("Max Search Results")).on_hover_text("How many results will be shown during a search.  A high number will use more memory and may take longer to run.");
-			ui.add(egui::Slider::new(&mut engine.max_distance_from_query, 0.0..=1.0).text("Max Query Dissimilarity")).on_hover_text("How dissimilar can an image be before it is removed from the results?  At 0, images must be identical to be shown.  At 1, unrelated images will be shown.");
+			// Engine settings are stored in the DB, so save them when one changes, though not for every step of a slider being dragged.
+			let previous_settings = engine.settings();
+
+			let mut edit = ui.add(egui::Slider::new(&mut engine.max_search_results, 0..=10000).text("Max Search Results")).on_hover_text("How many results will be shown during a search.  A high number will use more memory and may take longer to run.");
+			edit |= ui.add(egui::Slider::new(&mut engine.max_distance_from_query, 0.0..=1.0).text("Max Query Dissimilarity")).on_hover_text("How dissimilar can an image be before it is removed from the results?  At 0, images must be identical to be shown.  At 1, unrelated images will be shown.");
+			edit |= ui.add(egui::Slider::new(&mut engine.ranking_weights.visual, 0.0..=1.0).text("Visual Weight")).on_hover_text("When a search has both similar: and words, how much looking alike counts toward the order of results.");
+			edit |= ui.add(egui::Slider::new(&mut engine.ranking_weights.text, 0.0..=1.0).text("Text Weight")).on_hover_text("When a search has both similar: and words, how much matching the words counts toward the order of results.  Filenames that are mostly the search words rank higher.");
+			edit |= ui.add(egui::Slider::new(&mut engine.prefilter_candidates, 0..=100000).logarithmic(true).text("Phash Prefilter")).on_hover_text("Speed up similar: searches on big libraries by comparing only this many of the images with the closest perceptual hashes in detail.  Too few can miss images that look alike but were cropped or recolored.  0 compares every image.");
+			ui.horizontal(|ui|{
+				egui::ComboBox::from_label("Default Sort")
+					.selected_text(engine.sort_order.field.name())
+					.show_ui(ui, |ui| {
+						for field in SortField::ALL {
+							ui.selectable_value(&mut engine.sort_order.field, field, field.name());
+						}
+					}).response.on_hover_text("How results are ordered when a query doesn't specify 'sort:'.");
+				ui.checkbox(&mut engine.sort_order.descending, "Descending");
+			});
+			edit |= ui.add(egui::Slider::new(&mut engine.trash_retention_days, 0..=365).text("Trash Retention (Days)")).on_hover_text("How long purged images stay in the trash before they are permanently removed from the index.  Expired images are removed when the DB is opened.");
+
+			ui.checkbox(&mut engine.warn_on_near_duplicates, "Hold Near-Duplicates for Review").on_hover_text("While indexing, images that look almost exactly like an indexed image are listed in the Review tab instead of being added.");
+			edit |= ui.add(egui::Slider::new(&mut engine.near_duplicate_distance, 0.0..=0.25).text("Near-Duplicate Distance")).on_hover_text("How different two images' perceptual hashes can be and still count as near-duplicates, for holding them while indexing and for Hide Near-Duplicates in the View tab.  At 0, only visually identical images count.");
+			ui.checkbox(&mut engine.show_duplicates, "Show Duplicates").on_hover_text("Include images marked as duplicates of a canonical image in search results.  Mark them from the View tab.");
+			ui.checkbox(&mut engine.index_book_pages, "Index Every Book Page").on_hover_text("Index every image inside EPUBs and comic archives, not just the cover.  Takes effect the next time folders are indexed.");
+			edit |= ui.add(egui::Slider::new(&mut engine.max_archive_depth, 0..=10).text("Archive Depth")).on_hover_text("How many levels of archives inside archives, like a zip of zips, to look for images in.  At 0, only the images directly inside an archive are indexed.  Takes effect the next time folders are indexed.");
+			ui.checkbox(&mut engine.hash_cropped_frames, "Hash Cropped Frames").on_hover_text("Also hash each image without its borders and edges, so matted or watermarked copies of a picture can be found with method:cropped.  Indexing takes about twice as long.  Takes effect for images indexed after it's turned on.");
+			ui.checkbox(&mut engine.hash_animation_frames, "Hash Several Animation Frames").on_hover_text("Hash animated GIFs, PNGs, and WebPs by a few of their frames together, rather than only the middle one, so they can be found by scenes from anywhere in them.  Takes effect for images indexed after it's turned on.");
+			ui.checkbox(&mut engine.index_newest_first, "Index Newest First").on_hover_text("Index the most recently modified files first, so new photos can be searched early in a long reindex.  Folders with a higher priority still come first.  Nothing is indexed until every folder has been walked.");
+			ui.checkbox(&mut engine.skip_hidden_files, "Skip Hidden Files").on_hover_text("Don't index hidden files or anything in hidden folders, like .thumbnails, .cache, and .git, which are full of copies and blobs rather than pictures.  Files whose names start with a dot are hidden, and on Windows, so are files marked hidden.  Takes effect the next time folders are indexed.");
+			edit |= ui.add(egui::Slider::new(&mut engine.min_file_size_kb, 0..=1024).text("Minimum File Size (KB)")).on_hover_text("Don't index files smaller than this, like icons and tracking pixels.  Archives, books, and mail are still read, and the images in them are checked instead.  Takes effect the next time folders are indexed.");
+			edit |= ui.add(egui::Slider::new(&mut engine.max_file_size_mb, 0..=4096).text("Maximum File Size (MB)")).on_hover_text("Don't index files bigger than this, so huge files don't hold up indexing.  0 for no limit.  Archives, books, and mail are still read, and the images in them are checked instead.  Takes effect the next time folders are indexed.");
+			edit |= ui.add(egui::Slider::new(&mut engine.max_files_per_second, 0..=100).text("Files per Second")).on_hover_text("Index at most this many files a second, so indexing in the background doesn't keep the CPU and fans busy for hours.  0 for no limit.  Takes effect the next time folders are indexed.");
+			ui.checkbox(&mut engine.low_priority_indexing, "Index at Low Priority").on_hover_text("Let everything else on the computer have the CPU before indexing does.  Only works on Linux and Windows.  Takes effect the next time folders are indexed.");
+			edit |= ui.add(egui::Slider::new(&mut engine.crawler_threads, 1..=64).text("Crawler Threads")).on_hover_text("How many files are loaded at once while indexing.  More is faster on computers with many cores, up to the speed of the disk.  Takes effect the next time folders are indexed.");
+			edit |= ui.add(egui::Slider::new(&mut engine.embedding_threads, 0..=64).text("Embedding Threads")).on_hover_text("How many of the crawler threads can run the similarity model at once.  The model takes the most CPU and memory of anything in indexing.  0 lets them all.  Takes effect the next time folders are indexed.");
+			edit |= ui.add(egui::Slider::new(&mut engine.network_timeout_seconds, 0..=300).text("Network Timeout (Seconds)")).on_hover_text("Give up on a folder that hasn't answered in this long, like one on a NAS that's asleep or a share that's gone, instead of waiting on it forever.  0 waits as long as it takes.  Takes effect the next time folders are indexed.");
+			edit |= ui.add(egui::Slider::new(&mut engine.network_retries, 0..=10).text("Network Retries")).on_hover_text("How many more times to try a folder that timed out or whose share is down, waiting a little longer each time for it to wake up.  After that, it's skipped until the next index and shown as unreachable in Folders.  Takes effect the next time folders are indexed.");
+			ui.checkbox(&mut engine.watch_folders, "Watch Folders for Changes").on_hover_text("While PixelBox is open, index files as soon as they're added to or changed in a watched folder, and move the images of deleted files to the trash.  Changes are picked up once a file has sat still for a couple of seconds.  Needs a build with --features watch.");
+			ui.checkbox(&mut engine.reembed_on_open, "Re-embed on Open").on_hover_text("After the embedding model is upgraded, redo the old embeddings as soon as the DB is opened.  Off leaves time to measure how much similarity searches will change first, from Storage.");
+
+			ui.horizontal(|ui|{
+				ui.add(egui::TextEdit::singleline(&mut engine.backup_directory).hint_text("Backup Directory")).on_hover_text("Where scheduled backups go.  Leave empty to turn them off.");
+				if ui.button("Choose...").clicked() {
+					if let Some(dir) = rfd::FileDialog::new().pick_folder() {
+						engine.backup_directory = dir.display().to_string();
+					}
+				}
+			});
+			edit |= ui.add(egui::Slider::new(&mut engine.backup_interval_hours, 0..=168).text("Backup Interval (Hours)")).on_hover_text("How often to back up while PixelBox is open.  Only pages that changed since the last backup are stored.  0 turns scheduled backups off.");
+			edit |= ui.add(egui::Slider::new(&mut engine.backup_chains_to_keep, 1..=30).text("Full Backups to Keep")).on_hover_text("Every few backups a full copy is made.  Older full copies and the changes after them are deleted beyond this many.");
+
+			if edit.drag_released() || (engine.settings() != previous_settings && !edit.dragged()) {
+				if let Err(e) = engine.save_settings() {
+					eprintln!("Failed to save settings: {}", e);
+				}
+			}
 		} else {
 			// Honestly, this should never happen, but let's be safe.
 			ui.label("Max Search Results and Max Query Distance can be configured when a DB has been opened.");
 		}
 
+		ui.separator();
+		ui.collapsing("Keyboard Shortcuts", |ui|{
+			keymap_editor(app_state, ui);
+		});
+
+		ui.separator();
+		ui.horizontal(|ui|{
+			if ui.button("Export Configuration").on_hover_text("Save app settings and this DB's settings and rules to a JSON file.  Images are not included.").clicked() {
+				if let Some(file_path) = rfd::FileDialog::new().add_filter("JSON", &["json"]).set_file_name("pixelbox_config.json").save_file() {
+					if let Err(e) = export_config(app_state, &file_path) {
+						eprintln!("Failed to export configuration: {}", e);
+					}
+				}
+			}
+			if ui.button("Import Configuration").on_hover_text("Load settings and rules from an exported JSON file.  Rules with the same name are replaced.").clicked() {
+				if let Some(file_path) = rfd::FileDialog::new().add_filter("JSON", &["json"]).pick_file() {
+					if let Err(e) = import_config(app_state, &file_path) {
+						eprintln!("Failed to import configuration: {}", e);
+					}
+				}
+			}
+		});
+
+		if let Some(engine) = &mut app_state.engine {
+			ui.horizontal(|ui|{
+				if ui.button("Back Up DB").on_hover_text("Save a copy of the whole DB.  Safe to do while indexing.").clicked() {
+					if let Some(file_path) = rfd::FileDialog::new().add_filter("SQLite DB", &["db", "sqlite"]).set_file_name("pixelbox_backup.db").save_file() {
+						if let Err(e) = engine.backup_to(&file_path) {
+							eprintln!("Failed to back up the DB: {}", e);
+						}
+					}
+				}
+				if ui.add_enabled(!engine.backup_directory.is_empty(), egui::Button::new("Back Up Now")).on_hover_text("Back up to the backup directory now instead of waiting for the next scheduled backup.").clicked() {
+					if let Err(e) = engine.backup_incremental(Path::new(&engine.backup_directory)) {
+						eprintln!("Failed to back up the DB: {}", e);
+					}
+				}
+				if ui.button("Restore Backup").on_hover_text("Rebuild a DB from a file in the backup directory.  The open DB isn't changed.").clicked() {
+					if let Some(backup_path) = rfd::FileDialog::new().add_filter("PixelBox Backup", &["db"]).pick_file() {
+						if let Some(target_path) = rfd::FileDialog::new().add_filter("SQLite DB", &["db", "sqlite"]).set_file_name("pixelbox_restored.db").save_file() {
+							if let Err(e) = backup::restore_backup(&backup_path, &target_path) {
+								eprintln!("Failed to restore backup: {}", e);
+							}
+						}
+					}
+				}
+				if engine.is_maintenance_running() {
+					ui.spinner();
+					ui.label("Checking and compacting...");
+				} else if ui.button("Check and Compact DB").on_hover_text("Look for corruption, then shrink the DB file by reclaiming space left by deleted images.  This can take a while for large DBs.").clicked() {
+					if let Err(e) = engine.start_maintenance() {
+						app_state.maintenance_report = Some(format!("Maintenance failed: {}", e));
+					}
+				}
+				if ui.button("Analyze Storage").on_hover_text("Show what's taking up space in the DB and what could be done about it.").clicked() {
+					match engine.analyze_storage() {
+						Ok(report) => app_state.storage_report = Some(report),
+						Err(e) => app_state.maintenance_report = Some(format!("Failed to analyze storage: {}", e)),
+					}
+				}
+				if ui.button("Export Index").on_hover_text("Save every indexed image with its tags, hashes, thumbnail, and collections to a file, so the index can be moved to another machine without rehashing.").clicked() {
+					if let Some(file_path) = rfd::FileDialog::new().add_filter("PixelBox Index", &["pbindex"]).set_file_name("pixelbox_index.pbindex").save_file() {
+						// The save dialog already asked before replacing the file, and export_index won't write over one.
+						let _ = std::fs::remove_file(&file_path);
+						if let Err(e) = engine.export_index(&file_path) {
+							eprintln!("Failed to export index: {}", e);
+						}
+					}
+				}
+				if ui.button("Import Index").on_hover_text("Add the images from an exported index to this DB.  Images that are already indexed are skipped.").clicked() {
+					if let Some(file_path) = rfd::FileDialog::new().add_filter("PixelBox Index", &["pbindex"]).pick_file() {
+						if let Err(e) = engine.import_index(&file_path) {
+							eprintln!("Failed to import index: {}", e);
+						}
+					}
+				}
+			});
+		}
+
+		if let Some(report) = &app_state.maintenance_report {
+			ui.label(report);
+		}
+		storage_report(app_state, ui);
+
 		// Configuration options to implement
 		// Maybe search weights for similarity vector?
 		// Reindex/refresh check increment (disable background auto-check to use zero CPU when not in focus)
@@ -28,3 +177,332 @@ pub fn settings_panel(
 		//if ui.text_edit_singleline(&mut app_state.search_text).changed() {}
 	});
 }
+
+fn storage_report(app_state: &mut MainApp, ui: &mut egui::Ui) {
+	let engine = match app_state.engine.as_mut() {
+		Some(engine) => engine,
+		None => return,
+	};
+	if let Some(result) = engine.poll_maintenance() {
+		app_state.maintenance_report = Some(match result {
+			Ok(report) if report.problems.is_empty() => format!("No problems found.  Reclaimed {:.1} MB.", report.bytes_reclaimed() as f64 / (1 << 20) as f64),
+			Ok(report) => format!("The DB is damaged, so it wasn't compacted.  Restore a backup if you have one.\n{}", report.problems.join("\n")),
+			Err(e) => format!("Maintenance failed: {}", e),
+		});
+		app_state.storage_report = None; // Out of date now.
+		return;
+	}
+	if let Some(result) = engine.poll_compaction_job() {
+		app_state.maintenance_report = Some(match result {
+			Ok(saved) => format!("Freed {}.  Use Check and Compact DB to shrink the file.", format_file_size(saved)),
+			Err(e) => format!("Compaction failed: {}", e),
+		});
+		app_state.storage_report = None; // Out of date now.
+		return;
+	}
+	if let Some(result) = engine.poll_drift() {
+		app_state.maintenance_report = Some(match result {
+			Ok(drift) => format!(
+				"Compared the nearest neighbors of {} images under both models.  Rank correlation: {:.2} (1 is unchanged).  {:.0}% of nearest neighbors stay the same.",
+				drift.sample_size, drift.rank_correlation, 100.0 * drift.neighbor_overlap
+			),
+			Err(e) => format!("Measuring drift failed: {}", e),
+		});
+		return;
+	}
+	if let Some(result) = engine.poll_reembedding() {
+		app_state.maintenance_report = Some(match result {
+			Ok(count) => format!("Re-embedded {} images with the current model.", count),
+			Err(e) => format!("Re-embedding failed: {}", e),
+		});
+		app_state.storage_report = None;
+		return;
+	}
+	let report = match &app_state.storage_report {
+		Some(report) => report,
+		None => return,
+	};
+
+	let mut to_start: Option<CompactionJob> = None;
+	let mut start_reembedding = false;
+	let mut measure_drift = false;
+	ui.collapsing("Storage", |ui|{
+		ui.label(format!("DB file: {}, of which {} is unused until the DB is compacted.", format_file_size(report.file_bytes), format_file_size(report.free_bytes)));
+		ui.label(format!(
+			"Thumbnails: {}   Embeddings: {}   Other hashes: {}   Tags: {}",
+			format_file_size(report.thumbnail_bytes), format_file_size(report.embedding_bytes), format_file_size(report.other_hash_bytes), format_file_size(report.tag_bytes)
+		));
+		ui.collapsing("Tables and Indices", |ui|{
+			egui::Grid::new("storage_tables").striped(true).show(ui, |ui|{
+				for (name, size) in &report.table_bytes {
+					ui.label(name);
+					ui.label(format_file_size(*size));
+					ui.end_row();
+				}
+			});
+		});
+		ui.collapsing("Largest Images", |ui|{
+			egui::Grid::new("storage_largest_images").striped(true).show(ui, |ui|{
+				for (_, path, size) in &report.largest_images {
+					ui.label(path);
+					ui.label(format_file_size(*size));
+					ui.end_row();
+				}
+			});
+		});
+
+		let idle = !engine.is_compaction_running();
+		ui.horizontal(|ui|{
+			let recompress_hint = format!("Store thumbnails as PNG where that's smaller.  Should save about {}.", format_file_size(report.thumbnail_savings_estimate));
+			if ui.add_enabled(idle && report.thumbnail_savings_estimate > 0, egui::Button::new("Recompress Thumbnails")).on_hover_text(recompress_hint).clicked() {
+				to_start = Some(CompactionJob::RecompressThumbnails);
+			}
+			let webp_hint = format!("Store every thumbnail as a lossless WebP where that's smaller.  Should save about {}.", format_file_size(report.webp_savings_estimate));
+			if ui.add_enabled(idle && report.webp_savings_estimate > 0, egui::Button::new("Convert Thumbnails to WebP")).on_hover_text(webp_hint).clicked() {
+				to_start = Some(CompactionJob::WebpThumbnails);
+			}
+			let reduce_hint = format!("Delete the embeddings of cropped frames, keeping one embedding per image.  Will save {}.  method:cropped searches won't find images until they're reindexed with cropped frames.", format_file_size(report.embedding_reduction_estimate));
+			if ui.add_enabled(idle && report.embedding_reduction_estimate > 0, egui::Button::new("Reduce Embeddings")).on_hover_text(reduce_hint).clicked() {
+				to_start = Some(CompactionJob::ReduceEmbeddings);
+			}
+			let orphans_hint = format!("Delete hashes and tags left behind by images that are no longer indexed.  Will save {}.", format_file_size(report.orphaned_bytes));
+			if ui.add_enabled(idle && report.orphaned_bytes > 0, egui::Button::new("Remove Orphaned Data")).on_hover_text(orphans_hint).clicked() {
+				to_start = Some(CompactionJob::RemoveOrphans);
+			}
+			if !idle {
+				ui.spinner();
+			}
+		});
+
+		// Embeddings from a swapped-out model can't be compared with new ones.
+		match engine.get_reembedding_progress() {
+			Some((done, total)) => {
+				ui.add(egui::ProgressBar::new(done as f32 / total.max(1) as f32).text(format!("Re-embedding {} of {}", done, total)).animate(true));
+			},
+			None if report.stale_embeddings > 0 => {
+				ui.horizontal(|ui|{
+					ui.label(format!("{} images have embeddings from a different model, so similarity searches won't find them properly.", report.stale_embeddings));
+					if ui.button("Re-embed").clicked() {
+						start_reembedding = true;
+					}
+					if ui.add_enabled(!engine.is_measuring_drift(), egui::Button::new("Measure Drift")).on_hover_text("Redo a sample of the old embeddings without saving them, and see how much each image's nearest neighbors change.").clicked() {
+						measure_drift = true;
+					}
+					if engine.is_measuring_drift() {
+						ui.spinner();
+					}
+				});
+			},
+			None => {},
+		}
+	});
+
+	if measure_drift {
+		if let Err(e) = engine.start_measuring_drift() {
+			app_state.maintenance_report = Some(e.to_string());
+		}
+	}
+
+	if start_reembedding {
+		if let Err(e) = engine.start_reembedding() {
+			app_state.maintenance_report = Some(e.to_string());
+		}
+	}
+
+	if let Some(job) = to_start {
+		if let Err(e) = engine.start_compaction_job(job) {
+			app_state.maintenance_report = Some(e.to_string());
+		}
+	}
+}
+
+fn keymap_editor(app_state: &mut MainApp, ui: &mut egui::Ui) {
+	let previous_keymap = app_state.keymap.clone();
+
+	// While waiting for a shortcut, the next key pressed is bound.  Escape cancels.
+	if let Some(action) = app_state.keymap_capture {
+		let pressed = ui.input(|i| i.events.iter().find_map(|e| match e {
+			egui::Event::Key { key, pressed: true, modifiers, .. } => Some((*key, *modifiers)),
+			_ => None,
+		}));
+		match pressed {
+			Some((egui::Key::Escape, _)) => app_state.keymap_capture = None,
+			Some((key, modifiers)) => {
+				app_state.keymap.set(action, shortcut_from_key_press(key, modifiers));
+				app_state.keymap_capture = None;
+			},
+			None => {},
+		}
+	}
+
+	egui::Grid::new("keymap").num_columns(3).show(ui, |ui|{
+		for action in Action::ALL {
+			ui.label(action.label());
+			let shortcut_text = if app_state.keymap_capture == Some(action) { "Press a key...".to_string() } else { format_shortcut(&app_state.keymap.get(action)) };
+			if ui.button(shortcut_text).on_hover_text("Click, then press the new shortcut.  Escape cancels.").clicked() {
+				app_state.keymap_capture = Some(action);
+			}
+			ui.horizontal(|ui|{
+				if ui.small_button("Reset").clicked() {
+					app_state.keymap.reset(action);
+				}
+				let conflicts = app_state.keymap.conflicts(action);
+				if !conflicts.is_empty() {
+					let names: Vec<&str> = conflicts.iter().map(|a| a.label()).collect();
+					ui.colored_label(ui.visuals().warn_fg_color, format!("Also used by {}", names.join(", ")));
+				}
+			});
+			ui.end_row();
+		}
+	});
+	if ui.button("Reset All Shortcuts").clicked() {
+		app_state.keymap = Keymap::default();
+		app_state.keymap_capture = None;
+	}
+
+	if app_state.keymap != previous_keymap {
+		if let Err(e) = save_app_config(app_state) {
+			eprintln!("Failed to save shortcuts: {}", e);
+		}
+	}
+}
+
+/// App settings plus, if a DB is open, the DB's settings and rules.
+fn export_config(app_state: &mut MainApp, file_path: &Path) -> Result<()> {
+	let database = match app_state.engine.as_mut() {
+		Some(engine) => engine.export_config()?,
+		None => JSONValue::Null,
+	};
+	let config = json!({
+		"version": CONFIG_VERSION,
+		"app": app_config(app_state),
+		"database": database,
+	});
+	std::fs::write(file_path, serde_json::to_string_pretty(&config)?)?;
+	Ok(())
+}
+
+/// Anything missing from the file is left as-is.  The DB section is skipped if no DB is open.
+fn import_config(app_state: &mut MainApp, file_path: &Path) -> Result<()> {
+	let config = read_config(file_path)?;
+	if let Some(app) = config.get("app") {
+		apply_app_config(app_state, app);
+	}
+
+	if let (Some(engine), Some(database)) = (app_state.engine.as_mut(), config.get("database")) {
+		if !database.is_null() {
+			engine.import_config(database)?;
+		}
+	}
+	Ok(())
+}
+
+fn read_config(file_path: &Path) -> Result<JSONValue> {
+	let config: JSONValue = serde_json::from_str(&std::fs::read_to_string(file_path)?)?;
+	let version = config.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+	if version > CONFIG_VERSION {
+		return Err(anyhow!("Configuration version {} is newer than this version of PixelBox supports ({}).", version, CONFIG_VERSION));
+	}
+	Ok(config)
+}
+
+/// The settings that belong to the app rather than to a DB.
+fn app_config(app_state: &MainApp) -> JSONValue {
+	json!({
+		"dark_mode": app_state.dark_mode,
+		"high_contrast": app_state.high_contrast,
+		"thumbnail_size": app_state.thumbnail_size,
+		"search_text_min_length": app_state.search_text_min_length,
+		"results_per_page": app_state.results_per_page,
+		"keymap": app_state.keymap.to_json(),
+	})
+}
+
+fn apply_app_config(app_state: &mut MainApp, app: &JSONValue) {
+	if let Some(v) = app.get("dark_mode").and_then(|v| v.as_bool()) {
+		app_state.dark_mode = v;
+	}
+	if let Some(v) = app.get("high_contrast").and_then(|v| v.as_bool()) {
+		app_state.high_contrast = v;
+	}
+	if let Some(v) = app.get("thumbnail_size").and_then(|v| v.as_u64()) {
+		app_state.thumbnail_size = v.min(u8::MAX as u64) as u8;
+	}
+	if let Some(v) = app.get("search_text_min_length").and_then(|v| v.as_u64()) {
+		app_state.search_text_min_length = v.min(u8::MAX as u64) as u8;
+	}
+	if let Some(v) = app.get("results_per_page").and_then(|v| v.as_u64()) {
+		app_state.results_per_page = v.max(1);
+	}
+	if let Some(keymap) = app.get("keymap") {
+		app_state.keymap.update_from_json(keymap);
+	}
+}
+
+/// Where the app settings are kept between runs, like ~/.config/pixelbox/app_config.json.  DB settings are kept in the DB.
+fn app_config_path() -> Option<PathBuf> {
+	let config_dir = std::env::var_os("APPDATA").or_else(|| std::env::var_os("XDG_CONFIG_HOME")).map(PathBuf::from)
+		.or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+	Some(config_dir.join("pixelbox").join("app_config.json"))
+}
+
+/// Read the app settings saved by save_app_config.  Nothing is changed if they were never saved.
+pub fn load_app_config(app_state: &mut MainApp) -> Result<()> {
+	let file_path = match app_config_path() {
+		Some(file_path) if file_path.is_file() => file_path,
+		_ => return Ok(()),
+	};
+	if let Some(app) = read_config(&file_path)?.get("app") {
+		apply_app_config(app_state, app);
+	}
+	Ok(())
+}
+
+pub fn save_app_config(app_state: &MainApp) -> Result<()> {
+	let file_path = app_config_path().ok_or_else(|| anyhow!("There's no config directory to save app settings in."))?;
+	if let Some(parent) = file_path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	let config = json!({
+		"version": CONFIG_VERSION,
+		"app": app_config(app_state),
+	});
+	std::fs::write(file_path, serde_json::to_string_pretty(&config)?)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::MainApp;
+	use crate::ui::settings::{app_config, apply_app_config};
+	use crate::ui::theme_visuals;
+	use eframe::egui;
+
+	#[test]
+	fn test_app_config_round_trip() {
+		let mut saved = MainApp::default();
+		saved.dark_mode = false;
+		saved.high_contrast = true;
+		saved.thumbnail_size = 200;
+		let mut loaded = MainApp::default();
+		apply_app_config(&mut loaded, &app_config(&saved));
+		assert!(!loaded.dark_mode);
+		assert!(loaded.high_contrast);
+		assert_eq!(loaded.thumbnail_size, 200);
+
+		// Settings missing from older configs keep their defaults.
+		let mut loaded = MainApp::default();
+		apply_app_config(&mut loaded, &serde_json::json!({ "dark_mode": false }));
+		assert!(!loaded.high_contrast);
+	}
+
+	#[test]
+	fn test_theme_visuals() {
+		assert_eq!(theme_visuals(true, false), egui::Visuals::dark());
+		let high_contrast = theme_visuals(true, true);
+		assert_eq!(high_contrast.panel_fill, egui::Color32::BLACK);
+		assert_eq!(high_contrast.override_text_color, Some(egui::Color32::WHITE));
+		assert_eq!(high_contrast.widgets.active.bg_stroke, egui::Stroke::new(3.0, egui::Color32::YELLOW));
+		assert_eq!(theme_visuals(false, true).panel_fill, egui::Color32::WHITE);
+	}
+}
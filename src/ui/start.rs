@@ -3,6 +3,7 @@ use eframe::{egui, NativeOptions};
 use crate::engine::Engine;
 
 pub fn start_panel(
+		db_error: &str,
 		ui: &mut egui::Ui
 ) {
 	/*
@@ -14,6 +15,9 @@ pub fn start_panel(
 
 	ui.vertical(|ui|{
 		ui.heading("Welcome to PixelBox");
+		if !db_error.is_empty() {
+			ui.colored_label(ui.visuals().error_fg_color, db_error);
+		}
 		ui.label("To Begin:");
 		ui.label(" 1. Create a New Image Database (File > New DB)");
 		ui.label(" 2. Add Tracked Folders (Folders > Add Directory)");
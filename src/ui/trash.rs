@@ -0,0 +1,65 @@
+use crate::MainApp;
+use crate::ui::show_thumbnail;
+use eframe::egui;
+
+pub fn trash_panel(
+	app_state: &mut MainApp,
+	ui: &mut egui::Ui
+) {
+	let engine = match app_state.engine.as_mut() {
+		Some(engine) => engine,
+		None => {
+			ui.label("To view the trash, make sure a DB is loaded.");
+			return;
+		}
+	};
+
+	let trashed = match engine.get_trashed_images() {
+		Ok(trashed) => trashed,
+		Err(e) => {
+			ui.label(format!("Failed to load the trash: {}", e));
+			return;
+		}
+	};
+
+	// Deferred so we aren't mutating the engine while drawing the list.
+	let mut to_restore: Option<i64> = None;
+	let mut empty_trash = false;
+
+	ui.horizontal(|ui|{
+		ui.heading("Trash");
+		if ui.button("Empty Trash").on_hover_text("Permanently remove every trashed image from the index.  Files on disk are not touched.").clicked() {
+			empty_trash = true;
+		}
+	});
+	ui.label(format!("{} image(s).  Trashed images are hidden from search and removed from the index after {} days.", trashed.len(), engine.trash_retention_days));
+
+	egui::ScrollArea::vertical()
+		.auto_shrink([false, false])
+		.show(ui, |ui| {
+			for img in &trashed {
+				ui.horizontal(|ui|{
+					show_thumbnail(ui, img, &app_state.revealed_images, &mut app_state.image_id_to_texture_handle);
+					ui.vertical(|ui|{
+						ui.label(format!("Filename: {}", img.filename));
+						ui.label(format!("Path: {}", img.path));
+						if ui.button("Restore").clicked() {
+							to_restore = Some(img.id);
+						}
+					});
+				});
+			}
+		});
+
+	let engine = app_state.engine.as_mut().unwrap();
+	if let Some(image_id) = to_restore {
+		if let Err(e) = engine.restore_from_trash(image_id) {
+			eprintln!("Failed to restore image {} from the trash: {}", image_id, e);
+		}
+	}
+	if empty_trash {
+		if let Err(e) = engine.empty_trash(0) {
+			eprintln!("Failed to empty the trash: {}", e);
+		}
+	}
+}
@@ -1,9 +1,12 @@
 use std::ops::Mul;
 use crate::{AppTab, MainApp};
-use crate::ui::load_image_from_path;
+use crate::engine::EmbeddingModel;
+use crate::indexed_image::filepath_from_string;
+use crate::provenance;
+use crate::ui::search;
+use crate::ui::{format_file_size, show_thumbnail, thumbnail_alt_text, load_image_from_memory, load_image_from_path, palette_swatches, rating_stars};
 use eframe::{egui};
 use eframe::egui::{Context, TextureHandle, TextureOptions, Ui};
-use std::path::Path;
 use crate::egui::Color32;
 
 // Still TODO:
@@ -11,6 +14,8 @@ use crate::egui::Color32;
 // The image is just displayed plainly.  No ability to change anything or zoom or show meta.
 // No errors shown if an image can't be displayed.
 
+const METHOD_COMPARISON_SIZE: usize = 20;
+
 pub fn view_panel(
 	app_state: &mut MainApp,
 	ui: &mut egui::Ui
@@ -30,42 +35,352 @@ pub fn view_panel(
 	// That is to say, we might have switched the selected without clearing it.
 	if app_state.full_image_path != selected_image.path {
 		app_state.full_image_path = selected_image.path.clone();
+		app_state.selected_image_user_tags = None;
+		app_state.embedding_models = None;
+		app_state.method_comparison = None;
+		app_state.selected_image_duplicates = None;
+		app_state.selected_image_screenshot = None;
 		app_state.full_image = {
-			if let Ok(img) = load_image_from_path(Path::new(&app_state.full_image_path)) {
-				Some(ui.ctx().load_texture(app_state.full_image_path.clone(), img, TextureOptions::LINEAR))
-			} else {
-				None
-			}
+			// Shared collections may carry the originals for images that aren't on this machine.
+			let img = load_image_from_path(&filepath_from_string(&app_state.full_image_path)).ok().or_else(|| {
+				match app_state.engine.as_ref().unwrap().get_original(selected_image.id) {
+					Ok(Some(data)) => load_image_from_memory(&data).ok(),
+					_ => None,
+				}
+			});
+			img.map(|img| ui.ctx().load_texture(app_state.full_image_path.clone(), img, TextureOptions::LINEAR))
 		};
 		//app_state.full_image = Some(RetainedImage::)
 	}
 
+	if app_state.selected_image_user_tags.is_none() {
+		match app_state.engine.as_ref().unwrap().get_user_tags(selected_image.id) {
+			Ok(tags) => app_state.selected_image_user_tags = Some(tags),
+			Err(e) => {
+				eprintln!("Failed to load tags for {}: {}", selected_image.path, e);
+				app_state.selected_image_user_tags = Some(vec![]);
+			}
+		}
+	}
+	let user_tags = app_state.selected_image_user_tags.as_ref().unwrap();
+
+	if app_state.selected_image_duplicates.is_none() {
+		let engine = app_state.engine.as_ref().unwrap();
+		match (engine.get_canonical_image(selected_image.id), engine.get_duplicates(selected_image.id)) {
+			(Ok(canonical), Ok(duplicates)) => app_state.selected_image_duplicates = Some((canonical, duplicates)),
+			(Err(e), _) | (_, Err(e)) => {
+				eprintln!("Failed to load duplicates of {}: {}", selected_image.path, e);
+				app_state.selected_image_duplicates = Some((None, vec![]));
+			}
+		}
+	}
+	let (canonical_image, duplicates) = app_state.selected_image_duplicates.as_ref().unwrap();
+
+	if app_state.selected_image_screenshot.is_none() {
+		app_state.selected_image_screenshot = Some(app_state.engine.as_ref().unwrap().get_screenshot_info(selected_image.id).unwrap_or_else(|e| {
+			eprintln!("Failed to load the screenshot window of {}: {}", selected_image.path, e);
+			None
+		}));
+	}
+	let screenshot = app_state.selected_image_screenshot.as_ref().unwrap();
+
+	let mut search_by_color = None;
+	let mut search_session = None;
+	let mut toggle_reveal = false;
+	let mut new_rating = None;
+	let mut toggle_favorite = false;
+	let mut tag_to_add: Option<(String, String)> = None;
+	let mut tag_to_remove: Option<String> = None;
+	let mut compare_methods = false;
+	let mut image_to_view = None;
+	let mut hide_near_duplicates = false;
+	let mut make_canonical = None;
+	let mut unmark_duplicate = None;
+	let mut exclude = false;
 	ui.vertical(|ui|{
+		if selected_image.protected {
+			ui.label("🔒 Protected").on_hover_text("This image is excluded from bulk operations.");
+		}
+		if selected_image.blurred_thumbnail.is_some() {
+			ui.horizontal(|ui|{
+				ui.label("Sensitive").on_hover_text("Remove the 'sensitive' tag to stop blurring it.");
+				let revealed = app_state.revealed_images.contains(&selected_image.id);
+				if ui.button(if revealed { "Blur" } else { "Reveal" }).clicked() {
+					toggle_reveal = true;
+				}
+			});
+		}
+		ui.horizontal(|ui|{
+			new_rating = rating_stars(ui, selected_image.rating);
+			let heart = if selected_image.favorite { "♥" } else { "♡" };
+			if ui.add(egui::Label::new(egui::RichText::new(heart).color(Color32::LIGHT_RED)).sense(egui::Sense::click())).on_hover_text("Toggle favorite (fav:true)").clicked() {
+				toggle_favorite = true;
+			}
+			if ui.button("Exclude").on_hover_text("Move this to the trash and never index it again, even if it's copied or moved.  Restoring it from the trash undoes this.").clicked() {
+				exclude = true;
+			}
+		});
 		ui.label(format!("Filename: {}", selected_image.filename));
 		ui.label(format!("Path: {}", selected_image.path));
 		ui.label(format!("Size: {}x{}", selected_image.resolution.0, selected_image.resolution.1));
+		ui.label(format!("File Size: {}", format_file_size(selected_image.file_size)));
+		if let Some(url) = selected_image.tags.get(provenance::SOURCE_URL_TAG) {
+			ui.horizontal(|ui|{
+				ui.label("Downloaded From:");
+				ui.hyperlink(url);
+			});
+		}
+		if let Some(info) = screenshot {
+			ui.label(format!("Window: {}", info.source.window)).on_hover_text(format!("App: {}", info.source.app));
+			if let Some(session) = info.session.filter(|_| info.session_size > 1) {
+				ui.horizontal(|ui|{
+					ui.label(format!("Session: {} screenshots", info.session_size));
+					if ui.button("Show Session").on_hover_text(format!("session:{}", session)).clicked() {
+						search_session = Some(session);
+					}
+				});
+			}
+		}
+		if let Some(palette) = &selected_image.palette {
+			ui.horizontal(|ui|{
+				ui.label("Palette:");
+				if let Some(color) = palette_swatches(ui, palette, 16.0) {
+					search_by_color = Some(color);
+				}
+			});
+		}
+		ui.label("Tags:");
+		ui.horizontal_wrapped(|ui| {
+			for (k, v) in user_tags {
+				ui.colored_label(Color32::LIGHT_GRAY, k);
+				ui.colored_label(Color32::LIGHT_GREEN, v);
+				if ui.small_button("x").on_hover_text("Remove this tag.").clicked() {
+					tag_to_remove = Some(k.clone());
+				}
+			}
+		});
+		ui.horizontal(|ui|{
+			ui.add(egui::TextEdit::singleline(&mut app_state.new_tag_name).hint_text("Tag").desired_width(100.0));
+			ui.add(egui::TextEdit::singleline(&mut app_state.new_tag_value).hint_text("Value (optional)").desired_width(150.0));
+			if ui.add_enabled(!app_state.new_tag_name.trim().is_empty(), egui::Button::new("Add Tag")).on_hover_text("Adding a tag that already exists changes its value.").clicked() {
+				tag_to_add = Some((app_state.new_tag_name.clone(), app_state.new_tag_value.clone()));
+			}
+		});
 		ui.label("EXIF Tags:");
 		ui.horizontal_wrapped(|ui| {
 			// These are equivalent.
 			// ui.label(RichText::new("Text can have").color(Color32::from_rgb(110, 255, 110)));
 			// ui.colored_label(Color32::from_rgb(128, 140, 255), "color");
-			for (k, v) in &selected_image.tags {
+			for (k, v) in selected_image.tags.iter().filter(|(k, _)| !user_tags.iter().any(|(name, _)| name == *k)) {
 				let mut v_short = v.clone();
 				v_short.truncate(256);
 				ui.colored_label(Color32::LIGHT_GRAY, k);
 				ui.colored_label(Color32::LIGHT_BLUE, v_short).on_hover_text(v);
 			}
 		});
+		ui.collapsing(format!("Duplicates ({})", duplicates.len()), |ui| {
+			if let Some(canonical) = canonical_image {
+				ui.horizontal(|ui| {
+					ui.label("Duplicate of");
+					if ui.link(&canonical.filename).on_hover_text(&canonical.path).clicked() {
+						image_to_view = Some(canonical.clone());
+					}
+				});
+				ui.label("Duplicates only show up in searches when Show Duplicates is on in Settings.");
+				ui.horizontal(|ui| {
+					if ui.button("Make This Canonical").on_hover_text("Show this image in searches instead, and hide the rest of its duplicates.").clicked() {
+						make_canonical = Some(canonical.id);
+					}
+					if ui.button("Not a Duplicate").clicked() {
+						unmark_duplicate = Some(selected_image.id);
+					}
+				});
+			} else {
+				for duplicate in duplicates {
+					ui.horizontal(|ui| {
+						if ui.link(&duplicate.filename).on_hover_text(&duplicate.path).clicked() {
+							image_to_view = Some(duplicate.clone());
+						}
+						if ui.small_button("x").on_hover_text("Not a duplicate.  Show it in searches again.").clicked() {
+							unmark_duplicate = Some(duplicate.id);
+						}
+					});
+				}
+				if ui.button("Hide Near-Duplicates").on_hover_text("Mark images that look almost exactly like this one as its duplicates, so only this one shows up in searches.  Protected images are left alone, and nothing is deleted.").clicked() {
+					hide_near_duplicates = true;
+				}
+			}
+		});
+		ui.collapsing("Compare Embedding Models", |ui| {
+			if app_state.embedding_models.is_none() {
+				app_state.embedding_models = match app_state.engine.as_ref().unwrap().get_embedding_models() {
+					Ok(models) => Some(models),
+					Err(e) => {
+						eprintln!("Failed to load embedding models: {}", e);
+						Some(vec![])
+					}
+				};
+			}
+			let models = app_state.embedding_models.as_ref().unwrap();
+			if models.len() < 2 {
+				ui.label("Register at least two embedding models and store their embeddings to compare them.");
+				return;
+			}
+			ui.horizontal(|ui| {
+				embedding_model_picker(ui, "A", models, &mut app_state.comparison_models.0);
+				embedding_model_picker(ui, "B", models, &mut app_state.comparison_models.1);
+				let picked = app_state.comparison_models.0.is_some() && app_state.comparison_models.1.is_some();
+				if ui.add_enabled(picked, egui::Button::new("Compare")).on_hover_text(format!("Show the {} nearest images by each model.", METHOD_COMPARISON_SIZE)).clicked() {
+					compare_methods = true;
+				}
+			});
+			if let Some(comparison) = &app_state.method_comparison {
+				let rank_correlation = comparison.rank_correlation.map(|r| format!("{:.2}", r)).unwrap_or("n/a".to_string());
+				ui.label(format!("In both: {}  Jaccard: {:.2}  Rank correlation: {}", comparison.shared, comparison.jaccard, rank_correlation))
+					.on_hover_text("Rank correlation is 1 when the shared images are in the same order and -1 when the order is reversed.");
+				egui::Grid::new("method_comparison").striped(true).show(ui, |ui| {
+					ui.strong(model_name(models, app_state.comparison_models.0));
+					ui.strong(model_name(models, app_state.comparison_models.1));
+					ui.end_row();
+					for row in 0..comparison.a.len().max(comparison.b.len()) {
+						for (img, other) in [(comparison.a.get(row), &comparison.b), (comparison.b.get(row), &comparison.a)] {
+							match img {
+								Some(img) => {
+									// Images only one model found stand out.
+									let text = format!("{} ({:.3})", img.filename, img.distance_from_query.unwrap_or(0.0));
+									let text = if other.iter().any(|o| o.id == img.id) { egui::RichText::new(text) } else { egui::RichText::new(text).color(Color32::LIGHT_RED) };
+									if ui.link(text).on_hover_text(&img.path).clicked() {
+										image_to_view = Some(img.clone());
+									}
+								},
+								None => { ui.label(""); },
+							}
+						}
+						ui.end_row();
+					}
+				});
+			}
+		});
 	});
 
+	let image_id = selected_image.id;
+	if let Some((name, value)) = tag_to_add {
+		match app_state.engine.as_mut().unwrap().set_user_tag(image_id, &name, &value) {
+			Ok(_) => {
+				app_state.new_tag_name.clear();
+				app_state.new_tag_value.clear();
+			},
+			Err(e) => eprintln!("Failed to add tag {}: {}", name, e),
+		}
+		app_state.selected_image_user_tags = None;
+	}
+	if let Some(name) = tag_to_remove {
+		if let Err(e) = app_state.engine.as_mut().unwrap().remove_user_tag(image_id, &name) {
+			eprintln!("Failed to remove tag {}: {}", name, e);
+		}
+		app_state.selected_image_user_tags = None;
+	}
+	if app_state.selected_image_user_tags.is_none() {
+		// The tags changed, so it may have become sensitive or stopped being.
+		match app_state.engine.as_ref().unwrap().get_blurred_thumbnail(image_id) {
+			Ok(blurred) => app_state.selected_image.as_mut().unwrap().blurred_thumbnail = blurred,
+			Err(e) => eprintln!("Failed to load the blurred thumbnail: {}", e),
+		}
+	}
+	if toggle_reveal && !app_state.revealed_images.remove(&image_id) {
+		app_state.revealed_images.insert(image_id);
+	}
+
+	if let Some(rating) = new_rating {
+		match app_state.engine.as_mut().unwrap().set_rating(image_id, rating) {
+			Ok(_) => app_state.selected_image.as_mut().unwrap().rating = rating,
+			Err(e) => eprintln!("Failed to set rating: {}", e),
+		}
+	}
+
+	if toggle_favorite {
+		let favorite = !app_state.selected_image.as_ref().unwrap().favorite;
+		match app_state.engine.as_mut().unwrap().set_favorite(image_id, favorite) {
+			Ok(_) => app_state.selected_image.as_mut().unwrap().favorite = favorite,
+			Err(e) => eprintln!("Failed to set favorite: {}", e),
+		}
+	}
+
+	if hide_near_duplicates {
+		if let Err(e) = app_state.engine.as_mut().unwrap().hide_near_duplicates_of(image_id) {
+			eprintln!("Failed to hide near-duplicates: {}", e);
+		}
+		app_state.selected_image_duplicates = None;
+	}
+	if let Some(canonical_id) = make_canonical {
+		if let Err(e) = app_state.engine.as_mut().unwrap().set_canonical_image(image_id, &[canonical_id]) {
+			eprintln!("Failed to make the image canonical: {}", e);
+		}
+		app_state.selected_image_duplicates = None;
+	}
+	if let Some(duplicate_id) = unmark_duplicate {
+		if let Err(e) = app_state.engine.as_mut().unwrap().unmark_duplicate(duplicate_id) {
+			eprintln!("Failed to unmark duplicate: {}", e);
+		}
+		app_state.selected_image_duplicates = None;
+	}
+
+	if compare_methods {
+		if let (Some(a), Some(b)) = app_state.comparison_models {
+			match app_state.engine.as_ref().unwrap().compare_similarity_methods(image_id, a, b, METHOD_COMPARISON_SIZE) {
+				Ok(comparison) => app_state.method_comparison = Some(comparison),
+				Err(e) => eprintln!("Failed to compare embedding models: {}", e),
+			}
+		}
+	}
+
+	if exclude {
+		match app_state.engine.as_mut().unwrap().exclude_image(image_id) {
+			Ok(_) => {
+				app_state.selected_image = None;
+				return;
+			},
+			Err(e) => eprintln!("Failed to exclude the image: {}", e),
+		}
+	}
+
+	if let Some(img) = image_to_view {
+		app_state.selected_image = Some(img);
+		return;
+	}
+
+	if let Some(color) = search_by_color {
+		if let Err(e) = app_state.engine.as_mut().unwrap().query_by_color(color) {
+			app_state.query_error = e.to_string();
+		}
+		app_state.active_tab = AppTab::Search;
+		return;
+	}
+
+	if let Some(session) = search_session {
+		app_state.search_text = format!("session:{}", session);
+		app_state.current_page = 0;
+		app_state.active_tab = AppTab::Search;
+		search::run_search_page(app_state);
+		return;
+	}
+
 	// Show zoom rocker.
 	ui.horizontal(|ui|{
-		if ui.button("-").clicked() { app_state.zoom_level = (app_state.zoom_level - 0.1).max(0.1f32 ); }
-		if ui.button(format!("{}%", (app_state.zoom_level*100.0) as u32)).clicked() { app_state.zoom_level = 1.0f32; };
-		if ui.button("+").clicked() { app_state.zoom_level += 0.1; }
+		if ui.button("-").on_hover_text("Zoom out").clicked() { app_state.zoom_level = (app_state.zoom_level - 0.1).max(0.1f32 ); }
+		if ui.button(format!("{}%", (app_state.zoom_level*100.0) as u32)).on_hover_text("Reset zoom").clicked() { app_state.zoom_level = 1.0f32; };
+		if ui.button("+").on_hover_text("Zoom in").clicked() { app_state.zoom_level += 0.1; }
 	});
 
+	// Sensitive images are only shown blurred until they're revealed.
+	let selected_image = app_state.selected_image.as_ref().unwrap();
+	if selected_image.blurred_thumbnail.is_some() && !app_state.revealed_images.contains(&selected_image.id) {
+		show_thumbnail(ui, selected_image, &app_state.revealed_images, &mut app_state.image_id_to_texture_handle);
+		return;
+	}
+
 	// Show image.
+	let alt_text = thumbnail_alt_text(selected_image, &app_state.revealed_images);
 	if let Some(tex) = &app_state.full_image {
 		egui::ScrollArea::both()
 			.auto_shrink([false, false])
@@ -73,7 +388,22 @@ pub fn view_panel(
 				// Show the image:
 				//ui.add(egui::Image::new(texture, texture.size_vec2()));
 				// Same:
-				ui.image(tex);
+				ui.image(tex).widget_info(|| egui::WidgetInfo::labeled(egui::WidgetType::Label, &alt_text));
 			});
 	}
-}
\ No newline at end of file
+}
+
+fn model_name(models: &[EmbeddingModel], model_id: Option<i64>) -> String {
+	models.iter().find(|m| Some(m.id) == model_id).map(|m| format!("{} {}", m.name, m.version)).unwrap_or_default()
+}
+
+fn embedding_model_picker(ui: &mut Ui, label: &str, models: &[EmbeddingModel], model_id: &mut Option<i64>) {
+	ui.label(format!("{}:", label));
+	egui::ComboBox::from_id_source(format!("embedding_model_{}", label))
+		.selected_text(model_name(models, *model_id))
+		.show_ui(ui, |ui| {
+			for model in models {
+				ui.selectable_value(model_id, Some(model.id), format!("{} {} ({} images)", model.name, model.version, model.num_embeddings));
+			}
+		});
+}
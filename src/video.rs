@@ -0,0 +1,116 @@
+// Videos are indexed by the keyframe nearest their middle, with their length and codec as tags.
+// Decoding is done by FFmpeg, so it needs the video feature and FFmpeg's libraries.  Builds without them skip videos.
+
+use anyhow::{anyhow, Result};
+use image::DynamicImage;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const VIDEO_EXTENSIONS: &[&str] = &["mp4", "m4v", "mov", "mkv", "webm", "avi"];
+pub const SUPPORTED: bool = cfg!(feature = "video"); // Whether this build can read videos at all.
+
+#[derive(Clone, Debug)]
+pub struct VideoInfo {
+	pub frame: DynamicImage, // The keyframe nearest the middle.  Stands in for the video.
+	pub duration: Option<f64>, // In seconds.  Streams and broken files may not say.
+	pub codec: String,
+}
+
+impl VideoInfo {
+	/// Tags to index the frame under, so clips can be told apart from photos and found by length.
+	pub fn to_tags(&self) -> HashMap<String, String> {
+		let mut tags = HashMap::new();
+		tags.insert("Video Codec".to_string(), self.codec.clone());
+		if let Some(duration) = self.duration {
+			// Whole seconds, so it can be compared like tag:Duration>60.
+			tags.insert("Duration".to_string(), duration.round().to_string());
+		}
+		tags
+	}
+}
+
+/// True if the filename ends in one of the VIDEO_EXTENSIONS, in any case.
+pub fn is_video_file(filename: &str) -> bool {
+	filename.rsplit_once('.').map(|(_, extension)| VIDEO_EXTENSIONS.iter().any(|ext| extension.eq_ignore_ascii_case(ext))).unwrap_or(false)
+}
+
+/// The middle keyframe and details of the video at path.
+/// FFmpeg reads the file itself, so videos can't be read from memory or from inside archives.
+#[cfg(feature = "video")]
+pub fn read_video_info(path: &Path) -> Result<VideoInfo> {
+	use ffmpeg_next::{codec, format, media, software::scaling, util::frame::video::Video};
+	ffmpeg_next::init()?;
+	let mut input = format::input(path)?;
+	let (stream_index, parameters) = {
+		let stream = input.streams().best(media::Type::Video).ok_or_else(|| anyhow!("There's no video in it."))?;
+		(stream.index(), stream.parameters())
+	};
+	let mut decoder = codec::context::Context::from_parameters(parameters)?.decoder().video()?;
+	let codec = decoder.id().name().to_string();
+	let duration = if input.duration() > 0 { Some(input.duration() as f64 * f64::from(ffmpeg_next::rescale::TIME_BASE)) } else { None };
+	// Seeking lands on the last keyframe before the middle.  If it can't, the first frame will do.
+	if duration.is_some() {
+		let middle = input.duration() / 2;
+		let _ = input.seek(middle, ..middle + 1);
+	}
+
+	let mut scaler = scaling::Context::get(decoder.format(), decoder.width(), decoder.height(), format::Pixel::RGB24, decoder.width(), decoder.height(), scaling::Flags::BILINEAR)?;
+	let mut decoded = Video::empty();
+	let mut got_frame = false;
+	for (stream, packet) in input.packets() {
+		if stream.index() != stream_index {
+			continue;
+		}
+		decoder.send_packet(&packet)?;
+		if decoder.receive_frame(&mut decoded).is_ok() {
+			got_frame = true;
+			break;
+		}
+	}
+	// Some decoders hold frames back until they're told there are no more packets.
+	if !got_frame {
+		decoder.send_eof()?;
+		got_frame = decoder.receive_frame(&mut decoded).is_ok();
+	}
+	if !got_frame {
+		return Err(anyhow!("None of its frames could be decoded."));
+	}
+
+	let mut rgb = Video::empty();
+	scaler.run(&decoded, &mut rgb)?;
+	let (width, height) = (rgb.width(), rgb.height());
+	// Rows may be padded past the end of the pixels.
+	let mut pixels = Vec::with_capacity(width as usize * height as usize * 3);
+	for row in rgb.data(0).chunks(rgb.stride(0)).take(height as usize) {
+		pixels.extend_from_slice(&row[..width as usize * 3]);
+	}
+	let frame = image::RgbImage::from_raw(width, height, pixels).ok_or_else(|| anyhow!("The frame is cut off."))?;
+	Ok(VideoInfo { frame: DynamicImage::ImageRgb8(frame), duration, codec })
+}
+
+#[cfg(not(feature = "video"))]
+pub fn read_video_info(_path: &Path) -> Result<VideoInfo> {
+	Err(anyhow!("This build can't read videos.  Rebuild with --features video."))
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::video::*;
+
+	#[test]
+	fn test_video_info() {
+		assert!(is_video_file("clip.MP4"));
+		assert!(is_video_file("holiday.2019.webm"));
+		assert!(!is_video_file("mp4"));
+		assert!(!is_video_file("cover.png"));
+
+		let info = VideoInfo { frame: DynamicImage::new_rgb8(1, 1), duration: Some(3723.4), codec: "h264".to_string() };
+		let tags = info.to_tags();
+		assert_eq!(tags.get("Duration").map(String::as_str), Some("3723"));
+		assert_eq!(tags.get("Video Codec").map(String::as_str), Some("h264"));
+		assert!(!VideoInfo { duration: None, ..info }.to_tags().contains_key("Duration"));
+		if !SUPPORTED {
+			assert!(read_video_info(Path::new("clip.mp4")).is_err());
+		}
+	}
+}